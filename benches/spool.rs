@@ -0,0 +1,116 @@
+// spool.rs - Benchmarks of spool operations.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Benchmarks of the spool operations whose cost storage redesigns
+//! would change. Run them with "cargo bench".
+
+#[macro_use] extern crate criterion;
+extern crate byteorder;
+extern crate ed25519_dalek;
+extern crate multispool;
+extern crate rand;
+extern crate tempfile;
+
+use std::path::Path;
+use byteorder::{ByteOrder, BigEndian};
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput};
+use ed25519_dalek::Keypair;
+use rand::rngs::OsRng;
+use tempfile::{tempdir, TempDir};
+
+use multispool::spool::{MultiSpool, Spool, MESSAGE_ID_SIZE, MESSAGE_SIZE};
+
+/// The number of messages read by the batch read benchmark.
+const BATCH_SIZE: u32 = 100;
+
+/// The number of messages in the spool the recovery scan reopens.
+const RECOVERY_MESSAGES: u32 = 1000;
+
+fn message_id(index: u32) -> [u8; MESSAGE_ID_SIZE] {
+    let mut message_id = [0u8; MESSAGE_ID_SIZE];
+    BigEndian::write_u32(&mut message_id, index);
+    message_id
+}
+
+/// Makes a spool holding `count` full size messages.
+fn filled_spool(base_dir: &TempDir, count: u32) -> Spool {
+    let mut spool = Spool::new(&Path::new(base_dir.path()).join("spool.sled")).unwrap();
+    let message = vec![0x55u8; MESSAGE_SIZE];
+    for _ in 0..count {
+        spool.append(&message).unwrap();
+    }
+    spool.flush().unwrap();
+    spool
+}
+
+fn append(c: &mut Criterion) {
+    let base_dir = tempdir().unwrap();
+    let mut spool = filled_spool(&base_dir, 0);
+    let message = vec![0x55u8; MESSAGE_SIZE];
+    c.bench_function("append", move |b| b.iter(|| spool.append(&message).unwrap()));
+}
+
+fn read(c: &mut Criterion) {
+    let base_dir = tempdir().unwrap();
+    let spool = filled_spool(&base_dir, BATCH_SIZE);
+    let message_id = message_id(BATCH_SIZE / 2);
+    c.bench_function("read", move |b| b.iter(|| spool.read(&message_id).unwrap()));
+}
+
+fn batch_read(c: &mut Criterion) {
+    let base_dir = tempdir().unwrap();
+    let spool = filled_spool(&base_dir, BATCH_SIZE);
+    c.bench_function("batch_read", move |b| b.iter(|| {
+        for index in 0..BATCH_SIZE {
+            spool.read(&message_id(index)).unwrap();
+        }
+    }));
+}
+
+fn startup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("startup");
+    for &count in &[10u64, 100] {
+        let base_dir = tempdir().unwrap();
+        let base = base_dir.path().to_str().unwrap().to_string();
+        let mut multi_spool = MultiSpool::new(&base).unwrap();
+        let mut csprng = OsRng::new().unwrap();
+        for _ in 0..count {
+            let keypair = Keypair::generate(&mut csprng);
+            let signature = keypair.sign(&keypair.public.to_bytes());
+            multi_spool.create_spool(keypair.public, signature, &mut csprng).unwrap();
+        }
+        multi_spool.close().unwrap();
+        drop(multi_spool);
+        group.throughput(Throughput::Elements(count));
+        group.bench_with_input(BenchmarkId::new("spools", count), &base, |b, base| {
+            b.iter_batched(|| (), |_| MultiSpool::new(base).unwrap(), BatchSize::PerIteration)
+        });
+    }
+    group.finish();
+}
+
+fn recovery_scan(c: &mut Criterion) {
+    let base_dir = tempdir().unwrap();
+    let path = Path::new(base_dir.path()).join("spool.sled");
+    drop(filled_spool(&base_dir, RECOVERY_MESSAGES));
+    c.bench_function("recovery_scan", move |b| b.iter(|| {
+        let spool = Spool::new(&path).unwrap();
+        assert!(spool.check().unwrap().is_empty());
+    }));
+}
+
+criterion_group!(benches, append, read, batch_read, startup, recovery_scan);
+criterion_main!(benches);
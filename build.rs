@@ -0,0 +1,30 @@
+// build.rs - Multi-spool build script.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::process::Command;
+
+fn main() {
+    // Record the git commit we were built from, if we were built
+    // from a git checkout at all.
+    let output = Command::new("git").args(&["rev-parse", "HEAD"]).output();
+    if let Ok(output) = output {
+        if output.status.success() {
+            let commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            println!("cargo:rustc-env=MULTISPOOL_GIT_COMMIT={}", commit);
+        }
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+}
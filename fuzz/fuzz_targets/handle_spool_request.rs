@@ -0,0 +1,39 @@
+// handle_spool_request.rs - Fuzzes dispatch of spool requests.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Decodes arbitrary bytes as a SpoolRequest and dispatches it to a
+//! fresh in-memory spool store, then encodes the response the way the
+//! server does. Any panic along the way is a bug: the request comes
+//! straight from the mixnet.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use multispool::{SpoolRequest, encode_response, handle_spool_request};
+use multispool::store::{MemorySpoolStore, SpoolStore};
+
+/// The spool limit passed to the handler, so that creates can fail.
+const MAX_SPOOLS: u64 = 16;
+
+fuzz_target!(|data: &[u8]| {
+    let spool_request = match serde_cbor::from_slice::<SpoolRequest>(data) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    let store = MemorySpoolStore::default();
+    let mut spool_response = handle_spool_request(spool_request, 0, Some(MAX_SPOOLS), None, &store);
+    let _ = encode_response(&mut spool_response, store.max_message_size(), None);
+});
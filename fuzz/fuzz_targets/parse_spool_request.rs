@@ -0,0 +1,30 @@
+// parse_spool_request.rs - Fuzzes decoding of plugin request payloads.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Feeds arbitrary plugin request payloads, a big endian length
+//! followed by a CBOR encoded SpoolRequest, through the decoding the
+//! server does before dispatching.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use multispool::{SpoolRequest, spool_request_payload};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(raw_spool_request) = spool_request_payload(data) {
+        let _ = serde_cbor::from_slice::<SpoolRequest>(raw_spool_request);
+    }
+});
@@ -0,0 +1,150 @@
+// acl.rs - Appender access control lists.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Appender access control lists
+//!
+//! Anyone knowing a spool's ID may append to it, unless its owner
+//! attaches a list of writer public keys. Appends must then carry the
+//! public key of a listed writer and the writer's signature over the
+//! spool ID and the message, so the spool becomes a mailbox only
+//! those writers can drop messages into.
+//!
+//! The signature doesn't cover when a message was sent, so the
+//! service could append a message it has seen again. Writers worried
+//! about that send an idempotency key with their appends.
+
+use ed25519_dalek::{Keypair, PublicKey, Signature, PUBLIC_KEY_LENGTH};
+
+use crate::errors::AclError;
+
+
+/// The most writers a spool's access control list may hold.
+pub const MAX_APPENDERS: usize = 64;
+
+/// Prefix of the data a writer signs to append.
+const APPEND_CONTEXT: &[u8] = b"multispool append v1";
+
+/// Returns the data a writer signs to append `message` to a spool.
+fn signed_data(spool_id: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut data = APPEND_CONTEXT.to_vec();
+    data.extend_from_slice(spool_id);
+    data.extend_from_slice(message);
+    data
+}
+
+/// Signs an append of `message` to a spool as a listed writer.
+pub fn sign_append(keypair: &Keypair, spool_id: &[u8], message: &[u8]) -> Signature {
+    keypair.sign(&signed_data(spool_id, message))
+}
+
+/// AppendAuth is what an append carries to be let into a spool: a
+/// token if its owner requires append tokens, and a writer's public
+/// key and signature if it has an appender list. Whatever the spool
+/// doesn't need is left empty.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AppendAuth<'a> {
+    pub token: &'a [u8],
+    pub public_key: &'a [u8],
+    pub signature: &'a [u8],
+}
+
+impl<'a> AppendAuth<'a> {
+    /// Returns the credentials of an append carrying only a token.
+    pub fn token(token: &'a [u8]) -> AppendAuth<'a> {
+        AppendAuth {
+            token: token,
+            ..AppendAuth::default()
+        }
+    }
+}
+
+/// AppenderList is the public keys of the writers allowed to append
+/// to a spool.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AppenderList {
+    keys: Vec<PublicKey>,
+}
+
+impl AppenderList {
+    pub fn new(keys: Vec<PublicKey>) -> Result<AppenderList, AclError> {
+        if keys.is_empty() || keys.len() > MAX_APPENDERS {
+            return Err(AclError::InvalidAppenderList)
+        }
+        Ok(AppenderList {
+            keys: keys,
+        })
+    }
+
+    /// Decodes a list of concatenated public keys. A short key at the
+    /// end fails to decode like any other bad key.
+    pub fn from_bytes(bytes: &[u8]) -> Result<AppenderList, AclError> {
+        let keys = bytes.chunks(PUBLIC_KEY_LENGTH)
+            .map(|x| PublicKey::from_bytes(x).map_err(|_| AclError::InvalidAppenderList))
+            .collect::<Result<Vec<PublicKey>, AclError>>()?;
+        AppenderList::new(keys)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.keys.iter().flat_map(|x| x.to_bytes().to_vec()).collect()
+    }
+
+    pub fn keys(&self) -> &[PublicKey] {
+        &self.keys
+    }
+
+    /// Checks that `public_key` is a listed writer's and `signature`
+    /// its signature over an append of `message` to the spool.
+    pub fn verify(&self, spool_id: &[u8], message: &[u8], public_key: &[u8], signature: &[u8]) -> bool {
+        let public_key = match PublicKey::from_bytes(public_key) {
+            Ok(x) if self.keys.contains(&x) => x,
+            _ => return false,
+        };
+        match Signature::from_bytes(signature) {
+            Ok(signature) => public_key.verify(&signed_data(spool_id, message), &signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+    use super::*;
+
+    #[test]
+    fn appender_list_test() {
+        let mut csprng = thread_rng();
+        let writer = Keypair::generate(&mut csprng);
+        let stranger = Keypair::generate(&mut csprng);
+        let list = AppenderList::new(vec![writer.public]).unwrap();
+        assert_eq!(AppenderList::from_bytes(&list.to_bytes()).unwrap(), list);
+
+        let signature = sign_append(&writer, b"spool id", b"hello").to_bytes();
+        assert!(list.verify(b"spool id", b"hello", &writer.public.to_bytes(), &signature));
+
+        // Signatures are bound to the writer, spool and message.
+        assert!(!list.verify(b"other id", b"hello", &writer.public.to_bytes(), &signature));
+        assert!(!list.verify(b"spool id", b"hullo", &writer.public.to_bytes(), &signature));
+        let signature = sign_append(&stranger, b"spool id", b"hello").to_bytes();
+        assert!(!list.verify(b"spool id", b"hello", &stranger.public.to_bytes(), &signature));
+        assert!(!list.verify(b"spool id", b"hello", &[], &[]));
+
+        assert!(AppenderList::from_bytes(&[]).is_err());
+        assert!(AppenderList::from_bytes(&[1u8; PUBLIC_KEY_LENGTH + 1]).is_err());
+        assert!(AppenderList::new(vec![writer.public; MAX_APPENDERS + 1]).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+// activation.rs - Systemd socket activation.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Systemd socket activation
+//!
+//! Started by systemd from a socket unit, the service is handed its
+//! already bound listening sockets instead of binding them itself, so
+//! that systemd keeps them open while the service restarts and no
+//! connection is refused meanwhile. The sockets are passed as file
+//! descriptors from 3 on, counted by LISTEN_FDS and meant for the
+//! process LISTEN_PID, as sd_listen_fds(3) describes.
+//!
+//! Each socket is told apart by its FileDescriptorName in the unit,
+//! found in LISTEN_FDNAMES: "requests" for the spool service socket,
+//! "admin" for the admin socket and "replication" for the replication
+//! listener. Sockets without a name are taken in that order.
+
+use std::os::unix::io::RawFd;
+use std::process;
+
+use crate::errors::ActivationError;
+
+
+/// The first file descriptor passed by systemd.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// The name systemd gives sockets without a FileDescriptorName.
+const UNNAMED: &str = "unknown";
+
+/// ListenFds are the listening sockets passed by systemd and not yet
+/// taken.
+#[derive(Debug, Default)]
+pub struct ListenFds {
+    fds: Vec<(RawFd, Option<String>)>,
+}
+
+impl ListenFds {
+    /// Returns the sockets passed to this process, none if it wasn't
+    /// socket activated, and unsets the variables passing them so
+    /// that our children don't take them for theirs.
+    pub fn from_env() -> Result<ListenFds, ActivationError> {
+        let fds = ListenFds::parse(process::id(), |key| std::env::var(key).ok())?;
+        for key in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(key);
+        }
+        for (fd, _) in &fds.fds {
+            if unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+                return Err(ActivationError::InvalidFd(*fd))
+            }
+        }
+        Ok(fds)
+    }
+
+    /// Returns the sockets `getenv` says are passed to the process
+    /// `pid`.
+    pub fn parse<F: Fn(&str) -> Option<String>>(pid: u32, getenv: F) -> Result<ListenFds, ActivationError> {
+        let listen_pid = match getenv("LISTEN_PID") {
+            Some(x) => x.parse::<u32>().map_err(|_| ActivationError::InvalidVariable("LISTEN_PID".to_string()))?,
+            None => return Ok(ListenFds::default()),
+        };
+        if listen_pid != pid {
+            return Ok(ListenFds::default())
+        }
+        let count = match getenv("LISTEN_FDS") {
+            Some(x) => x.parse::<RawFd>().map_err(|_| ActivationError::InvalidVariable("LISTEN_FDS".to_string()))?,
+            None => return Ok(ListenFds::default()),
+        };
+        if count == 0 {
+            return Ok(ListenFds::default())
+        }
+        let names: Vec<Option<String>> = match getenv("LISTEN_FDNAMES") {
+            Some(x) => x.split(':')
+                .map(|x| if x.is_empty() || x == UNNAMED { None } else { Some(x.to_string()) })
+                .collect(),
+            None => vec![None; count.max(0) as usize],
+        };
+        if count < 0 || names.len() != count as usize {
+            return Err(ActivationError::InvalidVariable("LISTEN_FDNAMES".to_string()))
+        }
+        Ok(ListenFds {
+            fds: (LISTEN_FDS_START..).zip(names).collect(),
+        })
+    }
+
+    /// Takes the socket named `name`, or else the first one without a
+    /// name.
+    pub fn take(&mut self, name: &str) -> Option<RawFd> {
+        let index = self.fds.iter().position(|(_, x)| x.as_deref() == Some(name))
+            .or_else(|| self.fds.iter().position(|(_, x)| x.is_none()))?;
+        Some(self.fds.remove(index).0)
+    }
+
+    /// Returns the sockets not taken.
+    pub fn remaining(&self) -> Vec<RawFd> {
+        self.fds.iter().map(|(fd, _)| *fd).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    fn parse(pid: u32, env: &[(&str, &str)]) -> Result<ListenFds, ActivationError> {
+        let env: HashMap<String, String> = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        ListenFds::parse(pid, |key| env.get(key).cloned())
+    }
+
+    #[test]
+    fn listen_fds_test() {
+        assert!(parse(7, &[]).unwrap().remaining().is_empty());
+        // Sockets meant for another process, e.g. our parent.
+        assert!(parse(7, &[("LISTEN_PID", "8"), ("LISTEN_FDS", "1")]).unwrap().remaining().is_empty());
+
+        let mut fds = parse(7, &[("LISTEN_PID", "7"), ("LISTEN_FDS", "2")]).unwrap();
+        assert_eq!(fds.take("requests"), Some(3));
+        assert_eq!(fds.take("admin"), Some(4));
+        assert_eq!(fds.take("replication"), None);
+
+        let mut fds = parse(7, &[("LISTEN_PID", "7"), ("LISTEN_FDS", "3"), ("LISTEN_FDNAMES", "admin:unknown:metrics")]).unwrap();
+        assert_eq!(fds.take("admin"), Some(3));
+        assert_eq!(fds.take("requests"), Some(4));
+        assert_eq!(fds.take("replication"), None);
+        assert_eq!(fds.remaining(), vec![5]);
+
+        assert!(parse(7, &[("LISTEN_PID", "seven")]).is_err());
+        assert!(parse(7, &[("LISTEN_PID", "7"), ("LISTEN_FDS", "2"), ("LISTEN_FDNAMES", "requests")]).is_err());
+    }
+}
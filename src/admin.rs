@@ -0,0 +1,443 @@
+// admin.rs - Multi-spool operator admin API.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Operator admin API
+//!
+//! The admin API is served on its own unix socket and answers in
+//! JSON:
+//!
+//! * `GET /stats` summarizes all spools and counts the requests
+//!   handled since startup by command.
+//! * `GET /verify` checks every spool can be read.
+//! * `GET /spools` lists every spool.
+//! * `GET /spools/<id>` describes one spool.
+//! * `GET /spools/<id>/stats` returns what one spool holds, as its
+//!   owner's SPOOL_STATUS command does.
+//! * `GET /spools/<id>/messages` streams every message one spool
+//!   keeps, see below.
+//! * `DELETE /spools/<id>` purges a spool without the owner's signature.
+//! * `POST /spools/<id>/compact` compacts one spool.
+//! * `POST /compact` compacts every spool.
+//! * `POST /reencrypt` re-encrypts everything not yet encrypted with
+//!   the current master key.
+//! * `GET /audit` returns the latest audit log entries.
+//! * `GET /audit/<n>` returns the audit log entries following entry `n`.
+//! * `POST /backup` writes a backup of every spool to the backup
+//!   directory.
+//! * `POST /backup/incremental` writes a backup of the messages
+//!   appended to every spool since the last backup.
+//! * `GET /backups` lists the backups in the backup directory.
+//! * `POST /restore/<name>` replaces every spool with those in a backup.
+//! * `GET /orphans` lists the spool databases which belong to no spool.
+//! * `POST /gc` removes them.
+//! * `GET /role` returns the replication role and epoch.
+//! * `POST /promote` makes a standby the primary.
+//!
+//! Spool IDs are URL safe base64 encoded.
+//!
+//! Admin requests are handled alongside spool requests, so that
+//! backups, compactions and the like don't hold up the service. Only
+//! a restore waits for the spool requests being handled and keeps new
+//! ones waiting until it is done.
+//!
+//! A spool may hold more messages than fit in memory, so its messages
+//! aren't answered in JSON by `handle` but streamed by the service as
+//! a chunked CBOR sequence, RFC 8742, of `StreamedMessage`s, oldest
+//! first, read `STREAM_BATCH_SIZE` at a time.
+
+use serde_bytes::ByteBuf;
+use serde_cbor;
+use serde_json::Value;
+
+use crate::audit::{AuditEntry, DEFAULT_AUDIT_LIMIT};
+use crate::errors::{ArchiveError, MultiSpoolError, ReplicationError};
+use crate::spool::{MultiSpool, MultiSpoolStats, SpoolInfo, SpoolStats, SPOOL_ID_SIZE};
+
+
+/// The most messages read from a spool at a time while streaming
+/// its messages.
+pub const STREAM_BATCH_SIZE: usize = 256;
+
+/// The content type of streamed messages.
+pub const CBOR_SEQUENCE_CONTENT_TYPE: &str = "application/cbor-seq";
+
+/// AdminResponse is the HTTP status and JSON body of an admin request.
+pub struct AdminResponse {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl AdminResponse {
+    fn ok(body: Value) -> AdminResponse {
+        AdminResponse {
+            status: 200,
+            body: body,
+        }
+    }
+
+    fn error(status: u16, message: &str) -> AdminResponse {
+        AdminResponse {
+            status: status,
+            body: json!({ "error": message }),
+        }
+    }
+}
+
+impl From<MultiSpoolError> for AdminResponse {
+    fn from(error: MultiSpoolError) -> AdminResponse {
+        match error {
+            MultiSpoolError::NoSuchSpool => AdminResponse::error(404, "no such spool"),
+            MultiSpoolError::ArchiveError(ArchiveError::InvalidBackupName) => AdminResponse::error(400, "invalid backup name"),
+            MultiSpoolError::ReplicationError(ReplicationError::NotStandby) => AdminResponse::error(409, "not a standby"),
+            MultiSpoolError::NotPrimary => AdminResponse::error(409, "not the primary"),
+            e => AdminResponse::error(500, &format!("{}", e)),
+        }
+    }
+}
+
+/// StreamedMessage is one item of the CBOR sequence streamed for
+/// `GET /spools/<id>/messages`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StreamedMessage {
+    pub message_id: u32,
+    pub message: ByteBuf,
+}
+
+impl StreamedMessage {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, serde_cbor::Error> {
+        serde_cbor::to_vec(self)
+    }
+}
+
+/// Returns the spool whose messages an admin request asks to stream,
+/// or None if it asks for something else. An invalid spool ID is
+/// answered with the error response to send.
+pub fn streamed_spool(method: &str, path: &str) -> Option<Result<[u8; SPOOL_ID_SIZE], AdminResponse>> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["spools", encoded_id, "messages"]) => {
+            Some(decode_spool_id(encoded_id).ok_or_else(|| AdminResponse::error(400, "invalid spool id")))
+        },
+        _ => None,
+    }
+}
+
+/// Encodes a spool ID for use in admin API paths.
+pub fn encode_spool_id(spool_id: &[u8; SPOOL_ID_SIZE]) -> String {
+    base64::encode_config(spool_id, base64::URL_SAFE)
+}
+
+/// Decodes a spool ID from an admin API path.
+pub fn decode_spool_id(encoded: &str) -> Option<[u8; SPOOL_ID_SIZE]> {
+    match base64::decode_config(encoded, base64::URL_SAFE) {
+        Ok(ref raw) if raw.len() == SPOOL_ID_SIZE => Some(*array_ref![raw, 0, SPOOL_ID_SIZE]),
+        _ => None,
+    }
+}
+
+/// Compares an admin token in constant time.
+pub fn token_matches(expected: &str, given: &str) -> bool {
+    if expected.len() != given.len() {
+        return false
+    }
+    let mut diff = 0u8;
+    for (a, b) in expected.bytes().zip(given.bytes()) {
+        diff |= a ^ b;
+    }
+    diff == 0
+}
+
+fn spool_info_json(info: &SpoolInfo) -> Value {
+    json!({
+        "spool_id": encode_spool_id(&info.spool_id),
+        "public_key": base64::encode(&info.public_key.to_bytes()),
+        "message_count": info.message_count,
+        "size_bytes": info.size_bytes,
+        "shard": info.shard,
+        "age_seconds": info.age.map(|x| x.as_secs()),
+        "purged_at": info.purged_at,
+        "capacity": info.policy.as_ref().map(|x| x.capacity),
+        "circular": info.policy.as_ref().map(|x| x.circular),
+        "expires_at": info.policy.as_ref().and_then(|x| x.expires_at),
+        "reader_count": info.policy.as_ref().map(|x| x.readers.len()),
+        "hash_index": info.policy.as_ref().map(|x| x.hash_index),
+        "dedup_window": info.policy.as_ref().map(|x| x.dedup_window),
+        "created_at": info.activity.created_at,
+        "last_append": info.activity.last_append,
+        "last_read": info.activity.last_read,
+    })
+}
+
+fn spool_stats_json(stats: &SpoolStats) -> Value {
+    json!({
+        "message_count": stats.MessageCount,
+        "size_bytes": stats.SizeBytes,
+        "head": stats.Head,
+        "first_message": stats.FirstMessage,
+        "created_at": stats.CreatedAt,
+        "last_append": stats.LastAppend,
+        "last_read": stats.LastRead,
+        "capacity": stats.Capacity,
+        "expires_at": stats.ExpiresAt,
+    })
+}
+
+fn list_spools(multi_spool: &MultiSpool) -> AdminResponse {
+    let mut spools = vec![];
+    for spool_id in multi_spool.spool_ids() {
+        match multi_spool.spool_info(spool_id) {
+            Ok(info) => spools.push(spool_info_json(&info)),
+            Err(e) => return AdminResponse::from(e),
+        }
+    }
+    AdminResponse::ok(json!({ "spools": spools }))
+}
+
+/// Returns the JSON form of the summary of every spool.
+pub fn stats_json(stats: &MultiSpoolStats) -> Value {
+    let shards: Vec<Value> = stats.shards.iter().map(|shard| json!({
+        "dir": shard.dir,
+        "spool_count": shard.spool_count,
+        "message_count": shard.message_count,
+        "size_bytes": shard.size_bytes,
+    })).collect();
+    let commands: serde_json::Map<String, Value> = stats.commands.iter()
+        .map(|&(command, count)| (command.to_string(), json!(count)))
+        .collect();
+    json!({
+        "spool_count": stats.spool_count,
+        "message_count": stats.message_count,
+        "size_bytes": stats.size_bytes,
+        "shards": shards,
+        "requests": commands,
+    })
+}
+
+fn stats(multi_spool: &MultiSpool) -> AdminResponse {
+    match multi_spool.stats() {
+        Ok(stats) => AdminResponse::ok(stats_json(&stats)),
+        Err(e) => AdminResponse::from(e),
+    }
+}
+
+fn verify(multi_spool: &MultiSpool) -> AdminResponse {
+    let mut failures = vec![];
+    for spool_id in multi_spool.spool_ids() {
+        if let Err(e) = multi_spool.verify_spool(spool_id) {
+            failures.push(json!({
+                "spool_id": encode_spool_id(&spool_id),
+                "error": format!("{}", e),
+            }));
+        }
+    }
+    let status = if failures.is_empty() { 200 } else { 500 };
+    AdminResponse {
+        status: status,
+        body: json!({ "failures": failures }),
+    }
+}
+
+fn role(multi_spool: &MultiSpool) -> AdminResponse {
+    AdminResponse::ok(json!({
+        "role": multi_spool.role().as_str(),
+        "epoch": multi_spool.epoch(),
+    }))
+}
+
+fn audit_entry_json(sequence: u64, entry: &AuditEntry) -> Value {
+    let spool_id = if entry.spool_id.len() == SPOOL_ID_SIZE {
+        json!(encode_spool_id(array_ref![entry.spool_id, 0, SPOOL_ID_SIZE]))
+    } else {
+        Value::Null
+    };
+    json!({
+        "sequence": sequence,
+        "timestamp": entry.timestamp,
+        "action": entry.action,
+        "spool_id": spool_id,
+        "outcome": entry.outcome,
+        "detail": entry.detail,
+    })
+}
+
+fn audit(multi_spool: &MultiSpool, after: Option<u64>) -> AdminResponse {
+    match multi_spool.audit_log().entries(after, DEFAULT_AUDIT_LIMIT) {
+        Ok(entries) => {
+            let entries: Vec<Value> = entries.iter().map(|&(sequence, ref entry)| audit_entry_json(sequence, entry)).collect();
+            AdminResponse::ok(json!({ "entries": entries }))
+        },
+        Err(e) => AdminResponse::from(MultiSpoolError::from(e)),
+    }
+}
+
+/// Handles an admin request for `path` with the HTTP `method`.
+pub fn handle(method: &str, path: &str, multi_spool: &MultiSpool) -> AdminResponse {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["stats"]) => stats(multi_spool),
+        ("GET", ["verify"]) => verify(multi_spool),
+        ("GET", ["spools"]) => list_spools(multi_spool),
+        ("GET", ["audit"]) => audit(multi_spool, None),
+        ("GET", ["audit", after]) => {
+            match after.parse::<u64>() {
+                Ok(after) => audit(multi_spool, Some(after)),
+                Err(_) => AdminResponse::error(400, "invalid audit sequence number"),
+            }
+        },
+        ("GET", ["role"]) => role(multi_spool),
+        ("POST", ["promote"]) => {
+            match multi_spool.promote() {
+                Ok(_) => role(multi_spool),
+                Err(e) => AdminResponse::from(e),
+            }
+        },
+        ("POST", ["reencrypt"]) => {
+            match multi_spool.reencrypt() {
+                Ok(count) => AdminResponse::ok(json!({ "reencrypted": count })),
+                Err(e) => AdminResponse::from(e),
+            }
+        },
+        ("POST", ["backup"]) | ("POST", ["backup", "incremental"]) => {
+            match multi_spool.backup(segments.len() == 2) {
+                Ok(name) => AdminResponse::ok(json!({ "backup": name })),
+                Err(e) => AdminResponse::from(e),
+            }
+        },
+        ("GET", ["backups"]) => {
+            match multi_spool.backups() {
+                Ok(names) => AdminResponse::ok(json!({ "backups": names })),
+                Err(e) => AdminResponse::from(e),
+            }
+        },
+        ("POST", ["restore", name]) => {
+            match multi_spool.restore(name) {
+                Ok(count) => AdminResponse::ok(json!({ "restored": count })),
+                Err(e) => AdminResponse::from(e),
+            }
+        },
+        ("GET", ["orphans"]) => {
+            match multi_spool.orphans() {
+                Ok(paths) => {
+                    let paths: Vec<String> = paths.iter().map(|x| x.to_string_lossy().into_owned()).collect();
+                    AdminResponse::ok(json!({ "orphans": paths }))
+                },
+                Err(e) => AdminResponse::from(e),
+            }
+        },
+        ("POST", ["gc"]) => {
+            match multi_spool.collect_orphans() {
+                Ok(count) => AdminResponse::ok(json!({ "removed": count })),
+                Err(e) => AdminResponse::from(e),
+            }
+        },
+        ("POST", ["compact"]) => {
+            match multi_spool.compact() {
+                Ok(reclaimed) => AdminResponse::ok(json!({ "reclaimed_bytes": reclaimed })),
+                Err(e) => AdminResponse::from(e),
+            }
+        },
+        (_, ["spools", encoded_id]) | (_, ["spools", encoded_id, _]) => {
+            let spool_id = match decode_spool_id(encoded_id) {
+                Some(x) => x,
+                None => return AdminResponse::error(400, "invalid spool id"),
+            };
+            let result = match (method, segments.len()) {
+                ("GET", 2) => multi_spool.spool_info(spool_id).map(|x| spool_info_json(&x)),
+                ("GET", 3) if segments[2] == "stats" => multi_spool.spool_stats(spool_id).map(|x| spool_stats_json(&x)),
+                ("DELETE", 2) => multi_spool.force_purge_spool(spool_id).map(|_| json!({ "purged": encoded_id })),
+                ("POST", 3) if segments[2] == "compact" => {
+                    multi_spool.compact_spool(spool_id).map(|x| json!({ "reclaimed_bytes": x }))
+                },
+                _ => return AdminResponse::error(404, "not found"),
+            };
+            match result {
+                Ok(body) => AdminResponse::ok(body),
+                Err(e) => AdminResponse::from(e),
+            }
+        },
+        _ => AdminResponse::error(404, "not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use rand::thread_rng;
+    use ed25519_dalek::Keypair;
+    use self::tempfile::tempdir;
+    use super::*;
+
+    #[test]
+    fn token_matches_test() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secret", "secreT"));
+        assert!(!token_matches("secret", "secrets"));
+    }
+
+    #[test]
+    fn admin_list_and_purge_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let signature = keypair.sign(&keypair.public.to_bytes());
+        let spool_id = multi_spool.create_spool(keypair.public, signature, &mut csprng).unwrap();
+        let encoded_id = encode_spool_id(&spool_id);
+        assert_eq!(decode_spool_id(&encoded_id), Some(spool_id));
+
+        let response = handle("GET", "/spools", &multi_spool);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["spools"][0]["spool_id"], json!(encoded_id));
+        assert_eq!(response.body["spools"][0]["message_count"], json!(0));
+        let response = handle("GET", &format!("/spools/{}/stats", encoded_id), &multi_spool);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["head"], json!(0));
+        match streamed_spool("GET", &format!("/spools/{}/messages", encoded_id)) {
+            Some(Ok(x)) => assert_eq!(x, spool_id),
+            _ => panic!("expected a streamed spool"),
+        }
+        assert_eq!(streamed_spool("GET", "/spools/not-an-id/messages").map(|x| x.err().map(|x| x.status)), Some(Some(400)));
+        assert!(streamed_spool("POST", &format!("/spools/{}/messages", encoded_id)).is_none());
+        assert!(streamed_spool("GET", &format!("/spools/{}", encoded_id)).is_none());
+        assert_eq!(multi_spool.export_messages(spool_id, 0, STREAM_BATCH_SIZE).unwrap(), vec![]);
+        let streamed = StreamedMessage {
+            message_id: 3,
+            message: ByteBuf::from(b"hello".to_vec()),
+        };
+        assert_eq!(serde_cbor::from_slice::<StreamedMessage>(&streamed.to_bytes().unwrap()).unwrap(), streamed);
+        let response = handle("GET", "/stats", &multi_spool);
+        assert_eq!(response.body["spool_count"], json!(1));
+        assert_eq!(response.body["shards"][0]["spool_count"], json!(1));
+        assert!(response.body["requests"]["create"].is_u64());
+
+        let response = handle("DELETE", &format!("/spools/{}", encoded_id), &multi_spool);
+        assert_eq!(response.status, 200);
+        let response = handle("GET", &format!("/spools/{}", encoded_id), &multi_spool);
+        assert_eq!(response.status, 404);
+        let response = handle("GET", "/spools/not-an-id", &multi_spool);
+        assert_eq!(response.status, 400);
+
+        let response = handle("GET", "/audit", &multi_spool);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["entries"][0]["action"], json!("create"));
+        assert_eq!(response.body["entries"][1]["action"], json!("force_purge"));
+        assert_eq!(response.body["entries"][1]["spool_id"], json!(encoded_id));
+        assert_eq!(response.body["entries"][1]["outcome"], json!("ok"));
+        let response = handle("GET", "/audit/0", &multi_spool);
+        assert_eq!(response.body["entries"][0]["sequence"], json!(1));
+    }
+}
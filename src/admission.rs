@@ -0,0 +1,121 @@
+// admission.rs - Load shedding.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Load shedding
+//!
+//! Mixnet traffic comes in spikes. Rather than letting requests pile
+//! up until everyone times out, if so configured at most
+//! `max_in_flight` spool requests are handled at once and at most `max_queued` more wait for a turn.
+//! Any others are answered right away with a busy status, which
+//! clients treat like being rate limited.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+
+/// The number of requests waiting for a turn by default.
+pub const DEFAULT_MAX_QUEUED_REQUESTS: usize = 64;
+
+/// Queued counts a request as waiting until it is dropped, which it
+/// also is when the connection goes away while waiting.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for Queued<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Turn is a request's turn to be handled, which lasts until it is
+/// dropped.
+pub struct Turn {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Admission hands out turns to handle a request.
+pub struct Admission {
+    permits: Option<Arc<Semaphore>>,
+    queued: AtomicUsize,
+    max_queued: usize,
+}
+
+impl Admission {
+    /// Admits `max_in_flight` requests at once, or any number if None.
+    pub fn new(max_in_flight: Option<usize>, max_queued: usize) -> Admission {
+        Admission {
+            permits: max_in_flight.map(|x| Arc::new(Semaphore::new(x))),
+            queued: AtomicUsize::new(0),
+            max_queued: max_queued,
+        }
+    }
+
+    /// Waits for a turn, or returns None right away if the queue is
+    /// full.
+    pub async fn admit(&self) -> Option<Turn> {
+        let permits = match self.permits {
+            Some(ref permits) => permits,
+            None => return Some(Turn { _permit: None }),
+        };
+        if let Ok(permit) = permits.clone().try_acquire_owned() {
+            return Some(Turn { _permit: Some(permit) })
+        }
+        let _queued = Queued(&self.queued);
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+            return None
+        }
+        let permit = permits.clone().acquire_owned().await.ok()?;
+        Some(Turn { _permit: Some(permit) })
+    }
+
+    /// Returns the number of requests waiting for a turn.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admission_test() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let unlimited = Admission::new(None, 0);
+            let _turns = (unlimited.admit().await.unwrap(), unlimited.admit().await.unwrap());
+
+            let admission = Arc::new(Admission::new(Some(1), 1));
+            let first = admission.admit().await.unwrap();
+
+            // The second request waits for the first, and a third is
+            // turned away meanwhile.
+            let waiting = admission.clone();
+            let second = tokio::spawn(async move { waiting.admit().await.is_some() });
+            while admission.queued() == 0 {
+                tokio::task::yield_now().await;
+            }
+            assert!(admission.admit().await.is_none());
+            assert_eq!(admission.queued(), 1);
+            drop(first);
+            assert!(second.await.unwrap());
+            assert_eq!(admission.queued(), 0);
+            assert!(admission.admit().await.is_some());
+        });
+    }
+}
@@ -0,0 +1,232 @@
+// archive.rs - Portable spool archives.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Portable spool archives
+//!
+//! A spool can be exported into a single CBOR archive holding its
+//! owner key, its messages in order, its append token key and the
+//! tokens already spent, and imported under the same spool ID by
+//! another provider, so that users can move their mailbox without
+//! their correspondents noticing. Messages are archived as they were
+//! appended, without this service's compression or encryption, so an
+//! archive must be guarded like the spool itself.
+
+use std::mem;
+use ed25519_dalek::{PublicKey, PUBLIC_KEY_LENGTH};
+use serde_bytes::ByteBuf;
+use serde_cbor;
+
+use crate::acl::AppenderList;
+use crate::errors::ArchiveError;
+use crate::options::SpoolPolicy;
+use crate::spool::SPOOL_ID_SIZE;
+use crate::tokens::TokenKey;
+
+
+/// The archive format version written by `SpoolArchive::to_bytes`.
+pub const ARCHIVE_FORMAT_VERSION: u8 = 1;
+
+/// SpoolArchive is everything needed to recreate a spool elsewhere.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SpoolArchive {
+    pub version: u8,
+    #[serde(with = "serde_bytes")]
+    pub spool_id: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub public_key: Vec<u8>,
+    /// The ID of the first message, which is zero unless the archive
+    /// is part of an incremental backup, see `backup`.
+    #[serde(default)]
+    pub first_message: u64,
+    /// Every message from `first_message` on, in order.
+    pub messages: Vec<ByteBuf>,
+    /// The unix time each message was appended at, zero if unknown.
+    /// Empty if none is known.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub appended_at: Vec<u64>,
+    /// The owner's append token key, empty if appends need no token.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub append_token_key: Vec<u8>,
+    /// The IDs of the append tokens already spent.
+    #[serde(default)]
+    pub spent_tokens: Vec<ByteBuf>,
+    /// The public keys of the writers allowed to append, empty if
+    /// anyone may, see `acl`.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub appenders: Vec<u8>,
+    /// The policy of a spool created with options, see `options`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<SpoolPolicy>,
+    /// Seconds since the unix epoch.
+    pub exported_at: u64,
+    /// The unix time the spool was purged at, if it is waiting to be
+    /// deleted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purged_at: Option<u64>,
+}
+
+impl SpoolArchive {
+    /// Encodes the archive, which must be of the current version.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ArchiveError> {
+        if self.version != ARCHIVE_FORMAT_VERSION {
+            return Err(ArchiveError::UnsupportedVersion(self.version))
+        }
+        Ok(serde_cbor::to_vec(self)?)
+    }
+
+    /// Decodes and checks an archive.
+    pub fn from_bytes(bytes: &[u8]) -> Result<SpoolArchive, ArchiveError> {
+        let archive: SpoolArchive = serde_cbor::from_slice(bytes)?;
+        if archive.version != ARCHIVE_FORMAT_VERSION {
+            return Err(ArchiveError::UnsupportedVersion(archive.version))
+        }
+        archive.spool_id()?;
+        archive.public_key()?;
+        archive.append_token_key()?;
+        archive.appenders()?;
+        Ok(archive)
+    }
+
+    pub fn spool_id(&self) -> Result<[u8; SPOOL_ID_SIZE], ArchiveError> {
+        if self.spool_id.len() != SPOOL_ID_SIZE {
+            return Err(ArchiveError::InvalidArchive)
+        }
+        Ok(*array_ref![self.spool_id, 0, SPOOL_ID_SIZE])
+    }
+
+    pub fn public_key(&self) -> Result<PublicKey, ArchiveError> {
+        if self.public_key.len() != PUBLIC_KEY_LENGTH {
+            return Err(ArchiveError::InvalidArchive)
+        }
+        PublicKey::from_bytes(&self.public_key).map_err(|_| ArchiveError::InvalidArchive)
+    }
+
+    /// Returns the number of messages in the spool.
+    pub fn message_count(&self) -> u64 {
+        self.first_message + self.messages.len() as u64
+    }
+
+    /// Brings the archive up to date with `increment`, a later
+    /// archive of the same spool starting where this one ends.
+    pub fn apply(&mut self, increment: SpoolArchive) -> Result<(), ArchiveError> {
+        if increment.spool_id != self.spool_id || increment.first_message != self.message_count() {
+            return Err(ArchiveError::BrokenChain)
+        }
+        let mut appended_at = mem::replace(&mut self.appended_at, vec![]);
+        if !appended_at.is_empty() || !increment.appended_at.is_empty() {
+            appended_at.resize(self.messages.len(), 0);
+            appended_at.extend(&increment.appended_at);
+            appended_at.resize(self.messages.len() + increment.messages.len(), 0);
+        }
+        let mut messages = mem::replace(&mut self.messages, vec![]);
+        messages.extend(increment.messages);
+        *self = SpoolArchive {
+            first_message: self.first_message,
+            messages: messages,
+            appended_at: appended_at,
+            ..increment
+        };
+        Ok(())
+    }
+
+    /// Returns the unix time the message at `index` in `messages` was
+    /// appended at, if known.
+    pub fn appended_at(&self, index: usize) -> Option<u64> {
+        self.appended_at.get(index).cloned().filter(|x| *x != 0)
+    }
+
+    /// Returns the append token key, if appends need tokens.
+    pub fn append_token_key(&self) -> Result<Option<TokenKey>, ArchiveError> {
+        if self.append_token_key.is_empty() {
+            return Ok(None)
+        }
+        let key = TokenKey::from_bytes(&self.append_token_key).map_err(|_| ArchiveError::InvalidArchive)?;
+        Ok(Some(key))
+    }
+
+    /// Returns the writers allowed to append, if the spool has an
+    /// appender list.
+    pub fn appenders(&self) -> Result<Option<AppenderList>, ArchiveError> {
+        if self.appenders.is_empty() {
+            return Ok(None)
+        }
+        let appenders = AppenderList::from_bytes(&self.appenders).map_err(|_| ArchiveError::InvalidArchive)?;
+        Ok(Some(appenders))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+
+    use self::rand::thread_rng;
+    use ed25519_dalek::Keypair;
+    use super::*;
+
+    #[test]
+    fn archive_encoding_test() {
+        let mut csprng = thread_rng();
+        let keypair = Keypair::generate(&mut csprng);
+        let mut archive = SpoolArchive {
+            version: ARCHIVE_FORMAT_VERSION,
+            spool_id: vec![1u8; SPOOL_ID_SIZE],
+            public_key: keypair.public.to_bytes().to_vec(),
+            first_message: 0,
+            messages: vec![ByteBuf::from(b"hello".to_vec()), ByteBuf::from(vec![])],
+            appended_at: vec![],
+            append_token_key: vec![],
+            spent_tokens: vec![],
+            appenders: vec![],
+            policy: None,
+            exported_at: 1,
+            purged_at: None,
+        };
+        let decoded = SpoolArchive::from_bytes(&archive.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded, archive);
+        assert_eq!(decoded.spool_id().unwrap(), [1u8; SPOOL_ID_SIZE]);
+        assert!(decoded.append_token_key().unwrap().is_none());
+        assert!(decoded.appenders().unwrap().is_none());
+
+        let mut increment = decoded.clone();
+        increment.first_message = 2;
+        increment.messages = vec![ByteBuf::from(b"world".to_vec())];
+        let mut merged = decoded.clone();
+        merged.apply(increment.clone()).unwrap();
+        assert_eq!(merged.message_count(), 3);
+        assert_eq!(merged.messages[2], ByteBuf::from(b"world".to_vec()));
+        assert!(merged.apply(increment.clone()).is_err());
+
+        // Append times known only for later messages are kept.
+        increment.appended_at = vec![7];
+        let mut merged = decoded.clone();
+        merged.apply(increment).unwrap();
+        assert_eq!(merged.appended_at, vec![0, 0, 7]);
+        assert_eq!(merged.appended_at(0), None);
+        assert_eq!(merged.appended_at(2), Some(7));
+
+        archive.spool_id = vec![1u8; SPOOL_ID_SIZE - 1];
+        assert!(SpoolArchive::from_bytes(&serde_cbor::to_vec(&archive).unwrap()).is_err());
+        archive.spool_id = vec![1u8; SPOOL_ID_SIZE];
+        archive.version = ARCHIVE_FORMAT_VERSION + 1;
+        assert!(archive.to_bytes().is_err());
+        match SpoolArchive::from_bytes(&serde_cbor::to_vec(&archive).unwrap()) {
+            Err(ArchiveError::UnsupportedVersion(x)) => assert_eq!(x, ARCHIVE_FORMAT_VERSION + 1),
+            _ => panic!("expected an unsupported version"),
+        }
+        assert!(SpoolArchive::from_bytes(b"not an archive").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+// audit.rs - Multi-spool audit log.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Audit log of administrative and destructive operations
+//!
+//! Spool creation and purges, token key changes, master and identity
+//! key changes and admin actions are recorded, whether they succeed
+//! or not, in an append-only sled tree kept with the spool set. Each
+//! entry is keyed by a big endian sequence number and flushed to disk
+//! before the operation returns, so that the log survives crashes for
+//! post-incident forensics. There is no way to delete entries.
+
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{ByteOrder, BigEndian};
+use serde_cbor;
+use sled::Tree;
+
+use crate::errors::AuditError;
+use crate::spool::SPOOL_ID_SIZE;
+
+
+/// The number of entries returned by default when reading the log.
+pub const DEFAULT_AUDIT_LIMIT: usize = 100;
+
+/// The outcome recorded for successful operations.
+pub const OUTCOME_OK: &str = "ok";
+
+/// AuditEntry is one operation in the audit log.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AuditEntry {
+    /// Seconds since the unix epoch.
+    pub timestamp: u64,
+    pub action: String,
+    /// The spool the operation was about, empty if none.
+    #[serde(with = "serde_bytes")]
+    pub spool_id: Vec<u8>,
+    /// OUTCOME_OK or the error the operation failed with.
+    pub outcome: String,
+    /// Anything else worth knowing, such as a new public key.
+    #[serde(default)]
+    pub detail: String,
+}
+
+fn sequence_key(sequence: u64) -> [u8; 8] {
+    let mut key = [0u8; 8];
+    BigEndian::write_u64(&mut key, sequence);
+    key
+}
+
+fn decode_entry(key: &[u8], value: &[u8]) -> Result<(u64, AuditEntry), AuditError> {
+    if key.len() != 8 {
+        return Err(AuditError::CorruptEntry)
+    }
+    Ok((BigEndian::read_u64(key), serde_cbor::from_slice(value)?))
+}
+
+/// AuditLog appends entries to the audit tree.
+#[derive(Clone)]
+pub struct AuditLog {
+    tree: Arc<Tree>,
+    next_sequence: Arc<Mutex<u64>>,
+}
+
+impl AuditLog {
+    pub fn new(tree: Arc<Tree>) -> Result<AuditLog, AuditError> {
+        let next_sequence = match tree.iter().keys().next_back() {
+            Some(key) => {
+                let key = key?;
+                if key.len() != 8 {
+                    return Err(AuditError::CorruptEntry)
+                }
+                BigEndian::read_u64(&key) + 1
+            },
+            None => 0,
+        };
+        Ok(AuditLog {
+            tree: tree,
+            next_sequence: Arc::new(Mutex::new(next_sequence)),
+        })
+    }
+
+    /// Appends an entry and flushes it to disk, returning its
+    /// sequence number.
+    pub fn record(&self,
+                  action: &str,
+                  spool_id: Option<&[u8; SPOOL_ID_SIZE]>,
+                  outcome: &str,
+                  detail: &str)
+                  -> Result<u64, AuditError> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0);
+        let entry = AuditEntry {
+            timestamp: timestamp,
+            action: action.to_string(),
+            spool_id: spool_id.map(|x| x.to_vec()).unwrap_or_default(),
+            outcome: outcome.to_string(),
+            detail: detail.to_string(),
+        };
+        let mut next_sequence = self.next_sequence.lock().unwrap_or_else(PoisonError::into_inner);
+        let sequence = *next_sequence;
+        self.tree.set(sequence_key(sequence), serde_cbor::to_vec(&entry)?)?;
+        self.tree.flush()?;
+        *next_sequence += 1;
+        Ok(sequence)
+    }
+
+    /// Returns up to `limit` entries following the one numbered
+    /// `after`, or the latest `limit` entries if `after` is None.
+    pub fn entries(&self, after: Option<u64>, limit: usize) -> Result<Vec<(u64, AuditEntry)>, AuditError> {
+        let mut entries = vec![];
+        match after {
+            Some(after) => {
+                if after == u64::max_value() {
+                    return Ok(entries)
+                }
+                for item in self.tree.scan(sequence_key(after + 1)).take(limit) {
+                    let (key, value) = item?;
+                    entries.push(decode_entry(&key, &value)?);
+                }
+            },
+            None => {
+                for item in self.tree.iter().rev().take(limit) {
+                    let (key, value) = item?;
+                    entries.push(decode_entry(&key, &value)?);
+                }
+                entries.reverse();
+            },
+        }
+        Ok(entries)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempdir;
+    use sled::Db;
+    use super::*;
+
+    #[test]
+    fn audit_log_test() {
+        let dir = tempdir().unwrap();
+        let db = Db::start_default(dir.path()).unwrap();
+        let log = AuditLog::new(db.open_tree(b"audit".to_vec()).unwrap()).unwrap();
+        let spool_id = [1u8; SPOOL_ID_SIZE];
+        assert_eq!(log.record("create", Some(&spool_id), OUTCOME_OK, "").unwrap(), 0);
+        assert_eq!(log.record("purge", Some(&spool_id), "Error, no such spool.", "").unwrap(), 1);
+        assert_eq!(log.record("reencrypt", None, OUTCOME_OK, "2 entries").unwrap(), 2);
+
+        let entries = log.entries(None, 2).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, 1);
+        assert_eq!(entries[0].1.action, "purge");
+        assert_eq!(entries[1].1.detail, "2 entries");
+        assert!(entries[1].1.spool_id.is_empty());
+        let entries = log.entries(Some(0), 1).unwrap();
+        assert_eq!(entries[0].1.spool_id, spool_id.to_vec());
+        assert!(log.entries(Some(2), 10).unwrap().is_empty());
+
+        // Sequence numbers carry on where the log left off.
+        let log = AuditLog::new(db.open_tree(b"audit".to_vec()).unwrap()).unwrap();
+        assert_eq!(log.record("compact", None, OUTCOME_OK, "").unwrap(), 3);
+    }
+}
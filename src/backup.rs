@@ -0,0 +1,280 @@
+// backup.rs - Multi-spool backups.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Backups of every spool
+//!
+//! A backup is a single CBOR file holding the archive of every spool,
+//! see `archive`, taken while the spools are locked so that it is
+//! consistent, along with a SHA-256 digest of the archives. Restoring
+//! checks the digest and decodes every archive before any live spool
+//! is touched. Backups are written to and read from the backup
+//! directory only, by file name.
+//!
+//! An incremental backup names the backup it builds on, its base,
+//! and only holds the messages appended since the base was taken,
+//! which the spool set tracks with a watermark per spool. The spool
+//! metadata, which is small, is always included in full. Restoring an
+//! incremental backup reads its whole chain back to a full backup.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use byteorder::{ByteOrder, BigEndian};
+use serde_bytes::ByteBuf;
+use serde_cbor;
+use sha2::{Digest, Sha256};
+
+use crate::archive::SpoolArchive;
+use crate::errors::ArchiveError;
+
+
+/// The backup format version written by `Backup::to_bytes`.
+pub const BACKUP_FORMAT_VERSION: u8 = 1;
+
+/// The backup directory's name within the data directory, unless
+/// another is configured.
+pub const DEFAULT_BACKUP_DIR: &str = "backups";
+
+/// The file name prefix and suffix of backups.
+const BACKUP_PREFIX: &str = "backup.";
+const BACKUP_SUFFIX: &str = ".cbor";
+
+/// Backup holds every spool at one point in time.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Backup {
+    pub version: u8,
+    /// Seconds since the unix epoch.
+    pub created_at: u64,
+    /// The encoded archive of every spool.
+    pub spools: Vec<ByteBuf>,
+    /// The SHA-256 digest of the spool archives and the base.
+    #[serde(with = "serde_bytes")]
+    pub digest: Vec<u8>,
+    /// The name of the backup this one builds on, empty for full
+    /// backups.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub base: String,
+}
+
+/// Hashes each archive along with its length, followed by the base
+/// of incremental backups.
+fn backup_digest(spools: &[ByteBuf], base: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    let mut len = [0u8; 8];
+    for spool in spools {
+        BigEndian::write_u64(&mut len, spool.len() as u64);
+        hasher.input(len);
+        hasher.input(spool);
+    }
+    if !base.is_empty() {
+        BigEndian::write_u64(&mut len, base.len() as u64);
+        hasher.input(len);
+        hasher.input(base.as_bytes());
+    }
+    hasher.result().to_vec()
+}
+
+/// Returns true if `name` could be the file name of a backup, and
+/// not a path leading out of the backup directory.
+fn is_backup_name(name: &str) -> bool {
+    name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_SUFFIX) && !name.contains('/')
+}
+
+impl Backup {
+    /// Makes a backup of `spools`, which is incremental if `base`
+    /// is given.
+    pub fn new(spools: &[SpoolArchive], created_at: u64, base: Option<&str>) -> Result<Backup, ArchiveError> {
+        let mut encoded = Vec::with_capacity(spools.len());
+        for spool in spools {
+            encoded.push(ByteBuf::from(spool.to_bytes()?));
+        }
+        let base = base.unwrap_or_default().to_string();
+        Ok(Backup {
+            version: BACKUP_FORMAT_VERSION,
+            created_at: created_at,
+            digest: backup_digest(&encoded, &base),
+            spools: encoded,
+            base: base,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ArchiveError> {
+        Ok(serde_cbor::to_vec(self)?)
+    }
+
+    /// Decodes a backup, checking its version and digest.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Backup, ArchiveError> {
+        let backup: Backup = serde_cbor::from_slice(bytes)?;
+        if backup.version != BACKUP_FORMAT_VERSION {
+            return Err(ArchiveError::UnsupportedVersion(backup.version))
+        }
+        if backup_digest(&backup.spools, &backup.base) != backup.digest {
+            return Err(ArchiveError::DigestMismatch)
+        }
+        Ok(backup)
+    }
+
+    /// Decodes every spool archive, refusing backups holding a spool
+    /// twice.
+    pub fn spools(&self) -> Result<Vec<SpoolArchive>, ArchiveError> {
+        let mut spool_ids = HashSet::new();
+        let mut spools = Vec::with_capacity(self.spools.len());
+        for encoded in &self.spools {
+            let spool = SpoolArchive::from_bytes(encoded)?;
+            if !spool_ids.insert(spool.spool_id()?) {
+                return Err(ArchiveError::InvalidArchive)
+            }
+            spools.push(spool);
+        }
+        Ok(spools)
+    }
+
+    /// Writes the backup to a new file in `dir`, which is created if
+    /// need be, returning the file's name. Only the owner may read
+    /// backups.
+    pub fn write(&self, dir: &Path) -> Result<String, ArchiveError> {
+        fs::create_dir_all(dir)?;
+        let bytes = self.to_bytes()?;
+        let mut attempt = 0;
+        loop {
+            let name = format!("{}{}.{}{}", BACKUP_PREFIX, self.created_at, attempt, BACKUP_SUFFIX);
+            let result = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(dir.join(&name));
+            match result {
+                Ok(mut file) => {
+                    file.write_all(&bytes)?;
+                    file.sync_all()?;
+                    return Ok(name)
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+                Err(e) => return Err(ArchiveError::from(e)),
+            }
+        }
+    }
+
+    /// Reads and checks the backup called `name` in `dir`.
+    pub fn read(dir: &Path, name: &str) -> Result<Backup, ArchiveError> {
+        if !is_backup_name(name) {
+            return Err(ArchiveError::InvalidBackupName)
+        }
+        let mut bytes = vec![];
+        fs::File::open(dir.join(name))?.read_to_end(&mut bytes)?;
+        Backup::from_bytes(&bytes)
+    }
+}
+
+/// Reads the backup called `name` in `dir` and, if it is incremental,
+/// every backup it builds on, returning the spools as of `name`.
+pub fn read_chain(dir: &Path, name: &str) -> Result<Vec<SpoolArchive>, ArchiveError> {
+    let mut chain = vec![];
+    let mut names = HashSet::new();
+    let mut next = name.to_string();
+    loop {
+        if !names.insert(next.clone()) {
+            return Err(ArchiveError::BrokenChain)
+        }
+        let backup = Backup::read(dir, &next)?;
+        next = backup.base.clone();
+        chain.push(backup);
+        if next.is_empty() {
+            break
+        }
+    }
+    let mut spools: Vec<SpoolArchive> = vec![];
+    for backup in chain.iter().rev() {
+        let mut previous: HashMap<Vec<u8>, SpoolArchive> = spools.into_iter()
+            .map(|x| (x.spool_id.clone(), x))
+            .collect();
+        spools = vec![];
+        for spool in backup.spools()? {
+            if spool.first_message == 0 {
+                spools.push(spool);
+                continue
+            }
+            let mut base = previous.remove(&spool.spool_id).ok_or(ArchiveError::BrokenChain)?;
+            base.apply(spool)?;
+            spools.push(base);
+        }
+    }
+    Ok(spools)
+}
+
+/// Returns the names of the backups in `dir`, oldest first.
+pub fn list(dir: &Path) -> Result<Vec<String>, ArchiveError> {
+    if !dir.exists() {
+        return Ok(vec![])
+    }
+    let mut backups = vec![];
+    for entry in fs::read_dir(dir)? {
+        if let Ok(name) = entry?.file_name().into_string() {
+            if is_backup_name(&name) {
+                backups.push(name);
+            }
+        }
+    }
+    backups.sort_by_key(|x| {
+        let stem = &x[BACKUP_PREFIX.len()..x.len() - BACKUP_SUFFIX.len()];
+        let mut parts = stem.splitn(2, '.').map(|x| x.parse::<u64>().unwrap_or(0));
+        (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+    });
+    Ok(backups)
+}
+
+/// Returns the backup directory within `base_dir`.
+pub fn default_backup_dir(base_dir: &str) -> PathBuf {
+    Path::new(base_dir).join(DEFAULT_BACKUP_DIR)
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempdir;
+    use super::*;
+
+    #[test]
+    fn backup_file_test() {
+        let dir = tempdir().unwrap();
+        let backup_dir = dir.path().join(DEFAULT_BACKUP_DIR);
+        assert!(list(&backup_dir).unwrap().is_empty());
+        let backup = Backup::new(&[], 7, None).unwrap();
+        assert_eq!(backup.write(&backup_dir).unwrap(), "backup.7.0.cbor");
+        assert_eq!(backup.write(&backup_dir).unwrap(), "backup.7.1.cbor");
+        assert_eq!(Backup::new(&[], 10, Some("backup.7.1.cbor")).unwrap().write(&backup_dir).unwrap(), "backup.10.0.cbor");
+        assert_eq!(list(&backup_dir).unwrap(), vec!["backup.7.0.cbor", "backup.7.1.cbor", "backup.10.0.cbor"]);
+        assert_eq!(Backup::read(&backup_dir, "backup.7.1.cbor").unwrap(), backup);
+        assert!(Backup::read(&backup_dir, "../backup.7.1.cbor").is_err());
+        assert_eq!(Backup::read(&backup_dir, "backup.10.0.cbor").unwrap().base, "backup.7.1.cbor");
+        assert!(read_chain(&backup_dir, "backup.10.0.cbor").unwrap().is_empty());
+
+        // Tampering is caught before anything is decoded.
+        let mut tampered = backup.clone();
+        tampered.spools.push(ByteBuf::from(vec![0u8]));
+        match Backup::from_bytes(&tampered.to_bytes().unwrap()) {
+            Err(ArchiveError::DigestMismatch) => {},
+            _ => panic!("expected a digest mismatch"),
+        }
+        tampered = backup.clone();
+        tampered.base = String::from("backup.1.0.cbor");
+        assert!(Backup::from_bytes(&tampered.to_bytes().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::process;
+use clap::{Arg, App, ArgMatches, SubCommand};
+use hyper::{header, Body, Client, Method, Request};
+use hyperlocal::UnixConnector;
+
+use multispool::admin::{self, AdminResponse};
+use multispool::archive::SpoolArchive;
+use multispool::boltdb::{self, BoltDb};
+use multispool::config::DEFAULT_IDENTITY_KEY_FILE;
+use multispool::keys;
+use multispool::spool::{Durability, MultiSpool};
+
+use crate::common::{load_keyring, master_key_paths, shard_dirs};
+
+
+/// Sends an admin request to a running spool service.
+fn request_online(socket_path: &str, token: Option<&str>, method: Method, path: &str) -> Result<AdminResponse, String> {
+    let client = Client::builder().build::<_, Body>(UnixConnector);
+    let uri: hyper::Uri = hyperlocal::Uri::new(socket_path, path).into();
+    let mut request = Request::builder().method(method).uri(uri);
+    if let Some(token) = token {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    let request = request.body(Body::empty()).map_err(|e| format!("{}", e))?;
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| format!("{}", e))?;
+    let (status, body) = runtime.block_on(async {
+        let response = client.request(request).await?;
+        let status = response.status().as_u16();
+        hyper::body::to_bytes(response.into_body()).await.map(|body| (status, body))
+    }).map_err(|e| format!("{}", e))?;
+    let body = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+    Ok(AdminResponse {
+        status: status,
+        body: body,
+    })
+}
+
+/// Opens the spools in the data directory. The spool service must
+/// not be running.
+fn open_offline(data_dir: &str, shard_dirs: &[String], master_key_paths: &[&str]) -> Result<MultiSpool, String> {
+    let multi_spool = MultiSpool::with_shards(&String::from(data_dir), shard_dirs, Durability::default()).map_err(|e| format!("{}", e))?;
+    if let Some(keyring) = load_keyring(master_key_paths)? {
+        multi_spool.set_keyring(Some(keyring)).map_err(|e| format!("{}", e))?;
+    }
+    Ok(multi_spool)
+}
+
+/// Answers an admin request directly from the data directory.
+fn request_offline(data_dir: &str,
+                   shard_dirs: &[String],
+                   master_key_paths: &[&str],
+                   backup_dir: Option<&str>,
+                   method: Method,
+                   path: &str)
+                   -> Result<AdminResponse, String> {
+    let mut multi_spool = open_offline(data_dir, shard_dirs, master_key_paths)?;
+    if let Some(backup_dir) = backup_dir {
+        multi_spool.set_backup_dir(backup_dir);
+    }
+    let response = admin::handle(method.as_str(), path, &multi_spool);
+    multi_spool.close().map_err(|e| format!("{}", e))?;
+    Ok(response)
+}
+
+/// Writes a spool's archive to `archive_path`, which must not exist
+/// yet and is only readable by its owner.
+fn export(multi_spool: &MultiSpool, spool_id: &str, archive_path: &Path) -> Result<AdminResponse, String> {
+    let spool_id = admin::decode_spool_id(spool_id).ok_or_else(|| String::from("invalid spool ID"))?;
+    let archive = multi_spool.export_spool(spool_id).map_err(|e| format!("{}", e))?;
+    let bytes = archive.to_bytes().map_err(|e| format!("{}", e))?;
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(archive_path)
+        .map_err(|e| format!("{}", e))?;
+    file.write_all(&bytes).and_then(|_| file.sync_all()).map_err(|e| format!("{}", e))?;
+    Ok(AdminResponse {
+        status: 200,
+        body: json!({
+            "spool_id": admin::encode_spool_id(&spool_id),
+            "message_count": archive.messages.len(),
+        }),
+    })
+}
+
+/// Imports every spool in the Go memspool's boltdb file at
+/// `bolt_path`, keeping spool and message IDs.
+fn migrate_from_boltdb(multi_spool: &mut MultiSpool, bolt_path: &Path) -> Result<AdminResponse, String> {
+    let db = BoltDb::open(bolt_path).map_err(|e| format!("{}", e))?;
+    let exported_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0);
+    let archives = boltdb::memspool_archives(&db, exported_at).map_err(|e| format!("{}", e))?;
+    let mut spools = vec![];
+    let mut failures = vec![];
+    for archive in &archives {
+        let encoded_id = archive.spool_id().map(|x| admin::encode_spool_id(&x)).unwrap_or_default();
+        match multi_spool.import_spool(archive) {
+            Ok(_) => spools.push(json!({
+                "spool_id": encoded_id,
+                "message_count": archive.messages.len(),
+            })),
+            Err(e) => failures.push(json!({
+                "spool_id": encoded_id,
+                "error": format!("{}", e),
+            })),
+        }
+    }
+    Ok(AdminResponse {
+        status: if failures.is_empty() { 200 } else { 500 },
+        body: json!({
+            "migrated": spools,
+            "failures": failures,
+        }),
+    })
+}
+
+/// Recreates a spool from the archive at `archive_path`.
+fn import(multi_spool: &mut MultiSpool, archive_path: &Path) -> Result<AdminResponse, String> {
+    let bytes = fs::read(archive_path).map_err(|e| format!("{}", e))?;
+    let archive = SpoolArchive::from_bytes(&bytes).map_err(|e| format!("{}", e))?;
+    let spool_id = multi_spool.import_spool(&archive).map_err(|e| format!("{}", e))?;
+    Ok(AdminResponse {
+        status: 200,
+        body: json!({
+            "spool_id": admin::encode_spool_id(&spool_id),
+            "message_count": archive.messages.len(),
+        }),
+    })
+}
+
+/// Shows the service identity public key, generating a new keypair
+/// first if `rotate` is set. The service must be restarted to pick
+/// up a rotated key.
+fn identity(key_path: &Path, rotate: bool) -> Result<AdminResponse, String> {
+    let body = if rotate {
+        let old = keys::load_keypair(key_path).map_err(|e| format!("{}", e))?;
+        let new = keys::rotate_keypair(key_path).map_err(|e| format!("{}", e))?;
+        json!({
+            "identity_key": keys::encode_public_key(&new.public),
+            "old_identity_key": keys::encode_public_key(&old.public),
+        })
+    } else {
+        let keypair = keys::load_keypair(key_path).map_err(|e| format!("{}", e))?;
+        json!({ "identity_key": keys::encode_public_key(&keypair.public) })
+    };
+    Ok(AdminResponse {
+        status: 200,
+        body: body,
+    })
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("admin")
+        .about("Administers a multispool service via its admin API or its data directory.")
+        .arg(Arg::with_name("admin_socket_path")
+             .short("s")
+             .long("admin_socket_path")
+             .value_name("FILE")
+             .help("Talks to the admin API on this unix socket.")
+             .takes_value(true))
+        .arg(Arg::with_name("admin_token")
+             .short("t")
+             .long("admin_token")
+             .value_name("TOKEN")
+             .help("Sends this admin bearer token.")
+             .takes_value(true))
+        .arg(Arg::with_name("data_dir")
+             .short("d")
+             .long("data_dir")
+             .value_name("DIR")
+             .help("Opens this data directory directly. The service must be stopped.")
+             .conflicts_with("admin_socket_path")
+             .takes_value(true))
+        .arg(Arg::with_name("shard_dir")
+             .long("shard_dir")
+             .value_name("DIR")
+             .help("With --data_dir, a shard directory the spools are sharded across. May be repeated.")
+             .requires("data_dir")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true))
+        .arg(Arg::with_name("master_key")
+             .short("k")
+             .long("master_key")
+             .value_name("FILE")
+             .help("With --data_dir, a master key file, current key first. May be repeated.")
+             .requires("data_dir")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true))
+        .arg(Arg::with_name("backup_dir")
+             .short("b")
+             .long("backup_dir")
+             .value_name("DIR")
+             .help("With --data_dir, the backup directory. Defaults to backups in --data_dir.")
+             .requires("data_dir")
+             .takes_value(true))
+        .subcommand(SubCommand::with_name("list")
+                    .about("Lists every spool."))
+        .subcommand(SubCommand::with_name("inspect")
+                    .about("Describes a spool.")
+                    .arg(Arg::with_name("spool_id").required(true)))
+        .subcommand(SubCommand::with_name("purge")
+                    .about("Purges a spool without its owner's signature.")
+                    .arg(Arg::with_name("spool_id").required(true)))
+        .subcommand(SubCommand::with_name("stats")
+                    .about("Summarizes all spools."))
+        .subcommand(SubCommand::with_name("verify")
+                    .about("Checks that every spool can be read."))
+        .subcommand(SubCommand::with_name("reencrypt")
+                    .about("Re-encrypts everything with the current master key."))
+        .subcommand(SubCommand::with_name("audit")
+                    .about("Shows the latest audit log entries, or those following entry AFTER.")
+                    .arg(Arg::with_name("after")))
+        .subcommand(SubCommand::with_name("backup")
+                    .about("Writes a backup of every spool to the backup directory.")
+                    .arg(Arg::with_name("incremental")
+                         .long("incremental")
+                         .help("Only backs up the messages appended since the last backup.")))
+        .subcommand(SubCommand::with_name("backups")
+                    .about("Lists the backups in the backup directory."))
+        .subcommand(SubCommand::with_name("restore")
+                    .about("Replaces every spool with those in the backup NAME.")
+                    .arg(Arg::with_name("name").required(true)))
+        .subcommand(SubCommand::with_name("orphans")
+                    .about("Lists the spool databases which belong to no spool."))
+        .subcommand(SubCommand::with_name("gc")
+                    .about("Removes the spool databases which belong to no spool."))
+        .subcommand(SubCommand::with_name("role")
+                    .about("Shows the replication role and epoch."))
+        .subcommand(SubCommand::with_name("promote")
+                    .about("Makes a standby the primary."))
+        .subcommand(SubCommand::with_name("export")
+                    .about("Writes a spool to a portable archive. Needs --data_dir.")
+                    .arg(Arg::with_name("spool_id").required(true))
+                    .arg(Arg::with_name("archive_file").required(true)))
+        .subcommand(SubCommand::with_name("import")
+                    .about("Recreates a spool from an archive under its old spool ID. Needs --data_dir.")
+                    .arg(Arg::with_name("archive_file").required(true)))
+        .subcommand(SubCommand::with_name("migrate-from-boltdb")
+                    .about("Imports every spool from a Go memspool boltdb file. Needs --data_dir.")
+                    .arg(Arg::with_name("path").required(true)))
+        .subcommand(SubCommand::with_name("identity")
+                    .about("Shows, or rotates, the service identity public key.")
+                    .arg(Arg::with_name("key_file")
+                         .short("f")
+                         .long("key_file")
+                         .value_name("FILE")
+                         .help("The identity keypair file. Defaults to identity.key in --data_dir.")
+                         .takes_value(true))
+                    .arg(Arg::with_name("rotate")
+                         .long("rotate")
+                         .help("Replaces the keypair, keeping the old one with an .old suffix.")))
+}
+
+/// Runs an admin subcommand.
+pub fn run(matches: &ArgMatches) {
+    if let ("identity", Some(sub)) = matches.subcommand() {
+        let key_path = match (sub.value_of("key_file"), matches.value_of("data_dir")) {
+            (Some(key_file), _) => Path::new(key_file).to_path_buf(),
+            (None, Some(data_dir)) => Path::new(data_dir).join(DEFAULT_IDENTITY_KEY_FILE),
+            (None, None) => {
+                eprintln!("either --key_file or --data_dir is required");
+                process::exit(2)
+            },
+        };
+        print_response(identity(&key_path, sub.is_present("rotate")));
+        return
+    }
+
+    if let (name @ "export", Some(sub)) | (name @ "import", Some(sub)) | (name @ "migrate-from-boltdb", Some(sub)) = matches.subcommand() {
+        let data_dir = match matches.value_of("data_dir") {
+            Some(x) => x,
+            None => {
+                eprintln!("{} requires --data_dir", name);
+                process::exit(2)
+            },
+        };
+        if !Path::new(data_dir).is_dir() {
+            eprintln!("data_dir must exist and be a directory");
+            process::exit(2)
+        }
+        let master_key_paths = master_key_paths(matches);
+        let result = open_offline(data_dir, &shard_dirs(matches), &master_key_paths).and_then(|mut multi_spool| {
+            let result = match name {
+                "export" => export(&multi_spool, sub.value_of("spool_id").unwrap(), Path::new(sub.value_of("archive_file").unwrap())),
+                "import" => import(&mut multi_spool, Path::new(sub.value_of("archive_file").unwrap())),
+                _ => migrate_from_boltdb(&mut multi_spool, Path::new(sub.value_of("path").unwrap())),
+            };
+            multi_spool.close().map_err(|e| format!("{}", e))?;
+            result
+        });
+        print_response(result);
+        return
+    }
+
+    let (method, path) = match matches.subcommand() {
+        ("list", _) => (Method::GET, String::from("/spools")),
+        ("inspect", Some(sub)) => (Method::GET, format!("/spools/{}", sub.value_of("spool_id").unwrap())),
+        ("purge", Some(sub)) => (Method::DELETE, format!("/spools/{}", sub.value_of("spool_id").unwrap())),
+        ("stats", _) => (Method::GET, String::from("/stats")),
+        ("verify", _) => (Method::GET, String::from("/verify")),
+        ("reencrypt", _) => (Method::POST, String::from("/reencrypt")),
+        ("backup", Some(sub)) if sub.is_present("incremental") => (Method::POST, String::from("/backup/incremental")),
+        ("backup", _) => (Method::POST, String::from("/backup")),
+        ("backups", _) => (Method::GET, String::from("/backups")),
+        ("restore", Some(sub)) => (Method::POST, format!("/restore/{}", sub.value_of("name").unwrap())),
+        ("orphans", _) => (Method::GET, String::from("/orphans")),
+        ("gc", _) => (Method::POST, String::from("/gc")),
+        ("role", _) => (Method::GET, String::from("/role")),
+        ("promote", _) => (Method::POST, String::from("/promote")),
+        ("audit", Some(sub)) => match sub.value_of("after") {
+            Some(after) => (Method::GET, format!("/audit/{}", after)),
+            None => (Method::GET, String::from("/audit")),
+        },
+        _ => {
+            eprintln!("{}", matches.usage());
+            process::exit(2)
+        },
+    };
+
+    let result = match (matches.value_of("admin_socket_path"), matches.value_of("data_dir")) {
+        (Some(socket_path), None) => request_online(socket_path, matches.value_of("admin_token"), method, &path),
+        (None, Some(data_dir)) => {
+            if !Path::new(data_dir).is_dir() {
+                eprintln!("data_dir must exist and be a directory");
+                process::exit(2)
+            }
+            let master_key_paths = master_key_paths(matches);
+            request_offline(data_dir, &shard_dirs(matches), &master_key_paths, matches.value_of("backup_dir"), method, &path)
+        },
+        _ => {
+            eprintln!("either --admin_socket_path or --data_dir is required");
+            process::exit(2)
+        },
+    };
+    print_response(result);
+}
+
+fn print_response(result: Result<AdminResponse, String>) {
+    match result {
+        Ok(response) => {
+            println!("{}", serde_json::to_string_pretty(&response.body).unwrap());
+            if response.status != 200 {
+                process::exit(1)
+            }
+        },
+        Err(e) => {
+            eprintln!("FAILED: {}", e);
+            process::exit(1)
+        },
+    }
+}
@@ -0,0 +1,533 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
+use byteorder::{ByteOrder, BigEndian};
+use clap::{Arg, App, ArgMatches, SubCommand};
+use ed25519_dalek::{Keypair, PublicKey};
+use hyper::{Body, Client, Method, Request as HttpRequest};
+use hyperlocal::UnixConnector;
+use serde_bytes::ByteBuf;
+
+use multispool::admin::{decode_spool_id, encode_spool_id};
+use multispool::keys;
+use multispool::merkle;
+use multispool::options::SpoolOptions;
+use multispool::tokens::{BlindedToken, TokenKey};
+use multispool::client::{SpoolRequestBuilder, SpoolReply, encode_request, decode_response, parse_response, verify_response};
+use multispool::spool::{MESSAGE_ID_SIZE, SPOOL_ID_SIZE};
+use multispool::{SpoolResponse,
+                 CREATE_SPOOL_COMMAND, PURGE_SPOOL_COMMAND,
+                 APPEND_MESSAGE_COMMAND, RETRIEVE_MESSAGE_COMMAND, SET_APPEND_TOKEN_KEY_COMMAND,
+                 UNDELETE_SPOOL_COMMAND, SET_APPENDERS_COMMAND, SPOOL_STATUS_COMMAND, COPY_SPOOL_COMMAND,
+                 REKEY_SPOOL_COMMAND, RETRIEVE_BY_HASH_COMMAND, RETRIEVE_LATEST_COMMAND};
+
+
+/// Loads the keypair from `path`, generating and saving a new one
+/// if the file does not exist.
+fn load_or_generate_keypair(path: &str) -> Result<Keypair, String> {
+    let exists = Path::new(path).exists();
+    let keypair = keys::load_or_generate_keypair(path).map_err(|e| format!("{}", e))?;
+    if !exists {
+        eprintln!("generated a new keypair in {}", path);
+    }
+    Ok(keypair)
+}
+
+/// Loads the append token key from `path`, generating and saving a
+/// new one if the file does not exist.
+fn load_or_generate_token_key(path: &str) -> Result<TokenKey, String> {
+    if Path::new(path).exists() {
+        let raw = keys::read_key_file(path).map_err(|e| format!("{}", e))?;
+        return TokenKey::from_bytes(&raw).map_err(|e| format!("{}", e))
+    }
+    let key = TokenKey::generate();
+    keys::write_key_file(path, &key.to_bytes()).map_err(|e| format!("{}", e))?;
+    eprintln!("generated a new token key in {}", path);
+    Ok(key)
+}
+
+/// Issues an append token for the spool with the owner's token key.
+/// A correspondent would make the blinded token and unblind the
+/// answer itself, so that the owner can't recognize the token.
+fn issue_token(matches: &ArgMatches) -> Result<Vec<u8>, String> {
+    let spool_id = spool_id_arg(matches)?;
+    let key = load_or_generate_token_key(matches.value_of("token_key").unwrap())?;
+    let request = BlindedToken::new(&spool_id);
+    let signed = key.sign_blinded(&request.blinded()).map_err(|e| format!("{}", e))?;
+    request.unblind(&signed).map_err(|e| format!("{}", e))
+}
+
+/// Decodes the service identity key, as published in its
+/// "identity_key" parameter.
+fn server_key_arg(matches: &ArgMatches) -> Result<Option<PublicKey>, String> {
+    match matches.value_of("server_key") {
+        Some(encoded) => {
+            let raw = base64::decode(encoded).map_err(|e| format!("{}", e))?;
+            PublicKey::from_bytes(&raw).map(Some).map_err(|e| format!("{}", e))
+        },
+        None => Ok(None),
+    }
+}
+
+/// Sends a spool request payload to the spool service listening on
+/// `socket_path`.
+fn send(socket_path: &str, request_id: u64, payload: Vec<u8>) -> Result<SpoolResponse, String> {
+    let body = encode_request(request_id, payload).map_err(|e| format!("{}", e))?;
+    let client = Client::builder().build::<_, Body>(UnixConnector);
+    let uri: hyper::Uri = hyperlocal::Uri::new(socket_path, "/request").into();
+    let http_request = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .body(Body::from(body))
+        .map_err(|e| format!("{}", e))?;
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| format!("{}", e))?;
+    let raw_response = runtime.block_on(async {
+        let response = client.request(http_request).await?;
+        hyper::body::to_bytes(response.into_body()).await
+    }).map_err(|e| format!("{}", e))?;
+    decode_response(&raw_response).map_err(|e| format!("{}", e))
+}
+
+fn spool_id_arg(matches: &ArgMatches) -> Result<[u8; SPOOL_ID_SIZE], String> {
+    let encoded = matches.value_of("spool_id").unwrap();
+    decode_spool_id(encoded).ok_or_else(|| format!("invalid spool id {}", encoded))
+}
+
+/// Builds the spool request payload for the given subcommand,
+/// returning it along with the command and message ID.
+fn build_request(keypair: &Keypair, matches: &ArgMatches) -> Result<(u8, Vec<u8>, Vec<u8>), String> {
+    let mut message_id_field = vec![];
+    let (command, builder) = match matches.subcommand() {
+        ("create", Some(sub)) => {
+            let mut options = SpoolOptions::default();
+            if let Some(capacity) = sub.value_of("capacity") {
+                options.Capacity = capacity.parse::<u32>().map_err(|e| format!("{}", e))?;
+            }
+            options.Circular = sub.is_present("circular");
+            options.HashIndex = sub.is_present("hash_index");
+            if let Some(window) = sub.value_of("dedup_window") {
+                options.DedupWindow = window.parse::<u32>().map_err(|e| format!("{}", e))?;
+            }
+            if let Some(ttl) = sub.value_of("ttl") {
+                options.TTL = ttl.parse::<u64>().map_err(|e| format!("{}", e))?;
+            }
+            for encoded in sub.values_of("appender_key").into_iter().flatten() {
+                options.Appenders.push(ByteBuf::from(base64::decode(encoded).map_err(|e| format!("{}", e))?));
+            }
+            for encoded in sub.values_of("reader_key").into_iter().flatten() {
+                options.Readers.push(ByteBuf::from(base64::decode(encoded).map_err(|e| format!("{}", e))?));
+            }
+            let mut builder = SpoolRequestBuilder::new(CREATE_SPOOL_COMMAND).sign(keypair);
+            if options != SpoolOptions::default() {
+                builder = builder.options(options);
+            }
+            (CREATE_SPOOL_COMMAND, builder)
+        },
+        ("append", Some(sub)) => {
+            let mut message = vec![];
+            match sub.value_of("file") {
+                Some("-") | None => io::stdin().read_to_end(&mut message),
+                Some(path) => File::open(path).and_then(|mut x| x.read_to_end(&mut message)),
+            }.map_err(|e| format!("{}", e))?;
+            let mut builder = SpoolRequestBuilder::new(APPEND_MESSAGE_COMMAND)
+                .spool_id(spool_id_arg(sub)?)
+                .message(&message);
+            if let Some(difficulty) = sub.value_of("pow_difficulty") {
+                builder = builder.proof_of_work(difficulty.parse::<u32>().map_err(|e| format!("{}", e))?);
+            }
+            if let Some(token) = sub.value_of("token") {
+                builder = builder.append_token(&base64::decode(token).map_err(|e| format!("{}", e))?);
+            }
+            if let Some(key) = sub.value_of("idempotency_key") {
+                builder = builder.idempotency_key(&base64::decode(key).map_err(|e| format!("{}", e))?);
+            }
+            if sub.is_present("durable") {
+                builder = builder.durable();
+            }
+            if sub.is_present("writer") {
+                builder = builder.appender(keypair);
+            }
+            (APPEND_MESSAGE_COMMAND, builder)
+        },
+        ("read", Some(sub)) => {
+            let message_id = sub.value_of("message_id").unwrap().parse::<u32>().map_err(|e| format!("{}", e))?;
+            message_id_field = vec![0u8; MESSAGE_ID_SIZE];
+            BigEndian::write_u32(&mut message_id_field, message_id);
+            let mut builder = SpoolRequestBuilder::new(RETRIEVE_MESSAGE_COMMAND)
+                .spool_id(spool_id_arg(sub)?)
+                .message_id(message_id)
+                .sign(keypair);
+            if matches.is_present("server_key") {
+                builder = builder.want_proof();
+            }
+            (RETRIEVE_MESSAGE_COMMAND, builder)
+        },
+        ("find", Some(sub)) => {
+            let mut hash = [0u8; merkle::HASH_SIZE];
+            match sub.value_of("hash") {
+                Some(encoded) => {
+                    let raw = base64::decode(encoded).map_err(|e| format!("{}", e))?;
+                    if raw.len() != merkle::HASH_SIZE {
+                        return Err(format!("invalid hash {}", encoded))
+                    }
+                    hash.copy_from_slice(&raw);
+                },
+                None => {
+                    let mut message = vec![];
+                    match sub.value_of("file") {
+                        Some("-") | None => io::stdin().read_to_end(&mut message),
+                        Some(path) => File::open(path).and_then(|mut x| x.read_to_end(&mut message)),
+                    }.map_err(|e| format!("{}", e))?;
+                    hash = merkle::leaf_hash(&message);
+                },
+            }
+            let mut builder = SpoolRequestBuilder::new(RETRIEVE_BY_HASH_COMMAND)
+                .spool_id(spool_id_arg(sub)?)
+                .message_hash(&hash)
+                .sign(keypair);
+            if matches.is_present("server_key") {
+                builder = builder.want_proof();
+            }
+            (RETRIEVE_BY_HASH_COMMAND, builder)
+        },
+        ("latest", Some(sub)) => {
+            let offset = match sub.value_of("offset") {
+                Some(offset) => offset.parse::<u32>().map_err(|e| format!("{}", e))?,
+                None => 0,
+            };
+            let mut builder = SpoolRequestBuilder::new(RETRIEVE_LATEST_COMMAND)
+                .spool_id(spool_id_arg(sub)?)
+                .offset(offset)
+                .sign(keypair);
+            if matches.is_present("server_key") {
+                builder = builder.want_proof();
+            }
+            (RETRIEVE_LATEST_COMMAND, builder)
+        },
+        ("token_key", Some(sub)) => {
+            let key = if sub.is_present("disable") {
+                None
+            } else {
+                Some(load_or_generate_token_key(sub.value_of("token_key").unwrap())?)
+            };
+            (SET_APPEND_TOKEN_KEY_COMMAND, SpoolRequestBuilder::new(SET_APPEND_TOKEN_KEY_COMMAND)
+             .spool_id(spool_id_arg(sub)?)
+             .token_key(key.as_ref())
+             .sign(keypair))
+        },
+        ("appenders", Some(sub)) => {
+            let mut writers = vec![];
+            for encoded in sub.values_of("writer_key").into_iter().flatten() {
+                let raw = base64::decode(encoded).map_err(|e| format!("{}", e))?;
+                writers.push(PublicKey::from_bytes(&raw).map_err(|e| format!("{}", e))?);
+            }
+            if writers.is_empty() && !sub.is_present("disable") {
+                return Err(String::from("either --writer_key or --disable must be given"))
+            }
+            (SET_APPENDERS_COMMAND, SpoolRequestBuilder::new(SET_APPENDERS_COMMAND)
+             .spool_id(spool_id_arg(sub)?)
+             .appenders(&writers)
+             .sign(keypair))
+        },
+        ("purge", Some(sub)) => (PURGE_SPOOL_COMMAND, SpoolRequestBuilder::new(PURGE_SPOOL_COMMAND)
+                                 .spool_id(spool_id_arg(sub)?)
+                                 .sign(keypair)),
+        ("undelete", Some(sub)) => (UNDELETE_SPOOL_COMMAND, SpoolRequestBuilder::new(UNDELETE_SPOOL_COMMAND)
+                                    .spool_id(spool_id_arg(sub)?)
+                                    .sign(keypair)),
+        ("status", Some(sub)) => (SPOOL_STATUS_COMMAND, SpoolRequestBuilder::new(SPOOL_STATUS_COMMAND)
+                                  .spool_id(spool_id_arg(sub)?)
+                                  .sign(keypair)),
+        ("copy", Some(sub)) => {
+            let mut builder = SpoolRequestBuilder::new(COPY_SPOOL_COMMAND)
+                .spool_id(spool_id_arg(sub)?)
+                .sign(keypair);
+            if let Some(encoded) = sub.value_of("owner_key") {
+                let raw = base64::decode(encoded).map_err(|e| format!("{}", e))?;
+                builder = builder.new_owner(&PublicKey::from_bytes(&raw).map_err(|e| format!("{}", e))?);
+            }
+            (COPY_SPOOL_COMMAND, builder)
+        },
+        ("rekey", Some(sub)) => {
+            let new_keypair = load_or_generate_keypair(sub.value_of("new_key").unwrap())?;
+            (REKEY_SPOOL_COMMAND, SpoolRequestBuilder::new(REKEY_SPOOL_COMMAND)
+             .spool_id(spool_id_arg(sub)?)
+             .new_keypair(&new_keypair)
+             .sign(keypair))
+        },
+        _ => return Err(String::from(matches.usage())),
+    };
+    let payload = builder.encode().map_err(|e| format!("{}", e))?;
+    Ok((command, message_id_field, payload))
+}
+
+/// Checks the proof of a read against the service identity key.
+fn verify_read(matches: &ArgMatches, server_key: &PublicKey, reply: SpoolReply) -> Result<SpoolReply, String> {
+    let (sub, message_id) = match (matches.subcommand(), &reply) {
+        (("read", Some(sub)), _) => (sub, sub.value_of("message_id").unwrap().parse::<u32>().map_err(|e| format!("{}", e))?),
+        (("find", Some(sub)), SpoolReply::Found(message_id, ..)) |
+        (("latest", Some(sub)), SpoolReply::Found(message_id, ..)) => (sub, *message_id),
+        _ => return Ok(reply),
+    };
+    let verified = match reply {
+        SpoolReply::Message(ref message, Some(ref proof), _, _) |
+        SpoolReply::Found(_, ref message, Some(ref proof), _, _) => proof.verify(&spool_id_arg(sub)?, message_id, message, server_key),
+        _ => false,
+    };
+    if !verified {
+        return Err(String::from("read proof verification failed"))
+    }
+    Ok(reply)
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+    let spool_id_arg = Arg::with_name("spool_id")
+        .short("i")
+        .long("spool_id")
+        .value_name("ID")
+        .help("The URL safe base64 encoded spool ID.")
+        .takes_value(true)
+        .required(true);
+    let token_key_arg = Arg::with_name("token_key")
+        .short("f")
+        .long("token_key")
+        .value_name("FILE")
+        .help("The append token key file, generated if it does not exist.")
+        .default_value("spool_token.key")
+        .takes_value(true);
+    SubCommand::with_name("client")
+        .about("Sends signed spool requests to a running multispool service.")
+        .arg(Arg::with_name("socket_path")
+             .short("s")
+             .long("socket_path")
+             .value_name("FILE")
+             .help("The spool service unix socket.")
+             .takes_value(true)
+             .required(true))
+        .arg(Arg::with_name("key")
+             .short("k")
+             .long("key")
+             .value_name("FILE")
+             .help("The ed25519 keypair file, generated if it does not exist.")
+             .default_value("spool_client.key")
+             .takes_value(true))
+        .arg(Arg::with_name("server_key")
+             .long("server_key")
+             .value_name("KEY")
+             .help("The base64 encoded service identity key to verify responses and read proofs against.")
+             .takes_value(true))
+        .subcommand(SubCommand::with_name("create")
+                    .about("Creates a spool owned by our key.")
+                    .arg(Arg::with_name("capacity")
+                         .long("capacity")
+                         .value_name("COUNT")
+                         .help("The most messages the spool keeps.")
+                         .takes_value(true))
+                    .arg(Arg::with_name("circular")
+                         .long("circular")
+                         .requires("capacity")
+                         .help("Drops the oldest message when a full spool is appended to, instead of refusing the append."))
+                    .arg(Arg::with_name("ttl")
+                         .long("ttl")
+                         .value_name("SECONDS")
+                         .help("Deletes the spool this many seconds after it is created.")
+                         .takes_value(true))
+                    .arg(Arg::with_name("hash_index")
+                         .long("hash_index")
+                         .help("Indexes the messages by hash, so that they can be found with the find subcommand."))
+                    .arg(Arg::with_name("dedup_window")
+                         .long("dedup_window")
+                         .value_name("COUNT")
+                         .help("Drops appends of a message equal to one of the last COUNT messages appended.")
+                         .takes_value(true))
+                    .arg(Arg::with_name("appender_key")
+                         .long("appender_key")
+                         .value_name("KEY")
+                         .help("A base64 encoded public key of a writer allowed to append.")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("reader_key")
+                         .long("reader_key")
+                         .value_name("KEY")
+                         .help("A base64 encoded public key of a reader allowed to read besides us.")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)))
+        .subcommand(SubCommand::with_name("append")
+                    .about("Appends a file, or stdin, to a spool.")
+                    .arg(spool_id_arg.clone())
+                    .arg(Arg::with_name("pow_difficulty")
+                         .short("w")
+                         .long("pow_difficulty")
+                         .value_name("BITS")
+                         .help("Attaches a proof of work, as the service's append_pow_difficulty parameter asks.")
+                         .takes_value(true))
+                    .arg(Arg::with_name("token")
+                         .short("t")
+                         .long("token")
+                         .value_name("TOKEN")
+                         .help("Spends this base64 encoded append token.")
+                         .takes_value(true))
+                    .arg(Arg::with_name("idempotency_key")
+                         .long("idempotency_key")
+                         .value_name("KEY")
+                         .help("Identifies the append by this base64 encoded key, reused when retrying it.")
+                         .takes_value(true))
+                    .arg(Arg::with_name("durable")
+                         .long("durable")
+                         .help("Asks for the message to be on disk before the append is acknowledged."))
+                    .arg(Arg::with_name("writer")
+                         .long("writer")
+                         .help("Signs the append with our key, as spools with an appender list need."))
+                    .arg(Arg::with_name("file")))
+        .subcommand(SubCommand::with_name("read")
+                    .about("Reads a message from a spool to stdout.")
+                    .arg(spool_id_arg.clone())
+                    .arg(Arg::with_name("time")
+                         .long("time")
+                         .help("Prints the unix time the message was appended at to stderr."))
+                    .arg(Arg::with_name("message_id").required(true)))
+        .subcommand(SubCommand::with_name("find")
+                    .about("Reads the latest message of a spool created with --hash_index equal to a file, or stdin, to stdout.")
+                    .arg(spool_id_arg.clone())
+                    .arg(Arg::with_name("hash")
+                         .long("hash")
+                         .value_name("HASH")
+                         .help("The base64 encoded Merkle leaf hash of the message, instead of the message itself.")
+                         .takes_value(true)
+                         .conflicts_with("file"))
+                    .arg(Arg::with_name("file")))
+        .subcommand(SubCommand::with_name("latest")
+                    .about("Reads the newest message of a spool, or one OFFSET messages before it, to stdout.")
+                    .arg(spool_id_arg.clone())
+                    .arg(Arg::with_name("offset")))
+        .subcommand(SubCommand::with_name("token_key")
+                    .about("Makes appends to a spool owned by our key spend tokens issued with our token key.")
+                    .arg(spool_id_arg.clone())
+                    .arg(token_key_arg.clone())
+                    .arg(Arg::with_name("disable")
+                         .long("disable")
+                         .help("Stops requiring append tokens.")))
+        .subcommand(SubCommand::with_name("token_issue")
+                    .about("Prints an append token for a spool, issued with our token key.")
+                    .arg(spool_id_arg.clone())
+                    .arg(token_key_arg))
+        .subcommand(SubCommand::with_name("appenders")
+                    .about("Restricts appends to a spool owned by our key to the given writers.")
+                    .arg(spool_id_arg.clone())
+                    .arg(Arg::with_name("writer_key")
+                         .short("w")
+                         .long("writer_key")
+                         .value_name("KEY")
+                         .help("A base64 encoded public key of a writer allowed to append.")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("disable")
+                         .long("disable")
+                         .conflicts_with("writer_key")
+                         .help("Lets anyone append again.")))
+        .subcommand(SubCommand::with_name("purge")
+                    .about("Purges a spool owned by our key.")
+                    .arg(spool_id_arg.clone()))
+        .subcommand(SubCommand::with_name("undelete")
+                    .about("Restores a spool purged within the service's grace period.")
+                    .arg(spool_id_arg.clone()))
+        .subcommand(SubCommand::with_name("status")
+                    .about("Shows how many messages a spool owned by our key holds.")
+                    .arg(spool_id_arg.clone()))
+        .subcommand(SubCommand::with_name("rekey")
+                    .about("Hands a spool owned by our key over to another key, keeping its ID.")
+                    .arg(spool_id_arg.clone())
+                    .arg(Arg::with_name("new_key")
+                         .short("n")
+                         .long("new_key")
+                         .value_name("FILE")
+                         .help("The keypair file of the new owner, generated if missing.")
+                         .required(true)
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("copy")
+                    .about("Copies a spool owned by our key into a new spool, printing its ID.")
+                    .arg(spool_id_arg)
+                    .arg(Arg::with_name("owner_key")
+                         .short("o")
+                         .long("owner_key")
+                         .value_name("KEY")
+                         .help("The base64 encoded public key to own the copy, ours if not given.")
+                         .takes_value(true)))
+}
+
+/// Runs a client subcommand.
+pub fn run(matches: &ArgMatches) {
+    if let ("token_issue", Some(sub)) = matches.subcommand() {
+        match issue_token(sub) {
+            Ok(token) => println!("{}", base64::encode(&token)),
+            Err(e) => {
+                eprintln!("FAILED: {}", e);
+                process::exit(1)
+            },
+        }
+        return
+    }
+
+    let result = load_or_generate_keypair(matches.value_of("key").unwrap())
+        .and_then(|keypair| build_request(&keypair, matches))
+        .and_then(|(command, message_id, payload)| {
+            let server_key = server_key_arg(matches)?;
+            let request_id = rand::random();
+            let spool_response = send(matches.value_of("socket_path").unwrap(), request_id, payload)?;
+            if let Some(ref server_key) = server_key {
+                verify_response(&spool_response, request_id, &message_id, server_key).map_err(|e| format!("{}", e))?;
+            }
+            let reply = parse_response(command, spool_response).map_err(|e| format!("{}", e))?;
+            match server_key {
+                Some(ref server_key) => verify_read(matches, server_key, reply),
+                None => Ok(reply),
+            }
+        });
+    match result {
+        Ok(SpoolReply::Created(spool_id)) => {
+            println!("{}", encode_spool_id(&spool_id));
+        },
+        Ok(SpoolReply::Existing(spool_id)) => {
+            eprintln!("our key already owns a spool");
+            println!("{}", encode_spool_id(&spool_id));
+        },
+        Ok(SpoolReply::Message(message, _, appended_at, remaining)) => {
+            if let ("read", Some(sub)) = matches.subcommand() {
+                if sub.is_present("time") {
+                    match appended_at {
+                        Some(appended_at) => eprintln!("appended at {}", appended_at),
+                        None => eprintln!("appended at an unknown time"),
+                    }
+                }
+            }
+            if remaining > 0 {
+                eprintln!("{} more messages after this one", remaining);
+            }
+            io::stdout().write_all(&message).unwrap();
+        },
+        Ok(SpoolReply::Found(message_id, message, _, _, remaining)) => {
+            eprintln!("message {}", message_id);
+            if remaining > 0 {
+                eprintln!("{} more messages after this one", remaining);
+            }
+            io::stdout().write_all(&message).unwrap();
+        },
+        Ok(SpoolReply::Copied(spool_id)) => {
+            println!("{}", encode_spool_id(&spool_id));
+        },
+        Ok(SpoolReply::Status(stats)) => {
+            println!("{}", serde_json::to_string_pretty(&stats).unwrap());
+        },
+        Ok(_) => {
+            eprintln!("OK");
+        },
+        Err(e) => {
+            eprintln!("FAILED: {}", e);
+            process::exit(1)
+        },
+    }
+}
@@ -0,0 +1,24 @@
+use std::sync::Arc;
+use clap::ArgMatches;
+
+use multispool::encryption::Keyring;
+
+
+/// Returns the shard directories given with --shard_dir.
+pub fn shard_dirs(matches: &ArgMatches) -> Vec<String> {
+    matches.values_of("shard_dir").map(|x| x.map(String::from).collect()).unwrap_or_default()
+}
+
+/// Returns the master key files given with --master_key, the current
+/// key first.
+pub fn master_key_paths<'a>(matches: &'a ArgMatches) -> Vec<&'a str> {
+    matches.values_of("master_key").map(|x| x.collect()).unwrap_or_default()
+}
+
+/// Loads the master keys from `master_key_paths`, if any are given.
+pub fn load_keyring(master_key_paths: &[&str]) -> Result<Option<Arc<Keyring>>, String> {
+    if master_key_paths.is_empty() {
+        return Ok(None)
+    }
+    Keyring::load(master_key_paths).map(|x| Some(Arc::new(x))).map_err(|e| format!("{}", e))
+}
@@ -0,0 +1,105 @@
+use std::path::Path;
+use std::process;
+use clap::{Arg, App, ArgMatches, SubCommand};
+
+use multispool::admin;
+use multispool::fsck::{self, Problem, Report};
+
+use crate::common::{load_keyring, master_key_paths, shard_dirs};
+
+
+/// Exit statuses, as fsck(8) has them.
+const EXIT_OK: i32 = 0;
+const EXIT_CORRECTED: i32 = 1;
+const EXIT_UNCORRECTED: i32 = 4;
+const EXIT_FAILED: i32 = 8;
+const EXIT_USAGE: i32 = 16;
+
+fn problems_json(problems: &[Problem]) -> serde_json::Value {
+    json!(problems.iter().map(|x| x.to_string()).collect::<Vec<String>>())
+}
+
+fn report_json(report: &Report) -> serde_json::Value {
+    let spools: Vec<serde_json::Value> = report.spools.iter().map(|spool| json!({
+        "spool_id": spool.spool_id.as_ref().map(admin::encode_spool_id),
+        "path": spool.path.to_string_lossy(),
+        "problems": problems_json(&spool.problems),
+        "outcome": spool.outcome.as_str(),
+    })).collect();
+    json!({
+        "spool_set": {
+            "problems": problems_json(&report.spool_set),
+            "outcome": report.spool_set_outcome.as_str(),
+        },
+        "spools_checked": report.spool_count,
+        "spools": spools,
+    })
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("fsck")
+        .about("Checks, and repairs, a multispool data directory. The spool service must be stopped.")
+        .arg(Arg::with_name("data_dir")
+             .short("d")
+             .long("data_dir")
+             .value_name("DIR")
+             .help("The data directory to check.")
+             .required(true)
+             .takes_value(true))
+        .arg(Arg::with_name("shard_dir")
+             .short("s")
+             .long("shard_dir")
+             .value_name("DIR")
+             .help("A shard directory the spools are sharded across along with the data directory. May be repeated.")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true))
+        .arg(Arg::with_name("master_key")
+             .short("k")
+             .long("master_key")
+             .value_name("FILE")
+             .help("A master key file, current key first, needed to check encrypted spools. May be repeated.")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true))
+        .arg(Arg::with_name("repair")
+             .long("repair")
+             .help("Repairs what can be repaired and quarantines spools which can't."))
+}
+
+/// Checks a data directory and exits with an fsck(8) exit status.
+pub fn run(matches: &ArgMatches) -> ! {
+    let data_dir = matches.value_of("data_dir").unwrap();
+    if !Path::new(data_dir).is_dir() {
+        eprintln!("data_dir must exist and be a directory");
+        process::exit(EXIT_USAGE)
+    }
+    let shard_dirs = shard_dirs(matches);
+    if shard_dirs.iter().any(|x| !Path::new(x).is_dir()) {
+        eprintln!("shard_dir must exist and be a directory");
+        process::exit(EXIT_USAGE)
+    }
+    let keyring = match load_keyring(&master_key_paths(matches)) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("FAILED: {}", e);
+            process::exit(EXIT_USAGE)
+        },
+    };
+
+    let report = match fsck::check_shards(data_dir, &shard_dirs, keyring, matches.is_present("repair")) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("FAILED: {}", e);
+            process::exit(EXIT_FAILED)
+        },
+    };
+    println!("{}", serde_json::to_string_pretty(&report_json(&report)).unwrap());
+    if report.is_damaged() {
+        process::exit(EXIT_UNCORRECTED)
+    }
+    if report.is_changed() {
+        process::exit(EXIT_CORRECTED)
+    }
+    process::exit(EXIT_OK)
+}
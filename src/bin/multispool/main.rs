@@ -0,0 +1,68 @@
+#[macro_use] extern crate log;
+#[macro_use] extern crate serde;
+#[macro_use] extern crate serde_cbor;
+#[macro_use] extern crate serde_json;
+
+extern crate serde_bytes;
+extern crate log4rs;
+extern crate clap;
+extern crate hyper;
+extern crate hyperlocal;
+extern crate rand;
+extern crate multispool;
+extern crate tokio;
+extern crate libc;
+extern crate log_mdc;
+extern crate ed25519_dalek;
+extern crate base64;
+extern crate byteorder;
+
+mod common;
+mod serve;
+mod admin;
+mod client;
+mod fsck;
+
+use std::env;
+use std::ffi::OsString;
+use clap::{App, AppSettings};
+
+
+/// The subcommands, serve first as the one run when none is given.
+const SUBCOMMANDS: &[&str] = &["serve", "admin", "client", "fsck", "help"];
+
+/// Returns the command line arguments, with the serve subcommand
+/// added if none is given. Katzenpost starts plugins with their
+/// configuration as flags and nothing else, so that a bare
+/// `multispool -l /path/to/logs` serves the spools.
+fn args() -> Vec<OsString> {
+    let mut args: Vec<OsString> = env::args_os().collect();
+    let given = match args.get(1).and_then(|x| x.to_str()) {
+        Some(x) => SUBCOMMANDS.contains(&x) || ["-h", "--help", "-V", "--version"].contains(&x),
+        None => false,
+    };
+    if !given {
+        args.insert(1, OsString::from(SUBCOMMANDS[0]));
+    }
+    args
+}
+
+fn main() {
+    let matches = App::new("Katzenpost MultiSpool")
+        .version("1.0")
+        .author("David Stainton <dawuud@riseup.net>")
+        .about("Serves, administers and talks to Katzenpost spools.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(serve::subcommand())
+        .subcommand(admin::subcommand())
+        .subcommand(client::subcommand())
+        .subcommand(fsck::subcommand())
+        .get_matches_from(args());
+    match matches.subcommand() {
+        ("serve", Some(sub)) => serve::run(sub.clone()),
+        ("admin", Some(sub)) => admin::run(sub),
+        ("client", Some(sub)) => client::run(sub),
+        ("fsck", Some(sub)) => fsck::run(sub),
+        _ => unreachable!("clap requires a subcommand"),
+    }
+}
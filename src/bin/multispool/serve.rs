@@ -0,0 +1,1082 @@
+use std::net::TcpListener as StdTcpListener;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::Path;
+use std::str;
+use std::{fs, io};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::future;
+use std::iter;
+use std::time::Duration;
+use std::process;
+use clap::{Arg, App, ArgMatches, SubCommand};
+use hyper::{header, Method, StatusCode};
+use hyper::service::service_fn;
+use hyper::server::conn::Http;
+use hyper::Body;
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+use serde_bytes::ByteBuf;
+use serde_cbor::from_slice;
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{self, Instant};
+
+use multispool::spool::{MultiSpool, SPOOL_ID_SIZE};
+use multispool::keys::load_or_generate_keypair;
+use multispool::config;
+use multispool::metrics;
+use multispool::trace;
+use multispool::admin;
+use multispool::logging::{Logger, LogOutput};
+use multispool::encryption::Keyring;
+use multispool::errors::{ConfigError, MultiSpoolError, ReplicationError};
+use multispool::replication::{ReplicaPosition, ReplicationBatch, ReplicationLog, Role, MAX_REPLICATION_BATCH};
+use multispool::plugin::{Handshake, HandshakeStyle};
+use multispool::privileges::{self, Credentials};
+use multispool::sandbox;
+use multispool::permissions;
+use multispool::activation::ListenFds;
+use multispool::service::{blocking, blocking_within, read_body, SpoolService};
+
+
+/// How often to check for in-flight requests while draining.
+const DRAIN_POLL_INTERVAL_MS: u64 = 50;
+
+/// How often to delete purged spools whose grace period is over, and
+/// spools whose TTL is over.
+const TOMBSTONE_SWEEP_INTERVAL_SECS: u64 = 60;
+
+/// How often to check whether logging the spool summary was turned
+/// on while it is off.
+const STATS_LOG_RECHECK_SECS: u64 = 60;
+
+/// How often to check whether periodic compaction was turned on while
+/// it is off.
+const COMPACTION_RECHECK_SECS: u64 = 60;
+
+/// How often to check for spool changes to send to an idle replica.
+const REPLICATION_POLL_INTERVAL_MS: u64 = 100;
+
+/// How long to wait before sending a batch a replica didn't take again.
+const REPLICATION_RETRY_MS: u64 = 1000;
+
+/// ServerState is the state shared by all connections.
+#[derive(Clone)]
+struct ServerState {
+    config: Arc<RwLock<config::Config>>,
+    logger: Arc<Logger>,
+    multi_spool: MultiSpool,
+    service: SpoolService,
+    replication: Option<Arc<ReplicationLog>>,
+    replication_keyring: Option<Arc<Keyring>>,
+    replica_position: Arc<Mutex<ReplicaPosition>>,
+}
+
+impl ServerState {
+    /// Locks the configuration for reading. A panic during a reload
+    /// leaves the configuration usable, so a poisoned lock is
+    /// recovered instead of failing every later request.
+    fn config(&self) -> RwLockReadGuard<'_, config::Config> {
+        self.config.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the configuration for a reload, see `config`.
+    fn config_mut(&self) -> RwLockWriteGuard<'_, config::Config> {
+        self.config.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Checks the connecting peer's credentials (SO_PEERCRED) against
+/// the configured user and group allow-lists. Unless told otherwise
+/// only our own user may connect.
+fn peer_allowed(stream: &UnixStream, cfg: &config::Config) -> bool {
+    match stream.peer_cred() {
+        Ok(cred) => {
+            if cfg.allowed_uids.is_empty() && cfg.allowed_gids.is_empty() {
+                if cred.uid() == unsafe { libc::getuid() } {
+                    return true
+                }
+            } else if cfg.allowed_uids.contains(&cred.uid()) || cfg.allowed_gids.contains(&cred.gid()) {
+                return true
+            }
+            info!("rejecting connection from uid {} gid {}", cred.uid(), cred.gid());
+            false
+        },
+        Err(e) => {
+            info!("FAILED to get peer credentials: {}", e);
+            false
+        },
+    }
+}
+
+/// Checks the admin peer's user id against the admin allow-list.
+/// Unless told otherwise only our own user may connect.
+fn admin_peer_allowed(stream: &UnixStream, cfg: &config::Config) -> bool {
+    match stream.peer_cred() {
+        Ok(cred) => {
+            if cfg.admin_uids.is_empty() && cred.uid() == unsafe { libc::getuid() } {
+                return true
+            }
+            if cfg.admin_uids.contains(&cred.uid()) {
+                return true
+            }
+            warn!("rejecting admin connection from uid {}", cred.uid());
+            false
+        },
+        Err(e) => {
+            info!("FAILED to get admin peer credentials: {}", e);
+            false
+        },
+    }
+}
+
+fn admin_token_matches(req: &hyper::Request<Body>, cfg: &config::Config) -> bool {
+    match cfg.admin_token {
+        Some(ref token) => {
+            let given = req.headers().get(header::AUTHORIZATION)
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.trim().splitn(2, ' ').nth(1))
+                .unwrap_or("");
+            admin::token_matches(token, given)
+        },
+        None => true,
+    }
+}
+
+async fn admin_handler(req: hyper::Request<Body>, state: ServerState) -> Result<hyper::Response<Body>, hyper::Error> {
+    let mut response = hyper::Response::new(Body::empty());
+    if !admin_token_matches(&req, &state.config()) {
+        warn!("rejecting admin request with a bad token");
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        return Ok(response)
+    }
+    info!("admin {} {}", req.method(), req.uri().path());
+    let method = req.method().as_str().to_string();
+    let path = req.uri().path().to_string();
+    let multi_spool = state.multi_spool.clone();
+    match admin::streamed_spool(&method, &path) {
+        Some(Ok(spool_id)) => return Ok(stream_messages(multi_spool, spool_id).await),
+        Some(Err(admin_response)) => return Ok(admin_json_response(admin_response)),
+        None => {},
+    }
+    let admin_response = blocking(move || {
+        admin::handle(&method, &path, &multi_spool)
+    }).await;
+    let admin_response = match admin_response {
+        Some(x) => x,
+        None => {
+            error!("FAILED to handle admin request");
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            return Ok(response)
+        },
+    };
+    Ok(admin_json_response(admin_response))
+}
+
+fn admin_json_response(admin_response: admin::AdminResponse) -> hyper::Response<Body> {
+    let mut response = hyper::Response::new(Body::from(admin_response.body.to_string()));
+    *response.status_mut() = StatusCode::from_u16(admin_response.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    response.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+    response
+}
+
+/// Reads a batch of the messages a spool keeps from `first_message`
+/// on, without holding any lock once it returns.
+async fn export_batch(multi_spool: MultiSpool,
+                      spool_id: [u8; SPOOL_ID_SIZE],
+                      first_message: u64)
+                      -> Result<Vec<(u32, Vec<u8>)>, admin::AdminResponse> {
+    let batch = blocking(move || multi_spool.export_messages(spool_id, first_message, admin::STREAM_BATCH_SIZE)).await;
+    match batch {
+        Some(result) => result.map_err(admin::AdminResponse::from),
+        None => Err(admin::AdminResponse {
+            status: 500,
+            body: json!({ "error": "failed to read the messages" }),
+        }),
+    }
+}
+
+/// Answers `GET /spools/<id>/messages` with the spool's messages as a
+/// chunked CBOR sequence, a message per chunk, reading the next batch
+/// only once the last one was taken by the client.
+async fn stream_messages(multi_spool: MultiSpool, spool_id: [u8; SPOOL_ID_SIZE]) -> hyper::Response<Body> {
+    let mut batch = match export_batch(multi_spool.clone(), spool_id, 0).await {
+        Ok(batch) => batch,
+        Err(admin_response) => return admin_json_response(admin_response),
+    };
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        while !batch.is_empty() {
+            let mut next_message = 0;
+            for (message_id, message) in batch {
+                let streamed = admin::StreamedMessage {
+                    message_id: message_id,
+                    message: ByteBuf::from(message),
+                };
+                let chunk = match streamed.to_bytes() {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        error!("FAILED to encode a streamed message: {}", e);
+                        sender.abort();
+                        return
+                    },
+                };
+                if sender.send_data(chunk.into()).await.is_err() {
+                    info!("admin client stopped reading streamed messages");
+                    return
+                }
+                next_message = u64::from(message_id) + 1;
+            }
+            batch = match export_batch(multi_spool.clone(), spool_id, next_message).await {
+                Ok(batch) => batch,
+                Err(admin_response) => {
+                    error!("FAILED to stream messages: {}", admin_response.body);
+                    sender.abort();
+                    return
+                },
+            };
+        }
+    });
+    let mut response = hyper::Response::new(body);
+    response.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static(admin::CBOR_SEQUENCE_CONTENT_TYPE));
+    response
+}
+
+/// Listeners are the sockets we serve on, bound before dropping
+/// privileges so that they may be in restricted places.
+struct Listeners {
+    requests: StdUnixListener,
+    requests_path: String,
+    admin: Option<StdUnixListener>,
+    replication: Option<StdTcpListener>,
+    /// The sockets we bound ourselves, and so remove on shutdown.
+    socket_paths: Vec<String>,
+}
+
+/// Binds the unix socket at `path`, unless systemd passed us one named
+/// `name`, see `activation`.
+fn bind_unix(activated: &mut ListenFds, name: &str, path: &str, socket_paths: &mut Vec<String>) -> io::Result<StdUnixListener> {
+    let listener = match activated.take(name) {
+        Some(fd) => unsafe { StdUnixListener::from_raw_fd(fd) },
+        None => {
+            let listener = StdUnixListener::bind(path)?;
+            socket_paths.push(path.to_string());
+            listener
+        },
+    };
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+impl Listeners {
+    /// Binds the spool service socket and, if configured, the admin
+    /// socket and the replication address, or takes them from
+    /// `activated`.
+    fn bind(cfg: &config::Config, socket_path: &str, activated: &mut ListenFds) -> io::Result<Listeners> {
+        let mut socket_paths = vec![];
+        let requests = bind_unix(activated, "requests", socket_path, &mut socket_paths)?;
+        let requests_path = requests.local_addr()?.as_pathname()
+            .map(|x| x.to_string_lossy().into_owned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the spool service socket has no path"))?;
+        let admin = match cfg.admin_socket_path {
+            Some(ref admin_socket_path) => Some(bind_unix(activated, "admin", admin_socket_path, &mut socket_paths)?),
+            None => None,
+        };
+        let replication = match cfg.replication_listen {
+            Some(ref address) => {
+                let replication = match activated.take("replication") {
+                    Some(fd) => unsafe { StdTcpListener::from_raw_fd(fd) },
+                    None => StdTcpListener::bind(address)?,
+                };
+                replication.set_nonblocking(true)?;
+                Some(replication)
+            },
+            None => None,
+        };
+        Ok(Listeners {
+            requests: requests,
+            requests_path: requests_path,
+            admin: admin,
+            replication: replication,
+            socket_paths: socket_paths,
+        })
+    }
+
+    /// Gives the socket files to `credentials`, so that the peers
+    /// allowed to connect to a service started as that user still can.
+    fn chown(&self, credentials: &Credentials) {
+        for socket_path in &self.socket_paths {
+            privileges::chown(socket_path, credentials).expect("failed to change the owner of a socket");
+        }
+    }
+}
+
+/// Serves spool requests from the peers allowed to connect until
+/// accepting a connection fails.
+async fn accept_requests(listener: UnixListener, state: ServerState) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("FAILED to accept connection: {}", e);
+                return
+            },
+        };
+        if !peer_allowed(&stream, &state.config()) {
+            continue
+        }
+        let service = state.service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                error!("connection error: {}", e);
+            }
+        });
+    }
+}
+
+/// Serves admin requests from the admin peers allowed to connect
+/// until accepting a connection fails.
+async fn accept_admin_requests(listener: UnixListener, state: ServerState) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("FAILED to accept admin connection: {}", e);
+                return
+            },
+        };
+        if !admin_peer_allowed(&stream, &state.config()) {
+            continue
+        }
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| admin_handler(req, state.clone()));
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                error!("admin connection error: {}", e);
+            }
+        });
+    }
+}
+
+/// Streams the replication log to the replica at `address`, sending
+/// each batch again until the replica acknowledges it. Stops once the
+/// replica has seen a newer primary, after fencing ourselves.
+async fn replicate_to(multi_spool: MultiSpool, log: Arc<ReplicationLog>, keyring: Arc<Keyring>, replica: usize, address: String) {
+    let client = hyper::Client::new();
+    let uri = format!("http://{}/replicate", address);
+    loop {
+        metrics::REPLICATION_BACKLOG.with_label_values(&[&address]).set(log.backlog(replica) as f64);
+        let batch = match log.pending(replica, MAX_REPLICATION_BATCH) {
+            Some(x) => x,
+            None => {
+                time::sleep(Duration::from_millis(REPLICATION_POLL_INTERVAL_MS)).await;
+                continue
+            },
+        };
+        let request = batch.seal(&keyring).map_err(|e| e.to_string())
+            .and_then(|sealed| hyper::Request::post(&uri).body(Body::from(sealed)).map_err(|e| e.to_string()));
+        let result = match request {
+            Ok(request) => client.request(request).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(ref response) if response.status().is_success() => {
+                log.acknowledge(replica, batch.next_sequence());
+                continue
+            },
+            Ok(ref response) if response.status() == StatusCode::PRECONDITION_FAILED => {
+                error!("replica {} follows a newer primary, fencing ourselves and refusing writes", address);
+                match blocking(move || multi_spool.fence()).await {
+                    Some(Ok(())) => {},
+                    Some(Err(e)) => error!("FAILED to fence: {}", e),
+                    None => error!("FAILED to fence"),
+                }
+                return
+            },
+            Ok(response) => warn!("replica {} refused changes {} on: {}", address, batch.sequence, response.status()),
+            Err(e) => warn!("FAILED to send changes {} on to replica {}: {}", batch.sequence, address, e),
+        }
+        time::sleep(Duration::from_millis(REPLICATION_RETRY_MS)).await;
+    }
+}
+
+/// Opens a batch of changes from our primary and applies those not
+/// yet applied, returning the status to answer with.
+fn apply_replication_batch(sealed: &[u8], state: &ServerState) -> StatusCode {
+    let keyring = match state.replication_keyring {
+        Some(ref x) => x,
+        None => return StatusCode::FORBIDDEN,
+    };
+    let batch = match ReplicationBatch::open(sealed, keyring) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("rejecting replication batch: {}", e);
+            return StatusCode::UNAUTHORIZED
+        },
+    };
+    let multi_spool = &state.multi_spool;
+    let _entered = multi_spool.enter();
+    // Tell a primary a standby was promoted over to fence itself.
+    match multi_spool.accept_epoch(batch.epoch) {
+        Ok(()) => {},
+        Err(MultiSpoolError::ReplicationError(e @ ReplicationError::StaleEpoch { .. })) => {
+            warn!("rejecting replication batch: {}", e);
+            return StatusCode::PRECONDITION_FAILED
+        },
+        Err(e) => {
+            warn!("rejecting replication batch: {}", e);
+            return StatusCode::CONFLICT
+        },
+    }
+    let mut position = state.replica_position.lock().unwrap_or_else(PoisonError::into_inner);
+    let events = match position.unapplied(&batch) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("FAILED to apply replication batch: {}", e);
+            return StatusCode::CONFLICT
+        },
+    };
+    for event in events {
+        if let Err(e) = multi_spool.apply_replicated(event) {
+            error!("FAILED to apply replicated change: {}", e);
+            return match e {
+                MultiSpoolError::ReplicationError(ReplicationError::MessageGap { .. }) => StatusCode::CONFLICT,
+                MultiSpoolError::ReplicationError(ReplicationError::InvalidEvent) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        }
+    }
+    position.advance(&batch);
+    StatusCode::OK
+}
+
+async fn replication_handler(req: hyper::Request<Body>, state: ServerState) -> Result<hyper::Response<Body>, hyper::Error> {
+    let mut response = hyper::Response::new(Body::empty());
+    if req.method() != Method::POST || req.uri().path() != "/replicate" {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response)
+    }
+    let (max_size, read_timeout, request_timeout) = {
+        let cfg = state.config();
+        (cfg.max_request_size().saturating_mul(MAX_REPLICATION_BATCH), cfg.read_timeout(), cfg.request_timeout())
+    };
+    let body = match read_body(req.into_body(), max_size, read_timeout).await? {
+        Ok(body) => body,
+        Err(status) => {
+            *response.status_mut() = status;
+            return Ok(response)
+        },
+    };
+    *response.status_mut() = match blocking_within(request_timeout, move || apply_replication_batch(&body, &state)).await {
+        Ok(status) => status.unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(_) => StatusCode::GATEWAY_TIMEOUT,
+    };
+    Ok(response)
+}
+
+/// Accepts changes from our primary until accepting a connection
+/// fails. Anyone may connect, but only batches sealed with the
+/// replication key are applied.
+async fn accept_replication(listener: TcpListener, state: ServerState) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("FAILED to accept replication connection: {}", e);
+                return
+            },
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| replication_handler(req, state.clone()));
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                error!("replication connection error: {}", e);
+            }
+        });
+    }
+}
+
+/// Resolves once the process receives SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let (mut sigterm, mut sigint) = match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+        (Ok(sigterm), Ok(sigint)) => (sigterm, sigint),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("FAILED to wait for signal: {}", e);
+            return
+        },
+    };
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = sigint.recv() => {},
+    }
+}
+
+/// Resolves once every replica is up to date or the drain timeout
+/// expires.
+async fn drain_replication(log: Arc<ReplicationLog>, timeout: Duration) {
+    let wait = async {
+        while !log.is_empty() {
+            time::sleep(Duration::from_millis(DRAIN_POLL_INTERVAL_MS)).await;
+        }
+    };
+    if time::timeout(timeout, wait).await.is_err() {
+        warn!("drain timeout expired before every replica was up to date, resync them from a backup");
+    }
+}
+
+/// Flushes and closes every spool and removes our unix sockets.
+fn shutdown(multi_spool: &MultiSpool, socket_paths: &[String]) {
+    if let Err(e) = multi_spool.clone().close() {
+        error!("FAILED to close spools: {}", e);
+    }
+    for socket_path in socket_paths {
+        if let Err(e) = fs::remove_file(socket_path) {
+            error!("FAILED to remove socket {}: {}", socket_path, e);
+        }
+    }
+}
+
+/// Builds the service configuration from the optional config file
+/// overridden by `MULTISPOOL_*` environment variables and then by any
+/// command line arguments.
+fn load_config(matches: &ArgMatches) -> Result<config::Config, ConfigError> {
+    let mut cfg = match matches.value_of("config") {
+        Some(path) => config::Config::load(path)?,
+        None => config::Config::default(),
+    };
+    cfg.apply_env()?;
+    if let Some(data_dir) = matches.value_of("data_dir") {
+        cfg.data_dir = Some(String::from(data_dir));
+    }
+    if let Some(log_dir) = matches.value_of("log_dir") {
+        cfg.log_dir = Some(String::from(log_dir));
+    }
+    if let Some(socket_path) = matches.value_of("socket_path") {
+        cfg.socket_path = Some(String::from(socket_path));
+    }
+    if let Some(admin_socket_path) = matches.value_of("admin_socket_path") {
+        cfg.admin_socket_path = Some(String::from(admin_socket_path));
+    }
+    if let Some(log_output) = matches.value_of("log_output") {
+        cfg.log_output = Some(String::from(log_output));
+    }
+    if let Some(otlp_endpoint) = matches.value_of("otlp_endpoint") {
+        cfg.otlp_endpoint = Some(String::from(otlp_endpoint));
+    }
+    if let Some(statsd_address) = matches.value_of("statsd_address") {
+        cfg.statsd_address = Some(String::from(statsd_address));
+    }
+    if let Some(log_level) = matches.value_of("log_level") {
+        cfg.log_level = Some(String::from(log_level));
+    }
+    if let Some(max_spools) = matches.value_of("max_spools") {
+        cfg.max_spools = Some(max_spools.parse::<u64>().expect("max_spools must be an integer"));
+    }
+    if matches.is_present("fix_permissions") {
+        cfg.fix_permissions = Some(true);
+    }
+    if let Some(user) = matches.value_of("user") {
+        cfg.user = Some(String::from(user));
+    }
+    if let Some(group) = matches.value_of("group") {
+        cfg.group = Some(String::from(group));
+    }
+    if let Some(worker_threads) = matches.value_of("worker_threads") {
+        cfg.worker_threads = Some(worker_threads.parse::<usize>().expect("worker_threads must be an integer"));
+    }
+    if let Some(drain_timeout_ms) = matches.value_of("drain_timeout_ms") {
+        cfg.drain_timeout_ms = Some(drain_timeout_ms.parse::<u64>().expect("drain_timeout_ms must be an integer"));
+    }
+    if let Some(values) = matches.values_of("allowed_uid") {
+        cfg.allowed_uids = values.map(|x| x.parse::<u32>().expect("allowed_uid must be an integer")).collect();
+    }
+    if let Some(values) = matches.values_of("allowed_gid") {
+        cfg.allowed_gids = values.map(|x| x.parse::<u32>().expect("allowed_gid must be an integer")).collect();
+    }
+    cfg.log_level_filter()?;
+    cfg.log_output()?;
+    cfg.syslog_facility()?;
+    cfg.check_rate_limits()?;
+    Ok(cfg)
+}
+
+/// Periodically logs a summary of every spool and the requests handled
+/// since startup, as often as the current configuration says.
+async fn log_stats(state: ServerState) {
+    loop {
+        let period = state.config().stats_log_period();
+        time::sleep(period.unwrap_or(Duration::from_secs(STATS_LOG_RECHECK_SECS))).await;
+        if period.is_none() {
+            continue
+        }
+        let multi_spool = state.multi_spool.clone();
+        match blocking(move || multi_spool.stats()).await {
+            Some(Ok(stats)) => {
+                let requests: Vec<String> = stats.commands.iter().map(|(command, count)| format!("{}={}", command, count)).collect();
+                info!("stats: {} spools, {} messages, {} bytes, requests {}",
+                      stats.spool_count, stats.message_count, stats.size_bytes, requests.join(" "));
+            },
+            Some(Err(e)) => error!("FAILED to summarize spools: {}", e),
+            None => {},
+        }
+    }
+}
+
+/// Periodically compacts every spool, as often as the current
+/// configuration says. Spools are compacted one at a time, so that
+/// only requests to the spool being compacted wait.
+async fn compact_spools(state: ServerState) {
+    loop {
+        let period = state.config().compaction_period();
+        time::sleep(period.unwrap_or(Duration::from_secs(COMPACTION_RECHECK_SECS))).await;
+        if period.is_none() {
+            continue
+        }
+        let multi_spool = state.multi_spool.clone();
+        let spool_ids = match blocking(move || multi_spool.spool_ids()).await {
+            Some(x) => x,
+            None => continue,
+        };
+        let mut reclaimed = 0;
+        for spool_id in spool_ids {
+            let multi_spool = state.multi_spool.clone();
+            match blocking(move || multi_spool.compact_spool(spool_id)).await {
+                Some(Ok(x)) => reclaimed += x,
+                Some(Err(MultiSpoolError::NoSuchSpool)) | None => {},
+                Some(Err(e)) => error!("FAILED to compact spool {}: {}", base64::encode(&spool_id), e),
+            }
+        }
+        info!("compacted spools, {} bytes reclaimed", reclaimed);
+    }
+}
+
+/// Periodically deletes purged spools whose grace period is over,
+/// and spools whose TTL is over, and purges abandoned spools.
+async fn sweep_tombstones(multi_spool: MultiSpool) {
+    let period = Duration::from_secs(TOMBSTONE_SWEEP_INTERVAL_SECS);
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        let tombstones = multi_spool.clone();
+        match blocking(move || tombstones.expire_tombstones()).await {
+            Some(Ok(0)) | None => {},
+            Some(Ok(count)) => info!("deleted {} purged spools", count),
+            Some(Err(e)) => error!("FAILED to delete purged spools: {}", e),
+        }
+        let expired = multi_spool.clone();
+        match blocking(move || expired.expire_spools()).await {
+            Some(Ok(0)) | None => {},
+            Some(Ok(count)) => info!("deleted {} spools whose TTL is over", count),
+            Some(Err(e)) => error!("FAILED to delete expired spools: {}", e),
+        }
+        let inactive = multi_spool.clone();
+        match blocking(move || inactive.purge_inactive_spools()).await {
+            Some(Ok(0)) | None => {},
+            Some(Ok(count)) => info!("purged {} inactive spools", count),
+            Some(Err(e)) => error!("FAILED to purge inactive spools: {}", e),
+        }
+    }
+}
+
+/// Applies the runtime tunables of the configuration to the spools,
+/// on startup and again on every reload.
+fn apply_tunables(multi_spool: &MultiSpool, cfg: &config::Config) {
+    multi_spool.set_compression_level(cfg.compression_level);
+    multi_spool.set_dedup_cache_size(cfg.dedup_cache_size());
+    let (max_failures, backoff) = cfg.signature_backoff();
+    multi_spool.set_signature_backoff(max_failures, backoff);
+    multi_spool.set_purge_grace_period(cfg.purge_grace_period());
+    multi_spool.set_inactive_period(cfg.inactive_period());
+    multi_spool.set_min_free_bytes(cfg.min_free_bytes());
+    multi_spool.set_flush_on_append(cfg.durability().flush_on_append);
+}
+
+/// Reloads the configuration, applying only the runtime tunables.
+fn reload_config(matches: &ArgMatches, state: &ServerState) {
+    info!("SIGHUP received, reloading configuration");
+    match load_config(matches) {
+        Ok(new_cfg) => {
+            let mut cfg = state.config_mut();
+            if new_cfg.data_dir != cfg.data_dir || new_cfg.shard_dirs != cfg.shard_dirs || new_cfg.log_dir != cfg.log_dir || new_cfg.socket_path != cfg.socket_path || new_cfg.admin_socket_path != cfg.admin_socket_path || new_cfg.max_message_size != cfg.max_message_size || new_cfg.identity_key_path != cfg.identity_key_path || new_cfg.backup_dir != cfg.backup_dir || new_cfg.flush_every_ms != cfg.flush_every_ms || new_cfg.snapshot_after_ops != cfg.snapshot_after_ops || new_cfg.group_commit_window_ms != cfg.group_commit_window_ms || new_cfg.replicas != cfg.replicas || new_cfg.replication_listen != cfg.replication_listen || new_cfg.replication_key_paths != cfg.replication_key_paths || new_cfg.max_replication_backlog != cfg.max_replication_backlog || new_cfg.standby != cfg.standby || new_cfg.max_in_flight != cfg.max_in_flight || new_cfg.max_queued_requests != cfg.max_queued_requests || new_cfg.worker_threads != cfg.worker_threads || new_cfg.blocking_threads != cfg.blocking_threads || new_cfg.user != cfg.user || new_cfg.group != cfg.group || new_cfg.sandbox != cfg.sandbox {
+                warn!("data_dir, shard_dirs, log_dir, socket_path, admin_socket_path, max_message_size, identity_key_path, backup_dir, flush_every_ms, snapshot_after_ops, group_commit_window_ms, max_in_flight, max_queued_requests, worker_threads, blocking_threads, user, group, sandbox and replication changes require a restart");
+            }
+            let keyring = match new_cfg.keyring() {
+                Ok(keyring) => keyring,
+                Err(e) => {
+                    error!("FAILED to load master keys, keeping the old ones: {}", e);
+                    return
+                },
+            };
+            cfg.apply_tunables(&new_cfg);
+            state.service.rate_limiter().set_limits(cfg.rate_limits.clone());
+            apply_tunables(&state.multi_spool, &cfg);
+            if let Err(e) = state.multi_spool.set_keyring(keyring.map(Arc::new)) {
+                error!("FAILED to set up spool keys: {}", e);
+            }
+            if let Ok(level) = cfg.log_level_filter() {
+                state.logger.set_level(level);
+            }
+        },
+        Err(e) => {
+            error!("FAILED to reload configuration: {}", e);
+        },
+    }
+}
+
+/// Reloads the configuration on every SIGHUP. Only the runtime
+/// tunables are applied, everything else requires a restart.
+async fn reload_on_sighup(matches: ArgMatches<'static>, state: ServerState) {
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("FAILED to wait for SIGHUP: {}", e);
+            return
+        },
+    };
+    while sighup.recv().await.is_some() {
+        let matches = matches.clone();
+        let state = state.clone();
+        blocking(move || reload_config(&matches, &state)).await;
+    }
+}
+
+/// Logs a diagnostic snapshot of the service on every SIGUSR1, for
+/// looking into a misbehaving service without stopping it.
+async fn dump_on_sigusr1(state: ServerState) {
+    let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("FAILED to wait for SIGUSR1: {}", e);
+            return
+        },
+    };
+    while sigusr1.recv().await.is_some() {
+        let in_flight = state.service.in_flight();
+        let multi_spool = state.multi_spool.clone();
+        let diagnostics = match blocking(move || multi_spool.diagnostics()).await {
+            Some(Ok(x)) => x,
+            Some(Err(e)) => {
+                error!("FAILED to take a diagnostic snapshot: {}", e);
+                continue
+            },
+            None => continue,
+        };
+        info!("diagnostics: {} requests in flight, {} open spools, {} pending flushes, {} dedup keys, {} throttled spools, {} tombstones, {} policies, {} owners",
+              in_flight, diagnostics.open_spools, diagnostics.pending_flushes, diagnostics.dedup_keys,
+              diagnostics.throttled_spools, diagnostics.tombstones, diagnostics.policies, diagnostics.owners);
+        for (spool_id, stats) in &diagnostics.spools {
+            info!("diagnostics: spool {}: {} messages from {} to {}, {} bytes, capacity {}, last append {:?}, last read {:?}",
+                  base64::encode(spool_id), stats.MessageCount, stats.FirstMessage, stats.Head, stats.SizeBytes,
+                  stats.Capacity, stats.LastAppend, stats.LastRead);
+        }
+    }
+}
+
+pub fn subcommand() -> App<'static, 'static> {
+    SubCommand::with_name("serve")
+        .about("Functions as a plugin to be executed by the Katzenpost server.")
+        .arg(Arg::with_name("transport")
+             .long("transport")
+             .value_name("TRANSPORT")
+             .help("Sets how requests are served. cbor-http, the Katzenpost CBOR plugin protocol over HTTP on a unix socket, is the only transport.")
+             .possible_values(&["cbor-http"])
+             .default_value("cbor-http")
+             .takes_value(true))
+        .arg(Arg::with_name("handshake")
+             .long("handshake")
+             .value_name("HANDSHAKE")
+             .help("Sets how we tell the host where to reach us: katzenpost prints the socket path, go-plugin the go-plugin handshake line.")
+             .possible_values(&["katzenpost", "go-plugin"])
+             .default_value("katzenpost")
+             .takes_value(true))
+        .arg(Arg::with_name("config")
+             .short("c")
+             .long("config")
+             .value_name("FILE")
+             .help("Sets the TOML configuration file.")
+             .takes_value(true))
+        .arg(Arg::with_name("data_dir")
+             .short("d")
+             .long("data_dir")
+             .value_name("DIR")
+             .help("Sets the data directory.")
+             .takes_value(true))
+        .arg(Arg::with_name("log_dir")
+             .short("l")
+             .long("log_dir")
+             .value_name("DIR")
+             .help("Sets the log directory.")
+             .takes_value(true))
+        .arg(Arg::with_name("socket_path")
+             .short("s")
+             .long("socket_path")
+             .value_name("PATH")
+             .help("Sets the unix socket path. Defaults to a random socket in /tmp.")
+             .takes_value(true))
+        .arg(Arg::with_name("log_output")
+             .long("log_output")
+             .value_name("OUTPUT")
+             .help("Sets where to log: file, stdout, stderr or syslog. Defaults to a file in log_dir.")
+             .takes_value(true))
+        .arg(Arg::with_name("log_level")
+             .long("log_level")
+             .value_name("LEVEL")
+             .help("Sets the log level: off, error, warn, info, debug or trace.")
+             .takes_value(true))
+        .arg(Arg::with_name("admin_socket_path")
+             .long("admin_socket_path")
+             .value_name("FILE")
+             .help("Serves the admin API on this unix socket.")
+             .takes_value(true))
+        .arg(Arg::with_name("otlp_endpoint")
+             .long("otlp_endpoint")
+             .value_name("URL")
+             .help("Exports request traces to this OTLP/HTTP collector, e.g. http://127.0.0.1:4318.")
+             .takes_value(true))
+        .arg(Arg::with_name("statsd_address")
+             .long("statsd_address")
+             .value_name("ADDRESS")
+             .help("Pushes metrics to this statsd server over UDP, e.g. 127.0.0.1:8125.")
+             .takes_value(true))
+        .arg(Arg::with_name("max_spools")
+             .long("max_spools")
+             .value_name("COUNT")
+             .help("Sets the maximum number of spools which may be created.")
+             .takes_value(true))
+        .arg(Arg::with_name("fix_permissions")
+             .long("fix_permissions")
+             .help("Takes group and world access away from the data and log directories instead of refusing to run."))
+        .arg(Arg::with_name("user")
+             .long("user")
+             .value_name("USER")
+             .help("Sets the user to run as once the sockets are bound.")
+             .takes_value(true))
+        .arg(Arg::with_name("group")
+             .long("group")
+             .value_name("GROUP")
+             .help("Sets the group to run as with --user, by default the user's primary group.")
+             .takes_value(true))
+        .arg(Arg::with_name("worker_threads")
+             .long("worker_threads")
+             .value_name("COUNT")
+             .help("Sets the number of threads serving connections, by default the number of CPUs.")
+             .takes_value(true))
+        .arg(Arg::with_name("drain_timeout_ms")
+             .long("drain_timeout_ms")
+             .value_name("MILLISECONDS")
+             .help("Sets how long to wait for in-flight requests on shutdown.")
+             .takes_value(true))
+        .arg(Arg::with_name("allowed_uid")
+             .long("allowed_uid")
+             .value_name("UID")
+             .help("Allows peers running as this user id to connect. May be repeated.")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true))
+        .arg(Arg::with_name("allowed_gid")
+             .long("allowed_gid")
+             .value_name("GID")
+             .help("Allows peers running as this group id to connect. May be repeated.")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true))
+}
+
+/// Runs the spool service until it is told to shut down.
+pub fn run(matches: ArgMatches<'static>) {
+    let style = matches.value_of("handshake").unwrap().parse::<HandshakeStyle>().expect("unknown handshake");
+    let handshake = match Handshake::from_env(style) {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1)
+        },
+    };
+    let cfg = load_config(&matches).expect("failed to load config file");
+    let log_output = cfg.log_output().unwrap();
+    let data_dir = cfg.data_dir.clone().expect("data_dir must be set");
+
+    // Ensure log_dir exists and is a directory when logging to a file.
+    if log_output == LogOutput::File {
+        match cfg.log_dir {
+            Some(ref log_dir) if Path::new(log_dir).is_dir() => {},
+            _ => panic!("log_dir must exist and be a directory"),
+        }
+    }
+
+    // Ensure data_dir exists and is a directory.
+    if !Path::new(&data_dir).is_dir() {
+        panic!("data_dir must exist and be a directory");
+    }
+
+    // Setup logging.
+    let logger = Logger::init(&cfg).expect("failed to start logging");
+
+    // Start our service.
+    let socket_path = match cfg.socket_path {
+        Some(ref path) => path.clone(),
+        None => {
+            let rand_string: String = thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(10)
+                .collect();
+            format!("/tmp/multispool_{}.sock", rand_string)
+        },
+    };
+    let mut activated = ListenFds::from_env().expect("failed to take the sockets passed by systemd");
+    let listeners = Listeners::bind(&cfg, &socket_path, &mut activated).expect("failed to bind the sockets");
+    for fd in activated.remaining() {
+        warn!("ignoring socket {} passed by systemd, which isn't configured", fd);
+    }
+
+    // Drop privileges before touching the spools or any request.
+    if let Some(ref user) = cfg.user {
+        let credentials = privileges::lookup(user, cfg.group.as_deref()).expect("failed to look up user");
+        listeners.chown(&credentials);
+        privileges::drop_to(&credentials).expect("failed to drop privileges");
+        info!("running as {}", privileges::current_user());
+    }
+
+    // Refuse to run with spools others could read, and keep whatever
+    // we create from now on private.
+    let fix_permissions = cfg.fix_permissions.unwrap_or(false);
+    for dir in iter::once(&data_dir).chain(cfg.shard_dirs.iter()).chain(cfg.backup_dir.iter()).chain(cfg.log_dir.iter()) {
+        if !Path::new(dir).exists() {
+            continue
+        }
+        match permissions::check_tree(dir, fix_permissions) {
+            Ok(0) => {},
+            Ok(fixed) => warn!("took group and world access away from {} paths in {}", fixed, dir),
+            Err(e) => panic!("refusing to run: {}, see fix_permissions", e),
+        }
+    }
+    permissions::restrict_umask();
+
+    // Confine ourselves before parsing anything from the network.
+    if cfg.sandbox.unwrap_or(false) {
+        match sandbox::apply(&cfg, matches.value_of("config")) {
+            Ok(true) => info!("sandboxed with landlock and seccomp"),
+            Ok(false) => warn!("landlock is not supported by this kernel, sandboxed with seccomp only"),
+            Err(e) => panic!("failed to set up the sandbox: {}", e),
+        }
+    }
+
+    // Setup tracing and statsd.
+    if let Some(ref endpoint) = cfg.otlp_endpoint {
+        trace::set_exporter(trace::OtlpExporter::start(endpoint));
+    }
+    if let Some(ref address) = cfg.statsd_address {
+        metrics::start_statsd(address).expect("failed to start pushing metrics to statsd");
+    }
+
+    for shard_dir in &cfg.shard_dirs {
+        if !Path::new(shard_dir).is_dir() {
+            panic!("shard_dirs must exist and be directories");
+        }
+    }
+    let multi_spool = MultiSpool::with_shards(&data_dir, &cfg.shard_dirs, cfg.durability()).unwrap();
+    multi_spool.set_max_message_size(cfg.max_message_size());
+    apply_tunables(&multi_spool, &cfg);
+    if let Some(ref backup_dir) = cfg.backup_dir {
+        multi_spool.set_backup_dir(backup_dir);
+    }
+    let keyring = cfg.keyring().expect("failed to load master keys");
+    multi_spool.set_keyring(keyring.map(Arc::new)).expect("failed to set up spool keys");
+    let identity_key_path = cfg.identity_key_path().unwrap();
+    let identity = Arc::new(load_or_generate_keypair(&identity_key_path).expect("failed to load identity key"));
+    multi_spool.set_identity(Some(identity.clone()));
+    let replication_keyring = cfg.replication_keyring().expect("failed to load replication keys").map(Arc::new);
+    if (!cfg.replicas.is_empty() || cfg.replication_listen.is_some()) && replication_keyring.is_none() {
+        panic!("replication_key_paths must be set for replication");
+    }
+    let replication = if cfg.replicas.is_empty() {
+        None
+    } else {
+        Some(Arc::new(ReplicationLog::new(cfg.replicas.len(), cfg.max_replication_backlog())))
+    };
+    multi_spool.set_replication_log(replication.clone());
+    if cfg.standby() {
+        if cfg.replication_listen.is_none() {
+            panic!("replication_listen must be set for a standby");
+        }
+        multi_spool.set_role(Role::Standby).expect("failed to become a standby");
+    }
+    info!("replication role {}, epoch {}", multi_spool.role().as_str(), multi_spool.epoch());
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.worker_threads(cfg.worker_threads()).enable_all();
+    if let Some(blocking_threads) = cfg.blocking_threads.filter(|x| *x > 0) {
+        runtime.max_blocking_threads(blocking_threads);
+    }
+    info!("serving with {} worker threads", cfg.worker_threads());
+    let config = Arc::new(RwLock::new(cfg));
+    let state = ServerState {
+        config: config.clone(),
+        logger: Arc::new(logger),
+        multi_spool: multi_spool.clone(),
+        service: SpoolService::new(config, multi_spool, identity),
+        replication: replication,
+        replication_keyring: replication_keyring,
+        replica_position: Arc::new(Mutex::new(ReplicaPosition::default())),
+    };
+    let runtime = runtime.build().expect("failed to start the runtime");
+    let handshake_line = handshake.line("unix", &listeners.requests_path, matches.value_of("transport").unwrap());
+    runtime.block_on(serve(matches, state, listeners, handshake_line));
+}
+
+/// Serves the spool service, and the admin API if configured, until
+/// we are told to shut down or accepting connections fails.
+async fn serve(matches: ArgMatches<'static>, state: ServerState, listeners: Listeners, handshake_line: String) {
+    let listener = UnixListener::from_std(listeners.requests).expect("failed to listen for requests");
+    let server = accept_requests(listener, state.clone());
+
+    // The admin API listens on its own socket, if configured.
+    let socket_paths = listeners.socket_paths;
+    let admin_listener = listeners.admin.map(|x| UnixListener::from_std(x).expect("failed to listen for admin requests"));
+    let admin_state = state.clone();
+    let admin_server = async move {
+        match admin_listener {
+            Some(admin_listener) => accept_admin_requests(admin_listener, admin_state).await,
+            None => future::pending().await,
+        }
+    };
+
+    // Replicas take changes from their primary on a TCP address.
+    let replication_listener = listeners.replication.map(|x| TcpListener::from_std(x).expect("failed to listen for replication"));
+    let replication_state = state.clone();
+    let replication_server = async move {
+        match replication_listener {
+            Some(replication_listener) => accept_replication(replication_listener, replication_state).await,
+            None => future::pending().await,
+        }
+    };
+    if let (Some(log), Some(keyring)) = (state.replication.clone(), state.replication_keyring.clone()) {
+        for (replica, address) in state.config().replicas.iter().enumerate() {
+            tokio::spawn(replicate_to(state.multi_spool.clone(), log.clone(), keyring.clone(), replica, address.clone()));
+        }
+    }
+    tokio::spawn(reload_on_sighup(matches, state.clone()));
+    tokio::spawn(sweep_tombstones(state.multi_spool.clone()));
+    tokio::spawn(log_stats(state.clone()));
+    tokio::spawn(compact_spools(state.clone()));
+    tokio::spawn(dump_on_sigusr1(state.clone()));
+    println!("{}", handshake_line);
+
+    // On shutdown we stop accepting connections, refuse new requests,
+    // give in-flight requests until the drain timeout to complete and
+    // then flush everything to disk.
+    tokio::select! {
+        _ = server => {},
+        _ = admin_server => {},
+        _ = replication_server => {},
+        _ = shutdown_signal() => {},
+    }
+    let drain_timeout_ms = state.config().drain_timeout_ms.unwrap_or(config::DEFAULT_DRAIN_TIMEOUT_MS);
+    state.service.drain(Duration::from_millis(drain_timeout_ms)).await;
+    if let Some(log) = state.replication.clone() {
+        drain_replication(log, Duration::from_millis(drain_timeout_ms)).await;
+    }
+    info!("shutting down");
+    shutdown(&state.multi_spool, &socket_paths);
+    process::exit(0)
+}
@@ -0,0 +1,483 @@
+// boltdb.rs - Reader for the Go memspool's boltdb files.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Migration from the Go memspool
+//!
+//! The Go implementation of the spool service keeps its spools in a
+//! boltdb file. This module reads just enough of the bolt on-disk
+//! format, from the most recent valid meta page down through branch,
+//! leaf and inline bucket pages, to turn every spool in such a file
+//! into a `SpoolArchive` which `MultiSpool::import_spool` can import.
+//! Files are only ever read.
+//!
+//! The Go memspool keeps a "spools" bucket holding one bucket per
+//! spool, named by spool ID, each with the owner's "public_key" and a
+//! "messages" bucket keyed by big endian message ID. Its message IDs
+//! start at one, so missing IDs, including zero, are filled in with
+//! empty messages to keep every message under its old ID, up to
+//! MAX_MISSING_MESSAGES of them per spool.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+use serde_bytes::ByteBuf;
+
+use crate::archive::{SpoolArchive, ARCHIVE_FORMAT_VERSION};
+use crate::errors::BoltError;
+use crate::spool::{MESSAGE_ID_SIZE, SPOOL_ID_SIZE};
+
+
+/// The magic number of bolt meta pages.
+const BOLT_MAGIC: u32 = 0xED0C_DAED;
+
+/// The bolt file format version we can read.
+const BOLT_VERSION: u32 = 2;
+
+const PAGE_HEADER_SIZE: usize = 16;
+const BRANCH_ELEMENT_SIZE: usize = 16;
+const LEAF_ELEMENT_SIZE: usize = 16;
+const BUCKET_HEADER_SIZE: usize = 16;
+const META_CHECKSUM_OFFSET: usize = 56;
+
+const BRANCH_PAGE_FLAG: u16 = 0x01;
+const LEAF_PAGE_FLAG: u16 = 0x02;
+const BUCKET_LEAF_FLAG: u32 = 0x01;
+
+/// How deep pages may nest. Pages are also never visited twice, so
+/// corrupt files with cyclic or shared page references can't make us
+/// recurse forever or walk the same pages over and over.
+const MAX_DEPTH: usize = 64;
+
+/// The most missing message IDs filled in with empty messages per
+/// spool, which keeps a forged message ID from making us allocate
+/// billions of them. Spools with bigger gaps are refused.
+pub const MAX_MISSING_MESSAGES: usize = 1 << 20;
+
+/// The Go memspool's bucket and key names.
+const MEMSPOOL_METADATA_BUCKET: &[u8] = b"metadata";
+const MEMSPOOL_VERSION_KEY: &[u8] = b"version";
+const MEMSPOOL_SPOOLS_BUCKET: &[u8] = b"spools";
+const MEMSPOOL_PUBLIC_KEY_KEY: &[u8] = b"public_key";
+const MEMSPOOL_MESSAGES_BUCKET: &[u8] = b"messages";
+
+/// The Go memspool storage version we can migrate from.
+const MEMSPOOL_VERSION: u8 = 0;
+
+/// BoltDb is a read-only boltdb file, held in memory.
+pub struct BoltDb {
+    data: Vec<u8>,
+    page_size: usize,
+    root: u64,
+}
+
+/// Entry is a key in a bucket and its value, which is a nested
+/// bucket's header if `is_bucket` is set.
+pub struct Entry<'a> {
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+    pub is_bucket: bool,
+}
+
+/// Bucket is a bucket in a BoltDb.
+pub struct Bucket<'a> {
+    db: &'a BoltDb,
+    root: u64,
+    /// The page of an inline bucket, stored in its parent's value.
+    inline: Option<&'a [u8]>,
+}
+
+/// The 64 bit FNV-1a hash bolt checksums meta pages with.
+fn fnv1a64(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in data {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Returns `len` bytes of `data` from `start`, or an error if they
+/// aren't all there.
+fn slice(data: &[u8], start: usize, len: usize) -> Result<&[u8], BoltError> {
+    match start.checked_add(len) {
+        Some(end) if end <= data.len() => Ok(&data[start..end]),
+        _ => Err(BoltError::Corrupt),
+    }
+}
+
+/// Parses the meta page at `offset`, returning its page size, root
+/// bucket page and transaction ID if it is valid.
+fn parse_meta(data: &[u8], offset: usize) -> Result<(usize, u64, u64), BoltError> {
+    let meta = slice(data, offset + PAGE_HEADER_SIZE, META_CHECKSUM_OFFSET + 8)?;
+    if LittleEndian::read_u32(&meta[0..4]) != BOLT_MAGIC {
+        return Err(BoltError::NotBoltDb)
+    }
+    let version = LittleEndian::read_u32(&meta[4..8]);
+    if version != BOLT_VERSION {
+        return Err(BoltError::UnsupportedVersion(version))
+    }
+    if fnv1a64(&meta[..META_CHECKSUM_OFFSET]) != LittleEndian::read_u64(&meta[META_CHECKSUM_OFFSET..]) {
+        return Err(BoltError::Corrupt)
+    }
+    let page_size = LittleEndian::read_u32(&meta[8..12]) as usize;
+    if page_size < PAGE_HEADER_SIZE + META_CHECKSUM_OFFSET + 8 {
+        return Err(BoltError::Corrupt)
+    }
+    Ok((page_size, LittleEndian::read_u64(&meta[16..24]), LittleEndian::read_u64(&meta[48..56])))
+}
+
+impl BoltDb {
+    /// Reads the boltdb file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<BoltDb, BoltError> {
+        BoltDb::from_bytes(fs::read(path)?)
+    }
+
+    /// Uses the newest of the two meta pages which is valid.
+    pub fn from_bytes(data: Vec<u8>) -> Result<BoltDb, BoltError> {
+        let first = parse_meta(&data, 0);
+        let page_size = match first {
+            Ok((page_size, _, _)) => page_size,
+            Err(BoltError::NotBoltDb) => return Err(BoltError::NotBoltDb),
+            Err(BoltError::UnsupportedVersion(x)) => return Err(BoltError::UnsupportedVersion(x)),
+            Err(_) => {
+                // The page size is still in the first meta page, even
+                // if its checksum is off.
+                let meta = slice(&data, PAGE_HEADER_SIZE, 12)?;
+                LittleEndian::read_u32(&meta[8..12]) as usize
+            },
+        };
+        let second = parse_meta(&data, page_size);
+        let (_, root, _) = match (first, second) {
+            (Ok(a), Ok(b)) => if a.2 >= b.2 { a } else { b },
+            (Ok(a), Err(_)) => a,
+            (Err(_), Ok(b)) => b,
+            (Err(e), Err(_)) => return Err(e),
+        };
+        Ok(BoltDb {
+            data: data,
+            page_size: page_size,
+            root: root,
+        })
+    }
+
+    /// Returns the root bucket, whose entries are the top level
+    /// buckets.
+    pub fn root<'a>(&'a self) -> Bucket<'a> {
+        Bucket {
+            db: self,
+            root: self.root,
+            inline: None,
+        }
+    }
+
+    /// Returns the page `pgid`, including its overflow pages.
+    fn page(&self, pgid: u64) -> Result<&[u8], BoltError> {
+        let start = (pgid as usize).checked_mul(self.page_size).ok_or(BoltError::Corrupt)?;
+        let header = slice(&self.data, start, PAGE_HEADER_SIZE)?;
+        let overflow = LittleEndian::read_u32(&header[12..16]) as usize;
+        let len = overflow.checked_add(1).and_then(|x| x.checked_mul(self.page_size)).ok_or(BoltError::Corrupt)?;
+        slice(&self.data, start, len)
+    }
+}
+
+impl<'a> Bucket<'a> {
+    /// Returns every entry in key order.
+    pub fn entries(&self) -> Result<Vec<Entry<'a>>, BoltError> {
+        let mut entries = vec![];
+        let mut visited = HashSet::new();
+        match self.inline {
+            Some(page) => self.walk(page, &mut entries, &mut visited, 0)?,
+            None => self.walk_page(self.root, &mut entries, &mut visited, 0)?,
+        }
+        Ok(entries)
+    }
+
+    /// Walks the page `pgid`, refusing pages already walked.
+    fn walk_page(&self, pgid: u64, entries: &mut Vec<Entry<'a>>, visited: &mut HashSet<u64>, depth: usize) -> Result<(), BoltError> {
+        if !visited.insert(pgid) {
+            return Err(BoltError::Corrupt)
+        }
+        self.walk(self.db.page(pgid)?, entries, visited, depth)
+    }
+
+    fn walk(&self, page: &'a [u8], entries: &mut Vec<Entry<'a>>, visited: &mut HashSet<u64>, depth: usize) -> Result<(), BoltError> {
+        if depth > MAX_DEPTH {
+            return Err(BoltError::Corrupt)
+        }
+        let header = slice(page, 0, PAGE_HEADER_SIZE)?;
+        let flags = LittleEndian::read_u16(&header[8..10]);
+        let count = LittleEndian::read_u16(&header[10..12]) as usize;
+        for i in 0..count {
+            if flags & BRANCH_PAGE_FLAG != 0 {
+                let element = slice(page, PAGE_HEADER_SIZE + i * BRANCH_ELEMENT_SIZE, BRANCH_ELEMENT_SIZE)?;
+                let pgid = LittleEndian::read_u64(&element[8..16]);
+                self.walk_page(pgid, entries, visited, depth + 1)?;
+            } else if flags & LEAF_PAGE_FLAG != 0 {
+                let offset = PAGE_HEADER_SIZE + i * LEAF_ELEMENT_SIZE;
+                let element = slice(page, offset, LEAF_ELEMENT_SIZE)?;
+                let element_flags = LittleEndian::read_u32(&element[0..4]);
+                let pos = LittleEndian::read_u32(&element[4..8]) as usize;
+                let key_size = LittleEndian::read_u32(&element[8..12]) as usize;
+                let value_size = LittleEndian::read_u32(&element[12..16]) as usize;
+                let key = slice(page, offset + pos, key_size)?;
+                let value = slice(page, offset + pos + key_size, value_size)?;
+                // Every entry takes a leaf element in the file, so a
+                // bucket can't have more of them than that.
+                if entries.len() >= self.db.data.len() / LEAF_ELEMENT_SIZE {
+                    return Err(BoltError::Corrupt)
+                }
+                entries.push(Entry {
+                    key: key,
+                    value: value,
+                    is_bucket: element_flags & BUCKET_LEAF_FLAG != 0,
+                });
+            } else {
+                return Err(BoltError::Corrupt)
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the value of a key which isn't a bucket.
+    pub fn get(&self, key: &[u8]) -> Result<Option<&'a [u8]>, BoltError> {
+        Ok(self.entries()?.into_iter().find(|x| !x.is_bucket && x.key == key).map(|x| x.value))
+    }
+
+    /// Returns the nested bucket called `name`.
+    pub fn bucket(&self, name: &[u8]) -> Result<Option<Bucket<'a>>, BoltError> {
+        match self.entries()?.into_iter().find(|x| x.is_bucket && x.key == name) {
+            Some(entry) => Ok(Some(self.nested(entry.value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Opens a nested bucket from its header.
+    fn nested(&self, header: &'a [u8]) -> Result<Bucket<'a>, BoltError> {
+        let root = LittleEndian::read_u64(slice(header, 0, BUCKET_HEADER_SIZE)?);
+        Ok(Bucket {
+            db: self.db,
+            root: root,
+            inline: if root == 0 { Some(&header[BUCKET_HEADER_SIZE..]) } else { None },
+        })
+    }
+}
+
+/// Reads every spool in a Go memspool database into an archive.
+pub fn memspool_archives(db: &BoltDb, exported_at: u64) -> Result<Vec<SpoolArchive>, BoltError> {
+    let root = db.root();
+    if let Some(metadata) = root.bucket(MEMSPOOL_METADATA_BUCKET)? {
+        match metadata.get(MEMSPOOL_VERSION_KEY)? {
+            Some(version) if version == [MEMSPOOL_VERSION] => {},
+            Some(version) => return Err(BoltError::UnsupportedMemspoolVersion(version.first().cloned().unwrap_or(0))),
+            None => {},
+        }
+    }
+    let spools = match root.bucket(MEMSPOOL_SPOOLS_BUCKET)? {
+        Some(x) => x,
+        None => return Err(BoltError::NotMemspool),
+    };
+    let mut archives = vec![];
+    for entry in spools.entries()? {
+        if !entry.is_bucket || entry.key.len() != SPOOL_ID_SIZE {
+            return Err(BoltError::NotMemspool)
+        }
+        let spool = spools.nested(entry.value)?;
+        let public_key = spool.get(MEMSPOOL_PUBLIC_KEY_KEY)?.ok_or(BoltError::NotMemspool)?;
+        let mut messages = vec![];
+        if let Some(bucket) = spool.bucket(MEMSPOOL_MESSAGES_BUCKET)? {
+            let entries = bucket.entries()?;
+            // Missing IDs are kept as empty messages, up to
+            // MAX_MISSING_MESSAGES of them.
+            let mut missing = 0;
+            for message in entries {
+                if message.is_bucket || message.key.len() != MESSAGE_ID_SIZE {
+                    return Err(BoltError::NotMemspool)
+                }
+                let message_id = BigEndian::read_u32(message.key) as usize;
+                if message_id < messages.len() {
+                    return Err(BoltError::NotMemspool)
+                }
+                missing += message_id - messages.len();
+                if missing > MAX_MISSING_MESSAGES {
+                    return Err(BoltError::MessageGap)
+                }
+                while messages.len() < message_id {
+                    messages.push(ByteBuf::from(vec![]));
+                }
+                messages.push(ByteBuf::from(message.value.to_vec()));
+            }
+        }
+        archives.push(SpoolArchive {
+            version: ARCHIVE_FORMAT_VERSION,
+            spool_id: entry.key.to_vec(),
+            public_key: public_key.to_vec(),
+            first_message: 0,
+            messages: messages,
+            appended_at: vec![],
+            append_token_key: vec![],
+            spent_tokens: vec![],
+            appenders: vec![],
+            policy: None,
+            exported_at: exported_at,
+            purged_at: None,
+        });
+    }
+    Ok(archives)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a leaf page holding `entries`.
+    fn leaf_page(page_size: usize, pgid: u64, entries: &[(&[u8], &[u8], bool)]) -> Vec<u8> {
+        let mut page = vec![0u8; PAGE_HEADER_SIZE + entries.len() * LEAF_ELEMENT_SIZE];
+        LittleEndian::write_u64(&mut page[0..8], pgid);
+        LittleEndian::write_u16(&mut page[8..10], LEAF_PAGE_FLAG);
+        LittleEndian::write_u16(&mut page[10..12], entries.len() as u16);
+        for (i, &(key, value, is_bucket)) in entries.iter().enumerate() {
+            let offset = PAGE_HEADER_SIZE + i * LEAF_ELEMENT_SIZE;
+            let pos = page.len() - offset;
+            LittleEndian::write_u32(&mut page[offset..offset + 4], if is_bucket { BUCKET_LEAF_FLAG } else { 0 });
+            LittleEndian::write_u32(&mut page[offset + 4..offset + 8], pos as u32);
+            LittleEndian::write_u32(&mut page[offset + 8..offset + 12], key.len() as u32);
+            LittleEndian::write_u32(&mut page[offset + 12..offset + 16], value.len() as u32);
+            page.extend_from_slice(key);
+            page.extend_from_slice(value);
+        }
+        if page_size > 0 {
+            page.resize(page_size, 0);
+        }
+        page
+    }
+
+    /// Builds the value of an inline bucket holding `entries`.
+    fn inline_bucket(entries: &[(&[u8], &[u8], bool)]) -> Vec<u8> {
+        let mut value = vec![0u8; BUCKET_HEADER_SIZE];
+        value.extend(leaf_page(0, 0, entries));
+        value
+    }
+
+    fn meta_page(page_size: usize, pgid: u64, root: u64, txid: u64) -> Vec<u8> {
+        let mut page = vec![0u8; page_size];
+        LittleEndian::write_u64(&mut page[0..8], pgid);
+        LittleEndian::write_u16(&mut page[8..10], 0x04);
+        {
+            let meta = &mut page[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + META_CHECKSUM_OFFSET + 8];
+            LittleEndian::write_u32(&mut meta[0..4], BOLT_MAGIC);
+            LittleEndian::write_u32(&mut meta[4..8], BOLT_VERSION);
+            LittleEndian::write_u32(&mut meta[8..12], page_size as u32);
+            LittleEndian::write_u64(&mut meta[16..24], root);
+            LittleEndian::write_u64(&mut meta[48..56], txid);
+            let checksum = fnv1a64(&meta[..META_CHECKSUM_OFFSET]);
+            LittleEndian::write_u64(&mut meta[META_CHECKSUM_OFFSET..], checksum);
+        }
+        page
+    }
+
+    #[test]
+    fn memspool_migration_test() {
+        let page_size = 4096;
+        let spool_id = [7u8; SPOOL_ID_SIZE];
+        let public_key = [9u8; 32];
+        let messages = inline_bucket(&[(&[0, 0, 0, 1], b"hello", false), (&[0, 0, 0, 2], b"world", false)]);
+        let spool = inline_bucket(&[(MEMSPOOL_MESSAGES_BUCKET, &messages, true), (MEMSPOOL_PUBLIC_KEY_KEY, &public_key, false)]);
+        let mut spools_header = vec![0u8; BUCKET_HEADER_SIZE];
+        LittleEndian::write_u64(&mut spools_header[0..8], 3);
+        let metadata = inline_bucket(&[(MEMSPOOL_VERSION_KEY, &[MEMSPOOL_VERSION], false)]);
+
+        // The second meta page is newer, and points at page 2.
+        let mut data = meta_page(page_size, 0, 4, 1);
+        data.extend(meta_page(page_size, 1, 2, 2));
+        data.extend(leaf_page(page_size, 2, &[(MEMSPOOL_METADATA_BUCKET, &metadata, true), (MEMSPOOL_SPOOLS_BUCKET, &spools_header, true)]));
+        data.extend(leaf_page(page_size, 3, &[(&spool_id, &spool, true)]));
+        let db = BoltDb::from_bytes(data.clone()).unwrap();
+        let archives = memspool_archives(&db, 1).unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(archives[0].spool_id, spool_id.to_vec());
+        assert_eq!(archives[0].public_key, public_key.to_vec());
+        assert_eq!(archives[0].messages, vec![ByteBuf::from(vec![]), ByteBuf::from(b"hello".to_vec()), ByteBuf::from(b"world".to_vec())]);
+
+        // Sparse message IDs are filled in, up to MAX_MISSING_MESSAGES.
+        let with_messages = |messages: &[(&[u8], &[u8], bool)]| {
+            let messages = inline_bucket(messages);
+            let spool = inline_bucket(&[(MEMSPOOL_MESSAGES_BUCKET, &messages, true), (MEMSPOOL_PUBLIC_KEY_KEY, &public_key, false)]);
+            let mut data = meta_page(page_size, 0, 2, 1);
+            data.extend(meta_page(page_size, 1, 2, 1));
+            data.extend(leaf_page(page_size, 2, &[(MEMSPOOL_METADATA_BUCKET, &metadata, true), (MEMSPOOL_SPOOLS_BUCKET, &spools_header, true)]));
+            data.extend(leaf_page(page_size, 3, &[(&spool_id, &spool, true)]));
+            memspool_archives(&BoltDb::from_bytes(data).unwrap(), 1)
+        };
+        let sparse = with_messages(&[(&[0, 0, 0, 0], b"hello", false), (&[0, 0, 0, 5], b"world", false)]).unwrap();
+        assert_eq!(sparse[0].messages.len(), 6);
+        assert_eq!(sparse[0].messages[5], ByteBuf::from(b"world".to_vec()));
+        match with_messages(&[(&[0, 0, 0, 1], b"hello", false), (&[0xff, 0xff, 0xff, 0xff], b"world", false)]) {
+            Err(BoltError::MessageGap) => {},
+            _ => panic!("expected a message gap"),
+        }
+
+        // A torn second meta page falls back to the first, whose root
+        // page is missing.
+        data[page_size + PAGE_HEADER_SIZE + 48] ^= 1;
+        assert!(memspool_archives(&BoltDb::from_bytes(data).unwrap(), 1).is_err());
+        match BoltDb::from_bytes(vec![0u8; 2 * page_size]) {
+            Err(BoltError::NotBoltDb) => {},
+            _ => panic!("expected not a boltdb file"),
+        }
+    }
+
+    /// Builds a branch page whose elements all point at `children`.
+    fn branch_page(page_size: usize, pgid: u64, children: &[u64]) -> Vec<u8> {
+        let mut page = vec![0u8; page_size];
+        LittleEndian::write_u64(&mut page[0..8], pgid);
+        LittleEndian::write_u16(&mut page[8..10], BRANCH_PAGE_FLAG);
+        LittleEndian::write_u16(&mut page[10..12], children.len() as u16);
+        for (i, child) in children.iter().enumerate() {
+            let offset = PAGE_HEADER_SIZE + i * BRANCH_ELEMENT_SIZE;
+            LittleEndian::write_u64(&mut page[offset + 8..offset + 16], *child);
+        }
+        page
+    }
+
+    #[test]
+    fn shared_pages_test() {
+        let page_size = 4096;
+        let mut data = meta_page(page_size, 0, 2, 1);
+        data.extend(meta_page(page_size, 1, 2, 1));
+
+        // Every branch page points twice at the next, which would
+        // take 2^64 page visits to walk.
+        for pgid in 2..66 {
+            data.extend(branch_page(page_size, pgid, &[pgid + 1, pgid + 1]));
+        }
+        data.extend(leaf_page(page_size, 66, &[(b"key", b"value", false)]));
+        match BoltDb::from_bytes(data).unwrap().root().entries() {
+            Err(BoltError::Corrupt) => {},
+            _ => panic!("expected a corrupt file"),
+        }
+
+        // A page pointing back at itself is refused too.
+        let mut data = meta_page(page_size, 0, 2, 1);
+        data.extend(meta_page(page_size, 1, 2, 1));
+        data.extend(branch_page(page_size, 2, &[2]));
+        match BoltDb::from_bytes(data).unwrap().root().entries() {
+            Err(BoltError::Corrupt) => {},
+            _ => panic!("expected a corrupt file"),
+        }
+    }
+}
@@ -0,0 +1,811 @@
+// client.rs - Multi-spool client request builders.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Client side of the spool protocol
+//!
+//! `SpoolRequestBuilder` builds, signs and encodes spool requests and
+//! `parse_response` turns a `SpoolResponse` into a typed `SpoolReply`:
+//!
+//! ```ignore
+//! let payload = SpoolRequestBuilder::new(CREATE_SPOOL_COMMAND)
+//!     .sign(&keypair)
+//!     .encode()?;
+//! let request = encode_request(request_id, payload)?;
+//! ```
+//!
+//! Clients which know the service identity key, published as its
+//! "identity_key" parameter, should check responses with
+//! `verify_response` before parsing them.
+//!
+//! Messages larger than a spool message can be split with `fragment`
+//! into consecutive spool entries and put back together by a
+//! `Reassembler` as they are read.
+
+use std::mem;
+use byteorder::{ByteOrder, BigEndian};
+use ed25519_dalek::{Keypair, PublicKey};
+use rand::{thread_rng, Rng};
+use serde_cbor;
+
+use crate::acl;
+use crate::dedup::{IDEMPOTENCY_KEY_SIZE, MAX_IDEMPOTENCY_KEY_SIZE};
+use crate::errors::ClientError;
+use crate::merkle::{self, ReadProof};
+use crate::options::SpoolOptions;
+use crate::pow;
+use crate::protobuf;
+use crate::rekey::Rekey;
+use crate::tokens::TokenKey;
+use crate::spool::{SpoolStats, MESSAGE_ID_SIZE, MESSAGE_SIZE, SPOOL_ID_SIZE};
+use crate::version::{BuildInfo, ProtocolVersion};
+use crate::{SpoolRequest, SpoolResponse, CREATE_SPOOL_COMMAND, PURGE_SPOOL_COMMAND,
+     APPEND_MESSAGE_COMMAND, RETRIEVE_MESSAGE_COMMAND, VERSION_COMMAND,
+     SET_APPEND_TOKEN_KEY_COMMAND, UNDELETE_SPOOL_COMMAND, SET_APPENDERS_COMMAND, SPOOL_STATUS_COMMAND, COPY_SPOOL_COMMAND, REKEY_SPOOL_COMMAND, RETRIEVE_BY_HASH_COMMAND, RETRIEVE_LATEST_COMMAND, RATE_LIMITED_STATUS,
+     BUSY_STATUS, UNSUPPORTED_VERSION_STATUS};
+
+
+/// The Katzenpost plugin request envelope.
+#[derive(Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct Request {
+    pub ID: u64,
+    #[serde(with = "serde_bytes")]
+    pub Payload: Vec<u8>,
+    pub HasSURB: bool,
+}
+
+/// The Katzenpost plugin response envelope.
+#[derive(Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct Response {
+    #[serde(with = "serde_bytes")]
+    pub Payload: Vec<u8>,
+}
+
+/// SpoolRequestBuilder builds spool requests, taking care of
+/// signing and field sizes.
+pub struct SpoolRequestBuilder {
+    command: u8,
+    spool_id: Option<[u8; SPOOL_ID_SIZE]>,
+    keypair_fields: Option<(Vec<u8>, Vec<u8>)>,
+    message_id: Option<u32>,
+    message: Option<Vec<u8>>,
+    max_message_size: usize,
+    want_proof: bool,
+    pow_difficulty: Option<u32>,
+    append_token: Option<Vec<u8>>,
+    idempotency_key: Option<Vec<u8>>,
+    durable: bool,
+    token_key: Option<Vec<u8>>,
+    appender: Option<Keypair>,
+    appenders: Option<Vec<u8>>,
+    new_owner: Option<PublicKey>,
+    new_keypair: Option<Keypair>,
+    hash: Option<merkle::Hash>,
+    offset: u32,
+    options: Option<SpoolOptions>,
+}
+
+impl SpoolRequestBuilder {
+    pub fn new(command: u8) -> SpoolRequestBuilder {
+        SpoolRequestBuilder {
+            command: command,
+            spool_id: None,
+            keypair_fields: None,
+            message_id: None,
+            message: None,
+            max_message_size: MESSAGE_SIZE,
+            want_proof: false,
+            pow_difficulty: None,
+            append_token: None,
+            idempotency_key: None,
+            durable: false,
+            token_key: None,
+            appender: None,
+            appenders: None,
+            new_owner: None,
+            new_keypair: None,
+            hash: None,
+            offset: 0,
+            options: None,
+        }
+    }
+
+    /// Sets the largest message the service accepts, as advertised in
+    /// its "message_size" parameter. Defaults to MESSAGE_SIZE.
+    pub fn max_message_size(mut self, max_message_size: usize) -> SpoolRequestBuilder {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Sets the spool the request is about.
+    pub fn spool_id(mut self, spool_id: [u8; SPOOL_ID_SIZE]) -> SpoolRequestBuilder {
+        self.spool_id = Some(spool_id);
+        self
+    }
+
+    /// Signs the request with the spool owner's keypair.
+    pub fn sign(mut self, keypair: &Keypair) -> SpoolRequestBuilder {
+        let signature = keypair.sign(&keypair.public.to_bytes());
+        self.keypair_fields = Some((signature.to_bytes().to_vec(), keypair.public.to_bytes().to_vec()));
+        self
+    }
+
+    /// Sets the ID of the message to read.
+    pub fn message_id(mut self, message_id: u32) -> SpoolRequestBuilder {
+        self.message_id = Some(message_id);
+        self
+    }
+
+    /// Asks the service to prove the message read is in the spool.
+    pub fn want_proof(mut self) -> SpoolRequestBuilder {
+        self.want_proof = true;
+        self
+    }
+
+    /// Attaches a proof of work of `difficulty` bits to an append, as
+    /// advertised in the "append_pow_difficulty" parameter.
+    pub fn proof_of_work(mut self, difficulty: u32) -> SpoolRequestBuilder {
+        self.pow_difficulty = Some(difficulty);
+        self
+    }
+
+    /// Spends an append token, needed to append to spools whose owner
+    /// has set a token key.
+    pub fn append_token(mut self, token: &[u8]) -> SpoolRequestBuilder {
+        self.append_token = Some(token.to_vec());
+        self
+    }
+
+    /// Sets a key identifying an append, so that the service ignores
+    /// retransmissions of it. Retries must reuse the key, which should
+    /// be random, see `random_idempotency_key`.
+    pub fn idempotency_key(mut self, key: &[u8]) -> SpoolRequestBuilder {
+        self.idempotency_key = Some(key.to_vec());
+        self
+    }
+
+    /// Asks the service to flush an append to disk before
+    /// acknowledging it.
+    pub fn durable(mut self) -> SpoolRequestBuilder {
+        self.durable = true;
+        self
+    }
+
+    /// Sets the key append tokens are issued with, or stops requiring
+    /// tokens if `key` is None.
+    pub fn token_key(mut self, key: Option<&TokenKey>) -> SpoolRequestBuilder {
+        self.token_key = Some(key.map(|x| x.to_bytes().to_vec()).unwrap_or_default());
+        self
+    }
+
+    /// Signs an append as a writer allowed to append to spools with an
+    /// appender list.
+    pub fn appender(mut self, keypair: &Keypair) -> SpoolRequestBuilder {
+        self.appender = Keypair::from_bytes(&keypair.to_bytes()).ok();
+        self
+    }
+
+    /// Sets the writers allowed to append, or lets anyone append if
+    /// `keys` is empty.
+    pub fn appenders(mut self, keys: &[PublicKey]) -> SpoolRequestBuilder {
+        self.appenders = Some(keys.iter().flat_map(|x| x.to_bytes().to_vec()).collect());
+        self
+    }
+
+    /// Makes a copy of the spool owned by `key` rather than by the
+    /// signer, e.g. a new device's key.
+    pub fn new_owner(mut self, key: &PublicKey) -> SpoolRequestBuilder {
+        self.new_owner = Some(*key);
+        self
+    }
+
+    /// Hands the spool over to `keypair`, which signs its agreement.
+    pub fn new_keypair(mut self, keypair: &Keypair) -> SpoolRequestBuilder {
+        self.new_keypair = Keypair::from_bytes(&keypair.to_bytes()).ok();
+        self
+    }
+
+    /// Sets the Merkle leaf hash of the message to read by hash, see
+    /// `merkle::leaf_hash`.
+    pub fn message_hash(mut self, hash: &merkle::Hash) -> SpoolRequestBuilder {
+        self.hash = Some(*hash);
+        self
+    }
+
+    /// Sets how many messages before the newest the message to read
+    /// with RETRIEVE_LATEST is, the newest if not set.
+    pub fn offset(mut self, offset: u32) -> SpoolRequestBuilder {
+        self.offset = offset;
+        self
+    }
+
+    /// Sets the options the spool is created with.
+    pub fn options(mut self, options: SpoolOptions) -> SpoolRequestBuilder {
+        self.options = Some(options);
+        self
+    }
+
+    /// Sets the message to append.
+    pub fn message(mut self, message: &[u8]) -> SpoolRequestBuilder {
+        self.message = Some(message.to_vec());
+        self
+    }
+
+    /// Checks that the fields the command needs are set and builds
+    /// the request.
+    pub fn build(self) -> Result<SpoolRequest, ClientError> {
+        let mut request = SpoolRequest {
+            Command: self.command,
+            Version: Some(ProtocolVersion::current()),
+            ..SpoolRequest::default()
+        };
+        let needs_spool_id = self.command != CREATE_SPOOL_COMMAND && self.command != VERSION_COMMAND;
+        let needs_signature = self.command != APPEND_MESSAGE_COMMAND && self.command != VERSION_COMMAND;
+        if needs_spool_id {
+            request.SpoolID = self.spool_id.ok_or(ClientError::MissingField("SpoolID"))?.to_vec();
+        }
+        if needs_signature {
+            let (signature, public_key) = self.keypair_fields.ok_or(ClientError::MissingField("Signature"))?;
+            request.Signature = signature;
+            request.PublicKey = public_key;
+        }
+        if self.command == RETRIEVE_MESSAGE_COMMAND {
+            let mut message_id = vec![0u8; MESSAGE_ID_SIZE];
+            BigEndian::write_u32(&mut message_id, self.message_id.ok_or(ClientError::MissingField("MessageID"))?);
+            request.MessageID = message_id;
+            request.WantProof = self.want_proof;
+        }
+        if self.command == RETRIEVE_BY_HASH_COMMAND {
+            request.Message = self.hash.ok_or(ClientError::MissingField("Message"))?.to_vec();
+            request.WantProof = self.want_proof;
+        }
+        if self.command == RETRIEVE_LATEST_COMMAND {
+            request.Message = vec![0u8; 4];
+            BigEndian::write_u32(&mut request.Message, self.offset);
+            request.WantProof = self.want_proof;
+        }
+        if self.command == APPEND_MESSAGE_COMMAND {
+            let message = self.message.ok_or(ClientError::MissingField("Message"))?;
+            if message.len() > self.max_message_size {
+                return Err(ClientError::MessageTooLarge(message.len()))
+            }
+            if let Some(difficulty) = self.pow_difficulty {
+                request.ProofOfWork = pow::solve(&request.SpoolID, &message, difficulty).to_vec();
+            }
+            request.AppendToken = self.append_token.unwrap_or_default();
+            request.IdempotencyKey = self.idempotency_key.unwrap_or_default();
+            if request.IdempotencyKey.len() > MAX_IDEMPOTENCY_KEY_SIZE {
+                return Err(ClientError::InvalidIdempotencyKey)
+            }
+            request.DurableAppend = self.durable;
+            if let Some(appender) = self.appender {
+                request.Signature = acl::sign_append(&appender, &request.SpoolID, &message).to_bytes().to_vec();
+                request.PublicKey = appender.public.to_bytes().to_vec();
+            }
+            request.Message = message;
+        }
+        if self.command == SET_APPEND_TOKEN_KEY_COMMAND {
+            request.Message = self.token_key.ok_or(ClientError::MissingField("Message"))?;
+        }
+        if self.command == SET_APPENDERS_COMMAND {
+            request.Message = self.appenders.ok_or(ClientError::MissingField("Message"))?;
+        }
+        if self.command == COPY_SPOOL_COMMAND {
+            request.Message = self.new_owner.map(|x| x.to_bytes().to_vec()).unwrap_or_default();
+        }
+        if self.command == REKEY_SPOOL_COMMAND {
+            let new_keypair = self.new_keypair.ok_or(ClientError::MissingField("Message"))?;
+            let old_key = PublicKey::from_bytes(&request.PublicKey).map_err(|_| ClientError::InvalidSignature)?;
+            let spool_id = array_ref![request.SpoolID, 0, SPOOL_ID_SIZE];
+            request.Message = Rekey::sign(&new_keypair, spool_id, &old_key).to_bytes();
+        }
+        if self.command == CREATE_SPOOL_COMMAND {
+            request.Options = self.options;
+        }
+        Ok(request)
+    }
+
+    /// Builds the request and encodes it as a plugin request payload,
+    /// a big endian length prefix followed by the CBOR request.
+    pub fn encode(self) -> Result<Vec<u8>, ClientError> {
+        let raw_request = serde_cbor::to_vec(&self.build()?)?;
+        let mut payload = vec![0u8; 4];
+        BigEndian::write_u32(&mut payload, raw_request.len() as u32);
+        payload.extend_from_slice(&raw_request);
+        Ok(payload)
+    }
+
+    /// Builds the request and encodes it as a plugin request payload
+    /// carrying a protobuf request, for services listing "protobuf"
+    /// in their "encodings" parameter. The length prefix covers the
+    /// marker byte as well as the request.
+    pub fn encode_protobuf(self) -> Result<Vec<u8>, ClientError> {
+        let mut raw_request = vec![protobuf::PROTOBUF_MARKER];
+        raw_request.extend_from_slice(&protobuf::encode_request(&self.build()?));
+        let mut payload = vec![0u8; 4];
+        BigEndian::write_u32(&mut payload, raw_request.len() as u32);
+        payload.extend_from_slice(&raw_request);
+        Ok(payload)
+    }
+}
+
+/// The version of the fragment header format.
+const FRAGMENT_VERSION: u8 = 2;
+
+/// The size of the header at the start of every fragment: version,
+/// the random ID of the fragmented message and the big endian
+/// fragment index, fragment count and payload length.
+pub const FRAGMENT_HEADER_SIZE: usize = 1 + 8 + 2 + 2 + 4;
+
+/// The largest number of fragments a message may be split into.
+const MAX_FRAGMENTS: usize = 65535;
+
+/// Splits a message into fragments of at most `message_size` bytes,
+/// the service's advertised "message_size" parameter, each of which
+/// is to be appended to the spool in order. The fragments carry a
+/// random message ID, so that those of messages from other writers
+/// appended in between aren't taken for them.
+pub fn fragment(message: &[u8], message_size: usize) -> Result<Vec<Vec<u8>>, ClientError> {
+    if message_size <= FRAGMENT_HEADER_SIZE {
+        return Err(ClientError::MessageTooLarge(message.len()))
+    }
+    let payload_size = message_size - FRAGMENT_HEADER_SIZE;
+    if message.len() > payload_size * MAX_FRAGMENTS {
+        return Err(ClientError::MessageTooLarge(message.len()))
+    }
+    let mut chunks: Vec<&[u8]> = message.chunks(payload_size).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    let count = chunks.len();
+    let message_id: u64 = thread_rng().gen();
+    let mut fragments = vec![];
+    for (index, chunk) in chunks.iter().enumerate() {
+        let mut fragment = vec![0u8; FRAGMENT_HEADER_SIZE + chunk.len()];
+        fragment[0] = FRAGMENT_VERSION;
+        BigEndian::write_u64(&mut fragment[1..9], message_id);
+        BigEndian::write_u16(&mut fragment[9..11], index as u16);
+        BigEndian::write_u16(&mut fragment[11..13], count as u16);
+        BigEndian::write_u32(&mut fragment[13..17], chunk.len() as u32);
+        fragment[FRAGMENT_HEADER_SIZE..].copy_from_slice(chunk);
+        fragments.push(fragment);
+    }
+    Ok(fragments)
+}
+
+/// Reassembler puts fragmented messages back together from spool
+/// messages read in order. A fragment of any other message than the
+/// one being reassembled drops it, so that interleaved messages
+/// aren't spliced together and one never finished, as anyone may
+/// append to a spool without an appender list, can't hold up those
+/// after it. The first fragment of the other message starts on it,
+/// while any later one is refused.
+#[derive(Default)]
+pub struct Reassembler {
+    message: Vec<u8>,
+    next_index: u16,
+    /// The ID and fragment count of the message being reassembled.
+    current: Option<(u64, u16)>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    /// Adds the next fragment, returning the whole message once its
+    /// last fragment has been added.
+    pub fn push(&mut self, fragment: &[u8]) -> Result<Option<Vec<u8>>, ClientError> {
+        if fragment.len() < FRAGMENT_HEADER_SIZE || fragment[0] != FRAGMENT_VERSION {
+            return Err(ClientError::InvalidFragment)
+        }
+        let message_id = BigEndian::read_u64(&fragment[1..9]);
+        let index = BigEndian::read_u16(&fragment[9..11]);
+        let count = BigEndian::read_u16(&fragment[11..13]);
+        let len = BigEndian::read_u32(&fragment[13..17]) as usize;
+        if self.current.map_or(false, |x| x != (message_id, count)) {
+            self.reset();
+        }
+        if index != self.next_index || index >= count || len > fragment.len() - FRAGMENT_HEADER_SIZE {
+            self.reset();
+            return Err(ClientError::InvalidFragment)
+        }
+        self.message.extend_from_slice(&fragment[FRAGMENT_HEADER_SIZE..FRAGMENT_HEADER_SIZE + len]);
+        self.next_index += 1;
+        if self.next_index < count {
+            self.current = Some((message_id, count));
+            return Ok(None)
+        }
+        self.next_index = 0;
+        self.current = None;
+        Ok(Some(mem::replace(&mut self.message, vec![])))
+    }
+
+    /// Drops the message being reassembled.
+    fn reset(&mut self) {
+        self.message.clear();
+        self.next_index = 0;
+        self.current = None;
+    }
+}
+
+/// Returns a random idempotency key for an append, to be reused when
+/// the append is retried.
+pub fn random_idempotency_key() -> [u8; IDEMPOTENCY_KEY_SIZE] {
+    let mut key = [0u8; IDEMPOTENCY_KEY_SIZE];
+    thread_rng().fill(&mut key);
+    key
+}
+
+/// Wraps a spool request payload in a CBOR plugin request.
+pub fn encode_request(id: u64, payload: Vec<u8>) -> Result<Vec<u8>, ClientError> {
+    let request = Request {
+        ID: id,
+        Payload: payload,
+        HasSURB: true,
+    };
+    Ok(serde_cbor::to_vec(&request)?)
+}
+
+/// Unwraps the spool response from a CBOR plugin response.
+pub fn decode_response(raw_response: &[u8]) -> Result<SpoolResponse, ClientError> {
+    let response: Response = serde_cbor::from_slice(raw_response)?;
+    Ok(serde_cbor::from_slice(&response.Payload)?)
+}
+
+/// Unwraps the protobuf spool response from a CBOR plugin response,
+/// the answer to a request encoded with `encode_protobuf`.
+pub fn decode_protobuf_response(raw_response: &[u8]) -> Result<SpoolResponse, ClientError> {
+    let response: Response = serde_cbor::from_slice(raw_response)?;
+    Ok(protobuf::decode_response(&response.Payload)?)
+}
+
+/// Checks that the response is to the request with `request_id` and
+/// `message_id`, and is signed by the service identity key. Requests
+/// without a message ID, as for RETRIEVE_BY_HASH and RETRIEVE_LATEST,
+/// take the one the response names.
+pub fn verify_response(response: &SpoolResponse, request_id: u64, message_id: &[u8], server_key: &PublicKey) -> Result<(), ClientError> {
+    if response.RequestID != request_id {
+        return Err(ClientError::InvalidResponse)
+    }
+    let message_id = if message_id.is_empty() { &response.MessageID[..] } else { message_id };
+    if !response.MessageID.is_empty() && response.MessageID != message_id {
+        return Err(ClientError::InvalidResponse)
+    }
+    if !response.verify(message_id, server_key) {
+        return Err(ClientError::InvalidSignature)
+    }
+    Ok(())
+}
+
+/// SpoolReply is a successful spool response.
+#[derive(Debug, PartialEq)]
+pub enum SpoolReply {
+    Created([u8; SPOOL_ID_SIZE]),
+    /// The spool the key already owned, returned by CREATE_SPOOL
+    /// instead of a new one.
+    Existing([u8; SPOOL_ID_SIZE]),
+    Purged,
+    Undeleted,
+    Appended,
+    TokenKeySet,
+    AppendersSet,
+    /// A message read, with its proof if one was asked for, the unix
+    /// time it was appended at if the service knows it and the number
+    /// of messages after it.
+    Message(Vec<u8>, Option<ReadProof>, Option<u64>, u64),
+    Version(BuildInfo),
+    Status(SpoolStats),
+    /// The ID of a spool's copy.
+    Copied([u8; SPOOL_ID_SIZE]),
+    Rekeyed,
+    /// A message read by hash or by its offset from the newest, with
+    /// its message ID and the rest as for `Message`.
+    Found(u32, Vec<u8>, Option<ReadProof>, Option<u64>, u64),
+}
+
+/// Parses the response to a request with the given command.
+pub fn parse_response(command: u8, response: SpoolResponse) -> Result<SpoolReply, ClientError> {
+    // Services older than the echo leave the command out.
+    if response.Command.is_some() && response.Command != Some(command) {
+        return Err(ClientError::InvalidResponse)
+    }
+    if response.Status == RATE_LIMITED_STATUS {
+        return Err(ClientError::RateLimited)
+    }
+    if response.Status == BUSY_STATUS {
+        return Err(ClientError::Busy)
+    }
+    if response.Status == UNSUPPORTED_VERSION_STATUS {
+        return Err(ClientError::UnsupportedVersion(response.Version))
+    }
+    if response.Status != "OK" {
+        return Err(ClientError::ServerError(response.Status))
+    }
+    match command {
+        CREATE_SPOOL_COMMAND => {
+            if response.SpoolID.len() != SPOOL_ID_SIZE {
+                return Err(ClientError::InvalidResponse)
+            }
+            let spool_id = *array_ref![response.SpoolID, 0, SPOOL_ID_SIZE];
+            if response.Existing {
+                return Ok(SpoolReply::Existing(spool_id))
+            }
+            Ok(SpoolReply::Created(spool_id))
+        },
+        PURGE_SPOOL_COMMAND => Ok(SpoolReply::Purged),
+        UNDELETE_SPOOL_COMMAND => Ok(SpoolReply::Undeleted),
+        APPEND_MESSAGE_COMMAND => Ok(SpoolReply::Appended),
+        SET_APPEND_TOKEN_KEY_COMMAND => Ok(SpoolReply::TokenKeySet),
+        SET_APPENDERS_COMMAND => Ok(SpoolReply::AppendersSet),
+        RETRIEVE_MESSAGE_COMMAND => {
+            let appended_at = if response.AppendedAt != 0 { Some(response.AppendedAt) } else { None };
+            Ok(SpoolReply::Message(response.Message, response.Proof, appended_at, response.Remaining))
+        },
+        VERSION_COMMAND => Ok(SpoolReply::Version(serde_cbor::from_slice(&response.Message)?)),
+        SPOOL_STATUS_COMMAND => Ok(SpoolReply::Status(serde_cbor::from_slice(&response.Message)?)),
+        REKEY_SPOOL_COMMAND => Ok(SpoolReply::Rekeyed),
+        RETRIEVE_BY_HASH_COMMAND | RETRIEVE_LATEST_COMMAND => {
+            if response.MessageID.len() != MESSAGE_ID_SIZE {
+                return Err(ClientError::InvalidResponse)
+            }
+            let message_id = BigEndian::read_u32(&response.MessageID);
+            let appended_at = if response.AppendedAt != 0 { Some(response.AppendedAt) } else { None };
+            Ok(SpoolReply::Found(message_id, response.Message, response.Proof, appended_at, response.Remaining))
+        },
+        COPY_SPOOL_COMMAND => {
+            if response.SpoolID.len() != SPOOL_ID_SIZE {
+                return Err(ClientError::InvalidResponse)
+            }
+            Ok(SpoolReply::Copied(*array_ref![response.SpoolID, 0, SPOOL_ID_SIZE]))
+        },
+        _ => Err(ClientError::InvalidResponse),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+    use super::*;
+
+    #[test]
+    fn builder_encode_test() {
+        let mut csprng = thread_rng();
+        let keypair = Keypair::generate(&mut csprng);
+        let spool_id = [7u8; SPOOL_ID_SIZE];
+        let payload = SpoolRequestBuilder::new(RETRIEVE_MESSAGE_COMMAND)
+            .spool_id(spool_id)
+            .sign(&keypair)
+            .message_id(3)
+            .encode()
+            .unwrap();
+        let len = BigEndian::read_u32(&payload[..4]) as usize;
+        let request: SpoolRequest = serde_cbor::from_slice(&payload[4..len + 4]).unwrap();
+        assert_eq!(request.Command, RETRIEVE_MESSAGE_COMMAND);
+        assert_eq!(request.SpoolID, spool_id.to_vec());
+        assert_eq!(request.PublicKey, keypair.public.to_bytes().to_vec());
+        assert_eq!(BigEndian::read_u32(&request.MessageID), 3);
+    }
+
+    #[test]
+    fn builder_validation_test() {
+        assert!(SpoolRequestBuilder::new(PURGE_SPOOL_COMMAND).build().is_err());
+        let message = vec![0u8; MESSAGE_SIZE + 1];
+        assert!(SpoolRequestBuilder::new(APPEND_MESSAGE_COMMAND)
+                .spool_id([0u8; SPOOL_ID_SIZE])
+                .message(&message)
+                .build().is_err());
+        let request = SpoolRequestBuilder::new(APPEND_MESSAGE_COMMAND)
+            .spool_id([0u8; SPOOL_ID_SIZE])
+            .message(b"hello")
+            .build()
+            .unwrap();
+        assert_eq!(request.Message, b"hello".to_vec());
+        assert!(request.ProofOfWork.is_empty());
+        let request = SpoolRequestBuilder::new(APPEND_MESSAGE_COMMAND)
+            .spool_id([0u8; SPOOL_ID_SIZE])
+            .message(b"hello")
+            .proof_of_work(8)
+            .build()
+            .unwrap();
+        assert!(pow::verify(&request.SpoolID, b"hello", &request.ProofOfWork, 8));
+        let key = random_idempotency_key();
+        let request = SpoolRequestBuilder::new(APPEND_MESSAGE_COMMAND)
+            .spool_id([0u8; SPOOL_ID_SIZE])
+            .message(b"hello")
+            .idempotency_key(&key)
+            .build()
+            .unwrap();
+        assert_eq!(request.IdempotencyKey, key.to_vec());
+        assert!(!request.DurableAppend);
+        let request = SpoolRequestBuilder::new(APPEND_MESSAGE_COMMAND)
+            .spool_id([0u8; SPOOL_ID_SIZE])
+            .message(b"hello")
+            .durable()
+            .build()
+            .unwrap();
+        assert!(request.DurableAppend);
+        let writer = Keypair::generate(&mut thread_rng());
+        let request = SpoolRequestBuilder::new(APPEND_MESSAGE_COMMAND)
+            .spool_id([0u8; SPOOL_ID_SIZE])
+            .message(b"hello")
+            .appender(&writer)
+            .build()
+            .unwrap();
+        let appenders = acl::AppenderList::new(vec![writer.public]).unwrap();
+        assert!(appenders.verify(&request.SpoolID, b"hello", &request.PublicKey, &request.Signature));
+        assert!(SpoolRequestBuilder::new(SET_APPENDERS_COMMAND)
+                .spool_id([0u8; SPOOL_ID_SIZE])
+                .sign(&writer)
+                .build().is_err());
+        let options = SpoolOptions {
+            Capacity: 10,
+            Circular: true,
+            ..SpoolOptions::default()
+        };
+        let request = SpoolRequestBuilder::new(CREATE_SPOOL_COMMAND)
+            .sign(&writer)
+            .options(options.clone())
+            .build()
+            .unwrap();
+        assert_eq!(request.Options, Some(options));
+        assert!(SpoolRequestBuilder::new(APPEND_MESSAGE_COMMAND)
+                .spool_id([0u8; SPOOL_ID_SIZE])
+                .message(b"hello")
+                .idempotency_key(&[0u8; MAX_IDEMPOTENCY_KEY_SIZE + 1])
+                .build().is_err());
+        assert!(SpoolRequestBuilder::new(APPEND_MESSAGE_COMMAND)
+                .spool_id([0u8; SPOOL_ID_SIZE])
+                .max_message_size(4)
+                .message(b"hello")
+                .build().is_err());
+    }
+
+    #[test]
+    fn parse_response_test() {
+        let response = SpoolResponse {
+            SpoolID: vec![1u8; SPOOL_ID_SIZE],
+            Status: "OK".to_string(),
+            ..SpoolResponse::default()
+        };
+        assert_eq!(parse_response(CREATE_SPOOL_COMMAND, response).unwrap(), SpoolReply::Created([1u8; SPOOL_ID_SIZE]));
+        let response = SpoolResponse {
+            SpoolID: vec![1u8; SPOOL_ID_SIZE],
+            Status: "OK".to_string(),
+            Existing: true,
+            ..SpoolResponse::default()
+        };
+        assert_eq!(parse_response(CREATE_SPOOL_COMMAND, response).unwrap(), SpoolReply::Existing([1u8; SPOOL_ID_SIZE]));
+        let response = SpoolResponse {
+            Status: "error: invalid signature".to_string(),
+            ..SpoolResponse::default()
+        };
+        assert!(parse_response(PURGE_SPOOL_COMMAND, response).is_err());
+        let response = SpoolResponse {
+            Status: UNSUPPORTED_VERSION_STATUS.to_string(),
+            Version: Some(ProtocolVersion::current()),
+            ..SpoolResponse::default()
+        };
+        match parse_response(PURGE_SPOOL_COMMAND, response) {
+            Err(ClientError::UnsupportedVersion(Some(version))) => assert_eq!(version, ProtocolVersion::current()),
+            _ => panic!("expected an unsupported version error"),
+        }
+        let response = SpoolResponse {
+            Status: "OK".to_string(),
+            Command: Some(APPEND_MESSAGE_COMMAND),
+            ..SpoolResponse::default()
+        };
+        assert!(parse_response(PURGE_SPOOL_COMMAND, response).is_err());
+        let response = SpoolResponse {
+            Message: b"hello".to_vec(),
+            Command: Some(RETRIEVE_MESSAGE_COMMAND),
+            MessageID: vec![0, 0, 0, 1],
+            Status: "OK".to_string(),
+            Proof: Some(ReadProof::default()),
+            AppendedAt: 1500000000,
+            Remaining: 2,
+            ..SpoolResponse::default()
+        };
+        assert_eq!(parse_response(RETRIEVE_MESSAGE_COMMAND, response).unwrap(),
+                   SpoolReply::Message(b"hello".to_vec(), Some(ReadProof::default()), Some(1500000000), 2));
+        let stats = SpoolStats {
+            MessageCount: 2,
+            Head: 3,
+            FirstMessage: 1,
+            Capacity: 2,
+            ..SpoolStats::default()
+        };
+        let response = SpoolResponse {
+            Message: serde_cbor::to_vec(&stats).unwrap(),
+            Command: Some(SPOOL_STATUS_COMMAND),
+            Status: "OK".to_string(),
+            ..SpoolResponse::default()
+        };
+        assert_eq!(parse_response(SPOOL_STATUS_COMMAND, response).unwrap(), SpoolReply::Status(stats));
+        let response = SpoolResponse {
+            SpoolID: vec![4u8; SPOOL_ID_SIZE],
+            Command: Some(COPY_SPOOL_COMMAND),
+            Status: "OK".to_string(),
+            ..SpoolResponse::default()
+        };
+        assert_eq!(parse_response(COPY_SPOOL_COMMAND, response).unwrap(), SpoolReply::Copied([4u8; SPOOL_ID_SIZE]));
+        let response = SpoolResponse {
+            Message: b"hello".to_vec(),
+            Command: Some(RETRIEVE_BY_HASH_COMMAND),
+            Status: "OK".to_string(),
+            MessageID: vec![0, 0, 0, 5],
+            ..SpoolResponse::default()
+        };
+        assert_eq!(parse_response(RETRIEVE_BY_HASH_COMMAND, response).unwrap(), SpoolReply::Found(5, b"hello".to_vec(), None, None, 0));
+        let response = SpoolResponse {
+            Command: Some(RETRIEVE_BY_HASH_COMMAND),
+            Status: "OK".to_string(),
+            ..SpoolResponse::default()
+        };
+        assert!(parse_response(RETRIEVE_BY_HASH_COMMAND, response).is_err());
+        let response = SpoolResponse {
+            Message: b"hello".to_vec(),
+            Command: Some(RETRIEVE_LATEST_COMMAND),
+            Status: "OK".to_string(),
+            MessageID: vec![0, 0, 0, 9],
+            Remaining: 2,
+            ..SpoolResponse::default()
+        };
+        assert_eq!(parse_response(RETRIEVE_LATEST_COMMAND, response).unwrap(), SpoolReply::Found(9, b"hello".to_vec(), None, None, 2));
+    }
+
+    #[test]
+    fn fragment_reassemble_test() {
+        let payload_size = MESSAGE_SIZE - FRAGMENT_HEADER_SIZE;
+        let message: Vec<u8> = (0..payload_size * 2 + 10).map(|x| x as u8).collect();
+        let fragments = fragment(&message, MESSAGE_SIZE).unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert_eq!(fragments[0].len(), MESSAGE_SIZE);
+        assert_eq!(fragments[2].len(), FRAGMENT_HEADER_SIZE + 10);
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(&fragments[0]).unwrap(), None);
+        assert!(reassembler.push(&fragments[2]).is_err());
+        assert_eq!(reassembler.push(&fragments[0]).unwrap(), None);
+        assert_eq!(reassembler.push(&fragments[1]).unwrap(), None);
+        assert_eq!(reassembler.push(&fragments[2]).unwrap(), Some(message.clone()));
+
+        let empty = fragment(&[], MESSAGE_SIZE).unwrap();
+        assert_eq!(empty.len(), 1);
+        assert_eq!(reassembler.push(&empty[0]).unwrap(), Some(vec![]));
+
+        // A later fragment of another message read in between is
+        // refused, dropping the message being reassembled.
+        let other = fragment(&message, MESSAGE_SIZE).unwrap();
+        assert_eq!(reassembler.push(&fragments[0]).unwrap(), None);
+        assert!(reassembler.push(&other[1]).is_err());
+        assert!(reassembler.push(&fragments[1]).is_err());
+        let mut shorter = fragment(&message[..payload_size + 1], MESSAGE_SIZE).unwrap();
+        shorter[1][1..9].copy_from_slice(&fragments[0][1..9]);
+        assert_eq!(reassembler.push(&fragments[0]).unwrap(), None);
+        assert!(reassembler.push(&shorter[1]).is_err());
+        assert_eq!(reassembler.push(&fragments[0]).unwrap(), None);
+        assert_eq!(reassembler.push(&fragments[1]).unwrap(), None);
+        assert_eq!(reassembler.push(&fragments[2]).unwrap(), Some(message.clone()));
+
+        // A message which is never finished doesn't hold up the next.
+        assert_eq!(reassembler.push(&other[0]).unwrap(), None);
+        assert_eq!(reassembler.push(&other[1]).unwrap(), None);
+        assert_eq!(reassembler.push(&fragments[0]).unwrap(), None);
+        assert_eq!(reassembler.push(&fragments[1]).unwrap(), None);
+        assert_eq!(reassembler.push(&fragments[2]).unwrap(), Some(message));
+    }
+}
@@ -0,0 +1,808 @@
+// config.rs - Multi-spool service configuration.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Service configuration
+
+extern crate toml;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+use log::LevelFilter;
+
+use crate::admission::DEFAULT_MAX_QUEUED_REQUESTS;
+use crate::dedup::DEFAULT_DEDUP_CACHE_SIZE;
+use crate::disk::DEFAULT_MIN_FREE_BYTES;
+use crate::encryption::Keyring;
+use crate::errors::{ConfigError, EncryptionError};
+use crate::group_commit::DEFAULT_GROUP_COMMIT_WINDOW_MS;
+use crate::logging::{LogOutput, DEFAULT_LOG_OUTPUT};
+use crate::ratelimit::{RateLimits, COMMAND_CLASSES};
+use crate::replication::DEFAULT_MAX_REPLICATION_BACKLOG;
+use crate::spool::{Durability, DEFAULT_FLUSH_EVERY_MS, DEFAULT_PURGE_GRACE_PERIOD_SECS, DEFAULT_SNAPSHOT_AFTER_OPS, MESSAGE_SIZE};
+use crate::syslog::{Facility, DEFAULT_SYSLOG_FACILITY, DEFAULT_SYSLOG_PATH};
+use crate::throttle::{DEFAULT_MAX_SIGNATURE_FAILURES, DEFAULT_SIGNATURE_BACKOFF_MS};
+
+
+/// The prefix of environment variables overriding config values.
+pub const ENV_PREFIX: &str = "MULTISPOOL_";
+
+/// The default time in milliseconds to wait for in-flight requests
+/// to finish on shutdown.
+pub const DEFAULT_DRAIN_TIMEOUT_MS: u64 = 10000;
+
+/// The default time in milliseconds to wait for a request body.
+pub const DEFAULT_READ_TIMEOUT_MS: u64 = 10000;
+
+/// The default time in milliseconds to wait for a request to be
+/// handled, including its storage operations and response encoding.
+pub const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 30000;
+
+/// The default time in seconds between logged spool summaries.
+pub const DEFAULT_STATS_LOG_SECS: u64 = 3600;
+
+/// The default time in seconds between compactions of every spool.
+pub const DEFAULT_COMPACTION_SECS: u64 = 86400;
+
+/// The room a request takes besides its message, for its plugin and
+/// spool request envelopes, keys, signatures and options.
+pub const REQUEST_SIZE_OVERHEAD: usize = 16 * 1024;
+
+/// The name of the identity key file in the data directory.
+pub const DEFAULT_IDENTITY_KEY_FILE: &str = "identity.key";
+
+/// The default log level.
+pub const DEFAULT_LOG_LEVEL: &str = "debug";
+
+/// Config is the spool service configuration as read from a TOML
+/// file. Every field may also be set on the command line, which
+/// takes precedence over the file.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct Config {
+    /// The directory holding the spool set and spool databases.
+    pub data_dir: Option<String>,
+    /// More directories, e.g. on other disks, the spool databases are
+    /// sharded across along with the data directory. Adding one moves
+    /// the spools which belong in it there on the next start.
+    pub shard_dirs: Vec<String>,
+    /// The directory the service writes its log file into.
+    pub log_dir: Option<String>,
+    /// The unix socket path to listen on. If unset a randomly named
+    /// socket in /tmp is used.
+    pub socket_path: Option<String>,
+    /// User ids allowed to connect to the unix socket.
+    pub allowed_uids: Vec<u32>,
+    /// Group ids allowed to connect to the unix socket.
+    pub allowed_gids: Vec<u32>,
+    /// How long to wait for in-flight requests on shutdown before
+    /// flushing and exiting anyway.
+    pub drain_timeout_ms: Option<u64>,
+    /// The log level, one of off, error, warn, info, debug or trace.
+    pub log_level: Option<String>,
+    /// Where to write logs, one of file, stdout, stderr or syslog.
+    pub log_output: Option<String>,
+    /// The syslog socket used with the syslog log output.
+    pub syslog_path: Option<String>,
+    /// The syslog facility, daemon, user or local0 through local7.
+    pub syslog_facility: Option<String>,
+    /// The OpenTelemetry collector OTLP/HTTP endpoint to export
+    /// request traces to. Tracing is disabled when unset.
+    pub otlp_endpoint: Option<String>,
+    /// The statsd server, e.g. 127.0.0.1:8125, to push metrics to over
+    /// UDP besides serving them on the admin socket. Disabled when
+    /// unset.
+    pub statsd_address: Option<String>,
+    /// The maximum number of spools which may be created. Unlimited
+    /// when unset.
+    pub max_spools: Option<u64>,
+    /// The largest message which may be appended. Defaults to the
+    /// sphinx user forward payload size; set it to match the sphinx
+    /// geometry of the deployment.
+    pub max_message_size: Option<usize>,
+    /// The zstd level to compress stored messages at, trading CPU
+    /// for disk. Messages are stored uncompressed when unset.
+    pub compression_level: Option<i32>,
+    /// Files holding the 32 byte master keys messages and spool
+    /// owner keys are encrypted with at rest. The first key encrypts
+    /// new data, the rest are old keys which are kept until all data
+    /// is re-encrypted. Nothing is encrypted when empty.
+    pub master_key_paths: Vec<String>,
+    /// If set, appends must carry a proof of work of this many bits,
+    /// advertised as the "append_pow_difficulty" parameter.
+    pub append_pow_difficulty: Option<u32>,
+    /// Token bucket rate limits by command class, one of create,
+    /// append, read or other. Classes without limits are unlimited.
+    pub rate_limits: HashMap<String, RateLimits>,
+    /// The number of append idempotency keys remembered to ignore
+    /// retransmitted appends. Defaults to DEFAULT_DEDUP_CACHE_SIZE.
+    pub dedup_cache_size: Option<usize>,
+    /// The number of consecutive bad signatures after which a spool
+    /// backs off. Defaults to DEFAULT_MAX_SIGNATURE_FAILURES.
+    pub max_signature_failures: Option<u32>,
+    /// The first backoff, doubling with each further bad signature.
+    /// Defaults to DEFAULT_SIGNATURE_BACKOFF_MS.
+    pub signature_backoff_ms: Option<u64>,
+    /// How long purged spools can be undeleted for before they are
+    /// deleted for good. Zero deletes them right away. Defaults to
+    /// DEFAULT_PURGE_GRACE_PERIOD_SECS.
+    pub purge_grace_period_secs: Option<u64>,
+    /// How long a spool may go without appends or reads before it is
+    /// purged as abandoned. Unset or zero keeps spools forever.
+    pub inactive_spool_secs: Option<u64>,
+    /// How often a summary of every spool and the requests handled is
+    /// logged. Zero never logs it. Defaults to
+    /// DEFAULT_STATS_LOG_SECS.
+    pub stats_log_secs: Option<u64>,
+    /// How often every spool is compacted to reclaim the space of
+    /// deleted messages. Zero never compacts them but when asked on
+    /// the admin socket. Defaults to DEFAULT_COMPACTION_SECS.
+    pub compaction_secs: Option<u64>,
+    /// The free disk space in bytes below which creations and appends
+    /// are refused. Zero never refuses them. Defaults to
+    /// DEFAULT_MIN_FREE_BYTES.
+    pub min_free_bytes: Option<u64>,
+    /// Whether requests with fields this version doesn't know are
+    /// refused rather than having them ignored, see `schema`.
+    pub strict_requests: Option<bool>,
+    /// Whether spool requests are also taken as JSON on
+    /// `POST /debug/request`, see `debug`.
+    pub debug_json: Option<bool>,
+    /// The file holding the service's ed25519 identity keypair, which
+    /// signs responses and read proofs. Generated if missing; defaults
+    /// to identity.key in the data directory.
+    pub identity_key_path: Option<String>,
+    /// The directory backups are written to and restored from.
+    /// Defaults to backups in the data directory.
+    pub backup_dir: Option<String>,
+    /// The largest encoded response, which must fit in the SURB
+    /// reply payload. Unlimited when unset.
+    pub max_response_size: Option<usize>,
+    /// The largest request body read, larger ones being refused
+    /// unread. Defaults to the maximum message size plus
+    /// REQUEST_SIZE_OVERHEAD.
+    pub max_request_size: Option<usize>,
+    /// How long a peer may take to send a request body. Defaults to
+    /// DEFAULT_READ_TIMEOUT_MS.
+    pub read_timeout_ms: Option<u64>,
+    /// How long a request may take to be handled before it is answered
+    /// with a timeout status. Defaults to DEFAULT_REQUEST_TIMEOUT_MS.
+    pub request_timeout_ms: Option<u64>,
+    /// The most spool requests handled at once, any number when unset,
+    /// see `admission`. Changing it requires a restart.
+    pub max_in_flight: Option<usize>,
+    /// The most spool requests waiting for a turn beyond
+    /// max_in_flight, others being answered with a busy status.
+    /// Defaults to DEFAULT_MAX_QUEUED_REQUESTS; changing it requires a
+    /// restart.
+    pub max_queued_requests: Option<usize>,
+    /// The number of threads serving connections. Defaults to the
+    /// number of CPUs; changing it requires a restart.
+    pub worker_threads: Option<usize>,
+    /// The most threads running storage operations at once. Defaults
+    /// to the runtime's own limit; changing it requires a restart.
+    pub blocking_threads: Option<usize>,
+    /// The user to run as once the sockets are bound, a name or a
+    /// number, see `privileges`. Changing it requires a restart.
+    pub user: Option<String>,
+    /// The group to run as with `user`, by default the user's primary
+    /// group. Changing it requires a restart.
+    pub group: Option<String>,
+    /// Whether to confine the service with Landlock and seccomp, see
+    /// `sandbox`. Changing it requires a restart.
+    pub sandbox: Option<bool>,
+    /// Whether group and world access to the data, shard, backup and
+    /// log directories is taken away on startup rather than refused,
+    /// see `permissions`.
+    pub fix_permissions: Option<bool>,
+    /// The unix socket path of the admin API. The admin API is
+    /// disabled when unset.
+    pub admin_socket_path: Option<String>,
+    /// User ids allowed to connect to the admin socket. Only our
+    /// own user may connect when empty.
+    pub admin_uids: Vec<u32>,
+    /// If set, admin requests must carry this bearer token.
+    pub admin_token: Option<String>,
+    /// How often spools are flushed to disk, bounding the appends a
+    /// crash may lose. Zero leaves flushing to sled. Defaults to
+    /// DEFAULT_FLUSH_EVERY_MS.
+    pub flush_every_ms: Option<u64>,
+    /// The number of writes between spool page table snapshots, which
+    /// bound recovery time. Defaults to DEFAULT_SNAPSHOT_AFTER_OPS.
+    pub snapshot_after_ops: Option<usize>,
+    /// Whether every append is flushed to disk before it is
+    /// acknowledged, not only those asking to be durable.
+    pub flush_on_append: Option<bool>,
+    /// How long a durable append waits for others to share its flush
+    /// with. Defaults to DEFAULT_GROUP_COMMIT_WINDOW_MS.
+    pub group_commit_window_ms: Option<u64>,
+    /// The replication addresses, host:port, of the replicas every
+    /// spool change is streamed to. Nothing is replicated when empty.
+    pub replicas: Vec<String>,
+    /// The address, host:port, on which a replica accepts changes
+    /// from its primary. Not a replica when unset.
+    pub replication_listen: Option<String>,
+    /// Files holding the 32 byte keys shared by a primary and its
+    /// replicas. The first key seals changes, the rest are old keys
+    /// still accepted. Required for replication.
+    pub replication_key_paths: Vec<String>,
+    /// The number of changes queued for a replica before it is given
+    /// up on. Defaults to DEFAULT_MAX_REPLICATION_BACKLOG.
+    pub max_replication_backlog: Option<usize>,
+    /// Whether to start as a standby, refusing writes but those from
+    /// the primary until promoted through the admin API. Otherwise
+    /// the role kept from the last run is resumed, a fenced primary
+    /// staying fenced.
+    pub standby: Option<bool>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let mut file = File::open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// Returns the configured log level or the default.
+    pub fn log_level_filter(&self) -> Result<LevelFilter, ConfigError> {
+        let level = match self.log_level {
+            Some(ref x) => x.as_str(),
+            None => DEFAULT_LOG_LEVEL,
+        };
+        LevelFilter::from_str(level).map_err(|_| ConfigError::InvalidLogLevel(level.to_string()))
+    }
+
+    /// Returns the configured log output or the default.
+    pub fn log_output(&self) -> Result<LogOutput, ConfigError> {
+        match self.log_output {
+            Some(ref x) => LogOutput::from_str(x),
+            None => LogOutput::from_str(DEFAULT_LOG_OUTPUT),
+        }
+    }
+
+    /// Returns the configured maximum message size or the default.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size.unwrap_or(MESSAGE_SIZE)
+    }
+
+    /// Returns the configured maximum request size or the default.
+    pub fn max_request_size(&self) -> usize {
+        self.max_request_size.unwrap_or_else(|| self.max_message_size() + REQUEST_SIZE_OVERHEAD)
+    }
+
+    /// Returns the configured body read timeout or the default.
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_millis(self.read_timeout_ms.unwrap_or(DEFAULT_READ_TIMEOUT_MS))
+    }
+
+    /// Returns the configured request timeout or the default.
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms.unwrap_or(DEFAULT_REQUEST_TIMEOUT_MS))
+    }
+
+    /// Returns the configured request queue length or the default.
+    pub fn max_queued_requests(&self) -> usize {
+        self.max_queued_requests.unwrap_or(DEFAULT_MAX_QUEUED_REQUESTS)
+    }
+
+    /// Returns the configured number of worker threads, or the number
+    /// of CPUs.
+    pub fn worker_threads(&self) -> usize {
+        match self.worker_threads {
+            Some(x) if x > 0 => x,
+            _ => thread::available_parallelism().map(|x| x.get()).unwrap_or(1),
+        }
+    }
+
+    /// Returns the configured dedup cache size or the default.
+    pub fn dedup_cache_size(&self) -> usize {
+        self.dedup_cache_size.unwrap_or(DEFAULT_DEDUP_CACHE_SIZE)
+    }
+
+    /// Returns the configured durability settings or the defaults.
+    pub fn durability(&self) -> Durability {
+        let flush_every_ms = self.flush_every_ms.unwrap_or(DEFAULT_FLUSH_EVERY_MS);
+        Durability {
+            flush_every_ms: if flush_every_ms == 0 { None } else { Some(flush_every_ms) },
+            snapshot_after_ops: self.snapshot_after_ops.unwrap_or(DEFAULT_SNAPSHOT_AFTER_OPS),
+            flush_on_append: self.flush_on_append.unwrap_or(false),
+            group_commit_window_ms: self.group_commit_window_ms.unwrap_or(DEFAULT_GROUP_COMMIT_WINDOW_MS),
+        }
+    }
+
+    /// Returns the configured purge grace period or the default.
+    pub fn purge_grace_period(&self) -> Duration {
+        Duration::from_secs(self.purge_grace_period_secs.unwrap_or(DEFAULT_PURGE_GRACE_PERIOD_SECS))
+    }
+
+    /// Returns the configured free space threshold or the default.
+    pub fn min_free_bytes(&self) -> u64 {
+        self.min_free_bytes.unwrap_or(DEFAULT_MIN_FREE_BYTES)
+    }
+
+    /// Returns the configured inactivity period after which spools are
+    /// purged, if any.
+    pub fn inactive_period(&self) -> Option<Duration> {
+        self.inactive_spool_secs.filter(|x| *x > 0).map(Duration::from_secs)
+    }
+
+    /// Returns how often the spools are compacted, if at all.
+    pub fn compaction_period(&self) -> Option<Duration> {
+        Some(self.compaction_secs.unwrap_or(DEFAULT_COMPACTION_SECS))
+            .filter(|x| *x > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// Returns how often the spool summary is logged, if at all.
+    pub fn stats_log_period(&self) -> Option<Duration> {
+        Some(self.stats_log_secs.unwrap_or(DEFAULT_STATS_LOG_SECS))
+            .filter(|x| *x > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// Returns the configured number of signature failures allowed
+    /// and the first backoff, or the defaults.
+    pub fn signature_backoff(&self) -> (u32, Duration) {
+        (self.max_signature_failures.unwrap_or(DEFAULT_MAX_SIGNATURE_FAILURES),
+         Duration::from_millis(self.signature_backoff_ms.unwrap_or(DEFAULT_SIGNATURE_BACKOFF_MS)))
+    }
+
+    /// Returns the configured identity key path or the default, if
+    /// the data directory is set.
+    pub fn identity_key_path(&self) -> Option<String> {
+        if let Some(ref x) = self.identity_key_path {
+            return Some(x.clone())
+        }
+        self.data_dir.as_ref().map(|x| Path::new(x).join(DEFAULT_IDENTITY_KEY_FILE).to_string_lossy().into_owned())
+    }
+
+    /// Loads the configured master keys, if any.
+    pub fn keyring(&self) -> Result<Option<Keyring>, EncryptionError> {
+        if self.master_key_paths.is_empty() {
+            return Ok(None)
+        }
+        Ok(Some(Keyring::load(&self.master_key_paths)?))
+    }
+
+    /// Loads the configured replication keys, if any.
+    pub fn replication_keyring(&self) -> Result<Option<Keyring>, EncryptionError> {
+        if self.replication_key_paths.is_empty() {
+            return Ok(None)
+        }
+        Ok(Some(Keyring::load(&self.replication_key_paths)?))
+    }
+
+    /// Returns whether to start as a standby.
+    pub fn standby(&self) -> bool {
+        self.standby.unwrap_or(false)
+    }
+
+    /// Returns the configured replication backlog limit or the default.
+    pub fn max_replication_backlog(&self) -> usize {
+        self.max_replication_backlog.unwrap_or(DEFAULT_MAX_REPLICATION_BACKLOG)
+    }
+
+    /// Checks that rate limits are given for known command classes,
+    /// and allow at least one request.
+    pub fn check_rate_limits(&self) -> Result<(), ConfigError> {
+        for (class, limits) in &self.rate_limits {
+            if !COMMAND_CLASSES.contains(&class.as_str()) {
+                return Err(ConfigError::InvalidRateLimit(class.clone()))
+            }
+            for limit in limits.per_spool.iter().chain(limits.global.iter()) {
+                if !(limit.rate > 0.0) || limit.burst == 0 {
+                    return Err(ConfigError::InvalidRateLimit(class.clone()))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the configured syslog socket path or the default.
+    pub fn syslog_path(&self) -> &str {
+        match self.syslog_path {
+            Some(ref x) => x.as_str(),
+            None => DEFAULT_SYSLOG_PATH,
+        }
+    }
+
+    /// Returns the configured syslog facility or the default.
+    pub fn syslog_facility(&self) -> Result<Facility, ConfigError> {
+        match self.syslog_facility {
+            Some(ref x) => Facility::from_str(x),
+            None => Facility::from_str(DEFAULT_SYSLOG_FACILITY),
+        }
+    }
+
+    /// Overrides config values with any `MULTISPOOL_*` environment
+    /// variables, e.g. `MULTISPOOL_DATA_DIR`. List values are comma
+    /// separated.
+    pub fn apply_env(&mut self) -> Result<(), ConfigError> {
+        self.apply_env_from(|name| env::var(name).ok())
+    }
+
+    fn apply_env_from<F>(&mut self, lookup: F) -> Result<(), ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let var = |key: &str| lookup(&format!("{}{}", ENV_PREFIX, key));
+        if let Some(x) = var("DATA_DIR") {
+            self.data_dir = Some(x);
+        }
+        if let Some(x) = var("SHARD_DIRS") {
+            self.shard_dirs = parse_list("SHARD_DIRS", &x)?;
+        }
+        if let Some(x) = var("LOG_DIR") {
+            self.log_dir = Some(x);
+        }
+        if let Some(x) = var("SOCKET_PATH") {
+            self.socket_path = Some(x);
+        }
+        if let Some(x) = var("ALLOWED_UIDS") {
+            self.allowed_uids = parse_list("ALLOWED_UIDS", &x)?;
+        }
+        if let Some(x) = var("ALLOWED_GIDS") {
+            self.allowed_gids = parse_list("ALLOWED_GIDS", &x)?;
+        }
+        if let Some(x) = var("DRAIN_TIMEOUT_MS") {
+            self.drain_timeout_ms = Some(parse_value("DRAIN_TIMEOUT_MS", &x)?);
+        }
+        if let Some(x) = var("LOG_LEVEL") {
+            self.log_level = Some(x);
+        }
+        if let Some(x) = var("LOG_OUTPUT") {
+            self.log_output = Some(x);
+        }
+        if let Some(x) = var("SYSLOG_PATH") {
+            self.syslog_path = Some(x);
+        }
+        if let Some(x) = var("SYSLOG_FACILITY") {
+            self.syslog_facility = Some(x);
+        }
+        if let Some(x) = var("OTLP_ENDPOINT") {
+            self.otlp_endpoint = Some(x);
+        }
+        if let Some(x) = var("STATSD_ADDRESS") {
+            self.statsd_address = Some(x);
+        }
+        if let Some(x) = var("MAX_SPOOLS") {
+            self.max_spools = Some(parse_value("MAX_SPOOLS", &x)?);
+        }
+        if let Some(x) = var("MAX_MESSAGE_SIZE") {
+            self.max_message_size = Some(parse_value("MAX_MESSAGE_SIZE", &x)?);
+        }
+        if let Some(x) = var("COMPRESSION_LEVEL") {
+            self.compression_level = Some(parse_value("COMPRESSION_LEVEL", &x)?);
+        }
+        if let Some(x) = var("MASTER_KEY_PATHS") {
+            self.master_key_paths = parse_list("MASTER_KEY_PATHS", &x)?;
+        }
+        if let Some(x) = var("APPEND_POW_DIFFICULTY") {
+            self.append_pow_difficulty = Some(parse_value("APPEND_POW_DIFFICULTY", &x)?);
+        }
+        if let Some(x) = var("DEDUP_CACHE_SIZE") {
+            self.dedup_cache_size = Some(parse_value("DEDUP_CACHE_SIZE", &x)?);
+        }
+        if let Some(x) = var("MAX_SIGNATURE_FAILURES") {
+            self.max_signature_failures = Some(parse_value("MAX_SIGNATURE_FAILURES", &x)?);
+        }
+        if let Some(x) = var("SIGNATURE_BACKOFF_MS") {
+            self.signature_backoff_ms = Some(parse_value("SIGNATURE_BACKOFF_MS", &x)?);
+        }
+        if let Some(x) = var("PURGE_GRACE_PERIOD_SECS") {
+            self.purge_grace_period_secs = Some(parse_value("PURGE_GRACE_PERIOD_SECS", &x)?);
+        }
+        if let Some(x) = var("INACTIVE_SPOOL_SECS") {
+            self.inactive_spool_secs = Some(parse_value("INACTIVE_SPOOL_SECS", &x)?);
+        }
+        if let Some(x) = var("STATS_LOG_SECS") {
+            self.stats_log_secs = Some(parse_value("STATS_LOG_SECS", &x)?);
+        }
+        if let Some(x) = var("COMPACTION_SECS") {
+            self.compaction_secs = Some(parse_value("COMPACTION_SECS", &x)?);
+        }
+        if let Some(x) = var("STRICT_REQUESTS") {
+            self.strict_requests = Some(parse_value("STRICT_REQUESTS", &x)?);
+        }
+        if let Some(x) = var("DEBUG_JSON") {
+            self.debug_json = Some(parse_value("DEBUG_JSON", &x)?);
+        }
+        if let Some(x) = var("MIN_FREE_BYTES") {
+            self.min_free_bytes = Some(parse_value("MIN_FREE_BYTES", &x)?);
+        }
+        if let Some(x) = var("IDENTITY_KEY_PATH") {
+            self.identity_key_path = Some(x);
+        }
+        if let Some(x) = var("BACKUP_DIR") {
+            self.backup_dir = Some(x);
+        }
+        if let Some(x) = var("MAX_RESPONSE_SIZE") {
+            self.max_response_size = Some(parse_value("MAX_RESPONSE_SIZE", &x)?);
+        }
+        if let Some(x) = var("MAX_REQUEST_SIZE") {
+            self.max_request_size = Some(parse_value("MAX_REQUEST_SIZE", &x)?);
+        }
+        if let Some(x) = var("READ_TIMEOUT_MS") {
+            self.read_timeout_ms = Some(parse_value("READ_TIMEOUT_MS", &x)?);
+        }
+        if let Some(x) = var("REQUEST_TIMEOUT_MS") {
+            self.request_timeout_ms = Some(parse_value("REQUEST_TIMEOUT_MS", &x)?);
+        }
+        if let Some(x) = var("USER") {
+            self.user = Some(x);
+        }
+        if let Some(x) = var("GROUP") {
+            self.group = Some(x);
+        }
+        if let Some(x) = var("FIX_PERMISSIONS") {
+            self.fix_permissions = Some(parse_value("FIX_PERMISSIONS", &x)?);
+        }
+        if let Some(x) = var("SANDBOX") {
+            self.sandbox = Some(parse_value("SANDBOX", &x)?);
+        }
+        if let Some(x) = var("WORKER_THREADS") {
+            self.worker_threads = Some(parse_value("WORKER_THREADS", &x)?);
+        }
+        if let Some(x) = var("BLOCKING_THREADS") {
+            self.blocking_threads = Some(parse_value("BLOCKING_THREADS", &x)?);
+        }
+        if let Some(x) = var("MAX_IN_FLIGHT") {
+            self.max_in_flight = Some(parse_value("MAX_IN_FLIGHT", &x)?);
+        }
+        if let Some(x) = var("MAX_QUEUED_REQUESTS") {
+            self.max_queued_requests = Some(parse_value("MAX_QUEUED_REQUESTS", &x)?);
+        }
+        if let Some(x) = var("ADMIN_SOCKET_PATH") {
+            self.admin_socket_path = Some(x);
+        }
+        if let Some(x) = var("ADMIN_UIDS") {
+            self.admin_uids = parse_list("ADMIN_UIDS", &x)?;
+        }
+        if let Some(x) = var("ADMIN_TOKEN") {
+            self.admin_token = Some(x);
+        }
+        if let Some(x) = var("FLUSH_EVERY_MS") {
+            self.flush_every_ms = Some(parse_value("FLUSH_EVERY_MS", &x)?);
+        }
+        if let Some(x) = var("SNAPSHOT_AFTER_OPS") {
+            self.snapshot_after_ops = Some(parse_value("SNAPSHOT_AFTER_OPS", &x)?);
+        }
+        if let Some(x) = var("FLUSH_ON_APPEND") {
+            self.flush_on_append = Some(parse_value("FLUSH_ON_APPEND", &x)?);
+        }
+        if let Some(x) = var("GROUP_COMMIT_WINDOW_MS") {
+            self.group_commit_window_ms = Some(parse_value("GROUP_COMMIT_WINDOW_MS", &x)?);
+        }
+        if let Some(x) = var("REPLICAS") {
+            self.replicas = parse_list("REPLICAS", &x)?;
+        }
+        if let Some(x) = var("REPLICATION_LISTEN") {
+            self.replication_listen = Some(x);
+        }
+        if let Some(x) = var("REPLICATION_KEY_PATHS") {
+            self.replication_key_paths = parse_list("REPLICATION_KEY_PATHS", &x)?;
+        }
+        if let Some(x) = var("MAX_REPLICATION_BACKLOG") {
+            self.max_replication_backlog = Some(parse_value("MAX_REPLICATION_BACKLOG", &x)?);
+        }
+        if let Some(x) = var("STANDBY") {
+            self.standby = Some(parse_value("STANDBY", &x)?);
+        }
+        Ok(())
+    }
+
+    /// Applies the runtime tunables from `other`, leaving the
+    /// settings which require a restart untouched.
+    pub fn apply_tunables(&mut self, other: &Config) {
+        self.allowed_uids = other.allowed_uids.clone();
+        self.allowed_gids = other.allowed_gids.clone();
+        self.drain_timeout_ms = other.drain_timeout_ms;
+        self.log_level = other.log_level.clone();
+        self.max_spools = other.max_spools;
+        self.max_response_size = other.max_response_size;
+        self.max_request_size = other.max_request_size;
+        self.read_timeout_ms = other.read_timeout_ms;
+        self.request_timeout_ms = other.request_timeout_ms;
+        self.compression_level = other.compression_level;
+        self.master_key_paths = other.master_key_paths.clone();
+        self.append_pow_difficulty = other.append_pow_difficulty;
+        self.rate_limits = other.rate_limits.clone();
+        self.dedup_cache_size = other.dedup_cache_size;
+        self.max_signature_failures = other.max_signature_failures;
+        self.signature_backoff_ms = other.signature_backoff_ms;
+        self.purge_grace_period_secs = other.purge_grace_period_secs;
+        self.inactive_spool_secs = other.inactive_spool_secs;
+        self.stats_log_secs = other.stats_log_secs;
+        self.compaction_secs = other.compaction_secs;
+        self.min_free_bytes = other.min_free_bytes;
+        self.strict_requests = other.strict_requests;
+        self.debug_json = other.debug_json;
+        self.admin_uids = other.admin_uids.clone();
+        self.admin_token = other.admin_token.clone();
+        self.flush_on_append = other.flush_on_append;
+    }
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+    value.trim().parse::<T>().map_err(|_| ConfigError::InvalidEnvironmentVariable(format!("{}{}", ENV_PREFIX, key)))
+}
+
+fn parse_list<T: FromStr>(key: &str, value: &str) -> Result<Vec<T>, ConfigError> {
+    value.split(',')
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| parse_value(key, x))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    #[test]
+    fn env_override_test() {
+        let mut vars = HashMap::new();
+        vars.insert("MULTISPOOL_DATA_DIR", "/var/lib/multispool");
+        vars.insert("MULTISPOOL_SHARD_DIRS", "/mnt/disk1,/mnt/disk2");
+        vars.insert("MULTISPOOL_ALLOWED_UIDS", "1000, 1001");
+        vars.insert("MULTISPOOL_DRAIN_TIMEOUT_MS", "250");
+        vars.insert("MULTISPOOL_MAX_SPOOLS", "100");
+        let mut cfg = Config::default();
+        cfg.log_dir = Some(String::from("/var/log/multispool"));
+        cfg.apply_env_from(|name| vars.get(name).map(|x| x.to_string())).unwrap();
+        assert_eq!(cfg.data_dir, Some(String::from("/var/lib/multispool")));
+        assert_eq!(cfg.shard_dirs, vec![String::from("/mnt/disk1"), String::from("/mnt/disk2")]);
+        assert_eq!(cfg.log_dir, Some(String::from("/var/log/multispool")));
+        assert_eq!(cfg.allowed_uids, vec![1000, 1001]);
+        assert_eq!(cfg.drain_timeout_ms, Some(250));
+        assert_eq!(cfg.max_spools, Some(100));
+    }
+
+    #[test]
+    fn max_request_size_test() {
+        let mut cfg = Config::default();
+        assert_eq!(cfg.max_request_size(), MESSAGE_SIZE + REQUEST_SIZE_OVERHEAD);
+        cfg.max_message_size = Some(1000);
+        assert_eq!(cfg.max_request_size(), 1000 + REQUEST_SIZE_OVERHEAD);
+        let mut vars = HashMap::new();
+        vars.insert("MULTISPOOL_MAX_REQUEST_SIZE", "4096");
+        cfg.apply_env_from(|name| vars.get(name).map(|x| x.to_string())).unwrap();
+        assert_eq!(cfg.max_request_size(), 4096);
+    }
+
+    #[test]
+    fn timeouts_test() {
+        let mut cfg = Config::default();
+        assert_eq!(cfg.read_timeout(), Duration::from_millis(DEFAULT_READ_TIMEOUT_MS));
+        assert_eq!(cfg.request_timeout(), Duration::from_millis(DEFAULT_REQUEST_TIMEOUT_MS));
+        let mut vars = HashMap::new();
+        vars.insert("MULTISPOOL_READ_TIMEOUT_MS", "100");
+        vars.insert("MULTISPOOL_REQUEST_TIMEOUT_MS", "200");
+        cfg.apply_env_from(|name| vars.get(name).map(|x| x.to_string())).unwrap();
+        assert_eq!(cfg.read_timeout(), Duration::from_millis(100));
+        assert_eq!(cfg.request_timeout(), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn stats_log_period_test() {
+        let mut cfg = Config::default();
+        assert_eq!(cfg.stats_log_period(), Some(Duration::from_secs(DEFAULT_STATS_LOG_SECS)));
+        cfg.stats_log_secs = Some(0);
+        assert_eq!(cfg.stats_log_period(), None);
+        let mut vars = HashMap::new();
+        vars.insert("MULTISPOOL_STATS_LOG_SECS", "60");
+        cfg.apply_env_from(|name| vars.get(name).map(|x| x.to_string())).unwrap();
+        assert_eq!(cfg.stats_log_period(), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn compaction_period_test() {
+        let mut cfg = Config::default();
+        assert_eq!(cfg.compaction_period(), Some(Duration::from_secs(DEFAULT_COMPACTION_SECS)));
+        cfg.compaction_secs = Some(0);
+        assert_eq!(cfg.compaction_period(), None);
+        let mut vars = HashMap::new();
+        vars.insert("MULTISPOOL_COMPACTION_SECS", "600");
+        cfg.apply_env_from(|name| vars.get(name).map(|x| x.to_string())).unwrap();
+        assert_eq!(cfg.compaction_period(), Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn worker_threads_test() {
+        let mut cfg = Config::default();
+        assert!(cfg.worker_threads() >= 1);
+        cfg.worker_threads = Some(0);
+        assert!(cfg.worker_threads() >= 1);
+        let mut vars = HashMap::new();
+        vars.insert("MULTISPOOL_WORKER_THREADS", "3");
+        vars.insert("MULTISPOOL_BLOCKING_THREADS", "8");
+        cfg.apply_env_from(|name| vars.get(name).map(|x| x.to_string())).unwrap();
+        assert_eq!(cfg.worker_threads(), 3);
+        assert_eq!(cfg.blocking_threads, Some(8));
+    }
+
+    #[test]
+    fn durability_test() {
+        let mut cfg = Config::default();
+        assert_eq!(cfg.durability(), Durability::default());
+        let mut vars = HashMap::new();
+        vars.insert("MULTISPOOL_FLUSH_EVERY_MS", "0");
+        vars.insert("MULTISPOOL_FLUSH_ON_APPEND", "true");
+        cfg.apply_env_from(|name| vars.get(name).map(|x| x.to_string())).unwrap();
+        let durability = cfg.durability();
+        assert_eq!(durability.flush_every_ms, None);
+        assert_eq!(durability.snapshot_after_ops, DEFAULT_SNAPSHOT_AFTER_OPS);
+        assert!(durability.flush_on_append);
+    }
+
+    #[test]
+    fn replication_test() {
+        let mut cfg = Config::default();
+        assert!(cfg.replicas.is_empty());
+        assert!(cfg.replication_keyring().unwrap().is_none());
+        assert_eq!(cfg.max_replication_backlog(), DEFAULT_MAX_REPLICATION_BACKLOG);
+        let mut vars = HashMap::new();
+        vars.insert("MULTISPOOL_REPLICAS", "10.0.0.2:7000,10.0.0.3:7000");
+        vars.insert("MULTISPOOL_MAX_REPLICATION_BACKLOG", "10");
+        vars.insert("MULTISPOOL_STANDBY", "true");
+        assert!(!cfg.standby());
+        cfg.apply_env_from(|name| vars.get(name).map(|x| x.to_string())).unwrap();
+        assert!(cfg.standby());
+        assert_eq!(cfg.replicas, vec!["10.0.0.2:7000".to_string(), "10.0.0.3:7000".to_string()]);
+        assert_eq!(cfg.max_replication_backlog(), 10);
+    }
+
+    #[test]
+    fn rate_limits_test() {
+        let cfg: Config = toml::from_str(r#"
+            [rate_limits.append]
+            per_spool = { rate = 10.0, burst = 20 }
+            global = { rate = 1000.0, burst = 2000 }
+        "#).unwrap();
+        cfg.check_rate_limits().unwrap();
+        let limits = &cfg.rate_limits["append"];
+        assert_eq!(limits.per_spool.as_ref().unwrap().burst, 20);
+        assert_eq!(limits.global.as_ref().unwrap().rate, 1000.0);
+
+        let cfg: Config = toml::from_str(r#"
+            [rate_limits.appends]
+            global = { rate = 1.0, burst = 1 }
+        "#).unwrap();
+        assert!(cfg.check_rate_limits().is_err());
+        let cfg: Config = toml::from_str(r#"
+            [rate_limits.read]
+            per_spool = { rate = 0.0, burst = 1 }
+        "#).unwrap();
+        assert!(cfg.check_rate_limits().is_err());
+    }
+
+    #[test]
+    fn env_override_invalid_test() {
+        let mut cfg = Config::default();
+        assert!(cfg.apply_env_from(|name| {
+            if name == "MULTISPOOL_ALLOWED_GIDS" {
+                return Some(String::from("wheel"))
+            }
+            None
+        }).is_err());
+    }
+} // tests
@@ -0,0 +1,221 @@
+// debug.rs - JSON debug transport.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! JSON debug transport
+//!
+//! With `debug_json` set the service also takes spool requests as JSON
+//! on `POST /debug/request`, so that operators can try it out with
+//! curl. The JSON request has the fields of a `SpoolRequest` plus the
+//! `RequestID` of the plugin request, with byte strings in standard
+//! base64 and `Command` given by name, e.g.:
+//!
+//! ```text
+//! {"Command": "read", "SpoolID": "...", "Signature": "...", "MessageID": "AAAAAQ=="}
+//! ```
+//!
+//! It is handled exactly like a CBOR request, and the response is
+//! returned as JSON in the same way, less its padding.
+
+use byteorder::{ByteOrder, BigEndian};
+use serde_cbor;
+use serde_json::{self, Value};
+
+use crate::errors::DebugError;
+use crate::merkle::ReadProof;
+use crate::options::SpoolOptions;
+use crate::version::ProtocolVersion;
+use crate::{SpoolRequest, SpoolResponse, command_name, SUPPORTED_COMMANDS};
+
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+#[allow(non_snake_case)]
+struct JsonOptions {
+    Capacity: u32,
+    Circular: bool,
+    TTL: u64,
+    Appenders: Vec<String>,
+    Readers: Vec<String>,
+    HashIndex: bool,
+    DedupWindow: u32,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+#[allow(non_snake_case)]
+struct JsonRequest {
+    RequestID: u64,
+    Command: String,
+    SpoolID: String,
+    Signature: String,
+    PublicKey: String,
+    MessageID: String,
+    Message: String,
+    WantProof: bool,
+    ProofOfWork: String,
+    AppendToken: String,
+    IdempotencyKey: String,
+    DurableAppend: bool,
+    Options: Option<JsonOptions>,
+    Version: Option<ProtocolVersion>,
+}
+
+fn decode_field(name: &str, encoded: &str) -> Result<Vec<u8>, DebugError> {
+    base64::decode(encoded).map_err(|_| DebugError::InvalidBase64(name.to_string()))
+}
+
+fn decode_keys(name: &str, encoded: &[String]) -> Result<Vec<serde_bytes::ByteBuf>, DebugError> {
+    encoded.iter().map(|x| decode_field(name, x).map(serde_bytes::ByteBuf::from)).collect()
+}
+
+/// Decodes a JSON spool request, returning the plugin request ID it
+/// was given along with it.
+pub fn decode_request(body: &[u8]) -> Result<(u64, SpoolRequest), DebugError> {
+    let json: JsonRequest = serde_json::from_slice(body)?;
+    let command = SUPPORTED_COMMANDS.iter().cloned()
+        .find(|x| command_name(*x) == json.Command)
+        .ok_or_else(|| DebugError::UnknownCommand(json.Command.clone()))?;
+    let options = match json.Options {
+        Some(options) => Some(SpoolOptions {
+            Capacity: options.Capacity,
+            Circular: options.Circular,
+            TTL: options.TTL,
+            Appenders: decode_keys("Appenders", &options.Appenders)?,
+            Readers: decode_keys("Readers", &options.Readers)?,
+            HashIndex: options.HashIndex,
+            DedupWindow: options.DedupWindow,
+        }),
+        None => None,
+    };
+    let request = SpoolRequest {
+        Command: command,
+        SpoolID: decode_field("SpoolID", &json.SpoolID)?,
+        Signature: decode_field("Signature", &json.Signature)?,
+        PublicKey: decode_field("PublicKey", &json.PublicKey)?,
+        MessageID: decode_field("MessageID", &json.MessageID)?,
+        Message: decode_field("Message", &json.Message)?,
+        WantProof: json.WantProof,
+        ProofOfWork: decode_field("ProofOfWork", &json.ProofOfWork)?,
+        AppendToken: decode_field("AppendToken", &json.AppendToken)?,
+        IdempotencyKey: decode_field("IdempotencyKey", &json.IdempotencyKey)?,
+        DurableAppend: json.DurableAppend,
+        Options: options,
+        Version: json.Version,
+    };
+    Ok((json.RequestID, request))
+}
+
+/// Encodes a spool request as a plugin request payload, the way
+/// clients send it.
+pub fn encode_payload(request: &SpoolRequest) -> Result<Vec<u8>, DebugError> {
+    let raw_request = serde_cbor::to_vec(request)?;
+    let mut payload = vec![0u8; 4];
+    BigEndian::write_u32(&mut payload, raw_request.len() as u32);
+    payload.extend_from_slice(&raw_request);
+    Ok(payload)
+}
+
+fn proof_json(proof: &ReadProof) -> Value {
+    let audit_path: Vec<String> = proof.AuditPath.iter().map(base64::encode).collect();
+    json!({
+        "TreeSize": proof.TreeSize,
+        "LeafIndex": proof.LeafIndex,
+        "AuditPath": audit_path,
+        "Root": base64::encode(&proof.Root),
+        "RootSignature": base64::encode(&proof.RootSignature),
+    })
+}
+
+/// Returns the JSON form of a spool response, leaving out its
+/// padding.
+pub fn response_json(response: &SpoolResponse) -> Value {
+    json!({
+        "SpoolID": base64::encode(&response.SpoolID),
+        "Message": base64::encode(&response.Message),
+        "Status": response.Status,
+        "RequestID": response.RequestID,
+        "Command": response.Command.map(command_name),
+        "MessageID": base64::encode(&response.MessageID),
+        "Version": response.Version.as_ref().map(|x| x.to_string()),
+        "Proof": response.Proof.as_ref().map(proof_json),
+        "AppendedAt": response.AppendedAt,
+        "Remaining": response.Remaining,
+        "Existing": response.Existing,
+        "ResponseSignature": base64::encode(&response.ResponseSignature),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::spool::SPOOL_ID_SIZE;
+    use crate::{spool_request_payload, RETRIEVE_MESSAGE_COMMAND, CREATE_SPOOL_COMMAND};
+    use super::*;
+
+    #[test]
+    fn decode_request_test() {
+        let body = json!({
+            "RequestID": 7,
+            "Command": "read",
+            "SpoolID": base64::encode(&[1u8; SPOOL_ID_SIZE]),
+            "MessageID": "AAAAAQ==",
+            "WantProof": true,
+        });
+        let (request_id, request) = decode_request(body.to_string().as_bytes()).unwrap();
+        assert_eq!(request_id, 7);
+        assert_eq!(request.Command, RETRIEVE_MESSAGE_COMMAND);
+        assert_eq!(request.SpoolID, vec![1u8; SPOOL_ID_SIZE]);
+        assert_eq!(request.MessageID, vec![0, 0, 0, 1]);
+        assert!(request.WantProof);
+        let payload = encode_payload(&request).unwrap();
+        let decoded: SpoolRequest = serde_cbor::from_slice(spool_request_payload(&payload).unwrap()).unwrap();
+        assert_eq!(decoded.MessageID, request.MessageID);
+
+        let body = json!({
+            "Command": "create",
+            "Options": { "Capacity": 3, "Readers": [base64::encode(&[2u8; 32])] },
+        });
+        let (_, request) = decode_request(body.to_string().as_bytes()).unwrap();
+        assert_eq!(request.Command, CREATE_SPOOL_COMMAND);
+        let options = request.Options.unwrap();
+        assert_eq!(options.Capacity, 3);
+        assert_eq!(options.Readers[0].to_vec(), vec![2u8; 32]);
+
+        assert!(decode_request(br#"{"Command": "frobnicate"}"#).is_err());
+        assert!(decode_request(br#"{"Command": "read", "SpoolID": "not base64!"}"#).is_err());
+        assert!(decode_request(br#"{"Command": "read", "Spool": ""}"#).is_err());
+        assert!(decode_request(b"not json").is_err());
+    }
+
+    #[test]
+    fn response_json_test() {
+        let response = SpoolResponse {
+            Message: b"hello".to_vec(),
+            Status: "OK".to_string(),
+            Command: Some(RETRIEVE_MESSAGE_COMMAND),
+            Version: Some(ProtocolVersion::current()),
+            Proof: Some(ReadProof::default()),
+            Padding: vec![0u8; 100],
+            ..SpoolResponse::default()
+        };
+        let json = response_json(&response);
+        assert_eq!(json["Message"], "aGVsbG8=");
+        assert_eq!(json["Command"], "read");
+        assert_eq!(json["Version"], ProtocolVersion::current().to_string());
+        assert_eq!(json["Proof"]["TreeSize"], 0);
+        assert!(json.get("Padding").is_none());
+    }
+}
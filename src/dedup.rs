@@ -0,0 +1,142 @@
+// dedup.rs - Multi-spool append deduplication.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Idempotency key deduplication
+//!
+//! Mixnet retransmissions can deliver the same append more than once.
+//! Clients may send a random idempotency key with an append, which is
+//! remembered per spool once the append succeeds, so that retries
+//! carrying the same key are answered without appending again. Only
+//! the most recent keys are remembered.
+
+use std::collections::{HashSet, VecDeque};
+
+use crate::spool::SPOOL_ID_SIZE;
+
+
+/// The size of the idempotency keys clients generate, in bytes.
+pub const IDEMPOTENCY_KEY_SIZE: usize = 16;
+
+/// The largest idempotency key a client may send, in bytes.
+pub const MAX_IDEMPOTENCY_KEY_SIZE: usize = 32;
+
+/// The number of idempotency keys remembered by default.
+pub const DEFAULT_DEDUP_CACHE_SIZE: usize = 100000;
+
+/// DedupCache remembers the most recent idempotency keys of each
+/// spool, forgetting the oldest once it holds `capacity` keys.
+#[derive(Clone)]
+pub struct DedupCache {
+    capacity: usize,
+    keys: HashSet<Vec<u8>>,
+    order: VecDeque<Vec<u8>>,
+}
+
+fn cache_key(spool_id: &[u8; SPOOL_ID_SIZE], key: &[u8]) -> Vec<u8> {
+    let mut cache_key = spool_id.to_vec();
+    cache_key.extend_from_slice(key);
+    cache_key
+}
+
+impl DedupCache {
+    pub fn new(capacity: usize) -> DedupCache {
+        DedupCache {
+            capacity: capacity,
+            keys: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Changes the number of keys remembered, forgetting the oldest
+    /// ones if there are too many.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// Returns true if `key` was seen for the spool.
+    pub fn contains(&self, spool_id: &[u8; SPOOL_ID_SIZE], key: &[u8]) -> bool {
+        self.keys.contains(&cache_key(spool_id, key))
+    }
+
+    /// Remembers `key` for the spool.
+    pub fn insert(&mut self, spool_id: &[u8; SPOOL_ID_SIZE], key: &[u8]) {
+        let cache_key = cache_key(spool_id, key);
+        if self.keys.insert(cache_key.clone()) {
+            self.order.push_back(cache_key);
+            self.evict();
+        }
+    }
+
+    /// Forgets every key of a purged spool.
+    pub fn remove_spool(&mut self, spool_id: &[u8; SPOOL_ID_SIZE]) {
+        let keys = &mut self.keys;
+        self.order.retain(|x| {
+            if x[..SPOOL_ID_SIZE] == spool_id[..] {
+                keys.remove(x);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    fn evict(&mut self) {
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedup_cache_test() {
+        let alice = [1u8; SPOOL_ID_SIZE];
+        let bob = [2u8; SPOOL_ID_SIZE];
+        let mut cache = DedupCache::new(2);
+        cache.insert(&alice, b"one");
+        assert!(cache.contains(&alice, b"one"));
+        assert!(!cache.contains(&bob, b"one"));
+
+        // Inserting a key twice doesn't push out older keys.
+        cache.insert(&alice, b"one");
+        cache.insert(&bob, b"one");
+        assert_eq!(cache.len(), 2);
+        cache.insert(&alice, b"two");
+        assert!(!cache.contains(&alice, b"one"));
+        assert!(cache.contains(&bob, b"one"));
+
+        cache.remove_spool(&alice);
+        assert!(!cache.contains(&alice, b"two"));
+        assert_eq!(cache.len(), 1);
+        cache.set_capacity(0);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+// disk.rs - Free disk space monitoring.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Free disk space monitoring
+//!
+//! Sled fails in unpredictable places when its disk fills up, perhaps
+//! halfway through a spool creation. So the service refuses creations
+//! and appends once the free space left in the directories they write
+//! to drops below a threshold, leaving room for reads, purges and
+//! compactions to go on.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+
+/// The default free space in bytes below which creations and appends
+/// are refused, 100 MiB.
+pub const DEFAULT_MIN_FREE_BYTES: u64 = 100 * 1024 * 1024;
+
+/// How long a directory's free space is remembered for before it is
+/// checked again.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Returns the number of bytes left for unprivileged users on the
+/// filesystem `path` is on.
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+/// DiskMonitor tells whether directories have enough free space left
+/// to write to, checking each at most once every CHECK_INTERVAL.
+#[derive(Debug, Default)]
+pub struct DiskMonitor {
+    min_free_bytes: Mutex<u64>,
+    checked: Mutex<HashMap<PathBuf, (Instant, u64)>>,
+}
+
+impl DiskMonitor {
+    /// Creates a monitor refusing writes once less than
+    /// `min_free_bytes` are left, never if zero.
+    pub fn new(min_free_bytes: u64) -> DiskMonitor {
+        DiskMonitor {
+            min_free_bytes: Mutex::new(min_free_bytes),
+            checked: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_min_free_bytes(&self, min_free_bytes: u64) {
+        *self.min_free_bytes.lock().unwrap_or_else(PoisonError::into_inner) = min_free_bytes;
+    }
+
+    /// Returns true if the filesystem `dir` is on has at least the
+    /// minimum free space left. A directory whose free space can't be
+    /// found out is assumed to have room.
+    pub fn has_room(&self, dir: &Path) -> bool {
+        let min_free_bytes = *self.min_free_bytes.lock().unwrap_or_else(PoisonError::into_inner);
+        if min_free_bytes == 0 {
+            return true
+        }
+        let mut checked = self.checked.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        let free = match checked.get(dir) {
+            Some(&(at, free)) if now.duration_since(at) < CHECK_INTERVAL => free,
+            _ => match free_space(dir) {
+                Ok(free) => {
+                    checked.insert(dir.to_path_buf(), (now, free));
+                    free
+                },
+                Err(e) => {
+                    warn!("failed to find the free space in {}: {}", dir.display(), e);
+                    return true
+                },
+            },
+        };
+        free >= min_free_bytes
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempdir;
+    use super::*;
+
+    #[test]
+    fn disk_monitor_test() {
+        let dir = tempdir().unwrap();
+        assert!(free_space(dir.path()).unwrap() > 0);
+        assert!(free_space(&dir.path().join("missing")).is_err());
+
+        let monitor = DiskMonitor::new(0);
+        assert!(monitor.has_room(dir.path()));
+        monitor.set_min_free_bytes(u64::max_value());
+        assert!(!monitor.has_room(dir.path()));
+        assert!(monitor.has_room(&dir.path().join("missing")));
+        monitor.set_min_free_bytes(1);
+        assert!(monitor.has_room(dir.path()));
+    }
+}
@@ -0,0 +1,305 @@
+// encryption.rs - Multi-spool at-rest encryption.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! At-rest encryption with server master keys and per-spool keys.
+//!
+//! Every spool has a random secret from which its storage key is
+//! derived with HKDF-SHA256, bound to the spool ID and owner key.
+//! Spool secrets and owner keys are sealed with the master keys and
+//! messages with their spool's key, so destroying a spool's secret
+//! makes its messages unreadable. Data sealed with an old master key
+//! stays readable as long as the key is kept in the keyring, and is
+//! re-encrypted with the current key the next time it is read.
+
+use std::fs;
+use std::path::Path;
+use rand::{thread_rng, Rng};
+use blake2::{Blake2b, Digest};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::aead::generic_array::GenericArray;
+
+use crate::errors::EncryptionError;
+
+
+/// Master key size in bytes.
+pub const KEY_SIZE: usize = 32;
+
+/// Master key identity size in bytes.
+pub const KEY_ID_SIZE: usize = 4;
+
+/// XChaCha20-Poly1305 nonce size in bytes.
+const NONCE_SIZE: usize = 24;
+
+/// Poly1305 tag size in bytes.
+const TAG_SIZE: usize = 16;
+
+/// The number of bytes sealing with a master key adds to a plaintext.
+pub const SEAL_OVERHEAD: usize = KEY_ID_SIZE + NONCE_SIZE + TAG_SIZE;
+
+/// The number of bytes sealing with a spool key adds to a plaintext.
+pub const SPOOL_SEAL_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+/// The HKDF info prefix spool keys are derived with.
+const SPOOL_KEY_INFO: &[u8] = b"multispool spool key v1";
+
+/// The HKDF info prefix the secret spool lookups are keyed with is
+/// derived with.
+const LOOKUP_KEY_INFO: &[u8] = b"multispool lookup key v1";
+
+/// The HKDF info lookup keys are expanded with.
+const LOOKUP_INFO: &[u8] = b"multispool lookup v1";
+
+/// Encrypts `plaintext` and `aad` with a random nonce, returning the
+/// nonce and ciphertext after `prefix`.
+fn seal(cipher: &XChaCha20Poly1305, prefix: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    thread_rng().fill(&mut nonce);
+    let payload = Payload {
+        msg: plaintext,
+        aad: aad,
+    };
+    let ciphertext = cipher.encrypt(GenericArray::from_slice(&nonce), payload)
+        .map_err(|_| EncryptionError::EncryptionFailed)?;
+    let mut sealed = Vec::with_capacity(prefix.len() + NONCE_SIZE + ciphertext.len());
+    sealed.extend_from_slice(prefix);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Decrypts the nonce and ciphertext made by `seal`.
+fn open(cipher: &XChaCha20Poly1305, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+    if sealed.len() < NONCE_SIZE + TAG_SIZE {
+        return Err(EncryptionError::DecryptionFailed)
+    }
+    let payload = Payload {
+        msg: &sealed[NONCE_SIZE..],
+        aad: aad,
+    };
+    cipher.decrypt(GenericArray::from_slice(&sealed[..NONCE_SIZE]), payload)
+        .map_err(|_| EncryptionError::DecryptionFailed)
+}
+
+/// MasterKey is a server master key and its identity, the truncated
+/// BLAKE2b hash of the key.
+pub struct MasterKey {
+    id: [u8; KEY_ID_SIZE],
+    cipher: XChaCha20Poly1305,
+}
+
+impl MasterKey {
+    pub fn from_bytes(key: &[u8]) -> Result<MasterKey, EncryptionError> {
+        if key.len() != KEY_SIZE {
+            return Err(EncryptionError::InvalidKeySize(key.len()))
+        }
+        let mut id = [0u8; KEY_ID_SIZE];
+        id.copy_from_slice(&Blake2b::digest(key)[..KEY_ID_SIZE]);
+        Ok(MasterKey {
+            id: id,
+            cipher: XChaCha20Poly1305::new(GenericArray::from_slice(key)),
+        })
+    }
+
+    /// Loads a master key from a file holding exactly KEY_SIZE bytes.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<MasterKey, EncryptionError> {
+        MasterKey::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn id(&self) -> [u8; KEY_ID_SIZE] {
+        self.id
+    }
+}
+
+/// Keyring holds the current master key, which seals new data, and
+/// any old master keys which may still be needed to open data.
+pub struct Keyring {
+    current: MasterKey,
+    old: Vec<MasterKey>,
+}
+
+impl Keyring {
+    pub fn new(current: MasterKey, old: Vec<MasterKey>) -> Keyring {
+        Keyring {
+            current: current,
+            old: old,
+        }
+    }
+
+    /// Loads a keyring from key files. The first key is the current
+    /// key and the rest are old keys.
+    pub fn load<P: AsRef<Path>>(paths: &[P]) -> Result<Keyring, EncryptionError> {
+        let mut keys = paths.iter().map(MasterKey::load).collect::<Result<Vec<_>, _>>()?;
+        if keys.is_empty() {
+            return Err(EncryptionError::NoKeys)
+        }
+        let current = keys.remove(0);
+        Ok(Keyring::new(current, keys))
+    }
+
+    /// Returns the identity of the current master key.
+    pub fn current_id(&self) -> [u8; KEY_ID_SIZE] {
+        self.current.id
+    }
+
+    /// Encrypts and authenticates `plaintext` and `aad` with the
+    /// current master key, returning the key identity, nonce and
+    /// ciphertext.
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        seal(&self.current.cipher, &self.current.id, plaintext, aad)
+    }
+
+    /// Decrypts data sealed with any key in the keyring, checking
+    /// that it was sealed along with `aad`.
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return Err(EncryptionError::DecryptionFailed)
+        }
+        let mut id = [0u8; KEY_ID_SIZE];
+        id.copy_from_slice(&sealed[..KEY_ID_SIZE]);
+        match self.find(id) {
+            Some(key) => open(&key.cipher, &sealed[KEY_ID_SIZE..], aad),
+            None => Err(EncryptionError::UnknownKey(id)),
+        }
+    }
+
+    /// Returns true if `sealed` was sealed with the current key and
+    /// so needs no re-encryption.
+    pub fn is_current(&self, sealed: &[u8]) -> bool {
+        sealed.len() >= KEY_ID_SIZE && sealed[..KEY_ID_SIZE] == self.current.id
+    }
+
+    fn find(&self, id: [u8; KEY_ID_SIZE]) -> Option<&MasterKey> {
+        if self.current.id == id {
+            return Some(&self.current)
+        }
+        self.old.iter().find(|x| x.id == id)
+    }
+}
+
+/// SpoolKey is the key a spool's messages are encrypted with.
+pub struct SpoolKey {
+    cipher: XChaCha20Poly1305,
+    lookup_secret: [u8; KEY_SIZE],
+}
+
+impl SpoolKey {
+    /// Generates a new random spool secret.
+    pub fn generate_secret() -> [u8; KEY_SIZE] {
+        let mut secret = [0u8; KEY_SIZE];
+        thread_rng().fill(&mut secret);
+        secret
+    }
+
+    /// Derives a spool's key from its secret, binding it to the spool
+    /// ID and the owner's public key.
+    pub fn derive(secret: &[u8], spool_id: &[u8], owner_key: &[u8]) -> Result<SpoolKey, EncryptionError> {
+        if secret.len() != KEY_SIZE {
+            return Err(EncryptionError::InvalidKeySize(secret.len()))
+        }
+        let hkdf = Hkdf::<Sha256>::new(Some(spool_id), secret);
+        let mut info = SPOOL_KEY_INFO.to_vec();
+        info.extend_from_slice(spool_id);
+        info.extend_from_slice(owner_key);
+        let mut key = [0u8; KEY_SIZE];
+        hkdf.expand(&info, &mut key)
+            .map_err(|_| EncryptionError::InvalidKeySize(KEY_SIZE))?;
+        let mut info = LOOKUP_KEY_INFO.to_vec();
+        info.extend_from_slice(spool_id);
+        info.extend_from_slice(owner_key);
+        let mut lookup_secret = [0u8; KEY_SIZE];
+        hkdf.expand(&info, &mut lookup_secret)
+            .map_err(|_| EncryptionError::InvalidKeySize(KEY_SIZE))?;
+        Ok(SpoolKey {
+            cipher: XChaCha20Poly1305::new(GenericArray::from_slice(&key)),
+            lookup_secret: lookup_secret,
+        })
+    }
+
+    /// Returns the keyed hash `data` is looked up under in the
+    /// spool's indexes, so that they don't give away what is looked
+    /// up.
+    pub fn lookup_key(&self, data: &[u8]) -> Result<[u8; KEY_SIZE], EncryptionError> {
+        let mut key = [0u8; KEY_SIZE];
+        Hkdf::<Sha256>::new(Some(&self.lookup_secret), data).expand(LOOKUP_INFO, &mut key)
+            .map_err(|_| EncryptionError::InvalidKeySize(KEY_SIZE))?;
+        Ok(key)
+    }
+
+    /// Encrypts and authenticates `plaintext` and `aad`, returning
+    /// the nonce and ciphertext.
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        seal(&self.cipher, &[], plaintext, aad)
+    }
+
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        open(&self.cipher, sealed, aad)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_open_rotation_test() {
+        let old = Keyring::new(MasterKey::from_bytes(&[1u8; KEY_SIZE]).unwrap(), vec![]);
+        let sealed = old.seal(b"hello", b"aad").unwrap();
+        assert_eq!(sealed.len(), SEAL_OVERHEAD + 5);
+        assert_eq!(old.open(&sealed, b"aad").unwrap(), b"hello".to_vec());
+        assert!(old.open(&sealed, b"other aad").is_err());
+        assert!(old.is_current(&sealed));
+
+        // After rotation the old key still opens, but is not current.
+        let rotated = Keyring::new(MasterKey::from_bytes(&[2u8; KEY_SIZE]).unwrap(),
+                                   vec![MasterKey::from_bytes(&[1u8; KEY_SIZE]).unwrap()]);
+        assert_eq!(rotated.open(&sealed, b"aad").unwrap(), b"hello".to_vec());
+        assert!(!rotated.is_current(&sealed));
+        assert!(rotated.is_current(&rotated.seal(b"hello", b"aad").unwrap()));
+
+        // Once the old key is dropped the data can't be opened.
+        let new = Keyring::new(MasterKey::from_bytes(&[2u8; KEY_SIZE]).unwrap(), vec![]);
+        match new.open(&sealed, b"aad") {
+            Err(EncryptionError::UnknownKey(id)) => assert_eq!(id, old.current_id()),
+            _ => panic!("expected an unknown key"),
+        }
+        assert!(MasterKey::from_bytes(&[0u8; 16]).is_err());
+    }
+
+    #[test]
+    fn spool_key_test() {
+        let secret = SpoolKey::generate_secret();
+        let key = SpoolKey::derive(&secret, b"spool id", b"owner").unwrap();
+        let sealed = key.seal(b"hello", b"aad").unwrap();
+        assert_eq!(sealed.len(), SPOOL_SEAL_OVERHEAD + 5);
+        assert_eq!(key.open(&sealed, b"aad").unwrap(), b"hello".to_vec());
+
+        // The key is bound to the spool ID and owner as well as the secret.
+        let same = SpoolKey::derive(&secret, b"spool id", b"owner").unwrap();
+        assert_eq!(same.open(&sealed, b"aad").unwrap(), b"hello".to_vec());
+        assert!(SpoolKey::derive(&secret, b"spool id", b"other owner").unwrap().open(&sealed, b"aad").is_err());
+        assert!(SpoolKey::derive(&secret, b"other id", b"owner").unwrap().open(&sealed, b"aad").is_err());
+        assert!(SpoolKey::derive(&SpoolKey::generate_secret(), b"spool id", b"owner").unwrap().open(&sealed, b"aad").is_err());
+
+        // Lookup keys are stable for the same spool key only.
+        assert_eq!(key.lookup_key(b"hash").unwrap(), same.lookup_key(b"hash").unwrap());
+        assert_ne!(key.lookup_key(b"hash").unwrap(), key.lookup_key(b"other hash").unwrap());
+        assert_ne!(key.lookup_key(b"hash").unwrap(), SpoolKey::derive(&secret, b"other id", b"owner").unwrap().lookup_key(b"hash").unwrap());
+    }
+}
@@ -19,6 +19,12 @@ use std::error::Error;
 use std::io::Error as IoError;
 use sled::Error as SledError;
 use ed25519_dalek::SignatureError;
+use toml::de::Error as TomlError;
+use serde_cbor::error::Error as CborError;
+use serde_json::Error as JsonError;
+use rand::Error as RandError;
+
+use crate::version::ProtocolVersion;
 
 
 #[derive(Debug)]
@@ -28,6 +34,10 @@ pub enum SpoolError {
     IoError(IoError),
     NoSuchMessage,
     CorruptSpool,
+    MessageTooLarge(usize),
+    CorruptMessage,
+    EncryptionError(EncryptionError),
+    UnsupportedFormat(u8),
 }
 
 impl fmt::Display for SpoolError {
@@ -39,6 +49,10 @@ impl fmt::Display for SpoolError {
             IoError(x) => x.fmt(f),
             NoSuchMessage => write!(f, "No such message."),
             CorruptSpool => write!(f, "Corrupt spool."),
+            MessageTooLarge(x) => write!(f, "Message of {} bytes is too large.", x),
+            CorruptMessage => write!(f, "Corrupt message."),
+            EncryptionError(x) => x.fmt(f),
+            UnsupportedFormat(x) => write!(f, "Spool format version {} is newer than this build supports.", x),
         }
     }
 }
@@ -56,6 +70,10 @@ impl Error for SpoolError {
             IoError(x) => x.source(),
             NoSuchMessage => None,
             CorruptSpool => None,
+            MessageTooLarge(_) => None,
+            CorruptMessage => None,
+            EncryptionError(x) => x.source(),
+            UnsupportedFormat(_) => None,
         }
     }
 }
@@ -72,12 +90,23 @@ impl From<IoError> for SpoolError {
     }
 }
 
+impl From<EncryptionError> for SpoolError {
+    fn from(error: EncryptionError) -> Self {
+        SpoolError::EncryptionError(error)
+    }
+}
+
 #[derive(Debug)]
 pub enum SpoolSetError {
     CreateSpoolSetCacheFailed,
     SledError(SledError<()>),
     NoSuchSpoolId,
     SignatureError(SignatureError),
+    EncryptionError(EncryptionError),
+    UnsupportedFormat(u8),
+    CorruptFormatVersion,
+    CorruptPolicy,
+    OptionsError(OptionsError),
 }
 
 impl fmt::Display for SpoolSetError {
@@ -88,6 +117,11 @@ impl fmt::Display for SpoolSetError {
             SledError(x) => x.fmt(f),
             NoSuchSpoolId => write!(f, "Failed to find spool identity."),
             SignatureError(x) => x.fmt(f),
+            EncryptionError(x) => x.fmt(f),
+            UnsupportedFormat(x) => write!(f, "Spool set format version {} is newer than this build supports.", x),
+            CorruptFormatVersion => write!(f, "Corrupt spool set format version."),
+            CorruptPolicy => write!(f, "Corrupt spool policy."),
+            OptionsError(x) => x.fmt(f),
         }
     }
 }
@@ -104,6 +138,11 @@ impl Error for SpoolSetError {
             SledError(x) => x.source(),
             NoSuchSpoolId => None,
             SignatureError(_x) => None, // XXX no cause or source method available
+            EncryptionError(x) => x.source(),
+            UnsupportedFormat(_) => None,
+            CorruptFormatVersion => None,
+            CorruptPolicy => None,
+            OptionsError(x) => x.source(),
         }
     }
 }
@@ -120,6 +159,18 @@ impl From<SignatureError> for SpoolSetError {
     }
 }
 
+impl From<EncryptionError> for SpoolSetError {
+    fn from(error: EncryptionError) -> Self {
+        SpoolSetError::EncryptionError(error)
+    }
+}
+
+impl From<OptionsError> for SpoolSetError {
+    fn from(error: OptionsError) -> Self {
+        SpoolSetError::OptionsError(error)
+    }
+}
+
 #[derive(Debug)]
 pub enum MultiSpoolError {
     SpoolSetError(SpoolSetError),
@@ -128,6 +179,25 @@ pub enum MultiSpoolError {
     NoSuchSpool,
     SignatureError(SignatureError),
     IoError(IoError),
+    InvalidAppendToken,
+    SpentAppendToken,
+    InvalidIdempotencyKey,
+    AuditError(AuditError),
+    ArchiveError(ArchiveError),
+    SpoolExists,
+    RandError(RandError),
+    ReplicationError(ReplicationError),
+    NotPrimary,
+    AppendNotAllowed,
+    OptionsError(OptionsError),
+    SpoolFull,
+    NotReader,
+    StorageExhausted,
+    InvalidRekey,
+    NoHashIndex,
+    OptionsMismatch,
+    SignatureBackoff,
+    LostSpoolSet,
 }
 
 impl fmt::Display for MultiSpoolError {
@@ -140,6 +210,25 @@ impl fmt::Display for MultiSpoolError {
             NoSuchSpool => write!(f, "Error, no such spool."),
             SignatureError(x) => x.fmt(f),
             IoError(x) => x.fmt(f),
+            InvalidAppendToken => write!(f, "Error, invalid append token."),
+            SpentAppendToken => write!(f, "Error, append token already spent."),
+            InvalidIdempotencyKey => write!(f, "Error, invalid idempotency key."),
+            AuditError(x) => x.fmt(f),
+            ArchiveError(x) => x.fmt(f),
+            SpoolExists => write!(f, "Error, spool already exists."),
+            RandError(x) => x.fmt(f),
+            ReplicationError(x) => x.fmt(f),
+            NotPrimary => write!(f, "Error, not the primary."),
+            AppendNotAllowed => write!(f, "Error, appender not allowed."),
+            OptionsError(x) => x.fmt(f),
+            SpoolFull => write!(f, "Error, spool is full."),
+            NotReader => write!(f, "Error, not a reader of the spool."),
+            StorageExhausted => write!(f, "Error, storage exhausted."),
+            InvalidRekey => write!(f, "Error, invalid new owner key signature."),
+            NoHashIndex => write!(f, "Error, spool has no hash index."),
+            OptionsMismatch => write!(f, "Error, spool exists with other options."),
+            SignatureBackoff => write!(f, "Error, spool backing off after too many signature failures."),
+            LostSpoolSet => write!(f, "Error, spool databases found but the spool set has no spools."),
         }
     }
 }
@@ -158,6 +247,25 @@ impl Error for MultiSpoolError {
             NoSuchSpool => None,
             SignatureError(_x) => None, // XXX no cause or source method available
             IoError(x) => x.source(),
+            InvalidAppendToken => None,
+            SpentAppendToken => None,
+            InvalidIdempotencyKey => None,
+            AuditError(x) => x.source(),
+            ArchiveError(x) => x.source(),
+            SpoolExists => None,
+            RandError(x) => x.source(),
+            ReplicationError(x) => x.source(),
+            NotPrimary => None,
+            AppendNotAllowed => None,
+            OptionsError(x) => x.source(),
+            SpoolFull => None,
+            NotReader => None,
+            StorageExhausted => None,
+            InvalidRekey => None,
+            NoHashIndex => None,
+            OptionsMismatch => None,
+            SignatureBackoff => None,
+            LostSpoolSet => None,
         }
     }
 }
@@ -168,6 +276,12 @@ impl From<SpoolSetError> for MultiSpoolError {
     }
 }
 
+impl From<OptionsError> for MultiSpoolError {
+    fn from(error: OptionsError) -> Self {
+        MultiSpoolError::OptionsError(error)
+    }
+}
+
 impl From<SpoolError> for MultiSpoolError {
     fn from(error: SpoolError) -> Self {
         MultiSpoolError::SpoolError(error)
@@ -191,3 +305,874 @@ impl From<IoError> for MultiSpoolError {
         MultiSpoolError::IoError(error)
     }
 }
+
+impl From<AuditError> for MultiSpoolError {
+    fn from(error: AuditError) -> Self {
+        MultiSpoolError::AuditError(error)
+    }
+}
+
+impl From<ArchiveError> for MultiSpoolError {
+    fn from(error: ArchiveError) -> Self {
+        MultiSpoolError::ArchiveError(error)
+    }
+}
+
+impl From<RandError> for MultiSpoolError {
+    fn from(error: RandError) -> Self {
+        MultiSpoolError::RandError(error)
+    }
+}
+
+impl From<ReplicationError> for MultiSpoolError {
+    fn from(error: ReplicationError) -> Self {
+        MultiSpoolError::ReplicationError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(IoError),
+    TomlError(TomlError),
+    InvalidEnvironmentVariable(String),
+    InvalidLogLevel(String),
+    InvalidLogOutput(String),
+    InvalidSyslogFacility(String),
+    InvalidRateLimit(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ConfigError::*;
+        match self {
+            IoError(x) => x.fmt(f),
+            TomlError(x) => x.fmt(f),
+            InvalidEnvironmentVariable(x) => write!(f, "Invalid value for environment variable {}.", x),
+            InvalidLogLevel(x) => write!(f, "Invalid log level {}.", x),
+            InvalidLogOutput(x) => write!(f, "Invalid log output {}.", x),
+            InvalidSyslogFacility(x) => write!(f, "Invalid syslog facility {}.", x),
+            InvalidRateLimit(x) => write!(f, "Invalid rate limit for {}.", x),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        "I'm a ConfigError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::ConfigError::*;
+        match self {
+            IoError(x) => x.source(),
+            TomlError(x) => x.source(),
+            InvalidEnvironmentVariable(_) => None,
+            InvalidLogLevel(_) => None,
+            InvalidLogOutput(_) => None,
+            InvalidSyslogFacility(_) => None,
+            InvalidRateLimit(_) => None,
+        }
+    }
+}
+
+impl From<IoError> for ConfigError {
+    fn from(error: IoError) -> Self {
+        ConfigError::IoError(error)
+    }
+}
+
+impl From<TomlError> for ConfigError {
+    fn from(error: TomlError) -> Self {
+        ConfigError::TomlError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    CborError(CborError),
+    ProtobufError(ProtobufError),
+    MissingField(&'static str),
+    MessageTooLarge(usize),
+    InvalidResponse,
+    ServerError(String),
+    InvalidFragment,
+    InvalidSignature,
+    RateLimited,
+    Busy,
+    InvalidIdempotencyKey,
+    UnsupportedVersion(Option<ProtocolVersion>),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ClientError::*;
+        match self {
+            CborError(x) => x.fmt(f),
+            ProtobufError(x) => x.fmt(f),
+            MissingField(x) => write!(f, "Error, missing request field {}.", x),
+            MessageTooLarge(x) => write!(f, "Error, message of {} bytes is too large.", x),
+            InvalidResponse => write!(f, "Error, invalid response."),
+            ServerError(x) => write!(f, "Error, server replied: {}", x),
+            InvalidFragment => write!(f, "Error, invalid message fragment."),
+            InvalidSignature => write!(f, "Error, invalid response signature."),
+            RateLimited => write!(f, "Error, rate limited by the server."),
+            Busy => write!(f, "Error, the server is too busy to take the request."),
+            InvalidIdempotencyKey => write!(f, "Error, idempotency key too large."),
+            UnsupportedVersion(Some(x)) => write!(f, "Error, the server speaks protocol version {}, not ours.", x),
+            UnsupportedVersion(None) => write!(f, "Error, the server doesn't speak our protocol version."),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn description(&self) -> &str {
+        "I'm a ClientError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::ClientError::*;
+        match self {
+            CborError(x) => x.source(),
+            ProtobufError(x) => x.source(),
+            MissingField(_) => None,
+            MessageTooLarge(_) => None,
+            InvalidResponse => None,
+            ServerError(_) => None,
+            InvalidFragment => None,
+            InvalidSignature => None,
+            RateLimited => None,
+            Busy => None,
+            InvalidIdempotencyKey => None,
+            UnsupportedVersion(_) => None,
+        }
+    }
+}
+
+impl From<CborError> for ClientError {
+    fn from(error: CborError) -> Self {
+        ClientError::CborError(error)
+    }
+}
+
+impl From<ProtobufError> for ClientError {
+    fn from(error: ProtobufError) -> Self {
+        ClientError::ProtobufError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    CborError(CborError),
+    UnknownField(String),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RequestError::*;
+        match self {
+            CborError(x) => x.fmt(f),
+            UnknownField(x) => write!(f, "Error, unknown request field {}.", x),
+        }
+    }
+}
+
+impl Error for RequestError {
+    fn description(&self) -> &str {
+        "I'm a RequestError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::RequestError::*;
+        match self {
+            CborError(x) => x.source(),
+            UnknownField(_) => None,
+        }
+    }
+}
+
+impl From<CborError> for RequestError {
+    fn from(error: CborError) -> Self {
+        RequestError::CborError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum DebugError {
+    JsonError(JsonError),
+    CborError(CborError),
+    UnknownCommand(String),
+    InvalidBase64(String),
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DebugError::*;
+        match self {
+            JsonError(x) => x.fmt(f),
+            CborError(x) => x.fmt(f),
+            UnknownCommand(x) => write!(f, "Error, unknown command {}.", x),
+            InvalidBase64(x) => write!(f, "Error, request field {} is not valid base64.", x),
+        }
+    }
+}
+
+impl Error for DebugError {
+    fn description(&self) -> &str {
+        "I'm a DebugError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::DebugError::*;
+        match self {
+            JsonError(x) => x.source(),
+            CborError(x) => x.source(),
+            UnknownCommand(_) => None,
+            InvalidBase64(_) => None,
+        }
+    }
+}
+
+impl From<JsonError> for DebugError {
+    fn from(error: JsonError) -> Self {
+        DebugError::JsonError(error)
+    }
+}
+
+impl From<CborError> for DebugError {
+    fn from(error: CborError) -> Self {
+        DebugError::CborError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtobufError {
+    Truncated,
+    InvalidWireType(u8),
+    InvalidField(u32),
+    UnknownField(u32),
+}
+
+impl fmt::Display for ProtobufError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ProtobufError::*;
+        match self {
+            Truncated => write!(f, "Error, truncated protobuf message."),
+            InvalidWireType(x) => write!(f, "Error, unsupported protobuf wire type {}.", x),
+            InvalidField(x) => write!(f, "Error, invalid protobuf field {}.", x),
+            UnknownField(x) => write!(f, "Error, unknown protobuf request field {}.", x),
+        }
+    }
+}
+
+impl Error for ProtobufError {
+    fn description(&self) -> &str {
+        "I'm a ProtobufError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum ActivationError {
+    InvalidVariable(String),
+    InvalidFd(i32),
+}
+
+impl fmt::Display for ActivationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ActivationError::*;
+        match self {
+            InvalidVariable(x) => write!(f, "Error, invalid {} socket activation variable.", x),
+            InvalidFd(x) => write!(f, "Error, socket activation passed invalid file descriptor {}.", x),
+        }
+    }
+}
+
+impl Error for ActivationError {
+    fn description(&self) -> &str {
+        "I'm an ActivationError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::ActivationError::*;
+        match self {
+            InvalidVariable(_) => None,
+            InvalidFd(_) => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PermissionError {
+    IoError(IoError),
+    TooOpen(String, u32),
+}
+
+impl fmt::Display for PermissionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PermissionError::*;
+        match self {
+            IoError(x) => x.fmt(f),
+            TooOpen(path, mode) => write!(f, "Error, {} has mode {:o}, readable by others than its owner.", path, mode),
+        }
+    }
+}
+
+impl Error for PermissionError {
+    fn description(&self) -> &str {
+        "I'm a PermissionError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::PermissionError::*;
+        match self {
+            IoError(x) => x.source(),
+            TooOpen(_, _) => None,
+        }
+    }
+}
+
+impl From<IoError> for PermissionError {
+    fn from(error: IoError) -> Self {
+        PermissionError::IoError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum SandboxError {
+    LandlockFailed(IoError),
+    SeccompFailed(IoError),
+    UnsupportedArch,
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SandboxError::*;
+        match self {
+            LandlockFailed(x) => write!(f, "Error, failed to set up landlock: {}", x),
+            SeccompFailed(x) => write!(f, "Error, failed to set up seccomp: {}", x),
+            UnsupportedArch => write!(f, "Error, the sandbox is not supported on this architecture."),
+        }
+    }
+}
+
+impl Error for SandboxError {
+    fn description(&self) -> &str {
+        "I'm a SandboxError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::SandboxError::*;
+        match self {
+            LandlockFailed(x) => x.source(),
+            SeccompFailed(x) => x.source(),
+            UnsupportedArch => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PrivilegeError {
+    UnknownUser(String),
+    UnknownGroup(String),
+    DropFailed(IoError),
+    StillPrivileged,
+}
+
+impl fmt::Display for PrivilegeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PrivilegeError::*;
+        match self {
+            UnknownUser(x) => write!(f, "Error, unknown user {}.", x),
+            UnknownGroup(x) => write!(f, "Error, unknown group {}.", x),
+            DropFailed(x) => write!(f, "Error, failed to drop privileges: {}", x),
+            StillPrivileged => write!(f, "Error, root privileges could be regained after dropping them."),
+        }
+    }
+}
+
+impl Error for PrivilegeError {
+    fn description(&self) -> &str {
+        "I'm a PrivilegeError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::PrivilegeError::*;
+        match self {
+            UnknownUser(_) => None,
+            UnknownGroup(_) => None,
+            DropFailed(x) => x.source(),
+            StillPrivileged => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PluginError {
+    UnknownHandshake(String),
+    MissingMagicCookie,
+    InvalidProtocolVersions(String),
+    UnsupportedProtocolVersions(Vec<u32>),
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PluginError::*;
+        match self {
+            UnknownHandshake(x) => write!(f, "Error, unknown plugin handshake {}.", x),
+            MissingMagicCookie => write!(f, "Error, this is a plugin and not meant to be run directly. Start it from its host."),
+            InvalidProtocolVersions(x) => write!(f, "Error, invalid plugin protocol versions {:?}.", x),
+            UnsupportedProtocolVersions(x) => write!(f, "Error, none of the plugin protocol versions {:?} is supported.", x),
+        }
+    }
+}
+
+impl Error for PluginError {
+    fn description(&self) -> &str {
+        "I'm a PluginError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum ResponseError {
+    CborError(CborError),
+    Unpaddable(usize),
+    TooLarge { size: usize, max_size: usize },
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ResponseError::*;
+        match self {
+            CborError(x) => x.fmt(f),
+            Unpaddable(x) => write!(f, "Error, cannot pad a {} byte response.", x),
+            TooLarge { size, max_size } => write!(f, "Error, {} byte response exceeds the {} byte maximum.", size, max_size),
+        }
+    }
+}
+
+impl Error for ResponseError {
+    fn description(&self) -> &str {
+        "I'm a ResponseError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::ResponseError::*;
+        match self {
+            CborError(x) => x.source(),
+            Unpaddable(_) => None,
+            TooLarge { .. } => None,
+        }
+    }
+}
+
+impl From<CborError> for ResponseError {
+    fn from(error: CborError) -> Self {
+        ResponseError::CborError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum EncryptionError {
+    IoError(IoError),
+    InvalidKeySize(usize),
+    NoKeys,
+    UnknownKey([u8; 4]),
+    EncryptionFailed,
+    DecryptionFailed,
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::EncryptionError::*;
+        match self {
+            IoError(x) => x.fmt(f),
+            InvalidKeySize(x) => write!(f, "Error, a master key must be 32 bytes, not {}.", x),
+            NoKeys => write!(f, "Error, no master keys."),
+            UnknownKey(x) => write!(f, "Error, unknown master key {:02x}{:02x}{:02x}{:02x}.", x[0], x[1], x[2], x[3]),
+            EncryptionFailed => write!(f, "Error, encryption failed."),
+            DecryptionFailed => write!(f, "Error, decryption failed."),
+        }
+    }
+}
+
+impl Error for EncryptionError {
+    fn description(&self) -> &str {
+        "I'm an EncryptionError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::EncryptionError::*;
+        match self {
+            IoError(x) => x.source(),
+            InvalidKeySize(_) => None,
+            NoKeys => None,
+            UnknownKey(_) => None,
+            EncryptionFailed => None,
+            DecryptionFailed => None,
+        }
+    }
+}
+
+impl From<IoError> for EncryptionError {
+    fn from(error: IoError) -> Self {
+        EncryptionError::IoError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    IoError(IoError),
+    RandError(RandError),
+    SignatureError(SignatureError),
+    InsecurePermissions(u32),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::KeyError::*;
+        match self {
+            IoError(x) => x.fmt(f),
+            RandError(x) => x.fmt(f),
+            SignatureError(x) => x.fmt(f),
+            InsecurePermissions(x) => write!(f, "Error, key file mode {:o} lets other users read it.", x),
+        }
+    }
+}
+
+impl Error for KeyError {
+    fn description(&self) -> &str {
+        "I'm a KeyError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::KeyError::*;
+        match self {
+            IoError(x) => x.source(),
+            RandError(x) => x.source(),
+            SignatureError(_x) => None, // XXX no cause or source method available
+            InsecurePermissions(_) => None,
+        }
+    }
+}
+
+impl From<IoError> for KeyError {
+    fn from(error: IoError) -> Self {
+        KeyError::IoError(error)
+    }
+}
+
+impl From<RandError> for KeyError {
+    fn from(error: RandError) -> Self {
+        KeyError::RandError(error)
+    }
+}
+
+impl From<SignatureError> for KeyError {
+    fn from(error: SignatureError) -> Self {
+        KeyError::SignatureError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum TokenError {
+    InvalidKey,
+    InvalidPoint,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::TokenError::*;
+        match self {
+            InvalidKey => write!(f, "Error, invalid token key."),
+            InvalidPoint => write!(f, "Error, invalid token point."),
+        }
+    }
+}
+
+impl Error for TokenError {
+    fn description(&self) -> &str {
+        "I'm a TokenError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum AclError {
+    InvalidAppenderList,
+}
+
+impl fmt::Display for AclError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::AclError::*;
+        match self {
+            InvalidAppenderList => write!(f, "Error, invalid appender list."),
+        }
+    }
+}
+
+impl Error for AclError {
+    fn description(&self) -> &str {
+        "I'm an AclError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum OptionsError {
+    CircularWithoutCapacity,
+    InvalidKey,
+    TooManyKeys,
+    CborError(CborError),
+}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::OptionsError::*;
+        match self {
+            CircularWithoutCapacity => write!(f, "Error, a circular spool needs a capacity."),
+            InvalidKey => write!(f, "Error, invalid public key in spool options."),
+            TooManyKeys => write!(f, "Error, too many public keys in spool options."),
+            CborError(x) => x.fmt(f),
+        }
+    }
+}
+
+impl Error for OptionsError {
+    fn description(&self) -> &str {
+        "I'm an OptionsError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::OptionsError::*;
+        match self {
+            CircularWithoutCapacity => None,
+            InvalidKey => None,
+            TooManyKeys => None,
+            CborError(x) => x.source(),
+        }
+    }
+}
+
+impl From<CborError> for OptionsError {
+    fn from(error: CborError) -> Self {
+        OptionsError::CborError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum AuditError {
+    SledError(SledError<()>),
+    CborError(CborError),
+    CorruptEntry,
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::AuditError::*;
+        match self {
+            SledError(x) => x.fmt(f),
+            CborError(x) => x.fmt(f),
+            CorruptEntry => write!(f, "Error, corrupt audit log entry."),
+        }
+    }
+}
+
+impl Error for AuditError {
+    fn description(&self) -> &str {
+        "I'm an AuditError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::AuditError::*;
+        match self {
+            SledError(x) => x.source(),
+            CborError(x) => x.source(),
+            CorruptEntry => None,
+        }
+    }
+}
+
+impl From<SledError<()>> for AuditError {
+    fn from(error: SledError<()>) -> Self {
+        AuditError::SledError(error)
+    }
+}
+
+impl From<CborError> for AuditError {
+    fn from(error: CborError) -> Self {
+        AuditError::CborError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    CborError(CborError),
+    IoError(IoError),
+    UnsupportedVersion(u8),
+    InvalidArchive,
+    DigestMismatch,
+    InvalidBackupName,
+    BrokenChain,
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ArchiveError::*;
+        match self {
+            CborError(x) => x.fmt(f),
+            IoError(x) => x.fmt(f),
+            UnsupportedVersion(x) => write!(f, "Error, unsupported archive version {}.", x),
+            InvalidArchive => write!(f, "Error, invalid spool archive."),
+            DigestMismatch => write!(f, "Error, backup digest mismatch."),
+            InvalidBackupName => write!(f, "Error, invalid backup name."),
+            BrokenChain => write!(f, "Error, incremental backup doesn't follow its base."),
+        }
+    }
+}
+
+impl Error for ArchiveError {
+    fn description(&self) -> &str {
+        "I'm an ArchiveError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::ArchiveError::*;
+        match self {
+            CborError(x) => x.source(),
+            IoError(x) => x.source(),
+            UnsupportedVersion(_) => None,
+            InvalidArchive => None,
+            DigestMismatch => None,
+            InvalidBackupName => None,
+            BrokenChain => None,
+        }
+    }
+}
+
+impl From<CborError> for ArchiveError {
+    fn from(error: CborError) -> Self {
+        ArchiveError::CborError(error)
+    }
+}
+
+impl From<IoError> for ArchiveError {
+    fn from(error: IoError) -> Self {
+        ArchiveError::IoError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplicationError {
+    CborError(CborError),
+    EncryptionError(EncryptionError),
+    InvalidEvent,
+    MessageGap { expected: u64, got: u32 },
+    SequenceGap { expected: u64, got: u64 },
+    StaleEpoch { current: u64, got: u64 },
+    NotStandby,
+}
+
+impl fmt::Display for ReplicationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ReplicationError::*;
+        match self {
+            CborError(x) => x.fmt(f),
+            EncryptionError(x) => x.fmt(f),
+            InvalidEvent => write!(f, "Error, invalid replication event."),
+            MessageGap { expected, got } => write!(f, "Error, replicated message {} but the spool ends at {}.", got, expected),
+            SequenceGap { expected, got } => write!(f, "Error, replication batch {} but expected {}.", got, expected),
+            StaleEpoch { current, got } => write!(f, "Error, replication epoch {} is older than epoch {}.", got, current),
+            NotStandby => write!(f, "Error, not a standby."),
+        }
+    }
+}
+
+impl Error for ReplicationError {
+    fn description(&self) -> &str {
+        "I'm a ReplicationError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::ReplicationError::*;
+        match self {
+            CborError(x) => x.source(),
+            EncryptionError(x) => x.source(),
+            InvalidEvent => None,
+            MessageGap { .. } => None,
+            SequenceGap { .. } => None,
+            StaleEpoch { .. } => None,
+            NotStandby => None,
+        }
+    }
+}
+
+impl From<CborError> for ReplicationError {
+    fn from(error: CborError) -> Self {
+        ReplicationError::CborError(error)
+    }
+}
+
+impl From<EncryptionError> for ReplicationError {
+    fn from(error: EncryptionError) -> Self {
+        ReplicationError::EncryptionError(error)
+    }
+}
+
+#[derive(Debug)]
+pub enum BoltError {
+    IoError(IoError),
+    NotBoltDb,
+    UnsupportedVersion(u32),
+    Corrupt,
+    NotMemspool,
+    UnsupportedMemspoolVersion(u8),
+    MessageGap,
+}
+
+impl fmt::Display for BoltError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::BoltError::*;
+        match self {
+            IoError(x) => x.fmt(f),
+            NotBoltDb => write!(f, "Error, not a boltdb file."),
+            UnsupportedVersion(x) => write!(f, "Error, unsupported boltdb version {}.", x),
+            Corrupt => write!(f, "Error, corrupt boltdb file."),
+            NotMemspool => write!(f, "Error, not a memspool database."),
+            UnsupportedMemspoolVersion(x) => write!(f, "Error, unsupported memspool version {}.", x),
+            MessageGap => write!(f, "Error, too many missing memspool message IDs."),
+        }
+    }
+}
+
+impl Error for BoltError {
+    fn description(&self) -> &str {
+        "I'm a BoltError."
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        use self::BoltError::*;
+        match self {
+            IoError(x) => x.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<IoError> for BoltError {
+    fn from(error: IoError) -> Self {
+        BoltError::IoError(error)
+    }
+}
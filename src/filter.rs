@@ -0,0 +1,128 @@
+// filter.rs - Spool existence filter.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Spool existence filter
+//!
+//! Every request names a spool, and most requests for spools that
+//! don't exist are probes. The spool set keeps a bloom filter of its
+//! spool IDs in memory, so that asking for an unknown spool is
+//! answered without reading the database; only IDs the filter may
+//! hold are looked up. Spool IDs are drawn at random, so the filter
+//! takes its bit positions from the IDs themselves rather than
+//! hashing them.
+//!
+//! Bloom filters can't forget, so a purged spool's ID stays in the
+//! filter, costing a database lookup when asked for, until the filter
+//! is rebuilt. It is rebuilt at startup and whenever it grows.
+
+use byteorder::{ByteOrder, BigEndian};
+
+use crate::spool::SPOOL_ID_SIZE;
+
+
+/// The number of bits kept per spool ID, for about one false positive
+/// in a hundred.
+const BITS_PER_ID: usize = 10;
+
+/// The number of bits set for each spool ID.
+const HASH_COUNT: u64 = 7;
+
+/// The fewest spool IDs a filter is sized for.
+const MIN_CAPACITY: usize = 1024;
+
+/// SpoolFilter is a bloom filter of spool IDs.
+#[derive(Clone, Debug)]
+pub struct SpoolFilter {
+    bits: Vec<u64>,
+    capacity: usize,
+    len: usize,
+}
+
+impl SpoolFilter {
+    /// Returns an empty filter sized for `capacity` spool IDs.
+    pub fn new(capacity: usize) -> SpoolFilter {
+        let capacity = capacity.max(MIN_CAPACITY);
+        SpoolFilter {
+            bits: vec![0u64; capacity * BITS_PER_ID / 64 + 1],
+            capacity: capacity,
+            len: 0,
+        }
+    }
+
+    /// Returns the positions of the bits of a spool ID, by double
+    /// hashing with its first eight bytes and the rest.
+    fn positions(&self, spool_id: &[u8; SPOOL_ID_SIZE]) -> impl Iterator<Item = usize> {
+        let h1 = BigEndian::read_u64(&spool_id[..8]);
+        let h2 = u64::from(BigEndian::read_u32(&spool_id[8..])) | 1;
+        let size = (self.bits.len() * 64) as u64;
+        (0..HASH_COUNT).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % size) as usize)
+    }
+
+    /// Adds a spool ID to the filter.
+    pub fn insert(&mut self, spool_id: &[u8; SPOOL_ID_SIZE]) {
+        for position in self.positions(spool_id) {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+        self.len += 1;
+    }
+
+    /// Returns false if the spool ID was never added, and true if it
+    /// may have been.
+    pub fn may_contain(&self, spool_id: &[u8; SPOOL_ID_SIZE]) -> bool {
+        self.positions(spool_id).all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+
+    /// Returns true once more spool IDs were added than the filter is
+    /// sized for, after which it should be rebuilt larger.
+    pub fn is_full(&self) -> bool {
+        self.len > self.capacity
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, Rng};
+    use super::*;
+
+    #[test]
+    fn spool_filter_test() {
+        let mut rng = thread_rng();
+        let mut filter = SpoolFilter::new(0);
+        let mut added = vec![];
+        for _ in 0..MIN_CAPACITY {
+            let mut spool_id = [0u8; SPOOL_ID_SIZE];
+            rng.fill(&mut spool_id[..]);
+            filter.insert(&spool_id);
+            added.push(spool_id);
+        }
+        assert!(added.iter().all(|x| filter.may_contain(x)));
+        assert!(!filter.is_full());
+
+        let mut false_positives = 0;
+        for _ in 0..10000 {
+            let mut spool_id = [0u8; SPOOL_ID_SIZE];
+            rng.fill(&mut spool_id[..]);
+            if filter.may_contain(&spool_id) {
+                false_positives += 1;
+            }
+        }
+        assert!(false_positives < 500);
+
+        filter.insert(&[0u8; SPOOL_ID_SIZE]);
+        assert!(filter.is_full());
+    }
+}
@@ -0,0 +1,397 @@
+// fsck.rs - Offline multi-spool consistency checks.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Offline consistency checks of a data directory
+//!
+//! `check` opens the spool set and every spool as they are on disk,
+//! without the repairs the service quietly makes when opening them,
+//! and looks for:
+//!
+//! * format versions this build can't read or hasn't upgraded yet,
+//! * spool set trees which disagree about which spools exist,
+//! * spools in the spool set without a database and vice versa,
+//! * gaps in the message IDs of a spool,
+//! * messages which can't be decoded or don't match their stored
+//!   Merkle leaf hash.
+//!
+//! Nothing is changed unless asked to repair, in which case problems
+//! are fixed where no message is at stake and spools which can't be
+//! fixed are quarantined: their database is moved into the quarantine
+//! directory for forensics and they are removed from the spool set.
+//! Spools written by a newer version are always left alone.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use base64;
+
+use crate::admin;
+use crate::encryption::Keyring;
+use crate::errors::MultiSpoolError;
+use crate::shard::Shards;
+use crate::spool::{self, EntryCodec, Spool, SpoolSet, SPOOL_ID_SIZE};
+
+
+/// The quarantine directory's name within the data directory.
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// Problem is something wrong with the spool set or a spool.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// Written by a newer version.
+    UnsupportedFormat(u8),
+    /// Written by an older version and not yet upgraded.
+    OutdatedFormat(u8),
+    CorruptFormatVersion,
+    /// Spool set tree entries about spools which don't exist.
+    StaleEntries(u64),
+    UnreadableOwnerKey,
+    /// A spool in the spool set without a database.
+    MissingDatabase,
+    /// A spool database which isn't in the spool set.
+    OrphanDatabase,
+    UnreadableDatabase(String),
+    /// Keys in a spool which aren't message IDs.
+    StrayKeys(u64),
+    MissingMessages {
+        first: u32,
+        count: u64,
+    },
+    UnreadableMessages {
+        first: u32,
+        count: u64,
+    },
+    ChecksumMismatches {
+        first: u32,
+        count: u64,
+    },
+    /// Messages appended before spools kept leaf hashes.
+    MissingChecksums(u64),
+}
+
+/// Remedy is what a repair does about a problem.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Remedy {
+    /// Leave it alone, this build can't do anything about it.
+    Nothing,
+    Repair,
+    Quarantine,
+}
+
+impl Problem {
+    pub fn remedy(&self) -> Remedy {
+        use self::Problem::*;
+        match self {
+            UnsupportedFormat(_) | CorruptFormatVersion => Remedy::Nothing,
+            OutdatedFormat(_) | StaleEntries(_) | StrayKeys(_) | MissingChecksums(_) => Remedy::Repair,
+            UnreadableOwnerKey | MissingDatabase | OrphanDatabase | UnreadableDatabase(_) |
+            MissingMessages { .. } | UnreadableMessages { .. } | ChecksumMismatches { .. } => Remedy::Quarantine,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Problem::*;
+        match self {
+            UnsupportedFormat(x) => write!(f, "format version {} is newer than this build supports", x),
+            OutdatedFormat(x) => write!(f, "format version {} needs upgrading", x),
+            CorruptFormatVersion => write!(f, "corrupt format version"),
+            StaleEntries(x) => write!(f, "{} entries about spools which don't exist", x),
+            UnreadableOwnerKey => write!(f, "unreadable owner key"),
+            MissingDatabase => write!(f, "spool database missing"),
+            OrphanDatabase => write!(f, "spool database not in the spool set"),
+            UnreadableDatabase(x) => write!(f, "unreadable spool database: {}", x),
+            StrayKeys(x) => write!(f, "{} keys which aren't message IDs", x),
+            MissingMessages { first, count } => write!(f, "{} messages missing, the first is {}", count, first),
+            UnreadableMessages { first, count } => write!(f, "{} unreadable messages, the first is {}", count, first),
+            ChecksumMismatches { first, count } => write!(f, "{} messages don't match their leaf hash, the first is {}", count, first),
+            MissingChecksums(x) => write!(f, "{} messages without a leaf hash", x),
+        }
+    }
+}
+
+/// Outcome is the state the spool set or a spool was left in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Ok,
+    /// Problems were found and left as they are.
+    Damaged,
+    Repaired,
+    Quarantined,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Damaged => "damaged",
+            Outcome::Repaired => "repaired",
+            Outcome::Quarantined => "quarantined",
+        }
+    }
+}
+
+/// Returns what repairing, or not, `problems` will leave behind.
+fn outcome(problems: &[Problem], repair: bool) -> Outcome {
+    let remedies: Vec<Remedy> = problems.iter().map(|x| x.remedy()).collect();
+    if remedies.is_empty() {
+        Outcome::Ok
+    } else if !repair || remedies.contains(&Remedy::Nothing) {
+        Outcome::Damaged
+    } else if remedies.contains(&Remedy::Quarantine) {
+        Outcome::Quarantined
+    } else {
+        Outcome::Repaired
+    }
+}
+
+/// SpoolReport is what was found wrong with a spool.
+#[derive(Clone, Debug)]
+pub struct SpoolReport {
+    /// None for databases whose name doesn't give their spool ID.
+    pub spool_id: Option<[u8; SPOOL_ID_SIZE]>,
+    pub path: PathBuf,
+    pub problems: Vec<Problem>,
+    pub outcome: Outcome,
+}
+
+/// Report is what was found wrong with a data directory.
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub spool_set: Vec<Problem>,
+    pub spool_set_outcome: Outcome,
+    /// Only the spools with problems.
+    pub spools: Vec<SpoolReport>,
+    /// The number of spools checked.
+    pub spool_count: usize,
+}
+
+impl Report {
+    /// Returns true if problems were left as they are.
+    pub fn is_damaged(&self) -> bool {
+        self.spool_set_outcome == Outcome::Damaged || self.spools.iter().any(|x| x.outcome == Outcome::Damaged)
+    }
+
+    /// Returns true if anything was repaired or quarantined.
+    pub fn is_changed(&self) -> bool {
+        self.spool_set_outcome != Outcome::Ok || !self.spools.is_empty()
+    }
+}
+
+/// Returns the ID of the spool whose database is at `path`, unless
+/// its name was mangled by a "//" in its encoding.
+fn database_spool_id(base_dir: &Path, path: &Path) -> Option<[u8; SPOOL_ID_SIZE]> {
+    let name = path.strip_prefix(base_dir).ok()?.to_str()?;
+    let encoded = &name["spool.".len()..name.len() - ".sled".len()];
+    match base64::decode(encoded) {
+        Ok(ref raw) if raw.len() == SPOOL_ID_SIZE => Some(*array_ref![raw, 0, SPOOL_ID_SIZE]),
+        _ => None,
+    }
+}
+
+/// Moves a spool's database at `path`, if any, into the quarantine
+/// directory of the shard directory `base_dir` and removes the spool
+/// from the spool set.
+fn quarantine(base_dir: &String,
+              spool_set: &mut SpoolSet,
+              spool_id: Option<[u8; SPOOL_ID_SIZE]>,
+              path: &Path)
+              -> Result<(), MultiSpoolError> {
+    if path.exists() {
+        let dir = Path::new(base_dir).join(QUARANTINE_DIR);
+        fs::create_dir_all(&dir)?;
+        let name = match spool_id {
+            Some(ref x) => format!("spool.{}.sled", admin::encode_spool_id(x)),
+            None => String::from("spool.unknown.sled"),
+        };
+        let mut target = dir.join(&name);
+        let mut attempt = 1;
+        while target.exists() {
+            target = dir.join(format!("{}.{}", name, attempt));
+            attempt += 1;
+        }
+        fs::rename(&path, &target)?;
+        spool::remove_empty_parent(Path::new(base_dir), path);
+    }
+    if let Some(spool_id) = spool_id {
+        if spool_set.has(spool_id)? {
+            spool_set.delete(spool_id)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks a spool, repairing it if need be, and returns its problems.
+fn check_spool(spool_set: &SpoolSet,
+               keyring: &Option<Arc<Keyring>>,
+               path: &PathBuf,
+               spool_id: [u8; SPOOL_ID_SIZE],
+               repair: bool)
+               -> Result<Vec<Problem>, MultiSpoolError> {
+    let mut problems = vec![];
+    if spool_set.check_owner_key(spool_id).is_err() {
+        problems.push(Problem::UnreadableOwnerKey);
+    }
+    let mut spool = match Spool::open_unchecked(path) {
+        Ok(x) => x,
+        Err(e) => {
+            problems.push(Problem::UnreadableDatabase(e.to_string()));
+            return Ok(problems)
+        },
+    };
+    spool.set_codec(EntryCodec {
+        keyring: keyring.clone(),
+        spool_key: spool_set.existing_spool_key(spool_id).ok().and_then(|x| x).map(Arc::new),
+        ..EntryCodec::default()
+    });
+    match spool.check() {
+        Ok(x) => problems.extend(x),
+        Err(e) => problems.push(Problem::UnreadableDatabase(e.to_string())),
+    }
+    if outcome(&problems, repair) == Outcome::Repaired {
+        spool.repair()?;
+    }
+    Ok(problems)
+}
+
+/// Checks the data directory `base_dir`, which the spool service
+/// must not be using, and repairs it if `repair` is set. The master
+/// keys are needed to check encrypted messages.
+pub fn check(base_dir: &str, keyring: Option<Arc<Keyring>>, repair: bool) -> Result<Report, MultiSpoolError> {
+    check_shards(base_dir, &[], keyring, repair)
+}
+
+/// Checks a data directory like `check`, with the spool databases
+/// sharded across it and `shard_dirs`. A database in another shard
+/// than its spool's, which the service moves on its next start, is
+/// checked where it is.
+pub fn check_shards(base_dir: &str, shard_dirs: &[String], keyring: Option<Arc<Keyring>>, repair: bool) -> Result<Report, MultiSpoolError> {
+    let base_dir = String::from(base_dir);
+    let shards = Shards::new(&base_dir, shard_dirs);
+    let mut spool_set = SpoolSet::open_unchecked(&spool::spool_set_path(&base_dir))?;
+    spool_set.set_keyring(keyring.clone());
+    let problems = spool_set.check()?;
+    let mut report = Report {
+        spool_set_outcome: outcome(&problems, repair),
+        spool_set: problems,
+        spools: vec![],
+        spool_count: 0,
+    };
+    if report.spool_set.iter().any(|x| x.remedy() == Remedy::Nothing) {
+        return Ok(report)
+    }
+    if report.spool_set_outcome == Outcome::Repaired {
+        spool_set.repair()?;
+    }
+
+    let mut in_set = BTreeSet::new();
+    for key in spool_set.keys() {
+        let key = key?;
+        if key.len() == SPOOL_ID_SIZE {
+            in_set.insert(*array_ref![key, 0, SPOOL_ID_SIZE]);
+        }
+    }
+    let mut on_disk = BTreeSet::new();
+    for dir in shards.dirs() {
+        on_disk.extend(spool::spool_databases(Path::new(dir))?);
+    }
+    let mut spools = vec![];
+    for spool_id in in_set {
+        let mut path = shards.spool_path(spool_id);
+        if !on_disk.contains(&path) {
+            if let Some(misplaced) = shards.misplaced(spool_id).filter(|x| on_disk.contains(x)) {
+                path = misplaced;
+            }
+        }
+        let problems = if on_disk.remove(&path) {
+            check_spool(&spool_set, &keyring, &path, spool_id, repair)?
+        } else {
+            vec![Problem::MissingDatabase]
+        };
+        spools.push((Some(spool_id), path, problems));
+    }
+    for path in on_disk {
+        let spool_id = shards.dir_of(&path).and_then(|dir| database_spool_id(Path::new(dir), &path));
+        spools.push((spool_id, path, vec![Problem::OrphanDatabase]));
+    }
+    for (spool_id, path, problems) in spools {
+        report.spool_count += 1;
+        let outcome = outcome(&problems, repair);
+        if outcome == Outcome::Quarantined {
+            let dir = shards.dir_of(&path).unwrap_or(&base_dir);
+            quarantine(dir, &mut spool_set, spool_id, &path)?;
+        }
+        if !problems.is_empty() {
+            report.spools.push(SpoolReport {
+                spool_id: spool_id,
+                path: path,
+                problems: problems,
+                outcome: outcome,
+            });
+        }
+    }
+    spool_set.flush()?;
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempdir;
+    use super::*;
+
+    #[test]
+    fn outcome_test() {
+        assert_eq!(outcome(&[], true), Outcome::Ok);
+        let repairable = vec![Problem::MissingChecksums(2), Problem::StrayKeys(1)];
+        assert_eq!(outcome(&repairable, false), Outcome::Damaged);
+        assert_eq!(outcome(&repairable, true), Outcome::Repaired);
+        let mut lost = repairable.clone();
+        lost.push(Problem::MissingMessages { first: 3, count: 1 });
+        assert_eq!(outcome(&lost, true), Outcome::Quarantined);
+        lost.push(Problem::UnsupportedFormat(9));
+        assert_eq!(outcome(&lost, true), Outcome::Damaged);
+    }
+
+    #[test]
+    fn spool_databases_test() {
+        let dir = tempdir().unwrap();
+        let base_dir = dir.path().to_string_lossy().into_owned();
+        let alice = [1u8; SPOOL_ID_SIZE];
+        // The base64 encoding of these IDs holds a '/' and a "//".
+        let bob = [0xfcu8; SPOOL_ID_SIZE];
+        let carol = [0xffu8; SPOOL_ID_SIZE];
+        let mut paths = BTreeSet::new();
+        for &spool_id in &[alice, bob, carol] {
+            let path = spool::spool_path(&base_dir, spool_id);
+            fs::create_dir_all(&path).unwrap();
+            paths.insert(path);
+        }
+        fs::create_dir_all(spool::spool_set_path(&base_dir)).unwrap();
+        fs::create_dir_all(dir.path().join(QUARANTINE_DIR)).unwrap();
+        let found = spool::spool_databases(dir.path()).unwrap();
+        assert_eq!(found, paths);
+        assert_eq!(database_spool_id(dir.path(), &spool::spool_path(&base_dir, alice)), Some(alice));
+        assert_eq!(database_spool_id(dir.path(), &spool::spool_path(&base_dir, bob)), Some(bob));
+        assert!(found.contains(&dir.path().join("spool./.sled")));
+        assert_eq!(database_spool_id(dir.path(), &dir.path().join("spool./.sled")), None);
+    }
+}
@@ -0,0 +1,145 @@
+// group_commit.rs - Coalescing of durable append flushes.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Group commit
+//!
+//! Flushing a spool to disk costs about as much for one append as for
+//! a hundred. Durable appends therefore don't flush on their own: the
+//! first one waiting becomes the leader, lets the appends arriving
+//! within a short window join it, and flushes once for all of them,
+//! while the others wait for that flush before they are acknowledged.
+
+use std::cmp::max;
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+
+/// How long a leader waits for more appends before flushing, by
+/// default.
+pub const DEFAULT_GROUP_COMMIT_WINDOW_MS: u64 = 1;
+
+struct State {
+    appended: u64,
+    durable: u64,
+    flushing: bool,
+}
+
+/// GroupCommit hands out a ticket for each write to a spool and
+/// flushes the writes of everyone waiting on a ticket at once.
+pub struct GroupCommit {
+    window: Duration,
+    state: Mutex<State>,
+    flushed: Condvar,
+}
+
+impl GroupCommit {
+    pub fn new(window: Duration) -> GroupCommit {
+        GroupCommit {
+            window: window,
+            state: Mutex::new(State {
+                appended: 0,
+                durable: 0,
+                flushing: false,
+            }),
+            flushed: Condvar::new(),
+        }
+    }
+
+    /// Records a write, returning the ticket to wait for it with.
+    /// Must be called after the write, under the lock ordering the
+    /// spool's writes.
+    pub fn record(&self) -> u64 {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.appended += 1;
+        state.appended
+    }
+
+    /// Returns the number of writes recorded and not yet known to be
+    /// flushed.
+    pub fn pending(&self) -> u64 {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.appended - state.durable
+    }
+
+    /// Waits until the write `ticket` was handed out for is flushed,
+    /// calling `flush` if no other waiter is about to. A failed flush
+    /// is retried by the next waiter.
+    pub fn wait<F, E>(&self, ticket: u64, flush: F) -> Result<(), E>
+    where
+        F: Fn() -> Result<(), E>,
+    {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if state.durable >= ticket {
+                return Ok(())
+            }
+            if !state.flushing {
+                break
+            }
+            state = self.flushed.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+        state.flushing = true;
+        drop(state);
+        thread::sleep(self.window);
+        let target = self.state.lock().unwrap_or_else(PoisonError::into_inner).appended;
+        let result = flush();
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.flushing = false;
+        if result.is_ok() {
+            state.durable = max(state.durable, target);
+        }
+        self.flushed.notify_all();
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+
+    #[test]
+    fn group_commit_test() {
+        let commit = Arc::new(GroupCommit::new(Duration::from_millis(50)));
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let tickets: Vec<u64> = (0..8).map(|_| commit.record()).collect();
+        assert_eq!(commit.pending(), 8);
+        let threads: Vec<_> = tickets.into_iter().map(|ticket| {
+            let commit = commit.clone();
+            let flushes = flushes.clone();
+            thread::spawn(move || {
+                commit.wait(ticket, || -> Result<(), ()> {
+                    flushes.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }).unwrap();
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
+        assert_eq!(commit.pending(), 0);
+
+        // A failed flush is left for the next waiter to retry.
+        let ticket = commit.record();
+        assert!(commit.wait(ticket, || Err(())).is_err());
+        commit.wait(ticket, || -> Result<(), ()> { Ok(()) }).unwrap();
+        commit.wait(ticket, || Err(())).unwrap();
+    }
+}
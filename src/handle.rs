@@ -0,0 +1,210 @@
+// handle.rs - Spool reader and writer handles.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Spool handles
+//!
+//! Applications embedding a `SpoolStore` can work with a spool through
+//! a handle holding its ID and what it takes to use it, rather than
+//! passing spool IDs, keys and signatures to every call. A
+//! `SpoolWriter` appends, signing as a listed writer if it was given
+//! a writer key, and a `SpoolReader` reads the messages in order as
+//! the spool's owner or one of its readers.
+//!
+//! A reader returns the same message from `read_next` until it is
+//! acknowledged with `ack`, so that a message is never skipped because
+//! the application failed to handle it. Its position can be saved and
+//! given back to a new reader to carry on where it left off.
+
+use byteorder::{ByteOrder, BigEndian};
+use ed25519_dalek::{Keypair, PublicKey, Signature};
+
+use crate::acl::{self, AppendAuth};
+use crate::errors::{MultiSpoolError, SpoolError};
+use crate::spool::{SPOOL_ID_SIZE, MESSAGE_ID_SIZE};
+use crate::store::SpoolStore;
+
+
+/// SpoolWriter appends to a spool.
+pub struct SpoolWriter<'a> {
+    store: &'a dyn SpoolStore,
+    spool_id: [u8; SPOOL_ID_SIZE],
+    appender: Option<Keypair>,
+}
+
+impl<'a> SpoolWriter<'a> {
+    /// Returns a writer appending to a spool anyone may append to.
+    pub fn new(store: &'a dyn SpoolStore, spool_id: [u8; SPOOL_ID_SIZE]) -> SpoolWriter<'a> {
+        SpoolWriter {
+            store: store,
+            spool_id: spool_id,
+            appender: None,
+        }
+    }
+
+    /// Signs appends as a writer allowed to append to a spool with an
+    /// appender list.
+    pub fn appender(mut self, keypair: &Keypair) -> SpoolWriter<'a> {
+        self.appender = Keypair::from_bytes(&keypair.to_bytes()).ok();
+        self
+    }
+
+    pub fn spool_id(&self) -> [u8; SPOOL_ID_SIZE] {
+        self.spool_id
+    }
+
+    /// Appends a message. Returns false if the spool dropped it as a
+    /// duplicate.
+    pub fn append(&self, message: &[u8]) -> Result<bool, MultiSpoolError> {
+        self.append_with_token(message, &[])
+    }
+
+    /// Appends a message to a spool requiring append tokens, spending
+    /// `token`.
+    pub fn append_with_token(&self, message: &[u8], token: &[u8]) -> Result<bool, MultiSpoolError> {
+        let (public_key, signature) = match self.appender {
+            Some(ref keypair) => {
+                let signature = acl::sign_append(keypair, &self.spool_id, message);
+                (keypair.public.to_bytes().to_vec(), signature.to_bytes().to_vec())
+            },
+            None => (vec![], vec![]),
+        };
+        let auth = AppendAuth {
+            token: token,
+            public_key: &public_key,
+            signature: &signature,
+        };
+        self.store.append_idempotent(self.spool_id, message, &auth, &[], false)
+    }
+}
+
+/// SpoolReader reads the messages of a spool in order.
+pub struct SpoolReader<'a> {
+    store: &'a dyn SpoolStore,
+    spool_id: [u8; SPOOL_ID_SIZE],
+    public_key: PublicKey,
+    signature: Signature,
+    position: u32,
+    unacked: Option<u32>,
+}
+
+impl<'a> SpoolReader<'a> {
+    /// Returns a reader of a spool owned by `keypair`, or which it
+    /// may read, starting at message `position`.
+    pub fn new(store: &'a dyn SpoolStore,
+               spool_id: [u8; SPOOL_ID_SIZE],
+               keypair: &Keypair,
+               position: u32)
+               -> SpoolReader<'a> {
+        SpoolReader {
+            store: store,
+            spool_id: spool_id,
+            public_key: keypair.public,
+            signature: keypair.sign(&keypair.public.to_bytes()),
+            position: position,
+            unacked: None,
+        }
+    }
+
+    pub fn spool_id(&self) -> [u8; SPOOL_ID_SIZE] {
+        self.spool_id
+    }
+
+    /// Returns the ID of the next message to read, which is what to
+    /// save to carry on reading later.
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    /// Returns the next message not yet acknowledged and its ID, or
+    /// None if there is none yet. Messages dropped from the spool
+    /// before they were read are skipped.
+    pub fn read_next(&mut self) -> Result<Option<(u32, Vec<u8>)>, MultiSpoolError> {
+        let count = self.store.message_count(self.spool_id)?;
+        while u64::from(self.position) < count {
+            let mut message_id = [0u8; MESSAGE_ID_SIZE];
+            BigEndian::write_u32(&mut message_id, self.position);
+            match self.store.read_from_spool(self.spool_id, &self.public_key, self.signature, &message_id) {
+                Ok((message, _)) => {
+                    self.unacked = Some(self.position);
+                    return Ok(Some((self.position, message)))
+                },
+                Err(MultiSpoolError::SpoolError(SpoolError::NoSuchMessage)) => self.position += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Acknowledges the message last returned by `read_next`, so that
+    /// the next call returns the one after it.
+    pub fn ack(&mut self) {
+        if let Some(message_id) = self.unacked.take() {
+            self.position = message_id + 1;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use crate::acl::AppenderList;
+    use crate::options::SpoolOptions;
+    use crate::store::MemorySpoolStore;
+    use super::*;
+
+    #[test]
+    fn spool_handles_test() {
+        let mut csprng = OsRng::new().unwrap();
+        let owner = Keypair::generate(&mut csprng);
+        let store = MemorySpoolStore::default();
+        let options = SpoolOptions {
+            Capacity: 2,
+            Circular: true,
+            ..SpoolOptions::default()
+        };
+        let (spool_id, _) = store.allocate_spool(owner.public, owner.sign(&owner.public.to_bytes()), &options).unwrap();
+
+        let writer = SpoolWriter::new(&store, spool_id);
+        let mut reader = SpoolReader::new(&store, spool_id, &owner, 0);
+        assert_eq!(reader.read_next().unwrap(), None);
+        assert!(writer.append(b"one").unwrap());
+        assert_eq!(reader.read_next().unwrap(), Some((0, b"one".to_vec())));
+        // Until acknowledged the message is read again.
+        assert_eq!(reader.read_next().unwrap(), Some((0, b"one".to_vec())));
+        reader.ack();
+        assert_eq!(reader.position(), 1);
+        assert_eq!(reader.read_next().unwrap(), None);
+
+        // "two" is dropped before it is read.
+        for message in &[b"two", b"six", b"ten"] {
+            writer.append(*message).unwrap();
+        }
+        assert_eq!(reader.read_next().unwrap(), Some((2, b"six".to_vec())));
+        reader.ack();
+        let mut reader = SpoolReader::new(&store, spool_id, &owner, reader.position());
+        assert_eq!(reader.read_next().unwrap(), Some((3, b"ten".to_vec())));
+
+        let stranger = Keypair::generate(&mut csprng);
+        assert!(SpoolReader::new(&store, spool_id, &stranger, 0).read_next().is_err());
+
+        // Spools with an appender list take appends from listed writers.
+        let list = AppenderList::new(vec![stranger.public]).unwrap();
+        store.set_appenders(spool_id, owner.sign(&owner.public.to_bytes()), Some(list)).unwrap();
+        assert!(writer.append(b"one").is_err());
+        assert!(SpoolWriter::new(&store, spool_id).appender(&stranger).append(b"one").unwrap());
+    }
+}
@@ -0,0 +1,144 @@
+// keys.rs - Multi-spool keypair management.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Long-term ed25519 keypairs, such as the service identity key.
+//!
+//! Keypair files hold the 64 byte secret and public key and are only
+//! readable by their owner; files other users can read are refused,
+//! as are other key files read with `read_key_file`.
+//! Rotating a keypair keeps the previous one next to it, with an
+//! ".old" suffix, so that it isn't lost before clients have the new
+//! key.
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use base64;
+use ed25519_dalek::{Keypair, PublicKey};
+use rand::rngs::OsRng;
+
+use crate::errors::KeyError;
+
+
+/// The suffix of the file a rotated out keypair is kept in.
+pub const OLD_KEYPAIR_SUFFIX: &str = ".old";
+
+/// Returns the path the keypair at `path` is moved to when rotated.
+pub fn old_keypair_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut old = path.as_ref().as_os_str().to_owned();
+    old.push(OLD_KEYPAIR_SUFFIX);
+    PathBuf::from(old)
+}
+
+/// Reads a secret key file, which must not be readable by other
+/// users.
+pub fn read_key_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, KeyError> {
+    let mode = fs::metadata(&path)?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(KeyError::InsecurePermissions(mode))
+    }
+    Ok(fs::read(path)?)
+}
+
+/// Writes a new secret key file readable only by us, failing if the
+/// file exists.
+pub fn write_key_file<P: AsRef<Path>>(path: P, key: &[u8]) -> Result<(), KeyError> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(key)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Loads the keypair from `path`, which must not be readable by
+/// other users.
+pub fn load_keypair<P: AsRef<Path>>(path: P) -> Result<Keypair, KeyError> {
+    Ok(Keypair::from_bytes(&read_key_file(path)?)?)
+}
+
+/// Generates a new keypair and saves it to `path`, readable only by
+/// us, failing if the file exists.
+pub fn generate_keypair<P: AsRef<Path>>(path: P) -> Result<Keypair, KeyError> {
+    let mut csprng = OsRng::new()?;
+    let keypair = Keypair::generate(&mut csprng);
+    write_key_file(path, &keypair.to_bytes())?;
+    Ok(keypair)
+}
+
+/// Loads the keypair from `path`, generating and saving a new one if
+/// the file does not exist.
+pub fn load_or_generate_keypair<P: AsRef<Path>>(path: P) -> Result<Keypair, KeyError> {
+    if path.as_ref().exists() {
+        return load_keypair(path)
+    }
+    generate_keypair(path)
+}
+
+/// Replaces the keypair at `path` with a new one, moving the current
+/// keypair to `old_keypair_path`. Any older keypair is overwritten.
+pub fn rotate_keypair<P: AsRef<Path>>(path: P) -> Result<Keypair, KeyError> {
+    load_keypair(&path)?;
+    let mut next = path.as_ref().as_os_str().to_owned();
+    next.push(".next");
+    let next = PathBuf::from(next);
+    if next.exists() {
+        fs::remove_file(&next)?;
+    }
+    let keypair = generate_keypair(&next)?;
+    fs::rename(&path, old_keypair_path(&path))?;
+    fs::rename(&next, &path)?;
+    Ok(keypair)
+}
+
+/// Encodes a public key the way it is published in the service
+/// parameters.
+pub fn encode_public_key(public_key: &PublicKey) -> String {
+    base64::encode(public_key.as_bytes())
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempdir;
+    use super::*;
+
+    #[test]
+    fn keypair_rotation_test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("identity.key");
+        let first = load_or_generate_keypair(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(load_or_generate_keypair(&path).unwrap().public, first.public);
+        assert!(generate_keypair(&path).is_err());
+
+        let second = rotate_keypair(&path).unwrap();
+        assert!(second.public != first.public);
+        assert_eq!(load_keypair(&path).unwrap().public, second.public);
+        assert_eq!(load_keypair(old_keypair_path(&path)).unwrap().public, first.public);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        match load_keypair(&path) {
+            Err(KeyError::InsecurePermissions(mode)) => assert_eq!(mode, 0o644),
+            _ => panic!("expected an insecure permissions error"),
+        }
+    }
+}
@@ -20,79 +20,572 @@
 #[macro_use] extern crate arrayref;
 #[macro_use] extern crate serde_derive;
 #[macro_use] extern crate serde;
+#[macro_use] extern crate lazy_static;
+#[macro_use] extern crate prometheus;
+#[macro_use] extern crate serde_json;
 extern crate serde_bytes;
+extern crate serde_cbor;
 extern crate log4rs;
+extern crate log_mdc;
 extern crate base64;
 extern crate byteorder;
 extern crate sled;
 extern crate ed25519_dalek;
 extern crate rand;
 extern crate sphinxcrypto;
+extern crate toml;
+extern crate blake2;
+extern crate chacha20poly1305;
+extern crate hkdf;
+extern crate sha2;
+extern crate curve25519_dalek;
+extern crate hyper;
+extern crate tokio;
+extern crate libc;
 
 pub mod spool;
 pub mod errors;
+pub mod config;
+pub mod metrics;
+pub mod logging;
+pub mod syslog;
+pub mod trace;
+pub mod version;
+pub mod admin;
+pub mod client;
+pub mod encryption;
+pub mod merkle;
+pub mod keys;
+pub mod pow;
+pub mod tokens;
+pub mod acl;
+pub mod rekey;
+pub mod options;
+pub mod ratelimit;
+pub mod dedup;
+pub mod schema;
+pub mod protobuf;
+pub mod debug;
+pub mod service;
+pub mod plugin;
+pub mod admission;
+pub mod privileges;
+pub mod sandbox;
+pub mod permissions;
+pub mod activation;
+pub mod disk;
+pub mod group_commit;
+pub mod replication;
+pub mod shard;
+pub mod store;
+pub mod throttle;
+pub mod audit;
+pub mod archive;
+pub mod backup;
+pub mod boltdb;
+pub mod fsck;
+pub mod filter;
+pub mod handle;
 
+use std::cmp;
+use std::fmt;
 use std::str;
-use serde::{Deserialize, Serialize};
-use rand::rngs::OsRng;
-use ed25519_dalek::{PublicKey, Signature, SIGNATURE_LENGTH, PUBLIC_KEY_LENGTH};
+use std::collections::HashMap;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::{self, Unexpected, Visitor};
+use byteorder::{ByteOrder, BigEndian};
+use ed25519_dalek::{Keypair, PublicKey, Signature, SIGNATURE_LENGTH, PUBLIC_KEY_LENGTH};
+use sha2::{Digest, Sha256};
 
-use spool::{MultiSpool, SPOOL_ID_SIZE, MESSAGE_ID_SIZE, MESSAGE_SIZE};
-use errors::MultiSpoolError;
+use crate::acl::{AppendAuth, AppenderList};
+use crate::options::SpoolOptions;
+use crate::rekey::Rekey;
+use crate::spool::{SPOOL_ID_SIZE, MESSAGE_ID_SIZE};
+use crate::store::SpoolStore;
+use crate::errors::{MultiSpoolError, ResponseError, SpoolError};
+use crate::merkle::ReadProof;
+use crate::tokens::TokenKey;
+use crate::version::{BuildInfo, ProtocolVersion, PROTOCOL_MINOR_VERSION, PROTOCOL_VERSION, SUPPORTED_PROTOCOL_VERSIONS};
 
 pub const CREATE_SPOOL_COMMAND: u8 = 0;
 pub const PURGE_SPOOL_COMMAND: u8 = 1;
 pub const APPEND_MESSAGE_COMMAND: u8 = 2;
 pub const RETRIEVE_MESSAGE_COMMAND: u8 = 3;
+pub const VERSION_COMMAND: u8 = 4;
+pub const SET_APPEND_TOKEN_KEY_COMMAND: u8 = 5;
+pub const UNDELETE_SPOOL_COMMAND: u8 = 6;
+pub const SET_APPENDERS_COMMAND: u8 = 7;
+pub const SPOOL_STATUS_COMMAND: u8 = 8;
+pub const COPY_SPOOL_COMMAND: u8 = 9;
+pub const REKEY_SPOOL_COMMAND: u8 = 10;
+pub const RETRIEVE_BY_HASH_COMMAND: u8 = 11;
+pub const RETRIEVE_LATEST_COMMAND: u8 = 12;
 
+/// The status of a request rejected by the rate limiter, or with a
+/// bad signature for a spool backing off, see `throttle`.
+pub const RATE_LIMITED_STATUS: &str = "error: rate limited";
 
-#[derive(Deserialize)]
+/// The status of a request which failed because of a bug or a
+/// poisoned lock rather than anything the client did.
+pub const INTERNAL_ERROR_STATUS: &str = "error: internal error";
+
+/// The status of a request whose SpoolID is not SPOOL_ID_SIZE bytes.
+pub const INVALID_SPOOL_ID_STATUS: &str = "error: invalid spool id";
+
+/// The status of a write sent to a standby or a fenced primary, see
+/// `replication::Role`.
+pub const NOT_PRIMARY_STATUS: &str = "error: not the primary";
+
+/// The status of an append to a spool with an appender list which
+/// isn't signed by a listed writer, see `acl`.
+pub const APPEND_NOT_ALLOWED_STATUS: &str = "error: appender not allowed";
+
+/// The status of an append to a spool created with a capacity which
+/// already holds that many messages, see `options`.
+pub const SPOOL_FULL_STATUS: &str = "error: spool full";
+
+/// The status of a creation or append refused because the service's
+/// disk is running out of space, see `disk`.
+pub const STORAGE_EXHAUSTED_STATUS: &str = "error: storage exhausted";
+
+/// The status of a creation by a key which already owns a spool
+/// created with other options, see `MultiSpool::create_or_get`.
+pub const OPTIONS_MISMATCH_STATUS: &str = "error: spool exists with other options";
+
+/// The status of a request of a major protocol version the service
+/// doesn't speak. The response carries the service's own version.
+pub const UNSUPPORTED_VERSION_STATUS: &str = "error: unsupported protocol version";
+
+/// The status of a request turned away because the service already
+/// has as many requests as it is configured to take, see `admission`.
+pub const BUSY_STATUS: &str = "error: busy";
+
+/// The status of a request not handled within the request timeout,
+/// most likely because storage stalled. It may still take effect.
+pub const TIMEOUT_STATUS: &str = "error: timeout";
+
+/// The status of a request which could not be decoded, see `schema`.
+pub const INVALID_REQUEST_STATUS: &str = "error: invalid request";
+
+/// Returns a human readable name for a spool command, used in logs
+/// and as a metrics label.
+pub fn command_name(command: u8) -> &'static str {
+    match command {
+        CREATE_SPOOL_COMMAND => "create",
+        PURGE_SPOOL_COMMAND => "purge",
+        APPEND_MESSAGE_COMMAND => "append",
+        RETRIEVE_MESSAGE_COMMAND => "read",
+        VERSION_COMMAND => "version",
+        SET_APPEND_TOKEN_KEY_COMMAND => "set_token_key",
+        UNDELETE_SPOOL_COMMAND => "undelete",
+        SET_APPENDERS_COMMAND => "set_appenders",
+        SPOOL_STATUS_COMMAND => "status",
+        COPY_SPOOL_COMMAND => "copy",
+        REKEY_SPOOL_COMMAND => "rekey",
+        RETRIEVE_BY_HASH_COMMAND => "read_by_hash",
+        RETRIEVE_LATEST_COMMAND => "read_latest",
+        _ => "invalid",
+    }
+}
+
+/// The commands understood by this build, in command number order.
+pub const SUPPORTED_COMMANDS: &[u8] = &[
+    CREATE_SPOOL_COMMAND,
+    PURGE_SPOOL_COMMAND,
+    APPEND_MESSAGE_COMMAND,
+    RETRIEVE_MESSAGE_COMMAND,
+    VERSION_COMMAND,
+    SET_APPEND_TOKEN_KEY_COMMAND,
+    UNDELETE_SPOOL_COMMAND,
+    SET_APPENDERS_COMMAND,
+    SPOOL_STATUS_COMMAND,
+    COPY_SPOOL_COMMAND,
+    REKEY_SPOOL_COMMAND,
+    RETRIEVE_BY_HASH_COMMAND,
+    RETRIEVE_LATEST_COMMAND,
+];
+
+/// Parameters are the service capabilities advertised to Katzenpost
+/// clients via the plugin parameters endpoint.
+pub type Parameters = HashMap<String, String>;
+
+/// Returns the parameters advertising this service's capabilities.
+/// `max_spools` is the configured spool limit, if any,
+/// `max_message_size` the largest message which may be appended,
+/// `identity_key` the key responses and read proofs are signed with,
+/// if any, and `append_pow_difficulty` the proof of work appends
+/// need, if any.
+pub fn parameters(max_spools: Option<u64>, max_message_size: usize, identity_key: Option<&PublicKey>, append_pow_difficulty: Option<u32>) -> Parameters {
+    let mut params = Parameters::new();
+    params.insert("message_size".to_string(), max_message_size.to_string());
+    params.insert("message_id_size".to_string(), MESSAGE_ID_SIZE.to_string());
+    params.insert("spool_id_size".to_string(), SPOOL_ID_SIZE.to_string());
+    if let Some(max_spools) = max_spools {
+        params.insert("max_spools".to_string(), max_spools.to_string());
+    }
+    if let Some(identity_key) = identity_key {
+        params.insert("identity_key".to_string(), keys::encode_public_key(identity_key));
+    }
+    if let Some(difficulty) = append_pow_difficulty {
+        params.insert("append_pow_difficulty".to_string(), difficulty.to_string());
+    }
+    let commands: Vec<&str> = SUPPORTED_COMMANDS.iter().map(|x| command_name(*x)).collect();
+    params.insert("commands".to_string(), commands.join(","));
+    params.insert("protocol_version".to_string(), PROTOCOL_VERSION.to_string());
+    params.insert("protocol_minor_version".to_string(), PROTOCOL_MINOR_VERSION.to_string());
+    let versions: Vec<String> = SUPPORTED_PROTOCOL_VERSIONS.iter().map(|x| x.to_string()).collect();
+    params.insert("protocol_versions".to_string(), versions.join(","));
+    params.insert("encodings".to_string(), "cbor,protobuf".to_string());
+    params
+}
+
+#[derive(Serialize, Deserialize, Default)]
 #[allow(non_snake_case)]
 pub struct SpoolRequest {
     pub Command: u8,
-    #[serde(with = "serde_bytes")]
+    #[serde(serialize_with = "serde_bytes::serialize", deserialize_with = "nullable_bytes")]
     pub SpoolID: Vec<u8>,
-    #[serde(with = "serde_bytes")]
+    #[serde(serialize_with = "serde_bytes::serialize", deserialize_with = "nullable_bytes")]
     pub Signature: Vec<u8>,
-    #[serde(with = "serde_bytes")]
+    #[serde(serialize_with = "serde_bytes::serialize", deserialize_with = "nullable_bytes")]
     pub PublicKey: Vec<u8>,
-    #[serde(with = "serde_bytes")]
+    /// Go clients send the ID as a number, which is decoded into its
+    /// MESSAGE_ID_SIZE big endian bytes.
+    #[serde(serialize_with = "serde_bytes::serialize", deserialize_with = "message_id")]
     pub MessageID: Vec<u8>,
-    #[serde(with = "serde_bytes")]
+    #[serde(serialize_with = "serde_bytes::serialize", deserialize_with = "nullable_bytes")]
     pub Message: Vec<u8>,
+    /// Asks for a `ReadProof` with the message read. Left out of the
+    /// encoding when false.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub WantProof: bool,
+    /// The proof of work nonce of an append, see `pow`. Left out of
+    /// the encoding when empty.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub ProofOfWork: Vec<u8>,
+    /// The append token spent by an append to a spool which needs
+    /// them, see `tokens`. Left out of the encoding when empty.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub AppendToken: Vec<u8>,
+    /// A random key identifying an append, so that the service can
+    /// ignore retransmissions of it, see `dedup`. Left out of the
+    /// encoding when empty.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub IdempotencyKey: Vec<u8>,
+    /// Asks for an append to be flushed to disk before it is
+    /// acknowledged. Left out of the encoding when false.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub DurableAppend: bool,
+    /// The options a spool is created with, see `options`. Left out
+    /// of the encoding when None.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub Options: Option<SpoolOptions>,
+    /// The protocol version the request is in. Requests without one
+    /// predate it and are taken to be of major version 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub Version: Option<ProtocolVersion>,
 }
 
-#[derive(Serialize, Default)]
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
 #[allow(non_snake_case)]
 pub struct SpoolResponse {
-    #[serde(with = "serde_bytes")]
+    #[serde(serialize_with = "serde_bytes::serialize", deserialize_with = "nullable_bytes")]
     pub SpoolID: Vec<u8>,
-    #[serde(with = "serde_bytes")]
+    #[serde(serialize_with = "serde_bytes::serialize", deserialize_with = "nullable_bytes")]
     pub Message: Vec<u8>,
     pub Status: String,
+    /// The ID of the Katzenpost request this is a response to. Left
+    /// out of the encoding when zero, matching the Go implementation.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub RequestID: u64,
+    /// The command of the request, so that clients with several
+    /// requests outstanding can tell which this answers. Left out of
+    /// the encoding when the request couldn't be decoded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Command: Option<u8>,
+    /// The MessageID of the request, left out of the encoding when
+    /// empty or not MESSAGE_ID_SIZE bytes.
+    #[serde(with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub MessageID: Vec<u8>,
+    /// The protocol version of the service.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Version: Option<ProtocolVersion>,
+    /// The proof of the read message's position in its spool, if it
+    /// was asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Proof: Option<ReadProof>,
+    /// The unix time the read message was appended at. Left out of
+    /// the encoding when unknown.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub AppendedAt: u64,
+    /// How many messages the spool holds after the read one, so that
+    /// clients know whether to keep reading. Left out of the encoding
+    /// when zero.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub Remaining: u64,
+    /// Set when CREATE_SPOOL returned a spool the key already owned
+    /// rather than a new one.
+    #[serde(skip_serializing_if = "is_false")]
+    pub Existing: bool,
+    /// The service identity key's signature over `signing_message`,
+    /// left out of the encoding when the response is unsigned.
+    #[serde(with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub ResponseSignature: Vec<u8>,
+    /// Zero bytes which bring every encoded response to the same
+    /// size, see `encode_response`.
+    #[serde(with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub Padding: Vec<u8>,
+}
+
+/// Prefix of the messages responses are signed over.
+const RESPONSE_SIGNATURE_CONTEXT: &[u8] = b"multispool response v1";
+
+fn push_field(message: &mut Vec<u8>, field: &[u8]) {
+    let mut len = [0u8; 4];
+    BigEndian::write_u32(&mut len, field.len() as u32);
+    message.extend_from_slice(&len);
+    message.extend_from_slice(field);
+}
+
+impl SpoolResponse {
+    /// Returns the message the response is signed over: the request
+    /// ID, spool ID, the `message_id` of the request, the hash of the
+    /// message and the status, followed by the time the message was
+    /// appended at if it is known and the number of messages after it
+    /// if any, each tagged with its name so neither can pass for the
+    /// other.
+    pub fn signing_message(&self, message_id: &[u8]) -> Vec<u8> {
+        let mut message = RESPONSE_SIGNATURE_CONTEXT.to_vec();
+        let mut request_id = [0u8; 8];
+        BigEndian::write_u64(&mut request_id, self.RequestID);
+        message.extend_from_slice(&request_id);
+        push_field(&mut message, &self.SpoolID);
+        push_field(&mut message, message_id);
+        message.extend_from_slice(&Sha256::digest(&self.Message));
+        push_field(&mut message, self.Status.as_bytes());
+        if self.AppendedAt != 0 {
+            let mut appended_at = [0u8; 8];
+            BigEndian::write_u64(&mut appended_at, self.AppendedAt);
+            push_field(&mut message, b"appended_at");
+            message.extend_from_slice(&appended_at);
+        }
+        if self.Remaining != 0 {
+            let mut remaining = [0u8; 8];
+            BigEndian::write_u64(&mut remaining, self.Remaining);
+            push_field(&mut message, b"remaining");
+            message.extend_from_slice(&remaining);
+        }
+        message
+    }
+
+    /// Echoes the command and message ID of the request answered.
+    /// Message IDs of the wrong size are left out, so that padding
+    /// can't be defeated with a long one.
+    pub fn echo(&mut self, command: Option<u8>, message_id: &[u8]) {
+        self.Command = command;
+        self.MessageID = if message_id.len() == MESSAGE_ID_SIZE { message_id.to_vec() } else { vec![] };
+    }
+
+    /// Signs the response to a request for `message_id` with the
+    /// service identity key.
+    pub fn sign(&mut self, message_id: &[u8], identity: &Keypair) {
+        self.ResponseSignature = identity.sign(&self.signing_message(message_id)).to_bytes().to_vec();
+    }
+
+    /// Checks the response to a request for `message_id` is signed by
+    /// the service identity key `server_key`.
+    pub fn verify(&self, message_id: &[u8], server_key: &PublicKey) -> bool {
+        match Signature::from_bytes(&self.ResponseSignature) {
+            Ok(signature) => server_key.verify(&self.signing_message(message_id), &signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+fn is_zero(x: &u64) -> bool {
+    *x == 0
+}
+
+fn is_false(x: &bool) -> bool {
+    !*x
+}
+
+/// WireBytes decodes a byte string field the way the Go memspool
+/// encodes it: nil slices as null, and a MessageID as a number if
+/// `message_id`.
+struct WireBytes {
+    message_id: bool,
+}
+
+impl<'de> Visitor<'de> for WireBytes {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.message_id {
+            f.write_str("a byte string, a 32 bit unsigned integer or null")
+        } else {
+            f.write_str("a byte string or null")
+        }
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(v)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Vec<u8>, E> {
+        Ok(vec![])
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Vec<u8>, E> {
+        Ok(vec![])
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Vec<u8>, E> {
+        if !self.message_id {
+            return Err(E::invalid_type(Unexpected::Unsigned(v), &self))
+        }
+        if v > u64::from(std::u32::MAX) {
+            return Err(E::invalid_value(Unexpected::Unsigned(v), &self))
+        }
+        let mut message_id = vec![0u8; MESSAGE_ID_SIZE];
+        BigEndian::write_u32(&mut message_id, v as u32);
+        Ok(message_id)
+    }
+}
+
+fn nullable_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    deserializer.deserialize_any(WireBytes { message_id: false })
 }
 
-fn error_response(error_message: &'static str) -> SpoolResponse {
+fn message_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    deserializer.deserialize_any(WireBytes { message_id: true })
+}
+
+/// The longest status a padded response may carry.
+pub const MAX_STATUS_SIZE: usize = 64;
+
+/// The encoded size of the "Padding" map key.
+const PADDING_KEY_SIZE: usize = 8;
+
+/// The size of the padding's byte string header. It always carries a
+/// four byte length, though CBOR allows shorter ones for short byte
+/// strings, so that every size can be padded to exactly.
+const PADDING_HEADER_SIZE: usize = 5;
+
+/// The CBOR byte string header with a four byte length to follow.
+const BYTE_STRING_U32_HEADER: u8 = 0x5a;
+
+/// Returns the encoded size of every padded response: that of the
+/// largest response we can send, carrying a `max_message_size`
+/// message, the largest proof and a signature, plus room for the
+/// padding field.
+pub fn padded_response_size(max_message_size: usize) -> usize {
+    let largest = SpoolResponse {
+        SpoolID: vec![0u8; SPOOL_ID_SIZE],
+        Message: vec![0u8; max_message_size],
+        Status: "x".repeat(MAX_STATUS_SIZE),
+        RequestID: u64::max_value(),
+        Command: Some(u8::max_value()),
+        MessageID: vec![0u8; MESSAGE_ID_SIZE],
+        Version: Some(ProtocolVersion {
+            Major: u8::max_value(),
+            Minor: u8::max_value(),
+        }),
+        Proof: Some(ReadProof::largest()),
+        AppendedAt: u64::max_value(),
+        Remaining: u64::max_value(),
+        Existing: true,
+        ResponseSignature: vec![0u8; SIGNATURE_LENGTH],
+        ..SpoolResponse::default()
+    };
+    let size = serde_cbor::to_vec(&largest).map(|x| x.len()).unwrap_or(0);
+    size + PADDING_KEY_SIZE + PADDING_HEADER_SIZE
+}
+
+/// Pads the response to `padded_response_size`, or to `max_size` if
+/// that is smaller, and encodes it, so that a network observer can't
+/// tell a read hit from a miss or an error by its size. `max_size`
+/// is the largest encoded response which fits in a SURB reply.
+pub fn encode_response(response: &mut SpoolResponse, max_message_size: usize, max_size: Option<usize>) -> Result<Vec<u8>, ResponseError> {
+    response.Padding = vec![];
+    let unpadded = serde_cbor::to_vec(response)?;
+    let target = match max_size {
+        Some(max_size) => {
+            if unpadded.len() > max_size {
+                return Err(ResponseError::TooLarge { size: unpadded.len(), max_size: max_size })
+            }
+            cmp::min(max_size, padded_response_size(max_message_size))
+        },
+        None => padded_response_size(max_message_size),
+    };
+    if unpadded.len() + PADDING_KEY_SIZE + PADDING_HEADER_SIZE > target {
+        return Err(ResponseError::Unpaddable(unpadded.len()))
+    }
+    let len = target - unpadded.len() - PADDING_KEY_SIZE - PADDING_HEADER_SIZE;
+    // Encode a single byte of padding, so that the map counts the
+    // padding field, and then swap it, last in the map, for the
+    // padding behind a header of PADDING_HEADER_SIZE.
+    response.Padding = vec![0u8];
+    let mut raw = serde_cbor::to_vec(response)?;
+    raw.truncate(raw.len() - 2);
+    let mut header = [BYTE_STRING_U32_HEADER, 0, 0, 0, 0];
+    BigEndian::write_u32(&mut header[1..], len as u32);
+    raw.extend_from_slice(&header);
+    raw.resize(target, 0);
+    response.Padding = vec![0u8; len];
+    Ok(raw)
+}
+
+/// Returns a response carrying nothing but the given error status.
+pub fn error_response(error_message: &'static str) -> SpoolResponse {
     SpoolResponse{
         SpoolID: vec![],
         Message: vec![],
         Status: error_message.to_string(),
+        ..SpoolResponse::default()
+    }
+}
+
+/// Returns the request's spool ID, or None if it has the wrong size.
+fn request_spool_id(spool_request: &SpoolRequest) -> Option<[u8; SPOOL_ID_SIZE]> {
+    if spool_request.SpoolID.len() != SPOOL_ID_SIZE {
+        return None
     }
+    Some(*array_ref![spool_request.SpoolID, 0, SPOOL_ID_SIZE])
 }
 
-pub fn create_spool(spool_request: SpoolRequest, multi_spool: &mut MultiSpool) -> SpoolResponse {
+pub fn create_spool(spool_request: SpoolRequest, multi_spool: &dyn SpoolStore) -> SpoolResponse {
     let mut spool_response = SpoolResponse::default();
     if let Ok(signature) = Signature::from_bytes(&spool_request.Signature) {
         if let Ok(pub_key) = PublicKey::from_bytes(&spool_request.PublicKey) {
-            let mut csprng: OsRng = OsRng::new().unwrap();
-            match multi_spool.create_spool(pub_key, signature, &mut csprng) {
-                Ok(spool_id) => {
+            let options = spool_request.Options.clone().unwrap_or_default();
+            match multi_spool.allocate_spool(pub_key, signature, &options) {
+                Ok((spool_id, created)) => {
                     spool_response = SpoolResponse {
                         SpoolID: spool_id[..].to_vec(),
                         Message: vec![],
                         Status: "OK".to_string(),
+                        Existing: !created,
+                        ..SpoolResponse::default()
                     }
                 },
+                Err(MultiSpoolError::NotPrimary) => {
+                    spool_response = error_response(NOT_PRIMARY_STATUS);
+                },
+                Err(MultiSpoolError::OptionsError(_)) => {
+                    spool_response = error_response("error: invalid spool options");
+                },
+                Err(MultiSpoolError::OptionsMismatch) => {
+                    spool_response = error_response(OPTIONS_MISMATCH_STATUS);
+                },
+                Err(MultiSpoolError::StorageExhausted) => {
+                    spool_response = error_response(STORAGE_EXHAUSTED_STATUS);
+                },
                 Err(_) => {
                     spool_response = error_response("error: invalid create spool failed");
                 },
@@ -106,21 +599,29 @@ pub fn create_spool(spool_request: SpoolRequest, multi_spool: &mut MultiSpool) -
     spool_response
 }
 
-pub fn purge_spool(spool_request: SpoolRequest, multi_spool: &mut MultiSpool) -> SpoolResponse {
+pub fn purge_spool(spool_request: SpoolRequest, multi_spool: &dyn SpoolStore) -> SpoolResponse {
     let mut spool_response = SpoolResponse::default();
     if let Ok(signature) = Signature::from_bytes(&spool_request.Signature) {
         if let Ok(pub_key) = PublicKey::from_bytes(&spool_request.PublicKey) {
-            let mut csprng: OsRng = OsRng::new().unwrap();
-            let mut spool_id = [0u8; SPOOL_ID_SIZE];
-            spool_id[..].clone_from_slice(&spool_request.SpoolID);
+            let spool_id = match request_spool_id(&spool_request) {
+                Some(x) => x,
+                None => return error_response(INVALID_SPOOL_ID_STATUS),
+            };
             match multi_spool.purge_spool(spool_id, signature) {
                 Ok(_) => {
                     spool_response = SpoolResponse {
                         SpoolID: spool_request.SpoolID,
                         Message: vec![],
                         Status: "OK".to_string(),
+                        ..SpoolResponse::default()
                     }
                 },
+                Err(MultiSpoolError::SignatureBackoff) => {
+                    spool_response = error_response(RATE_LIMITED_STATUS);
+                },
+                Err(MultiSpoolError::NotPrimary) => {
+                    spool_response = error_response(NOT_PRIMARY_STATUS);
+                },
                 Err(_) => {
                     spool_response = error_response("error: purge spool failed");
                 },
@@ -134,20 +635,52 @@ pub fn purge_spool(spool_request: SpoolRequest, multi_spool: &mut MultiSpool) ->
     spool_response
 }
 
-pub fn append_to_spool(spool_request: SpoolRequest, multi_spool: &mut MultiSpool) -> SpoolResponse {
+pub fn append_to_spool(spool_request: SpoolRequest, multi_spool: &dyn SpoolStore) -> SpoolResponse {
     let mut spool_response = SpoolResponse::default();
-    let mut message = [0u8; MESSAGE_SIZE];
-    message.copy_from_slice(&spool_request.Message);
-    let mut spool_id = [0u8; SPOOL_ID_SIZE];
-    spool_id[..].clone_from_slice(&spool_request.SpoolID);
-    match multi_spool.append_to_spool(spool_id, message) {
+    if spool_request.Message.len() > multi_spool.max_message_size() {
+        return error_response("error: message too large")
+    }
+    let spool_id = match request_spool_id(&spool_request) {
+        Some(x) => x,
+        None => return error_response(INVALID_SPOOL_ID_STATUS),
+    };
+    // Appends to spools with an appender list carry the writer's
+    // public key and signature.
+    let auth = AppendAuth {
+        token: &spool_request.AppendToken,
+        public_key: &spool_request.PublicKey,
+        signature: &spool_request.Signature,
+    };
+    match multi_spool.append_idempotent(spool_id, &spool_request.Message, &auth, &spool_request.IdempotencyKey, spool_request.DurableAppend) {
         Ok(_) => {
             spool_response = SpoolResponse {
                 SpoolID: spool_request.SpoolID,
                 Message: vec![],
                 Status: "OK".to_string(),
+                ..SpoolResponse::default()
             }
                 },
+        Err(MultiSpoolError::InvalidAppendToken) => {
+            spool_response = error_response("error: invalid append token");
+        },
+        Err(MultiSpoolError::SpentAppendToken) => {
+            spool_response = error_response("error: append token already spent");
+        },
+        Err(MultiSpoolError::AppendNotAllowed) => {
+            spool_response = error_response(APPEND_NOT_ALLOWED_STATUS);
+        },
+        Err(MultiSpoolError::SpoolFull) => {
+            spool_response = error_response(SPOOL_FULL_STATUS);
+        },
+        Err(MultiSpoolError::StorageExhausted) => {
+            spool_response = error_response(STORAGE_EXHAUSTED_STATUS);
+        },
+        Err(MultiSpoolError::InvalidIdempotencyKey) => {
+            spool_response = error_response("error: invalid idempotency key");
+        },
+        Err(MultiSpoolError::NotPrimary) => {
+            spool_response = error_response(NOT_PRIMARY_STATUS);
+        },
         Err(_) => {
             spool_response = error_response("error: purge spool failed");
         },
@@ -155,22 +688,200 @@ pub fn append_to_spool(spool_request: SpoolRequest, multi_spool: &mut MultiSpool
     spool_response
 }
 
-pub fn read_from_spool(spool_request: SpoolRequest, multi_spool: &MultiSpool) -> SpoolResponse {
+/// Restores a purged spool before its grace period is over.
+pub fn undelete_spool(spool_request: SpoolRequest, multi_spool: &dyn SpoolStore) -> SpoolResponse {
+    let signature = match Signature::from_bytes(&spool_request.Signature) {
+        Ok(x) => x,
+        Err(_) => return error_response("error: invalid signature"),
+    };
+    let spool_id = match request_spool_id(&spool_request) {
+        Some(x) => x,
+        None => return error_response(INVALID_SPOOL_ID_STATUS),
+    };
+    match multi_spool.undelete_spool(spool_id, signature) {
+        Ok(_) => SpoolResponse {
+            SpoolID: spool_request.SpoolID,
+            Status: "OK".to_string(),
+            ..SpoolResponse::default()
+        },
+        Err(MultiSpoolError::SignatureBackoff) => error_response(RATE_LIMITED_STATUS),
+        Err(MultiSpoolError::NotPrimary) => error_response(NOT_PRIMARY_STATUS),
+        Err(_) => error_response("error: undelete spool failed"),
+    }
+}
+
+/// Sets or, given an empty message, clears the key the spool owner
+/// issues append tokens with.
+pub fn set_append_token_key(spool_request: SpoolRequest, multi_spool: &dyn SpoolStore) -> SpoolResponse {
+    let signature = match Signature::from_bytes(&spool_request.Signature) {
+        Ok(x) => x,
+        Err(_) => return error_response("error: invalid signature"),
+    };
+    let key = if spool_request.Message.is_empty() {
+        None
+    } else {
+        match TokenKey::from_bytes(&spool_request.Message) {
+            Ok(x) => Some(x),
+            Err(_) => return error_response("error: invalid token key"),
+        }
+    };
+    let spool_id = match request_spool_id(&spool_request) {
+        Some(x) => x,
+        None => return error_response(INVALID_SPOOL_ID_STATUS),
+    };
+    match multi_spool.set_append_token_key(spool_id, signature, key) {
+        Ok(_) => SpoolResponse {
+            SpoolID: spool_request.SpoolID,
+            Status: "OK".to_string(),
+            ..SpoolResponse::default()
+        },
+        Err(MultiSpoolError::SignatureBackoff) => error_response(RATE_LIMITED_STATUS),
+        Err(MultiSpoolError::NotPrimary) => error_response(NOT_PRIMARY_STATUS),
+        Err(_) => error_response("error: set token key failed"),
+    }
+}
+
+/// Restricts appends to the writers whose public keys are
+/// concatenated in the message or, given an empty message, lets
+/// anyone append again.
+pub fn set_appenders(spool_request: SpoolRequest, multi_spool: &dyn SpoolStore) -> SpoolResponse {
+    let signature = match Signature::from_bytes(&spool_request.Signature) {
+        Ok(x) => x,
+        Err(_) => return error_response("error: invalid signature"),
+    };
+    let appenders = if spool_request.Message.is_empty() {
+        None
+    } else {
+        match AppenderList::from_bytes(&spool_request.Message) {
+            Ok(x) => Some(x),
+            Err(_) => return error_response("error: invalid appender list"),
+        }
+    };
+    let spool_id = match request_spool_id(&spool_request) {
+        Some(x) => x,
+        None => return error_response(INVALID_SPOOL_ID_STATUS),
+    };
+    match multi_spool.set_appenders(spool_id, signature, appenders) {
+        Ok(_) => SpoolResponse {
+            SpoolID: spool_request.SpoolID,
+            Status: "OK".to_string(),
+            ..SpoolResponse::default()
+        },
+        Err(MultiSpoolError::SignatureBackoff) => error_response(RATE_LIMITED_STATUS),
+        Err(MultiSpoolError::NotPrimary) => error_response(NOT_PRIMARY_STATUS),
+        Err(_) => error_response("error: set appenders failed"),
+    }
+}
+
+/// Answers a SPOOL_STATUS command with the CBOR encoded SpoolStats of
+/// the spool in the response message.
+pub fn spool_status(spool_request: SpoolRequest, multi_spool: &dyn SpoolStore) -> SpoolResponse {
+    let signature = match Signature::from_bytes(&spool_request.Signature) {
+        Ok(x) => x,
+        Err(_) => return error_response("error: invalid signature"),
+    };
+    let spool_id = match request_spool_id(&spool_request) {
+        Some(x) => x,
+        None => return error_response(INVALID_SPOOL_ID_STATUS),
+    };
+    let stats = match multi_spool.spool_status(spool_id, signature) {
+        Ok(x) => x,
+        Err(MultiSpoolError::SignatureBackoff) => return error_response(RATE_LIMITED_STATUS),
+        Err(_) => return error_response("error: spool status failed"),
+    };
+    match serde_cbor::to_vec(&stats) {
+        Ok(message) => SpoolResponse {
+            SpoolID: spool_request.SpoolID,
+            Message: message,
+            Status: "OK".to_string(),
+            ..SpoolResponse::default()
+        },
+        Err(_) => error_response("error: failed to encode spool status"),
+    }
+}
+
+/// Copies a spool into a new one, answering with the new spool ID. The
+/// message holds the public key of the copy's owner or, if empty, the
+/// copy has the same owner.
+pub fn copy_spool(spool_request: SpoolRequest, multi_spool: &dyn SpoolStore) -> SpoolResponse {
+    let signature = match Signature::from_bytes(&spool_request.Signature) {
+        Ok(x) => x,
+        Err(_) => return error_response("error: invalid signature"),
+    };
+    let new_owner = if spool_request.Message.is_empty() {
+        None
+    } else {
+        match PublicKey::from_bytes(&spool_request.Message) {
+            Ok(x) => Some(x),
+            Err(_) => return error_response("error: invalid ed25519 public key"),
+        }
+    };
+    let spool_id = match request_spool_id(&spool_request) {
+        Some(x) => x,
+        None => return error_response(INVALID_SPOOL_ID_STATUS),
+    };
+    match multi_spool.copy_spool(spool_id, signature, new_owner) {
+        Ok(copy_id) => SpoolResponse {
+            SpoolID: copy_id.to_vec(),
+            Status: "OK".to_string(),
+            ..SpoolResponse::default()
+        },
+        Err(MultiSpoolError::SignatureBackoff) => error_response(RATE_LIMITED_STATUS),
+        Err(MultiSpoolError::NotPrimary) => error_response(NOT_PRIMARY_STATUS),
+        Err(MultiSpoolError::StorageExhausted) => error_response(STORAGE_EXHAUSTED_STATUS),
+        Err(_) => error_response("error: copy spool failed"),
+    }
+}
+
+/// Hands a spool over to the new owner key in the message, keeping
+/// its spool ID, see `rekey`.
+pub fn rekey_spool(spool_request: SpoolRequest, multi_spool: &dyn SpoolStore) -> SpoolResponse {
+    let signature = match Signature::from_bytes(&spool_request.Signature) {
+        Ok(x) => x,
+        Err(_) => return error_response("error: invalid signature"),
+    };
+    let rekey = match Rekey::from_bytes(&spool_request.Message) {
+        Some(x) => x,
+        None => return error_response("error: invalid new owner key"),
+    };
+    let spool_id = match request_spool_id(&spool_request) {
+        Some(x) => x,
+        None => return error_response(INVALID_SPOOL_ID_STATUS),
+    };
+    match multi_spool.rekey_spool(spool_id, signature, &rekey) {
+        Ok(_) => SpoolResponse {
+            SpoolID: spool_request.SpoolID,
+            Status: "OK".to_string(),
+            ..SpoolResponse::default()
+        },
+        Err(MultiSpoolError::SignatureBackoff) => error_response(RATE_LIMITED_STATUS),
+        Err(MultiSpoolError::NotPrimary) => error_response(NOT_PRIMARY_STATUS),
+        Err(MultiSpoolError::InvalidRekey) => error_response("error: invalid new owner key"),
+        Err(_) => error_response("error: rekey spool failed"),
+    }
+}
+
+pub fn read_from_spool(spool_request: SpoolRequest, multi_spool: &dyn SpoolStore) -> SpoolResponse {
     let mut spool_response = SpoolResponse::default();
     if let Ok(signature) = Signature::from_bytes(&spool_request.Signature) {
         if let Ok(pub_key) = PublicKey::from_bytes(&spool_request.PublicKey) {
-            let mut csprng: OsRng = OsRng::new().unwrap();
-            let mut spool_id = [0u8; SPOOL_ID_SIZE];
-            spool_id[..].clone_from_slice(&spool_request.SpoolID);
-            let mut message_id = [0u8; MESSAGE_ID_SIZE];
-            message_id[..].clone_from_slice(&spool_request.MessageID);
-            match multi_spool.read_from_spool(spool_id, signature, &message_id) {
-                Ok(response_message) => {
-                    spool_response = SpoolResponse {
-                        SpoolID: spool_request.SpoolID,
-                        Message: response_message.to_vec(),
-                        Status: "OK".to_string(),
-                    }
+            let spool_id = match request_spool_id(&spool_request) {
+                Some(x) => x,
+                None => return error_response(INVALID_SPOOL_ID_STATUS),
+            };
+            if spool_request.MessageID.len() != MESSAGE_ID_SIZE {
+                return error_response("error: invalid message id")
+            }
+            let message_id = *array_ref![spool_request.MessageID, 0, MESSAGE_ID_SIZE];
+            match multi_spool.read_from_spool(spool_id, &pub_key, signature, &message_id) {
+                Ok((response_message, appended_at)) => {
+                    spool_response = read_response(&spool_request, spool_id, &message_id, response_message, appended_at, multi_spool);
+                },
+                Err(MultiSpoolError::SignatureBackoff) => {
+                    spool_response = error_response(RATE_LIMITED_STATUS);
+                },
+                Err(MultiSpoolError::NotReader) => {
+                    spool_response = error_response("error: not a reader");
                 },
                 Err(_) => {
                     spool_response = error_response("error: purge spool failed");
@@ -184,3 +895,360 @@ pub fn read_from_spool(spool_request: SpoolRequest, multi_spool: &MultiSpool) ->
     }
     spool_response
 }
+
+/// Answers a read of the message with `message_id`, counting the
+/// messages after it and adding its proof if the request asked for
+/// one.
+fn read_response(spool_request: &SpoolRequest,
+                 spool_id: [u8; SPOOL_ID_SIZE],
+                 message_id: &[u8; MESSAGE_ID_SIZE],
+                 message: Vec<u8>,
+                 appended_at: Option<u64>,
+                 multi_spool: &dyn SpoolStore)
+                 -> SpoolResponse {
+    let remaining = match multi_spool.message_count(spool_id) {
+        Ok(count) => count.saturating_sub(u64::from(BigEndian::read_u32(message_id)) + 1),
+        Err(_) => return error_response("error: failed to count messages"),
+    };
+    let mut proof = None;
+    if spool_request.WantProof {
+        match multi_spool.read_proof(spool_id, message_id) {
+            Ok(x) => proof = Some(x),
+            Err(_) => return error_response("error: failed to make read proof"),
+        }
+    }
+    SpoolResponse {
+        SpoolID: spool_request.SpoolID.clone(),
+        Message: message,
+        Status: "OK".to_string(),
+        Proof: proof,
+        AppendedAt: appended_at.unwrap_or(0),
+        Remaining: remaining,
+        ..SpoolResponse::default()
+    }
+}
+
+/// Reads the latest message whose Merkle leaf hash is in the request
+/// message, from a spool created with a hash index. It is answered
+/// like a read, with the ID of the message found in MessageID.
+pub fn read_by_hash(spool_request: SpoolRequest, multi_spool: &dyn SpoolStore) -> SpoolResponse {
+    let signature = match Signature::from_bytes(&spool_request.Signature) {
+        Ok(x) => x,
+        Err(_) => return error_response("error: invalid signature"),
+    };
+    let public_key = match PublicKey::from_bytes(&spool_request.PublicKey) {
+        Ok(x) => x,
+        Err(_) => return error_response("error: invalid ed25519 public key"),
+    };
+    if spool_request.Message.len() != merkle::HASH_SIZE {
+        return error_response("error: invalid message hash")
+    }
+    let leaf = *array_ref![spool_request.Message, 0, merkle::HASH_SIZE];
+    let spool_id = match request_spool_id(&spool_request) {
+        Some(x) => x,
+        None => return error_response(INVALID_SPOOL_ID_STATUS),
+    };
+    match multi_spool.read_by_hash(spool_id, &public_key, signature, &leaf) {
+        Ok((message_id, message, appended_at)) => {
+            let mut response = read_response(&spool_request, spool_id, &message_id, message, appended_at, multi_spool);
+            if response.Status == "OK" {
+                response.MessageID = message_id.to_vec();
+            }
+            response
+        },
+        Err(MultiSpoolError::SignatureBackoff) => error_response(RATE_LIMITED_STATUS),
+        Err(MultiSpoolError::NotReader) => error_response("error: not a reader"),
+        Err(MultiSpoolError::NoHashIndex) => error_response("error: no hash index"),
+        Err(MultiSpoolError::SpoolError(SpoolError::NoSuchMessage)) => error_response("error: no such message"),
+        Err(_) => error_response("error: read by hash failed"),
+    }
+}
+
+/// Reads the message as many messages before the newest as the big
+/// endian offset in the request message says, zero for the newest.
+/// It is answered like a read, with the ID of the message read in
+/// MessageID.
+pub fn read_latest(spool_request: SpoolRequest, multi_spool: &dyn SpoolStore) -> SpoolResponse {
+    let signature = match Signature::from_bytes(&spool_request.Signature) {
+        Ok(x) => x,
+        Err(_) => return error_response("error: invalid signature"),
+    };
+    let public_key = match PublicKey::from_bytes(&spool_request.PublicKey) {
+        Ok(x) => x,
+        Err(_) => return error_response("error: invalid ed25519 public key"),
+    };
+    if spool_request.Message.len() != 4 {
+        return error_response("error: invalid offset")
+    }
+    let offset = BigEndian::read_u32(&spool_request.Message);
+    let spool_id = match request_spool_id(&spool_request) {
+        Some(x) => x,
+        None => return error_response(INVALID_SPOOL_ID_STATUS),
+    };
+    match multi_spool.read_latest(spool_id, &public_key, signature, offset) {
+        Ok((message_id, message, appended_at)) => {
+            let mut response = read_response(&spool_request, spool_id, &message_id, message, appended_at, multi_spool);
+            if response.Status == "OK" {
+                response.MessageID = message_id.to_vec();
+            }
+            response
+        },
+        Err(MultiSpoolError::SignatureBackoff) => error_response(RATE_LIMITED_STATUS),
+        Err(MultiSpoolError::NotReader) => error_response("error: not a reader"),
+        Err(MultiSpoolError::SpoolError(SpoolError::NoSuchMessage)) => error_response("error: no such message"),
+        Err(_) => error_response("error: read latest failed"),
+    }
+}
+
+/// Answers a VERSION command with the CBOR encoded BuildInfo of
+/// this build in the response message.
+pub fn version(spool_request: SpoolRequest) -> SpoolResponse {
+    match serde_cbor::to_vec(&BuildInfo::new()) {
+        Ok(build_info) => {
+            SpoolResponse {
+                SpoolID: spool_request.SpoolID,
+                Message: build_info,
+                Status: "OK".to_string(),
+                ..SpoolResponse::default()
+            }
+        },
+        Err(_) => {
+            error_response("error: failed to encode build info")
+        },
+    }
+}
+
+/// Returns the spool request a plugin request payload carries after
+/// its big endian length, or None if the payload is too short.
+pub fn spool_request_payload(payload: &[u8]) -> Option<&[u8]> {
+    if payload.len() < 4 {
+        return None
+    }
+    let len = BigEndian::read_u32(&payload[..4]) as usize;
+    payload[4..].get(..len)
+}
+
+/// Dispatches a decoded spool request to the handler for its command.
+/// `max_spools` and `append_pow_difficulty` are the limits configured
+/// for creates and appends.
+pub fn handle_spool_request(spool_request: SpoolRequest, request_id: u64, max_spools: Option<u64>, append_pow_difficulty: Option<u32>, multi_spool: &dyn SpoolStore) -> SpoolResponse {
+    let _timer = metrics::REQUEST_LATENCY.with_label_values(&[command_name(spool_request.Command)]).start_timer();
+    let mut span = trace::span("handle_spool_request");
+    span.set_attribute("command", command_name(spool_request.Command).to_string());
+    span.set_attribute("request_id", request_id.to_string());
+    info!("handling {} request", command_name(spool_request.Command));
+    if let Some(version) = spool_request.Version {
+        if !version.is_supported() {
+            info!("refused a request of protocol version {}", version);
+            return error_response(UNSUPPORTED_VERSION_STATUS)
+        }
+    }
+    match spool_request.Command {
+        CREATE_SPOOL_COMMAND => {
+            if let Some(max_spools) = max_spools {
+                if multi_spool.spool_count() as u64 >= max_spools {
+                    return error_response("error: too many spools")
+                }
+            }
+            return create_spool(spool_request, multi_spool)
+        },
+        PURGE_SPOOL_COMMAND => {
+            return purge_spool(spool_request, multi_spool)
+        },
+        APPEND_MESSAGE_COMMAND => {
+            if let Some(difficulty) = append_pow_difficulty {
+                if !pow::verify(&spool_request.SpoolID, &spool_request.Message, &spool_request.ProofOfWork, difficulty) {
+                    return error_response("error: invalid proof of work")
+                }
+            }
+            return append_to_spool(spool_request, multi_spool)
+        },
+        RETRIEVE_MESSAGE_COMMAND => {
+            return read_from_spool(spool_request, multi_spool)
+        }
+        VERSION_COMMAND => {
+            return version(spool_request)
+        }
+        SET_APPEND_TOKEN_KEY_COMMAND => {
+            return set_append_token_key(spool_request, multi_spool)
+        }
+        UNDELETE_SPOOL_COMMAND => {
+            return undelete_spool(spool_request, multi_spool)
+        }
+        SET_APPENDERS_COMMAND => {
+            return set_appenders(spool_request, multi_spool)
+        }
+        SPOOL_STATUS_COMMAND => {
+            return spool_status(spool_request, multi_spool)
+        }
+        COPY_SPOOL_COMMAND => {
+            return copy_spool(spool_request, multi_spool)
+        }
+        REKEY_SPOOL_COMMAND => {
+            return rekey_spool(spool_request, multi_spool)
+        }
+        RETRIEVE_BY_HASH_COMMAND => {
+            return read_by_hash(spool_request, multi_spool)
+        }
+        RETRIEVE_LATEST_COMMAND => {
+            return read_latest(spool_request, multi_spool)
+        }
+        _ => {
+            return SpoolResponse{
+                SpoolID: spool_request.SpoolID,
+                Message: vec![],
+                Status: String::from("error, invalid command"),
+                ..SpoolResponse::default()
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_cbor;
+
+    use crate::spool::MESSAGE_SIZE;
+    use super::*;
+
+    #[test]
+    fn padded_responses_are_the_same_size_test() {
+        let mut hit = SpoolResponse {
+            SpoolID: vec![1u8; SPOOL_ID_SIZE],
+            Message: vec![2u8; MESSAGE_SIZE],
+            Status: "OK".to_string(),
+            RequestID: 1234,
+            ..SpoolResponse::default()
+        };
+        let mut miss = error_response("error: no such message");
+        let raw_hit = encode_response(&mut hit, MESSAGE_SIZE, None).unwrap();
+        let raw_miss = encode_response(&mut miss, MESSAGE_SIZE, None).unwrap();
+        assert_eq!(raw_hit.len(), padded_response_size(MESSAGE_SIZE));
+        assert_eq!(raw_miss.len(), padded_response_size(MESSAGE_SIZE));
+        let decoded: SpoolResponse = serde_cbor::from_slice(&raw_miss).unwrap();
+        assert_eq!(decoded.Status, "error: no such message");
+    }
+
+    #[test]
+    fn padding_every_status_size_test() {
+        for size in 0..MAX_STATUS_SIZE + 1 {
+            let mut response = SpoolResponse {
+                Status: "x".repeat(size),
+                ..SpoolResponse::default()
+            };
+            let raw = encode_response(&mut response, MESSAGE_SIZE, None).unwrap();
+            assert_eq!(raw.len(), padded_response_size(MESSAGE_SIZE));
+        }
+    }
+
+    #[test]
+    fn padding_every_message_size_test() {
+        // Spans the sizes CBOR would give a shorter padding header.
+        let max_message_size = 600;
+        for size in 0..max_message_size + 1 {
+            let mut response = SpoolResponse {
+                Message: vec![7u8; size],
+                Status: "OK".to_string(),
+                ..SpoolResponse::default()
+            };
+            let raw = encode_response(&mut response, max_message_size, None).unwrap();
+            assert_eq!(raw.len(), padded_response_size(max_message_size));
+            let decoded: SpoolResponse = serde_cbor::from_slice(&raw).unwrap();
+            assert_eq!(decoded.Message, response.Message);
+            assert_eq!(decoded.Padding.len(), response.Padding.len());
+        }
+    }
+
+    #[test]
+    fn response_signature_test() {
+        let identity = Keypair::generate(&mut rand::thread_rng());
+        let mut response = SpoolResponse {
+            SpoolID: vec![1u8; SPOOL_ID_SIZE],
+            Message: b"hello".to_vec(),
+            Status: "OK".to_string(),
+            RequestID: 1234,
+            ..SpoolResponse::default()
+        };
+        response.sign(&[0, 0, 0, 3], &identity);
+        let raw = encode_response(&mut response, MESSAGE_SIZE, None).unwrap();
+        let mut decoded: SpoolResponse = serde_cbor::from_slice(&raw).unwrap();
+        assert!(decoded.verify(&[0, 0, 0, 3], &identity.public));
+        assert!(!decoded.verify(&[0, 0, 0, 4], &identity.public));
+        decoded.Status = "error: no such message".to_string();
+        assert!(!decoded.verify(&[0, 0, 0, 3], &identity.public));
+        decoded.Status = "OK".to_string();
+        decoded.Remaining = 1;
+        assert!(!decoded.verify(&[0, 0, 0, 3], &identity.public));
+
+        // An append time can't pass for a count of remaining messages.
+        let remaining = decoded.signing_message(&[0, 0, 0, 3]);
+        decoded.Remaining = 0;
+        decoded.AppendedAt = 1;
+        assert_ne!(decoded.signing_message(&[0, 0, 0, 3]), remaining);
+        assert!(!error_response("error: no such message").verify(&[], &identity.public));
+    }
+
+    #[test]
+    fn echoed_response_padding_test() {
+        let mut response = SpoolResponse {
+            SpoolID: vec![1u8; SPOOL_ID_SIZE],
+            Message: vec![2u8; MESSAGE_SIZE],
+            Status: "OK".to_string(),
+            RequestID: 1234,
+            AppendedAt: 1500000000,
+            Remaining: 3,
+            ..SpoolResponse::default()
+        };
+        response.echo(Some(RETRIEVE_MESSAGE_COMMAND), &[0, 0, 0, 1]);
+        let raw = encode_response(&mut response, MESSAGE_SIZE, None).unwrap();
+        assert_eq!(raw.len(), padded_response_size(MESSAGE_SIZE));
+        assert_eq!(response.MessageID, vec![0, 0, 0, 1]);
+
+        response.echo(Some(RETRIEVE_MESSAGE_COMMAND), &[7u8; 1000]);
+        assert!(response.MessageID.is_empty());
+        assert_eq!(encode_response(&mut response, MESSAGE_SIZE, None).unwrap().len(), raw.len());
+    }
+
+    #[test]
+    fn unsupported_version_test() {
+        let store = crate::store::MemorySpoolStore::default();
+        let request = |version| SpoolRequest {
+            Command: VERSION_COMMAND,
+            Version: version,
+            ..SpoolRequest::default()
+        };
+        assert_eq!(handle_spool_request(request(None), 1, None, None, &store).Status, "OK");
+        assert_eq!(handle_spool_request(request(Some(ProtocolVersion::current())), 1, None, None, &store).Status, "OK");
+        let next = ProtocolVersion {
+            Major: PROTOCOL_VERSION + 1,
+            Minor: 0,
+        };
+        assert_eq!(handle_spool_request(request(Some(next)), 1, None, None, &store).Status, UNSUPPORTED_VERSION_STATUS);
+    }
+
+    #[test]
+    fn request_spool_id_test() {
+        let mut request = SpoolRequest {
+            SpoolID: vec![1u8; SPOOL_ID_SIZE],
+            ..SpoolRequest::default()
+        };
+        assert_eq!(request_spool_id(&request), Some([1u8; SPOOL_ID_SIZE]));
+        request.SpoolID.pop();
+        assert_eq!(request_spool_id(&request), None);
+    }
+
+    #[test]
+    fn max_response_size_test() {
+        let mut response = SpoolResponse {
+            Message: vec![2u8; MESSAGE_SIZE],
+            Status: "OK".to_string(),
+            ..SpoolResponse::default()
+        };
+        match encode_response(&mut response, MESSAGE_SIZE, Some(MESSAGE_SIZE)) {
+            Err(ResponseError::TooLarge { max_size, .. }) => assert_eq!(max_size, MESSAGE_SIZE),
+            _ => panic!("expected a TooLarge error"),
+        }
+        let mut error = error_response("error: response too large");
+        assert_eq!(encode_response(&mut error, MESSAGE_SIZE, Some(MESSAGE_SIZE)).unwrap().len(), MESSAGE_SIZE);
+    }
+}
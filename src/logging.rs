@@ -0,0 +1,150 @@
+// logging.rs - Multi-spool service logging.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Service logging
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use log::LevelFilter;
+use log4rs::{self, Handle};
+use log4rs::append::Append;
+use log4rs::append::console::{ConsoleAppender, Target};
+use log4rs::append::file::FileAppender;
+use log4rs::config::{Appender, Config, Root};
+use log4rs::encode::pattern::PatternEncoder;
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+
+use crate::config;
+use crate::errors::ConfigError;
+use crate::syslog::{Facility, SyslogAppender};
+
+
+/// The mapped diagnostic context key holding the Katzenpost request
+/// ID of the request being handled.
+pub const REQUEST_ID_KEY: &str = "request_id";
+
+/// The log output used when none is configured.
+pub const DEFAULT_LOG_OUTPUT: &str = "file";
+
+/// LogOutput selects where log records are written. Note that the
+/// Katzenpost server reads the plugin's socket path from stdout, so
+/// stderr is the better choice for stream logging under Katzenpost.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogOutput {
+    /// A randomly named file in the log directory.
+    File,
+    Stdout,
+    Stderr,
+    /// The local syslog or journald socket.
+    Syslog,
+}
+
+impl FromStr for LogOutput {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<LogOutput, ConfigError> {
+        match s {
+            "file" => Ok(LogOutput::File),
+            "stdout" => Ok(LogOutput::Stdout),
+            "stderr" => Ok(LogOutput::Stderr),
+            "syslog" => Ok(LogOutput::Syslog),
+            _ => Err(ConfigError::InvalidLogOutput(s.to_string())),
+        }
+    }
+}
+
+/// Logger owns the log4rs handle so that the log level can be
+/// changed while the service is running.
+pub struct Logger {
+    handle: Handle,
+    destination: Destination,
+}
+
+/// Destination is everything needed to rebuild our appender.
+struct Destination {
+    output: LogOutput,
+    log_path: Option<PathBuf>,
+    syslog_path: PathBuf,
+    syslog_facility: Facility,
+}
+
+impl Logger {
+    /// Starts logging as configured. File output goes to a randomly
+    /// named file in the log directory.
+    pub fn init(cfg: &config::Config) -> Result<Logger, ConfigError> {
+        let output = cfg.log_output()?;
+        let log_path = match output {
+            LogOutput::File => {
+                let rand_string: String = thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(10)
+                    .collect();
+                let log_dir = cfg.log_dir.as_ref().expect("log_dir must be set when logging to a file");
+                Some(Path::new(log_dir).join(format!("multispool_{}.log", rand_string)))
+            },
+            _ => None,
+        };
+        let destination = Destination {
+            output: output,
+            log_path: log_path,
+            syslog_path: PathBuf::from(cfg.syslog_path()),
+            syslog_facility: cfg.syslog_facility()?,
+        };
+        let handle = log4rs::init_config(log_config(&destination, cfg.log_level_filter()?)).unwrap();
+        Ok(Logger {
+            handle: handle,
+            destination: destination,
+        })
+    }
+
+    /// Replaces the log level of the running logger.
+    pub fn set_level(&self, level: LevelFilter) {
+        self.handle.set_config(log_config(&self.destination, level));
+        info!("log level set to {}", level);
+    }
+}
+
+fn log_config(destination: &Destination, level: LevelFilter) -> Config {
+    let encoder = Box::new(PatternEncoder::new("{d} - {X(request_id)(-)} - {m}{n}"));
+    let appender: Box<Append> = match destination.output {
+        LogOutput::File => {
+            Box::new(FileAppender::builder()
+                     .encoder(encoder)
+                     .build(destination.log_path.as_ref().unwrap())
+                     .unwrap())
+        },
+        LogOutput::Syslog => {
+            Box::new(SyslogAppender::new(&destination.syslog_path, destination.syslog_facility).unwrap())
+        },
+        LogOutput::Stdout => {
+            Box::new(ConsoleAppender::builder()
+                     .encoder(encoder)
+                     .target(Target::Stdout)
+                     .build())
+        },
+        LogOutput::Stderr => {
+            Box::new(ConsoleAppender::builder()
+                     .encoder(encoder)
+                     .target(Target::Stderr)
+                     .build())
+        },
+    };
+    Config::builder()
+        .appender(Appender::builder().build("requests", appender))
+        .build(Root::builder().appender("requests").build(level))
+        .unwrap()
+}
@@ -0,0 +1,243 @@
+// merkle.rs - Multi-spool Merkle tree proofs.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Verifiable reads
+//!
+//! Every spool is the list of leaves of an RFC 6962 style Merkle
+//! tree, each leaf being the hash of a message. A read may carry a
+//! `ReadProof`: the message's audit path and the tree root, signed by
+//! the service's identity key. Clients holding the service's public
+//! key can then check that the message they got is the one at its
+//! position in the spool, and by comparing tree sizes and roots over
+//! time, that no message was dropped or substituted.
+
+use byteorder::{ByteOrder, BigEndian};
+use ed25519_dalek::{Keypair, PublicKey, Signature};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+
+/// The size of a tree hash in bytes.
+pub const HASH_SIZE: usize = 32;
+
+/// The longest audit path, that of a tree with 2^32 leaves.
+pub const MAX_AUDIT_PATH_LEN: usize = 32;
+
+/// Prefix of the messages signed roots are signed over.
+const ROOT_SIGNATURE_CONTEXT: &[u8] = b"multispool merkle root v1";
+
+pub type Hash = [u8; HASH_SIZE];
+
+fn hash(parts: &[&[u8]]) -> Hash {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.input(part);
+    }
+    let mut out = [0u8; HASH_SIZE];
+    out.copy_from_slice(&hasher.result());
+    out
+}
+
+/// Returns the leaf hash of a message.
+pub fn leaf_hash(message: &[u8]) -> Hash {
+    hash(&[&[0u8], message])
+}
+
+fn node_hash(left: &[u8], right: &[u8]) -> Hash {
+    hash(&[&[1u8], left, right])
+}
+
+/// Returns the largest power of two smaller than `n`, for n > 1.
+fn split(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+/// Returns the root of the tree with the given leaf hashes.
+pub fn root(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => hash(&[]),
+        1 => leaves[0],
+        n => {
+            let k = split(n);
+            node_hash(&root(&leaves[..k]), &root(&leaves[k..]))
+        },
+    }
+}
+
+/// Returns the audit path of the leaf at `index`, from the leaf up.
+pub fn audit_path(index: usize, leaves: &[Hash]) -> Vec<Hash> {
+    let n = leaves.len();
+    if n <= 1 || index >= n {
+        return vec![]
+    }
+    let k = split(n);
+    let (mut path, sibling) = if index < k {
+        (audit_path(index, &leaves[..k]), root(&leaves[k..]))
+    } else {
+        (audit_path(index - k, &leaves[k..]), root(&leaves[..k]))
+    };
+    path.push(sibling);
+    path
+}
+
+/// Checks that `leaf` is at `index` of the tree of `tree_size` leaves
+/// with the given root, per RFC 9162 section 2.1.3.2.
+pub fn verify_inclusion(leaf: &Hash, index: u64, tree_size: u64, path: &[Hash], root: &Hash) -> bool {
+    if index >= tree_size {
+        return false
+    }
+    let mut f = index;
+    let mut s = tree_size - 1;
+    let mut r = *leaf;
+    for p in path {
+        if s == 0 {
+            return false
+        }
+        if f & 1 == 1 || f == s {
+            r = node_hash(p, &r);
+            while f & 1 == 0 && f != 0 {
+                f >>= 1;
+                s >>= 1;
+            }
+        } else {
+            r = node_hash(&r, p);
+        }
+        f >>= 1;
+        s >>= 1;
+    }
+    s == 0 && r == *root
+}
+
+/// Returns the message a spool's tree root is signed over.
+pub fn root_signing_message(spool_id: &[u8], tree_size: u32, root: &Hash) -> Vec<u8> {
+    let mut message = ROOT_SIGNATURE_CONTEXT.to_vec();
+    message.extend_from_slice(spool_id);
+    let mut size = [0u8; 4];
+    BigEndian::write_u32(&mut size, tree_size);
+    message.extend_from_slice(&size);
+    message.extend_from_slice(root);
+    message
+}
+
+/// ReadProof proves a message's position in its spool.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[allow(non_snake_case)]
+pub struct ReadProof {
+    pub TreeSize: u32,
+    pub LeafIndex: u32,
+    pub AuditPath: Vec<ByteBuf>,
+    #[serde(with = "serde_bytes")]
+    pub Root: Vec<u8>,
+    /// The service's signature over `root_signing_message`, empty if
+    /// the service has no identity key.
+    #[serde(with = "serde_bytes")]
+    pub RootSignature: Vec<u8>,
+}
+
+impl ReadProof {
+    /// Makes the proof for the leaf at `index`, signing the root with
+    /// `identity` if given.
+    pub fn new(spool_id: &[u8], index: u32, leaves: &[Hash], identity: Option<&Keypair>) -> ReadProof {
+        let root = root(leaves);
+        let signature = match identity {
+            Some(keypair) => keypair.sign(&root_signing_message(spool_id, leaves.len() as u32, &root)).to_bytes().to_vec(),
+            None => vec![],
+        };
+        ReadProof {
+            TreeSize: leaves.len() as u32,
+            LeafIndex: index,
+            AuditPath: audit_path(index as usize, leaves).iter().map(|x| ByteBuf::from(x.to_vec())).collect(),
+            Root: root.to_vec(),
+            RootSignature: signature,
+        }
+    }
+
+    /// Checks that `message` is the message at `message_id` of the
+    /// spool, and that the root is signed by `server_key`.
+    pub fn verify(&self, spool_id: &[u8], message_id: u32, message: &[u8], server_key: &PublicKey) -> bool {
+        if self.LeafIndex != message_id || self.Root.len() != HASH_SIZE || self.AuditPath.len() > MAX_AUDIT_PATH_LEN {
+            return false
+        }
+        let mut path = Vec::with_capacity(self.AuditPath.len());
+        for node in &self.AuditPath {
+            if node.len() != HASH_SIZE {
+                return false
+            }
+            let mut hash = [0u8; HASH_SIZE];
+            hash.copy_from_slice(node);
+            path.push(hash);
+        }
+        let mut root = [0u8; HASH_SIZE];
+        root.copy_from_slice(&self.Root);
+        if !verify_inclusion(&leaf_hash(message), u64::from(self.LeafIndex), u64::from(self.TreeSize), &path, &root) {
+            return false
+        }
+        let signature = match Signature::from_bytes(&self.RootSignature) {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+        server_key.verify(&root_signing_message(spool_id, self.TreeSize, &root), &signature).is_ok()
+    }
+
+    /// Returns the largest proof, used to size padded responses.
+    pub fn largest() -> ReadProof {
+        ReadProof {
+            TreeSize: u32::max_value(),
+            LeafIndex: u32::max_value(),
+            AuditPath: vec![ByteBuf::from(vec![0u8; HASH_SIZE]); MAX_AUDIT_PATH_LEN],
+            Root: vec![0u8; HASH_SIZE],
+            RootSignature: vec![0u8; 64],
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inclusion_test() {
+        for n in 1..20 {
+            let leaves: Vec<Hash> = (0..n).map(|i| leaf_hash(&[i as u8])).collect();
+            let tree_root = root(&leaves);
+            for i in 0..n {
+                let path = audit_path(i, &leaves);
+                assert!(verify_inclusion(&leaves[i], i as u64, n as u64, &path, &tree_root));
+                assert!(!verify_inclusion(&leaf_hash(b"substituted"), i as u64, n as u64, &path, &tree_root));
+                let mut grown = leaves.clone();
+                grown.push(leaf_hash(b"appended"));
+                assert!(!verify_inclusion(&leaves[i], i as u64, n as u64 + 1, &path, &root(&grown)));
+                if n > 1 {
+                    assert!(!verify_inclusion(&leaves[i], ((i + 1) % n) as u64, n as u64, &path, &tree_root));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rfc6962_shape_test() {
+        // A three leaf tree is ((a, b), c).
+        let leaves = [leaf_hash(b"a"), leaf_hash(b"b"), leaf_hash(b"c")];
+        assert_eq!(root(&leaves), node_hash(&node_hash(&leaves[0], &leaves[1]), &leaves[2]));
+        assert_eq!(audit_path(2, &leaves), vec![node_hash(&leaves[0], &leaves[1])]);
+        assert_eq!(audit_path(0, &leaves), vec![leaves[1], leaves[2]]);
+    }
+}
@@ -0,0 +1,273 @@
+// metrics.rs - Multi-spool service metrics.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Service metrics, exported in the Prometheus text format, and
+//! optionally pushed to statsd over UDP where scraping the admin
+//! socket is impractical.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+use prometheus::{self, Counter, CounterVec, Encoder, GaugeVec, HistogramVec, TextEncoder};
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+
+
+/// How often metrics are pushed to statsd.
+const STATSD_INTERVAL_MS: u64 = 10000;
+
+/// The largest datagram sent to statsd, small enough not to be
+/// fragmented on the usual networks.
+const STATSD_MAX_DATAGRAM: usize = 1432;
+
+
+lazy_static! {
+    /// End to end spool request latency labelled by command.
+    pub static ref REQUEST_LATENCY: HistogramVec = register_histogram_vec!(
+        "multispool_request_duration_seconds",
+        "Spool request handling latency by command.",
+        &["command"]
+    ).unwrap();
+
+    /// MultiSpool storage operation latency labelled by operation.
+    pub static ref STORAGE_LATENCY: HistogramVec = register_histogram_vec!(
+        "multispool_storage_duration_seconds",
+        "MultiSpool storage operation latency.",
+        &["operation"]
+    ).unwrap();
+
+    /// Requests rejected by the rate limiter labelled by command.
+    pub static ref RATE_LIMITED: CounterVec = register_counter_vec!(
+        "multispool_rate_limited_total",
+        "Spool requests rejected by the rate limiter.",
+        &["command"]
+    ).unwrap();
+
+    /// Retried appends answered from the idempotency key cache.
+    pub static ref DUPLICATE_APPENDS: Counter = register_counter!(
+        "multispool_duplicate_appends_total",
+        "Appends not repeated because their idempotency key was seen."
+    ).unwrap();
+
+    /// Appends dropped because the spool already held their message.
+    pub static ref DUPLICATE_MESSAGES: Counter = register_counter!(
+        "multispool_duplicate_messages_total",
+        "Appends dropped because the spool already held their message."
+    ).unwrap();
+
+    /// Bad spool owner and reader signatures.
+    pub static ref SIGNATURE_FAILURES: Counter = register_counter!(
+        "multispool_signature_failures_total",
+        "Requests with a bad spool owner or reader signature."
+    ).unwrap();
+
+    /// Bad signatures refused as rate limited, see `throttle`.
+    pub static ref SIGNATURE_BACKOFFS: Counter = register_counter!(
+        "multispool_signature_backoffs_total",
+        "Requests with a bad signature refused for spools backing off after repeated signature failures."
+    ).unwrap();
+
+    /// Requests refused unread for their body size.
+    pub static ref REQUESTS_TOO_LARGE: Counter = register_counter!(
+        "multispool_requests_too_large_total",
+        "Requests refused because their body exceeded the maximum request size."
+    ).unwrap();
+
+    /// Requests turned away because too many were waiting.
+    pub static ref REQUESTS_SHED: Counter = register_counter!(
+        "multispool_requests_shed_total",
+        "Requests answered with a busy status because the request queue was full."
+    ).unwrap();
+
+    /// Requests given up on, labelled by whether reading the body or
+    /// handling the request took too long.
+    pub static ref REQUEST_TIMEOUTS: CounterVec = register_counter_vec!(
+        "multispool_request_timeouts_total",
+        "Requests given up on for taking longer than their timeout.",
+        &["phase"]
+    ).unwrap();
+
+    /// Spool compactions, periodic and asked for.
+    pub static ref COMPACTIONS: Counter = register_counter!(
+        "multispool_compactions_total",
+        "Spool databases compacted."
+    ).unwrap();
+
+    /// Disk space given back by compacting spools.
+    pub static ref COMPACTION_RECLAIMED_BYTES: Counter = register_counter!(
+        "multispool_compaction_reclaimed_bytes_total",
+        "Bytes of disk space reclaimed by compacting spool databases."
+    ).unwrap();
+
+    /// Spool changes not yet acknowledged, labelled by replica.
+    pub static ref REPLICATION_BACKLOG: GaugeVec = register_gauge_vec!(
+        "multispool_replication_backlog",
+        "Spool changes replicas have yet to acknowledge.",
+        &["replica"]
+    ).unwrap();
+}
+
+/// Returns the number of spool requests with `command`, a command
+/// name, handled since startup.
+pub fn request_count(command: &str) -> u64 {
+    use prometheus::core::Metric as _;
+    REQUEST_LATENCY.with_label_values(&[command]).metric().get_histogram().get_sample_count()
+}
+
+/// Returns the statsd name of a metric, its family's name followed by
+/// its labels' names and values, e.g.
+/// multispool_rate_limited_total.command.read.
+fn statsd_name(family: &MetricFamily, metric: &Metric) -> String {
+    let mut name = family.get_name().to_string();
+    for label in metric.get_label() {
+        name.push('.');
+        name.push_str(label.get_name());
+        name.push('.');
+        name.extend(label.get_value().chars().map(|x| if x.is_ascii_alphanumeric() || x == '-' { x } else { '_' }));
+    }
+    name
+}
+
+/// StatsdEncoder encodes metrics as statsd lines. Prometheus counters
+/// only ever grow while statsd counters are sums of increments, so
+/// the encoder remembers what it sent last and sends the difference.
+#[derive(Default)]
+pub struct StatsdEncoder {
+    last: HashMap<String, f64>,
+}
+
+impl StatsdEncoder {
+    fn counter(&mut self, lines: &mut Vec<String>, name: String, value: f64) {
+        let delta = value - self.last.get(&name).cloned().unwrap_or(0.0);
+        if delta > 0.0 {
+            lines.push(format!("{}:{}|c", name, delta));
+        }
+        self.last.insert(name, value);
+    }
+
+    /// Returns the statsd lines for `families`. Histograms and
+    /// summaries are sent as counters of their samples' count and sum.
+    pub fn encode(&mut self, families: &[MetricFamily]) -> Vec<String> {
+        let mut lines = vec![];
+        for family in families {
+            for metric in family.get_metric() {
+                let name = statsd_name(family, metric);
+                match family.get_field_type() {
+                    MetricType::COUNTER => self.counter(&mut lines, name, metric.get_counter().get_value()),
+                    MetricType::GAUGE => lines.push(format!("{}:{}|g", name, metric.get_gauge().get_value())),
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        self.counter(&mut lines, format!("{}.count", name), histogram.get_sample_count() as f64);
+                        self.counter(&mut lines, format!("{}.sum", name), histogram.get_sample_sum());
+                    },
+                    MetricType::SUMMARY => {
+                        let summary = metric.get_summary();
+                        self.counter(&mut lines, format!("{}.count", name), summary.get_sample_count() as f64);
+                        self.counter(&mut lines, format!("{}.sum", name), summary.get_sample_sum());
+                    },
+                    MetricType::UNTYPED => {},
+                }
+            }
+        }
+        lines
+    }
+}
+
+/// Packs statsd lines into as few datagrams as fit them.
+fn datagrams(lines: &[String]) -> Vec<String> {
+    let mut datagrams: Vec<String> = vec![];
+    for line in lines {
+        match datagrams.last_mut() {
+            Some(datagram) if datagram.len() + 1 + line.len() <= STATSD_MAX_DATAGRAM => {
+                datagram.push('\n');
+                datagram.push_str(line);
+            },
+            _ => datagrams.push(line.clone()),
+        }
+    }
+    datagrams
+}
+
+/// Starts pushing all registered metrics to the statsd server at
+/// `address`, e.g. 127.0.0.1:8125, every STATSD_INTERVAL_MS.
+pub fn start_statsd(address: &str) -> io::Result<()> {
+    let address = address.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the statsd address resolves to nothing"))?;
+    let socket = UdpSocket::bind(if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.connect(address)?;
+    thread::spawn(move || {
+        let mut encoder = StatsdEncoder::default();
+        loop {
+            thread::sleep(Duration::from_millis(STATSD_INTERVAL_MS));
+            for datagram in datagrams(&encoder.encode(&prometheus::gather())) {
+                if let Err(e) = socket.send(datagram.as_bytes()) {
+                    warn!("FAILED to push metrics to statsd at {}: {}", address, e);
+                    break
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Returns all registered metrics in the Prometheus text format.
+pub fn gather() -> Vec<u8> {
+    let mut buffer = vec![];
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!("FAILED to encode metrics: {}", e);
+    }
+    buffer
+}
+
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{Gauge, Opts, Registry};
+    use super::*;
+
+    #[test]
+    fn statsd_encoder_test() {
+        let registry = Registry::new();
+        let requests = CounterVec::new(Opts::new("requests_total", "Requests."), &["replica"]).unwrap();
+        let spools = Gauge::new("spools", "Spools.").unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(spools.clone())).unwrap();
+
+        let mut encoder = StatsdEncoder::default();
+        requests.with_label_values(&["10.0.0.1:9000"]).inc_by(3.0);
+        spools.set(7.0);
+        let mut lines = encoder.encode(&registry.gather());
+        lines.sort();
+        assert_eq!(lines, vec!["requests_total.replica.10_0_0_1_9000:3|c", "spools:7|g"]);
+
+        // Counters are sent as increments, and not at all when idle.
+        requests.with_label_values(&["10.0.0.1:9000"]).inc();
+        assert_eq!(encoder.encode(&registry.gather()), vec!["requests_total.replica.10_0_0_1_9000:1|c", "spools:7|g"]);
+        assert_eq!(encoder.encode(&registry.gather()), vec!["spools:7|g"]);
+    }
+
+    #[test]
+    fn datagrams_test() {
+        let lines: Vec<String> = (0..100).map(|x| format!("metric_{}:{}|c", x, x)).collect();
+        let datagrams = datagrams(&lines);
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|x| x.len() <= STATSD_MAX_DATAGRAM));
+        assert_eq!(datagrams.join("\n"), lines.join("\n"));
+    }
+}
@@ -0,0 +1,251 @@
+// options.rs - Spool creation options.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Spool creation options
+//!
+//! A CREATE_SPOOL request may carry a CBOR map of options choosing how
+//! the new spool behaves: how many messages it keeps and whether an
+//! append to a full spool fails or drops the oldest message, how long
+//! the spool lives, who may append to it, see `acl`, who besides its
+//! owner may read it, whether its messages can be looked up by hash
+//! and whether a message it already holds is appended again. Spools
+//! created without options keep every message forever and take
+//! appends from anyone.
+//!
+//! What the service must remember of the options is its `SpoolPolicy`,
+//! kept in the spool set. The appender list is kept in the spool like
+//! one set later with SET_APPENDERS.
+
+use ed25519_dalek::{PublicKey, PUBLIC_KEY_LENGTH};
+use serde_bytes::ByteBuf;
+use serde_cbor;
+
+use crate::acl::AppenderList;
+use crate::errors::OptionsError;
+
+
+/// The most readers a spool may have besides its owner.
+pub const MAX_READERS: usize = 64;
+
+/// SpoolOptions are the options a spool is created with.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+#[allow(non_snake_case)]
+pub struct SpoolOptions {
+    /// The most messages the spool keeps, unlimited when zero.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub Capacity: u32,
+    /// Makes an append to a full spool drop the oldest message
+    /// instead of failing.
+    #[serde(skip_serializing_if = "is_false")]
+    pub Circular: bool,
+    /// How many seconds after its creation the spool is deleted,
+    /// never when zero.
+    #[serde(skip_serializing_if = "is_zero64")]
+    pub TTL: u64,
+    /// The public keys of the writers allowed to append, anyone when
+    /// empty.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub Appenders: Vec<ByteBuf>,
+    /// The public keys of the readers allowed to read besides the
+    /// owner.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub Readers: Vec<ByteBuf>,
+    /// Keeps an index of the messages by their leaf hash, so that
+    /// they can be read without knowing their message IDs.
+    #[serde(skip_serializing_if = "is_false")]
+    pub HashIndex: bool,
+    /// Drops an append whose message equals one of the last
+    /// DedupWindow messages appended, as mixnet retransmissions do,
+    /// off when zero.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub DedupWindow: u32,
+}
+
+fn is_zero(x: &u32) -> bool {
+    *x == 0
+}
+
+fn is_zero64(x: &u64) -> bool {
+    *x == 0
+}
+
+fn is_false(x: &bool) -> bool {
+    !*x
+}
+
+impl SpoolOptions {
+    /// Returns the appender list the options ask for, if any.
+    pub fn appenders(&self) -> Result<Option<AppenderList>, OptionsError> {
+        if self.Appenders.is_empty() {
+            return Ok(None)
+        }
+        let keys = self.Appenders.iter()
+            .map(|x| PublicKey::from_bytes(x).map_err(|_| OptionsError::InvalidKey))
+            .collect::<Result<Vec<PublicKey>, OptionsError>>()?;
+        AppenderList::new(keys).map(Some).map_err(|_| OptionsError::TooManyKeys)
+    }
+
+    /// Checks the options, returning the policy of a spool created
+    /// with them at `now`, in seconds since the unix epoch.
+    pub fn policy(&self, now: u64) -> Result<SpoolPolicy, OptionsError> {
+        if self.Circular && self.Capacity == 0 {
+            return Err(OptionsError::CircularWithoutCapacity)
+        }
+        if self.Readers.len() > MAX_READERS {
+            return Err(OptionsError::TooManyKeys)
+        }
+        for reader in &self.Readers {
+            PublicKey::from_bytes(reader).map_err(|_| OptionsError::InvalidKey)?;
+        }
+        self.appenders()?;
+        Ok(SpoolPolicy {
+            capacity: self.Capacity,
+            circular: self.Circular,
+            expires_at: if self.TTL > 0 { Some(now.saturating_add(self.TTL)) } else { None },
+            readers: self.Readers.clone(),
+            hash_index: self.HashIndex,
+            dedup_window: self.DedupWindow,
+        })
+    }
+}
+
+/// SpoolPolicy is how the service treats a spool, as chosen by the
+/// options it was created with.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SpoolPolicy {
+    #[serde(default)]
+    pub capacity: u32,
+    #[serde(default)]
+    pub circular: bool,
+    /// The unix time the spool is deleted at, if it has a TTL.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub readers: Vec<ByteBuf>,
+    #[serde(default)]
+    pub hash_index: bool,
+    #[serde(default)]
+    pub dedup_window: u32,
+}
+
+impl SpoolPolicy {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, OptionsError> {
+        Ok(serde_cbor::to_vec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<SpoolPolicy, OptionsError> {
+        Ok(serde_cbor::from_slice(bytes)?)
+    }
+
+    /// Returns true if the policy is that of a spool created without
+    /// options, which needn't be kept.
+    pub fn is_default(&self) -> bool {
+        *self == SpoolPolicy::default()
+    }
+
+    /// Returns true if an append to a spool keeping `retained`
+    /// messages must be refused.
+    pub fn is_full(&self, retained: u64) -> bool {
+        self.capacity > 0 && !self.circular && retained >= u64::from(self.capacity)
+    }
+
+    /// Returns true if the spool's TTL is over at `now`.
+    pub fn is_expired(&self, now: u64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
+
+    /// Returns true if the spool's messages are indexed by hash,
+    /// which duplicate suppression needs too.
+    pub fn indexes_hashes(&self) -> bool {
+        self.hash_index || self.dedup_window > 0
+    }
+
+    /// Returns true if an append repeating message `message_id` of a
+    /// spool holding `message_count` messages is a duplicate to drop.
+    pub fn is_duplicate(&self, message_id: u32, message_count: u64) -> bool {
+        self.dedup_window > 0 && u64::from(message_id) + u64::from(self.dedup_window) >= message_count
+    }
+
+    /// Returns true if `public_key` may read the spool besides its
+    /// owner.
+    pub fn is_reader(&self, public_key: &PublicKey) -> bool {
+        self.readers.iter().any(|x| x.len() == PUBLIC_KEY_LENGTH && x[..] == public_key.as_bytes()[..])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::Keypair;
+    use rand::thread_rng;
+    use super::*;
+
+    #[test]
+    fn spool_options_test() {
+        let options = SpoolOptions::default();
+        let policy = options.policy(100).unwrap();
+        assert!(policy.is_default());
+        assert!(!policy.is_full(u64::from(u32::max_value())));
+        assert!(!policy.is_expired(u64::max_value()));
+        assert!(options.appenders().unwrap().is_none());
+
+        let mut csprng = thread_rng();
+        let reader = Keypair::generate(&mut csprng);
+        let options = SpoolOptions {
+            Capacity: 2,
+            TTL: 60,
+            Readers: vec![ByteBuf::from(reader.public.to_bytes().to_vec())],
+            HashIndex: true,
+            ..SpoolOptions::default()
+        };
+        let decoded: SpoolOptions = serde_cbor::from_slice(&serde_cbor::to_vec(&options).unwrap()).unwrap();
+        assert_eq!(decoded, options);
+        let policy = SpoolPolicy::from_bytes(&options.policy(100).unwrap().to_bytes().unwrap()).unwrap();
+        assert!(!policy.is_full(1));
+        assert!(policy.is_full(2));
+        assert!(!policy.is_expired(159));
+        assert!(policy.is_expired(160));
+        assert!(policy.is_reader(&reader.public));
+        assert!(!policy.is_reader(&Keypair::generate(&mut csprng).public));
+        assert!(policy.hash_index);
+        assert!(policy.indexes_hashes());
+        assert!(!policy.is_duplicate(0, 1));
+
+        let dedup = SpoolOptions {
+            DedupWindow: 2,
+            ..SpoolOptions::default()
+        }.policy(100).unwrap();
+        assert!(dedup.indexes_hashes());
+        assert!(dedup.is_duplicate(3, 4));
+        assert!(dedup.is_duplicate(2, 4));
+        assert!(!dedup.is_duplicate(1, 4));
+
+        let circular = SpoolOptions {
+            Circular: true,
+            ..SpoolOptions::default()
+        };
+        assert!(circular.policy(100).is_err());
+        let bad_reader = SpoolOptions {
+            Readers: vec![ByteBuf::from(vec![1u8; 3])],
+            ..SpoolOptions::default()
+        };
+        assert!(bad_reader.policy(100).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+// permissions.rs - Data directory permissions.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Data directory permissions
+//!
+//! Spools hold messages and their owners' keys, so nobody but the
+//! service's user may read them. On startup the data, shard, backup
+//! and log directories and everything in them are checked for group
+//! or world access, and the service refuses to run if any is found,
+//! unless told to take it away. The service creates its files with a
+//! umask of 077 from then on.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::errors::PermissionError;
+
+
+/// The access bits nobody but the owner may have.
+const GROUP_OTHER_BITS: u32 = 0o077;
+
+/// Checks that `path` and everything below it is accessible by its
+/// owner only. If `fix` is set, group and world access is taken away
+/// instead of refused, returning the number of paths changed.
+pub fn check_tree<P: AsRef<Path>>(path: P, fix: bool) -> Result<usize, PermissionError> {
+    let path = path.as_ref();
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.file_type().is_symlink() {
+        return Ok(0)
+    }
+    let mut fixed = 0;
+    let mode = metadata.permissions().mode();
+    if mode & GROUP_OTHER_BITS != 0 {
+        if !fix {
+            return Err(PermissionError::TooOpen(path.to_string_lossy().into_owned(), mode & 0o7777))
+        }
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & !GROUP_OTHER_BITS & 0o7777))?;
+        fixed += 1;
+    }
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            fixed += check_tree(entry?.path(), fix)?;
+        }
+    }
+    Ok(fixed)
+}
+
+/// Makes the files and directories we create from now on accessible
+/// by their owner only.
+pub fn restrict_umask() {
+    unsafe { libc::umask(GROUP_OTHER_BITS as libc::mode_t) };
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempdir;
+    use super::*;
+
+    #[test]
+    fn check_tree_test() {
+        let dir = tempdir().unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+        let spool = dir.path().join("spool");
+        fs::create_dir(&spool).unwrap();
+        fs::set_permissions(&spool, fs::Permissions::from_mode(0o700)).unwrap();
+        let db = spool.join("db");
+        fs::write(&db, b"messages").unwrap();
+        fs::set_permissions(&db, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(check_tree(dir.path(), false).unwrap(), 0);
+
+        fs::set_permissions(&db, fs::Permissions::from_mode(0o644)).unwrap();
+        match check_tree(dir.path(), false) {
+            Err(PermissionError::TooOpen(path, mode)) => {
+                assert_eq!(path, db.to_string_lossy());
+                assert_eq!(mode, 0o644);
+            },
+            x => panic!("unexpected {:?}", x),
+        }
+        fs::set_permissions(&spool, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(check_tree(dir.path(), true).unwrap(), 2);
+        assert_eq!(fs::metadata(&db).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::metadata(&spool).unwrap().permissions().mode() & 0o777, 0o700);
+        assert_eq!(check_tree(dir.path(), false).unwrap(), 0);
+    }
+}
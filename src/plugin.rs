@@ -0,0 +1,163 @@
+// plugin.rs - Plugin handshake.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Plugin handshake
+//!
+//! A plugin tells the host where to reach it on its first line of
+//! stdout. Katzenpost's CBOR plugins print just the socket path.
+//! HashiCorp go-plugin hosts instead set a magic cookie in the
+//! plugin's environment, to keep it from being run by hand, and the
+//! application protocol versions they speak in
+//! `PLUGIN_PROTOCOL_VERSIONS`, and expect the line
+//!
+//! ```text
+//! CORE-PROTOCOL-VERSION|APP-PROTOCOL-VERSION|NETWORK|ADDRESS|PROTOCOL
+//! ```
+//!
+//! with the highest application protocol version both sides speak.
+
+use std::env;
+use std::str::FromStr;
+
+use crate::errors::PluginError;
+
+
+/// The version of the go-plugin handshake itself.
+pub const CORE_PROTOCOL_VERSION: u32 = 1;
+
+/// The application protocol versions we speak, the preferred last.
+pub const APP_PROTOCOL_VERSIONS: &[u32] = &[1];
+
+/// The environment variable go-plugin hosts set the magic cookie in.
+pub const MAGIC_COOKIE_KEY: &str = "MULTISPOOL_PLUGIN_MAGIC_COOKIE";
+
+/// The magic cookie go-plugin hosts must set.
+pub const MAGIC_COOKIE_VALUE: &str = "9d3c6a4e1f0b8e27a5c4d2f6b1e0a93c7d5f8b2e4a6c1d0f3e9b7a5c2d4e6f81";
+
+/// The environment variable go-plugin hosts list the application
+/// protocol versions they speak in, separated by commas.
+pub const PROTOCOL_VERSIONS_KEY: &str = "PLUGIN_PROTOCOL_VERSIONS";
+
+/// HandshakeStyle is how we tell the host where to reach us.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandshakeStyle {
+    /// Print the socket path, as Katzenpost expects.
+    Katzenpost,
+    /// Print the go-plugin handshake line.
+    GoPlugin,
+}
+
+impl FromStr for HandshakeStyle {
+    type Err = PluginError;
+
+    fn from_str(s: &str) -> Result<HandshakeStyle, PluginError> {
+        match s {
+            "katzenpost" => Ok(HandshakeStyle::Katzenpost),
+            "go-plugin" => Ok(HandshakeStyle::GoPlugin),
+            _ => Err(PluginError::UnknownHandshake(s.to_string())),
+        }
+    }
+}
+
+/// Handshake is the outcome of checking the host's environment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Handshake {
+    pub style: HandshakeStyle,
+    /// The negotiated application protocol version, for go-plugin.
+    pub app_protocol_version: u32,
+}
+
+impl Handshake {
+    /// Checks the environment we were started in for a handshake of
+    /// the given style.
+    pub fn from_env(style: HandshakeStyle) -> Result<Handshake, PluginError> {
+        Handshake::negotiate(style, |key| env::var(key).ok())
+    }
+
+    /// Checks the environment `getenv` looks variables up in, for a
+    /// handshake of the given style. go-plugin hosts must have set the
+    /// magic cookie and, if they list any, speak one of our
+    /// application protocol versions.
+    pub fn negotiate<F: Fn(&str) -> Option<String>>(style: HandshakeStyle, getenv: F) -> Result<Handshake, PluginError> {
+        let preferred = APP_PROTOCOL_VERSIONS[APP_PROTOCOL_VERSIONS.len() - 1];
+        if style == HandshakeStyle::Katzenpost {
+            return Ok(Handshake {
+                style: style,
+                app_protocol_version: preferred,
+            })
+        }
+        if getenv(MAGIC_COOKIE_KEY).as_deref() != Some(MAGIC_COOKIE_VALUE) {
+            return Err(PluginError::MissingMagicCookie)
+        }
+        let app_protocol_version = match getenv(PROTOCOL_VERSIONS_KEY) {
+            Some(ref versions) if !versions.trim().is_empty() => {
+                let versions = versions.split(',')
+                    .map(|x| x.trim().parse::<u32>().map_err(|_| PluginError::InvalidProtocolVersions(versions.clone())))
+                    .collect::<Result<Vec<u32>, PluginError>>()?;
+                APP_PROTOCOL_VERSIONS.iter().rev().cloned()
+                    .find(|x| versions.contains(x))
+                    .ok_or(PluginError::UnsupportedProtocolVersions(versions))?
+            },
+            _ => preferred,
+        };
+        Ok(Handshake {
+            style: style,
+            app_protocol_version: app_protocol_version,
+        })
+    }
+
+    /// Returns the line telling the host we serve `protocol` on
+    /// `address` of `network`, e.g. "unix" and a socket path.
+    pub fn line(&self, network: &str, address: &str, protocol: &str) -> String {
+        match self.style {
+            HandshakeStyle::Katzenpost => address.to_string(),
+            HandshakeStyle::GoPlugin => format!("{}|{}|{}|{}|{}", CORE_PROTOCOL_VERSION, self.app_protocol_version, network, address, protocol),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    fn negotiate(style: HandshakeStyle, vars: &[(&str, &str)]) -> Result<Handshake, PluginError> {
+        let vars: HashMap<String, String> = vars.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+        Handshake::negotiate(style, |key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn handshake_test() {
+        assert_eq!("go-plugin".parse::<HandshakeStyle>().unwrap(), HandshakeStyle::GoPlugin);
+        assert!("netrpc".parse::<HandshakeStyle>().is_err());
+        let handshake = negotiate(HandshakeStyle::Katzenpost, &[]).unwrap();
+        assert_eq!(handshake.line("unix", "/tmp/spool.sock", "cbor-http"), "/tmp/spool.sock");
+
+        assert!(negotiate(HandshakeStyle::GoPlugin, &[]).is_err());
+        assert!(negotiate(HandshakeStyle::GoPlugin, &[(MAGIC_COOKIE_KEY, "wrong")]).is_err());
+        let handshake = negotiate(HandshakeStyle::GoPlugin, &[(MAGIC_COOKIE_KEY, MAGIC_COOKIE_VALUE)]).unwrap();
+        assert_eq!(handshake.line("unix", "/tmp/spool.sock", "cbor-http"), "1|1|unix|/tmp/spool.sock|cbor-http");
+
+        let handshake = negotiate(HandshakeStyle::GoPlugin, &[(MAGIC_COOKIE_KEY, MAGIC_COOKIE_VALUE), (PROTOCOL_VERSIONS_KEY, "3, 1,2")]).unwrap();
+        assert_eq!(handshake.app_protocol_version, 1);
+        match negotiate(HandshakeStyle::GoPlugin, &[(MAGIC_COOKIE_KEY, MAGIC_COOKIE_VALUE), (PROTOCOL_VERSIONS_KEY, "2,3")]) {
+            Err(PluginError::UnsupportedProtocolVersions(versions)) => assert_eq!(versions, vec![2, 3]),
+            x => panic!("unexpected {:?}", x),
+        }
+        assert!(negotiate(HandshakeStyle::GoPlugin, &[(MAGIC_COOKIE_KEY, MAGIC_COOKIE_VALUE), (PROTOCOL_VERSIONS_KEY, "one")]).is_err());
+    }
+}
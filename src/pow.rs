@@ -0,0 +1,98 @@
+// pow.rs - Multi-spool append proof of work.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Hashcash style proof of work for appends
+//!
+//! Appends need no signature, so anyone who learns a spool ID can
+//! flood it. When the service advertises an "append_pow_difficulty"
+//! parameter, appends must carry a nonce such that the SHA-256 hash
+//! of the nonce, spool ID and message hash starts with at least that
+//! many zero bits, making each append cost the sender about
+//! 2^difficulty hashes.
+
+use byteorder::{ByteOrder, BigEndian};
+use sha2::{Digest, Sha256};
+
+
+/// The size of a proof of work nonce in bytes.
+pub const NONCE_SIZE: usize = 8;
+
+/// Prefix of the data proof of work hashes are computed over.
+const POW_CONTEXT: &[u8] = b"multispool append pow v1";
+
+fn pow_hash(spool_id: &[u8], message_hash: &[u8], nonce: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.input(POW_CONTEXT);
+    hasher.input(spool_id);
+    hasher.input(message_hash);
+    hasher.input(nonce);
+    hasher.result().to_vec()
+}
+
+/// Returns the number of leading zero bits of `hash`.
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte != 0 {
+            return bits + byte.leading_zeros()
+        }
+        bits += 8;
+    }
+    bits
+}
+
+/// Checks that `nonce` is a proof of work of at least `difficulty`
+/// bits for appending `message` to the spool.
+pub fn verify(spool_id: &[u8], message: &[u8], nonce: &[u8], difficulty: u32) -> bool {
+    if nonce.len() != NONCE_SIZE {
+        return false
+    }
+    leading_zero_bits(&pow_hash(spool_id, &Sha256::digest(message), nonce)) >= difficulty
+}
+
+/// Finds a proof of work of `difficulty` bits for appending
+/// `message` to the spool.
+pub fn solve(spool_id: &[u8], message: &[u8], difficulty: u32) -> [u8; NONCE_SIZE] {
+    let message_hash = Sha256::digest(message);
+    let mut nonce = [0u8; NONCE_SIZE];
+    for counter in 0u64.. {
+        BigEndian::write_u64(&mut nonce, counter);
+        if leading_zero_bits(&pow_hash(spool_id, &message_hash, &nonce)) >= difficulty {
+            break
+        }
+    }
+    nonce
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn solve_verify_test() {
+        let nonce = solve(b"spool id", b"hello", 12);
+        assert!(verify(b"spool id", b"hello", &nonce, 12));
+        assert!(verify(b"spool id", b"hello", &nonce, 0));
+        assert!(!verify(b"spool id", b"hello", &nonce[..4], 0));
+
+        // A proof is bound to its spool and message.
+        let nonces = (0..8).map(|i| solve(b"spool id", &[i], 12)).collect::<Vec<_>>();
+        assert!(nonces.iter().enumerate().any(|(i, x)| !verify(b"other id", &[i as u8], x, 12)));
+        assert!(nonces.iter().enumerate().any(|(i, x)| !verify(b"spool id", &[i as u8 + 1], x, 12)));
+        assert_eq!(leading_zero_bits(&[0, 0x10, 0xff]), 11);
+    }
+}
@@ -0,0 +1,171 @@
+// privileges.rs - Dropping root privileges.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Dropping root privileges
+//!
+//! The service may be started as root to bind sockets in restricted
+//! directories, and then become an unprivileged user before opening
+//! the spools or handling any request. Everything it writes from then
+//! on is owned by that user, so the data directory must be writable
+//! by it.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+use libc::{gid_t, uid_t};
+
+use crate::errors::PrivilegeError;
+
+
+/// The size of the buffer passwd and group entries are read into.
+const ENTRY_BUFFER_SIZE: usize = 16384;
+
+/// Credentials are the user and group ids to run as.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Credentials {
+    pub uid: uid_t,
+    pub gid: gid_t,
+}
+
+/// Looks up the user and group id of `user`, a name or a number, and
+/// its primary group.
+fn lookup_user(user: &str) -> Result<Credentials, PrivilegeError> {
+    let name = CString::new(user).map_err(|_| PrivilegeError::UnknownUser(user.to_string()))?;
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let found = unsafe {
+        libc::getpwnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) == 0 && !result.is_null()
+    };
+    if found {
+        return Ok(Credentials {
+            uid: entry.pw_uid,
+            gid: entry.pw_gid,
+        })
+    }
+    match user.parse::<uid_t>() {
+        Ok(uid) => {
+            let mut result = ptr::null_mut();
+            let found = unsafe {
+                libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) == 0 && !result.is_null()
+            };
+            Ok(Credentials {
+                uid: uid,
+                gid: if found { entry.pw_gid } else { uid },
+            })
+        },
+        Err(_) => Err(PrivilegeError::UnknownUser(user.to_string())),
+    }
+}
+
+/// Looks up the id of `group`, a name or a number.
+fn lookup_group(group: &str) -> Result<gid_t, PrivilegeError> {
+    let name = CString::new(group).map_err(|_| PrivilegeError::UnknownGroup(group.to_string()))?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let found = unsafe {
+        libc::getgrnam_r(name.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) == 0 && !result.is_null()
+    };
+    if found {
+        return Ok(entry.gr_gid)
+    }
+    group.parse::<gid_t>().map_err(|_| PrivilegeError::UnknownGroup(group.to_string()))
+}
+
+/// Returns the credentials to run as: those of `user`, in `group` if
+/// given and otherwise in the user's primary group.
+pub fn lookup(user: &str, group: Option<&str>) -> Result<Credentials, PrivilegeError> {
+    let mut credentials = lookup_user(user)?;
+    if let Some(group) = group {
+        credentials.gid = lookup_group(group)?;
+    }
+    Ok(credentials)
+}
+
+/// Gives the file at `path` to `credentials`.
+pub fn chown<P: AsRef<Path>>(path: P, credentials: &Credentials) -> Result<(), PrivilegeError> {
+    let path = CString::new(path.as_ref().as_os_str().as_bytes())
+        .map_err(|_| PrivilegeError::DropFailed(io::Error::from(io::ErrorKind::InvalidInput)))?;
+    check(unsafe { libc::chown(path.as_ptr(), credentials.uid, credentials.gid) })
+}
+
+/// Returns the name of the user we run as, for logging.
+pub fn current_user() -> String {
+    let uid = unsafe { libc::getuid() };
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; ENTRY_BUFFER_SIZE];
+    let mut result = ptr::null_mut();
+    let found = unsafe {
+        libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) == 0 && !result.is_null()
+    };
+    if found {
+        unsafe { CStr::from_ptr(entry.pw_name) }.to_string_lossy().into_owned()
+    } else {
+        uid.to_string()
+    }
+}
+
+fn check(result: libc::c_int) -> Result<(), PrivilegeError> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(PrivilegeError::DropFailed(io::Error::last_os_error()))
+    }
+}
+
+/// Becomes `credentials` for good, giving up any supplementary groups.
+/// Nothing happens if we already are them, so that the service can be
+/// started either way with the same configuration.
+pub fn drop_to(credentials: &Credentials) -> Result<(), PrivilegeError> {
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    if uid == credentials.uid && gid == credentials.gid {
+        return Ok(())
+    }
+    unsafe {
+        check(libc::setgroups(0, ptr::null()))?;
+        check(libc::setgid(credentials.gid))?;
+        check(libc::setuid(credentials.uid))?;
+    }
+    // Make sure there is no way back.
+    if credentials.uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(PrivilegeError::StillPrivileged)
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_test() {
+        let root = lookup("root", None).unwrap();
+        assert_eq!(root, Credentials { uid: 0, gid: 0 });
+        assert_eq!(lookup("0", Some("0")).unwrap(), root);
+        assert_eq!(lookup("root", Some("12345")).unwrap().gid, 12345);
+        assert!(lookup("no such user here", None).is_err());
+        assert!(lookup("root", Some("no such group here")).is_err());
+
+        // Becoming who we are is always allowed.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        drop_to(&Credentials { uid: uid, gid: gid }).unwrap();
+    }
+}
@@ -0,0 +1,512 @@
+// protobuf.rs - Protobuf encoding of the spool protocol.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Protobuf encoding of the spool protocol
+//!
+//! Requests and responses may be encoded as the protobuf messages in
+//! proto/spool.proto instead of CBOR. A request payload whose spool
+//! request starts with PROTOBUF_MARKER, a byte no CBOR encoded
+//! request starts with, holds a protobuf SpoolRequest after it, and
+//! is answered with a protobuf SpoolResponse. The messages are small
+//! enough to be encoded here by hand rather than generated.
+
+use std::cmp;
+
+use serde_bytes::ByteBuf;
+
+use crate::errors::{ProtobufError, ResponseError};
+use crate::merkle::ReadProof;
+use crate::options::SpoolOptions;
+use crate::version::ProtocolVersion;
+use crate::{SpoolRequest, SpoolResponse, padded_response_size};
+
+
+/// The byte protobuf encoded spool requests are prefixed with.
+pub const PROTOBUF_MARKER: u8 = 0;
+
+/// The field number of a SpoolResponse's padding.
+const PADDING_FIELD: u32 = 15;
+
+/// The size of the varint the padding's length is put in, long
+/// enough for any padding.
+const PADDING_LENGTH_SIZE: usize = 5;
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_BYTES: u8 = 2;
+const WIRE_FIXED32: u8 = 5;
+
+/// Encoding is how a spool request and its response are encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Cbor,
+    Protobuf,
+}
+
+/// Returns the encoding of a spool request taken from a plugin request
+/// payload, and the encoded request without its marker.
+pub fn split_encoding(raw_spool_request: &[u8]) -> (Encoding, &[u8]) {
+    match raw_spool_request.split_first() {
+        Some((&PROTOBUF_MARKER, rest)) => (Encoding::Protobuf, rest),
+        _ => (Encoding::Cbor, raw_spool_request),
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push((x as u8 & 0x7f) | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+fn put_key(out: &mut Vec<u8>, field: u32, wire_type: u8) {
+    put_varint(out, u64::from(field) << 3 | u64::from(wire_type));
+}
+
+/// Puts a scalar field, left out when zero as proto3 does.
+fn put_uint(out: &mut Vec<u8>, field: u32, x: u64) {
+    if x != 0 {
+        put_key(out, field, WIRE_VARINT);
+        put_varint(out, x);
+    }
+}
+
+fn put_bool(out: &mut Vec<u8>, field: u32, x: bool) {
+    put_uint(out, field, x as u64);
+}
+
+/// Puts a length delimited field, which is always present.
+fn put_delimited(out: &mut Vec<u8>, field: u32, x: &[u8]) {
+    put_key(out, field, WIRE_BYTES);
+    put_varint(out, x.len() as u64);
+    out.extend_from_slice(x);
+}
+
+/// Puts a bytes or string field, left out when empty.
+fn put_bytes(out: &mut Vec<u8>, field: u32, x: &[u8]) {
+    if !x.is_empty() {
+        put_delimited(out, field, x);
+    }
+}
+
+/// Field is a field's value as found on the wire.
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+impl<'a> Field<'a> {
+    fn uint(&self, field: u32, max: u64) -> Result<u64, ProtobufError> {
+        match *self {
+            Field::Varint(x) if x <= max => Ok(x),
+            _ => Err(ProtobufError::InvalidField(field)),
+        }
+    }
+
+    fn bool(&self, field: u32) -> Result<bool, ProtobufError> {
+        Ok(self.uint(field, 1)? == 1)
+    }
+
+    fn bytes(&self, field: u32) -> Result<&'a [u8], ProtobufError> {
+        match *self {
+            Field::Bytes(x) => Ok(x),
+            _ => Err(ProtobufError::InvalidField(field)),
+        }
+    }
+}
+
+/// Reader reads the fields of an encoded message in order.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader {
+            buf: buf,
+        }
+    }
+
+    fn varint(&mut self) -> Result<u64, ProtobufError> {
+        let mut x = 0u64;
+        for (i, byte) in self.buf.iter().enumerate().take(10) {
+            x |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.buf = &self.buf[i + 1..];
+                return Ok(x)
+            }
+        }
+        Err(ProtobufError::Truncated)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ProtobufError> {
+        if len > self.buf.len() {
+            return Err(ProtobufError::Truncated)
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    /// Returns the next field's number and value, or None at the end
+    /// of the message.
+    fn next(&mut self) -> Result<Option<(u32, Field<'a>)>, ProtobufError> {
+        if self.buf.is_empty() {
+            return Ok(None)
+        }
+        let key = self.varint()?;
+        let field = (key >> 3) as u32;
+        let value = match key as u8 & 7 {
+            WIRE_VARINT => Field::Varint(self.varint()?),
+            WIRE_FIXED64 => {
+                self.take(8)?;
+                Field::Fixed
+            },
+            WIRE_BYTES => {
+                let len = self.varint()?;
+                Field::Bytes(self.take(cmp::min(len, usize::max_value() as u64) as usize)?)
+            },
+            WIRE_FIXED32 => {
+                self.take(4)?;
+                Field::Fixed
+            },
+            x => return Err(ProtobufError::InvalidWireType(x)),
+        };
+        Ok(Some((field, value)))
+    }
+}
+
+fn encode_version(version: &ProtocolVersion) -> Vec<u8> {
+    let mut out = vec![];
+    put_uint(&mut out, 1, u64::from(version.Major));
+    put_uint(&mut out, 2, u64::from(version.Minor));
+    out
+}
+
+fn decode_version(buf: &[u8]) -> Result<ProtocolVersion, ProtobufError> {
+    let mut version = ProtocolVersion::default();
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = reader.next()? {
+        match field {
+            1 => version.Major = value.uint(field, 0xff)? as u8,
+            2 => version.Minor = value.uint(field, 0xff)? as u8,
+            _ => {},
+        }
+    }
+    Ok(version)
+}
+
+fn encode_options(options: &SpoolOptions) -> Vec<u8> {
+    let mut out = vec![];
+    put_uint(&mut out, 1, u64::from(options.Capacity));
+    put_bool(&mut out, 2, options.Circular);
+    put_uint(&mut out, 3, options.TTL);
+    for key in &options.Appenders {
+        put_delimited(&mut out, 4, key);
+    }
+    for key in &options.Readers {
+        put_delimited(&mut out, 5, key);
+    }
+    put_bool(&mut out, 6, options.HashIndex);
+    put_uint(&mut out, 7, u64::from(options.DedupWindow));
+    out
+}
+
+fn decode_options(buf: &[u8]) -> Result<SpoolOptions, ProtobufError> {
+    let mut options = SpoolOptions::default();
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = reader.next()? {
+        match field {
+            1 => options.Capacity = value.uint(field, u64::from(u32::max_value()))? as u32,
+            2 => options.Circular = value.bool(field)?,
+            3 => options.TTL = value.uint(field, u64::max_value())?,
+            4 => options.Appenders.push(ByteBuf::from(value.bytes(field)?.to_vec())),
+            5 => options.Readers.push(ByteBuf::from(value.bytes(field)?.to_vec())),
+            6 => options.HashIndex = value.bool(field)?,
+            7 => options.DedupWindow = value.uint(field, u64::from(u32::max_value()))? as u32,
+            _ => {},
+        }
+    }
+    Ok(options)
+}
+
+fn encode_proof(proof: &ReadProof) -> Vec<u8> {
+    let mut out = vec![];
+    put_uint(&mut out, 1, u64::from(proof.TreeSize));
+    put_uint(&mut out, 2, u64::from(proof.LeafIndex));
+    for hash in &proof.AuditPath {
+        put_delimited(&mut out, 3, hash);
+    }
+    put_bytes(&mut out, 4, &proof.Root);
+    put_bytes(&mut out, 5, &proof.RootSignature);
+    out
+}
+
+fn decode_proof(buf: &[u8]) -> Result<ReadProof, ProtobufError> {
+    let mut proof = ReadProof::default();
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = reader.next()? {
+        match field {
+            1 => proof.TreeSize = value.uint(field, u64::from(u32::max_value()))? as u32,
+            2 => proof.LeafIndex = value.uint(field, u64::from(u32::max_value()))? as u32,
+            3 => proof.AuditPath.push(ByteBuf::from(value.bytes(field)?.to_vec())),
+            4 => proof.Root = value.bytes(field)?.to_vec(),
+            5 => proof.RootSignature = value.bytes(field)?.to_vec(),
+            _ => {},
+        }
+    }
+    Ok(proof)
+}
+
+/// Encodes a spool request, without the marker.
+pub fn encode_request(request: &SpoolRequest) -> Vec<u8> {
+    let mut out = vec![];
+    put_uint(&mut out, 1, u64::from(request.Command));
+    put_bytes(&mut out, 2, &request.SpoolID);
+    put_bytes(&mut out, 3, &request.Signature);
+    put_bytes(&mut out, 4, &request.PublicKey);
+    put_bytes(&mut out, 5, &request.MessageID);
+    put_bytes(&mut out, 6, &request.Message);
+    put_bool(&mut out, 7, request.WantProof);
+    put_bytes(&mut out, 8, &request.ProofOfWork);
+    put_bytes(&mut out, 9, &request.AppendToken);
+    put_bytes(&mut out, 10, &request.IdempotencyKey);
+    put_bool(&mut out, 11, request.DurableAppend);
+    if let Some(ref options) = request.Options {
+        put_delimited(&mut out, 12, &encode_options(options));
+    }
+    if let Some(ref version) = request.Version {
+        put_delimited(&mut out, 13, &encode_version(version));
+    }
+    out
+}
+
+/// Decodes a spool request, without the marker. Fields this version
+/// doesn't know are skipped, as protobuf decoders do, unless
+/// `strict`, when they are refused like those of CBOR requests, see
+/// `schema`.
+pub fn decode_request(buf: &[u8], strict: bool) -> Result<SpoolRequest, ProtobufError> {
+    let mut request = SpoolRequest::default();
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = reader.next()? {
+        match field {
+            1 => request.Command = value.uint(field, 0xff)? as u8,
+            2 => request.SpoolID = value.bytes(field)?.to_vec(),
+            3 => request.Signature = value.bytes(field)?.to_vec(),
+            4 => request.PublicKey = value.bytes(field)?.to_vec(),
+            5 => request.MessageID = value.bytes(field)?.to_vec(),
+            6 => request.Message = value.bytes(field)?.to_vec(),
+            7 => request.WantProof = value.bool(field)?,
+            8 => request.ProofOfWork = value.bytes(field)?.to_vec(),
+            9 => request.AppendToken = value.bytes(field)?.to_vec(),
+            10 => request.IdempotencyKey = value.bytes(field)?.to_vec(),
+            11 => request.DurableAppend = value.bool(field)?,
+            12 => request.Options = Some(decode_options(value.bytes(field)?)?),
+            13 => request.Version = Some(decode_version(value.bytes(field)?)?),
+            _ if strict => return Err(ProtobufError::UnknownField(field)),
+            _ => {},
+        }
+    }
+    Ok(request)
+}
+
+/// Encodes a spool response without padding.
+fn encode_unpadded(response: &SpoolResponse) -> Vec<u8> {
+    let mut out = vec![];
+    put_bytes(&mut out, 1, &response.SpoolID);
+    put_bytes(&mut out, 2, &response.Message);
+    put_bytes(&mut out, 3, response.Status.as_bytes());
+    put_uint(&mut out, 4, response.RequestID);
+    if let Some(command) = response.Command {
+        // Command is an optional field, so present even when zero.
+        put_key(&mut out, 5, WIRE_VARINT);
+        put_varint(&mut out, u64::from(command));
+    }
+    put_bytes(&mut out, 6, &response.MessageID);
+    if let Some(ref version) = response.Version {
+        put_delimited(&mut out, 7, &encode_version(version));
+    }
+    if let Some(ref proof) = response.Proof {
+        put_delimited(&mut out, 8, &encode_proof(proof));
+    }
+    put_uint(&mut out, 9, response.AppendedAt);
+    put_uint(&mut out, 10, response.Remaining);
+    put_bool(&mut out, 11, response.Existing);
+    put_bytes(&mut out, 12, &response.ResponseSignature);
+    out
+}
+
+/// Pads the response to the size a CBOR response would be padded to,
+/// see `crate::encode_response`, and encodes it. Protobuf encodings
+/// are the smaller, so every response which fits in CBOR fits here.
+pub fn encode_response(response: &SpoolResponse, max_message_size: usize, max_size: Option<usize>) -> Result<Vec<u8>, ResponseError> {
+    let mut out = encode_unpadded(response);
+    let target = match max_size {
+        Some(max_size) => {
+            if out.len() > max_size {
+                return Err(ResponseError::TooLarge { size: out.len(), max_size: max_size })
+            }
+            cmp::min(max_size, padded_response_size(max_message_size))
+        },
+        None => padded_response_size(max_message_size),
+    };
+    // The padding field's key is a single byte, and its length is
+    // always PADDING_LENGTH_SIZE bytes so that every size can be
+    // padded to exactly.
+    if out.len() + 1 + PADDING_LENGTH_SIZE > target {
+        return Err(ResponseError::Unpaddable(out.len()))
+    }
+    let len = target - out.len() - 1 - PADDING_LENGTH_SIZE;
+    put_key(&mut out, PADDING_FIELD, WIRE_BYTES);
+    for i in 0..PADDING_LENGTH_SIZE {
+        let group = (len >> (7 * i)) as u8 & 0x7f;
+        out.push(if i + 1 < PADDING_LENGTH_SIZE { group | 0x80 } else { group });
+    }
+    out.resize(target, 0);
+    Ok(out)
+}
+
+/// Decodes a spool response. Padding is dropped.
+pub fn decode_response(buf: &[u8]) -> Result<SpoolResponse, ProtobufError> {
+    let mut response = SpoolResponse::default();
+    let mut reader = Reader::new(buf);
+    while let Some((field, value)) = reader.next()? {
+        match field {
+            1 => response.SpoolID = value.bytes(field)?.to_vec(),
+            2 => response.Message = value.bytes(field)?.to_vec(),
+            3 => {
+                response.Status = String::from_utf8(value.bytes(field)?.to_vec())
+                    .map_err(|_| ProtobufError::InvalidField(field))?;
+            },
+            4 => response.RequestID = value.uint(field, u64::max_value())?,
+            5 => response.Command = Some(value.uint(field, 0xff)? as u8),
+            6 => response.MessageID = value.bytes(field)?.to_vec(),
+            7 => response.Version = Some(decode_version(value.bytes(field)?)?),
+            8 => response.Proof = Some(decode_proof(value.bytes(field)?)?),
+            9 => response.AppendedAt = value.uint(field, u64::max_value())?,
+            10 => response.Remaining = value.uint(field, u64::max_value())?,
+            11 => response.Existing = value.bool(field)?,
+            12 => response.ResponseSignature = value.bytes(field)?.to_vec(),
+            _ => {},
+        }
+    }
+    Ok(response)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::spool::{MESSAGE_SIZE, SPOOL_ID_SIZE};
+    use crate::{error_response, CREATE_SPOOL_COMMAND, RETRIEVE_MESSAGE_COMMAND};
+    use super::*;
+
+    #[test]
+    fn request_round_trip_test() {
+        let request = SpoolRequest {
+            Command: RETRIEVE_MESSAGE_COMMAND,
+            SpoolID: vec![1u8; SPOOL_ID_SIZE],
+            Signature: vec![2u8; 64],
+            PublicKey: vec![3u8; 32],
+            MessageID: vec![0, 0, 0, 1],
+            WantProof: true,
+            IdempotencyKey: vec![4u8; 16],
+            Options: Some(SpoolOptions {
+                Capacity: 300,
+                TTL: 1 << 40,
+                Readers: vec![ByteBuf::from(vec![5u8; 32]), ByteBuf::from(vec![6u8; 32])],
+                HashIndex: true,
+                DedupWindow: 8,
+                ..SpoolOptions::default()
+            }),
+            Version: Some(ProtocolVersion::current()),
+            ..SpoolRequest::default()
+        };
+        let raw = encode_request(&request);
+        let decoded = decode_request(&raw, true).unwrap();
+        assert_eq!(serde_cbor::to_vec(&decoded).unwrap(), serde_cbor::to_vec(&request).unwrap());
+
+        let mut payload = vec![PROTOBUF_MARKER];
+        payload.extend_from_slice(&raw);
+        assert_eq!(split_encoding(&payload), (Encoding::Protobuf, &raw[..]));
+        let cbor = serde_cbor::to_vec(&request).unwrap();
+        assert_eq!(split_encoding(&cbor).0, Encoding::Cbor);
+
+        // An empty message is a request with every field zero.
+        assert_eq!(decode_request(&[], true).unwrap().Command, CREATE_SPOOL_COMMAND);
+    }
+
+    #[test]
+    fn invalid_request_test() {
+        // A truncated length delimited field.
+        assert!(decode_request(&[2 << 3 | 2, 5, 1, 2], false).is_err());
+        // A Command too large for a byte.
+        assert!(decode_request(&[1 << 3, 0x80, 0x02], false).is_err());
+        // SpoolID sent as a varint.
+        assert!(decode_request(&[2 << 3, 1], false).is_err());
+        // Groups are not supported.
+        assert!(decode_request(&[2 << 3 | 3], false).is_err());
+        // Unknown fields are skipped, unless decoding is strict.
+        let unknown = [14 << 3, 1, 15 << 3 | 5, 0, 0, 0, 0, 0xa0, 0x01, 7, 1 << 3, 3];
+        let request = decode_request(&unknown, false).unwrap();
+        assert_eq!(request.Command, RETRIEVE_MESSAGE_COMMAND);
+        match decode_request(&unknown, true) {
+            Err(ProtobufError::UnknownField(14)) => {},
+            _ => panic!("expected an unknown field"),
+        }
+    }
+
+    #[test]
+    fn response_padding_test() {
+        let mut hit = SpoolResponse {
+            SpoolID: vec![1u8; SPOOL_ID_SIZE],
+            Message: vec![2u8; MESSAGE_SIZE],
+            Status: "OK".to_string(),
+            RequestID: 1234,
+            Proof: Some(ReadProof::default()),
+            AppendedAt: 1500000000,
+            ..SpoolResponse::default()
+        };
+        hit.echo(Some(CREATE_SPOOL_COMMAND), &[0, 0, 0, 1]);
+        let miss = error_response("error: no such message");
+        let raw_hit = encode_response(&hit, MESSAGE_SIZE, None).unwrap();
+        let raw_miss = encode_response(&miss, MESSAGE_SIZE, None).unwrap();
+        assert_eq!(raw_hit.len(), padded_response_size(MESSAGE_SIZE));
+        assert_eq!(raw_miss.len(), raw_hit.len());
+
+        let decoded = decode_response(&raw_hit).unwrap();
+        assert_eq!(decoded.Message, hit.Message);
+        assert_eq!(decoded.Command, Some(CREATE_SPOOL_COMMAND));
+        assert_eq!(decoded.Proof, Some(ReadProof::default()));
+        assert_eq!(decoded.AppendedAt, 1500000000);
+        assert_eq!(decode_response(&raw_miss).unwrap().Status, "error: no such message");
+        for size in 0..300 {
+            let response = SpoolResponse {
+                Message: vec![7u8; size],
+                ..SpoolResponse::default()
+            };
+            let raw = encode_response(&response, 300, None).unwrap();
+            assert_eq!(raw.len(), padded_response_size(300));
+            assert_eq!(decode_response(&raw).unwrap().Message, response.Message);
+        }
+
+        match encode_response(&hit, MESSAGE_SIZE, Some(100)) {
+            Err(ResponseError::TooLarge { .. }) => {},
+            _ => panic!("expected the response to be too large"),
+        }
+    }
+}
@@ -0,0 +1,317 @@
+// ratelimit.rs - Multi-spool request rate limiting.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Token bucket rate limiting
+//!
+//! Requests are grouped into command classes, each of which may be
+//! limited per spool and across all spools, so that a single noisy
+//! client can't starve everyone else of the sled writeback path.
+//! Requests naming no existing spool all share one bucket per class,
+//! so made up spool IDs can't crowd out the buckets of real spools.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::spool::SPOOL_ID_SIZE;
+use crate::{CREATE_SPOOL_COMMAND, APPEND_MESSAGE_COMMAND, RETRIEVE_MESSAGE_COMMAND, RETRIEVE_BY_HASH_COMMAND, RETRIEVE_LATEST_COMMAND};
+
+
+/// The command classes rate limits are configured for.
+pub const COMMAND_CLASSES: &[&str] = &["create", "append", "read", "other"];
+
+/// The most per spool buckets kept. Spool IDs come from clients, so
+/// beyond it a bucket is dropped to make room for a new one, see
+/// `RateLimiter::make_room`.
+const MAX_TRACKED_SPOOLS: usize = 10000;
+
+/// Returns the class a command is rate limited as.
+pub fn command_class(command: u8) -> &'static str {
+    match command {
+        CREATE_SPOOL_COMMAND => "create",
+        APPEND_MESSAGE_COMMAND => "append",
+        RETRIEVE_MESSAGE_COMMAND | RETRIEVE_BY_HASH_COMMAND | RETRIEVE_LATEST_COMMAND => "read",
+        _ => "other",
+    }
+}
+
+/// RateLimit is a token bucket allowing `rate` requests per second
+/// on average, in bursts of up to `burst` requests.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+/// RateLimits are the limits of a command class, for each spool and
+/// for all spools together. Either is unlimited when unset.
+#[derive(Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RateLimits {
+    pub per_spool: Option<RateLimit>,
+    pub global: Option<RateLimit>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: f64::from(limit.burst),
+            last: now,
+        }
+    }
+
+    /// Returns the tokens the bucket would hold if refilled now.
+    fn level(&self, limit: &RateLimit, now: Instant) -> f64 {
+        if now <= self.last {
+            return self.tokens
+        }
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        (self.tokens + elapsed * limit.rate).min(f64::from(limit.burst))
+    }
+
+    /// Refills the bucket and returns true if a token is available.
+    fn refill(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        if now > self.last {
+            self.tokens = self.level(limit, now);
+            self.last = now;
+        }
+        self.tokens >= 1.0
+    }
+}
+
+/// The key of a per spool bucket, whose spool is None for the bucket
+/// shared by spools not known to exist.
+type SpoolKey = (&'static str, Option<[u8; SPOOL_ID_SIZE]>);
+
+/// RateLimiter holds the token buckets of every command class and
+/// recently seen spool.
+pub struct RateLimiter {
+    limits: HashMap<String, RateLimits>,
+    global: HashMap<&'static str, TokenBucket>,
+    per_spool: HashMap<SpoolKey, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: HashMap<String, RateLimits>) -> RateLimiter {
+        RateLimiter {
+            limits: limits,
+            global: HashMap::new(),
+            per_spool: HashMap::new(),
+        }
+    }
+
+    /// Replaces the limits, keeping the current bucket levels.
+    pub fn set_limits(&mut self, limits: HashMap<String, RateLimits>) {
+        self.limits = limits;
+    }
+
+    /// Takes a token for a request, returning false if the request
+    /// should be rejected. `spool_id` is ignored unless it is a
+    /// valid spool ID, and unless the spool is `known` to exist it
+    /// is limited together with every other unknown spool.
+    pub fn check(&mut self, command: u8, spool_id: &[u8], known: bool) -> bool {
+        self.check_at(command, spool_id, known, Instant::now())
+    }
+
+    fn check_at(&mut self, command: u8, spool_id: &[u8], known: bool, now: Instant) -> bool {
+        let class = command_class(command);
+        let limits = match self.limits.get(class) {
+            Some(x) => x.clone(),
+            None => return true,
+        };
+        let spool_key = match spool_id.len() {
+            SPOOL_ID_SIZE if known => Some((class, Some(*array_ref![spool_id, 0, SPOOL_ID_SIZE]))),
+            SPOOL_ID_SIZE => Some((class, None)),
+            _ => None,
+        };
+        if let (Some(ref limit), Some(key)) = (&limits.per_spool, spool_key) {
+            if !self.per_spool.contains_key(&key) && (self.per_spool.len() < MAX_TRACKED_SPOOLS || self.make_room(now)) {
+                self.per_spool.insert(key, TokenBucket::new(limit, now));
+            }
+            // A spool left untracked is only held to the global limit.
+            if let Some(bucket) = self.per_spool.get_mut(&key) {
+                if !bucket.refill(limit, now) {
+                    return false
+                }
+            }
+        }
+        if let Some(ref limit) = limits.global {
+            let bucket = self.global.entry(class).or_insert_with(|| TokenBucket::new(limit, now));
+            if !bucket.refill(limit, now) {
+                return false
+            }
+            bucket.tokens -= 1.0;
+        }
+        if let (Some(_), Some(key)) = (&limits.per_spool, spool_key) {
+            if let Some(bucket) = self.per_spool.get_mut(&key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        true
+    }
+
+    /// Makes room for another per spool bucket, returning false if
+    /// there is none. Buckets which have refilled to their burst are
+    /// all dropped, as that loses nothing, or failing that the least
+    /// recently used one with a token left. A bucket which is
+    /// limiting its spool is never dropped, so that cycling through
+    /// made up spool IDs can't buy a spool a fresh burst.
+    fn make_room(&mut self, now: Instant) -> bool {
+        let limits = &self.limits;
+        let mut oldest: Option<(SpoolKey, Instant)> = None;
+        self.per_spool.retain(|key, bucket| {
+            let limit = match limits.get(key.0).and_then(|x| x.per_spool.as_ref()) {
+                Some(x) => x,
+                None => return false,
+            };
+            let level = bucket.level(limit, now);
+            if level >= f64::from(limit.burst) {
+                return false
+            }
+            if level >= 1.0 && oldest.map_or(true, |(_, last)| bucket.last < last) {
+                oldest = Some((*key, bucket.last));
+            }
+            true
+        });
+        if self.per_spool.len() < MAX_TRACKED_SPOOLS {
+            return true
+        }
+        match oldest {
+            Some((key, _)) => {
+                self.per_spool.remove(&key);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn token_bucket_test() {
+        let mut limits = HashMap::new();
+        limits.insert(String::from("append"), RateLimits {
+            per_spool: Some(RateLimit { rate: 1.0, burst: 2 }),
+            global: Some(RateLimit { rate: 10.0, burst: 3 }),
+        });
+        let mut limiter = RateLimiter::new(limits);
+        let now = Instant::now();
+        let alice = [1u8; SPOOL_ID_SIZE];
+        let bob = [2u8; SPOOL_ID_SIZE];
+
+        // Alice's spool gets its burst and no more.
+        assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &alice, true, now));
+        assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &alice, true, now));
+        assert!(!limiter.check_at(APPEND_MESSAGE_COMMAND, &alice, true, now));
+
+        // Bob's spool is limited separately, but all spools share the
+        // global bucket.
+        assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &bob, true, now));
+        assert!(!limiter.check_at(APPEND_MESSAGE_COMMAND, &bob, true, now));
+
+        // Reads are not limited, and buckets refill over time.
+        assert!(limiter.check_at(RETRIEVE_MESSAGE_COMMAND, &alice, true, now));
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &alice, true, later));
+        assert!(!limiter.check_at(APPEND_MESSAGE_COMMAND, &alice, true, later));
+    }
+
+    #[test]
+    fn tracked_spools_test() {
+        let mut limits = HashMap::new();
+        limits.insert(String::from("append"), RateLimits {
+            per_spool: Some(RateLimit { rate: 0.001, burst: 2 }),
+            global: None,
+        });
+        let mut limiter = RateLimiter::new(limits);
+        let now = Instant::now();
+        let spool_id = |i: usize| {
+            let mut spool_id = [0u8; SPOOL_ID_SIZE];
+            spool_id[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            spool_id
+        };
+
+        // Spool 0 is being limited, while the others have a token
+        // left, spool 1 having been used the longest ago.
+        let later = now + Duration::from_millis(1);
+        assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &spool_id(0), true, later));
+        assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &spool_id(0), true, later));
+        assert!(!limiter.check_at(APPEND_MESSAGE_COMMAND, &spool_id(0), true, later));
+        assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &spool_id(1), true, now));
+        for i in 2..MAX_TRACKED_SPOOLS {
+            assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &spool_id(i), true, later));
+        }
+        assert_eq!(limiter.per_spool.len(), MAX_TRACKED_SPOOLS);
+
+        // The least recently used bucket with a token left makes room.
+        assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &spool_id(MAX_TRACKED_SPOOLS), true, later));
+        assert_eq!(limiter.per_spool.len(), MAX_TRACKED_SPOOLS);
+        assert!(!limiter.per_spool.contains_key(&("append", Some(spool_id(1)))));
+
+        // Once every bucket is limiting, new spools go untracked and
+        // the limited spool keeps its bucket however many there are.
+        for i in 2..MAX_TRACKED_SPOOLS + 1 {
+            assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &spool_id(i), true, later));
+        }
+        for i in MAX_TRACKED_SPOOLS + 1..MAX_TRACKED_SPOOLS + 100 {
+            assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &spool_id(i), true, later));
+            assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &spool_id(i), true, later));
+        }
+        assert_eq!(limiter.per_spool.len(), MAX_TRACKED_SPOOLS);
+        assert!(!limiter.check_at(APPEND_MESSAGE_COMMAND, &spool_id(0), true, later));
+
+        // Buckets which have refilled are all dropped.
+        let refilled = later + Duration::from_secs(2000);
+        assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &spool_id(MAX_TRACKED_SPOOLS + 100), true, refilled));
+        assert_eq!(limiter.per_spool.len(), 1);
+    }
+    #[test]
+    fn unknown_spools_test() {
+        let mut limits = HashMap::new();
+        limits.insert(String::from("append"), RateLimits {
+            per_spool: Some(RateLimit { rate: 0.001, burst: 2 }),
+            global: None,
+        });
+        let mut limiter = RateLimiter::new(limits);
+        let now = Instant::now();
+        let known = [1u8; SPOOL_ID_SIZE];
+
+        // Made up spool IDs share a single bucket, leaving the known
+        // spool its own.
+        assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &[2u8; SPOOL_ID_SIZE], false, now));
+        assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &[3u8; SPOOL_ID_SIZE], false, now));
+        for i in 0..MAX_TRACKED_SPOOLS {
+            let mut spool_id = [0xffu8; SPOOL_ID_SIZE];
+            spool_id[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            assert!(!limiter.check_at(APPEND_MESSAGE_COMMAND, &spool_id, false, now));
+        }
+        assert_eq!(limiter.per_spool.len(), 1);
+        assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &known, true, now));
+        assert!(limiter.check_at(APPEND_MESSAGE_COMMAND, &known, true, now));
+        assert!(!limiter.check_at(APPEND_MESSAGE_COMMAND, &known, true, now));
+        assert_eq!(limiter.per_spool.len(), 2);
+    }
+}
@@ -0,0 +1,113 @@
+// rekey.rs - Spool re-keying.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+
+//! Spool re-keying
+//!
+//! A spool owner moving to a new key, e.g. on a new device, may hand
+//! the spool over to it with the REKEY_SPOOL command instead of
+//! copying it, so that the spool ID its correspondents know stays
+//! valid. The command is signed by the old key like any owner command
+//! and carries the new public key along with the new key's signature
+//! over the spool ID and the old key. A spool is thus never handed to
+//! a key whose holder didn't ask for it, and the signature can't be
+//! replayed to take over another spool.
+
+use ed25519_dalek::{Keypair, PublicKey, Signature, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+
+use crate::spool::SPOOL_ID_SIZE;
+
+
+/// The size of a REKEY_SPOOL request's message: the new public key
+/// followed by its signature.
+pub const REKEY_MESSAGE_SIZE: usize = PUBLIC_KEY_LENGTH + SIGNATURE_LENGTH;
+
+/// Prefix of the data the new key signs.
+const REKEY_CONTEXT: &[u8] = b"multispool rekey v1";
+
+/// Returns the data the new key signs to take over a spool from
+/// `old_key`.
+fn signed_data(spool_id: &[u8; SPOOL_ID_SIZE], old_key: &PublicKey) -> Vec<u8> {
+    let mut data = REKEY_CONTEXT.to_vec();
+    data.extend_from_slice(spool_id);
+    data.extend_from_slice(old_key.as_bytes());
+    data
+}
+
+/// Rekey is the new owner key of a spool and its signature agreeing
+/// to take the spool over.
+#[derive(Clone, Debug)]
+pub struct Rekey {
+    pub new_key: PublicKey,
+    pub signature: Signature,
+}
+
+impl Rekey {
+    /// Signs the takeover of a spool from `old_key` by `new_keypair`.
+    pub fn sign(new_keypair: &Keypair, spool_id: &[u8; SPOOL_ID_SIZE], old_key: &PublicKey) -> Rekey {
+        Rekey {
+            new_key: new_keypair.public,
+            signature: new_keypair.sign(&signed_data(spool_id, old_key)),
+        }
+    }
+
+    /// Decodes a REKEY_SPOOL request's message.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Rekey> {
+        if bytes.len() != REKEY_MESSAGE_SIZE {
+            return None
+        }
+        let (new_key, signature) = bytes.split_at(PUBLIC_KEY_LENGTH);
+        Some(Rekey {
+            new_key: PublicKey::from_bytes(new_key).ok()?,
+            signature: Signature::from_bytes(signature).ok()?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.new_key.to_bytes().to_vec();
+        bytes.extend_from_slice(&self.signature.to_bytes());
+        bytes
+    }
+
+    /// Checks that the new key signed the takeover of the spool from
+    /// `old_key`.
+    pub fn verify(&self, spool_id: &[u8; SPOOL_ID_SIZE], old_key: &PublicKey) -> bool {
+        self.new_key.verify(&signed_data(spool_id, old_key), &self.signature).is_ok()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+    use super::*;
+
+    #[test]
+    fn rekey_test() {
+        let mut csprng = thread_rng();
+        let old = Keypair::generate(&mut csprng);
+        let new = Keypair::generate(&mut csprng);
+        let spool_id = [1u8; SPOOL_ID_SIZE];
+        let rekey = Rekey::from_bytes(&Rekey::sign(&new, &spool_id, &old.public).to_bytes()).unwrap();
+        assert_eq!(rekey.new_key, new.public);
+        assert!(rekey.verify(&spool_id, &old.public));
+
+        // The signature is bound to the spool and the old key.
+        assert!(!rekey.verify(&[2u8; SPOOL_ID_SIZE], &old.public));
+        assert!(!rekey.verify(&spool_id, &new.public));
+        assert!(Rekey::from_bytes(&rekey.to_bytes()[1..]).is_none());
+    }
+}
@@ -0,0 +1,447 @@
+// replication.rs - Streaming spool changes to replica servers.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Replication
+//!
+//! A primary spool service can stream every spool it creates, every
+//! message appended, every change to who may append to a spool and
+//! every append token spent, and
+//! every spool it purges, undeletes or deletes to replica spool
+//! services, so that losing its disk loses no mailboxes and a promoted
+//! replica enforces the same access control. Each change
+//! is numbered and queued for every replica in a `ReplicationLog`,
+//! sent in batches sealed with a replication key the primary and its
+//! replicas share, and applied in order by `MultiSpool::apply_replicated`.
+//! Applying a change twice does nothing, so a batch whose
+//! acknowledgement was lost is simply sent again.
+//!
+//! The log is kept in memory. A replica which falls more than the
+//! backlog limit behind, or misses changes because the primary
+//! restarted before sending them, has to be resynced from a backup.
+//!
+//! A replica runs as a standby, which refuses writes from clients,
+//! until an operator promotes it. Every primary has an epoch, one more
+//! than that of the primary it took over from, which is kept in the
+//! spool set and sent with every batch. A standby refuses batches from
+//! an older epoch than it has seen, and the primary they came from
+//! fences itself on learning that, refusing writes from then on, even
+//! across restarts. So once a promoted standby has heard from the old
+//! primary, or the old primary from it, only one of them takes writes.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use ed25519_dalek::{PublicKey, PUBLIC_KEY_LENGTH};
+use rand::{thread_rng, Rng};
+use serde_bytes::ByteBuf;
+use serde_cbor;
+
+use crate::encryption::Keyring;
+use crate::errors::ReplicationError;
+use crate::options::SpoolPolicy;
+use crate::spool::SPOOL_ID_SIZE;
+
+
+/// The number of changes queued for a replica, by default, before it
+/// is given up on.
+pub const DEFAULT_MAX_REPLICATION_BACKLOG: usize = 100000;
+
+/// The most changes sent to a replica at once.
+pub const MAX_REPLICATION_BATCH: usize = 256;
+
+/// The associated data batches are sealed with.
+const REPLICATION_AAD: &[u8] = b"multispool replication v1";
+
+/// Role is what a spool service does with writes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    /// Takes writes and replicates them.
+    Primary,
+    /// Takes writes only from its primary's replication stream.
+    Standby,
+    /// Was the primary until a standby was promoted in its place, and
+    /// takes no writes.
+    Fenced,
+}
+
+impl Role {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Role::Primary => 0,
+            Role::Standby => 1,
+            Role::Fenced => 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Role> {
+        match byte {
+            0 => Some(Role::Primary),
+            1 => Some(Role::Standby),
+            2 => Some(Role::Fenced),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Primary => "primary",
+            Role::Standby => "standby",
+            Role::Fenced => "fenced",
+        }
+    }
+}
+
+/// ReplicationEvent is a change to the spools of the primary.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ReplicationEvent {
+    Create {
+        #[serde(with = "serde_bytes")]
+        spool_id: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        public_key: Vec<u8>,
+        /// The policy of a spool created with options.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        policy: Option<SpoolPolicy>,
+        /// The appender list of a spool created with one, see `acl`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        appenders: Option<ByteBuf>,
+    },
+    Append {
+        #[serde(with = "serde_bytes")]
+        spool_id: Vec<u8>,
+        message_id: u32,
+        #[serde(with = "serde_bytes")]
+        message: Vec<u8>,
+        /// The unix time the message was appended at on the primary.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        appended_at: Option<u64>,
+    },
+    Delete {
+        #[serde(with = "serde_bytes")]
+        spool_id: Vec<u8>,
+    },
+    /// A spool handed over to a new owner key.
+    Rekey {
+        #[serde(with = "serde_bytes")]
+        spool_id: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        public_key: Vec<u8>,
+    },
+    /// A spool's appender list set, or cleared if None.
+    SetAppenders {
+        #[serde(with = "serde_bytes")]
+        spool_id: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        appenders: Option<ByteBuf>,
+    },
+    /// A spool's append token key set, or cleared if None.
+    SetTokenKey {
+        #[serde(with = "serde_bytes")]
+        spool_id: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<ByteBuf>,
+    },
+    /// An append token spent on a spool, which can't be spent again.
+    SpendToken {
+        #[serde(with = "serde_bytes")]
+        spool_id: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        token_id: Vec<u8>,
+    },
+    /// A spool purged, which can be undeleted until it is deleted.
+    Tombstone {
+        #[serde(with = "serde_bytes")]
+        spool_id: Vec<u8>,
+        purged_at: u64,
+    },
+    /// A purged spool restored.
+    Undelete {
+        #[serde(with = "serde_bytes")]
+        spool_id: Vec<u8>,
+    },
+}
+
+impl ReplicationEvent {
+    pub fn spool_id(&self) -> Result<[u8; SPOOL_ID_SIZE], ReplicationError> {
+        let spool_id = match self {
+            ReplicationEvent::Create { spool_id, .. } => spool_id,
+            ReplicationEvent::Append { spool_id, .. } => spool_id,
+            ReplicationEvent::Delete { spool_id } => spool_id,
+            ReplicationEvent::Rekey { spool_id, .. } => spool_id,
+            ReplicationEvent::SetAppenders { spool_id, .. } => spool_id,
+            ReplicationEvent::SetTokenKey { spool_id, .. } => spool_id,
+            ReplicationEvent::SpendToken { spool_id, .. } => spool_id,
+            ReplicationEvent::Tombstone { spool_id, .. } => spool_id,
+            ReplicationEvent::Undelete { spool_id } => spool_id,
+        };
+        if spool_id.len() != SPOOL_ID_SIZE {
+            return Err(ReplicationError::InvalidEvent)
+        }
+        Ok(*array_ref![spool_id, 0, SPOOL_ID_SIZE])
+    }
+
+    /// Returns the owner key of a created or re-keyed spool.
+    pub fn public_key(&self) -> Result<PublicKey, ReplicationError> {
+        match self {
+            ReplicationEvent::Create { public_key, .. } | ReplicationEvent::Rekey { public_key, .. } if public_key.len() == PUBLIC_KEY_LENGTH => {
+                PublicKey::from_bytes(public_key).map_err(|_| ReplicationError::InvalidEvent)
+            },
+            _ => Err(ReplicationError::InvalidEvent),
+        }
+    }
+}
+
+/// ReplicationBatch is a run of consecutive changes sent to a replica.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ReplicationBatch {
+    /// Identifies the primary's log, which starts over with every
+    /// start of the primary.
+    pub stream: u64,
+    /// The epoch of the primary, see `Role`.
+    #[serde(default)]
+    pub epoch: u64,
+    /// The sequence number of the first event.
+    pub sequence: u64,
+    pub events: Vec<ReplicationEvent>,
+}
+
+impl ReplicationBatch {
+    /// Encodes and encrypts the batch with the current replication key.
+    pub fn seal(&self, keyring: &Keyring) -> Result<Vec<u8>, ReplicationError> {
+        Ok(keyring.seal(&serde_cbor::to_vec(self)?, REPLICATION_AAD)?)
+    }
+
+    /// Decrypts and decodes a batch sealed with any key in `keyring`,
+    /// which proves that it comes from a primary holding the key.
+    pub fn open(sealed: &[u8], keyring: &Keyring) -> Result<ReplicationBatch, ReplicationError> {
+        Ok(serde_cbor::from_slice(&keyring.open(sealed, REPLICATION_AAD)?)?)
+    }
+
+    /// Returns the sequence number following the last event.
+    pub fn next_sequence(&self) -> u64 {
+        self.sequence + self.events.len() as u64
+    }
+}
+
+struct Backlog {
+    events: VecDeque<(u64, ReplicationEvent)>,
+    dropped: bool,
+}
+
+struct LogState {
+    next_sequence: u64,
+    backlogs: Vec<Backlog>,
+}
+
+/// ReplicationLog queues the changes each replica has yet to
+/// acknowledge.
+pub struct ReplicationLog {
+    stream: u64,
+    epoch: AtomicU64,
+    max_backlog: usize,
+    state: Mutex<LogState>,
+}
+
+impl ReplicationLog {
+    pub fn new(replicas: usize, max_backlog: usize) -> ReplicationLog {
+        ReplicationLog {
+            stream: thread_rng().gen(),
+            epoch: AtomicU64::new(0),
+            max_backlog: max_backlog,
+            state: Mutex::new(LogState {
+                next_sequence: 0,
+                backlogs: (0..replicas).map(|_| Backlog { events: VecDeque::new(), dropped: false }).collect(),
+            }),
+        }
+    }
+
+    /// Sets the epoch batches are sent with.
+    pub fn set_epoch(&self, epoch: u64) {
+        self.epoch.store(epoch, Ordering::SeqCst);
+    }
+
+    /// Queues a change for every replica. Must be called in the order
+    /// the changes were made, i.e. under the lock ordering them.
+    pub fn record(&self, event: ReplicationEvent) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+        for (replica, backlog) in state.backlogs.iter_mut().enumerate() {
+            if backlog.dropped {
+                continue
+            }
+            if backlog.events.len() >= self.max_backlog {
+                error!("replica {} fell {} changes behind and is no longer replicated to, resync it from a backup", replica, self.max_backlog);
+                backlog.dropped = true;
+                backlog.events.clear();
+                continue
+            }
+            backlog.events.push_back((sequence, event.clone()));
+        }
+    }
+
+    /// Returns up to `max` of the oldest changes a replica has yet to
+    /// acknowledge, or None if there are none.
+    pub fn pending(&self, replica: usize, max: usize) -> Option<ReplicationBatch> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let backlog = &state.backlogs[replica];
+        let sequence = backlog.events.front()?.0;
+        Some(ReplicationBatch {
+            stream: self.stream,
+            epoch: self.epoch.load(Ordering::SeqCst),
+            sequence: sequence,
+            events: backlog.events.iter().take(max).map(|(_, event)| event.clone()).collect(),
+        })
+    }
+
+    /// Forgets the changes before `next_sequence`, which a replica
+    /// has applied.
+    pub fn acknowledge(&self, replica: usize, next_sequence: u64) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let events = &mut state.backlogs[replica].events;
+        while let Some(&(sequence, _)) = events.front() {
+            if sequence >= next_sequence {
+                break
+            }
+            events.pop_front();
+        }
+    }
+
+    /// Returns the number of changes a replica has yet to acknowledge.
+    pub fn backlog(&self, replica: usize) -> usize {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).backlogs[replica].events.len()
+    }
+
+    /// Returns true if every replica is up to date or given up on.
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).backlogs.iter().all(|x| x.events.is_empty())
+    }
+}
+
+/// ReplicaPosition is how far a replica got through the log of its
+/// primary.
+#[derive(Default)]
+pub struct ReplicaPosition {
+    stream: Option<u64>,
+    next_sequence: u64,
+}
+
+impl ReplicaPosition {
+    /// Returns the events of `batch` not yet applied. A batch from a
+    /// new log is applied whole, since applying a change twice does
+    /// nothing, but within a log no change may be skipped.
+    pub fn unapplied<'a>(&self, batch: &'a ReplicationBatch) -> Result<&'a [ReplicationEvent], ReplicationError> {
+        if self.stream != Some(batch.stream) {
+            return Ok(&batch.events)
+        }
+        if batch.sequence > self.next_sequence {
+            return Err(ReplicationError::SequenceGap { expected: self.next_sequence, got: batch.sequence })
+        }
+        let applied = cmp::min((self.next_sequence - batch.sequence) as usize, batch.events.len());
+        Ok(&batch.events[applied..])
+    }
+
+    /// Records that every event of `batch` was applied.
+    pub fn advance(&mut self, batch: &ReplicationBatch) {
+        self.stream = Some(batch.stream);
+        self.next_sequence = cmp::max(self.next_sequence, batch.next_sequence());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::encryption::{MasterKey, KEY_SIZE};
+    use super::*;
+
+    fn delete(i: u8) -> ReplicationEvent {
+        ReplicationEvent::Delete { spool_id: vec![i; SPOOL_ID_SIZE] }
+    }
+
+    #[test]
+    fn replication_log_test() {
+        let log = ReplicationLog::new(2, 3);
+        assert!(log.pending(0, MAX_REPLICATION_BATCH).is_none());
+        for i in 0..3 {
+            log.record(delete(i));
+        }
+        log.set_epoch(3);
+        let batch = log.pending(0, 2).unwrap();
+        assert_eq!(batch.epoch, 3);
+        assert_eq!(batch.sequence, 0);
+        assert_eq!(batch.events, vec![delete(0), delete(1)]);
+        log.acknowledge(0, batch.next_sequence());
+        assert_eq!(log.backlog(0), 1);
+        assert_eq!(log.backlog(1), 3);
+
+        // The second replica is given up on once its backlog is full.
+        log.record(delete(3));
+        assert_eq!(log.pending(0, 10).unwrap().events, vec![delete(2), delete(3)]);
+        assert_eq!(log.pending(0, 10).unwrap().sequence, 2);
+        assert!(log.pending(1, 10).is_none());
+        log.record(delete(4));
+        assert!(log.pending(1, 10).is_none());
+        log.acknowledge(0, 5);
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn sealed_batch_test() {
+        let keyring = Keyring::new(MasterKey::from_bytes(&[1u8; KEY_SIZE]).unwrap(), vec![]);
+        let batch = ReplicationBatch {
+            stream: 7,
+            epoch: 1,
+            sequence: 42,
+            events: vec![ReplicationEvent::Append { spool_id: vec![1u8; SPOOL_ID_SIZE], message_id: 3, message: b"hello".to_vec(), appended_at: Some(1) }],
+        };
+        let sealed = batch.seal(&keyring).unwrap();
+        assert_eq!(ReplicationBatch::open(&sealed, &keyring).unwrap(), batch);
+
+        let other = Keyring::new(MasterKey::from_bytes(&[2u8; KEY_SIZE]).unwrap(), vec![]);
+        assert!(ReplicationBatch::open(&sealed, &other).is_err());
+        let mut tampered = sealed.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(ReplicationBatch::open(&tampered, &keyring).is_err());
+    }
+
+    #[test]
+    fn replica_position_test() {
+        let batch = |sequence, count: u8| ReplicationBatch {
+            stream: 1,
+            epoch: 0,
+            sequence: sequence,
+            events: (0..count).map(delete).collect(),
+        };
+        let mut position = ReplicaPosition::default();
+        let first = batch(10, 3);
+        assert_eq!(position.unapplied(&first).unwrap().len(), 3);
+        position.advance(&first);
+
+        // A resent batch is only applied where it goes past the position.
+        assert_eq!(position.unapplied(&batch(11, 4)).unwrap().len(), 2);
+        assert!(position.unapplied(&batch(10, 3)).unwrap().is_empty());
+        match position.unapplied(&batch(14, 1)) {
+            Err(ReplicationError::SequenceGap { expected: 13, got: 14 }) => {},
+            _ => panic!("a gap in the log was not noticed"),
+        }
+
+        // A restarted primary starts a new log.
+        let restarted = ReplicationBatch { stream: 2, ..batch(0, 1) };
+        assert_eq!(position.unapplied(&restarted).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,341 @@
+// sandbox.rs - Landlock and seccomp sandbox.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Landlock and seccomp sandbox
+//!
+//! The service parses requests from the network, so with `sandbox`
+//! set it confines itself before opening the spools. Landlock limits
+//! the files it can reach to its data, shard, backup and log
+//! directories, the directories of its key and configuration files
+//! read only, the directories of its sockets to remove them, and the
+//! few system files name resolution reads. A seccomp filter refuses
+//! the system calls it never makes but an attacker would want, such
+//! as execve and ptrace, with EPERM.
+//!
+//! The filter is a denylist, allowing every system call not listed,
+//! on purpose. The calls the service makes depend on the libc, the
+//! allocator, tokio and sled versions it is built with, and grow as
+//! they adopt new ones such as clone3, rseq or statx, so an allowlist
+//! would break the service in production whenever one of them changed
+//! rather than at build time. File access is what a bug in request
+//! parsing would most likely reach for, and Landlock confines that;
+//! the denylist takes away the system calls which would let an
+//! attacker escape Landlock or the service's user: running programs,
+//! debugging other processes, changing mounts, namespaces or
+//! credentials, and loading kernel code.
+//!
+//! Landlock needs Linux 5.13. On older kernels only the seccomp
+//! filter is applied, which `apply` reports.
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::errors::SandboxError;
+
+
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+pub const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+pub const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+pub const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+pub const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+pub const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+pub const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+pub const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+pub const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+pub const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+pub const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+pub const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+pub const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+pub const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+
+/// Every access right of the first Landlock ABI, all of which are
+/// denied but where a rule grants them.
+const HANDLED_ACCESS_FS: u64 = (1 << 13) - 1;
+
+/// What the service may do in its data, shard, backup and log
+/// directories.
+pub const READ_WRITE: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_DIR | ACCESS_FS_REMOVE_DIR |
+    ACCESS_FS_REMOVE_FILE | ACCESS_FS_MAKE_DIR | ACCESS_FS_MAKE_REG;
+
+/// What the service may do in the directories of its key and
+/// configuration files.
+pub const READ_ONLY: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+/// The system files read to resolve replica and collector addresses.
+const SYSTEM_FILES: &[&str] = &["/etc/hosts", "/etc/resolv.conf", "/etc/nsswitch.conf", "/etc/gai.conf", "/etc/host.conf", "/dev/urandom"];
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Returns the parent directory of `path`.
+fn parent(path: &str) -> PathBuf {
+    match Path::new(path).parent() {
+        Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Returns the paths the service may reach and what it may do there,
+/// given its configuration and the configuration file it was loaded
+/// from, if any.
+pub fn rules(cfg: &Config, config_file: Option<&str>) -> BTreeMap<PathBuf, u64> {
+    let mut rules = BTreeMap::new();
+    let mut allow = |path: PathBuf, access: u64| {
+        *rules.entry(path).or_insert(0) |= access;
+    };
+    for dir in cfg.data_dir.iter().chain(cfg.shard_dirs.iter()).chain(cfg.log_dir.iter()).chain(cfg.backup_dir.iter()) {
+        allow(PathBuf::from(dir), READ_WRITE);
+    }
+    if let Some(path) = cfg.identity_key_path() {
+        // The identity key is generated on first start.
+        allow(parent(&path), READ_ONLY | ACCESS_FS_WRITE_FILE | ACCESS_FS_MAKE_REG);
+    }
+    for path in cfg.master_key_paths.iter().chain(cfg.replication_key_paths.iter()).chain(config_file.map(String::from).iter()) {
+        allow(parent(path), READ_ONLY);
+    }
+    for path in cfg.socket_path.iter().chain(cfg.admin_socket_path.iter()) {
+        allow(parent(path), ACCESS_FS_REMOVE_FILE);
+    }
+    for path in SYSTEM_FILES {
+        if Path::new(path).exists() {
+            allow(PathBuf::from(path), ACCESS_FS_READ_FILE);
+        }
+    }
+    rules
+}
+
+fn landlock_error() -> SandboxError {
+    SandboxError::LandlockFailed(io::Error::last_os_error())
+}
+
+/// Restricts the process to `rules` with Landlock, returning false if
+/// the kernel doesn't support it.
+fn landlock(rules: &BTreeMap<PathBuf, u64>) -> Result<bool, SandboxError> {
+    let attr = RulesetAttr {
+        handled_access_fs: HANDLED_ACCESS_FS,
+    };
+    let ruleset = unsafe { libc::syscall(SYS_LANDLOCK_CREATE_RULESET, &attr as *const RulesetAttr, mem::size_of::<RulesetAttr>(), 0) };
+    if ruleset < 0 {
+        return match io::Error::last_os_error().raw_os_error() {
+            Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => Ok(false),
+            _ => Err(landlock_error()),
+        }
+    }
+    let ruleset = ruleset as libc::c_int;
+    let result = add_rules(ruleset, rules);
+    unsafe { libc::close(ruleset) };
+    result.map(|_| true)
+}
+
+fn add_rules(ruleset: libc::c_int, rules: &BTreeMap<PathBuf, u64>) -> Result<(), SandboxError> {
+    for (path, access) in rules {
+        let file = match open_path(path) {
+            Ok(file) => file,
+            // Paths missing now, such as a log directory which isn't
+            // used, stay out of reach.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(SandboxError::LandlockFailed(e)),
+        };
+        // Only the file rights apply to a file rather than a directory.
+        let access = if path.is_dir() { *access } else { *access & (ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE) };
+        let attr = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: file.as_raw_fd(),
+        };
+        if unsafe { libc::syscall(SYS_LANDLOCK_ADD_RULE, ruleset, LANDLOCK_RULE_PATH_BENEATH, &attr as *const PathBeneathAttr, 0) } < 0 {
+            return Err(landlock_error())
+        }
+    }
+    if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0) } < 0 {
+        return Err(landlock_error())
+    }
+    Ok(())
+}
+
+/// Opens `path` for use in a Landlock rule only.
+fn open_path(path: &Path) -> io::Result<File> {
+    let name = CString::new(path.as_os_str().as_bytes()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let fd = unsafe { libc::open(name.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(unsafe { std::os::unix::io::FromRawFd::from_raw_fd(fd) })
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+/// BPF_LD | BPF_W | BPF_ABS
+const BPF_LD_W_ABS: u16 = 0x20;
+/// BPF_JMP | BPF_JEQ | BPF_K
+const BPF_JMP_JEQ_K: u16 = 0x15;
+/// BPF_JMP | BPF_JGE | BPF_K
+const BPF_JMP_JGE_K: u16 = 0x35;
+/// BPF_RET | BPF_K
+const BPF_RET_K: u16 = 0x06;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_SET_MODE_FILTER: libc::c_long = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_long = 1;
+
+/// The offsets of the system call number and architecture in
+/// struct seccomp_data.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// System calls with numbers from here on are x32 ones on x86_64.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// The system calls the service never makes, the only ones the
+/// filter refuses, see above.
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve, libc::SYS_execveat, libc::SYS_ptrace, libc::SYS_process_vm_readv, libc::SYS_process_vm_writev,
+    libc::SYS_mount, libc::SYS_umount2, libc::SYS_pivot_root, libc::SYS_chroot, libc::SYS_unshare, libc::SYS_setns,
+    libc::SYS_kexec_load, libc::SYS_init_module, libc::SYS_finit_module, libc::SYS_delete_module, libc::SYS_reboot,
+    libc::SYS_swapon, libc::SYS_swapoff, libc::SYS_bpf, libc::SYS_perf_event_open, libc::SYS_userfaultfd,
+    libc::SYS_keyctl, libc::SYS_add_key, libc::SYS_request_key, libc::SYS_personality,
+    libc::SYS_setuid, libc::SYS_setgid, libc::SYS_setreuid, libc::SYS_setregid, libc::SYS_setresuid,
+    libc::SYS_setresgid, libc::SYS_setgroups, libc::SYS_setfsuid, libc::SYS_setfsgid,
+];
+
+fn statement(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter {
+        code: code,
+        jt: jt,
+        jf: jf,
+        k: k,
+    }
+}
+
+/// Returns the seccomp filter for `arch`, killing the process on
+/// system calls of another architecture.
+fn filter(arch: u32) -> Vec<SockFilter> {
+    let deny = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    let mut program = vec![
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_ARCH, 0, 0),
+        statement(BPF_JMP_JEQ_K, arch, 1, 0),
+        statement(BPF_RET_K, SECCOMP_RET_KILL_PROCESS, 0, 0),
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_NR, 0, 0),
+        statement(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1),
+        statement(BPF_RET_K, deny, 0, 0),
+    ];
+    for syscall in DENIED_SYSCALLS {
+        program.push(statement(BPF_JMP_JEQ_K, *syscall as u32, 0, 1));
+        program.push(statement(BPF_RET_K, deny, 0, 0));
+    }
+    program.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW, 0, 0));
+    program
+}
+
+/// Installs the seccomp filter on every thread of the process.
+fn seccomp() -> Result<(), SandboxError> {
+    let arch = AUDIT_ARCH.ok_or(SandboxError::UnsupportedArch)?;
+    let program = filter(arch);
+    let prog = SockFprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_ptr(),
+    };
+    let result = unsafe {
+        libc::syscall(libc::SYS_seccomp, SECCOMP_SET_MODE_FILTER, SECCOMP_FILTER_FLAG_TSYNC, &prog as *const SockFprog)
+    };
+    if result != 0 {
+        return Err(SandboxError::SeccompFailed(io::Error::last_os_error()))
+    }
+    Ok(())
+}
+
+/// Confines the process for good as described above, returning false
+/// if Landlock was unavailable and only the seccomp filter applied.
+/// Threads started before are only subject to the seccomp filter.
+pub fn apply(cfg: &Config, config_file: Option<&str>) -> Result<bool, SandboxError> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(SandboxError::SeccompFailed(io::Error::last_os_error()))
+    }
+    let landlocked = landlock(&rules(cfg, config_file))?;
+    seccomp()?;
+    Ok(landlocked)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_test() {
+        let cfg = Config {
+            data_dir: Some(String::from("/var/lib/multispool")),
+            shard_dirs: vec![String::from("/mnt/disk1")],
+            master_key_paths: vec![String::from("/etc/multispool/master.key")],
+            socket_path: Some(String::from("/run/multispool/spool.sock")),
+            ..Config::default()
+        };
+        let rules = rules(&cfg, Some("/etc/multispool/multispool.toml"));
+        assert_eq!(rules[Path::new("/var/lib/multispool")], READ_WRITE);
+        assert_eq!(rules[Path::new("/mnt/disk1")], READ_WRITE);
+        assert_eq!(rules[Path::new("/etc/multispool")], READ_ONLY);
+        assert_eq!(rules[Path::new("/run/multispool")], ACCESS_FS_REMOVE_FILE);
+        assert!(rules.values().all(|x| x & ACCESS_FS_EXECUTE == 0));
+    }
+
+    #[test]
+    fn filter_test() {
+        let program = filter(0xc000_003e);
+        assert_eq!(program.len(), 7 + 2 * DENIED_SYSCALLS.len());
+        assert_eq!(program[1], statement(BPF_JMP_JEQ_K, 0xc000_003e, 1, 0));
+        assert_eq!(program[program.len() - 1], statement(BPF_RET_K, SECCOMP_RET_ALLOW, 0, 0));
+    }
+}
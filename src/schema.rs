@@ -0,0 +1,199 @@
+// schema.rs - Spool request schema validation.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Spool request schema validation
+//!
+//! Requests with fields of the wrong type, missing fields or bytes
+//! after the encoded request are always refused. Fields this version
+//! doesn't know are ignored, as a newer client may send them, unless
+//! decoding is strict, when they are refused too so that a client
+//! misspelling a field finds out.
+
+use std::fmt;
+
+use serde::de::{Deserialize, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde_cbor;
+
+use crate::errors::RequestError;
+use crate::SpoolRequest;
+
+
+/// The fields of a SpoolRequest.
+pub const REQUEST_FIELDS: &[&str] = &[
+    "Command",
+    "SpoolID",
+    "Signature",
+    "PublicKey",
+    "MessageID",
+    "Message",
+    "WantProof",
+    "ProofOfWork",
+    "AppendToken",
+    "IdempotencyKey",
+    "DurableAppend",
+    "Options",
+    "Version",
+];
+
+/// FieldNames is the keys of an encoded map, its values skipped.
+struct FieldNames(Vec<String>);
+
+struct FieldNamesVisitor;
+
+impl<'de> Visitor<'de> for FieldNamesVisitor {
+    type Value = FieldNames;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map with text keys")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<FieldNames, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut names = vec![];
+        while let Some(name) = map.next_key::<String>()? {
+            map.next_value::<IgnoredAny>()?;
+            names.push(name);
+        }
+        Ok(FieldNames(names))
+    }
+}
+
+impl<'de> Deserialize<'de> for FieldNames {
+    fn deserialize<D>(deserializer: D) -> Result<FieldNames, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(FieldNamesVisitor)
+    }
+}
+
+/// Decodes a CBOR encoded spool request, refusing fields it doesn't
+/// know if `strict`.
+pub fn decode_request(raw: &[u8], strict: bool) -> Result<SpoolRequest, RequestError> {
+    if strict {
+        let FieldNames(names) = serde_cbor::from_slice(raw)?;
+        if let Some(name) = names.into_iter().find(|x| !REQUEST_FIELDS.contains(&x.as_str())) {
+            return Err(RequestError::UnknownField(name))
+        }
+    }
+    Ok(serde_cbor::from_slice(raw)?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+    use crate::options::SpoolOptions;
+    use crate::version::ProtocolVersion;
+    use super::*;
+
+    fn full_request() -> SpoolRequest {
+        SpoolRequest {
+            Command: 3,
+            SpoolID: vec![1u8; 12],
+            Signature: vec![2u8; 64],
+            PublicKey: vec![3u8; 32],
+            MessageID: vec![0, 0, 0, 1],
+            Message: b"hello".to_vec(),
+            WantProof: true,
+            ProofOfWork: vec![4u8; 8],
+            AppendToken: vec![5u8; 8],
+            IdempotencyKey: vec![6u8; 16],
+            DurableAppend: true,
+            Options: Some(SpoolOptions::default()),
+            Version: Some(ProtocolVersion::current()),
+        }
+    }
+
+    #[test]
+    fn decode_request_test() {
+        let raw = serde_cbor::to_vec(&full_request()).unwrap();
+        let request = decode_request(&raw, true).unwrap();
+        assert_eq!(request.Message, b"hello".to_vec());
+        assert_eq!(request.Version, Some(ProtocolVersion::current()));
+
+        // Trailing bytes are refused however strict the decoding.
+        let mut trailing = raw.clone();
+        trailing.push(0);
+        assert!(decode_request(&trailing, false).is_err());
+        assert!(decode_request(&trailing, true).is_err());
+
+        let minimal = SpoolRequest::default();
+        assert!(decode_request(&serde_cbor::to_vec(&minimal).unwrap(), true).is_ok());
+    }
+
+    #[test]
+    fn unknown_field_test() {
+        let raw = serde_cbor::to_vec(&SpoolRequest::default()).unwrap();
+        let names: FieldNames = serde_cbor::from_slice(&raw).unwrap();
+        assert!(names.0.iter().all(|x| REQUEST_FIELDS.contains(&x.as_str())));
+
+        #[derive(Serialize)]
+        #[allow(non_snake_case)]
+        struct Misspelled {
+            Command: u8,
+            SpoolID: ByteBuf,
+            Signature: ByteBuf,
+            PublicKey: ByteBuf,
+            MessageID: ByteBuf,
+            Message: ByteBuf,
+            WantProfe: bool,
+        }
+        let misspelled = Misspelled {
+            Command: 3,
+            SpoolID: ByteBuf::from(vec![1u8; 12]),
+            Signature: ByteBuf::from(vec![]),
+            PublicKey: ByteBuf::from(vec![]),
+            MessageID: ByteBuf::from(vec![0, 0, 0, 1]),
+            Message: ByteBuf::from(vec![]),
+            WantProfe: true,
+        };
+        let raw = serde_cbor::to_vec(&misspelled).unwrap();
+        assert!(!decode_request(&raw, false).unwrap().WantProof);
+        match decode_request(&raw, true) {
+            Err(RequestError::UnknownField(name)) => assert_eq!(name, "WantProfe"),
+            _ => panic!("expected an unknown field"),
+        }
+    }
+
+    #[test]
+    fn wrong_type_test() {
+        #[derive(Serialize)]
+        #[allow(non_snake_case)]
+        struct TextCommand {
+            Command: String,
+            SpoolID: ByteBuf,
+            Signature: ByteBuf,
+            PublicKey: ByteBuf,
+            MessageID: ByteBuf,
+            Message: ByteBuf,
+        }
+        let request = TextCommand {
+            Command: "read".to_string(),
+            SpoolID: ByteBuf::from(vec![1u8; 12]),
+            Signature: ByteBuf::from(vec![]),
+            PublicKey: ByteBuf::from(vec![]),
+            MessageID: ByteBuf::from(vec![]),
+            Message: ByteBuf::from(vec![]),
+        };
+        let raw = serde_cbor::to_vec(&request).unwrap();
+        assert!(decode_request(&raw, false).is_err());
+        assert!(decode_request(&raw, true).is_err());
+        assert!(decode_request(&[0x01], true).is_err());
+    }
+}
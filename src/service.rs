@@ -0,0 +1,653 @@
+// service.rs - Spool service request handling.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Spool service
+//!
+//! `SpoolService` answers Katzenpost plugin requests for a
+//! `MultiSpool`. `handle_request` takes an encoded plugin request and
+//! returns the encoded plugin response, and as a hyper `Service` it
+//! serves the plugin's HTTP routes:
+//!
+//! * `POST /request` handles a plugin request.
+//! * `POST /parameters` returns the plugin parameters.
+//! * `GET /metrics` returns the Prometheus metrics.
+//! * `GET /version` returns the build information.
+//! * `GET /healthz` checks that the spools can be read.
+//! * `POST /debug/request` handles a JSON request, see `debug`, if
+//!   `debug_json` is set.
+//!
+//! Embedders serve it on connections of their own with
+//! `hyper::server::conn::Http::serve_connection`.
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use ed25519_dalek::Keypair;
+use hyper::body::{Bytes, HttpBody};
+use hyper::{header, Body, Method, StatusCode};
+use serde_cbor;
+use serde_json::Value;
+use tokio::task;
+use tokio::time;
+
+use crate::admission::Admission;
+use crate::config::Config;
+use crate::debug;
+use crate::errors::ResponseError;
+use crate::logging::REQUEST_ID_KEY;
+use crate::metrics;
+use crate::protobuf::{self, split_encoding, Encoding};
+use crate::ratelimit::RateLimiter;
+use crate::schema::decode_request;
+use crate::spool::{MultiSpool, MESSAGE_ID_SIZE};
+use crate::trace;
+use crate::version::{BuildInfo, ProtocolVersion};
+use crate::{SpoolRequest, SpoolResponse, command_name, parameters, error_response, encode_response,
+            handle_spool_request, spool_request_payload, INVALID_REQUEST_STATUS, RATE_LIMITED_STATUS,
+            INTERNAL_ERROR_STATUS, TIMEOUT_STATUS, BUSY_STATUS};
+
+
+/// How often to check for in-flight requests while draining.
+const DRAIN_POLL_INTERVAL_MS: u64 = 50;
+
+/// The Katzenpost plugin request envelope.
+#[derive(Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct Request {
+    pub ID: u64,
+    #[serde(with = "serde_bytes")]
+    pub Payload: Vec<u8>,
+    pub HasSURB: bool,
+}
+
+/// The Katzenpost plugin response envelope.
+#[derive(Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct Response {
+    #[serde(with = "serde_bytes")]
+    pub Payload: Vec<u8>,
+}
+
+/// InFlightGuard counts a request as in flight until it is dropped.
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl InFlightGuard {
+    fn new(in_flight: &Arc<AtomicUsize>) -> InFlightGuard {
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(in_flight.clone())
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// RequestIdGuard tags log records written on this thread with the
+/// Katzenpost request ID until it is dropped.
+pub struct RequestIdGuard;
+
+impl RequestIdGuard {
+    pub fn new(request_id: u64) -> RequestIdGuard {
+        log_mdc::insert(REQUEST_ID_KEY, request_id.to_string());
+        RequestIdGuard
+    }
+}
+
+impl Drop for RequestIdGuard {
+    fn drop(&mut self) {
+        log_mdc::remove(REQUEST_ID_KEY);
+    }
+}
+
+/// Encodes a spool response the way its request was encoded.
+fn encode_spool_response(response: &mut SpoolResponse, encoding: Encoding, max_message_size: usize, max_response_size: Option<usize>) -> Result<Vec<u8>, ResponseError> {
+    match encoding {
+        Encoding::Cbor => encode_response(response, max_message_size, max_response_size),
+        Encoding::Protobuf => protobuf::encode_response(response, max_message_size, max_response_size),
+    }
+}
+
+/// Runs `f` on the blocking thread pool, so that slow disk operations
+/// don't hold up the connections served by the runtime's workers.
+pub async fn blocking<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(x) => Some(x),
+        Err(e) => {
+            error!("FAILED to run blocking task: {}", e);
+            None
+        },
+    }
+}
+
+/// Reads a request body of at most `max_size` bytes within `timeout`.
+/// The body is read a chunk at a time, so a peer can't make us buffer
+/// more than that, nor hold on to us by sending it slowly. A body
+/// which is too large or too slow is refused with the HTTP status to
+/// answer it with.
+pub async fn read_body(mut body: Body, max_size: usize, timeout: Duration) -> Result<Result<Bytes, StatusCode>, hyper::Error> {
+    if body.size_hint().lower() > max_size as u64 {
+        return Ok(Err(refuse_too_large()))
+    }
+    let read = async {
+        let mut buf = vec![];
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            if buf.len() + chunk.len() > max_size {
+                return Ok(Err(refuse_too_large()))
+            }
+            buf.extend_from_slice(&chunk);
+        }
+        Ok(Ok(Bytes::from(buf)))
+    };
+    match time::timeout(timeout, read).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!("refusing a request body not read within {:?}", timeout);
+            metrics::REQUEST_TIMEOUTS.with_label_values(&["read"]).inc();
+            Ok(Err(StatusCode::REQUEST_TIMEOUT))
+        },
+    }
+}
+
+fn refuse_too_large() -> StatusCode {
+    warn!("refusing a request body over the maximum request size");
+    metrics::REQUESTS_TOO_LARGE.inc();
+    StatusCode::PAYLOAD_TOO_LARGE
+}
+
+/// Runs `f` on the blocking thread pool like `blocking`, giving up on
+/// it after `timeout`. The task still runs to completion, but the
+/// request waiting for it is answered.
+pub async fn blocking_within<F, R>(timeout: Duration, f: F) -> Result<Option<R>, time::error::Elapsed>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let result = time::timeout(timeout, blocking(f)).await;
+    if result.is_err() {
+        warn!("request not handled within {:?}", timeout);
+        metrics::REQUEST_TIMEOUTS.with_label_values(&["handle"]).inc();
+    }
+    result
+}
+
+/// SpoolService is the state shared by all connections to the plugin.
+#[derive(Clone)]
+pub struct SpoolService {
+    config: Arc<RwLock<Config>>,
+    multi_spool: MultiSpool,
+    identity: Arc<Keypair>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    admission: Arc<Admission>,
+    in_flight: Arc<AtomicUsize>,
+    draining: Arc<AtomicBool>,
+}
+
+impl SpoolService {
+    /// Serves the spools, signing responses with `identity`. The
+    /// configuration is shared with whoever reloads it.
+    pub fn new(config: Arc<RwLock<Config>>, multi_spool: MultiSpool, identity: Arc<Keypair>) -> SpoolService {
+        let (rate_limiter, admission) = {
+            let cfg = config.read().unwrap_or_else(PoisonError::into_inner);
+            (RateLimiter::new(cfg.rate_limits.clone()), Admission::new(cfg.max_in_flight, cfg.max_queued_requests()))
+        };
+        SpoolService {
+            config: config,
+            multi_spool: multi_spool,
+            identity: identity,
+            rate_limiter: Arc::new(Mutex::new(rate_limiter)),
+            admission: Arc::new(admission),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            draining: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Locks the configuration for reading. A panic during a reload
+    /// leaves the configuration usable, so a poisoned lock is
+    /// recovered instead of failing every later request.
+    fn config(&self) -> RwLockReadGuard<'_, Config> {
+        self.config.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the rate limiter, whose buckets stay usable after a
+    /// panic like the configuration.
+    pub fn rate_limiter(&self) -> MutexGuard<'_, RateLimiter> {
+        self.rate_limiter.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes a rate limiter token for a request, returning true if
+    /// it is to be refused. Spools are only looked up once the
+    /// request is decoded, so that the limiter only keeps a bucket of
+    /// its own for spools which exist.
+    fn rate_limited(&self, spool_request: &SpoolRequest) -> bool {
+        let known = self.multi_spool.has_spool(&spool_request.SpoolID);
+        !self.rate_limiter().check(spool_request.Command, &spool_request.SpoolID, known)
+    }
+
+    /// Returns the number of requests being read or handled.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Returns the maximum request size, the body read timeout and the
+    /// request timeout.
+    fn limits(&self) -> (usize, Duration, Duration) {
+        let cfg = self.config();
+        (cfg.max_request_size(), cfg.read_timeout(), cfg.request_timeout())
+    }
+
+    /// Decodes a Katzenpost plugin request, handles the spool request
+    /// it carries and returns the encoded plugin response, or None if
+    /// either could not be decoded.
+    pub fn handle_request(&self, body: &[u8]) -> Option<Vec<u8>> {
+        let _span = trace::span("spool_request");
+        let body_result: Result<Request, serde_cbor::error::Error> = {
+            let _span = trace::span("parse_request");
+            serde_cbor::from_slice(body)
+        };
+        let request = match body_result {
+            Ok(request) => request,
+            Err(e) => {
+                info!("FAILED to deserialize CBOR request: {}", e);
+                return None
+            },
+        };
+        let _request_id = RequestIdGuard::new(request.ID);
+        info!("decoded CBOR Request");
+        let inner_response = Response {
+            Payload: self.handle_plugin_request(&request),
+        };
+        match serde_cbor::to_vec(&inner_response) {
+            Ok(cbor_response) => Some(cbor_response),
+            Err(e) => {
+                info!("FAILED to serialize CBOR response: {}", e);
+                None
+            },
+        }
+    }
+
+    /// Handles the spool request a plugin request carries and returns the
+    /// encoded spool response, empty if it could not be encoded.
+    fn handle_plugin_request(&self, request: &Request) -> Vec<u8> {
+        let spool_response;
+        let mut message_id = vec![];
+        let mut command = None;
+        let mut encoding = Encoding::Cbor;
+        let strict = self.config().strict_requests.unwrap_or(false);
+        let request_result: Result<SpoolRequest, String> = match spool_request_payload(&request.Payload) {
+            Some(raw_spool_request) => {
+                info!("big endian encoded raw SpoolRequest length is {}", raw_spool_request.len());
+                let _span = trace::span("parse_spool_request");
+                let (request_encoding, raw_spool_request) = split_encoding(raw_spool_request);
+                encoding = request_encoding;
+                match encoding {
+                    Encoding::Cbor => decode_request(raw_spool_request, strict).map_err(|e| e.to_string()),
+                    Encoding::Protobuf => protobuf::decode_request(raw_spool_request, strict).map_err(|e| e.to_string()),
+                }
+            },
+            None => Err(String::from("truncated payload")),
+        };
+        match request_result {
+            Ok(ref spool_request) if self.rate_limited(spool_request) => {
+                info!("rate limited {} request", command_name(spool_request.Command));
+                metrics::RATE_LIMITED.with_label_values(&[command_name(spool_request.Command)]).inc();
+                message_id = spool_request.MessageID.clone();
+                command = Some(spool_request.Command);
+                spool_response = error_response(RATE_LIMITED_STATUS);
+            },
+            Ok(spool_request) => {
+                message_id = spool_request.MessageID.clone();
+                command = Some(spool_request.Command);
+                let (max_spools, append_pow_difficulty) = {
+                    let cfg = self.config();
+                    (cfg.max_spools, cfg.append_pow_difficulty)
+                };
+                let _entered = self.multi_spool.enter();
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    handle_spool_request(spool_request, request.ID, max_spools, append_pow_difficulty, &self.multi_spool)
+                }));
+                spool_response = result.unwrap_or_else(|_| {
+                    error!("FAILED to handle request, the handler panicked");
+                    error_response(INTERNAL_ERROR_STATUS)
+                });
+            },
+            Err(e) => {
+                info!("FAILED to deserialize {:?} SpoolRequest: {}", encoding, e);
+                spool_response = error_response(INVALID_REQUEST_STATUS);
+            },
+        }
+        self.finish_response(spool_response, request.ID, command, &message_id, encoding)
+    }
+
+    /// Decodes as much of the spool request a plugin request carries as
+    /// an error response needs to echo: its encoding, command and
+    /// message ID.
+    fn peek_spool_request(&self, request: &Request) -> (Encoding, Option<u8>, Vec<u8>) {
+        let raw_spool_request = match spool_request_payload(&request.Payload) {
+            Some(x) => x,
+            None => return (Encoding::Cbor, None, vec![]),
+        };
+        let (encoding, raw_spool_request) = split_encoding(raw_spool_request);
+        let spool_request = match encoding {
+            Encoding::Cbor => decode_request(raw_spool_request, false).ok(),
+            Encoding::Protobuf => protobuf::decode_request(raw_spool_request, false).ok(),
+        };
+        match spool_request {
+            Some(x) => (encoding, Some(x.Command), x.MessageID),
+            None => (encoding, None, vec![]),
+        }
+    }
+
+    /// Answers the plugin request in `body` with an error `status`, or
+    /// returns None if it can't be decoded. Nothing is stored or read,
+    /// so this is safe to call when storage is stalled.
+    pub fn error_request_response(&self, body: &[u8], status: &'static str) -> Option<Vec<u8>> {
+        let request: Request = serde_cbor::from_slice(body).ok()?;
+        let (encoding, command, message_id) = self.peek_spool_request(&request);
+        let inner_response = Response {
+            Payload: self.finish_response(error_response(status), request.ID, command, &message_id, encoding),
+        };
+        serde_cbor::to_vec(&inner_response).ok()
+    }
+
+    /// Echoes, signs and encodes the spool response to a request,
+    /// returning an empty payload if it could not be encoded.
+    fn finish_response(&self, mut spool_response: SpoolResponse, request_id: u64, command: Option<u8>, message_id: &[u8], encoding: Encoding) -> Vec<u8> {
+        // Responses naming the message they read, as to
+        // RETRIEVE_BY_HASH and RETRIEVE_LATEST, are echoed and signed
+        // with its ID.
+        let found_id = spool_response.MessageID.clone();
+        let message_id = if found_id.len() == MESSAGE_ID_SIZE { &found_id[..] } else { message_id };
+        spool_response.RequestID = request_id;
+        spool_response.echo(command, message_id);
+        spool_response.Version = Some(ProtocolVersion::current());
+        spool_response.sign(message_id, &self.identity);
+        let (max_message_size, max_response_size) = {
+            let cfg = self.config();
+            (cfg.max_message_size(), cfg.max_response_size)
+        };
+        let mut spool_response_result = encode_spool_response(&mut spool_response, encoding, max_message_size, max_response_size);
+        if let Err(ResponseError::TooLarge { size, max_size }) = spool_response_result {
+            warn!("{} byte response exceeds the {} byte maximum", size, max_size);
+            let mut too_large = error_response("error: response too large");
+            too_large.SpoolID = spool_response.SpoolID;
+            too_large.RequestID = request_id;
+            too_large.echo(command, message_id);
+            too_large.Version = Some(ProtocolVersion::current());
+            too_large.sign(message_id, &self.identity);
+            spool_response_result = encode_spool_response(&mut too_large, encoding, max_message_size, max_response_size);
+        }
+        let mut response_payload = vec![];
+        match spool_response_result {
+            Ok(x) => {
+                response_payload = x;
+            },
+            Err(e) => {
+                info!("FAILED to serialize {:?} SpoolResponse: {}", encoding, e);
+            },
+        }
+        response_payload
+    }
+
+    /// Handles a JSON encoded spool request, see `debug`, by passing it
+    /// on as a CBOR request, and returns the JSON encoded response.
+    pub fn handle_debug_request(&self, body: &[u8]) -> Result<Value, String> {
+        let (request_id, spool_request) = debug::decode_request(body).map_err(|e| e.to_string())?;
+        let request = Request {
+            ID: request_id,
+            Payload: debug::encode_payload(&spool_request).map_err(|e| e.to_string())?,
+            HasSURB: true,
+        };
+        let _request_id = RequestIdGuard::new(request.ID);
+        let raw_response = self.handle_plugin_request(&request);
+        let spool_response: SpoolResponse = serde_cbor::from_slice(&raw_response).map_err(|e| e.to_string())?;
+        Ok(debug::response_json(&spool_response))
+    }
+
+    /// Answers an HTTP request to the plugin.
+    pub async fn handle_http(self, req: hyper::Request<Body>) -> Result<hyper::Response<Body>, hyper::Error> {
+        let mut response = hyper::Response::new(Body::empty());
+        if self.draining.load(Ordering::SeqCst) {
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            return Ok(response)
+        }
+        let _in_flight = InFlightGuard::new(&self.in_flight);
+        match (req.method(), req.uri().path()) {
+            (&Method::POST, "/parameters") => {
+                let params = {
+                    let cfg = self.config();
+                    parameters(cfg.max_spools, cfg.max_message_size(), Some(&self.identity.public), cfg.append_pow_difficulty)
+                };
+                match serde_cbor::to_vec(&params) {
+                    Ok(cbor_params) => {
+                        *response.body_mut() = Body::from(cbor_params);
+                    },
+                    Err(e) => {
+                        error!("FAILED to serialize CBOR parameters: {}", e);
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    },
+                }
+            }
+            (&Method::GET, "/metrics") => {
+                *response.body_mut() = Body::from(metrics::gather());
+            }
+            (&Method::GET, "/version") => {
+                match serde_json::to_vec(&BuildInfo::new()) {
+                    Ok(build_info) => {
+                        *response.body_mut() = Body::from(build_info);
+                    },
+                    Err(e) => {
+                        error!("FAILED to serialize build info: {}", e);
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    },
+                }
+            }
+            (&Method::GET, "/healthz") => {
+                let multi_spool = self.multi_spool.clone();
+                let health = blocking(move || multi_spool.check_health()).await;
+                match health {
+                    Some(Ok(())) => {
+                        *response.body_mut() = Body::from("ok");
+                    },
+                    Some(Err(e)) => {
+                        error!("FAILED health check: {}", e);
+                        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                        *response.body_mut() = Body::from(format!("{}", e));
+                    },
+                    None => {
+                        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                    },
+                }
+            }
+            (&Method::POST, "/request") => {
+                info!("POST /request");
+                let (max_request_size, read_timeout, request_timeout) = self.limits();
+                let body = match read_body(req.into_body(), max_request_size, read_timeout).await? {
+                    Ok(body) => body,
+                    Err(status) => {
+                        *response.status_mut() = status;
+                        return Ok(response)
+                    },
+                };
+                let _turn = match self.admission.admit().await {
+                    Some(turn) => turn,
+                    None => {
+                        metrics::REQUESTS_SHED.inc();
+                        if let Some(payload) = self.error_request_response(&body, BUSY_STATUS) {
+                            *response.body_mut() = Body::from(payload);
+                        }
+                        return Ok(response)
+                    },
+                };
+                let service = self.clone();
+                let request_body = body.clone();
+                let payload = match blocking_within(request_timeout, move || service.handle_request(&request_body)).await {
+                    Ok(payload) => payload.and_then(|x| x),
+                    Err(_) => self.error_request_response(&body, TIMEOUT_STATUS),
+                };
+                if let Some(payload) = payload {
+                    *response.body_mut() = Body::from(payload);
+                }
+            }
+            (&Method::POST, "/debug/request") if self.config().debug_json.unwrap_or(false) => {
+                info!("POST /debug/request");
+                let (max_request_size, read_timeout, request_timeout) = self.limits();
+                let body = match read_body(req.into_body(), max_request_size, read_timeout).await? {
+                    Ok(body) => body,
+                    Err(status) => {
+                        *response.status_mut() = status;
+                        return Ok(response)
+                    },
+                };
+                response.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+                let _turn = match self.admission.admit().await {
+                    Some(turn) => turn,
+                    None => {
+                        metrics::REQUESTS_SHED.inc();
+                        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                        *response.body_mut() = Body::from(json!({ "error": BUSY_STATUS }).to_string());
+                        return Ok(response)
+                    },
+                };
+                match blocking_within(request_timeout, move || self.handle_debug_request(&body)).await {
+                    Ok(Some(Ok(json))) => {
+                        *response.body_mut() = Body::from(json.to_string());
+                    },
+                    Ok(Some(Err(e))) => {
+                        *response.status_mut() = StatusCode::BAD_REQUEST;
+                        *response.body_mut() = Body::from(json!({ "error": e }).to_string());
+                    },
+                    Ok(None) => {
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    },
+                    Err(_) => {
+                        *response.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+                        *response.body_mut() = Body::from(json!({ "error": TIMEOUT_STATUS }).to_string());
+                    },
+                }
+            }
+            // The 404 Not Found route...
+            _ => {
+                *response.status_mut() = StatusCode::NOT_FOUND;
+            }
+        };
+        Ok(response)
+    }
+
+    /// Refuses new requests from now on, and resolves once no requests
+    /// are in flight or the drain timeout expires.
+    pub async fn drain(&self, timeout: Duration) {
+        info!("draining {} in-flight requests", self.in_flight.load(Ordering::SeqCst));
+        self.draining.store(true, Ordering::SeqCst);
+        let in_flight = self.in_flight.clone();
+        let wait = async {
+            while in_flight.load(Ordering::SeqCst) > 0 {
+                time::sleep(Duration::from_millis(DRAIN_POLL_INTERVAL_MS)).await;
+            }
+        };
+        if time::timeout(timeout, wait).await.is_err() {
+            warn!("drain timeout expired with {} requests in flight", in_flight.load(Ordering::SeqCst));
+        }
+    }
+}
+
+impl hyper::service::Service<hyper::Request<Body>> for SpoolService {
+    type Response = hyper::Response<Body>;
+    type Error = hyper::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<hyper::Response<Body>, hyper::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), hyper::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: hyper::Request<Body>) -> Self::Future {
+        Box::pin(self.clone().handle_http(req))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempdir;
+    use rand::thread_rng;
+    use super::*;
+
+    #[test]
+    fn read_body_test() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let timeout = Duration::from_secs(10);
+            let body = read_body(Body::from(vec![1u8; 10]), 10, timeout).await.unwrap();
+            assert_eq!(body.unwrap().to_vec(), vec![1u8; 10]);
+            assert_eq!(read_body(Body::from(vec![1u8; 11]), 10, timeout).await.unwrap(), Err(StatusCode::PAYLOAD_TOO_LARGE));
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                for _ in 0..3 {
+                    if sender.send_data(Bytes::from(vec![2u8; 4])).await.is_err() {
+                        return
+                    }
+                }
+            });
+            assert_eq!(read_body(body, 10, timeout).await.unwrap(), Err(StatusCode::PAYLOAD_TOO_LARGE));
+
+            // A body which never ends times out.
+            let (_sender, body) = Body::channel();
+            assert_eq!(read_body(body, 10, Duration::from_millis(10)).await.unwrap(), Err(StatusCode::REQUEST_TIMEOUT));
+            assert!(blocking_within(Duration::from_millis(10), || std::thread::sleep(Duration::from_millis(200))).await.is_err());
+        });
+    }
+
+    #[test]
+    fn handle_request_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let identity = Keypair::generate(&mut thread_rng());
+        let service = SpoolService::new(Arc::new(RwLock::new(Config::default())), multi_spool, Arc::new(identity));
+
+        assert!(service.handle_request(b"not cbor").is_none());
+
+        let request = Request {
+            ID: 7,
+            Payload: vec![0, 0],
+            HasSURB: true,
+        };
+        let raw_response = service.handle_request(&serde_cbor::to_vec(&request).unwrap()).unwrap();
+        let response: Response = serde_cbor::from_slice(&raw_response).unwrap();
+        let spool_response: SpoolResponse = serde_cbor::from_slice(&response.Payload).unwrap();
+        assert_eq!(spool_response.RequestID, 7);
+        assert_eq!(spool_response.Status, INVALID_REQUEST_STATUS);
+
+        let raw_response = service.error_request_response(&serde_cbor::to_vec(&request).unwrap(), TIMEOUT_STATUS).unwrap();
+        let response: Response = serde_cbor::from_slice(&raw_response).unwrap();
+        let spool_response: SpoolResponse = serde_cbor::from_slice(&response.Payload).unwrap();
+        assert_eq!(spool_response.RequestID, 7);
+        assert_eq!(spool_response.Status, TIMEOUT_STATUS);
+        assert!(service.error_request_response(b"not cbor", TIMEOUT_STATUS).is_none());
+    }
+}
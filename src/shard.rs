@@ -0,0 +1,163 @@
+// shard.rs - Sharding of spools across data directories.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Spool sharding
+//!
+//! The spool databases may be spread over several data directories,
+//! each perhaps on a disk of its own. The data directory holds the
+//! spool set and the first shard, and the shard directories the
+//! others. Which shard a spool is in only depends on its ID and the
+//! number of shards, so no record of it is kept. Spool IDs are drawn
+//! at random, so the spools are spread evenly.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, BigEndian};
+
+use crate::spool::{spool_path, SPOOL_ID_SIZE};
+
+
+/// Shards maps spool IDs to the directories their databases are in.
+#[derive(Clone, Debug)]
+pub struct Shards {
+    dirs: Vec<String>,
+}
+
+impl Shards {
+    /// Shards spools across `base_dir` and `shard_dirs`.
+    pub fn new(base_dir: &String, shard_dirs: &[String]) -> Shards {
+        let mut dirs = vec![base_dir.clone()];
+        dirs.extend(shard_dirs.iter().filter(|x| *x != base_dir).cloned());
+        Shards {
+            dirs: dirs,
+        }
+    }
+
+    /// Returns the shard directories, the data directory first.
+    pub fn dirs(&self) -> &[String] {
+        &self.dirs
+    }
+
+    /// Returns the index of the shard a spool is in.
+    pub fn index(&self, spool_id: &[u8; SPOOL_ID_SIZE]) -> usize {
+        BigEndian::read_u64(&spool_id[..8]) as usize % self.dirs.len()
+    }
+
+    /// Returns the directory a spool's database is in.
+    pub fn dir(&self, spool_id: &[u8; SPOOL_ID_SIZE]) -> &String {
+        &self.dirs[self.index(spool_id)]
+    }
+
+    /// Returns the path of a spool's database.
+    pub fn spool_path(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> PathBuf {
+        spool_path(self.dir(&spool_id), spool_id)
+    }
+
+    /// Returns the shard directory `path` is in, if any.
+    pub fn dir_of(&self, path: &Path) -> Option<&String> {
+        self.dirs.iter().find(|dir| path.starts_with(dir))
+    }
+
+    /// Returns the path of a spool database found in another shard
+    /// than the spool's own, which is where it was before the shard
+    /// directories changed.
+    pub fn misplaced(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Option<PathBuf> {
+        let own = self.index(&spool_id);
+        self.dirs.iter().enumerate()
+            .filter(|&(index, _)| index != own)
+            .map(|(_, dir)| spool_path(dir, spool_id))
+            .find(|path| path.exists())
+    }
+}
+
+/// Copies the file or directory at `from` to `to`.
+fn copy_all(from: &Path, to: &Path) -> io::Result<()> {
+    if !from.is_dir() {
+        fs::copy(from, to)?;
+        return Ok(())
+    }
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_all(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+/// Moves the sled database at `from` to `to`, copying it if they are
+/// on different filesystems.
+pub fn move_db(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(())
+    }
+    copy_all(from, to)?;
+    if from.is_dir() {
+        fs::remove_dir_all(from)
+    } else {
+        fs::remove_file(from)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempdir;
+    use super::*;
+
+    #[test]
+    fn shards_test() {
+        let base_dir = String::from("/data");
+        let shards = Shards::new(&base_dir, &[]);
+        assert_eq!(shards.dirs().to_vec(), vec![base_dir.clone()]);
+        assert_eq!(shards.spool_path([7u8; SPOOL_ID_SIZE]), spool_path(&base_dir, [7u8; SPOOL_ID_SIZE]));
+
+        let shards = Shards::new(&base_dir, &[String::from("/disk1"), base_dir.clone(), String::from("/disk2")]);
+        assert_eq!(shards.dirs().len(), 3);
+        let mut spool_id = [0u8; SPOOL_ID_SIZE];
+        for index in 0..6 {
+            spool_id[7] = index;
+            assert_eq!(shards.index(&spool_id), index as usize % 3);
+            assert_eq!(shards.dir_of(&shards.spool_path(spool_id)), Some(shards.dir(&spool_id)));
+        }
+    }
+
+    #[test]
+    fn misplaced_test() {
+        let data = tempdir().unwrap();
+        let disk = tempdir().unwrap();
+        let base_dir = data.path().to_string_lossy().into_owned();
+        let shard_dirs = vec![disk.path().to_string_lossy().into_owned()];
+        let spool_id = [1u8; SPOOL_ID_SIZE];
+        let before = Shards::new(&base_dir, &[]);
+        fs::create_dir_all(before.spool_path(spool_id)).unwrap();
+        fs::write(before.spool_path(spool_id).join("db"), b"spool").unwrap();
+
+        let after = Shards::new(&base_dir, &shard_dirs);
+        assert_eq!(after.dir(&spool_id), &shard_dirs[0]);
+        let from = after.misplaced(spool_id).unwrap();
+        move_db(&from, &after.spool_path(spool_id)).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(after.spool_path(spool_id).join("db")).unwrap(), b"spool");
+        assert_eq!(after.misplaced(spool_id), None);
+    }
+}
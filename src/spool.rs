@@ -22,25 +22,50 @@ extern crate sled;
 extern crate arrayref;
 extern crate ed25519_dalek;
 extern crate sphinxcrypto;
+extern crate zstd;
 
+use std::cmp;
 use std::io;
-use std::sync::Arc;
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::collections::{BTreeSet, HashMap};
 use std::path::{Path, PathBuf};
-use std::fs::remove_file;
+use std::fs::{self, remove_file};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use byteorder::{ByteOrder, BigEndian};
 use sled::{Db, Tree};
-use ed25519_dalek::{PublicKey, Signature};
-use rand::CryptoRng;
-use rand::Rng;
+use serde_bytes::ByteBuf;
+use ed25519_dalek::{Keypair, PublicKey, Signature, PUBLIC_KEY_LENGTH};
+use rand::{CryptoRng, Rng, RngCore, SeedableRng};
+use rand::rngs::{OsRng, StdRng};
 
 use sphinxcrypto::constants::{USER_FORWARD_PAYLOAD_SIZE};
 
-use errors::{SpoolError, SpoolSetError, MultiSpoolError};
+use crate::encryption::{Keyring, SpoolKey, KEY_SIZE};
+use crate::errors::{ArchiveError, EncryptionError, ReplicationError, SpoolError, SpoolSetError, MultiSpoolError};
+use crate::acl::{AppendAuth, AppenderList};
+use crate::archive::{SpoolArchive, ARCHIVE_FORMAT_VERSION};
+use crate::audit::{AuditLog, OUTCOME_OK};
+use crate::backup::{self, Backup};
+use crate::disk::DiskMonitor;
+use crate::dedup::{DedupCache, DEFAULT_DEDUP_CACHE_SIZE, MAX_IDEMPOTENCY_KEY_SIZE};
+use crate::filter::SpoolFilter;
+use crate::fsck::Problem;
+use crate::group_commit::{GroupCommit, DEFAULT_GROUP_COMMIT_WINDOW_MS};
+use crate::merkle::{self, ReadProof};
+use crate::options::{SpoolOptions, SpoolPolicy};
+use crate::rekey::Rekey;
+use crate::replication::{ReplicationEvent, ReplicationLog, Role};
+use crate::shard::{self, Shards};
+use crate::tokens::{self, TokenKey};
+use crate::metrics::{self, COMPACTIONS, COMPACTION_RECLAIMED_BYTES, DUPLICATE_APPENDS, DUPLICATE_MESSAGES, SIGNATURE_BACKOFFS, SIGNATURE_FAILURES, STORAGE_LATENCY};
+use crate::throttle::SignatureBackoff;
+use crate::trace;
+use crate::{command_name, SUPPORTED_COMMANDS};
 
 // Spool constants
 
-/// The size of a message.
+/// The default maximum size of a message, the sphinx user forward
+/// payload size. See `MultiSpool::set_max_message_size`.
 pub const MESSAGE_SIZE: usize = USER_FORWARD_PAYLOAD_SIZE;
 
 /// The size of a message identity in bytes.
@@ -52,9 +77,356 @@ const SPOOL_SIZE: usize = 1000;
 /// The metadata tree identity.
 const META_TREE_ID: &[u8] = b"meta_tree_id";
 
-/// The key whose value points to the index of the end of the spool.
+/// The tree holding the Merkle leaf hash of every message.
+const HASH_TREE_ID: &[u8] = b"hash_tree_id";
+
+/// The tree holding a spool's append token key and spent tokens.
+const TOKEN_TREE_ID: &[u8] = b"token_tree_id";
+
+/// The tree indexing the messages of a spool with a hash index by
+/// their leaf hash, see `Spool::find_by_hash`. The leaf hashes are
+/// keyed with the spool's key if it has one.
+const INDEX_TREE_ID: &[u8] = b"index_tree_id";
+
+/// The tree the format 0 migration stages rewritten messages in, see
+/// `Spool::add_entry_headers`.
+const MIGRATION_TREE_ID: &[u8] = b"migration_tree_id";
+
+/// The meta tree key present once every message of a format 0 spool
+/// is staged in the migration tree.
+const MIGRATION_STAGED_KEY: &[u8] = b"migration staged";
+
+/// The index tree key of whether the index is keyed, one byte.
+const INDEX_KEYED_KEY: &[u8] = b"keyed";
+
+/// The token tree key of the append token key. Spent tokens are kept
+/// under their 32 byte token IDs.
+const TOKEN_KEY_KEY: &[u8] = b"append token key";
+
+/// The meta tree key of a spool's appender list, see `acl`.
+const APPENDERS_KEY: &[u8] = b"appenders";
+
+/// The meta tree key of the ID of a circular spool's oldest message,
+/// the messages before it having been dropped, see `Spool::trim`.
+const FIRST_MESSAGE_KEY: &[u8] = b"first message";
+
+/// The spool set tree holding the policy of each spool created with
+/// options, see `options`.
+const POLICY_TREE_ID: &[u8] = b"policy_tree_id";
+
+/// Additional data sealed along with spool policies.
+const POLICY_AAD: &[u8] = b"spool policy";
+
+/// The first byte of a stored policy, telling whether the rest is
+/// sealed with the master keys.
+const POLICY_PLAIN: u8 = 0;
+const POLICY_SEALED: u8 = 1;
+
+/// The spool set tree holding when each spool was created and last
+/// appended to and read, keyed by spool ID followed by an `Activity`
+/// byte.
+const ACTIVITY_TREE_ID: &[u8] = b"activity_tree_id";
+
+/// The sled Tree ID of the tree mapping purged spools to the unix
+/// time they were purged at, until they are deleted for good.
+const TOMBSTONE_TREE_ID: &[u8] = b"tombstone_tree_id";
+
+/// How long purged spools can be undeleted for by default.
+pub const DEFAULT_PURGE_GRACE_PERIOD_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// The sled Tree ID of the tree holding the message count of each
+/// spool as of the last backup, and that backup's name.
+const WATERMARK_TREE_ID: &[u8] = b"watermark_tree_id";
+
+/// The watermark tree key of the last backup's name. Watermarks are
+/// kept under spool IDs.
+const LAST_BACKUP_KEY: &[u8] = b"last backup";
+
+/// The sled Tree ID of the spool set's journal of spools being
+/// created or deleted, see `Intent`.
+const JOURNAL_TREE_ID: &[u8] = b"journal_tree_id";
+
+/// The sled Tree ID of the spool set's audit log, see `audit`.
+const AUDIT_TREE_ID: &[u8] = b"audit_tree_id";
+
+/// The sled Tree ID of the tree the health check writes its probe to.
+const HEALTH_TREE_ID: &[u8] = b"health_tree_id";
+
+/// The spool set tree holding each spool's sealed secret, from which
+/// the spool's key is derived. A re-keyed spool's secret is followed
+/// by the owner key its spool key was derived from.
+const SECRET_TREE_ID: &[u8] = b"secret_tree_id";
+
+/// Additional data sealed along with spool secrets, which keeps them
+/// apart from owner keys sealed under the same spool ID.
+const SECRET_AAD: &[u8] = b"spool secret";
+
+/// The key the health check writes and then deletes.
+const HEALTH_PROBE_KEY: &[u8] = b"health_probe";
+
+/// The spool set tree holding the replication role and epoch.
+const REPLICATION_TREE_ID: &[u8] = b"replication_tree_id";
+
+/// The key of the replication role, see `Role`.
+const ROLE_KEY: &[u8] = b"role";
+
+/// The key of the epoch of the latest primary seen.
+const EPOCH_KEY: &[u8] = b"epoch";
+
+/// The key whose value pointed to the last message of a spool of
+/// format 1 or older.
 static END_KEY: &'static [u8] = b"key";
 
+/// The key whose value is the format version of a spool, in its meta
+/// tree, or of the spool set, in its format tree.
+static FORMAT_KEY: &'static [u8] = b"format";
+
+/// The spool format version. Spools are upgraded when opened by
+/// running the migrations in SPOOL_MIGRATIONS from their version on:
+///
+/// * 0: bare MESSAGE_SIZE messages, without a format key.
+/// * 1: a flags byte and the big endian message length in front of
+///   every message, see `EntryCodec`.
+/// * 2: as 1, without END_KEY. The last message is the last key of
+///   the message tree, so that an append is a single write.
+/// * 3: as 2, with entries which may carry the time their message was
+///   appended at, see `ENTRY_FLAG_TIMESTAMP`.
+pub const SPOOL_FORMAT_VERSION: u8 = 3;
+
+/// A migration from one format version to the next.
+type Migration<T, E> = fn(&mut T) -> Result<(), E>;
+
+/// The migrations from each spool format version to the next.
+const SPOOL_MIGRATIONS: [Migration<Spool, SpoolError>; SPOOL_FORMAT_VERSION as usize] = [
+    Spool::add_entry_headers,
+    Spool::drop_end_key,
+    |_| Ok(()),
+];
+
+/// The spool set format version, upgraded like spools:
+///
+/// * 0: without a format key.
+/// * 1: as 0, with a format key.
+pub const SPOOL_SET_FORMAT_VERSION: u8 = 1;
+
+/// The migrations from each spool set format version to the next.
+const SPOOL_SET_MIGRATIONS: [Migration<SpoolSet, SpoolSetError>; SPOOL_SET_FORMAT_VERSION as usize] = [
+    |_| Ok(()),
+];
+
+/// The spool set tree holding its format version.
+const FORMAT_TREE_ID: &[u8] = b"format_tree_id";
+
+/// The size of the header in front of every stored message.
+const ENTRY_HEADER_SIZE: usize = 5;
+
+/// Entry flags, the first byte of every stored message.
+const ENTRY_FLAGS_NONE: u8 = 0;
+
+/// Entry flag set when the stored message is zstd compressed.
+const ENTRY_FLAG_COMPRESSED: u8 = 1;
+
+/// Entry flag set when the stored message is sealed with a master
+/// key, as messages were before spools had their own keys.
+/// Compression, if any, happens before encryption.
+const ENTRY_FLAG_ENCRYPTED: u8 = 2;
+
+/// Entry flag set when the stored message is sealed with its spool's
+/// key. Compression, if any, happens before encryption.
+const ENTRY_FLAG_SPOOL_KEY: u8 = 4;
+
+/// Entry flag set when the stored message is preceded by the big
+/// endian unix time it was appended at. The time is compressed and
+/// sealed along with the message.
+const ENTRY_FLAG_TIMESTAMP: u8 = 8;
+
+/// All known entry flags.
+const ENTRY_FLAGS_ALL: u8 = ENTRY_FLAG_COMPRESSED | ENTRY_FLAG_ENCRYPTED | ENTRY_FLAG_SPOOL_KEY | ENTRY_FLAG_TIMESTAMP;
+
+/// EntryCodec holds the settings messages are stored with. Entries
+/// record how they were stored, so they can be read back whatever
+/// the current settings are, given the keys they were sealed with.
+#[derive(Clone, Default)]
+pub struct EntryCodec {
+    /// The zstd level to compress messages at, if any.
+    pub compression_level: Option<i32>,
+    /// The master keys, needed to read messages sealed with them.
+    pub keyring: Option<Arc<Keyring>>,
+    /// The spool's key to encrypt messages with, if any.
+    pub spool_key: Option<Arc<SpoolKey>>,
+}
+
+impl EntryCodec {
+    /// Encodes a message to be stored under `key` as a spool entry.
+    /// Messages are only compressed if that makes them smaller.
+    fn encode(&self, message: &[u8], key: &[u8]) -> Result<Vec<u8>, SpoolError> {
+        self.encode_timed(message, None, key)
+    }
+
+    /// Encodes a message like `encode`, along with the unix time it
+    /// was appended at if known.
+    fn encode_timed(&self, message: &[u8], appended_at: Option<u64>, key: &[u8]) -> Result<Vec<u8>, SpoolError> {
+        let mut flags = ENTRY_FLAGS_NONE;
+        let mut payload = message.to_vec();
+        if let Some(appended_at) = appended_at {
+            flags |= ENTRY_FLAG_TIMESTAMP;
+            payload = vec![0u8; 8];
+            BigEndian::write_u64(&mut payload, appended_at);
+            payload.extend_from_slice(message);
+        }
+        if let Some(level) = self.compression_level {
+            let _span = trace::span("zstd_compress");
+            let compressed = zstd::encode_all(payload.as_slice(), level)?;
+            if compressed.len() < payload.len() {
+                flags |= ENTRY_FLAG_COMPRESSED;
+                payload = compressed;
+            }
+        }
+        if let Some(ref spool_key) = self.spool_key {
+            let _span = trace::span("seal");
+            flags |= ENTRY_FLAG_SPOOL_KEY;
+            payload = spool_key.seal(&payload, key)?;
+        }
+        let mut entry = vec![0u8; ENTRY_HEADER_SIZE];
+        entry[0] = flags;
+        BigEndian::write_u32(&mut entry[1..ENTRY_HEADER_SIZE], payload.len() as u32);
+        entry.extend_from_slice(&payload);
+        Ok(entry)
+    }
+
+    /// Decodes a spool entry stored under `key`. Also returns true if
+    /// the entry is stale, that is not encrypted with the spool's key
+    /// although the spool has one, and should be rewritten.
+    fn decode(&self, entry: &[u8], key: &[u8]) -> Result<(Vec<u8>, bool), SpoolError> {
+        let (payload, _, stale) = self.decode_timed(entry, key)?;
+        Ok((payload, stale))
+    }
+
+    /// Decodes a spool entry like `decode`, also returning the unix
+    /// time its message was appended at if it was stored.
+    fn decode_timed(&self, entry: &[u8], key: &[u8]) -> Result<(Vec<u8>, Option<u64>, bool), SpoolError> {
+        if entry.len() < ENTRY_HEADER_SIZE || entry[0] & !ENTRY_FLAGS_ALL != 0 {
+            return Err(SpoolError::CorruptMessage)
+        }
+        let len = BigEndian::read_u32(&entry[1..ENTRY_HEADER_SIZE]) as usize;
+        if entry.len() != ENTRY_HEADER_SIZE + len {
+            return Err(SpoolError::CorruptMessage)
+        }
+        let flags = entry[0];
+        let mut payload = entry[ENTRY_HEADER_SIZE..].to_vec();
+        let stale = self.spool_key.is_some() && flags & ENTRY_FLAG_SPOOL_KEY == 0;
+        if flags & ENTRY_FLAG_SPOOL_KEY != 0 {
+            let _span = trace::span("open");
+            payload = match self.spool_key {
+                Some(ref spool_key) => spool_key.open(&payload, key)?,
+                None => return Err(SpoolError::EncryptionError(EncryptionError::NoKeys)),
+            };
+        } else if flags & ENTRY_FLAG_ENCRYPTED != 0 {
+            let _span = trace::span("open");
+            payload = match self.keyring {
+                Some(ref keyring) => keyring.open(&payload, key)?,
+                None => return Err(SpoolError::EncryptionError(EncryptionError::NoKeys)),
+            };
+        }
+        if flags & ENTRY_FLAG_COMPRESSED != 0 {
+            let _span = trace::span("zstd_decompress");
+            payload = zstd::decode_all(payload.as_slice()).map_err(|_| SpoolError::CorruptMessage)?;
+        }
+        if flags & ENTRY_FLAG_TIMESTAMP == 0 {
+            return Ok((payload, None, stale))
+        }
+        if payload.len() < 8 {
+            return Err(SpoolError::CorruptMessage)
+        }
+        let appended_at = BigEndian::read_u64(&payload[..8]);
+        Ok((payload.split_off(8), Some(appended_at), stale))
+    }
+
+    /// Returns the key `data` is looked up under in a spool's
+    /// indexes, keyed with the spool's key if it has one.
+    fn lookup_key(&self, data: &[u8]) -> Result<Vec<u8>, SpoolError> {
+        match self.spool_key {
+            Some(ref spool_key) => Ok(spool_key.lookup_key(data)?.to_vec()),
+            None => Ok(data.to_vec()),
+        }
+    }
+}
+
+/// Returns the additional data a message's leaf hash is sealed with,
+/// which keeps it apart from the message sealed under the same ID.
+fn leaf_hash_aad(message_id: &[u8; MESSAGE_ID_SIZE]) -> Vec<u8> {
+    let mut aad = message_id.to_vec();
+    aad.extend_from_slice(b"leaf hash");
+    aad
+}
+
+/// Intent is an operation on a spool which touches both the spool set
+/// and the spool's database. It is recorded in the spool set's journal
+/// before the operation starts and removed once the operation is done,
+/// so that operations interrupted by a crash can be finished when the
+/// service starts again, see `MultiSpool::new`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Intent {
+    /// The spool is being created. Interrupted creations are rolled
+    /// back, as the spool's ID never reached its owner.
+    Create,
+    /// The spool is being deleted for good. Interrupted deletions are
+    /// carried out.
+    Delete,
+}
+
+impl Intent {
+    fn to_byte(self) -> u8 {
+        match self {
+            Intent::Create => 1,
+            Intent::Delete => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Intent> {
+        match byte {
+            1 => Some(Intent::Create),
+            2 => Some(Intent::Delete),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Intent::Create => "create",
+            Intent::Delete => "delete",
+        }
+    }
+}
+
+/// Activity is what the spool set keeps the time of for each spool.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activity {
+    Created,
+    Appended,
+    Read,
+}
+
+impl Activity {
+    fn to_byte(self) -> u8 {
+        match self {
+            Activity::Created => 1,
+            Activity::Appended => 2,
+            Activity::Read => 3,
+        }
+    }
+}
+
+/// SpoolActivity is when a spool was created and last appended to
+/// and read, in seconds since the unix epoch. Spools created before
+/// this was kept have no creation time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SpoolActivity {
+    pub created_at: Option<u64>,
+    pub last_append: Option<u64>,
+    pub last_read: Option<u64>,
+}
+
 // SpoolSet constants
 
 /// Spool identity size in bytes.
@@ -66,6 +438,39 @@ const SPOOL_SET_FLUSH_FREQUENCY: u64 = 10000;
 /// Spool set size. The maximum allowed number of spools.
 pub const SPOOL_SET_SIZE: usize = 10000;
 
+/// Flush spool writeback caches every 10 seconds by default.
+pub const DEFAULT_FLUSH_EVERY_MS: u64 = 10000;
+
+/// Snapshot spool page tables every 1000 writes by default.
+pub const DEFAULT_SNAPSHOT_AFTER_OPS: usize = 1000;
+
+/// Durability trades append throughput against how many acknowledged
+/// messages a crash may lose.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Durability {
+    /// How often spools flush their writeback cache, or never if None.
+    pub flush_every_ms: Option<u64>,
+    /// How many writes sled takes between page table snapshots.
+    pub snapshot_after_ops: usize,
+    /// Whether every append is flushed to disk before it is
+    /// acknowledged, as if each one asked to be durable.
+    pub flush_on_append: bool,
+    /// How long durable appends wait for others to share their
+    /// flush with, see `group_commit`.
+    pub group_commit_window_ms: u64,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability {
+            flush_every_ms: Some(DEFAULT_FLUSH_EVERY_MS),
+            snapshot_after_ops: DEFAULT_SNAPSHOT_AFTER_OPS,
+            flush_on_append: false,
+            group_commit_window_ms: DEFAULT_GROUP_COMMIT_WINDOW_MS,
+        }
+    }
+}
+
 
 /// Spool is an append only message spool.
 #[derive(Clone)]
@@ -74,430 +479,4867 @@ pub struct Spool {
     last_key: Option<u32>,
     db: Db,
     meta: Arc<Tree>,
+    hashes: Arc<Tree>,
+    tokens: Arc<Tree>,
+    index: Arc<Tree>,
+    codec: EntryCodec,
+    commit: Arc<GroupCommit>,
+}
+
+/// PendingFlush is an append to a spool which has yet to be flushed
+/// to disk.
+pub struct PendingFlush {
+    db: Db,
+    commit: Arc<GroupCommit>,
+    ticket: u64,
+}
+
+impl PendingFlush {
+    /// Waits until the append is on disk, flushing it together with
+    /// the other appends waiting meanwhile.
+    pub fn wait(self) -> Result<(), SpoolError> {
+        let db = self.db;
+        self.commit.wait(self.ticket, || {
+            db.flush()?;
+            Ok(())
+        })
+    }
 }
 
 impl Spool {
     pub fn new<P: AsRef<Path>>(path: &P) -> Result<Spool, SpoolError> {
+        Spool::with_durability(path, &Durability::default())
+    }
 
-        fn increment_merge(_key: &[u8], old_value: Option<&[u8]>, new_value: &[u8]) -> Option<Vec<u8>> {
-            if let Some(old_value_bytes) = old_value {
-                let old: u32 = BigEndian::read_u32(old_value_bytes);
-                let new: u32 = BigEndian::read_u32(new_value);
-                if old == new {
-                    return Some(old_value_bytes.to_vec())
-                }
-                if old > new {
-                    return Some(old_value_bytes.to_vec())
-                }
-            }
-            return Some(new_value.to_vec())
-        }
+    /// Opens a spool like `new`, flushing it as `durability` says.
+    pub fn with_durability<P: AsRef<Path>>(path: &P, durability: &Durability) -> Result<Spool, SpoolError> {
+        let mut spool = Spool::open(path, durability)?;
+        spool.migrate()?;
+        Ok(spool)
+    }
 
+    /// Opens a spool as it is on disk, without the repairs and
+    /// upgrades `new` makes, so that it can be checked.
+    pub fn open_unchecked<P: AsRef<Path>>(path: &P) -> Result<Spool, SpoolError> {
+        Spool::open(path, &Durability::default())
+    }
+
+    fn open<P: AsRef<Path>>(path: &P, durability: &Durability) -> Result<Spool, SpoolError> {
         let spool_cfg_builder = sled::ConfigBuilder::default()
-            .merge_operator(increment_merge)
             .path(path)
             .cache_capacity(SPOOL_SIZE * MESSAGE_SIZE)
             .use_compression(false)
-            .flush_every_ms(Some(SPOOL_SET_FLUSH_FREQUENCY))
-            .snapshot_after_ops(1000);
+            .flush_every_ms(durability.flush_every_ms)
+            .snapshot_after_ops(durability.snapshot_after_ops);
         let db = Db::start(spool_cfg_builder.build())?;
         let meta = db.open_tree(META_TREE_ID.to_vec())?;
+        let hashes = db.open_tree(HASH_TREE_ID.to_vec())?;
+        let tokens = db.open_tree(TOKEN_TREE_ID.to_vec())?;
+        let index = db.open_tree(INDEX_TREE_ID.to_vec())?;
         let mut spool = Spool {
             path: PathBuf::from(path.as_ref()),
             last_key: None,
             db: db,
             meta: meta,
+            hashes: hashes,
+            tokens: tokens,
+            index: index,
+            codec: EntryCodec::default(),
+            commit: Arc::new(GroupCommit::new(Duration::from_millis(durability.group_commit_window_ms))),
         };
-        spool.ensure_consistency()?;
-        let end_key_res = spool.meta.get(END_KEY).unwrap();
-        if end_key_res.is_none() {
-            spool.last_key = None;
-        } else {
-            spool.last_key = Some(BigEndian::read_u32(&end_key_res.unwrap()));
-        }
+        spool.last_key = spool.last_message()?;
         Ok(spool)
     }
 
-    fn ensure_consistency(&mut self) -> Result<(), SpoolError> {
-        if self.meta.get(END_KEY)?.is_none() {
-            return Ok(());
+    /// Returns the ID of the last message, the last key of the message
+    /// tree which is a message ID.
+    fn last_message(&self) -> Result<Option<u32>, SpoolError> {
+        for key in self.db.iter().keys().rev() {
+            let key = key?;
+            if key.len() == MESSAGE_ID_SIZE {
+                return Ok(Some(BigEndian::read_u32(&key)))
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the spool's format version.
+    pub fn format_version(&self) -> Result<u8, SpoolError> {
+        match self.meta.get(FORMAT_KEY)? {
+            Some(ref x) if x.len() == 1 => Ok(x[0]),
+            Some(_) => Err(SpoolError::CorruptSpool),
+            None => Ok(0),
+        }
+    }
+
+    /// Upgrades the spool to SPOOL_FORMAT_VERSION, one version at a
+    /// time, or refuses spools written by a newer version. Each
+    /// migration may be run again over its own partial result, so an
+    /// interrupted upgrade is redone from the last version stored.
+    fn migrate(&mut self) -> Result<(), SpoolError> {
+        let mut version = self.format_version()?;
+        if version > SPOOL_FORMAT_VERSION {
+            return Err(SpoolError::UnsupportedFormat(version))
         }
-        let mut _raw_last_key_option = self.meta.get(END_KEY)?;
-        if _raw_last_key_option.is_none() {
-            if !self.db.is_empty() {
-                return Err(SpoolError::CorruptSpool);
+        while version < SPOOL_FORMAT_VERSION {
+            SPOOL_MIGRATIONS[version as usize](self)?;
+            version += 1;
+            self.meta.set(FORMAT_KEY, vec![version])?;
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Migrates from format 0 by rewriting bare messages into
+    /// entries which carry their length. Every entry is staged in the
+    /// migration tree before any message is overwritten, and copied
+    /// over from there, so that a message is never wrapped twice
+    /// however often this is interrupted.
+    fn add_entry_headers(&mut self) -> Result<(), SpoolError> {
+        let staging = self.db.open_tree(MIGRATION_TREE_ID.to_vec())?;
+        if self.meta.get(MIGRATION_STAGED_KEY)?.is_none() {
+            for entry in self.db.iter() {
+                let (key, value) = entry?;
+                let entry = self.codec.encode(&value, &key)?;
+                staging.set(key, entry)?;
             }
+            self.meta.set(MIGRATION_STAGED_KEY.to_vec(), vec![])?;
+            self.db.flush()?;
         }
-        let mut _raw_last_key = _raw_last_key_option.unwrap();
-        let mut raw_last_key: Vec<u8> = _raw_last_key.to_vec();
-        loop {
-            let mut last_key = BigEndian::read_u32(&raw_last_key);
-            let prev_key = last_key;
-            let raw_prev_key = raw_last_key.to_vec().clone();
-            last_key += 1;
-            BigEndian::write_u32(&mut raw_last_key, last_key); // XXX
-            if !self.db.contains_key(raw_last_key.to_vec())? {
-                self.last_key = Some(prev_key);
-                self.meta.set(END_KEY, raw_prev_key.to_vec())?;
-                return Ok(())
+        let mut staged = vec![];
+        for entry in staging.iter() {
+            let (key, value) = entry?;
+            self.db.set(key.clone(), value.to_vec())?;
+            staged.push(key);
+        }
+        self.db.flush()?;
+        // The staged marker stays, so that a run interrupted from
+        // here on copies the rest over instead of staging again.
+        for key in staged {
+            staging.del(key)?;
+        }
+        Ok(())
+    }
+
+    /// Migrates from format 1 by forgetting END_KEY, which a crash
+    /// could leave behind the last message.
+    fn drop_end_key(&mut self) -> Result<(), SpoolError> {
+        self.meta.del(END_KEY)?;
+        Ok(())
+    }
+
+    /// Checks the spool without changing it, see `fsck`. Messages are
+    /// only decoded if the spool is of the current format.
+    pub fn check(&self) -> Result<Vec<Problem>, SpoolError> {
+        let mut problems = vec![];
+        let decode = match self.format_version() {
+            Ok(version) if version > SPOOL_FORMAT_VERSION => {
+                problems.push(Problem::UnsupportedFormat(version));
+                return Ok(problems)
+            },
+            Ok(version) if version < SPOOL_FORMAT_VERSION => {
+                problems.push(Problem::OutdatedFormat(version));
+                false
+            },
+            Ok(_) => true,
+            Err(SpoolError::CorruptSpool) => {
+                problems.push(Problem::CorruptFormatVersion);
+                return Ok(problems)
+            },
+            Err(e) => return Err(e),
+        };
+        // Each tally is the first message ID and the number of messages.
+        fn tally(tally: &mut Option<(u32, u64)>, message_id: u32, count: u64) {
+            match tally {
+                Some((_, ref mut total)) => *total += count,
+                None => *tally = Some((message_id, count)),
+            }
+        }
+        let mut missing = None;
+        let mut unreadable = None;
+        let mut mismatches = None;
+        let mut stray_keys = 0;
+        let mut missing_checksums = 0;
+        let mut next = u64::from(self.first_message()?);
+        for entry in self.db.iter() {
+            let (key, value) = entry?;
+            if key.len() != MESSAGE_ID_SIZE {
+                stray_keys += 1;
+                continue
+            }
+            let message_id = *array_ref![key, 0, MESSAGE_ID_SIZE];
+            let id = BigEndian::read_u32(&message_id);
+            if u64::from(id) > next {
+                tally(&mut missing, next as u32, u64::from(id) - next);
+            }
+            next = u64::from(id) + 1;
+            if !decode {
+                continue
+            }
+            let message = match self.codec.decode(&value, &message_id) {
+                Ok((message, _)) => message,
+                Err(_) => {
+                    tally(&mut unreadable, id, 1);
+                    continue
+                },
+            };
+            match self.hashes.get(message_id.to_vec())? {
+                Some(entry) => match self.codec.decode(&entry, &leaf_hash_aad(&message_id)) {
+                    Ok((ref leaf, _)) if leaf[..] == merkle::leaf_hash(&message)[..] => {},
+                    _ => tally(&mut mismatches, id, 1),
+                },
+                None => missing_checksums += 1,
+            }
+        }
+
+        if stray_keys > 0 {
+            problems.push(Problem::StrayKeys(stray_keys));
+        }
+        if let Some((first, count)) = missing {
+            problems.push(Problem::MissingMessages { first: first, count: count });
+        }
+        if let Some((first, count)) = unreadable {
+            problems.push(Problem::UnreadableMessages { first: first, count: count });
+        }
+        if let Some((first, count)) = mismatches {
+            problems.push(Problem::ChecksumMismatches { first: first, count: count });
+        }
+        if missing_checksums > 0 {
+            problems.push(Problem::MissingChecksums(missing_checksums));
+        }
+        Ok(problems)
+    }
+
+    /// Repairs the problems `check` finds which don't cost messages:
+    /// upgrades the format, removes stray keys and stores any missing
+    /// leaf hashes.
+    pub fn repair(&mut self) -> Result<(), SpoolError> {
+        self.migrate()?;
+        for key in self.db.iter().keys() {
+            let key = key?;
+            if key.len() != MESSAGE_ID_SIZE {
+                self.db.del(key)?;
             }
         }
+        self.leaf_hashes()?;
+        self.flush()
     }
 
     pub fn purge(&mut self) -> Result<(), SpoolError> {
+        let _span = trace::span("sled_purge");
         self.db.drop_tree(META_TREE_ID)?;
+        self.db.drop_tree(HASH_TREE_ID)?;
+        self.db.drop_tree(TOKEN_TREE_ID)?;
+        self.db.drop_tree(INDEX_TREE_ID)?;
         self.db.clear()?;
-        self.last_key = Some(0);
+        self.last_key = None;
         Ok(())
     }
 
-    pub fn append(&mut self, message: [u8; MESSAGE_SIZE]) -> Result<(), SpoolError> {
-        if self.last_key.is_some() {
-            self.last_key = Some(self.last_key.unwrap() + 1);
-            let mut _last_key = [0; 4];
-            BigEndian::write_u32(&mut _last_key, self.last_key.unwrap());
-            self.db.set(_last_key, message.to_vec())?;
-            self.meta.merge(END_KEY, _last_key.to_vec())?;
-            return Ok(());
-        }
-        self.last_key = Some(0);
-        let mut _last_key = [0; 4];
-        self.db.set(_last_key, message.to_vec())?;
-        self.meta.merge(END_KEY, _last_key.to_vec())?;
-        return Ok(());
+    /// Sets how messages appended from now on are stored, and the
+    /// master keys used to read them back.
+    pub fn set_codec(&mut self, codec: EntryCodec) {
+        self.codec = codec;
+    }
+
+    /// Appends a message, stamped with the current time. Message size
+    /// limits are up to the caller.
+    pub fn append(&mut self, message: &[u8]) -> Result<(), SpoolError> {
+        self.append_at(message, Some(unix_time()))
+    }
+
+    /// Appends a message stamped with `appended_at`, the unix time it
+    /// was appended at if known. Storing the message is a single write
+    /// which also advances the spool, so a crash can't keep one
+    /// without the other. A missing leaf hash is recomputed when
+    /// needed.
+    pub fn append_at(&mut self, message: &[u8], appended_at: Option<u64>) -> Result<(), SpoolError> {
+        let _span = trace::span("sled_append");
+        let next_key = match self.last_key {
+            Some(last_key) => last_key + 1,
+            None => 0,
+        };
+        let mut message_id = [0u8; MESSAGE_ID_SIZE];
+        BigEndian::write_u32(&mut message_id, next_key);
+        self.db.set(message_id, self.codec.encode_timed(message, appended_at, &message_id)?)?;
+        self.last_key = Some(next_key);
+        self.set_leaf_hash(&message_id, &merkle::leaf_hash(message))?;
+        Ok(())
+    }
+
+    /// Stores the leaf hash of a message, encoded like the messages
+    /// themselves so that it doesn't give away what they are.
+    fn set_leaf_hash(&self, message_id: &[u8; MESSAGE_ID_SIZE], leaf: &merkle::Hash) -> Result<(), SpoolError> {
+        self.hashes.set(message_id.to_vec(), self.codec.encode(leaf, &leaf_hash_aad(message_id))?)?;
+        Ok(())
     }
 
-    pub fn read(&self, message_id: &[u8; MESSAGE_ID_SIZE]) -> Result<[u8; MESSAGE_SIZE], SpoolError> {
-        if let Some(message) = self.db.get(message_id)? {
-            return Ok(*array_ref![message, 0, MESSAGE_SIZE])
+    /// Returns the Merkle leaf hashes of every message in order,
+    /// hashing messages appended before spools kept hashes.
+    pub fn leaf_hashes(&self) -> Result<Vec<merkle::Hash>, SpoolError> {
+        let _span = trace::span("sled_leaf_hashes");
+        let mut leaves = Vec::with_capacity(self.message_count() as usize);
+        let mut message_id = [0u8; MESSAGE_ID_SIZE];
+        for i in 0..self.message_count() {
+            BigEndian::write_u32(&mut message_id, i as u32);
+            let leaf = match self.hashes.get(message_id.to_vec())? {
+                Some(entry) => {
+                    let (raw, stale) = self.codec.decode(&entry, &leaf_hash_aad(&message_id))?;
+                    if raw.len() != merkle::HASH_SIZE {
+                        return Err(SpoolError::CorruptMessage)
+                    }
+                    let mut leaf = [0u8; merkle::HASH_SIZE];
+                    leaf.copy_from_slice(&raw);
+                    if stale {
+                        self.set_leaf_hash(&message_id, &leaf)?;
+                    }
+                    leaf
+                },
+                None => {
+                    let leaf = merkle::leaf_hash(&self.read(&message_id)?);
+                    self.set_leaf_hash(&message_id, &leaf)?;
+                    leaf
+                },
+            };
+            leaves.push(leaf);
         }
-        return Err(SpoolError::NoSuchMessage)
+        Ok(leaves)
     }
-}
 
-/// SpoolSet is essentially a persistent set of spool identities.
-#[derive(Clone)]
-pub struct SpoolSet {
-    db: Db,
-    meta: Arc<Tree>,
-}
+    /// Indexes the message with `message_id` under its leaf hash, so
+    /// that `find_by_hash` finds it. Of several messages with the same
+    /// hash the latest is indexed.
+    pub fn index_hash(&self, message_id: &[u8; MESSAGE_ID_SIZE], leaf: &merkle::Hash) -> Result<(), SpoolError> {
+        self.sync_index()?;
+        self.index.set(self.codec.lookup_key(leaf)?, message_id.to_vec())?;
+        Ok(())
+    }
 
-impl SpoolSet {
-    pub fn new<P: AsRef<Path>>(path: &P) -> Result<SpoolSet, SpoolSetError> {
-        let cache_cfg_builder = sled::ConfigBuilder::default()
-            .path(path)
-            .cache_capacity(SPOOL_SET_SIZE * SPOOL_ID_SIZE)
-            .use_compression(false)
-            .flush_every_ms(Some(SPOOL_SET_FLUSH_FREQUENCY))
-            .snapshot_after_ops(100);
-        let cache_cfg = cache_cfg_builder.build();
-        let db = Db::start(cache_cfg)?;
-        let meta = db.open_tree(META_TREE_ID.to_vec())?;
-        let mut spool_set = SpoolSet{
-            db: db,
-            meta: meta,
+    /// Returns the ID of the latest message kept whose leaf hash is
+    /// `leaf`, if the spool's hash index has one.
+    pub fn find_by_hash(&self, leaf: &merkle::Hash) -> Result<Option<[u8; MESSAGE_ID_SIZE]>, SpoolError> {
+        let _span = trace::span("sled_find_by_hash");
+        self.sync_index()?;
+        let message_id = match self.index.get(self.codec.lookup_key(leaf)?)? {
+            Some(ref x) if x.len() == MESSAGE_ID_SIZE => *array_ref![x, 0, MESSAGE_ID_SIZE],
+            Some(_) => return Err(SpoolError::CorruptSpool),
+            None => return Ok(None),
         };
-        spool_set.ensure_consistency()?;
-        Ok(spool_set)
+        if BigEndian::read_u32(&message_id) < self.first_message()? {
+            return Ok(None)
+        }
+        Ok(Some(message_id))
     }
 
-    fn ensure_consistency(&mut self) -> Result<(), SpoolSetError> {
-        for key_result in self.db.iter().keys() {
-            let key = key_result?;
-            if !self.meta.contains_key(key.clone())? {
-                self.db.del(key)?;
+    /// Drops a message about to be trimmed from the hash index, unless
+    /// a later message with the same hash is indexed instead.
+    fn unindex(&self, message_id: &[u8; MESSAGE_ID_SIZE]) -> Result<(), SpoolError> {
+        let entry = match self.hashes.get(message_id.to_vec())? {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let (leaf, _) = self.codec.decode(&entry, &leaf_hash_aad(message_id))?;
+        let key = self.codec.lookup_key(&leaf)?;
+        if let Some(ref x) = self.index.get(key.clone())? {
+            if x[..] == message_id[..] {
+                self.index.del(key)?;
             }
         }
-        for key_result in self.meta.iter().keys() {
-            let key = key_result?;
-            if !self.db.contains_key(key.clone())? {
-                self.meta.del(key)?;
+        Ok(())
+    }
+
+    /// Rebuilds the hash index from the leaf hashes of the messages
+    /// kept if it isn't keyed the way lookups now are, as after
+    /// encryption was turned on, or was never built.
+    fn sync_index(&self) -> Result<(), SpoolError> {
+        let keyed = self.codec.spool_key.is_some() as u8;
+        if let Some(ref x) = self.index.get(INDEX_KEYED_KEY)? {
+            if x.len() == 1 && x[0] == keyed {
+                return Ok(())
             }
         }
+        self.index.clear()?;
+        let first = self.first_message()? as usize;
+        let mut message_id = [0u8; MESSAGE_ID_SIZE];
+        for (i, leaf) in self.leaf_hashes()?.iter().enumerate().skip(first) {
+            BigEndian::write_u32(&mut message_id, i as u32);
+            self.index.set(self.codec.lookup_key(leaf)?, message_id.to_vec())?;
+        }
+        self.index.set(INDEX_KEYED_KEY.to_vec(), vec![keyed])?;
         Ok(())
     }
 
-    pub fn put(&mut self, spool_id: [u8; SPOOL_ID_SIZE], public_key: PublicKey) -> Result<(), SpoolSetError> {
-        self.db.set(spool_id.to_vec(), vec![])?;
-        self.meta.set(spool_id.to_vec(), public_key.to_bytes().to_vec())?;
+    /// Sets the key append tokens are checked against, or stops
+    /// requiring tokens if `key` is None. The key is encoded like the
+    /// messages.
+    pub fn set_append_token_key(&self, key: Option<&TokenKey>) -> Result<(), SpoolError> {
+        match key {
+            Some(key) => self.tokens.set(TOKEN_KEY_KEY.to_vec(), self.codec.encode(&key.to_bytes(), TOKEN_KEY_KEY)?)?,
+            None => self.tokens.del(TOKEN_KEY_KEY)?,
+        };
         Ok(())
     }
 
-    pub fn has(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<bool, SpoolSetError> {
-        Ok(self.db.contains_key(spool_id.to_vec())?)
+    /// Returns the key append tokens are checked against, if appends
+    /// need tokens.
+    pub fn append_token_key(&self) -> Result<Option<TokenKey>, SpoolError> {
+        let entry = match self.tokens.get(TOKEN_KEY_KEY)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let (raw, stale) = self.codec.decode(&entry, TOKEN_KEY_KEY)?;
+        let key = TokenKey::from_bytes(&raw).map_err(|_| SpoolError::CorruptSpool)?;
+        if stale {
+            self.set_append_token_key(Some(&key))?;
+        }
+        Ok(Some(key))
     }
 
-    pub fn delete(&mut self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<(), SpoolSetError> {
-        self.db.del(spool_id.to_vec())?;
-        self.meta.del(spool_id.to_vec())?;
+    /// Sets the writers allowed to append to the spool, or lets anyone
+    /// append if `appenders` is None. The list is encoded like the
+    /// messages.
+    pub fn set_appenders(&self, appenders: Option<&AppenderList>) -> Result<(), SpoolError> {
+        match appenders {
+            Some(appenders) => self.meta.set(APPENDERS_KEY.to_vec(), self.codec.encode(&appenders.to_bytes(), APPENDERS_KEY)?)?,
+            None => self.meta.del(APPENDERS_KEY)?,
+        };
         Ok(())
     }
 
-    pub fn keys<'a>(&'a self) -> impl 'a + DoubleEndedIterator<Item = Result<Vec<u8>, sled::Error<()>>> {
-        self.db.iter().keys()
+    /// Returns the writers allowed to append to the spool, if it has
+    /// an appender list.
+    pub fn appenders(&self) -> Result<Option<AppenderList>, SpoolError> {
+        let entry = match self.meta.get(APPENDERS_KEY)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let (raw, stale) = self.codec.decode(&entry, APPENDERS_KEY)?;
+        let appenders = AppenderList::from_bytes(&raw).map_err(|_| SpoolError::CorruptSpool)?;
+        if stale {
+            self.set_appenders(Some(&appenders))?;
+        }
+        Ok(Some(appenders))
     }
 
-    pub fn get_public_key(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<PublicKey, SpoolSetError> {
-        if let Some(pub_key) = self.meta.get(spool_id.to_vec())? {
-            return Ok(PublicKey::from_bytes(&pub_key)?);
+    /// Returns the ID of the oldest message kept, zero unless older
+    /// messages were dropped by `trim`.
+    pub fn first_message(&self) -> Result<u32, SpoolError> {
+        match self.meta.get(FIRST_MESSAGE_KEY)? {
+            Some(ref x) if x.len() == 4 => Ok(BigEndian::read_u32(x)),
+            Some(_) => Err(SpoolError::CorruptSpool),
+            None => Ok(0),
         }
-        Err(SpoolSetError::NoSuchSpoolId)
     }
-}
 
-/// MultiSpool allows for accessing multiple spools.
-#[derive(Clone)]
-pub struct MultiSpool {
-    map: HashMap<[u8; SPOOL_ID_SIZE], Spool>,
-    spool_set: SpoolSet,
-    base_dir: String,
-}
+    /// Returns the number of messages kept.
+    pub fn retained_count(&self) -> Result<u64, SpoolError> {
+        Ok(self.message_count().saturating_sub(u64::from(self.first_message()?)))
+    }
 
-fn spool_path(base_dir: &String, spool_id: [u8; SPOOL_ID_SIZE]) -> PathBuf {
-    let path = Path::new(base_dir).join(format!("spool.{}.sled", base64::encode(&spool_id)));
-    let pathbuf: PathBuf = path.to_owned();
-    pathbuf
-}
+    /// Drops the oldest messages until at most `capacity` are left,
+    /// returning the number dropped. Their leaf hashes are kept, so
+    /// read proofs still cover them, but they are dropped from the
+    /// hash index. The new first message is stored before the
+    /// messages are deleted, so a crash leaves messages behind rather
+    /// than a gap.
+    pub fn trim(&mut self, capacity: u64) -> Result<u64, SpoolError> {
+        let first = u64::from(self.first_message()?);
+        let count = self.message_count();
+        if count.saturating_sub(first) <= capacity {
+            return Ok(0)
+        }
+        let new_first = count - capacity;
+        let mut value = vec![0u8; 4];
+        BigEndian::write_u32(&mut value, new_first as u32);
+        self.meta.set(FIRST_MESSAGE_KEY.to_vec(), value)?;
+        let indexed = self.index.contains_key(INDEX_KEYED_KEY)?;
+        let mut message_id = [0u8; MESSAGE_ID_SIZE];
+        for i in first..new_first {
+            BigEndian::write_u32(&mut message_id, i as u32);
+            if indexed {
+                self.unindex(&message_id)?;
+            }
+            self.db.del(message_id)?;
+        }
+        Ok(new_first - first)
+    }
 
-fn remove_corrupt_spool(base_dir: &String, spool_id: [u8; SPOOL_ID_SIZE]) -> io::Result<()> {
-    let path = spool_path(base_dir, spool_id);
-    remove_file(&path)?;
-    Ok(())
-}
+    /// Returns true if the token with `token_id` was already spent.
+    pub fn is_token_spent(&self, token_id: &[u8]) -> Result<bool, SpoolError> {
+        Ok(self.tokens.contains_key(token_id.to_vec())?)
+    }
 
-impl MultiSpool {
+    /// Remembers the token with `token_id` as spent.
+    pub fn spend_token(&self, token_id: &[u8]) -> Result<(), SpoolError> {
+        self.tokens.set(token_id.to_vec(), vec![])?;
+        Ok(())
+    }
 
-    pub fn new(base_dir: &String) -> Result<Self, MultiSpoolError> {
-        let spool_set_path = Path::new(base_dir).join("spool_set.sled");
-        let mut spool_set = SpoolSet::new(&spool_set_path)?;
-        let spool_set_clone = spool_set.clone();
-        let mut map = HashMap::new();
-        for spool_id_result in spool_set_clone.keys() {
-            let raw_spool_id = spool_id_result?;
-            let spool_id = *array_ref![raw_spool_id, 0, SPOOL_ID_SIZE];
-            let path = spool_path(base_dir, spool_id.clone());
-            let spool_result = Spool::new(&path);
-            if spool_result.is_ok() {
-                map.insert(spool_id, spool_result.ok().unwrap());
-            } else {
-                match spool_result.err().unwrap() {
-                    SpoolError::CorruptSpool => {
-                        spool_set.delete(spool_id)?;
-                        remove_corrupt_spool(base_dir, spool_id)?;
-                    },
-                    e => {
-                        return Err(MultiSpoolError::SpoolError(e))
-                    }
+    /// Returns the IDs of every spent token.
+    pub fn spent_tokens(&self) -> Result<Vec<Vec<u8>>, SpoolError> {
+        let mut token_ids = vec![];
+        for key in self.tokens.iter().keys() {
+            let key = key?;
+            if key != TOKEN_KEY_KEY {
+                token_ids.push(key);
+            }
+        }
+        Ok(token_ids)
+    }
+
+    /// Reads a message, returning it at the length it was appended.
+    /// Messages not encrypted with the current master key are lazily
+    /// re-encrypted.
+    pub fn read(&self, message_id: &[u8; MESSAGE_ID_SIZE]) -> Result<Vec<u8>, SpoolError> {
+        Ok(self.read_timed(message_id)?.0)
+    }
+
+    /// Reads a message like `read`, along with the unix time it was
+    /// appended at, unknown for messages appended by older versions.
+    pub fn read_timed(&self, message_id: &[u8; MESSAGE_ID_SIZE]) -> Result<(Vec<u8>, Option<u64>), SpoolError> {
+        let _span = trace::span("sled_read");
+        if let Some(entry) = self.db.get(message_id)? {
+            let (message, appended_at, stale) = self.codec.decode_timed(&entry, message_id)?;
+            if stale {
+                self.db.set(message_id.to_vec(), self.codec.encode_timed(&message, appended_at, message_id)?)?;
+            }
+            return Ok((message, appended_at))
+        }
+        return Err(SpoolError::NoSuchMessage)
+    }
+
+    /// Returns an iterator over the messages kept, oldest first, as
+    /// their message IDs and the messages at the length they were
+    /// appended. `rev` iterates newest first. Unlike `read` it
+    /// leaves stale messages as they are.
+    pub fn iter(&self) -> SpoolIter<'_> {
+        SpoolIter {
+            inner: self.db.iter(),
+            codec: &self.codec,
+        }
+    }
+
+    /// Returns an iterator over the messages kept from `message_id`
+    /// on, like `iter`.
+    pub fn iter_from(&self, message_id: u32) -> SpoolIter<'_> {
+        let mut key = [0u8; MESSAGE_ID_SIZE];
+        BigEndian::write_u32(&mut key, message_id);
+        SpoolIter {
+            inner: self.db.scan(key),
+            codec: &self.codec,
+        }
+    }
+
+    /// Returns the newest `count` messages kept, newest first, like
+    /// `iter` but scanning from the end of the spool.
+    pub fn latest(&self, count: usize) -> Result<Vec<(u32, Vec<u8>)>, SpoolError> {
+        self.iter().rev().take(count).collect()
+    }
+
+    /// Returns the ID of the message `offset` messages before the
+    /// newest, if it is kept. The messages kept have consecutive IDs,
+    /// so no scan is needed.
+    pub fn latest_id(&self, offset: u32) -> Result<Option<[u8; MESSAGE_ID_SIZE]>, SpoolError> {
+        let first = u64::from(self.first_message()?);
+        let count = self.message_count();
+        if u64::from(offset) >= count.saturating_sub(first) {
+            return Ok(None)
+        }
+        let mut message_id = [0u8; MESSAGE_ID_SIZE];
+        BigEndian::write_u32(&mut message_id, (count - 1 - u64::from(offset)) as u32);
+        Ok(Some(message_id))
+    }
+
+    /// Re-encrypts every message not encrypted with the current
+    /// master key, returning the number of messages rewritten.
+    pub fn reencrypt(&self) -> Result<u64, SpoolError> {
+        let mut count = 0;
+        for entry in self.db.iter() {
+            let (key, value) = entry?;
+            let (message, appended_at, stale) = self.codec.decode_timed(&value, &key)?;
+            if stale {
+                self.db.set(key.clone(), self.codec.encode_timed(&message, appended_at, &key)?)?;
+                count += 1;
+            }
+        }
+        for entry in self.hashes.iter() {
+            let (key, value) = entry?;
+            let aad = leaf_hash_aad(array_ref![key, 0, MESSAGE_ID_SIZE]);
+            let (leaf, stale) = self.codec.decode(&value, &aad)?;
+            if stale {
+                self.hashes.set(key, self.codec.encode(&leaf, &aad)?)?;
+            }
+        }
+        self.append_token_key()?;
+        self.appenders()?;
+        Ok(count)
+    }
+
+    /// Returns the number of messages appended to the spool.
+    pub fn message_count(&self) -> u64 {
+        match self.last_key {
+            Some(last_key) => u64::from(last_key) + 1,
+            None => 0,
+        }
+    }
+
+    /// Returns the path of the spool's database.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Copies every message and all spool metadata into a new
+    /// spool at `path`, leaving behind the space sled has not yet
+    /// reclaimed.
+    fn copy_to<P: AsRef<Path>>(&self, path: &P) -> Result<(), SpoolError> {
+        let spool = Spool::new(path)?;
+        for entry in self.db.iter() {
+            let (key, value) = entry?;
+            spool.db.set(key, value.to_vec())?;
+        }
+        for entry in self.meta.iter() {
+            let (key, value) = entry?;
+            spool.meta.set(key, value.to_vec())?;
+        }
+        for entry in self.hashes.iter() {
+            let (key, value) = entry?;
+            spool.hashes.set(key, value.to_vec())?;
+        }
+        for entry in self.tokens.iter() {
+            let (key, value) = entry?;
+            spool.tokens.set(key, value.to_vec())?;
+        }
+        for entry in self.index.iter() {
+            let (key, value) = entry?;
+            spool.index.set(key, value.to_vec())?;
+        }
+        spool.flush()
+    }
+
+    /// Flushes the spool's writeback cache to disk.
+    pub fn flush(&self) -> Result<(), SpoolError> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Returns the number of appends waiting to be made durable.
+    pub fn pending_flushes(&self) -> u64 {
+        self.commit.pending()
+    }
+
+    /// Returns the flush which makes the writes so far durable, to be
+    /// waited for once the spool is no longer locked.
+    pub fn pending_flush(&self) -> PendingFlush {
+        PendingFlush {
+            db: self.db.clone(),
+            commit: self.commit.clone(),
+            ticket: self.commit.record(),
+        }
+    }
+}
+
+/// SpoolIter iterates over the messages of a spool, see `Spool::iter`.
+pub struct SpoolIter<'a> {
+    inner: sled::Iter<'a>,
+    codec: &'a EntryCodec,
+}
+
+impl<'a> SpoolIter<'a> {
+    fn decode(&self, key: &[u8], value: &[u8]) -> Result<(u32, Vec<u8>), SpoolError> {
+        let (message, _, _) = self.codec.decode_timed(value, key)?;
+        Ok((BigEndian::read_u32(key), message))
+    }
+}
+
+impl<'a> Iterator for SpoolIter<'a> {
+    type Item = Result<(u32, Vec<u8>), SpoolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, value) = match self.inner.next()? {
+                Ok(x) => x,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if key.len() == MESSAGE_ID_SIZE {
+                return Some(self.decode(&key, &value))
+            }
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for SpoolIter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, value) = match self.inner.next_back()? {
+                Ok(x) => x,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if key.len() == MESSAGE_ID_SIZE {
+                return Some(self.decode(&key, &value))
+            }
+        }
+    }
+}
+
+/// SpoolSet is essentially a persistent set of spool identities.
+#[derive(Clone)]
+pub struct SpoolSet {
+    db: Db,
+    meta: Arc<Tree>,
+    health: Arc<Tree>,
+    secrets: Arc<Tree>,
+    audit: Arc<Tree>,
+    tombstones: Arc<Tree>,
+    watermarks: Arc<Tree>,
+    format: Arc<Tree>,
+    journal: Arc<Tree>,
+    replication: Arc<Tree>,
+    policies: Arc<Tree>,
+    activity: Arc<Tree>,
+    keyring: Arc<RwLock<Option<Arc<Keyring>>>>,
+    filter: Arc<RwLock<SpoolFilter>>,
+}
+
+impl SpoolSet {
+    pub fn new<P: AsRef<Path>>(path: &P) -> Result<SpoolSet, SpoolSetError> {
+        let mut spool_set = SpoolSet::open_unchecked(path)?;
+        spool_set.migrate()?;
+        spool_set.ensure_consistency()?;
+        Ok(spool_set)
+    }
+
+    /// Opens the spool set as it is on disk, without the repairs and
+    /// upgrades `new` makes, so that it can be checked.
+    pub fn open_unchecked<P: AsRef<Path>>(path: &P) -> Result<SpoolSet, SpoolSetError> {
+        let cache_cfg_builder = sled::ConfigBuilder::default()
+            .path(path)
+            .cache_capacity(SPOOL_SET_SIZE * SPOOL_ID_SIZE)
+            .use_compression(false)
+            .flush_every_ms(Some(SPOOL_SET_FLUSH_FREQUENCY))
+            .snapshot_after_ops(100);
+        let cache_cfg = cache_cfg_builder.build();
+        let db = Db::start(cache_cfg)?;
+        let meta = db.open_tree(META_TREE_ID.to_vec())?;
+        let health = db.open_tree(HEALTH_TREE_ID.to_vec())?;
+        let secrets = db.open_tree(SECRET_TREE_ID.to_vec())?;
+        let audit = db.open_tree(AUDIT_TREE_ID.to_vec())?;
+        let tombstones = db.open_tree(TOMBSTONE_TREE_ID.to_vec())?;
+        let watermarks = db.open_tree(WATERMARK_TREE_ID.to_vec())?;
+        let format = db.open_tree(FORMAT_TREE_ID.to_vec())?;
+        let journal = db.open_tree(JOURNAL_TREE_ID.to_vec())?;
+        let replication = db.open_tree(REPLICATION_TREE_ID.to_vec())?;
+        let policies = db.open_tree(POLICY_TREE_ID.to_vec())?;
+        let activity = db.open_tree(ACTIVITY_TREE_ID.to_vec())?;
+        let spool_set = SpoolSet{
+            db: db,
+            meta: meta,
+            health: health,
+            secrets: secrets,
+            audit: audit,
+            tombstones: tombstones,
+            watermarks: watermarks,
+            format: format,
+            journal: journal,
+            replication: replication,
+            policies: policies,
+            activity: activity,
+            keyring: Arc::new(RwLock::new(None)),
+            filter: Arc::new(RwLock::new(SpoolFilter::new(0))),
+        };
+        spool_set.rebuild_filter(&mut write_lock(&spool_set.filter))?;
+        Ok(spool_set)
+    }
+
+    /// Refills the spool ID filter from the spool set, sizing it for
+    /// twice the spools there are.
+    fn rebuild_filter(&self, filter: &mut SpoolFilter) -> Result<(), SpoolSetError> {
+        let mut spool_ids = vec![];
+        for key_result in self.db.iter().keys() {
+            let key = key_result?;
+            if key.len() == SPOOL_ID_SIZE {
+                spool_ids.push(*array_ref![key, 0, SPOOL_ID_SIZE]);
+            }
+        }
+        *filter = SpoolFilter::new(spool_ids.len() * 2);
+        for spool_id in &spool_ids {
+            filter.insert(spool_id);
+        }
+        Ok(())
+    }
+
+    fn ensure_consistency(&mut self) -> Result<(), SpoolSetError> {
+        for key_result in self.db.iter().keys() {
+            let key = key_result?;
+            if !self.meta.contains_key(key.clone())? {
+                self.db.del(key)?;
+            }
+        }
+        for key_result in self.meta.iter().keys() {
+            let key = key_result?;
+            if !self.db.contains_key(key.clone())? {
+                self.meta.del(key)?;
+            }
+        }
+        for key_result in self.secrets.iter().keys() {
+            let key = key_result?;
+            if !self.db.contains_key(key.clone())? {
+                self.secrets.del(key)?;
+            }
+        }
+        for key_result in self.tombstones.iter().keys() {
+            let key = key_result?;
+            if !self.db.contains_key(key.clone())? {
+                self.tombstones.del(key)?;
+            }
+        }
+        for key_result in self.policies.iter().keys() {
+            let key = key_result?;
+            if !self.db.contains_key(key.clone())? {
+                self.policies.del(key)?;
+            }
+        }
+        for key_result in self.activity.iter().keys() {
+            let key = key_result?;
+            if key.len() != SPOOL_ID_SIZE + 1 || !self.db.contains_key(key[..SPOOL_ID_SIZE].to_vec())? {
+                self.activity.del(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the spool set's format version.
+    pub fn format_version(&self) -> Result<u8, SpoolSetError> {
+        match self.format.get(FORMAT_KEY)? {
+            Some(ref x) if x.len() == 1 => Ok(x[0]),
+            Some(_) => Err(SpoolSetError::CorruptFormatVersion),
+            None => Ok(0),
+        }
+    }
+
+    /// Upgrades the spool set to SPOOL_SET_FORMAT_VERSION like
+    /// `Spool::migrate`.
+    fn migrate(&mut self) -> Result<(), SpoolSetError> {
+        let mut version = self.format_version()?;
+        if version > SPOOL_SET_FORMAT_VERSION {
+            return Err(SpoolSetError::UnsupportedFormat(version))
+        }
+        while version < SPOOL_SET_FORMAT_VERSION {
+            SPOOL_SET_MIGRATIONS[version as usize](self)?;
+            version += 1;
+            self.format.set(FORMAT_KEY, vec![version])?;
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Checks the spool set without changing it, see `fsck`.
+    pub fn check(&self) -> Result<Vec<Problem>, SpoolSetError> {
+        let mut problems = vec![];
+        match self.format_version() {
+            Ok(version) if version > SPOOL_SET_FORMAT_VERSION => {
+                problems.push(Problem::UnsupportedFormat(version));
+                return Ok(problems)
+            },
+            Ok(version) if version < SPOOL_SET_FORMAT_VERSION => problems.push(Problem::OutdatedFormat(version)),
+            Ok(_) => {},
+            Err(SpoolSetError::CorruptFormatVersion) => {
+                problems.push(Problem::CorruptFormatVersion);
+                return Ok(problems)
+            },
+            Err(e) => return Err(e),
+        }
+        let mut stale = 0;
+        for key in self.db.iter().keys() {
+            if !self.meta.contains_key(key?)? {
+                stale += 1;
+            }
+        }
+        for tree in &[&self.meta, &self.secrets, &self.tombstones, &self.policies] {
+            for key in tree.iter().keys() {
+                if !self.db.contains_key(key?)? {
+                    stale += 1;
                 }
             }
         }
-        Ok(MultiSpool {
-            map: map,
-            spool_set: spool_set,
-            base_dir: base_dir.clone(),
+        for key in self.activity.iter().keys() {
+            let key = key?;
+            if key.len() != SPOOL_ID_SIZE + 1 || !self.db.contains_key(key[..SPOOL_ID_SIZE].to_vec())? {
+                stale += 1;
+            }
+        }
+        if stale > 0 {
+            problems.push(Problem::StaleEntries(stale));
+        }
+        Ok(problems)
+    }
+
+    /// Repairs what `check` finds, as opening the spool set does.
+    pub fn repair(&mut self) -> Result<(), SpoolSetError> {
+        self.migrate()?;
+        self.ensure_consistency()?;
+        self.flush()
+    }
+
+    /// Checks that a spool's owner key can be read, without
+    /// re-encrypting it.
+    pub fn check_owner_key(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<(), SpoolSetError> {
+        match self.meta.get(spool_id.to_vec())? {
+            Some(value) => self.open_public_key(spool_id, &value).map(|_| ()),
+            None => Err(SpoolSetError::NoSuchSpoolId),
+        }
+    }
+
+    /// Replaces the owner key of a spool. The spool's key stays
+    /// derived from the first owner's, so its messages can still be
+    /// read.
+    pub fn set_public_key(&self, spool_id: [u8; SPOOL_ID_SIZE], public_key: &PublicKey) -> Result<(), SpoolSetError> {
+        let _span = trace::span("sled_spool_set_put");
+        if self.meta.get(spool_id.to_vec())?.is_none() {
+            return Err(SpoolSetError::NoSuchSpoolId)
+        }
+        if let (Some(keyring), Some(sealed)) = (self.keyring(), self.secrets.get(spool_id.to_vec())?) {
+            let mut aad = spool_id.to_vec();
+            aad.extend_from_slice(SECRET_AAD);
+            let mut secret = keyring.open(&sealed, &aad)?;
+            if secret.len() == KEY_SIZE {
+                secret.extend_from_slice(self.get_public_key(spool_id)?.as_bytes());
+                self.secrets.set(spool_id.to_vec(), keyring.seal(&secret, &aad)?)?;
+            }
+        }
+        self.meta.set(spool_id.to_vec(), self.seal_public_key(spool_id, public_key)?)?;
+        Ok(())
+    }
+
+    pub fn put(&self, spool_id: [u8; SPOOL_ID_SIZE], public_key: PublicKey) -> Result<(), SpoolSetError> {
+        let _span = trace::span("sled_spool_set_put");
+        self.db.set(spool_id.to_vec(), vec![])?;
+        self.meta.set(spool_id.to_vec(), self.seal_public_key(spool_id, &public_key)?)?;
+        let mut filter = write_lock(&self.filter);
+        filter.insert(&spool_id);
+        if filter.is_full() {
+            self.rebuild_filter(&mut filter)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the tree the audit log is kept in.
+    pub fn audit_tree(&self) -> Arc<Tree> {
+        self.audit.clone()
+    }
+
+    /// Sets the master keys owner public keys are encrypted with, for
+    /// this spool set and all its clones.
+    pub fn set_keyring(&self, keyring: Option<Arc<Keyring>>) {
+        *write_lock(&self.keyring) = keyring;
+    }
+
+    fn keyring(&self) -> Option<Arc<Keyring>> {
+        read_lock(&self.keyring).clone()
+    }
+
+    fn seal_public_key(&self, spool_id: [u8; SPOOL_ID_SIZE], public_key: &PublicKey) -> Result<Vec<u8>, SpoolSetError> {
+        match self.keyring() {
+            Some(ref keyring) => Ok(keyring.seal(public_key.as_bytes(), &spool_id)?),
+            None => Ok(public_key.to_bytes().to_vec()),
+        }
+    }
+
+    /// Decodes a stored public key, which is sealed unless it is
+    /// exactly PUBLIC_KEY_LENGTH bytes. Also returns true if it is
+    /// not encrypted with the current master key.
+    fn open_public_key(&self, spool_id: [u8; SPOOL_ID_SIZE], value: &[u8]) -> Result<(PublicKey, bool), SpoolSetError> {
+        if value.len() == PUBLIC_KEY_LENGTH {
+            return Ok((PublicKey::from_bytes(value)?, self.keyring().is_some()))
+        }
+        match self.keyring() {
+            Some(ref keyring) => {
+                let public_key = PublicKey::from_bytes(&keyring.open(value, &spool_id)?)?;
+                Ok((public_key, !keyring.is_current(value)))
+            },
+            None => Err(SpoolSetError::EncryptionError(EncryptionError::NoKeys)),
+        }
+    }
+
+    /// Returns the key of a spool, generating the spool's secret if
+    /// it has none yet, or None if encryption is disabled.
+    pub fn spool_key(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<Option<SpoolKey>, SpoolSetError> {
+        self.load_spool_key(spool_id, true)
+    }
+
+    /// Returns the key of a spool, or None if it has no secret, without
+    /// writing anything.
+    pub fn existing_spool_key(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<Option<SpoolKey>, SpoolSetError> {
+        self.load_spool_key(spool_id, false)
+    }
+
+    /// Returns the key of a spool. Unless `may_write` is set missing
+    /// secrets aren't generated and nothing is re-encrypted.
+    fn load_spool_key(&self, spool_id: [u8; SPOOL_ID_SIZE], may_write: bool) -> Result<Option<SpoolKey>, SpoolSetError> {
+        let keyring = match self.keyring() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let mut aad = spool_id.to_vec();
+        aad.extend_from_slice(SECRET_AAD);
+        let secret = match self.secrets.get(spool_id.to_vec())? {
+            Some(sealed) => {
+                let secret = keyring.open(&sealed, &aad)?;
+                if may_write && !keyring.is_current(&sealed) {
+                    self.secrets.set(spool_id.to_vec(), keyring.seal(&secret, &aad)?)?;
+                }
+                secret
+            },
+            None if may_write => {
+                let secret = SpoolKey::generate_secret();
+                self.secrets.set(spool_id.to_vec(), keyring.seal(&secret, &aad)?)?;
+                secret.to_vec()
+            },
+            None => return Ok(None),
+        };
+        if secret.len() > KEY_SIZE {
+            let public_key = PublicKey::from_bytes(&secret[KEY_SIZE..])?;
+            return Ok(Some(SpoolKey::derive(&secret[..KEY_SIZE], &spool_id, public_key.as_bytes())?))
+        }
+        let public_key = match self.meta.get(spool_id.to_vec())? {
+            Some(ref value) if !may_write => self.open_public_key(spool_id, value)?.0,
+            _ => self.get_public_key(spool_id)?,
+        };
+        Ok(Some(SpoolKey::derive(&secret, &spool_id, public_key.as_bytes())?))
+    }
+
+    /// Re-encrypts every owner public key and spool secret not
+    /// encrypted with the current master key, returning the number
+    /// rewritten.
+    pub fn reencrypt(&self) -> Result<u64, SpoolSetError> {
+        let mut count = 0;
+        for entry in self.meta.iter() {
+            let (key, value) = entry?;
+            let spool_id = *array_ref![key, 0, SPOOL_ID_SIZE];
+            let (public_key, stale) = self.open_public_key(spool_id, &value)?;
+            if stale {
+                self.meta.set(key, self.seal_public_key(spool_id, &public_key)?)?;
+                count += 1;
+            }
+        }
+        if let Some(keyring) = self.keyring() {
+            for entry in self.secrets.iter() {
+                let (key, sealed) = entry?;
+                if !keyring.is_current(&sealed) {
+                    let mut aad = key.to_vec();
+                    aad.extend_from_slice(SECRET_AAD);
+                    let secret = keyring.open(&sealed, &aad)?;
+                    self.secrets.set(key, keyring.seal(&secret, &aad)?)?;
+                    count += 1;
+                }
+            }
+        }
+        for entry in self.policies.iter() {
+            let (key, value) = entry?;
+            let spool_id = *array_ref![key, 0, SPOOL_ID_SIZE];
+            let (policy, stale) = self.open_policy(spool_id, &value)?;
+            if stale {
+                self.policies.set(key, self.seal_policy(spool_id, &policy)?)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Returns true if the spool set holds the spool. Spools it
+    /// doesn't hold are mostly told apart by the spool ID filter
+    /// without a database lookup.
+    pub fn has(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<bool, SpoolSetError> {
+        if !read_lock(&self.filter).may_contain(&spool_id) {
+            return Ok(false)
+        }
+        Ok(self.db.contains_key(spool_id.to_vec())?)
+    }
+
+    /// Deletes a spool's identity, owner key and secret. Without its
+    /// secret an encrypted spool's messages can't be decrypted, even
+    /// if its database is recovered.
+    pub fn delete(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<(), SpoolSetError> {
+        for activity in &[Activity::Created, Activity::Appended, Activity::Read] {
+            self.activity.del(activity_key(spool_id, *activity))?;
+        }
+        self.tombstones.del(spool_id.to_vec())?;
+        self.watermarks.del(spool_id.to_vec())?;
+        self.policies.del(spool_id.to_vec())?;
+        self.secrets.del(spool_id.to_vec())?;
+        self.db.del(spool_id.to_vec())?;
+        self.meta.del(spool_id.to_vec())?;
+        Ok(())
+    }
+
+    /// Marks a spool as purged at `purged_at`, in seconds since the
+    /// unix epoch.
+    pub fn tombstone(&self, spool_id: [u8; SPOOL_ID_SIZE], purged_at: u64) -> Result<(), SpoolSetError> {
+        let mut value = vec![0u8; 8];
+        BigEndian::write_u64(&mut value, purged_at);
+        self.tombstones.set(spool_id.to_vec(), value)?;
+        self.tombstones.flush()?;
+        Ok(())
+    }
+
+    /// Unmarks a purged spool.
+    pub fn undelete(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<(), SpoolSetError> {
+        self.tombstones.del(spool_id.to_vec())?;
+        self.tombstones.flush()?;
+        Ok(())
+    }
+
+    /// Returns every purged spool and when it was purged.
+    pub fn tombstones(&self) -> Result<Vec<([u8; SPOOL_ID_SIZE], u64)>, SpoolSetError> {
+        let mut tombstones = vec![];
+        for item in self.tombstones.iter() {
+            let (key, value) = item?;
+            if key.len() == SPOOL_ID_SIZE && value.len() == 8 {
+                tombstones.push((*array_ref![key, 0, SPOOL_ID_SIZE], BigEndian::read_u64(&value)));
+            }
+        }
+        Ok(tombstones)
+    }
+
+    /// Records that a spool was created, appended to or read at `at`,
+    /// in seconds since the unix epoch. Times are kept to the second,
+    /// so repeated operations within a second write nothing.
+    pub fn touch(&self, spool_id: [u8; SPOOL_ID_SIZE], activity: Activity, at: u64) -> Result<(), SpoolSetError> {
+        let key = activity_key(spool_id, activity);
+        let mut value = vec![0u8; 8];
+        BigEndian::write_u64(&mut value, at);
+        if self.activity.get(&key)?.as_ref().map(|x| &x[..]) != Some(&value[..]) {
+            self.activity.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Returns when a spool was created and last appended to and read.
+    pub fn activity(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<SpoolActivity, SpoolSetError> {
+        let get = |activity| -> Result<Option<u64>, SpoolSetError> {
+            Ok(self.activity.get(activity_key(spool_id, activity))?
+               .filter(|x| x.len() == 8)
+               .map(|x| BigEndian::read_u64(&x)))
+        };
+        Ok(SpoolActivity {
+            created_at: get(Activity::Created)?,
+            last_append: get(Activity::Appended)?,
+            last_read: get(Activity::Read)?,
         })
     }
 
-    fn get_mut_spool(&mut self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<&mut Spool, MultiSpoolError> {
-        let spool: &mut Spool = match self.map.get_mut(&spool_id) {
-            Some(x) => x,
-            None => {
-                return Err(MultiSpoolError::NoSuchSpool);
-            },
+    /// Keeps the policy of a spool created with options, sealed like
+    /// its owner key, or forgets it if it is the default.
+    pub fn set_policy(&self, spool_id: [u8; SPOOL_ID_SIZE], policy: &SpoolPolicy) -> Result<(), SpoolSetError> {
+        if policy.is_default() {
+            self.policies.del(spool_id.to_vec())?;
+        } else {
+            self.policies.set(spool_id.to_vec(), self.seal_policy(spool_id, policy)?)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the policy of every spool created with options.
+    pub fn policies(&self) -> Result<Vec<([u8; SPOOL_ID_SIZE], SpoolPolicy)>, SpoolSetError> {
+        let mut policies = vec![];
+        for item in self.policies.iter() {
+            let (key, value) = item?;
+            if key.len() != SPOOL_ID_SIZE {
+                continue
+            }
+            let spool_id = *array_ref![key, 0, SPOOL_ID_SIZE];
+            policies.push((spool_id, self.open_policy(spool_id, &value)?.0));
+        }
+        Ok(policies)
+    }
+
+    fn seal_policy(&self, spool_id: [u8; SPOOL_ID_SIZE], policy: &SpoolPolicy) -> Result<Vec<u8>, SpoolSetError> {
+        let raw = policy.to_bytes()?;
+        match self.keyring() {
+            Some(ref keyring) => {
+                let mut value = vec![POLICY_SEALED];
+                value.extend(keyring.seal(&raw, &policy_aad(spool_id))?);
+                Ok(value)
+            },
+            None => {
+                let mut value = vec![POLICY_PLAIN];
+                value.extend(raw);
+                Ok(value)
+            },
+        }
+    }
+
+    /// Decodes a stored policy, also returning true if it is not
+    /// sealed with the current master key.
+    fn open_policy(&self, spool_id: [u8; SPOOL_ID_SIZE], value: &[u8]) -> Result<(SpoolPolicy, bool), SpoolSetError> {
+        match (value.first(), self.keyring()) {
+            (Some(&POLICY_PLAIN), keyring) => Ok((SpoolPolicy::from_bytes(&value[1..])?, keyring.is_some())),
+            (Some(&POLICY_SEALED), Some(ref keyring)) => {
+                let policy = SpoolPolicy::from_bytes(&keyring.open(&value[1..], &policy_aad(spool_id))?)?;
+                Ok((policy, !keyring.is_current(&value[1..])))
+            },
+            (Some(&POLICY_SEALED), None) => Err(SpoolSetError::EncryptionError(EncryptionError::NoKeys)),
+            _ => Err(SpoolSetError::CorruptPolicy),
+        }
+    }
+
+    /// Durably records that `intent` is about to be carried out on a
+    /// spool.
+    pub fn begin(&self, spool_id: [u8; SPOOL_ID_SIZE], intent: Intent) -> Result<(), SpoolSetError> {
+        self.journal.set(spool_id.to_vec(), vec![intent.to_byte()])?;
+        self.journal.flush()?;
+        Ok(())
+    }
+
+    /// Records that the operation begun on a spool is done, once the
+    /// spool set has been flushed.
+    pub fn commit(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<(), SpoolSetError> {
+        self.db.flush()?;
+        self.journal.del(spool_id.to_vec())?;
+        self.journal.flush()?;
+        Ok(())
+    }
+
+    /// Returns the operations begun but not committed. Journal entries
+    /// this build doesn't know are skipped.
+    pub fn pending(&self) -> Result<Vec<([u8; SPOOL_ID_SIZE], Intent)>, SpoolSetError> {
+        let mut pending = vec![];
+        for item in self.journal.iter() {
+            let (key, value) = item?;
+            if key.len() != SPOOL_ID_SIZE || value.len() != 1 {
+                continue
+            }
+            if let Some(intent) = Intent::from_byte(value[0]) {
+                pending.push((*array_ref![key, 0, SPOOL_ID_SIZE], intent));
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Returns the name of the last backup, if any.
+    pub fn last_backup(&self) -> Result<Option<String>, SpoolSetError> {
+        match self.watermarks.get(LAST_BACKUP_KEY)? {
+            Some(name) => Ok(Some(String::from_utf8_lossy(&name).into_owned())),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the number of messages a spool had as of the last
+    /// backup, zero if it wasn't in it.
+    pub fn watermark(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<u64, SpoolSetError> {
+        match self.watermarks.get(spool_id.to_vec())? {
+            Some(ref value) if value.len() == 8 => Ok(BigEndian::read_u64(value)),
+            _ => Ok(0),
+        }
+    }
+
+    /// Replaces every watermark with the message counts as of the
+    /// backup called `name`.
+    pub fn set_watermarks(&self, name: &str, watermarks: &[([u8; SPOOL_ID_SIZE], u64)]) -> Result<(), SpoolSetError> {
+        self.watermarks.clear()?;
+        let mut value = vec![0u8; 8];
+        for &(spool_id, message_count) in watermarks {
+            BigEndian::write_u64(&mut value, message_count);
+            self.watermarks.set(spool_id.to_vec(), value.clone())?;
+        }
+        self.watermarks.set(LAST_BACKUP_KEY.to_vec(), name.as_bytes().to_vec())?;
+        self.watermarks.flush()?;
+        Ok(())
+    }
+
+    pub fn keys<'a>(&'a self) -> impl 'a + DoubleEndedIterator<Item = Result<Vec<u8>, sled::Error<()>>> {
+        self.db.iter().keys()
+    }
+
+    pub fn get_public_key(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<PublicKey, SpoolSetError> {
+        let _span = trace::span("sled_spool_set_get");
+        if let Some(value) = self.meta.get(spool_id.to_vec())? {
+            let (public_key, stale) = self.open_public_key(spool_id, &value)?;
+            if stale {
+                self.meta.set(spool_id.to_vec(), self.seal_public_key(spool_id, &public_key)?)?;
+            }
+            return Ok(public_key);
+        }
+        Err(SpoolSetError::NoSuchSpoolId)
+    }
+
+    /// Returns the replication role kept with `set_role`, if any.
+    pub fn role(&self) -> Result<Option<Role>, SpoolSetError> {
+        match self.replication.get(ROLE_KEY)? {
+            Some(ref value) if value.len() == 1 => Ok(Role::from_byte(value[0])),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the epoch of the latest primary seen, zero if none.
+    pub fn epoch(&self) -> Result<u64, SpoolSetError> {
+        match self.replication.get(EPOCH_KEY)? {
+            Some(ref value) if value.len() == 8 => Ok(BigEndian::read_u64(value)),
+            _ => Ok(0),
+        }
+    }
+
+    /// Keeps the replication role and epoch, flushing them to disk
+    /// before returning.
+    pub fn set_role(&self, role: Role, epoch: u64) -> Result<(), SpoolSetError> {
+        let mut value = vec![0u8; 8];
+        BigEndian::write_u64(&mut value, epoch);
+        self.replication.set(EPOCH_KEY.to_vec(), value)?;
+        self.replication.set(ROLE_KEY.to_vec(), vec![role.to_byte()])?;
+        self.replication.flush()?;
+        Ok(())
+    }
+
+    /// Flushes the spool set's writeback cache to disk.
+    pub fn flush(&self) -> Result<(), SpoolSetError> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Checks that the spool set database is open and writable by
+    /// writing and then deleting a probe key in its own tree.
+    pub fn check_writable(&self) -> Result<(), SpoolSetError> {
+        self.health.set(HEALTH_PROBE_KEY.to_vec(), vec![])?;
+        self.health.del(HEALTH_PROBE_KEY.to_vec())?;
+        Ok(())
+    }
+}
+
+/// Returns the key of a spool's activity in the spool set.
+fn activity_key(spool_id: [u8; SPOOL_ID_SIZE], activity: Activity) -> Vec<u8> {
+    let mut key = spool_id.to_vec();
+    key.push(activity.to_byte());
+    key
+}
+
+/// SpoolInfo describes a spool for operators.
+#[derive(Clone, Debug)]
+pub struct SpoolInfo {
+    pub spool_id: [u8; SPOOL_ID_SIZE],
+    pub public_key: PublicKey,
+    pub message_count: u64,
+    pub size_bytes: u64,
+    /// The index of the shard directory the spool's database is in.
+    pub shard: usize,
+    /// The time since the spool's database was created, if the
+    /// filesystem can tell us.
+    pub age: Option<Duration>,
+    /// The unix time the spool was purged at, if it is waiting to be
+    /// deleted.
+    pub purged_at: Option<u64>,
+    /// The policy of a spool created with options, see `options`.
+    pub policy: Option<SpoolPolicy>,
+    /// When the spool was created and last appended to and read.
+    pub activity: SpoolActivity,
+}
+
+/// FoundMessage is a message read by hash: its message ID, the
+/// message and the unix time it was appended at, if known.
+pub type FoundMessage = ([u8; MESSAGE_ID_SIZE], Vec<u8>, Option<u64>);
+
+/// SpoolStats are what a spool holds and how much of its capacity it
+/// uses, as told to its owner by SPOOL_STATUS and to operators.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+#[allow(non_snake_case)]
+pub struct SpoolStats {
+    /// The number of messages kept.
+    pub MessageCount: u64,
+    /// The bytes the spool's database takes on disk.
+    pub SizeBytes: u64,
+    /// The ID the next message appended gets.
+    pub Head: u64,
+    /// The ID of the oldest message kept.
+    pub FirstMessage: u64,
+    pub CreatedAt: Option<u64>,
+    pub LastAppend: Option<u64>,
+    pub LastRead: Option<u64>,
+    /// The most messages the spool keeps, unlimited when zero.
+    pub Capacity: u32,
+    /// The unix time the spool is deleted at, if it has a TTL.
+    pub ExpiresAt: Option<u64>,
+}
+
+/// ShardStats summarize the spools in one shard directory.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ShardStats {
+    pub dir: String,
+    pub spool_count: usize,
+    pub message_count: u64,
+    pub size_bytes: u64,
+}
+
+/// MultiSpoolStats summarize every spool, purged ones included, and
+/// the spool requests handled since startup.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MultiSpoolStats {
+    pub spool_count: usize,
+    /// The number of messages appended, as `SpoolInfo::message_count`.
+    pub message_count: u64,
+    pub size_bytes: u64,
+    pub shards: Vec<ShardStats>,
+    /// The number of requests by command name, whatever their outcome.
+    pub commands: Vec<(&'static str, u64)>,
+}
+
+/// Diagnostics are a snapshot of the service's internal state, for
+/// debugging, see `MultiSpool::diagnostics`.
+#[derive(Clone, Debug, Default)]
+pub struct Diagnostics {
+    /// The number of spools whose database is open, purged ones
+    /// included.
+    pub open_spools: usize,
+    /// The number of idempotency keys remembered, see `dedup`.
+    pub dedup_keys: usize,
+    /// The number of spools with signature failures counted, see
+    /// `throttle`.
+    pub throttled_spools: usize,
+    /// The number of purged spools waiting to be deleted.
+    pub tombstones: usize,
+    /// The number of spools with a policy, see `options`.
+    pub policies: usize,
+    /// The number of owner keys with a spool.
+    pub owners: usize,
+    /// The number of appends waiting to be made durable, over all
+    /// spools.
+    pub pending_flushes: u64,
+    /// What each spool which isn't purged holds.
+    pub spools: Vec<([u8; SPOOL_ID_SIZE], SpoolStats)>,
+}
+
+/// A spool shared by the threads using it. Reads hold its read lock
+/// and appends its write lock, so that only operations on the same
+/// spool wait for each other. It is None once the spool is closed.
+type SharedSpool = Arc<RwLock<Option<Spool>>>;
+
+/// The number of shards of a `SpoolMap`.
+const SPOOL_MAP_SHARDS: usize = 16;
+
+/// SpoolMap maps spool IDs to shared spools. It is split into shards
+/// with a lock each, picked by the first byte of the spool ID, which
+/// is random, so that creating or removing a spool only holds up
+/// lookups in its own shard.
+struct SpoolMap {
+    shards: Vec<RwLock<HashMap<[u8; SPOOL_ID_SIZE], SharedSpool>>>,
+}
+
+impl SpoolMap {
+    fn new() -> SpoolMap {
+        SpoolMap {
+            shards: (0..SPOOL_MAP_SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn shard(&self, spool_id: &[u8; SPOOL_ID_SIZE]) -> &RwLock<HashMap<[u8; SPOOL_ID_SIZE], SharedSpool>> {
+        &self.shards[spool_id[0] as usize % SPOOL_MAP_SHARDS]
+    }
+
+    fn get(&self, spool_id: &[u8; SPOOL_ID_SIZE]) -> Option<SharedSpool> {
+        read_lock(self.shard(spool_id)).get(spool_id).cloned()
+    }
+
+    fn contains(&self, spool_id: &[u8; SPOOL_ID_SIZE]) -> bool {
+        read_lock(self.shard(spool_id)).contains_key(spool_id)
+    }
+
+    fn insert(&self, spool_id: [u8; SPOOL_ID_SIZE], spool: Spool) {
+        write_lock(self.shard(&spool_id)).insert(spool_id, Arc::new(RwLock::new(Some(spool))));
+    }
+
+    fn remove(&self, spool_id: &[u8; SPOOL_ID_SIZE]) -> Option<SharedSpool> {
+        write_lock(self.shard(spool_id)).remove(spool_id)
+    }
+
+    /// Returns every spool along with its ID.
+    fn spools(&self) -> Vec<([u8; SPOOL_ID_SIZE], SharedSpool)> {
+        let mut spools = vec![];
+        for shard in &self.shards {
+            spools.extend(read_lock(shard).iter().map(|(spool_id, spool)| (*spool_id, spool.clone())));
+        }
+        spools
+    }
+
+    fn ids(&self) -> Vec<[u8; SPOOL_ID_SIZE]> {
+        let mut ids = vec![];
+        for shard in &self.shards {
+            ids.extend(read_lock(shard).keys().cloned());
+        }
+        ids
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|x| read_lock(x).len()).sum()
+    }
+
+    fn clear(&self) {
+        for shard in &self.shards {
+            write_lock(shard).clear();
+        }
+    }
+}
+
+/// The runtime settings of a MultiSpool, shared by all its clones.
+#[derive(Clone)]
+struct Settings {
+    max_message_size: usize,
+    codec: EntryCodec,
+    identity: Option<Arc<Keypair>>,
+    purge_grace_period: Duration,
+    inactive_period: Option<Duration>,
+    backup_dir: PathBuf,
+    durability: Durability,
+    replication: Option<Arc<ReplicationLog>>,
+    role: Role,
+    epoch: u64,
+}
+
+/// SpoolRng is a cryptographically secure RNG spool IDs can be drawn
+/// from, see `MultiSpool::set_rng`.
+pub trait SpoolRng: RngCore + CryptoRng + Send {}
+
+impl<T> SpoolRng for T where T: RngCore + CryptoRng + Send {}
+
+/// MultiSpool allows for accessing multiple spools.
+///
+/// A MultiSpool is cheap to clone and its clones share all spools and
+/// settings, so it can be handed to many threads. Methods taking
+/// `&self` may be called from all of them at once. The spool map has
+/// locks of its own and so does every spool, see `SpoolMap` and
+/// `SharedSpool`. No spool is locked while a map lock is held, nor
+/// the map locked while a spool is: a spool being removed is emptied
+/// under its own lock, which is released before it leaves the map.
+/// `restore` replaces every spool, so it waits for the threads which
+/// `enter`ed to leave and keeps new ones out meanwhile. Methods taking
+/// `&mut self` are for offline use, and callers must keep other
+/// clones from using the spools meanwhile.
+#[derive(Clone)]
+pub struct MultiSpool {
+    map: Arc<SpoolMap>,
+    spool_set: SpoolSet,
+    shards: Shards,
+    settings: Arc<RwLock<Settings>>,
+    dedup: Arc<Mutex<DedupCache>>,
+    backoff: Arc<Mutex<SignatureBackoff>>,
+    audit: AuditLog,
+    tombstones: Arc<RwLock<HashMap<[u8; SPOOL_ID_SIZE], u64>>>,
+    policies: Arc<RwLock<HashMap<[u8; SPOOL_ID_SIZE], SpoolPolicy>>>,
+    /// The spools of each owner key, for `create_or_get`.
+    owners: Arc<RwLock<OwnerMap>>,
+    /// Held by `create_or_get`, so that retries of a creation racing
+    /// each other make a single spool.
+    create_lock: Arc<Mutex<()>>,
+    /// Held for writing by `restore` and for reading by the threads
+    /// handling requests, see `enter`.
+    exclusive: Arc<RwLock<()>>,
+    disk: Arc<DiskMonitor>,
+    rng: Arc<Mutex<Option<Box<dyn SpoolRng>>>>,
+}
+
+/// The spools of each owner key.
+type OwnerMap = HashMap<[u8; PUBLIC_KEY_LENGTH], Vec<[u8; SPOOL_ID_SIZE]>>;
+
+/// Returns the path of a spool's database.
+pub fn spool_path(base_dir: &String, spool_id: [u8; SPOOL_ID_SIZE]) -> PathBuf {
+    let path = Path::new(base_dir).join(format!("spool.{}.sled", base64::encode(&spool_id)));
+    let pathbuf: PathBuf = path.to_owned();
+    pathbuf
+}
+
+/// Returns the paths of the spool databases in `base_dir`. Spool IDs
+/// are base64 encoded in database names, so a name spans directories
+/// if its encoding holds a '/'.
+pub fn spool_databases(base_dir: &Path) -> io::Result<BTreeSet<PathBuf>> {
+    let mut paths = BTreeSet::new();
+    let mut pending = vec![String::new()];
+    while let Some(prefix) = pending.pop() {
+        let dir = if prefix.is_empty() { base_dir.to_path_buf() } else { base_dir.join(&prefix) };
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(x) => format!("{}{}", prefix, x),
+                Err(_) => continue,
+            };
+            if !name.starts_with("spool.") {
+                continue
+            }
+            if name.ends_with(".sled") {
+                paths.insert(base_dir.join(&name));
+            } else if entry.file_type()?.is_dir() {
+                pending.push(format!("{}/", name));
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Removes the directory `path` is in, if it is empty and isn't
+/// `base_dir`, as it was only there for a spool database name holding
+/// a '/'.
+pub fn remove_empty_parent(base_dir: &Path, path: &Path) {
+    if let Some(parent) = path.parent() {
+        if parent != base_dir {
+            let _ = fs::remove_dir(parent);
+        }
+    }
+}
+
+/// Returns the path of the spool set's database.
+pub fn spool_set_path(base_dir: &String) -> PathBuf {
+    Path::new(base_dir).join("spool_set.sled")
+}
+
+/// Decodes the appender list of a replicated change.
+fn replicated_appenders(appenders: &Option<ByteBuf>) -> Result<Option<AppenderList>, ReplicationError> {
+    match appenders {
+        Some(x) => AppenderList::from_bytes(x).map(Some).map_err(|_| ReplicationError::InvalidEvent),
+        None => Ok(None),
+    }
+}
+
+/// Returns the number of seconds since the unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0)
+}
+
+/// Returns the additional data a spool's policy is sealed with.
+fn policy_aad(spool_id: [u8; SPOOL_ID_SIZE]) -> Vec<u8> {
+    let mut aad = spool_id.to_vec();
+    aad.extend_from_slice(POLICY_AAD);
+    aad
+}
+
+/// Locks a mutex, recovering it if a panic poisoned it. A request
+/// which panicked is answered with an error on its own, so it mustn't
+/// make every later one fail too.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Locks for reading like `lock`.
+fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Locks for writing like `lock`.
+fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Verifies that the signature is the owner's signature over their
+/// own public key.
+fn verify_signature(public_key: &PublicKey, signature: &Signature) -> Result<(), MultiSpoolError> {
+    let _span = trace::span("verify_signature");
+    public_key.verify(&public_key.to_bytes(), signature)?;
+    Ok(())
+}
+
+/// Returns the number of bytes used by the file or directory at `path`.
+fn disk_usage(path: &Path) -> io::Result<u64> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len())
+    }
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        total += disk_usage(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+/// Removes the sled database at `path`, whether it is a file or a
+/// directory.
+fn remove_db(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        return fs::remove_dir_all(path)
+    }
+    remove_file(path)
+}
+
+/// Returns `path` with `suffix` appended.
+fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Replaces the database at `path` with the one at `replacement`,
+/// moving the original to `aside`, or leaves both where they were if
+/// that fails.
+fn swap_db(path: &Path, replacement: &Path, aside: &Path) -> io::Result<()> {
+    if aside.exists() {
+        remove_db(aside)?;
+    }
+    fs::rename(path, aside)?;
+    if let Err(e) = fs::rename(replacement, path) {
+        fs::rename(aside, path)?;
+        return Err(e)
+    }
+    Ok(())
+}
+
+/// Moves a spool database left in another shard by a change of the
+/// shard directories to the spool's own shard, or one a compaction
+/// interrupted by a crash had moved aside back in place.
+fn relocate_spool(shards: &Shards, spool_id: [u8; SPOOL_ID_SIZE], path: &Path) -> io::Result<()> {
+    let old_path = suffixed_path(path, ".old");
+    if old_path.exists() {
+        warn!("Spool database {} is missing, putting back the original an interrupted compaction moved aside.", path.display());
+        return fs::rename(&old_path, path)
+    }
+    let from = match shards.misplaced(spool_id) {
+        Some(x) => x,
+        None => {
+            warn!("Spool database {} is missing, starting it over empty.", path.display());
+            return Ok(())
+        },
+    };
+    info!("Moving spool database {} to its shard, {}.", from.display(), path.display());
+    shard::move_db(&from, path)?;
+    if let Some(dir) = shards.dir_of(&from) {
+        remove_empty_parent(Path::new(dir), &from);
+    }
+    Ok(())
+}
+
+impl MultiSpool {
+
+    pub fn new(base_dir: &String) -> Result<Self, MultiSpoolError> {
+        MultiSpool::with_durability(base_dir, Durability::default())
+    }
+
+    /// Opens the spools like `new`, flushing them as `durability`
+    /// says.
+    pub fn with_durability(base_dir: &String, durability: Durability) -> Result<Self, MultiSpoolError> {
+        MultiSpool::with_shards(base_dir, &[], durability)
+    }
+
+    /// Opens the spools like `with_durability`, with their databases
+    /// sharded across `base_dir` and `shard_dirs`, see `Shards`.
+    /// Databases in another shard than their spool's, as after adding
+    /// a shard directory, are moved to their spool's shard.
+    pub fn with_shards(base_dir: &String, shard_dirs: &[String], durability: Durability) -> Result<Self, MultiSpoolError> {
+        let shards = Shards::new(base_dir, shard_dirs);
+        let mut spool_set = SpoolSet::new(&spool_set_path(base_dir))?;
+        let audit = AuditLog::new(spool_set.audit_tree())?;
+        MultiSpool::recover(&shards, &mut spool_set, &audit)?;
+        let spool_set_clone = spool_set.clone();
+        let map = SpoolMap::new();
+        let mut owners = OwnerMap::new();
+        for spool_id_result in spool_set_clone.keys() {
+            let raw_spool_id = spool_id_result?;
+            let spool_id = *array_ref![raw_spool_id, 0, SPOOL_ID_SIZE];
+            let path = shards.spool_path(spool_id);
+            if !path.exists() {
+                relocate_spool(&shards, spool_id, &path)?;
+            }
+            let spool_result = Spool::with_durability(&path, &durability);
+            if spool_result.is_ok() {
+                map.insert(spool_id, spool_result.ok().unwrap());
+                if let Ok(public_key) = spool_set.get_public_key(spool_id) {
+                    owners.entry(public_key.to_bytes()).or_default().push(spool_id);
+                }
+            } else {
+                match spool_result.err().unwrap() {
+                    SpoolError::CorruptSpool => {
+                        spool_set.delete(spool_id)?;
+                        remove_file(&path)?;
+                    },
+                    e => {
+                        return Err(MultiSpoolError::SpoolError(e))
+                    }
+                }
+            }
+        }
+        let tombstones = spool_set.tombstones()?.into_iter()
+            .filter(|&(spool_id, _)| map.contains(&spool_id))
+            .collect();
+        let policies = spool_set.policies()?.into_iter()
+            .filter(|&(spool_id, _)| map.contains(&spool_id))
+            .collect();
+        let settings = Settings {
+            max_message_size: MESSAGE_SIZE,
+            codec: EntryCodec::default(),
+            identity: None,
+            purge_grace_period: Duration::from_secs(DEFAULT_PURGE_GRACE_PERIOD_SECS),
+            inactive_period: None,
+            backup_dir: backup::default_backup_dir(base_dir),
+            durability: durability,
+            replication: None,
+            role: spool_set.role()?.unwrap_or(Role::Primary),
+            epoch: spool_set.epoch()?,
+        };
+        let multi_spool = MultiSpool {
+            map: Arc::new(map),
+            spool_set: spool_set,
+            shards: shards,
+            settings: Arc::new(RwLock::new(settings)),
+            dedup: Arc::new(Mutex::new(DedupCache::new(DEFAULT_DEDUP_CACHE_SIZE))),
+            backoff: Arc::new(Mutex::new(SignatureBackoff::default())),
+            audit: audit,
+            tombstones: Arc::new(RwLock::new(tombstones)),
+            policies: Arc::new(RwLock::new(policies)),
+            owners: Arc::new(RwLock::new(owners)),
+            create_lock: Arc::new(Mutex::new(())),
+            exclusive: Arc::new(RwLock::new(())),
+            disk: Arc::new(DiskMonitor::default()),
+            rng: Arc::new(Mutex::new(None)),
+        };
+        // Orphans are only reported here: were the spool set lost,
+        // every spool database would look orphaned, so they are left
+        // for an operator to remove with `collect_orphans`.
+        let orphans = multi_spool.orphans()?;
+        if !orphans.is_empty() && multi_spool.map.len() == 0 {
+            error!("Found {} spool databases but no spools, is the spool set missing?", orphans.len());
+            return Err(MultiSpoolError::LostSpoolSet)
+        }
+        for path in &orphans {
+            warn!("Spool database {} belongs to no spool, see the gc admin command.", path.display());
+        }
+        Ok(multi_spool)
+    }
+
+    /// Finishes the spool creations and deletions a crash interrupted,
+    /// see `Intent`. Either way the spool is removed.
+    fn recover(shards: &Shards, spool_set: &mut SpoolSet, audit: &AuditLog) -> Result<(), MultiSpoolError> {
+        for (spool_id, intent) in spool_set.pending()? {
+            warn!("Finishing the interrupted {} of spool {}.", intent.as_str(), base64::encode(&spool_id));
+            if spool_set.has(spool_id)? {
+                spool_set.delete(spool_id)?;
+            }
+            for dir in shards.dirs() {
+                let path = spool_path(dir, spool_id);
+                if path.exists() {
+                    remove_db(&path)?;
+                    remove_empty_parent(Path::new(dir), &path);
+                }
+            }
+            spool_set.commit(spool_id)?;
+            if let Err(e) = audit.record("recover", Some(&spool_id), OUTCOME_OK, intent.as_str()) {
+                error!("FAILED to record recover in the audit log: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a shared spool, unless it doesn't exist or was purged
+    /// and `include_purged` is false.
+    fn shared_spool(&self, spool_id: [u8; SPOOL_ID_SIZE], include_purged: bool) -> Result<SharedSpool, MultiSpoolError> {
+        if !include_purged && read_lock(&self.tombstones).contains_key(&spool_id) {
+            return Err(MultiSpoolError::NoSuchSpool)
+        }
+        self.map.get(&spool_id).ok_or(MultiSpoolError::NoSuchSpool)
+    }
+
+    /// Calls `f` with a spool's read lock held, unless the spool
+    /// doesn't exist or was purged.
+    fn with_spool<F, R>(&self, spool_id: [u8; SPOOL_ID_SIZE], f: F) -> Result<R, MultiSpoolError>
+    where
+        F: FnOnce(&Spool) -> Result<R, MultiSpoolError>,
+    {
+        let shared = self.shared_spool(spool_id, false)?;
+        let guard = read_lock(&shared);
+        guard.as_ref().ok_or(MultiSpoolError::NoSuchSpool).and_then(f)
+    }
+
+    /// Calls `f` with a spool's read lock held, even if the spool
+    /// was purged but not yet deleted. This is meant for operator
+    /// facing methods.
+    fn with_any_spool<F, R>(&self, spool_id: [u8; SPOOL_ID_SIZE], f: F) -> Result<R, MultiSpoolError>
+    where
+        F: FnOnce(&Spool) -> Result<R, MultiSpoolError>,
+    {
+        let shared = self.shared_spool(spool_id, true)?;
+        let guard = read_lock(&shared);
+        guard.as_ref().ok_or(MultiSpoolError::NoSuchSpool).and_then(f)
+    }
+
+    /// Calls `f` with a spool's write lock held, unless the spool
+    /// doesn't exist or was purged.
+    fn with_spool_mut<F, R>(&self, spool_id: [u8; SPOOL_ID_SIZE], f: F) -> Result<R, MultiSpoolError>
+    where
+        F: FnOnce(&mut Spool) -> Result<R, MultiSpoolError>,
+    {
+        let shared = self.shared_spool(spool_id, false)?;
+        let mut guard = write_lock(&shared);
+        guard.as_mut().ok_or(MultiSpoolError::NoSuchSpool).and_then(f)
+    }
+
+    pub fn create_spool<T>(&self,
+                           public_key: PublicKey,
+                           signature: Signature,
+                           csprng: &mut T)
+                           -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError>
+    where
+        T: CryptoRng + Rng,
+    {
+        self.create_with_options(public_key, signature, &SpoolOptions::default(), csprng)
+    }
+
+    /// Creates a spool which behaves as `options` say, see `options`.
+    pub fn create_with_options<T>(&self,
+                                  public_key: PublicKey,
+                                  signature: Signature,
+                                  options: &SpoolOptions,
+                                  csprng: &mut T)
+                                  -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError>
+    where
+        T: CryptoRng + Rng,
+    {
+        let _timer = STORAGE_LATENCY.with_label_values(&["create"]).start_timer();
+        let result = self.check_primary().and_then(|_| self.new_spool(public_key, signature, options, csprng));
+        self.audit("create", result.as_ref().ok(), &result, "");
+        result
+    }
+
+    /// Creates a spool like `create_spool`, drawing its ID from the
+    /// RNG set with `set_rng`, or else from the operating system.
+    pub fn allocate_spool(&self,
+                          public_key: PublicKey,
+                          signature: Signature)
+                          -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError> {
+        self.allocate_with_options(public_key, signature, &SpoolOptions::default())
+    }
+
+    /// Creates a spool like `create_with_options`, drawing its ID like
+    /// `allocate_spool`.
+    pub fn allocate_with_options(&self,
+                                 public_key: PublicKey,
+                                 signature: Signature,
+                                 options: &SpoolOptions)
+                                 -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError> {
+        if let Some(ref mut rng) = *lock(&self.rng) {
+            return self.create_with_options(public_key, signature, options, rng)
+        }
+        self.create_with_options(public_key, signature, options, &mut OsRng::new()?)
+    }
+
+    /// Returns the ID of the spool owned by `public_key`, creating it
+    /// like `allocate_with_options` if there is none, and whether it
+    /// was created. Clients retrying a creation thus get the spool
+    /// their first attempt made. Purged spools are passed over, and if
+    /// the key owns several spools the oldest is returned, see
+    /// `owned_spool`. The options are checked first, and an existing
+    /// spool is refused with `OptionsMismatch` if they would have
+    /// given it another policy.
+    pub fn create_or_get(&self,
+                         public_key: PublicKey,
+                         signature: Signature,
+                         options: &SpoolOptions)
+                         -> Result<([u8; SPOOL_ID_SIZE], bool), MultiSpoolError> {
+        let _guard = lock(&self.create_lock);
+        self.check_primary()?;
+        verify_signature(&public_key, &signature)?;
+        options.policy(unix_time())?;
+        if let Some((spool_id, created_at)) = self.owned_spool(&public_key)? {
+            let policy = self.policy(spool_id).unwrap_or_default();
+            if options.policy(created_at.unwrap_or(0))? != policy {
+                return Err(MultiSpoolError::OptionsMismatch)
+            }
+            return Ok((spool_id, false))
+        }
+        Ok((self.allocate_with_options(public_key, signature, options)?, true))
+    }
+
+    /// Returns the oldest spool `public_key` owns which isn't purged,
+    /// with its creation time. Spools with no recorded creation time
+    /// count as oldest, and ties go to the lowest spool ID, so the
+    /// same spool is chosen across restarts.
+    fn owned_spool(&self, public_key: &PublicKey)
+                   -> Result<Option<([u8; SPOOL_ID_SIZE], Option<u64>)>, MultiSpoolError> {
+        let spool_ids = {
+            let owners = read_lock(&self.owners);
+            let tombstones = read_lock(&self.tombstones);
+            match owners.get(public_key.as_bytes()) {
+                Some(spool_ids) => spool_ids.iter()
+                    .filter(|spool_id| !tombstones.contains_key(*spool_id))
+                    .cloned()
+                    .collect::<Vec<_>>(),
+                None => return Ok(None),
+            }
+        };
+        let mut oldest = None;
+        for spool_id in spool_ids {
+            let created_at = self.spool_set.activity(spool_id)?.created_at;
+            oldest = match oldest {
+                Some((id, at)) if (at, id) <= (created_at, spool_id) => Some((id, at)),
+                _ => Some((spool_id, created_at)),
+            };
+        }
+        Ok(oldest)
+    }
+
+    fn add_owned_spool(&self, public_key: &PublicKey, spool_id: [u8; SPOOL_ID_SIZE]) {
+        write_lock(&self.owners).entry(public_key.to_bytes()).or_default().push(spool_id);
+    }
+
+    fn remove_owned_spool(&self, public_key: &PublicKey, spool_id: [u8; SPOOL_ID_SIZE]) {
+        let mut owners = write_lock(&self.owners);
+        let is_empty = match owners.get_mut(public_key.as_bytes()) {
+            Some(spool_ids) => {
+                spool_ids.retain(|x| *x != spool_id);
+                spool_ids.is_empty()
+            },
+            None => false,
+        };
+        if is_empty {
+            owners.remove(public_key.as_bytes());
+        }
+    }
+
+    /// Sets the RNG `allocate_spool` draws spool IDs from, or goes
+    /// back to the operating system's if None.
+    pub fn set_rng(&self, rng: Option<Box<dyn SpoolRng>>) {
+        *lock(&self.rng) = rng;
+    }
+
+    /// Draws spool IDs from an RNG seeded with `seed`, so that tests
+    /// can reproduce them. A service must never do this, as anyone
+    /// knowing the seed could predict its spool IDs.
+    pub fn seed_rng(&self, seed: u64) {
+        self.set_rng(Some(Box::new(StdRng::seed_from_u64(seed))));
+    }
+
+    fn new_spool<T>(&self,
+                    public_key: PublicKey,
+                    signature: Signature,
+                    options: &SpoolOptions,
+                    csprng: &mut T)
+                    -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError>
+    where
+        T: CryptoRng + Rng,
+    {
+        verify_signature(&public_key, &signature)?;
+        let now = unix_time();
+        let policy = options.policy(now)?;
+        let appenders = options.appenders()?;
+        let mut spool_id = [0u8; SPOOL_ID_SIZE];
+        csprng.fill_bytes(&mut spool_id);
+        self.check_free_space(spool_id)?;
+        self.insert_spool(spool_id, public_key, &policy, appenders.as_ref(), now)?;
+        Ok(spool_id)
+    }
+
+    /// Creates an empty spool under `spool_id`, recording it as
+    /// created at `created_at`.
+    fn insert_spool(&self,
+                    spool_id: [u8; SPOOL_ID_SIZE],
+                    public_key: PublicKey,
+                    policy: &SpoolPolicy,
+                    appenders: Option<&AppenderList>,
+                    created_at: u64)
+                    -> Result<(), MultiSpoolError> {
+        let spool_path = self.shards.spool_path(spool_id);
+        self.spool_set.begin(spool_id, Intent::Create)?;
+        self.spool_set.put(spool_id, public_key)?;
+        self.spool_set.set_policy(spool_id, policy)?;
+        self.spool_set.touch(spool_id, Activity::Created, created_at)?;
+        let spool = self.open_spool(spool_id, &spool_path)?;
+        spool.set_appenders(appenders)?;
+        spool.flush()?;
+        self.spool_set.commit(spool_id)?;
+        self.map.insert(spool_id, spool);
+        self.add_owned_spool(&public_key, spool_id);
+        if !policy.is_default() {
+            write_lock(&self.policies).insert(spool_id, policy.clone());
+        }
+        self.replicate(ReplicationEvent::Create {
+            spool_id: spool_id.to_vec(),
+            public_key: public_key.to_bytes().to_vec(),
+            policy: if policy.is_default() { None } else { Some(policy.clone()) },
+            appenders: appenders.map(|x| ByteBuf::from(x.to_bytes())),
+        });
+        Ok(())
+    }
+
+    /// Fails with StorageExhausted if the spool set's directory or the
+    /// one a spool's database is in is running out of space, see
+    /// `set_min_free_bytes`.
+    fn check_free_space(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<(), MultiSpoolError> {
+        let base_dir = &self.shards.dirs()[0];
+        let spool_dir = self.shards.dir(&spool_id);
+        if !self.disk.has_room(Path::new(base_dir)) || !self.disk.has_room(Path::new(spool_dir)) {
+            return Err(MultiSpoolError::StorageExhausted)
+        }
+        Ok(())
+    }
+
+    /// Sets the free space in bytes below which creations and appends
+    /// are refused with StorageExhausted, never if zero.
+    pub fn set_min_free_bytes(&self, min_free_bytes: u64) {
+        self.disk.set_min_free_bytes(min_free_bytes);
+    }
+
+    /// Returns the policy of a spool created with options, if any.
+    pub fn policy(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Option<SpoolPolicy> {
+        read_lock(&self.policies).get(&spool_id).cloned()
+    }
+
+    /// Records an operation and its outcome in the audit log. The
+    /// operation has already happened, so failing to record it is
+    /// logged rather than returned.
+    fn audit<T>(&self,
+                action: &str,
+                spool_id: Option<&[u8; SPOOL_ID_SIZE]>,
+                result: &Result<T, MultiSpoolError>,
+                detail: &str) {
+        let outcome = match result {
+            Ok(_) => OUTCOME_OK.to_string(),
+            Err(e) => format!("{}", e),
+        };
+        if let Err(e) = self.audit.record(action, spool_id, &outcome, detail) {
+            error!("FAILED to record {} in the audit log: {}", action, e);
+        }
+    }
+
+    /// Returns the spool databases in the shard directories which
+    /// belong to no spool, left behind by a crash while a spool was
+    /// created or deleted.
+    pub fn orphans(&self) -> Result<Vec<PathBuf>, MultiSpoolError> {
+        let mut paths = BTreeSet::new();
+        for dir in self.shards.dirs() {
+            paths.extend(spool_databases(Path::new(dir))?);
+        }
+        for spool_id in self.spool_ids() {
+            paths.remove(&self.shards.spool_path(spool_id));
+        }
+        // A spool is put in the spool set before its database is
+        // made, so those of spools being created are kept too.
+        for key in self.spool_set.keys() {
+            let key = key?;
+            if key.len() == SPOOL_ID_SIZE {
+                paths.remove(&self.shards.spool_path(*array_ref![key, 0, SPOOL_ID_SIZE]));
+            }
+        }
+        Ok(paths.into_iter().collect())
+    }
+
+    /// Removes orphaned spool databases, returning the number removed.
+    pub fn collect_orphans(&self) -> Result<usize, MultiSpoolError> {
+        let result = self.remove_orphans();
+        let detail = result.as_ref().map(|x| format!("{} removed", x)).unwrap_or_default();
+        self.audit("gc", None, &result, &detail);
+        result
+    }
+
+    fn remove_orphans(&self) -> Result<usize, MultiSpoolError> {
+        let orphans = self.orphans()?;
+        for path in &orphans {
+            warn!("Removing orphaned spool database {}", path.display());
+            remove_db(path)?;
+            if let Some(dir) = self.shards.dir_of(path) {
+                remove_empty_parent(Path::new(dir), path);
+            }
+        }
+        Ok(orphans.len())
+    }
+
+    /// Returns a guard keeping `restore` from replacing the spools
+    /// until it is dropped, to hold while handling a request. It must
+    /// not be held while calling `restore`.
+    pub fn enter(&self) -> RwLockReadGuard<'_, ()> {
+        self.exclusive.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the audit log.
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    /// Verifies the spool owner's signature. A good signature is
+    /// always accepted, while bad ones for a spool backing off after
+    /// too many of them are refused as SignatureBackoff, see
+    /// `throttle`.
+    fn verify_owner(&self, spool_id: [u8; SPOOL_ID_SIZE], signature: &Signature) -> Result<(), MultiSpoolError> {
+        let pub_key = self.spool_set.get_public_key(spool_id)?;
+        self.verify_throttled(spool_id, &pub_key, signature)
+    }
+
+    /// Verifies a reader's signature like `verify_owner`. Keys which
+    /// aren't readers are refused without counting as bad signatures.
+    fn verify_reader(&self, spool_id: [u8; SPOOL_ID_SIZE], public_key: &PublicKey, signature: &Signature) -> Result<(), MultiSpoolError> {
+        let is_reader = match read_lock(&self.policies).get(&spool_id) {
+            Some(policy) => policy.is_reader(public_key),
+            None => false,
+        };
+        if !is_reader {
+            return Err(MultiSpoolError::NotReader)
+        }
+        self.verify_throttled(spool_id, public_key, signature)
+    }
+
+    /// Verifies a signature without holding the backoff, then
+    /// records the result.
+    fn verify_throttled(&self, spool_id: [u8; SPOOL_ID_SIZE], public_key: &PublicKey, signature: &Signature) -> Result<(), MultiSpoolError> {
+        let result = verify_signature(public_key, signature);
+        let mut backoff = lock(&self.backoff);
+        match result {
+            Ok(()) => {
+                backoff.success(&spool_id);
+                Ok(())
+            },
+            Err(e) => {
+                SIGNATURE_FAILURES.inc();
+                if backoff.failure(&spool_id) {
+                    SIGNATURE_BACKOFFS.inc();
+                    return Err(MultiSpoolError::SignatureBackoff)
+                }
+                Err(e)
+            },
+        }
+    }
+
+    /// Sets how many consecutive bad signatures a spool may get
+    /// before it backs off, and for how long at first.
+    pub fn set_signature_backoff(&self, max_failures: u32, backoff: Duration) {
+        lock(&self.backoff).set_policy(max_failures, backoff);
+    }
+
+    /// Purges a spool given its owner's signature. Unless the grace
+    /// period is zero the spool is only marked as purged, and can be
+    /// undeleted until `expire_tombstones` deletes it for good.
+    pub fn purge_spool(&self, spool_id: [u8; SPOOL_ID_SIZE], signature: Signature) -> Result<(), MultiSpoolError> {
+        let _timer = STORAGE_LATENCY.with_label_values(&["purge"]).start_timer();
+        let soft = read_lock(&self.settings).purge_grace_period > Duration::from_secs(0);
+        let result = self.check_primary()
+            .and_then(|_| self.shared_spool(spool_id, false))
+            .and_then(|_| self.verify_owner(spool_id, &signature))
+            .and_then(|_| if soft { self.tombstone_spool(spool_id) } else { self.remove_spool(spool_id) });
+        self.audit("purge", Some(&spool_id), &result, if soft { "tombstoned" } else { "deleted" });
+        result
+    }
+
+    fn tombstone_spool(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<(), MultiSpoolError> {
+        self.tombstone_at(spool_id, unix_time())
+    }
+
+    fn tombstone_at(&self, spool_id: [u8; SPOOL_ID_SIZE], purged_at: u64) -> Result<(), MultiSpoolError> {
+        self.spool_set.tombstone(spool_id, purged_at)?;
+        write_lock(&self.tombstones).insert(spool_id, purged_at);
+        self.replicate(ReplicationEvent::Tombstone { spool_id: spool_id.to_vec(), purged_at: purged_at });
+        Ok(())
+    }
+
+    fn untombstone(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<(), MultiSpoolError> {
+        self.spool_set.undelete(spool_id)?;
+        write_lock(&self.tombstones).remove(&spool_id);
+        self.replicate(ReplicationEvent::Undelete { spool_id: spool_id.to_vec() });
+        Ok(())
+    }
+
+    /// Restores a purged spool which has not yet been deleted, given
+    /// its owner's signature.
+    pub fn undelete_spool(&self, spool_id: [u8; SPOOL_ID_SIZE], signature: Signature) -> Result<(), MultiSpoolError> {
+        let result = if let Err(e) = self.check_primary() {
+            Err(e)
+        } else if self.purged_at(spool_id).is_some() {
+            self.verify_owner(spool_id, &signature).and_then(|_| self.untombstone(spool_id))
+        } else {
+            Err(MultiSpoolError::NoSuchSpool)
+        };
+        self.audit("undelete", Some(&spool_id), &result, "");
+        result
+    }
+
+    /// Deletes for good every purged spool whose grace period is
+    /// over, returning the number deleted.
+    pub fn expire_tombstones(&self) -> Result<usize, MultiSpoolError> {
+        let now = unix_time();
+        let grace_period = read_lock(&self.settings).purge_grace_period.as_secs();
+        let expired: Vec<[u8; SPOOL_ID_SIZE]> = read_lock(&self.tombstones).iter()
+            .filter(|&(_, purged_at)| purged_at.saturating_add(grace_period) <= now)
+            .map(|(spool_id, _)| *spool_id)
+            .collect();
+        for spool_id in &expired {
+            let result = self.remove_spool(*spool_id);
+            self.audit("expire", Some(spool_id), &result, "");
+            result?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Deletes every spool whose TTL is over, returning the number
+    /// deleted.
+    pub fn expire_spools(&self) -> Result<usize, MultiSpoolError> {
+        let now = unix_time();
+        let expired: Vec<[u8; SPOOL_ID_SIZE]> = read_lock(&self.policies).iter()
+            .filter(|&(_, policy)| policy.is_expired(now))
+            .map(|(spool_id, _)| *spool_id)
+            .collect();
+        for spool_id in &expired {
+            let result = self.remove_spool(*spool_id);
+            self.audit("ttl", Some(spool_id), &result, "");
+            result?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Purges every spool neither appended to nor read for longer than
+    /// the inactivity period, returning the number purged. They are
+    /// tombstoned like spools purged by their owners, unless the purge
+    /// grace period is zero. Spools created before activity was kept
+    /// are only purged once they have been used again.
+    pub fn purge_inactive_spools(&self) -> Result<usize, MultiSpoolError> {
+        let (inactive_period, soft) = {
+            let settings = read_lock(&self.settings);
+            (settings.inactive_period, settings.purge_grace_period > Duration::from_secs(0))
+        };
+        let inactive_period = match inactive_period {
+            Some(x) => x.as_secs(),
+            None => return Ok(0),
+        };
+        let now = unix_time();
+        let mut inactive = vec![];
+        for spool_id in self.spool_ids() {
+            if self.purged_at(spool_id).is_some() {
+                continue
+            }
+            let activity = self.spool_set.activity(spool_id)?;
+            let last_active = activity.created_at.max(activity.last_append).max(activity.last_read);
+            if let Some(last_active) = last_active {
+                if now.saturating_sub(last_active) > inactive_period {
+                    inactive.push(spool_id);
+                }
+            }
+        }
+        for spool_id in &inactive {
+            let result = if soft { self.tombstone_spool(*spool_id) } else { self.remove_spool(*spool_id) };
+            self.audit("inactive", Some(spool_id), &result, if soft { "tombstoned" } else { "deleted" });
+            result?;
+        }
+        Ok(inactive.len())
+    }
+
+    /// Sets how long a spool may go without appends or reads before
+    /// `purge_inactive_spools` purges it, or None to keep it forever.
+    pub fn set_inactive_period(&self, inactive_period: Option<Duration>) {
+        write_lock(&self.settings).inactive_period = inactive_period;
+    }
+
+    /// Sets how long purged spools can be undeleted for. Zero purges
+    /// spools immediately.
+    pub fn set_purge_grace_period(&self, grace_period: Duration) {
+        write_lock(&self.settings).purge_grace_period = grace_period;
+    }
+
+    /// Returns the unix time a spool was purged at, if it is waiting
+    /// to be deleted.
+    pub fn purged_at(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Option<u64> {
+        read_lock(&self.tombstones).get(&spool_id).cloned()
+    }
+
+    /// Purges a spool without checking the owner's signature. This is
+    /// meant for operators only.
+    pub fn force_purge_spool(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<(), MultiSpoolError> {
+        let result = self.check_primary().and_then(|_| self.remove_spool(spool_id));
+        self.audit("force_purge", Some(&spool_id), &result, "");
+        result
+    }
+
+    fn remove_spool(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<(), MultiSpoolError> {
+        let shared = self.shared_spool(spool_id, true)?;
+        let mut guard = write_lock(&shared);
+        if guard.is_none() {
+            return Err(MultiSpoolError::NoSuchSpool)
+        }
+        let public_key = self.spool_set.get_public_key(spool_id).ok();
+        self.spool_set.begin(spool_id, Intent::Delete)?;
+        if let Some(ref mut spool) = *guard {
+            spool.purge()?;
+        }
+        self.spool_set.delete(spool_id)?;
+        let spool = guard.take();
+        drop(guard);
+        self.map.remove(&spool_id);
+        if let Some(spool) = spool {
+            let path = spool.path().to_path_buf();
+            drop(spool);
+            remove_db(&path)?;
+            remove_empty_parent(Path::new(self.shards.dir(&spool_id)), &path);
+        }
+        self.spool_set.commit(spool_id)?;
+        write_lock(&self.tombstones).remove(&spool_id);
+        write_lock(&self.policies).remove(&spool_id);
+        if let Some(public_key) = public_key {
+            self.remove_owned_spool(&public_key, spool_id);
+        }
+        lock(&self.dedup).remove_spool(&spool_id);
+        lock(&self.backoff).remove_spool(&spool_id);
+        self.replicate(ReplicationEvent::Delete { spool_id: spool_id.to_vec() });
+        Ok(())
+    }
+
+    /// Sets the log spool creations, appends, appender list and token
+    /// key changes, spent tokens, purges, undeletions and deletions
+    /// are recorded in for replicas. Imports and restores aren't replicated, and
+    /// purged spools are replicated as deleted only once their grace
+    /// period is over.
+    pub fn set_replication_log(&self, replication: Option<Arc<ReplicationLog>>) {
+        let mut settings = write_lock(&self.settings);
+        if let Some(ref replication) = replication {
+            replication.set_epoch(settings.epoch);
+        }
+        settings.replication = replication;
+    }
+
+    /// Returns the replication role, see `Role`.
+    pub fn role(&self) -> Role {
+        read_lock(&self.settings).role
+    }
+
+    /// Returns the epoch of the latest primary seen.
+    pub fn epoch(&self) -> u64 {
+        read_lock(&self.settings).epoch
+    }
+
+    /// Changes the replication role, which is kept across restarts.
+    pub fn set_role(&self, role: Role) -> Result<(), MultiSpoolError> {
+        let result = self.change_role(role, None);
+        self.audit("set_role", None, &result, role.as_str());
+        result
+    }
+
+    /// Makes a standby the primary with an epoch after that of the
+    /// primary it takes over from, returning the new epoch. Appender
+    /// lists, token keys, spent tokens and purges are replicated, so
+    /// the new primary restricts the same spools the old one did.
+    pub fn promote(&self) -> Result<u64, MultiSpoolError> {
+        let result = if self.role() == Role::Standby {
+            let epoch = self.epoch() + 1;
+            self.change_role(Role::Primary, Some(epoch)).map(|_| epoch)
+        } else {
+            Err(MultiSpoolError::ReplicationError(ReplicationError::NotStandby))
+        };
+        let detail = result.as_ref().map(|x| format!("epoch {}", x)).unwrap_or_default();
+        self.audit("promote", None, &result, &detail);
+        result
+    }
+
+    /// Fences a primary a standby was promoted in place of, so that
+    /// it refuses writes from now on.
+    pub fn fence(&self) -> Result<(), MultiSpoolError> {
+        let result = self.change_role(Role::Fenced, None);
+        self.audit("fence", None, &result, "");
+        result
+    }
+
+    fn change_role(&self, role: Role, epoch: Option<u64>) -> Result<(), MultiSpoolError> {
+        let mut settings = write_lock(&self.settings);
+        let epoch = epoch.unwrap_or(settings.epoch);
+        self.spool_set.set_role(role, epoch)?;
+        settings.role = role;
+        settings.epoch = epoch;
+        if let Some(ref replication) = settings.replication {
+            replication.set_epoch(epoch);
+        }
+        Ok(())
+    }
+
+    /// Checks that a standby may apply a batch of changes sent in
+    /// `epoch`, refusing batches from a primary older than the latest
+    /// seen, which must fence itself.
+    pub fn accept_epoch(&self, epoch: u64) -> Result<(), MultiSpoolError> {
+        let current = self.epoch();
+        if epoch < current {
+            return Err(MultiSpoolError::ReplicationError(ReplicationError::StaleEpoch { current: current, got: epoch }))
+        }
+        if self.role() != Role::Standby {
+            return Err(MultiSpoolError::ReplicationError(ReplicationError::NotStandby))
+        }
+        if epoch > current {
+            self.change_role(Role::Standby, Some(epoch))?;
+        }
+        Ok(())
+    }
+
+    /// Refuses writes unless this is the primary.
+    fn check_primary(&self) -> Result<(), MultiSpoolError> {
+        if self.role() != Role::Primary {
+            return Err(MultiSpoolError::NotPrimary)
+        }
+        Ok(())
+    }
+
+    fn replicate(&self, event: ReplicationEvent) {
+        if let Some(ref replication) = read_lock(&self.settings).replication {
+            replication.record(event);
+        }
+    }
+
+    /// Applies a change replicated from a primary. Changes already
+    /// applied are ignored, but an append must follow the last
+    /// message of its spool.
+    pub fn apply_replicated(&self, event: &ReplicationEvent) -> Result<(), MultiSpoolError> {
+        let spool_id = event.spool_id()?;
+        match event {
+            ReplicationEvent::Create { policy, appenders, .. } => {
+                if self.map.contains(&spool_id) {
+                    return Ok(())
+                }
+                let appenders = replicated_appenders(appenders)?;
+                self.insert_spool(spool_id, event.public_key()?, &policy.clone().unwrap_or_default(), appenders.as_ref(), unix_time())
+            },
+            ReplicationEvent::Append { message_id, message, appended_at, .. } => {
+                let pending = self.with_spool_mut(spool_id, |spool| {
+                    let expected = spool.message_count();
+                    if u64::from(*message_id) < expected {
+                        return Ok(None)
+                    }
+                    if u64::from(*message_id) > expected {
+                        return Err(MultiSpoolError::ReplicationError(ReplicationError::MessageGap { expected: expected, got: *message_id }))
+                    }
+                    self.append_locked(spool_id, spool, message, appended_at.unwrap_or_else(unix_time))?;
+                    Ok(self.pending_flush(spool, false))
+                })?;
+                self.finish_append(pending)
+            },
+            ReplicationEvent::Rekey { .. } => self.change_owner(spool_id, &event.public_key()?),
+            ReplicationEvent::SetAppenders { appenders, .. } => {
+                let appenders = replicated_appenders(appenders)?;
+                self.with_spool_mut(spool_id, |spool| self.set_appenders_locked(spool_id, spool, appenders.as_ref()))
+            },
+            ReplicationEvent::SetTokenKey { key, .. } => {
+                let key = match key {
+                    Some(x) => Some(TokenKey::from_bytes(x).map_err(|_| ReplicationError::InvalidEvent)?),
+                    None => None,
+                };
+                self.with_spool_mut(spool_id, |spool| self.set_append_token_key_locked(spool_id, spool, key.as_ref()))
+            },
+            ReplicationEvent::SpendToken { token_id, .. } => {
+                self.with_spool_mut(spool_id, |spool| Ok(spool.spend_token(token_id)?))
+            },
+            ReplicationEvent::Tombstone { purged_at, .. } => {
+                if !self.map.contains(&spool_id) {
+                    return Ok(())
+                }
+                self.tombstone_at(spool_id, *purged_at)
+            },
+            ReplicationEvent::Undelete { .. } => {
+                if self.purged_at(spool_id).is_none() {
+                    return Ok(())
+                }
+                self.untombstone(spool_id)
+            },
+            ReplicationEvent::Delete { .. } => {
+                match self.remove_spool(spool_id) {
+                    Err(MultiSpoolError::NoSuchSpool) => Ok(()),
+                    result => result,
+                }
+            },
+        }
+    }
+
+    pub fn append_to_spool(&self,
+                           spool_id: [u8; SPOOL_ID_SIZE],
+                           message: &[u8])
+                           -> Result<(), MultiSpoolError> {
+        self.check_primary()?;
+        let pending = self.with_spool_mut(spool_id, |spool| {
+            self.append_locked(spool_id, spool, message, unix_time())?;
+            Ok(self.pending_flush(spool, false))
+        })?;
+        self.finish_append(pending)
+    }
+
+    /// Appends a message to a spool whose write lock is held.
+    fn append_locked(&self,
+                     spool_id: [u8; SPOOL_ID_SIZE],
+                     spool: &mut Spool,
+                     message: &[u8],
+                     appended_at: u64)
+                     -> Result<(), MultiSpoolError> {
+        let _timer = STORAGE_LATENCY.with_label_values(&["append"]).start_timer();
+        if message.len() > self.max_message_size() {
+            return Err(MultiSpoolError::SpoolError(SpoolError::MessageTooLarge(message.len())))
+        }
+        self.check_free_space(spool_id)?;
+        let (capacity, hash_index) = match read_lock(&self.policies).get(&spool_id) {
+            Some(policy) if policy.is_full(spool.retained_count()?) => return Err(MultiSpoolError::SpoolFull),
+            Some(policy) if policy.circular => (Some(u64::from(policy.capacity)), policy.indexes_hashes()),
+            Some(policy) => (None, policy.indexes_hashes()),
+            None => (None, false),
+        };
+        spool.append_at(message, Some(appended_at))?;
+        if hash_index {
+            let mut message_id = [0u8; MESSAGE_ID_SIZE];
+            BigEndian::write_u32(&mut message_id, (spool.message_count() - 1) as u32);
+            spool.index_hash(&message_id, &merkle::leaf_hash(message))?;
+        }
+        if let Some(capacity) = capacity {
+            spool.trim(capacity)?;
+        }
+        self.spool_set.touch(spool_id, Activity::Appended, appended_at)?;
+        self.replicate(ReplicationEvent::Append {
+            spool_id: spool_id.to_vec(),
+            message_id: (spool.message_count() - 1) as u32,
+            message: message.to_vec(),
+            appended_at: Some(appended_at),
+        });
+        Ok(())
+    }
+
+    /// Returns the flush an append to a spool whose write lock is
+    /// held needs, if it asked to be durable or every append has to be.
+    fn pending_flush(&self, spool: &Spool, durable: bool) -> Option<PendingFlush> {
+        if durable || read_lock(&self.settings).durability.flush_on_append {
+            return Some(spool.pending_flush())
+        }
+        None
+    }
+
+    /// Waits for an append's flush once the spool's write lock is
+    /// released, so that the appends arriving meanwhile can share it.
+    fn finish_append(&self, pending: Option<PendingFlush>) -> Result<(), MultiSpoolError> {
+        if let Some(pending) = pending {
+            let _timer = STORAGE_LATENCY.with_label_values(&["flush"]).start_timer();
+            pending.wait()?;
+        }
+        Ok(())
+    }
+
+    /// Appends a message to a spool, first spending `token` if the
+    /// spool's owner requires append tokens.
+    pub fn append_with_token(&self,
+                             spool_id: [u8; SPOOL_ID_SIZE],
+                             message: &[u8],
+                             token: &[u8])
+                             -> Result<(), MultiSpoolError> {
+        self.append_authorized(spool_id, message, &AppendAuth::token(token))
+    }
+
+    /// Appends a message to a spool, first checking the writer's
+    /// signature if the spool has an appender list and spending the
+    /// token if its owner requires append tokens.
+    pub fn append_authorized(&self,
+                             spool_id: [u8; SPOOL_ID_SIZE],
+                             message: &[u8],
+                             auth: &AppendAuth)
+                             -> Result<(), MultiSpoolError> {
+        self.check_primary()?;
+        let pending = self.with_spool_mut(spool_id, |spool| {
+            self.append_authorized_locked(spool_id, spool, message, auth)?;
+            Ok(self.pending_flush(spool, false))
+        })?;
+        self.finish_append(pending)
+    }
+
+    /// Returns true if an append of `message` to a spool whose write
+    /// lock is held is to be dropped, its policy asking for
+    /// duplicates to be dropped and the spool holding the message
+    /// among its latest.
+    fn is_duplicate_locked(&self, spool_id: [u8; SPOOL_ID_SIZE], spool: &Spool, message: &[u8]) -> Result<bool, MultiSpoolError> {
+        let policy = match self.policy(spool_id) {
+            Some(x) if x.dedup_window > 0 => x,
+            _ => return Ok(false),
+        };
+        match spool.find_by_hash(&merkle::leaf_hash(message))? {
+            Some(message_id) => Ok(policy.is_duplicate(BigEndian::read_u32(&message_id), spool.message_count())),
+            None => Ok(false),
+        }
+    }
+
+    /// Appends like `append_authorized` to a spool whose write lock
+    /// is held, so that no token can be spent twice. Returns false if
+    /// the append was dropped as a duplicate, which spends no token.
+    fn append_authorized_locked(&self,
+                                spool_id: [u8; SPOOL_ID_SIZE],
+                                spool: &mut Spool,
+                                message: &[u8],
+                                auth: &AppendAuth)
+                                -> Result<bool, MultiSpoolError> {
+        let token_id = self.authorize_append_locked(spool_id, spool, message, auth)?;
+        self.append_spending_locked(spool_id, spool, message, token_id)
+    }
+
+    /// Checks the appender signature and append token of an append to
+    /// a spool whose read lock is held, returning the ID of the token
+    /// the append is to spend, if the spool asks for one. Whether the
+    /// token is already spent is left to the append.
+    fn authorize_append_locked(&self,
+                               spool_id: [u8; SPOOL_ID_SIZE],
+                               spool: &Spool,
+                               message: &[u8],
+                               auth: &AppendAuth)
+                               -> Result<Option<Vec<u8>>, MultiSpoolError> {
+        if let Some(appenders) = spool.appenders()? {
+            if !appenders.verify(&spool_id, message, auth.public_key, auth.signature) {
+                return Err(MultiSpoolError::AppendNotAllowed)
+            }
+        }
+        match spool.append_token_key()? {
+            Some(ref key) if !key.verify(&spool_id, auth.token) => Err(MultiSpoolError::InvalidAppendToken),
+            Some(_) => Ok(Some(tokens::token_id(auth.token))),
+            None => Ok(None),
+        }
+    }
+
+    /// Appends an authorized message to a spool whose write lock is
+    /// held, spending `token_id` unless the message is dropped as a
+    /// duplicate.
+    fn append_spending_locked(&self,
+                              spool_id: [u8; SPOOL_ID_SIZE],
+                              spool: &mut Spool,
+                              message: &[u8],
+                              token_id: Option<Vec<u8>>)
+                              -> Result<bool, MultiSpoolError> {
+        if self.is_duplicate_locked(spool_id, spool, message)? {
+            DUPLICATE_MESSAGES.inc();
+            return Ok(false)
+        }
+        if let Some(ref token_id) = token_id {
+            if spool.is_token_spent(token_id)? {
+                return Err(MultiSpoolError::SpentAppendToken)
+            }
+        }
+        self.append_locked(spool_id, spool, message, unix_time())?;
+        if let Some(token_id) = token_id {
+            spool.spend_token(&token_id)?;
+            self.replicate(ReplicationEvent::SpendToken { spool_id: spool_id.to_vec(), token_id: token_id });
+        }
+        Ok(true)
+    }
+
+    /// Appends a message like `append_authorized`, unless an append
+    /// to the spool with the same non-empty idempotency key already
+    /// succeeded or the spool drops the message as a duplicate.
+    /// Returns false if the append was a duplicate. A
+    /// `durable` append is on disk before this returns.
+    pub fn append_idempotent(&self,
+                             spool_id: [u8; SPOOL_ID_SIZE],
+                             message: &[u8],
+                             auth: &AppendAuth,
+                             idempotency_key: &[u8],
+                             durable: bool)
+                             -> Result<bool, MultiSpoolError> {
+        self.check_primary()?;
+        if idempotency_key.len() > MAX_IDEMPOTENCY_KEY_SIZE {
+            return Err(MultiSpoolError::InvalidIdempotencyKey)
+        }
+        let pending = self.with_spool_mut(spool_id, |spool| {
+            // Only an authorized retry learns its key was seen.
+            let token_id = self.authorize_append_locked(spool_id, spool, message, auth)?;
+            if !idempotency_key.is_empty() && lock(&self.dedup).contains(&spool_id, idempotency_key) {
+                DUPLICATE_APPENDS.inc();
+                return Ok(None)
+            }
+            if !self.append_spending_locked(spool_id, spool, message, token_id)? {
+                return Ok(None)
+            }
+            if !idempotency_key.is_empty() {
+                lock(&self.dedup).insert(&spool_id, idempotency_key);
+            }
+            Ok(Some(self.pending_flush(spool, durable)))
+        })?;
+        match pending {
+            Some(pending) => self.finish_append(pending).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Sets the number of idempotency keys remembered.
+    pub fn set_dedup_cache_size(&self, size: usize) {
+        lock(&self.dedup).set_capacity(size);
+    }
+
+    /// Sets the key the owner issues append tokens with, after which
+    /// appends must spend a token, or stops requiring tokens if `key`
+    /// is None.
+    pub fn set_append_token_key(&self,
+                                spool_id: [u8; SPOOL_ID_SIZE],
+                                signature: Signature,
+                                key: Option<TokenKey>)
+                                -> Result<(), MultiSpoolError> {
+        let detail = if key.is_some() { "set" } else { "cleared" };
+        let result = self.check_primary()
+            .and_then(|_| self.verify_owner(spool_id, &signature))
+            .and_then(|_| self.with_spool_mut(spool_id, |spool| self.set_append_token_key_locked(spool_id, spool, key.as_ref())));
+        self.audit("set_token_key", Some(&spool_id), &result, detail);
+        result
+    }
+
+    /// Sets the append token key of a spool whose write lock is held.
+    fn set_append_token_key_locked(&self,
+                                   spool_id: [u8; SPOOL_ID_SIZE],
+                                   spool: &mut Spool,
+                                   key: Option<&TokenKey>)
+                                   -> Result<(), MultiSpoolError> {
+        spool.set_append_token_key(key)?;
+        self.replicate(ReplicationEvent::SetTokenKey {
+            spool_id: spool_id.to_vec(),
+            key: key.map(|x| ByteBuf::from(x.to_bytes().to_vec())),
+        });
+        Ok(())
+    }
+
+    /// Restricts appends to a spool to the writers in `appenders`, or
+    /// lets anyone append again if it is None.
+    pub fn set_appenders(&self,
+                         spool_id: [u8; SPOOL_ID_SIZE],
+                         signature: Signature,
+                         appenders: Option<AppenderList>)
+                         -> Result<(), MultiSpoolError> {
+        let detail = match appenders {
+            Some(ref x) => format!("{} writers", x.keys().len()),
+            None => String::from("cleared"),
+        };
+        let result = self.check_primary()
+            .and_then(|_| self.verify_owner(spool_id, &signature))
+            .and_then(|_| self.with_spool_mut(spool_id, |spool| self.set_appenders_locked(spool_id, spool, appenders.as_ref())));
+        self.audit("set_appenders", Some(&spool_id), &result, &detail);
+        result
+    }
+
+    /// Sets the appender list of a spool whose write lock is held.
+    fn set_appenders_locked(&self,
+                            spool_id: [u8; SPOOL_ID_SIZE],
+                            spool: &mut Spool,
+                            appenders: Option<&AppenderList>)
+                            -> Result<(), MultiSpoolError> {
+        spool.set_appenders(appenders)?;
+        self.replicate(ReplicationEvent::SetAppenders {
+            spool_id: spool_id.to_vec(),
+            appenders: appenders.map(|x| ByteBuf::from(x.to_bytes())),
+        });
+        Ok(())
+    }
+
+    /// Copies a spool's messages, under their message IDs, into a new
+    /// spool owned by `new_owner`, or by the same owner if None. The
+    /// copy has the original's options, appender list and token key.
+    /// Appends to the original wait while its messages are read, but
+    /// it can be read from meanwhile.
+    pub fn copy_spool(&self,
+                      spool_id: [u8; SPOOL_ID_SIZE],
+                      signature: Signature,
+                      new_owner: Option<PublicKey>)
+                      -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError> {
+        let _timer = STORAGE_LATENCY.with_label_values(&["copy"]).start_timer();
+        let result = self.check_primary()
+            .and_then(|_| self.verify_owner(spool_id, &signature))
+            .and_then(|_| self.duplicate_spool(spool_id, new_owner));
+        let detail = match result {
+            Ok(ref copy_id) => format!("copied to {}", base64::encode(copy_id)),
+            Err(_) => String::new(),
+        };
+        self.audit("copy", Some(&spool_id), &result, &detail);
+        result
+    }
+
+    fn duplicate_spool(&self,
+                       spool_id: [u8; SPOOL_ID_SIZE],
+                       new_owner: Option<PublicKey>)
+                       -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError> {
+        let archive = self.with_spool(spool_id, |spool| self.archive_locked(spool_id, spool, 0))?;
+        let public_key = match new_owner {
+            Some(x) => x,
+            None => archive.public_key()?,
+        };
+        let token_key = archive.append_token_key()?;
+        let appenders = archive.appenders()?;
+        let copy_id = self.draw_spool_id()?;
+        self.check_free_space(copy_id)?;
+        self.insert_spool(copy_id, public_key, &archive.policy.clone().unwrap_or_default(), appenders.as_ref(), unix_time())?;
+        // Messages dropped from a circular original are copied empty,
+        // so that the rest keep their IDs, and trimmed again.
+        let result = self.with_spool_mut(copy_id, |spool| {
+            for (index, message) in archive.messages.iter().enumerate() {
+                let appended_at = archive.appended_at(index).unwrap_or_else(unix_time);
+                self.append_locked(copy_id, spool, message, appended_at)?;
+            }
+            self.set_append_token_key_locked(copy_id, spool, token_key.as_ref())?;
+            spool.flush()?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            if let Err(e) = self.remove_spool(copy_id) {
+                error!("FAILED to remove the partial copy of spool {}: {}", base64::encode(&spool_id), e);
+            }
+            return Err(e)
+        }
+        Ok(copy_id)
+    }
+
+    /// Hands a spool over to the new owner key in `rekey`, given the
+    /// signatures of both the current owner and the new key, see
+    /// `rekey`. The spool ID and messages stay as they are.
+    pub fn rekey_spool(&self,
+                       spool_id: [u8; SPOOL_ID_SIZE],
+                       signature: Signature,
+                       rekey: &Rekey)
+                       -> Result<(), MultiSpoolError> {
+        let result = self.check_primary()
+            .and_then(|_| self.verify_owner(spool_id, &signature))
+            .and_then(|_| self.with_spool_mut(spool_id, |_| {
+                // Checked with the spool locked, so that of two
+                // re-keyings racing each other only one succeeds.
+                let old_key = self.spool_set.get_public_key(spool_id)?;
+                if !rekey.verify(&spool_id, &old_key) {
+                    return Err(MultiSpoolError::InvalidRekey)
+                }
+                self.change_owner(spool_id, &rekey.new_key)
+            }));
+        self.audit("rekey", Some(&spool_id), &result, &base64::encode(rekey.new_key.as_bytes()));
+        result
+    }
+
+    /// Replaces a spool's owner key.
+    fn change_owner(&self, spool_id: [u8; SPOOL_ID_SIZE], public_key: &PublicKey) -> Result<(), MultiSpoolError> {
+        let old_key = self.spool_set.get_public_key(spool_id)?;
+        self.spool_set.set_public_key(spool_id, public_key)?;
+        self.remove_owned_spool(&old_key, spool_id);
+        self.add_owned_spool(public_key, spool_id);
+        self.replicate(ReplicationEvent::Rekey {
+            spool_id: spool_id.to_vec(),
+            public_key: public_key.to_bytes().to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Draws a spool ID from the RNG set with `set_rng`, or else from
+    /// the operating system.
+    fn draw_spool_id(&self) -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError> {
+        let mut spool_id = [0u8; SPOOL_ID_SIZE];
+        match *lock(&self.rng) {
+            Some(ref mut rng) => rng.fill_bytes(&mut spool_id),
+            None => OsRng::new()?.fill_bytes(&mut spool_id),
+        }
+        Ok(spool_id)
+    }
+
+    pub fn read_from_spool(&self,
+                           spool_id: [u8; SPOOL_ID_SIZE],
+                           signature: Signature,
+                           message_id: &[u8; MESSAGE_ID_SIZE])
+                           -> Result<Vec<u8>, MultiSpoolError> {
+        let _timer = STORAGE_LATENCY.with_label_values(&["read"]).start_timer();
+        self.verify_owner(spool_id, &signature)?;
+        Ok(self.read_timed(spool_id, message_id)?.0)
+    }
+
+    /// Reads a message along with the unix time it was appended at,
+    /// recording the read. Callers must have checked the signature of
+    /// the owner or a reader.
+    fn read_timed(&self,
+                  spool_id: [u8; SPOOL_ID_SIZE],
+                  message_id: &[u8; MESSAGE_ID_SIZE])
+                  -> Result<(Vec<u8>, Option<u64>), MultiSpoolError> {
+        let read = self.with_spool(spool_id, |spool| Ok(spool.read_timed(message_id)?))?;
+        self.spool_set.touch(spool_id, Activity::Read, unix_time())?;
+        Ok(read)
+    }
+
+    /// Reads a message as `public_key`, which must be the spool's
+    /// owner or one of the readers it was created with, see `options`.
+    /// Readers sign their own public key as owners do. Also returns
+    /// the unix time the message was appended at, if known.
+    pub fn read_as(&self,
+                   spool_id: [u8; SPOOL_ID_SIZE],
+                   public_key: &PublicKey,
+                   signature: Signature,
+                   message_id: &[u8; MESSAGE_ID_SIZE])
+                   -> Result<(Vec<u8>, Option<u64>), MultiSpoolError> {
+        let _timer = STORAGE_LATENCY.with_label_values(&["read"]).start_timer();
+        self.verify_owner_or_reader(spool_id, public_key, &signature)?;
+        self.read_timed(spool_id, message_id)
+    }
+
+    /// Reads the latest message kept whose Merkle leaf hash is `leaf`,
+    /// as `public_key` like `read_as`, from a spool created with a
+    /// hash index. Also returns the message's ID.
+    pub fn read_by_hash(&self,
+                        spool_id: [u8; SPOOL_ID_SIZE],
+                        public_key: &PublicKey,
+                        signature: Signature,
+                        leaf: &merkle::Hash)
+                        -> Result<FoundMessage, MultiSpoolError> {
+        let _timer = STORAGE_LATENCY.with_label_values(&["read"]).start_timer();
+        self.verify_owner_or_reader(spool_id, public_key, &signature)?;
+        match self.policy(spool_id) {
+            Some(ref policy) if policy.hash_index => {},
+            _ => return Err(MultiSpoolError::NoHashIndex),
+        }
+        let message_id = match self.with_spool(spool_id, |spool| Ok(spool.find_by_hash(leaf)?))? {
+            Some(x) => x,
+            None => return Err(MultiSpoolError::SpoolError(SpoolError::NoSuchMessage)),
+        };
+        let (message, appended_at) = self.read_timed(spool_id, &message_id)?;
+        Ok((message_id, message, appended_at))
+    }
+
+    /// Reads the message `offset` messages before the newest, as
+    /// `public_key` like `read_as`, so that clients can fetch their
+    /// latest messages without knowing how many the spool holds.
+    /// Also returns the message's ID.
+    pub fn read_latest(&self,
+                       spool_id: [u8; SPOOL_ID_SIZE],
+                       public_key: &PublicKey,
+                       signature: Signature,
+                       offset: u32)
+                       -> Result<FoundMessage, MultiSpoolError> {
+        let _timer = STORAGE_LATENCY.with_label_values(&["read"]).start_timer();
+        self.verify_owner_or_reader(spool_id, public_key, &signature)?;
+        let message_id = match self.with_spool(spool_id, |spool| Ok(spool.latest_id(offset)?))? {
+            Some(x) => x,
+            None => return Err(MultiSpoolError::SpoolError(SpoolError::NoSuchMessage)),
+        };
+        let (message, appended_at) = self.read_timed(spool_id, &message_id)?;
+        Ok((message_id, message, appended_at))
+    }
+
+    /// Checks that `signature` is the signature of `public_key`, which
+    /// must be the spool's owner or one of its readers.
+    fn verify_owner_or_reader(&self, spool_id: [u8; SPOOL_ID_SIZE], public_key: &PublicKey, signature: &Signature) -> Result<(), MultiSpoolError> {
+        if self.spool_set.get_public_key(spool_id)? == *public_key {
+            self.verify_owner(spool_id, signature)
+        } else {
+            self.verify_reader(spool_id, public_key, signature)
+        }
+    }
+
+    /// Returns the proof of a message's position in its spool, with
+    /// the root signed by the identity key if one is set. Callers
+    /// must have checked the owner's signature, as `read_from_spool`
+    /// does.
+    pub fn read_proof(&self,
+                      spool_id: [u8; SPOOL_ID_SIZE],
+                      message_id: &[u8; MESSAGE_ID_SIZE])
+                      -> Result<ReadProof, MultiSpoolError> {
+        let leaves = self.with_spool(spool_id, |spool| Ok(spool.leaf_hashes()?))?;
+        let index = BigEndian::read_u32(message_id);
+        if index as usize >= leaves.len() {
+            return Err(MultiSpoolError::SpoolError(SpoolError::NoSuchMessage))
+        }
+        let identity = read_lock(&self.settings).identity.clone();
+        Ok(ReadProof::new(&spool_id, index, &leaves, identity.as_ref().map(|x| &**x)))
+    }
+
+    /// Returns the number of messages appended to a spool, including
+    /// those since trimmed or dropped.
+    pub fn message_count(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<u64, MultiSpoolError> {
+        self.with_spool(spool_id, |spool| Ok(spool.message_count()))
+    }
+
+    /// Sets the service identity key which signs spool tree roots.
+    pub fn set_identity(&self, identity: Option<Arc<Keypair>>) {
+        if let Some(ref keypair) = identity {
+            self.audit::<()>("set_identity", None, &Ok(()), &base64::encode(keypair.public.as_bytes()));
+        }
+        write_lock(&self.settings).identity = identity;
+    }
+
+    /// Flushes the spool set and every open spool to disk.
+    pub fn flush(&self) -> Result<(), MultiSpoolError> {
+        self.spool_set.flush()?;
+        for (_, shared) in self.map.spools() {
+            if let Some(ref spool) = *read_lock(&shared) {
+                spool.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the largest message which may be appended.
+    pub fn max_message_size(&self) -> usize {
+        read_lock(&self.settings).max_message_size
+    }
+
+    /// Sets the largest message which may be appended, which defaults
+    /// to MESSAGE_SIZE.
+    pub fn set_max_message_size(&self, max_message_size: usize) {
+        write_lock(&self.settings).max_message_size = max_message_size;
+    }
+
+    /// Sets whether every append is flushed to disk before it is
+    /// acknowledged. The other durability settings only take effect
+    /// when the spools are opened.
+    pub fn set_flush_on_append(&self, flush_on_append: bool) {
+        write_lock(&self.settings).durability.flush_on_append = flush_on_append;
+    }
+
+    /// Sets the zstd level newly appended messages are compressed
+    /// at, or disables compression. Messages are read back the same
+    /// either way.
+    pub fn set_compression_level(&self, compression_level: Option<i32>) {
+        write_lock(&self.settings).codec.compression_level = compression_level;
+        for (_, shared) in self.map.spools() {
+            if let Some(ref mut spool) = *write_lock(&shared) {
+                spool.codec.compression_level = compression_level;
+            }
+        }
+    }
+
+    /// Sets the master keys spool secrets and owner keys are
+    /// encrypted with, or disables encryption, giving spools without
+    /// a secret a new one. Data encrypted with keys not in the
+    /// keyring can no longer be read.
+    pub fn set_keyring(&self, keyring: Option<Arc<Keyring>>) -> Result<(), MultiSpoolError> {
+        let detail = match keyring {
+            Some(ref x) => format!("current master key {}", base64::encode(&x.current_id())),
+            None => String::from("encryption disabled"),
+        };
+        let result = self.apply_keyring(keyring);
+        self.audit("set_keyring", None, &result, &detail);
+        result
+    }
+
+    fn apply_keyring(&self, keyring: Option<Arc<Keyring>>) -> Result<(), MultiSpoolError> {
+        self.spool_set.set_keyring(keyring.clone());
+        write_lock(&self.settings).codec.keyring = keyring;
+        for (spool_id, shared) in self.map.spools() {
+            let codec = self.spool_codec(spool_id)?;
+            if let Some(ref mut spool) = *write_lock(&shared) {
+                spool.set_codec(codec);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the codec for a spool, with the spool's own key.
+    fn spool_codec(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<EntryCodec, MultiSpoolError> {
+        let mut codec = read_lock(&self.settings).codec.clone();
+        codec.spool_key = self.spool_set.spool_key(spool_id)?.map(Arc::new);
+        Ok(codec)
+    }
+
+    fn open_spool(&self, spool_id: [u8; SPOOL_ID_SIZE], path: &PathBuf) -> Result<Spool, MultiSpoolError> {
+        let durability = read_lock(&self.settings).durability;
+        let mut spool = Spool::with_durability(path, &durability)?;
+        spool.set_codec(self.spool_codec(spool_id)?);
+        Ok(spool)
+    }
+
+    /// Re-encrypts all data not encrypted with the current master
+    /// key or its spool's key rather than waiting for it to be read,
+    /// after which old master keys may be retired. Returns the number
+    /// of messages, owner keys and spool secrets rewritten.
+    pub fn reencrypt(&self) -> Result<u64, MultiSpoolError> {
+        let result = self.reencrypt_all();
+        let detail = result.as_ref().map(|x| format!("{} rewritten", x)).unwrap_or_default();
+        self.audit("reencrypt", None, &result, &detail);
+        result
+    }
+
+    fn reencrypt_all(&self) -> Result<u64, MultiSpoolError> {
+        let mut count = self.spool_set.reencrypt()?;
+        for (_, shared) in self.map.spools() {
+            if let Some(ref spool) = *read_lock(&shared) {
+                count += spool.reencrypt()?;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Returns the directories spools are sharded across, see
+    /// `SpoolInfo::shard`.
+    pub fn shard_dirs(&self) -> &[String] {
+        self.shards.dirs()
+    }
+
+    /// Returns true if the spool set holds a spool with the given
+    /// ID, counting one it can't be read from as missing.
+    pub fn has_spool(&self, spool_id: &[u8]) -> bool {
+        spool_id.len() == SPOOL_ID_SIZE && self.spool_set.has(*array_ref![spool_id, 0, SPOOL_ID_SIZE]).unwrap_or(false)
+    }
+
+    /// Returns the IDs of all open spools.
+    pub fn spool_ids(&self) -> Vec<[u8; SPOOL_ID_SIZE]> {
+        self.map.ids()
+    }
+
+    /// Describes a spool for operators.
+    pub fn spool_info(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<SpoolInfo, MultiSpoolError> {
+        self.with_any_spool(spool_id, |spool| {
+            let metadata = fs::metadata(spool.path())?;
+            let age = metadata.created()
+                .or_else(|_| metadata.modified())
+                .ok()
+                .and_then(|x| SystemTime::now().duration_since(x).ok());
+            Ok(SpoolInfo {
+                spool_id: spool_id,
+                public_key: self.spool_set.get_public_key(spool_id)?,
+                message_count: spool.message_count(),
+                size_bytes: disk_usage(spool.path())?,
+                shard: self.shards.index(&spool_id),
+                age: age,
+                purged_at: self.purged_at(spool_id),
+                policy: self.policy(spool_id),
+                activity: self.spool_set.activity(spool_id)?,
+            })
+        })
+    }
+
+    /// Summarizes every spool and the requests handled since startup.
+    /// Spools deleted while they are counted are left out.
+    pub fn stats(&self) -> Result<MultiSpoolStats, MultiSpoolError> {
+        let mut stats = MultiSpoolStats {
+            shards: self.shard_dirs().iter().map(|dir| ShardStats {
+                dir: dir.clone(),
+                ..ShardStats::default()
+            }).collect(),
+            commands: SUPPORTED_COMMANDS.iter().map(|x| (command_name(*x), metrics::request_count(command_name(*x)))).collect(),
+            ..MultiSpoolStats::default()
+        };
+        for spool_id in self.spool_ids() {
+            let info = match self.spool_info(spool_id) {
+                Ok(x) => x,
+                Err(MultiSpoolError::NoSuchSpool) => continue,
+                Err(e) => return Err(e),
+            };
+            stats.spool_count += 1;
+            stats.message_count += info.message_count;
+            stats.size_bytes += info.size_bytes;
+            let shard = &mut stats.shards[info.shard];
+            shard.spool_count += 1;
+            shard.message_count += info.message_count;
+            shard.size_bytes += info.size_bytes;
+        }
+        Ok(stats)
+    }
+
+    /// Takes a snapshot of the spool map, caches and every spool for
+    /// debugging. Spools deleted meanwhile are left out.
+    pub fn diagnostics(&self) -> Result<Diagnostics, MultiSpoolError> {
+        let mut diagnostics = Diagnostics {
+            dedup_keys: lock(&self.dedup).len(),
+            throttled_spools: lock(&self.backoff).len(),
+            tombstones: read_lock(&self.tombstones).len(),
+            policies: read_lock(&self.policies).len(),
+            owners: read_lock(&self.owners).len(),
+            ..Diagnostics::default()
+        };
+        for (spool_id, shared) in self.map.spools() {
+            if let Some(ref spool) = *read_lock(&shared) {
+                diagnostics.open_spools += 1;
+                diagnostics.pending_flushes += spool.pending_flushes();
+            }
+            match self.spool_stats(spool_id) {
+                Ok(stats) => diagnostics.spools.push((spool_id, stats)),
+                Err(MultiSpoolError::NoSuchSpool) => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(diagnostics)
+    }
+
+    /// Returns what a spool holds, unless it was purged.
+    pub fn spool_stats(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<SpoolStats, MultiSpoolError> {
+        self.with_spool(spool_id, |spool| {
+            let activity = self.spool_set.activity(spool_id)?;
+            let policy = self.policy(spool_id).unwrap_or_default();
+            Ok(SpoolStats {
+                MessageCount: spool.retained_count()?,
+                SizeBytes: disk_usage(spool.path())?,
+                Head: spool.message_count(),
+                FirstMessage: u64::from(spool.first_message()?),
+                CreatedAt: activity.created_at,
+                LastAppend: activity.last_append,
+                LastRead: activity.last_read,
+                Capacity: policy.capacity,
+                ExpiresAt: policy.expires_at,
+            })
+        })
+    }
+
+    /// Returns what a spool holds to its owner, if `signature` is the
+    /// owner's.
+    pub fn spool_status(&self, spool_id: [u8; SPOOL_ID_SIZE], signature: Signature) -> Result<SpoolStats, MultiSpoolError> {
+        self.verify_owner(spool_id, &signature)?;
+        self.spool_stats(spool_id)
+    }
+
+    /// Checks that a spool's owner key is known and that every
+    /// message up to the spool's head can be read.
+    pub fn verify_spool(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<(), MultiSpoolError> {
+        self.with_any_spool(spool_id, |spool| {
+            self.spool_set.get_public_key(spool_id)?;
+            let mut message_id = [0u8; MESSAGE_ID_SIZE];
+            for i in u64::from(spool.first_message()?)..spool.message_count() {
+                BigEndian::write_u32(&mut message_id, i as u32);
+                spool.read(&message_id)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Exports a spool, even one waiting to be deleted, into a
+    /// portable archive. This is meant for operators only, as the
+    /// archive holds every message.
+    pub fn export_spool(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<SpoolArchive, MultiSpoolError> {
+        let result = self.archive_spool(spool_id, 0);
+        let detail = result.as_ref().map(|x| format!("{} messages", x.messages.len())).unwrap_or_default();
+        self.audit("export", Some(&spool_id), &result, &detail);
+        result
+    }
+
+    /// Returns up to `limit` of the messages a spool, even one waiting
+    /// to be deleted, keeps from `first_message` on, oldest first.
+    /// Exporting a large spool a batch at a time leaves it unlocked
+    /// between batches.
+    pub fn export_messages(&self, spool_id: [u8; SPOOL_ID_SIZE], first_message: u64, limit: usize) -> Result<Vec<(u32, Vec<u8>)>, MultiSpoolError> {
+        self.with_any_spool(spool_id, |spool| {
+            if first_message >= spool.message_count() {
+                return Ok(vec![])
+            }
+            let messages = spool.iter_from(first_message as u32).take(limit).collect::<Result<Vec<_>, SpoolError>>()?;
+            Ok(messages)
+        })
+    }
+
+    /// Archives a spool's messages from `first_message` on, along
+    /// with all its metadata, while appends to it wait.
+    fn archive_spool(&self, spool_id: [u8; SPOOL_ID_SIZE], first_message: u64) -> Result<SpoolArchive, MultiSpoolError> {
+        self.with_any_spool(spool_id, |spool| self.archive_locked(spool_id, spool, first_message))
+    }
+
+    fn archive_locked(&self, spool_id: [u8; SPOOL_ID_SIZE], spool: &Spool, first_message: u64) -> Result<SpoolArchive, MultiSpoolError> {
+        let public_key = self.spool_set.get_public_key(spool_id)?;
+        let first_message = cmp::min(first_message, spool.message_count());
+        let mut messages = Vec::with_capacity((spool.message_count() - first_message) as usize);
+        let mut appended_at = Vec::with_capacity(messages.capacity());
+        let mut message_id = [0u8; MESSAGE_ID_SIZE];
+        let dropped = u64::from(spool.first_message()?);
+        for i in first_message..spool.message_count() {
+            if i < dropped {
+                // Dropped from a circular spool, see `Spool::trim`.
+                messages.push(ByteBuf::from(vec![]));
+                appended_at.push(0);
+                continue
+            }
+            BigEndian::write_u32(&mut message_id, i as u32);
+            let (message, time) = spool.read_timed(&message_id)?;
+            messages.push(ByteBuf::from(message));
+            appended_at.push(time.unwrap_or(0));
+        }
+        if appended_at.iter().all(|x| *x == 0) {
+            appended_at.clear();
+        }
+        Ok(SpoolArchive {
+            version: ARCHIVE_FORMAT_VERSION,
+            spool_id: spool_id.to_vec(),
+            public_key: public_key.to_bytes().to_vec(),
+            first_message: first_message,
+            messages: messages,
+            appended_at: appended_at,
+            append_token_key: spool.append_token_key()?.map(|x| x.to_bytes().to_vec()).unwrap_or_default(),
+            spent_tokens: spool.spent_tokens()?.into_iter().map(ByteBuf::from).collect(),
+            appenders: spool.appenders()?.map(|x| x.to_bytes()).unwrap_or_default(),
+            policy: self.policy(spool_id),
+            exported_at: unix_time(),
+            purged_at: self.purged_at(spool_id),
+        })
+    }
+
+    /// Imports a spool from an archive under its original spool ID,
+    /// which must not already be in use. Message IDs are kept.
+    pub fn import_spool(&mut self, archive: &SpoolArchive) -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError> {
+        let result = self.restore_spool(archive);
+        let spool_id = archive.spool_id().ok();
+        let detail = format!("{} messages", archive.messages.len());
+        self.audit("import", spool_id.as_ref(), &result, &detail);
+        result
+    }
+
+    fn restore_spool(&self, archive: &SpoolArchive) -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError> {
+        if archive.first_message != 0 {
+            return Err(MultiSpoolError::ArchiveError(ArchiveError::InvalidArchive))
+        }
+        let spool_id = archive.spool_id()?;
+        let public_key = archive.public_key()?;
+        let token_key = archive.append_token_key()?;
+        let appenders = archive.appenders()?;
+        if self.map.contains(&spool_id) || self.spool_set.has(spool_id)? {
+            return Err(MultiSpoolError::SpoolExists)
+        }
+        let path = self.shards.spool_path(spool_id);
+        if path.exists() {
+            // Left behind by a spool the spool set has forgotten.
+            remove_db(&path)?;
+        }
+        self.spool_set.begin(spool_id, Intent::Create)?;
+        self.spool_set.put(spool_id, public_key)?;
+        let result = self.open_spool(spool_id, &path).and_then(|mut spool| {
+            for (index, message) in archive.messages.iter().enumerate() {
+                spool.append_at(message, archive.appended_at(index))?;
+            }
+            spool.set_append_token_key(token_key.as_ref())?;
+            for token_id in &archive.spent_tokens {
+                spool.spend_token(token_id)?;
+            }
+            spool.set_appenders(appenders.as_ref())?;
+            if let Some(ref policy) = archive.policy {
+                self.spool_set.set_policy(spool_id, policy)?;
+                if policy.circular {
+                    spool.trim(u64::from(policy.capacity))?;
+                }
+            }
+            spool.flush()?;
+            Ok(spool)
+        });
+        let result = result.and_then(|spool| {
+            if let Some(purged_at) = archive.purged_at {
+                self.spool_set.tombstone(spool_id, purged_at)?;
+                write_lock(&self.tombstones).insert(spool_id, purged_at);
+            }
+            Ok(spool)
+        });
+        match result {
+            Ok(spool) => {
+                self.spool_set.commit(spool_id)?;
+                self.map.insert(spool_id, spool);
+                self.add_owned_spool(&public_key, spool_id);
+                if let Some(ref policy) = archive.policy {
+                    write_lock(&self.policies).insert(spool_id, policy.clone());
+                }
+                Ok(spool_id)
+            },
+            Err(e) => {
+                self.spool_set.delete(spool_id)?;
+                if path.exists() {
+                    remove_db(&path)?;
+                }
+                self.spool_set.commit(spool_id)?;
+                Err(e)
+            },
+        }
+    }
+
+    /// Writes a backup of every spool to the backup directory,
+    /// returning its file name. An incremental backup only holds the
+    /// messages appended since the last backup, and is full if there
+    /// was none.
+    pub fn backup(&self, incremental: bool) -> Result<String, MultiSpoolError> {
+        let result = self.write_backup(incremental);
+        let detail = match result {
+            Ok(ref name) if incremental => format!("{} incremental", name),
+            Ok(ref name) => name.clone(),
+            Err(_) => String::new(),
+        };
+        self.audit("backup", None, &result, &detail);
+        result
+    }
+
+    fn write_backup(&self, incremental: bool) -> Result<String, MultiSpoolError> {
+        let base = if incremental { self.spool_set.last_backup()? } else { None };
+        let spool_ids = self.spool_ids();
+        let mut spools = Vec::with_capacity(spool_ids.len());
+        let mut watermarks = Vec::with_capacity(spool_ids.len());
+        for spool_id in spool_ids {
+            let first_message = match base {
+                Some(_) => self.spool_set.watermark(spool_id)?,
+                None => 0,
+            };
+            let spool = self.archive_spool(spool_id, first_message)?;
+            watermarks.push((spool_id, spool.message_count()));
+            spools.push(spool);
+        }
+        let backup = Backup::new(&spools, unix_time(), base.as_ref().map(|x| x.as_str()))?;
+        let name = backup.write(&self.backup_dir())?;
+        self.spool_set.set_watermarks(&name, &watermarks)?;
+        Ok(name)
+    }
+
+    /// Returns the file names of the backups in the backup directory,
+    /// oldest first.
+    pub fn backups(&self) -> Result<Vec<String>, MultiSpoolError> {
+        Ok(backup::list(&self.backup_dir())?)
+    }
+
+    /// Replaces every spool with those in the backup called `name`,
+    /// returning the number of spools restored. The backup, and every
+    /// backup it builds on, is read and checked in full before any
+    /// spool is removed.
+    pub fn restore(&self, name: &str) -> Result<usize, MultiSpoolError> {
+        let _exclusive = self.exclusive.write().unwrap_or_else(PoisonError::into_inner);
+        let result = self.restore_backup(name);
+        self.audit("restore", None, &result, name);
+        result
+    }
+
+    fn restore_backup(&self, name: &str) -> Result<usize, MultiSpoolError> {
+        let spools = backup::read_chain(&self.backup_dir(), name)?;
+        for spool_id in self.spool_ids() {
+            self.remove_spool(spool_id)?;
+        }
+        let mut watermarks = Vec::with_capacity(spools.len());
+        for spool in &spools {
+            watermarks.push((self.restore_spool(spool)?, spool.message_count()));
+        }
+        self.spool_set.set_watermarks(name, &watermarks)?;
+        self.flush()?;
+        Ok(spools.len())
+    }
+
+    /// Sets the directory backups are written to and restored from,
+    /// which defaults to DEFAULT_BACKUP_DIR in the data directory.
+    pub fn set_backup_dir<P: AsRef<Path>>(&self, backup_dir: P) {
+        write_lock(&self.settings).backup_dir = PathBuf::from(backup_dir.as_ref());
+    }
+
+    fn backup_dir(&self) -> PathBuf {
+        read_lock(&self.settings).backup_dir.clone()
+    }
+
+    /// Compacts a spool by copying it into a fresh database which
+    /// then replaces the original. Returns the number of bytes
+    /// reclaimed.
+    pub fn compact_spool(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<u64, MultiSpoolError> {
+        let result = self.copy_compact(spool_id);
+        if let Ok(reclaimed) = result {
+            COMPACTIONS.inc();
+            COMPACTION_RECLAIMED_BYTES.inc_by(reclaimed as f64);
+        }
+        let detail = result.as_ref().map(|x| format!("{} bytes reclaimed", x)).unwrap_or_default();
+        self.audit("compact", Some(&spool_id), &result, &detail);
+        result
+    }
+
+    fn copy_compact(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<u64, MultiSpoolError> {
+        let path = self.shards.spool_path(spool_id);
+        let compact_path = suffixed_path(&path, ".compact");
+        let old_path = suffixed_path(&path, ".old");
+        let shared = self.shared_spool(spool_id, true)?;
+        let mut guard = write_lock(&shared);
+        let before = {
+            let spool = guard.as_ref().ok_or(MultiSpoolError::NoSuchSpool)?;
+            if compact_path.exists() {
+                remove_db(&compact_path)?;
+            }
+            spool.flush()?;
+            spool.copy_to(&compact_path)?;
+            disk_usage(&path)?
+        };
+        // Close the original and move it aside for the copy, putting
+        // it back if the copy can't be put in place or opened.
+        *guard = None;
+        let result = swap_db(&path, &compact_path, &old_path)
+            .map_err(MultiSpoolError::from)
+            .and_then(|_| self.open_spool(spool_id, &path));
+        let error = match result {
+            Ok(spool) => {
+                *guard = Some(spool);
+                remove_db(&old_path)?;
+                let after = disk_usage(&path)?;
+                return Ok(before.saturating_sub(after))
+            },
+            Err(e) => e,
+        };
+        let restored = if !old_path.exists() {
+            Ok(())
+        } else if path.exists() {
+            remove_db(&path).and_then(|_| fs::rename(&old_path, &path))
+        } else {
+            fs::rename(&old_path, &path)
+        };
+        match restored.map_err(MultiSpoolError::from).and_then(|_| self.open_spool(spool_id, &path)) {
+            Ok(spool) => *guard = Some(spool),
+            Err(e) => {
+                error!("FAILED to reopen spool {} after a failed compaction: {}", base64::encode(&spool_id), e);
+                drop(guard);
+                self.map.remove(&spool_id);
+            },
+        }
+        if compact_path.exists() {
+            let _ = remove_db(&compact_path);
+        }
+        Err(error)
+    }
+
+    /// Compacts every spool, returning the number of bytes reclaimed.
+    pub fn compact(&self) -> Result<u64, MultiSpoolError> {
+        let mut reclaimed = 0;
+        for spool_id in self.spool_ids() {
+            reclaimed += self.compact_spool(spool_id)?;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Returns the number of open spools.
+    pub fn spool_count(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Checks that the spool set database is open and writable.
+    pub fn check_health(&self) -> Result<(), MultiSpoolError> {
+        self.spool_set.check_writable()?;
+        Ok(())
+    }
+
+    /// Flushes everything to disk and closes all open spools, once
+    /// the threads which `enter`ed have left. No spool may be accessed
+    /// afterwards.
+    pub fn close(&mut self) -> Result<(), MultiSpoolError> {
+        let exclusive = self.exclusive.clone();
+        let _exclusive = exclusive.write().unwrap_or_else(PoisonError::into_inner);
+        self.flush()?;
+        for (_, shared) in self.map.spools() {
+            write_lock(&shared).take();
+        }
+        self.map.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+    extern crate tempfile;
+
+    use std::assert_eq;
+    use std::thread;
+    use rand::rngs::OsRng;
+    use rand::{seq::SliceRandom, thread_rng};
+    use rand::CryptoRng;
+    use rand::Rng;
+    use ed25519_dalek::Keypair;
+    use ed25519_dalek::Signature;
+    use self::tempfile::tempdir;
+    use crate::acl::sign_append;
+    use crate::encryption::{MasterKey, KEY_SIZE};
+    use crate::tokens::BlindedToken;
+    use super::*;
+
+
+    #[test]
+    fn spool_append_read_test() {
+        let mut csprng = thread_rng();
+        let base_dir = tempdir().unwrap();
+        let mut spool_id = [0u8; SPOOL_ID_SIZE];
+        csprng.fill(&mut spool_id);
+        let path = Path::new(base_dir.path()).join(format!("spool.{}.sled", base64::encode(&spool_id)));
+        let pathbuf: PathBuf = path.to_owned();
+        let mut spool = Spool::new(&pathbuf).unwrap();
+
+        // message 1
+        let mut message1 = [0u8; MESSAGE_SIZE];
+        csprng.fill(&mut message1[..]);
+        spool.append(&message1).unwrap();
+
+        let mut message_id = [0u8; MESSAGE_ID_SIZE];
+        BigEndian::write_u32(&mut message_id, 0);
+        let read_message1 = spool.read(&message_id).unwrap();
+        assert_eq!(message1[..], read_message1[..]);
+
+        // message 2
+        let mut message2 = [0u8; MESSAGE_SIZE];
+        csprng.fill(&mut message2[..]);
+        spool.append(&message2).unwrap();
+
+        let mut message_id = [0u8; MESSAGE_ID_SIZE];
+        BigEndian::write_u32(&mut message_id, 1);
+        let read_message2 = spool.read(&message_id).unwrap();
+        assert_eq!(message2[..], read_message2[..]);
+    }
+
+    #[test]
+    fn spool_variable_length_test() {
+        let base_dir = tempdir().unwrap();
+        let path = Path::new(base_dir.path()).join("spool.variable.sled");
+        let mut spool = Spool::new(&path).unwrap();
+
+        spool.append(b"hello").unwrap();
+        spool.append(&[]).unwrap();
+
+        let mut message_id = [0u8; MESSAGE_ID_SIZE];
+        assert_eq!(spool.read(&message_id).unwrap(), b"hello".to_vec());
+        BigEndian::write_u32(&mut message_id, 1);
+        assert_eq!(spool.read(&message_id).unwrap(), Vec::<u8>::new());
+        assert_eq!(spool.message_count(), 2);
+    }
+
+    #[test]
+    fn spool_iter_test() {
+        let base_dir = tempdir().unwrap();
+        let path = Path::new(base_dir.path()).join("spool.iter.sled");
+        let mut spool = Spool::new(&path).unwrap();
+        assert!(spool.iter().next().is_none());
+
+        for message in &[b"one", b"two", b"six", b"ten"] {
+            spool.append(*message).unwrap();
+        }
+        spool.trim(3).unwrap();
+        let messages = spool.iter().collect::<Result<Vec<(u32, Vec<u8>)>, SpoolError>>().unwrap();
+        assert_eq!(messages, vec![(1, b"two".to_vec()), (2, b"six".to_vec()), (3, b"ten".to_vec())]);
+        let newest = spool.iter().rev().take(2).map(|x| x.unwrap().0).collect::<Vec<u32>>();
+        assert_eq!(newest, vec![3, 2]);
+        let from = spool.iter_from(2).map(|x| x.unwrap().0).collect::<Vec<u32>>();
+        assert_eq!(from, vec![2, 3]);
+        assert_eq!(spool.iter_from(0).count(), 3);
+        assert_eq!(spool.latest(2).unwrap(), vec![(3, b"ten".to_vec()), (2, b"six".to_vec())]);
+        assert_eq!(spool.latest_id(0).unwrap(), Some([0, 0, 0, 3]));
+        assert_eq!(spool.latest_id(2).unwrap(), Some([0, 0, 0, 1]));
+        assert_eq!(spool.latest_id(3).unwrap(), None);
+    }
+
+    #[test]
+    fn format_migration_test() {
+        let base_dir = tempdir().unwrap();
+        let path = Path::new(base_dir.path()).join("spool.format.sled");
+        let mut spool = Spool::new(&path).unwrap();
+        assert_eq!(spool.format_version().unwrap(), SPOOL_FORMAT_VERSION);
+
+        // A format 0 spool holds bare messages and gets entry headers.
+        spool.db.set(vec![0u8; MESSAGE_ID_SIZE], vec![7u8; MESSAGE_SIZE]).unwrap();
+        spool.meta.del(FORMAT_KEY).unwrap();
+        spool.migrate().unwrap();
+        assert_eq!(spool.format_version().unwrap(), SPOOL_FORMAT_VERSION);
+        assert_eq!(spool.read(&[0u8; MESSAGE_ID_SIZE]).unwrap(), vec![7u8; MESSAGE_SIZE]);
+
+        // Spools written by a newer version are refused untouched.
+        spool.meta.set(FORMAT_KEY, vec![SPOOL_FORMAT_VERSION + 1]).unwrap();
+        match spool.migrate() {
+            Err(SpoolError::UnsupportedFormat(x)) => assert_eq!(x, SPOOL_FORMAT_VERSION + 1),
+            _ => panic!("expected an unsupported format"),
+        }
+        assert_eq!(spool.format_version().unwrap(), SPOOL_FORMAT_VERSION + 1);
+
+        let set_path = Path::new(base_dir.path()).join("spool_set.sled");
+        let mut spool_set = SpoolSet::new(&set_path).unwrap();
+        assert_eq!(spool_set.format_version().unwrap(), SPOOL_SET_FORMAT_VERSION);
+        spool_set.format.set(FORMAT_KEY, vec![SPOOL_SET_FORMAT_VERSION + 1]).unwrap();
+        match spool_set.migrate() {
+            Err(SpoolSetError::UnsupportedFormat(x)) => assert_eq!(x, SPOOL_SET_FORMAT_VERSION + 1),
+            _ => panic!("expected an unsupported format"),
+        }
+    }
+
+    #[test]
+    fn interrupted_migration_test() {
+        let base_dir = tempdir().unwrap();
+        let path = Path::new(base_dir.path()).join("spool.interrupted.sled");
+        let mut spool = Spool::new(&path).unwrap();
+        let messages = [vec![1u8; MESSAGE_SIZE], vec![2u8; MESSAGE_SIZE], vec![3u8; MESSAGE_SIZE]];
+        let format_0 = |spool: &Spool| {
+            for (i, message) in messages.iter().enumerate() {
+                spool.db.set(vec![0, 0, 0, i as u8], message.clone()).unwrap();
+            }
+            spool.meta.del(FORMAT_KEY).unwrap();
+            spool.meta.del(MIGRATION_STAGED_KEY).unwrap();
+        };
+        let check = |spool: &Spool| {
+            assert_eq!(spool.format_version().unwrap(), SPOOL_FORMAT_VERSION);
+            for (i, message) in messages.iter().enumerate() {
+                assert_eq!(&spool.read(&[0, 0, 0, i as u8]).unwrap(), message);
+            }
+        };
+        let staging = spool.db.open_tree(MIGRATION_TREE_ID.to_vec()).unwrap();
+
+        // Interrupted while staging, with no message overwritten.
+        format_0(&spool);
+        staging.set(vec![0, 0, 0, 0], spool.codec.encode(&messages[0], &[0, 0, 0, 0]).unwrap()).unwrap();
+        spool.migrate().unwrap();
+        check(&spool);
+        assert!(staging.iter().next().is_none());
+
+        // Interrupted while copying, with the first message overwritten
+        // and the rest still bare.
+        format_0(&spool);
+        for (i, message) in messages.iter().enumerate() {
+            staging.set(vec![0, 0, 0, i as u8], spool.codec.encode(message, &[0, 0, 0, i as u8]).unwrap()).unwrap();
+        }
+        spool.meta.set(MIGRATION_STAGED_KEY.to_vec(), vec![]).unwrap();
+        spool.db.set(vec![0, 0, 0, 0], staging.get(vec![0, 0, 0, 0]).unwrap().unwrap().to_vec()).unwrap();
+        spool.migrate().unwrap();
+        check(&spool);
+
+        // Interrupted after every message was rewritten but before the
+        // format version was stored, twice over.
+        format_0(&spool);
+        spool.add_entry_headers().unwrap();
+        spool.add_entry_headers().unwrap();
+        spool.meta.del(FORMAT_KEY).unwrap();
+        drop(staging);
+        drop(spool);
+        let spool = Spool::new(&path).unwrap();
+        check(&spool);
+    }
+
+    #[test]
+    fn spool_check_test() {
+        let base_dir = tempdir().unwrap();
+        let path = Path::new(base_dir.path()).join("spool.check.sled");
+        let mut spool = Spool::new(&path).unwrap();
+        for message in &[b"one", b"two", b"six"] {
+            spool.append(&message[..]).unwrap();
+        }
+        assert!(spool.check().unwrap().is_empty());
+
+        // Problems which cost no messages are repaired.
+        spool.hashes.del(vec![0, 0, 0, 1]).unwrap();
+        spool.db.set(b"stray".to_vec(), vec![]).unwrap();
+        assert_eq!(spool.check().unwrap(), vec![
+            Problem::StrayKeys(1),
+            Problem::MissingChecksums(1),
+        ]);
+        spool.repair().unwrap();
+        assert!(spool.check().unwrap().is_empty());
+        assert_eq!(spool.message_count(), 3);
+
+        // Lost and tampered messages are not.
+        spool.db.set(vec![0, 0, 0, 0], spool.codec.encode(b"ten", &[0, 0, 0, 0]).unwrap()).unwrap();
+        spool.db.del(vec![0, 0, 0, 1]).unwrap();
+        spool.db.set(vec![0, 0, 0, 2], vec![0xff]).unwrap();
+        assert_eq!(spool.check().unwrap(), vec![
+            Problem::MissingMessages { first: 1, count: 1 },
+            Problem::UnreadableMessages { first: 2, count: 1 },
+            Problem::ChecksumMismatches { first: 0, count: 1 },
+        ]);
+    }
+
+    #[test]
+    fn entry_compression_test() {
+        let codec = EntryCodec {
+            compression_level: Some(3),
+            keyring: None,
+            spool_key: None,
+        };
+        let key = [0u8; MESSAGE_ID_SIZE];
+        let message = vec![7u8; 1000];
+        let entry = codec.encode(&message, &key).unwrap();
+        assert_eq!(entry[0], ENTRY_FLAG_COMPRESSED);
+        assert!(entry.len() < message.len());
+        assert_eq!(codec.decode(&entry, &key).unwrap(), (message.clone(), false));
+
+        // Incompressible messages are stored as is.
+        let entry = codec.encode(b"x", &key).unwrap();
+        assert_eq!(entry[0], ENTRY_FLAGS_NONE);
+        assert_eq!(codec.decode(&entry, &key).unwrap(), (b"x".to_vec(), false));
+
+        let mut entry = EntryCodec::default().encode(&message, &key).unwrap();
+        assert_eq!(codec.decode(&entry, &key).unwrap(), (message, false));
+        entry[0] = ENTRY_FLAG_COMPRESSED;
+        assert!(codec.decode(&entry, &key).is_err());
+    }
+
+    #[test]
+    fn entry_encryption_test() {
+        let keyring = Arc::new(Keyring::new(MasterKey::from_bytes(&[1u8; KEY_SIZE]).unwrap(), vec![]));
+        let secret = SpoolKey::generate_secret();
+        let spool_key = || Arc::new(SpoolKey::derive(&secret, &[0u8; SPOOL_ID_SIZE], &[0u8; 32]).unwrap());
+        let codec = EntryCodec {
+            compression_level: Some(3),
+            keyring: Some(keyring.clone()),
+            spool_key: Some(spool_key()),
+        };
+        let key = [0u8; MESSAGE_ID_SIZE];
+        let message = vec![7u8; 1000];
+        let entry = codec.encode(&message, &key).unwrap();
+        assert_eq!(entry[0], ENTRY_FLAG_COMPRESSED | ENTRY_FLAG_SPOOL_KEY);
+        assert_eq!(codec.decode(&entry, &key).unwrap(), (message.clone(), false));
+
+        // Entries are bound to their key.
+        assert!(codec.decode(&entry, &[1u8; MESSAGE_ID_SIZE]).is_err());
+        // Encrypted entries can't be read without the spool's key.
+        let master_only = EntryCodec {
+            compression_level: None,
+            keyring: Some(keyring.clone()),
+            spool_key: None,
+        };
+        assert!(master_only.decode(&entry, &key).is_err());
+
+        // Plaintext entries and those sealed with a master key are stale.
+        let plaintext = EntryCodec::default().encode(&message, &key).unwrap();
+        assert_eq!(codec.decode(&plaintext, &key).unwrap(), (message.clone(), true));
+        let mut legacy = vec![ENTRY_FLAG_ENCRYPTED, 0, 0, 0, 0];
+        let sealed = keyring.seal(&message, &key).unwrap();
+        BigEndian::write_u32(&mut legacy[1..ENTRY_HEADER_SIZE], sealed.len() as u32);
+        legacy.extend_from_slice(&sealed);
+        assert_eq!(codec.decode(&legacy, &key).unwrap(), (message.clone(), true));
+        assert_eq!(master_only.decode(&legacy, &key).unwrap(), (message, false));
+    }
+
+    #[test]
+    fn entry_timestamp_test() {
+        let key = [0u8; MESSAGE_ID_SIZE];
+        let message = vec![7u8; 1000];
+        for codec in &[EntryCodec::default(), EntryCodec { compression_level: Some(3), ..EntryCodec::default() }] {
+            let entry = codec.encode_timed(&message, Some(1500000000), &key).unwrap();
+            assert_ne!(entry[0] & ENTRY_FLAG_TIMESTAMP, 0);
+            assert_eq!(codec.decode_timed(&entry, &key).unwrap(), (message.clone(), Some(1500000000), false));
+            assert_eq!(codec.decode(&entry, &key).unwrap(), (message.clone(), false));
+            let entry = codec.encode(&message, &key).unwrap();
+            assert_eq!(codec.decode_timed(&entry, &key).unwrap(), (message.clone(), None, false));
+        }
+
+        // A timestamped entry too short to hold its time is corrupt.
+        let mut entry = EntryCodec::default().encode(b"short", &key).unwrap();
+        entry[0] = ENTRY_FLAG_TIMESTAMP;
+        assert!(EntryCodec::default().decode(&entry, &key).is_err());
+    }
+
+    #[test]
+    fn message_timestamp_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        let before = unix_time();
+        multi_spool.append_with_token(spool_id, b"hello", &[]).unwrap();
+        let (message, appended_at) = multi_spool.read_as(spool_id, &alice_keypair.public, alice_signature, &[0u8; MESSAGE_ID_SIZE]).unwrap();
+        assert_eq!(message, b"hello".to_vec());
+        assert!(appended_at.unwrap() >= before);
+
+        // Append times survive an export and import.
+        let archive = multi_spool.export_spool(spool_id).unwrap();
+        assert_eq!(archive.appended_at(0), appended_at);
+        let dir = tempdir().unwrap();
+        let mut other = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        other.import_spool(&archive).unwrap();
+        assert_eq!(other.read_as(spool_id, &alice_keypair.public, alice_signature, &[0u8; MESSAGE_ID_SIZE]).unwrap().1, appended_at);
+    }
+
+    #[test]
+    fn spool_purge_test() {
+        let mut csprng = thread_rng();
+        let base_dir = tempdir().unwrap();
+        let mut spool_id = [0u8; SPOOL_ID_SIZE];
+        csprng.fill(&mut spool_id);
+        let path = Path::new(base_dir.path()).join(format!("spool.{}.sled", base64::encode(&spool_id)));
+        let pathbuf: PathBuf = path.to_owned();
+        let mut spool = Spool::new(&pathbuf).unwrap();
+
+        // message 1
+        let mut message1 = [0u8; MESSAGE_SIZE];
+        csprng.fill(&mut message1[..]);
+        spool.append(&message1).unwrap();
+
+        let mut message_id = [0u8; MESSAGE_ID_SIZE];
+        BigEndian::write_u32(&mut message_id, 0);
+        let read_message1 = spool.read(&message_id).unwrap();
+        assert_eq!(message1[..], read_message1[..]);
+
+        spool.purge().unwrap();
+    }
+
+    #[test]
+    fn spoolset_filter_test() {
+        let mut csprng = thread_rng();
+        let base_dir = tempdir().unwrap();
+        let set_path = Path::new(base_dir.path()).join("spool_set.sled");
+        let spool_set = SpoolSet::new(&set_path).unwrap();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+
+        // Enough spools for the filter to grow.
+        let mut spool_ids = vec![];
+        for _ in 0..2000 {
+            let mut spool_id = [0u8; SPOOL_ID_SIZE];
+            csprng.fill(&mut spool_id);
+            spool_set.put(spool_id, alice_keypair.public).unwrap();
+            spool_ids.push(spool_id);
+        }
+        assert!(spool_ids.iter().all(|x| spool_set.has(*x).unwrap()));
+        drop(spool_set);
+
+        let spool_set = SpoolSet::new(&set_path).unwrap();
+        assert!(spool_ids.iter().all(|x| spool_set.has(*x).unwrap()));
+        spool_set.delete(spool_ids[0]).unwrap();
+        assert!(!spool_set.has(spool_ids[0]).unwrap());
+    }
+
+    #[test]
+    fn spoolset_basic_test() {
+        let mut csprng = thread_rng();
+        let base_dir = tempdir().unwrap();
+        let set_path = Path::new(base_dir.path()).join("spool_set.sled");
+        let spool_set = SpoolSet::new(&set_path).unwrap();
+
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let spool_id1 = [0u8; SPOOL_ID_SIZE];
+        spool_set.put(spool_id1, alice_keypair.public).unwrap();
+
+        assert!(spool_set.has(spool_id1).unwrap());
+        spool_set.delete(spool_id1).unwrap();
+        assert!(!spool_set.has(spool_id1).unwrap());
+
+        let mut spool_id2 = [0u8; SPOOL_ID_SIZE];
+        csprng.fill(&mut spool_id2);
+        spool_set.put(spool_id2, alice_keypair.public).unwrap();
+        let remote_pub_key = spool_set.get_public_key(spool_id2).unwrap();
+        assert_eq!(remote_pub_key, alice_keypair.public);
+
+        let mut spool_id3 = [0u8; SPOOL_ID_SIZE];
+        csprng.fill(&mut spool_id3);
+        spool_set.put(spool_id3, alice_keypair.public).unwrap();
+
+        //let mut ids = vec![];
+        let mut map: HashMap<[u8; SPOOL_ID_SIZE], bool> = HashMap::new();
+        for key in spool_set.keys() {
+            let mut id = [0u8; SPOOL_ID_SIZE];
+            id[..].clone_from_slice(&key.unwrap());
+            map.insert(id, true);
+        }
+        assert!(map.contains_key(&spool_id2));
+        assert!(map.contains_key(&spool_id3));
+    }
+
+    #[test]
+    fn spoolset_check_writable_test() {
+        let base_dir = tempdir().unwrap();
+        let set_path = Path::new(base_dir.path()).join("spool_set.sled");
+        let spool_set = SpoolSet::new(&set_path).unwrap();
+        spool_set.check_writable().unwrap();
+        assert_eq!(spool_set.keys().count(), 0);
+    }
+
+    #[test]
+    fn seeded_rng_test() {
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let mut spool_ids = vec![];
+        for _ in 0..2 {
+            let dir = tempdir().unwrap();
+            let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+            multi_spool.seed_rng(1234);
+            spool_ids.push(multi_spool.allocate_spool(alice_keypair.public, alice_signature).unwrap());
+            spool_ids.push(multi_spool.allocate_spool(alice_keypair.public, alice_signature).unwrap());
+        }
+        assert_eq!(spool_ids[..2], spool_ids[2..]);
+        assert_ne!(spool_ids[0], spool_ids[1]);
+    }
+
+    #[test]
+    fn simple_multi_spool_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+
+        let message1 = [0u8; MESSAGE_SIZE];
+        multi_spool.append_to_spool(spool_id, &message1).unwrap();
+        let mut message_id = [0u8; MESSAGE_ID_SIZE];
+        BigEndian::write_u32(&mut message_id, 0);
+        let read_message1 = multi_spool.read_from_spool(spool_id, alice_signature, &message_id).unwrap();
+        assert_eq!(message1[..], read_message1[..]);
+
+        multi_spool.append_to_spool(spool_id, &message1).unwrap();
+        BigEndian::write_u32(&mut message_id, 1);
+        let read_message1 = multi_spool.read_from_spool(spool_id, alice_signature, &message_id).unwrap();
+        assert_eq!(message1[..], read_message1[..]);
+
+        assert!(multi_spool.append_to_spool(spool_id, &vec![0u8; MESSAGE_SIZE + 1]).is_err());
+        multi_spool.set_max_message_size(MESSAGE_SIZE * 2);
+        multi_spool.append_to_spool(spool_id, &vec![0u8; MESSAGE_SIZE + 1]).unwrap();
+        assert_eq!(multi_spool.max_message_size(), MESSAGE_SIZE * 2);
+    }
+
+    #[test]
+    fn orphan_collection_test() {
+        let dir = tempdir().unwrap();
+        let base_dir = String::from(dir.path().to_str().unwrap());
+        let mut multi_spool = MultiSpool::new(&base_dir).unwrap();
+        let mut csprng = thread_rng();
+        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let signature = keypair.sign(&keypair.public.to_bytes());
+        let spool_id = multi_spool.create_spool(keypair.public, signature, &mut csprng).unwrap();
+
+        // A database left behind by a crash belongs to no spool.
+        let orphan = spool_path(&base_dir, [0xffu8; SPOOL_ID_SIZE]);
+        Spool::new(&orphan).unwrap().flush().unwrap();
+        assert_eq!(multi_spool.orphans().unwrap(), vec![orphan.clone()]);
+        assert_eq!(multi_spool.collect_orphans().unwrap(), 1);
+        assert!(!orphan.exists());
+        assert!(multi_spool.orphans().unwrap().is_empty());
+        assert!(spool_path(&base_dir, spool_id).exists());
+
+        // The database of a spool being created is not an orphan.
+        let creating = [0xfeu8; SPOOL_ID_SIZE];
+        multi_spool.spool_set.put(creating, keypair.public).unwrap();
+        Spool::new(&spool_path(&base_dir, creating)).unwrap().flush().unwrap();
+        assert!(multi_spool.orphans().unwrap().is_empty());
+        multi_spool.spool_set.delete(creating).unwrap();
+        remove_db(&spool_path(&base_dir, creating)).unwrap();
+
+        // Orphans are kept on startup, for an operator to remove.
+        Spool::new(&orphan).unwrap().flush().unwrap();
+        multi_spool.close().unwrap();
+        drop(multi_spool);
+        let mut multi_spool = MultiSpool::new(&base_dir).unwrap();
+        assert!(orphan.exists());
+        assert_eq!(multi_spool.spool_ids(), vec![spool_id]);
+        assert_eq!(multi_spool.orphans().unwrap(), vec![orphan.clone()]);
+
+        // Without the spool set every database looks orphaned, so
+        // starting is refused and they are all kept.
+        multi_spool.close().unwrap();
+        drop(multi_spool);
+        remove_db(&spool_set_path(&base_dir)).unwrap();
+        match MultiSpool::new(&base_dir) {
+            Err(MultiSpoolError::LostSpoolSet) => {},
+            _ => panic!("expected starting without the spool set to be refused"),
+        }
+        assert!(orphan.exists());
+        assert!(spool_path(&base_dir, spool_id).exists());
+    }
+
+    #[test]
+    fn journal_recovery_test() {
+        let dir = tempdir().unwrap();
+        let base_dir = String::from(dir.path().to_str().unwrap());
+        let mut multi_spool = MultiSpool::new(&base_dir).unwrap();
+        let mut csprng = thread_rng();
+        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let signature = keypair.sign(&keypair.public.to_bytes());
+        let kept = multi_spool.create_spool(keypair.public, signature, &mut csprng).unwrap();
+        let deleted = multi_spool.create_spool(keypair.public, signature, &mut csprng).unwrap();
+        assert!(multi_spool.spool_set.pending().unwrap().is_empty());
+
+        // Deleting a spool for good removes its database.
+        multi_spool.force_purge_spool(deleted).unwrap();
+        assert!(!spool_path(&base_dir, deleted).exists());
+
+        // A crash halfway through creating a spool.
+        let created = [7u8; SPOOL_ID_SIZE];
+        multi_spool.spool_set.begin(created, Intent::Create).unwrap();
+        multi_spool.spool_set.put(created, keypair.public).unwrap();
+        Spool::new(&spool_path(&base_dir, created)).unwrap().flush().unwrap();
+        assert_eq!(multi_spool.spool_set.pending().unwrap(), vec![(created, Intent::Create)]);
+        multi_spool.close().unwrap();
+        drop(multi_spool);
+
+        let multi_spool = MultiSpool::new(&base_dir).unwrap();
+        assert_eq!(multi_spool.spool_ids(), vec![kept]);
+        assert!(!spool_path(&base_dir, created).exists());
+        assert!(multi_spool.spool_set.pending().unwrap().is_empty());
+        let entries = multi_spool.audit_log().entries(None, 1).unwrap();
+        assert_eq!(entries[0].1.action, "recover");
+        assert_eq!(entries[0].1.spool_id, created.to_vec());
+    }
+
+    #[test]
+    fn multi_spool_key_rotation_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let old_key = || MasterKey::from_bytes(&[1u8; KEY_SIZE]).unwrap();
+        let new_key = || MasterKey::from_bytes(&[2u8; KEY_SIZE]).unwrap();
+
+        // A plaintext message, then one encrypted with the spool's key.
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        multi_spool.append_to_spool(spool_id, b"plaintext").unwrap();
+        multi_spool.set_keyring(Some(Arc::new(Keyring::new(old_key(), vec![])))).unwrap();
+        multi_spool.append_to_spool(spool_id, b"spool key").unwrap();
+        assert_eq!(multi_spool.reencrypt().unwrap(), 1);
+        assert_eq!(multi_spool.reencrypt().unwrap(), 0);
+
+        // Rotating re-encrypts the spool secret and owner key, after
+        // which the old key can be retired.
+        multi_spool.set_keyring(Some(Arc::new(Keyring::new(new_key(), vec![old_key()])))).unwrap();
+        multi_spool.set_keyring(Some(Arc::new(Keyring::new(new_key(), vec![])))).unwrap();
+        let mut message_id = [0u8; MESSAGE_ID_SIZE];
+        assert_eq!(multi_spool.read_from_spool(spool_id, alice_signature, &message_id).unwrap(), b"plaintext".to_vec());
+        BigEndian::write_u32(&mut message_id, 1);
+        assert_eq!(multi_spool.read_from_spool(spool_id, alice_signature, &message_id).unwrap(), b"spool key".to_vec());
+
+        // Purging destroys the spool's secret.
+        assert!(multi_spool.spool_set.secrets.contains_key(spool_id.to_vec()).unwrap());
+        multi_spool.force_purge_spool(spool_id).unwrap();
+        assert!(!multi_spool.spool_set.secrets.contains_key(spool_id.to_vec()).unwrap());
+    }
+
+    #[test]
+    fn read_proof_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let identity = Arc::new(Keypair::generate(&mut csprng));
+        multi_spool.set_identity(Some(identity.clone()));
+
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        for i in 0..5u8 {
+            multi_spool.append_to_spool(spool_id, &[i; 10]).unwrap();
+        }
+        let mut message_id = [0u8; MESSAGE_ID_SIZE];
+        BigEndian::write_u32(&mut message_id, 3);
+        let proof = multi_spool.read_proof(spool_id, &message_id).unwrap();
+        assert_eq!(proof.TreeSize, 5);
+        assert!(proof.verify(&spool_id, 3, &[3u8; 10], &identity.public));
+        assert!(!proof.verify(&spool_id, 3, &[4u8; 10], &identity.public));
+        assert!(!proof.verify(&spool_id, 3, &[3u8; 10], &alice_keypair.public));
+
+        BigEndian::write_u32(&mut message_id, 5);
+        assert!(multi_spool.read_proof(spool_id, &message_id).is_err());
+    }
+
+    #[test]
+    fn append_token_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        multi_spool.append_with_token(spool_id, b"no token needed", &[]).unwrap();
+
+        let key = TokenKey::generate();
+        multi_spool.set_append_token_key(spool_id, alice_signature, Some(TokenKey::from_bytes(&key.to_bytes()).unwrap())).unwrap();
+        let blinded = BlindedToken::new(&spool_id);
+        let token = blinded.unblind(&key.sign_blinded(&blinded.blinded()).unwrap()).unwrap();
+        match multi_spool.append_with_token(spool_id, b"hello", &[]) {
+            Err(MultiSpoolError::InvalidAppendToken) => {},
+            _ => panic!("expected an invalid append token"),
+        }
+        multi_spool.append_with_token(spool_id, b"hello", &token).unwrap();
+        match multi_spool.append_with_token(spool_id, b"hello again", &token) {
+            Err(MultiSpoolError::SpentAppendToken) => {},
+            _ => panic!("expected a spent append token"),
+        }
+        assert_eq!(multi_spool.spool_info(spool_id).unwrap().message_count, 2);
+
+        multi_spool.set_append_token_key(spool_id, alice_signature, None).unwrap();
+        multi_spool.append_with_token(spool_id, b"no token needed", &[]).unwrap();
+    }
+
+    #[test]
+    fn appenders_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let writer = Keypair::generate(&mut csprng);
+        let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        multi_spool.append_with_token(spool_id, b"anyone may append", &[]).unwrap();
+
+        let appenders = AppenderList::new(vec![writer.public]).unwrap();
+        multi_spool.set_appenders(spool_id, alice_signature, Some(appenders)).unwrap();
+        match multi_spool.append_with_token(spool_id, b"hello", &[]) {
+            Err(MultiSpoolError::AppendNotAllowed) => {},
+            _ => panic!("expected the append to be refused"),
+        }
+        let public_key = writer.public.to_bytes();
+        let signature = sign_append(&writer, &spool_id, b"hello").to_bytes();
+        let auth = AppendAuth {
+            public_key: &public_key,
+            signature: &signature,
+            ..AppendAuth::default()
+        };
+        multi_spool.append_authorized(spool_id, b"hello", &auth).unwrap();
+        match multi_spool.append_authorized(spool_id, b"hullo", &auth) {
+            Err(MultiSpoolError::AppendNotAllowed) => {},
+            _ => panic!("expected the append to be refused"),
+        }
+        assert_eq!(multi_spool.spool_info(spool_id).unwrap().message_count, 2);
+        let archive = multi_spool.export_spool(spool_id).unwrap();
+        assert_eq!(archive.appenders().unwrap(), Some(AppenderList::new(vec![writer.public]).unwrap()));
+
+        multi_spool.set_appenders(spool_id, alice_signature, None).unwrap();
+        multi_spool.append_with_token(spool_id, b"anyone may append", &[]).unwrap();
+    }
+
+    #[test]
+    fn create_with_options_test() {
+        let dir = tempdir().unwrap();
+        let base_dir = String::from(dir.path().to_str().unwrap());
+        let multi_spool = MultiSpool::new(&base_dir).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let bob_keypair: Keypair = Keypair::generate(&mut csprng);
+        let bob_signature = bob_keypair.sign(&bob_keypair.public.to_bytes());
+
+        let options = SpoolOptions {
+            Capacity: 2,
+            TTL: 3600,
+            Readers: vec![ByteBuf::from(bob_keypair.public.to_bytes().to_vec())],
+            ..SpoolOptions::default()
+        };
+        let full_id = multi_spool.create_with_options(alice_keypair.public, alice_signature, &options, &mut csprng).unwrap();
+        multi_spool.append_with_token(full_id, b"one", &[]).unwrap();
+        multi_spool.append_with_token(full_id, b"two", &[]).unwrap();
+        match multi_spool.append_with_token(full_id, b"six", &[]) {
+            Err(MultiSpoolError::SpoolFull) => {},
+            _ => panic!("expected the spool to be full"),
+        }
+        assert_eq!(multi_spool.read_as(full_id, &bob_keypair.public, bob_signature, &[0, 0, 0, 1]).unwrap().0, b"two".to_vec());
+        let stranger = Keypair::generate(&mut csprng);
+        match multi_spool.read_as(full_id, &stranger.public, stranger.sign(&stranger.public.to_bytes()), &[0, 0, 0, 1]) {
+            Err(MultiSpoolError::NotReader) => {},
+            _ => panic!("expected the read to be refused"),
+        }
+        assert_eq!(multi_spool.expire_spools().unwrap(), 0);
+
+        let options = SpoolOptions {
+            Capacity: 2,
+            Circular: true,
+            ..SpoolOptions::default()
+        };
+        let circular_id = multi_spool.create_with_options(alice_keypair.public, alice_signature, &options, &mut csprng).unwrap();
+        for message in &[b"one", b"two", b"six"] {
+            multi_spool.append_with_token(circular_id, *message, &[]).unwrap();
+        }
+        assert!(multi_spool.read_from_spool(circular_id, alice_signature, &[0, 0, 0, 0]).is_err());
+        assert_eq!(multi_spool.read_from_spool(circular_id, alice_signature, &[0, 0, 0, 2]).unwrap(), b"six".to_vec());
+        multi_spool.verify_spool(circular_id).unwrap();
+
+        // Policies outlive restarts.
+        drop(multi_spool);
+        let multi_spool = MultiSpool::new(&base_dir).unwrap();
+        assert!(multi_spool.policy(full_id).unwrap().expires_at.is_some());
+        assert!(multi_spool.policy(circular_id).unwrap().circular);
+        assert!(multi_spool.create_with_options(alice_keypair.public, alice_signature, &SpoolOptions {
+            Circular: true,
+            ..SpoolOptions::default()
+        }, &mut csprng).is_err());
+    }
+
+    #[test]
+    fn create_or_get_test() {
+        let dir = tempdir().unwrap();
+        let base_dir = String::from(dir.path().to_str().unwrap());
+        let multi_spool = MultiSpool::new(&base_dir).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let bob_keypair: Keypair = Keypair::generate(&mut csprng);
+
+        let (spool_id, created) = multi_spool.create_or_get(alice_keypair.public, alice_signature, &SpoolOptions::default()).unwrap();
+        assert!(created);
+        assert_eq!(multi_spool.create_or_get(alice_keypair.public, alice_signature, &SpoolOptions::default()).unwrap(), (spool_id, false));
+        assert!(multi_spool.create_or_get(alice_keypair.public, bob_keypair.sign(&alice_keypair.public.to_bytes()), &SpoolOptions::default()).is_err());
+        assert_eq!(multi_spool.spool_count(), 1);
+
+        drop(multi_spool);
+        let multi_spool = MultiSpool::new(&base_dir).unwrap();
+        assert_eq!(multi_spool.create_or_get(alice_keypair.public, alice_signature, &SpoolOptions::default()).unwrap(), (spool_id, false));
+
+        // Purged spools are passed over.
+        multi_spool.purge_spool(spool_id, alice_signature).unwrap();
+        let (new_spool_id, created) = multi_spool.create_or_get(alice_keypair.public, alice_signature, &SpoolOptions::default()).unwrap();
+        assert!(created);
+        assert_ne!(new_spool_id, spool_id);
+
+        // Options are checked first, and must match the spool's.
+        let invalid = SpoolOptions { Circular: true, ..SpoolOptions::default() };
+        match multi_spool.create_or_get(alice_keypair.public, alice_signature, &invalid) {
+            Err(MultiSpoolError::OptionsError(_)) => {},
+            _ => panic!("expected the options to be refused"),
+        }
+        let capped = SpoolOptions { Capacity: 10, ..SpoolOptions::default() };
+        match multi_spool.create_or_get(alice_keypair.public, alice_signature, &capped) {
+            Err(MultiSpoolError::OptionsMismatch) => {},
+            _ => panic!("expected the options mismatch to be refused"),
+        }
+        let (ttl_spool_id, created) = multi_spool.create_or_get(bob_keypair.public, bob_keypair.sign(&bob_keypair.public.to_bytes()), &SpoolOptions { TTL: 3600, ..SpoolOptions::default() }).unwrap();
+        assert!(created);
+        assert_eq!(multi_spool.create_or_get(bob_keypair.public, bob_keypair.sign(&bob_keypair.public.to_bytes()), &SpoolOptions { TTL: 3600, ..SpoolOptions::default() }).unwrap(), (ttl_spool_id, false));
+        assert!(multi_spool.create_or_get(bob_keypair.public, bob_keypair.sign(&bob_keypair.public.to_bytes()), &SpoolOptions { TTL: 60, ..SpoolOptions::default() }).is_err());
+
+        // Of several spools the oldest is returned, then the lowest ID.
+        let other_spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        let oldest = [new_spool_id, other_spool_id].iter()
+            .map(|x| (multi_spool.spool_info(*x).unwrap().activity.created_at, *x))
+            .min().unwrap().1;
+        for _ in 0..2 {
+            assert_eq!(multi_spool.create_or_get(alice_keypair.public, alice_signature, &SpoolOptions::default()).unwrap(), (oldest, false));
+        }
+    }
+
+    #[test]
+    fn spool_activity_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let before = unix_time();
+        let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        let activity = multi_spool.spool_info(spool_id).unwrap().activity;
+        assert!(activity.created_at.unwrap() >= before);
+        assert_eq!(activity.last_append, None);
+        assert_eq!(activity.last_read, None);
+
+        multi_spool.append_with_token(spool_id, b"hello", &[]).unwrap();
+        multi_spool.read_from_spool(spool_id, alice_signature, &[0u8; MESSAGE_ID_SIZE]).unwrap();
+        let activity = multi_spool.spool_info(spool_id).unwrap().activity;
+        assert!(activity.last_append.unwrap() >= activity.created_at.unwrap());
+        assert!(activity.last_read.unwrap() >= activity.last_append.unwrap());
+        let stats = multi_spool.spool_status(spool_id, alice_signature).unwrap();
+        assert_eq!(stats.MessageCount, 1);
+        assert_eq!(stats.Head, 1);
+        assert_eq!(stats.FirstMessage, 0);
+        assert_eq!(stats.LastRead, activity.last_read);
+        assert_eq!(stats.Capacity, 0);
+        let bob_keypair: Keypair = Keypair::generate(&mut csprng);
+        assert!(multi_spool.spool_status(spool_id, bob_keypair.sign(&bob_keypair.public.to_bytes())).is_err());
+
+        // Failed reads aren't activity.
+        multi_spool.spool_set.touch(spool_id, Activity::Read, 1).unwrap();
+        assert!(multi_spool.read_from_spool(spool_id, alice_signature, &[0, 0, 0, 1]).is_err());
+        assert_eq!(multi_spool.spool_info(spool_id).unwrap().activity.last_read, Some(1));
+
+        multi_spool.force_purge_spool(spool_id).unwrap();
+        assert_eq!(multi_spool.spool_set.activity(spool_id).unwrap(), SpoolActivity::default());
+    }
+
+    #[test]
+    fn copy_spool_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let options = SpoolOptions {
+            Capacity: 2,
+            Circular: true,
+            ..SpoolOptions::default()
+        };
+        let spool_id = multi_spool.create_with_options(alice_keypair.public, alice_signature, &options, &mut csprng).unwrap();
+        for message in &[b"one", b"two", b"six"] {
+            multi_spool.append_to_spool(spool_id, *message).unwrap();
+        }
+
+        let bob_keypair: Keypair = Keypair::generate(&mut csprng);
+        let bob_signature = bob_keypair.sign(&bob_keypair.public.to_bytes());
+        assert!(multi_spool.copy_spool(spool_id, bob_signature, None).is_err());
+        let copy_id = multi_spool.copy_spool(spool_id, alice_signature, Some(bob_keypair.public)).unwrap();
+        assert_ne!(copy_id, spool_id);
+        assert_eq!(multi_spool.spool_count(), 2);
+
+        // The copy keeps the message IDs, and is the new key's.
+        assert!(multi_spool.read_from_spool(copy_id, bob_signature, &[0, 0, 0, 0]).is_err());
+        assert_eq!(multi_spool.read_from_spool(copy_id, bob_signature, &[0, 0, 0, 2]).unwrap(), b"six".to_vec());
+        assert!(multi_spool.read_from_spool(copy_id, alice_signature, &[0, 0, 0, 2]).is_err());
+        assert_eq!(multi_spool.policy(copy_id), multi_spool.policy(spool_id));
+        multi_spool.append_to_spool(copy_id, b"ten").unwrap();
+        assert!(multi_spool.read_from_spool(copy_id, bob_signature, &[0, 0, 0, 1]).is_err());
+
+        // The original is left as it was.
+        assert_eq!(multi_spool.read_from_spool(spool_id, alice_signature, &[0, 0, 0, 1]).unwrap(), b"two".to_vec());
+        assert_eq!(multi_spool.message_count(spool_id).unwrap(), 3);
+    }
+
+    #[test]
+    fn rekey_spool_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let keyring = || Some(Arc::new(Keyring::new(MasterKey::from_bytes(&[1u8; KEY_SIZE]).unwrap(), vec![])));
+        multi_spool.set_keyring(keyring()).unwrap();
+        let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        multi_spool.append_to_spool(spool_id, b"hello").unwrap();
+
+        // The new key must agree to take the spool over.
+        let device_keypair: Keypair = Keypair::generate(&mut csprng);
+        let device_signature = device_keypair.sign(&device_keypair.public.to_bytes());
+        let forged = Rekey::sign(&device_keypair, &[0u8; SPOOL_ID_SIZE], &alice_keypair.public);
+        match multi_spool.rekey_spool(spool_id, alice_signature, &forged) {
+            Err(MultiSpoolError::InvalidRekey) => {},
+            _ => panic!("expected the rekey to be refused"),
+        }
+        let rekey = Rekey::sign(&device_keypair, &spool_id, &alice_keypair.public);
+        assert!(multi_spool.rekey_spool(spool_id, device_signature, &rekey).is_err());
+        multi_spool.rekey_spool(spool_id, alice_signature, &rekey).unwrap();
+
+        assert_eq!(multi_spool.read_from_spool(spool_id, device_signature, &[0u8; MESSAGE_ID_SIZE]).unwrap(), b"hello".to_vec());
+        assert!(multi_spool.read_from_spool(spool_id, alice_signature, &[0u8; MESSAGE_ID_SIZE]).is_err());
+        assert_eq!(multi_spool.owned_spool(&device_keypair.public).unwrap().map(|x| x.0), Some(spool_id));
+        assert_eq!(multi_spool.owned_spool(&alice_keypair.public).unwrap(), None);
+        // The spool's key is still derived from the first owner's.
+        multi_spool.set_keyring(keyring()).unwrap();
+        assert_eq!(multi_spool.read_from_spool(spool_id, device_signature, &[0u8; MESSAGE_ID_SIZE]).unwrap(), b"hello".to_vec());
+        // Replaying the handover to the old key fails, as it's signed
+        // for the old owner.
+        assert!(multi_spool.rekey_spool(spool_id, device_signature, &rekey).is_err());
+    }
+
+    #[test]
+    fn read_by_hash_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let plain_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        multi_spool.append_to_spool(plain_id, b"one").unwrap();
+        match multi_spool.read_by_hash(plain_id, &alice_keypair.public, alice_signature, &merkle::leaf_hash(b"one")) {
+            Err(MultiSpoolError::NoHashIndex) => {},
+            _ => panic!("expected the spool to have no hash index"),
+        }
+
+        let options = SpoolOptions {
+            Capacity: 3,
+            Circular: true,
+            HashIndex: true,
+            ..SpoolOptions::default()
         };
-        Ok(spool)
+        let spool_id = multi_spool.create_with_options(alice_keypair.public, alice_signature, &options, &mut csprng).unwrap();
+        for message in &[b"one", b"two", b"one"] {
+            multi_spool.append_to_spool(spool_id, *message).unwrap();
+        }
+        let (message_id, message, _) = multi_spool.read_by_hash(spool_id, &alice_keypair.public, alice_signature, &merkle::leaf_hash(b"one")).unwrap();
+        assert_eq!((message_id, message), ([0, 0, 0, 2], b"one".to_vec()));
+        assert!(multi_spool.read_by_hash(spool_id, &alice_keypair.public, alice_signature, &merkle::leaf_hash(b"six")).is_err());
+
+        // Turning encryption on keys the index, and trimmed messages
+        // are dropped from it.
+        multi_spool.set_keyring(Some(Arc::new(Keyring::new(MasterKey::from_bytes(&[1u8; KEY_SIZE]).unwrap(), vec![])))).unwrap();
+        assert_eq!(multi_spool.read_by_hash(spool_id, &alice_keypair.public, alice_signature, &merkle::leaf_hash(b"two")).unwrap().0, [0, 0, 0, 1]);
+        multi_spool.append_to_spool(spool_id, b"six").unwrap();
+        multi_spool.append_to_spool(spool_id, b"ten").unwrap();
+        assert!(multi_spool.read_by_hash(spool_id, &alice_keypair.public, alice_signature, &merkle::leaf_hash(b"two")).is_err());
+        assert_eq!(multi_spool.read_by_hash(spool_id, &alice_keypair.public, alice_signature, &merkle::leaf_hash(b"one")).unwrap().0, [0, 0, 0, 2]);
+        assert_eq!(multi_spool.read_by_hash(spool_id, &alice_keypair.public, alice_signature, &merkle::leaf_hash(b"ten")).unwrap().0, [0, 0, 0, 4]);
     }
 
-    fn get_spool(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<&Spool, MultiSpoolError> {
-        if let Some(spool) = self.map.get(&spool_id) {
-            return Ok(spool)
+    #[test]
+    fn read_latest_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        match multi_spool.read_latest(spool_id, &alice_keypair.public, alice_signature, 0) {
+            Err(MultiSpoolError::SpoolError(SpoolError::NoSuchMessage)) => {},
+            _ => panic!("expected no message in an empty spool"),
         }
-        Err(MultiSpoolError::NoSuchSpool)
-    }
 
-    pub fn create_spool<T>(&mut self,
-                           public_key: PublicKey,
-                           signature: Signature,
-                           csprng: &mut T)
-                           -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError>
-    where
-        T: CryptoRng + Rng,
-    {
-        public_key.verify(&public_key.to_bytes(), &signature)?;
-        let mut spool_id = [0u8; SPOOL_ID_SIZE];
-        csprng.fill_bytes(&mut spool_id);
-        let spool_path = spool_path(&self.base_dir, spool_id);
-        self.spool_set.put(spool_id, public_key)?;
-        self.map.insert(spool_id, Spool::new(&spool_path)?);
-        Ok(spool_id)
+        for message in &[b"one", b"two", b"six"] {
+            multi_spool.append_to_spool(spool_id, *message).unwrap();
+        }
+        let (message_id, message, _) = multi_spool.read_latest(spool_id, &alice_keypair.public, alice_signature, 0).unwrap();
+        assert_eq!((message_id, message), ([0, 0, 0, 2], b"six".to_vec()));
+        assert_eq!(multi_spool.read_latest(spool_id, &alice_keypair.public, alice_signature, 2).unwrap().1, b"one".to_vec());
+        assert!(multi_spool.read_latest(spool_id, &alice_keypair.public, alice_signature, 3).is_err());
+
+        let mallory_keypair: Keypair = Keypair::generate(&mut csprng);
+        let mallory_signature = mallory_keypair.sign(&mallory_keypair.public.to_bytes());
+        assert!(multi_spool.read_latest(spool_id, &mallory_keypair.public, mallory_signature, 0).is_err());
     }
 
-    pub fn purge_spool(&mut self, spool_id: [u8; SPOOL_ID_SIZE], signature: Signature) -> Result<(), MultiSpoolError> {
-        let pub_key = self.spool_set.get_public_key(spool_id)?;
-        pub_key.verify(&pub_key.to_bytes(), &signature)?;
-        {
-            let spool = self.get_mut_spool(spool_id)?;
-            spool.purge()?;
+    #[test]
+    fn storage_exhausted_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+
+        multi_spool.set_min_free_bytes(u64::max_value());
+        match multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng) {
+            Err(MultiSpoolError::StorageExhausted) => {},
+            _ => panic!("expected the storage to be exhausted"),
         }
-        self.spool_set.delete(spool_id)?;
-        self.map.remove(&spool_id);
-        Ok(())
-    }
+        match multi_spool.append_to_spool(spool_id, b"hello") {
+            Err(MultiSpoolError::StorageExhausted) => {},
+            _ => panic!("expected the storage to be exhausted"),
+        }
+        assert_eq!(multi_spool.spool_count(), 1);
+        multi_spool.purge_spool(spool_id, alice_signature).unwrap();
 
-    pub fn append_to_spool(&mut self,
-                           spool_id: [u8; SPOOL_ID_SIZE],
-                           message: [u8; MESSAGE_SIZE])
-                           -> Result<(), MultiSpoolError> {
-        let spool = self.get_mut_spool(spool_id)?;
-        spool.append(message)?;
-        return Ok(())
+        multi_spool.set_min_free_bytes(0);
+        multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
     }
 
-    pub fn read_from_spool(&self,
-                           spool_id: [u8; SPOOL_ID_SIZE],
-                           signature: Signature,
-                           message_id: &[u8; MESSAGE_ID_SIZE])
-                           -> Result<[u8; MESSAGE_SIZE], MultiSpoolError> {
-        let pub_key = self.spool_set.get_public_key(spool_id)?;
-        pub_key.verify(&pub_key.to_bytes(), &signature)?;
-        Ok(self.get_spool(spool_id)?.read(message_id)?)
+    #[test]
+    fn purge_inactive_spools_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let idle_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        let busy_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        let legacy_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        multi_spool.spool_set.touch(idle_id, Activity::Created, 1).unwrap();
+        multi_spool.spool_set.touch(busy_id, Activity::Created, 1).unwrap();
+        multi_spool.append_with_token(busy_id, b"hello", &[]).unwrap();
+        // Spools from before activity was kept have no record of it.
+        multi_spool.spool_set.activity.del(activity_key(legacy_id, Activity::Created)).unwrap();
+
+        assert_eq!(multi_spool.purge_inactive_spools().unwrap(), 0);
+        multi_spool.set_inactive_period(Some(Duration::from_secs(60)));
+        assert_eq!(multi_spool.purge_inactive_spools().unwrap(), 1);
+        assert!(multi_spool.purged_at(idle_id).is_some());
+        assert_eq!(multi_spool.purged_at(busy_id), None);
+        assert_eq!(multi_spool.purged_at(legacy_id), None);
+        assert_eq!(multi_spool.purge_inactive_spools().unwrap(), 0);
+
+        multi_spool.undelete_spool(idle_id, alice_signature).unwrap();
+        multi_spool.set_purge_grace_period(Duration::from_secs(0));
+        assert_eq!(multi_spool.purge_inactive_spools().unwrap(), 1);
+        assert!(multi_spool.spool_info(idle_id).is_err());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    extern crate rand;
-    extern crate tempfile;
+    #[test]
+    fn append_idempotent_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
 
-    use std::assert_eq;
-    use std::thread;
-    use rand::rngs::OsRng;
-    use rand::{seq::SliceRandom, thread_rng};
-    use rand::CryptoRng;
-    use rand::Rng;
-    use ed25519_dalek::Keypair;
-    use ed25519_dalek::Signature;
-    use self::tempfile::tempdir;
-    use super::*;
+        assert!(multi_spool.append_idempotent(spool_id, b"hello", &AppendAuth::default(), b"key one", false).unwrap());
+        assert!(!multi_spool.append_idempotent(spool_id, b"hello", &AppendAuth::default(), b"key one", false).unwrap());
+        assert!(multi_spool.append_idempotent(spool_id, b"hello", &AppendAuth::default(), b"key two", false).unwrap());
+        assert!(multi_spool.append_idempotent(spool_id, b"hello", &AppendAuth::default(), &[], false).unwrap());
+        assert!(multi_spool.append_idempotent(spool_id, b"hello", &AppendAuth::default(), &[], true).unwrap());
+        assert_eq!(multi_spool.spool_info(spool_id).unwrap().message_count, 4);
+        match multi_spool.append_idempotent(spool_id, b"hello", &AppendAuth::default(), &[0u8; MAX_IDEMPOTENCY_KEY_SIZE + 1], false) {
+            Err(MultiSpoolError::InvalidIdempotencyKey) => {},
+            _ => panic!("expected an invalid idempotency key"),
+        }
+
+        // Appends are authorized before their key is looked up, so
+        // a stranger can't learn which keys were seen.
+        let writer = Keypair::generate(&mut csprng);
+        let options = SpoolOptions {
+            Appenders: vec![ByteBuf::from(writer.public.to_bytes().to_vec())],
+            ..SpoolOptions::default()
+        };
+        let acl_id = multi_spool.create_with_options(alice_keypair.public, alice_signature, &options, &mut csprng).unwrap();
+        let public_key = writer.public.to_bytes();
+        let append_signature = sign_append(&writer, &acl_id, b"hello").to_bytes();
+        let auth = AppendAuth {
+            public_key: &public_key,
+            signature: &append_signature,
+            token: &[],
+        };
+        assert!(multi_spool.append_idempotent(acl_id, b"hello", &auth, b"key one", false).unwrap());
+        match multi_spool.append_idempotent(acl_id, b"hello", &AppendAuth::default(), b"key one", false) {
+            Err(MultiSpoolError::AppendNotAllowed) => {},
+            _ => panic!("expected the unsigned retry to be refused"),
+        }
+        assert!(!multi_spool.append_idempotent(acl_id, b"hello", &auth, b"key one", false).unwrap());
 
+        multi_spool.purge_spool(spool_id, alice_signature).unwrap();
+        assert!(multi_spool.append_idempotent(spool_id, b"hello", &AppendAuth::default(), b"key one", false).is_err());
+    }
 
     #[test]
-    fn spool_append_read_test() {
+    fn append_dedup_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
         let mut csprng = thread_rng();
-        let base_dir = tempdir().unwrap();
-        let mut spool_id = [0u8; SPOOL_ID_SIZE];
-        csprng.fill(&mut spool_id);
-        let path = Path::new(base_dir.path()).join(format!("spool.{}.sled", base64::encode(&spool_id)));
-        let pathbuf: PathBuf = path.to_owned();
-        let mut spool = Spool::new(&pathbuf).unwrap();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let options = SpoolOptions {
+            DedupWindow: 2,
+            ..SpoolOptions::default()
+        };
+        let spool_id = multi_spool.create_with_options(alice_keypair.public, alice_signature, &options, &mut csprng).unwrap();
 
-        // message 1
-        let mut message1 = [0u8; MESSAGE_SIZE];
-        csprng.fill(&mut message1[..]);
-        spool.append(message1).unwrap();
+        let append = |message: &[u8]| multi_spool.append_idempotent(spool_id, message, &AppendAuth::default(), &[], false).unwrap();
+        assert!(append(b"one"));
+        assert!(!append(b"one"));
+        assert!(append(b"two"));
+        assert!(append(b"six"));
+        // "one" is no longer among the last two messages.
+        assert!(append(b"one"));
+        assert!(!append(b"one"));
+        assert!(!append(b"six"));
+        assert_eq!(multi_spool.spool_info(spool_id).unwrap().message_count, 4);
 
-        let mut message_id = [0u8; MESSAGE_ID_SIZE];
-        BigEndian::write_u32(&mut message_id, 0);
-        let read_message1 = spool.read(&message_id).unwrap();
-        assert_eq!(message1[..], read_message1[..]);
+        // The index kept for it doesn't make the spool readable by hash.
+        match multi_spool.read_by_hash(spool_id, &alice_keypair.public, alice_signature, &merkle::leaf_hash(b"one")) {
+            Err(MultiSpoolError::NoHashIndex) => {},
+            _ => panic!("expected the spool to have no hash index"),
+        }
+    }
 
-        // message 2
-        let mut message2 = [0u8; MESSAGE_SIZE];
-        csprng.fill(&mut message2[..]);
-        spool.append(message2).unwrap();
+    #[test]
+    fn export_import_test() {
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let old_dir = tempdir().unwrap();
+        let old_spool = MultiSpool::new(&String::from(old_dir.path().to_str().unwrap())).unwrap();
+        let spool_id = old_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        old_spool.append_to_spool(spool_id, b"hello").unwrap();
+        old_spool.append_to_spool(spool_id, b"world").unwrap();
+        let token_key = TokenKey::generate();
+        let token_key_bytes = token_key.to_bytes();
+        old_spool.set_append_token_key(spool_id, alice_signature, Some(token_key)).unwrap();
+        old_spool.with_spool(spool_id, |spool| Ok(spool.spend_token(b"spent")?)).unwrap();
+        let archive = SpoolArchive::from_bytes(&old_spool.export_spool(spool_id).unwrap().to_bytes().unwrap()).unwrap();
+        assert_eq!(archive.messages.len(), 2);
 
+        // The spool carries on under the same ID with another provider.
+        let new_dir = tempdir().unwrap();
+        let mut new_spool = MultiSpool::new(&String::from(new_dir.path().to_str().unwrap())).unwrap();
+        assert_eq!(new_spool.import_spool(&archive).unwrap(), spool_id);
         let mut message_id = [0u8; MESSAGE_ID_SIZE];
         BigEndian::write_u32(&mut message_id, 1);
-        let read_message2 = spool.read(&message_id).unwrap();
-        assert_eq!(message2[..], read_message2[..]);
+        assert_eq!(new_spool.read_from_spool(spool_id, alice_signature, &message_id).unwrap(), b"world".to_vec());
+        new_spool.with_spool(spool_id, |spool| {
+            assert_eq!(spool.append_token_key().unwrap().unwrap().to_bytes(), token_key_bytes);
+            assert!(spool.is_token_spent(b"spent").unwrap());
+            Ok(())
+        }).unwrap();
+        match new_spool.import_spool(&archive) {
+            Err(MultiSpoolError::SpoolExists) => {},
+            _ => panic!("expected the spool to exist"),
+        }
     }
 
     #[test]
-    fn spool_purge_test() {
+    fn backup_restore_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        multi_spool.set_purge_grace_period(Duration::from_secs(0));
         let mut csprng = thread_rng();
-        let base_dir = tempdir().unwrap();
-        let mut spool_id = [0u8; SPOOL_ID_SIZE];
-        csprng.fill(&mut spool_id);
-        let path = Path::new(base_dir.path()).join(format!("spool.{}.sled", base64::encode(&spool_id)));
-        let pathbuf: PathBuf = path.to_owned();
-        let mut spool = Spool::new(&pathbuf).unwrap();
-
-        // message 1
-        let mut message1 = [0u8; MESSAGE_SIZE];
-        csprng.fill(&mut message1[..]);
-        spool.append(message1).unwrap();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let alice_spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        multi_spool.append_to_spool(alice_spool_id, b"hello").unwrap();
+        let bob_spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        let full = multi_spool.backup(false).unwrap();
+        multi_spool.append_to_spool(alice_spool_id, b"world").unwrap();
+        let name = multi_spool.backup(true).unwrap();
+        assert_eq!(multi_spool.backups().unwrap(), vec![full.clone(), name.clone()]);
 
+        multi_spool.purge_spool(alice_spool_id, alice_signature).unwrap();
+        let carol_spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        assert_eq!(multi_spool.restore(&name).unwrap(), 2);
+        let mut spool_ids = multi_spool.spool_ids();
+        spool_ids.sort();
+        let mut expected = vec![alice_spool_id, bob_spool_id];
+        expected.sort();
+        assert_eq!(spool_ids, expected);
+        assert!(multi_spool.append_to_spool(carol_spool_id, b"hello").is_err());
         let mut message_id = [0u8; MESSAGE_ID_SIZE];
-        BigEndian::write_u32(&mut message_id, 0);
-        let read_message1 = spool.read(&message_id).unwrap();
-        assert_eq!(message1[..], read_message1[..]);
+        assert_eq!(multi_spool.read_from_spool(alice_spool_id, alice_signature, &message_id).unwrap(), b"hello".to_vec());
+        BigEndian::write_u32(&mut message_id, 1);
+        assert_eq!(multi_spool.read_from_spool(alice_spool_id, alice_signature, &message_id).unwrap(), b"world".to_vec());
+        assert!(multi_spool.restore("../spool_set.sled").is_err());
 
-        spool.purge().unwrap();
+        // The full backup alone doesn't hold the second message.
+        assert_eq!(multi_spool.restore(&full).unwrap(), 2);
+        assert!(multi_spool.read_from_spool(alice_spool_id, alice_signature, &message_id).is_err());
     }
 
     #[test]
-    fn spoolset_basic_test() {
+    fn soft_purge_test() {
+        let dir = tempdir().unwrap();
+        let base_dir = String::from(dir.path().to_str().unwrap());
+        let mut multi_spool = MultiSpool::new(&base_dir).unwrap();
         let mut csprng = thread_rng();
-        let base_dir = tempdir().unwrap();
-        let set_path = Path::new(base_dir.path()).join("spool_set.sled");
-        let mut spool_set = SpoolSet::new(&set_path).unwrap();
-
         let alice_keypair: Keypair = Keypair::generate(&mut csprng);
-        let spool_id1 = [0u8; SPOOL_ID_SIZE];
-        spool_set.put(spool_id1, alice_keypair.public).unwrap();
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        multi_spool.append_to_spool(spool_id, b"hello").unwrap();
+        let message_id = [0u8; MESSAGE_ID_SIZE];
 
-        assert!(spool_set.has(spool_id1).unwrap());
-        spool_set.delete(spool_id1).unwrap();
-        assert!(!spool_set.has(spool_id1).unwrap());
+        // A purged spool is gone for its owner, but can be undeleted.
+        multi_spool.purge_spool(spool_id, alice_signature).unwrap();
+        assert!(multi_spool.append_to_spool(spool_id, b"hello").is_err());
+        assert!(multi_spool.read_from_spool(spool_id, alice_signature, &message_id).is_err());
+        assert!(multi_spool.purge_spool(spool_id, alice_signature).is_err());
+        assert!(multi_spool.spool_info(spool_id).unwrap().purged_at.is_some());
+        assert_eq!(multi_spool.expire_tombstones().unwrap(), 0);
+        multi_spool.undelete_spool(spool_id, alice_signature).unwrap();
+        assert_eq!(multi_spool.read_from_spool(spool_id, alice_signature, &message_id).unwrap(), b"hello".to_vec());
+        assert!(multi_spool.undelete_spool(spool_id, alice_signature).is_err());
 
-        let mut spool_id2 = [0u8; SPOOL_ID_SIZE];
-        csprng.fill(&mut spool_id2);
-        spool_set.put(spool_id2, alice_keypair.public).unwrap();
-        let remote_pub_key = spool_set.get_public_key(spool_id2).unwrap();
-        assert_eq!(remote_pub_key, alice_keypair.public);
+        // Tombstones survive restarts and expire after the grace period.
+        multi_spool.purge_spool(spool_id, alice_signature).unwrap();
+        multi_spool.close().unwrap();
+        drop(multi_spool);
+        let multi_spool = MultiSpool::new(&base_dir).unwrap();
+        assert!(multi_spool.append_to_spool(spool_id, b"hello").is_err());
+        multi_spool.set_purge_grace_period(Duration::from_secs(0));
+        assert_eq!(multi_spool.expire_tombstones().unwrap(), 1);
+        assert!(multi_spool.undelete_spool(spool_id, alice_signature).is_err());
+        assert_eq!(multi_spool.spool_count(), 0);
+    }
 
-        let mut spool_id3 = [0u8; SPOOL_ID_SIZE];
-        csprng.fill(&mut spool_id3);
-        spool_set.put(spool_id3, alice_keypair.public).unwrap();
+    #[test]
+    fn poisoned_lock_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let signature = keypair.sign(&keypair.public.to_bytes());
+        let spool_id = multi_spool.create_spool(keypair.public, signature, &mut csprng).unwrap();
 
-        //let mut ids = vec![];
-        let mut map: HashMap<[u8; SPOOL_ID_SIZE], bool> = HashMap::new();
-        for key in spool_set.keys() {
-            let mut id = [0u8; SPOOL_ID_SIZE];
-            id[..].clone_from_slice(&key.unwrap());
-            map.insert(id, true);
-        }
-        assert!(map.contains_key(&spool_id2));
-        assert!(map.contains_key(&spool_id3));
+        // A panic while holding the shared locks mustn't fail every
+        // later request.
+        let clone = multi_spool.clone();
+        assert!(thread::spawn(move || {
+            let _settings = clone.settings.write().unwrap();
+            let _policies = clone.policies.write().unwrap();
+            let _dedup = clone.dedup.lock().unwrap();
+            let shared = clone.map.get(&spool_id).unwrap();
+            let _spool = shared.write().unwrap();
+            panic!("poisoning the locks");
+        }).join().is_err());
+        multi_spool.append_to_spool(spool_id, b"hello").unwrap();
+        multi_spool.read_from_spool(spool_id, signature, &[0u8; MESSAGE_ID_SIZE]).unwrap();
+        multi_spool.create_spool(keypair.public, signature, &mut csprng).unwrap();
+        multi_spool.purge_spool(spool_id, signature).unwrap();
     }
 
     #[test]
-    fn simple_multi_spool_test() {
+    fn signature_backoff_test() {
         let dir = tempdir().unwrap();
-        let mut multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        multi_spool.set_signature_backoff(2, Duration::from_secs(60));
         let mut csprng = thread_rng();
-
         let alice_keypair: Keypair = Keypair::generate(&mut csprng);
         let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let bob_keypair: Keypair = Keypair::generate(&mut csprng);
+        let bob_signature = bob_keypair.sign(&bob_keypair.public.to_bytes());
         let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+        multi_spool.append_to_spool(spool_id, b"hello").unwrap();
+        let message_id = [0u8; MESSAGE_ID_SIZE];
 
-        let message1 = [0u8; MESSAGE_SIZE];
-        multi_spool.append_to_spool(spool_id, message1).unwrap();
-        let mut message_id = [0u8; MESSAGE_ID_SIZE];
-        BigEndian::write_u32(&mut message_id, 0);
-        let read_message1 = multi_spool.read_from_spool(spool_id, alice_signature, &message_id).unwrap();
-        assert_eq!(message1[..], read_message1[..]);
+        // Once the spool backs off, bad signatures are refused as such.
+        for _ in 0..2 {
+            match multi_spool.read_from_spool(spool_id, bob_signature, &message_id) {
+                Err(MultiSpoolError::SignatureError(_)) => {},
+                _ => panic!("expected a signature error"),
+            }
+        }
+        for _ in 0..10 {
+            match multi_spool.read_from_spool(spool_id, bob_signature, &message_id) {
+                Err(MultiSpoolError::SignatureBackoff) => {},
+                _ => panic!("expected the spool to back off"),
+            }
+            match multi_spool.purge_spool(spool_id, bob_signature) {
+                Err(MultiSpoolError::SignatureBackoff) => {},
+                _ => panic!("expected the spool to back off"),
+            }
+        }
+        assert_eq!(multi_spool.diagnostics().unwrap().throttled_spools, 1);
 
-        multi_spool.append_to_spool(spool_id, message1).unwrap();
-        BigEndian::write_u32(&mut message_id, 1);
-        let read_message1 = multi_spool.read_from_spool(spool_id, alice_signature, &message_id).unwrap();
-        assert_eq!(message1[..], read_message1[..]);
+        // The owner's good signature is never refused, and forgets the
+        // failures.
+        multi_spool.read_from_spool(spool_id, alice_signature, &message_id).unwrap();
+        match multi_spool.read_from_spool(spool_id, bob_signature, &message_id) {
+            Err(MultiSpoolError::SignatureError(_)) => {},
+            _ => panic!("expected a signature error"),
+        }
+
+        // Reads by keys which aren't readers don't count.
+        for _ in 0..3 {
+            match multi_spool.read_as(spool_id, &bob_keypair.public, bob_signature, &message_id) {
+                Err(MultiSpoolError::NotReader) => {},
+                _ => panic!("expected bob not to be a reader"),
+            }
+        }
+        match multi_spool.read_from_spool(spool_id, bob_signature, &message_id) {
+            Err(MultiSpoolError::SignatureError(_)) => {},
+            _ => panic!("expected a signature error"),
+        }
+        assert!(multi_spool.read_from_spool(spool_id, bob_signature, &message_id).is_err());
+        multi_spool.purge_spool(spool_id, alice_signature).unwrap();
     }
 
     #[test]
     fn create_invalid_signature_test() {
         let dir = tempdir().unwrap();
-        let mut multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
         let mut csprng = thread_rng();
 
         let alice_keypair: Keypair = Keypair::generate(&mut csprng);
@@ -512,7 +5354,7 @@ mod tests {
     #[test]
     fn create_twice_test() {
         let dir = tempdir().unwrap();
-        let mut multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
         let mut csprng = thread_rng();
 
         let alice_keypair: Keypair = Keypair::generate(&mut csprng);
@@ -526,7 +5368,7 @@ mod tests {
     #[test]
     fn spool_permissions_test() {
         let dir = tempdir().unwrap();
-        let mut multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
         let mut csprng = thread_rng();
 
         let alice_keypair: Keypair = Keypair::generate(&mut csprng);
@@ -540,7 +5382,7 @@ mod tests {
 
         let message1 = [0u8; MESSAGE_SIZE];
         let mut message_id1 = [0u8; MESSAGE_ID_SIZE];
-        multi_spool.append_to_spool(spool_id1, message1).unwrap();
+        multi_spool.append_to_spool(spool_id1, &message1).unwrap();
         BigEndian::write_u32(&mut message_id1, 0);
         let read_message1 = multi_spool.read_from_spool(spool_id1, alice_signature, &message_id1).unwrap();
         assert_eq!(message1[..], read_message1[..]);
@@ -549,7 +5391,7 @@ mod tests {
         let message2 = [9u8; MESSAGE_SIZE];
         let mut message_id2 = [0u8; MESSAGE_ID_SIZE];
         BigEndian::write_u32(&mut message_id2, 0);
-        multi_spool.append_to_spool(spool_id2, message2).unwrap();
+        multi_spool.append_to_spool(spool_id2, &message2).unwrap();
         let mut read_message2 = multi_spool.read_from_spool(spool_id2, bob_signature, &message_id1).unwrap();
         assert_eq!(message2[..], read_message2[..]);
         assert_ne!(message1[..], read_message2[..]);
@@ -559,10 +5401,304 @@ mod tests {
 
         multi_spool.purge_spool(spool_id2, alice_signature).is_err();
         multi_spool.purge_spool(spool_id2, bob_signature).unwrap();
-        multi_spool.append_to_spool(spool_id2, message2).is_err();
-        multi_spool.append_to_spool(spool_id1, message2).unwrap();
+        multi_spool.append_to_spool(spool_id2, &message2).is_err();
+        multi_spool.append_to_spool(spool_id1, &message2).unwrap();
         BigEndian::write_u32(&mut message_id2, 1);
         read_message2 = multi_spool.read_from_spool(spool_id1, alice_signature, &message_id2).unwrap();
         assert_eq!(message2[..], read_message2[..]);
     }
+
+    #[test]
+    fn concurrent_append_test() {
+        let dir = tempdir().unwrap();
+        let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+        let mut csprng = thread_rng();
+        let alice_keypair: Keypair = Keypair::generate(&mut csprng);
+        let alice_signature = alice_keypair.sign(&alice_keypair.public.to_bytes());
+        let shared_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+
+        // Each thread appends to a spool of its own and to a shared one.
+        let threads: Vec<_> = (0..4u8).map(|i| {
+            let multi_spool = multi_spool.clone();
+            let spool_id = multi_spool.create_spool(alice_keypair.public, alice_signature, &mut csprng).unwrap();
+            thread::spawn(move || {
+                for _ in 0..25 {
+                    multi_spool.append_to_spool(spool_id, &[i]).unwrap();
+                    multi_spool.append_idempotent(shared_id, &[i], &AppendAuth::default(), &[], false).unwrap();
+                }
+                spool_id
+            })
+        }).collect();
+        for (i, handle) in threads.into_iter().enumerate() {
+            let spool_id = handle.join().unwrap();
+            assert_eq!(multi_spool.spool_info(spool_id).unwrap().message_count, 25);
+            assert_eq!(multi_spool.read_from_spool(spool_id, alice_signature, &[0, 0, 0, 24]).unwrap(), vec![i as u8]);
+        }
+        assert_eq!(multi_spool.spool_info(shared_id).unwrap().message_count, 100);
+        multi_spool.verify_spool(shared_id).unwrap();
+
+        // Clones share settings as well as spools.
+        multi_spool.clone().set_max_message_size(1);
+        assert!(multi_spool.append_to_spool(shared_id, b"too large").is_err());
+        assert_eq!(multi_spool.spool_count(), 5);
+    }
+
+    #[test]
+    fn compaction_test() {
+        let dir = tempdir().unwrap();
+        let base_dir = String::from(dir.path().to_str().unwrap());
+        let multi_spool = MultiSpool::new(&base_dir).unwrap();
+        let mut csprng = thread_rng();
+        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let signature = keypair.sign(&keypair.public.to_bytes());
+        let options = SpoolOptions { Circular: true, Capacity: 2, ..SpoolOptions::default() };
+        let spool_id = multi_spool.create_with_options(keypair.public, signature, &options, &mut csprng).unwrap();
+        for i in 0..500u32 {
+            let mut message = vec![0u8; 1000];
+            BigEndian::write_u32(&mut message, i);
+            multi_spool.append_to_spool(spool_id, &message).unwrap();
+        }
+        multi_spool.flush().unwrap();
+
+        // The messages trimmed away are reclaimed and the rest kept.
+        assert!(multi_spool.compact_spool(spool_id).unwrap() > 0);
+        let path = multi_spool.shards.spool_path(spool_id);
+        assert!(!suffixed_path(&path, ".old").exists());
+        assert!(!suffixed_path(&path, ".compact").exists());
+        for i in 498..500u32 {
+            let mut message_id = [0u8; MESSAGE_ID_SIZE];
+            BigEndian::write_u32(&mut message_id, i);
+            assert_eq!(BigEndian::read_u32(&multi_spool.read_from_spool(spool_id, signature, &message_id).unwrap()), i);
+        }
+        multi_spool.append_to_spool(spool_id, b"after").unwrap();
+        multi_spool.compact().unwrap();
+        assert_eq!(multi_spool.read_from_spool(spool_id, signature, &[0, 0, 1, 244]).unwrap(), b"after".to_vec());
+
+        // A database a crash left moved aside is put back on start.
+        drop(multi_spool);
+        fs::rename(&path, suffixed_path(&path, ".old")).unwrap();
+        let multi_spool = MultiSpool::new(&base_dir).unwrap();
+        assert_eq!(multi_spool.read_from_spool(spool_id, signature, &[0, 0, 1, 244]).unwrap(), b"after".to_vec());
+    }
+
+    #[test]
+    fn replication_test() {
+        let primary_dir = tempdir().unwrap();
+        let replica_dir = tempdir().unwrap();
+        let primary = MultiSpool::new(&String::from(primary_dir.path().to_str().unwrap())).unwrap();
+        let replica = MultiSpool::new(&String::from(replica_dir.path().to_str().unwrap())).unwrap();
+        let log = Arc::new(ReplicationLog::new(1, 100));
+        primary.set_replication_log(Some(log.clone()));
+        primary.set_purge_grace_period(Duration::from_secs(0));
+
+        let mut csprng = thread_rng();
+        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let signature = keypair.sign(&keypair.public.to_bytes());
+        let spool_id = primary.create_spool(keypair.public, signature, &mut csprng).unwrap();
+        primary.append_to_spool(spool_id, b"one").unwrap();
+        primary.append_to_spool(spool_id, b"two").unwrap();
+
+        // Applying the batch twice, as after a lost acknowledgement,
+        // changes nothing.
+        let batch = log.pending(0, 10).unwrap();
+        assert_eq!(batch.events.len(), 3);
+        for _ in 0..2 {
+            for event in &batch.events {
+                replica.apply_replicated(event).unwrap();
+            }
+        }
+        log.acknowledge(0, batch.next_sequence());
+        assert_eq!(replica.spool_info(spool_id).unwrap().message_count, 2);
+        assert_eq!(replica.read_from_spool(spool_id, signature, &[0, 0, 0, 1]).unwrap(), b"two".to_vec());
+
+        // An append past the end of the replica's spool is refused.
+        let gap = ReplicationEvent::Append { spool_id: spool_id.to_vec(), message_id: 5, message: vec![], appended_at: None };
+        assert!(replica.apply_replicated(&gap).is_err());
+
+        primary.purge_spool(spool_id, signature).unwrap();
+        for event in &log.pending(0, 10).unwrap().events {
+            replica.apply_replicated(event).unwrap();
+        }
+        assert_eq!(replica.spool_count(), 0);
+    }
+
+    #[test]
+    fn replicated_access_control_test() {
+        let primary_dir = tempdir().unwrap();
+        let replica_dir = tempdir().unwrap();
+        let primary = MultiSpool::new(&String::from(primary_dir.path().to_str().unwrap())).unwrap();
+        let replica = MultiSpool::new(&String::from(replica_dir.path().to_str().unwrap())).unwrap();
+        let log = Arc::new(ReplicationLog::new(1, 100));
+        primary.set_replication_log(Some(log.clone()));
+
+        let mut csprng = thread_rng();
+        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let signature = keypair.sign(&keypair.public.to_bytes());
+        let writer = Keypair::generate(&mut csprng);
+        let options = SpoolOptions {
+            Appenders: vec![ByteBuf::from(writer.public.to_bytes().to_vec())],
+            ..SpoolOptions::default()
+        };
+        let spool_id = primary.create_with_options(keypair.public, signature, &options, &mut csprng).unwrap();
+        let key = TokenKey::generate();
+        primary.set_append_token_key(spool_id, signature, Some(TokenKey::from_bytes(&key.to_bytes()).unwrap())).unwrap();
+        let blinded = BlindedToken::new(&spool_id);
+        let token = blinded.unblind(&key.sign_blinded(&blinded.blinded()).unwrap()).unwrap();
+        let public_key = writer.public.to_bytes();
+        let append_signature = sign_append(&writer, &spool_id, b"hello").to_bytes();
+        let auth = AppendAuth {
+            public_key: &public_key,
+            signature: &append_signature,
+            token: &token,
+        };
+        primary.append_authorized(spool_id, b"hello", &auth).unwrap();
+        for event in &log.pending(0, 10).unwrap().events {
+            replica.apply_replicated(event).unwrap();
+        }
+
+        // The replica refuses appends the primary would refuse.
+        match replica.append_with_token(spool_id, b"hello", &[]) {
+            Err(MultiSpoolError::AppendNotAllowed) => {},
+            _ => panic!("a replica took an append from outside the appender list"),
+        }
+        let no_token = AppendAuth { token: &[], ..auth };
+        match replica.append_authorized(spool_id, b"hello", &no_token) {
+            Err(MultiSpoolError::InvalidAppendToken) => {},
+            _ => panic!("a replica took an append without a token"),
+        }
+        match replica.append_authorized(spool_id, b"hello", &auth) {
+            Err(MultiSpoolError::SpentAppendToken) => {},
+            _ => panic!("a replica took a spent token"),
+        }
+        assert_eq!(replica.spool_info(spool_id).unwrap().message_count, 1);
+    }
+
+    #[test]
+    fn sharding_test() {
+        let data_dir = tempdir().unwrap();
+        let disk = tempdir().unwrap();
+        let base_dir = String::from(data_dir.path().to_str().unwrap());
+        let shard_dirs = vec![String::from(disk.path().to_str().unwrap())];
+        let mut multi_spool = MultiSpool::new(&base_dir).unwrap();
+        let mut csprng = thread_rng();
+        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let signature = keypair.sign(&keypair.public.to_bytes());
+        let mut spool_ids = vec![];
+        for _ in 0..8 {
+            let spool_id = multi_spool.create_spool(keypair.public, signature, &mut csprng).unwrap();
+            multi_spool.append_to_spool(spool_id, &spool_id).unwrap();
+            spool_ids.push(spool_id);
+        }
+        multi_spool.close().unwrap();
+
+        // Adding a shard directory moves the spools belonging in it.
+        let mut multi_spool = MultiSpool::with_shards(&base_dir, &shard_dirs, Durability::default()).unwrap();
+        let shards = Shards::new(&base_dir, &shard_dirs);
+        assert_eq!(multi_spool.shard_dirs(), shards.dirs());
+        for spool_id in &spool_ids {
+            assert!(shards.spool_path(*spool_id).exists());
+            assert_eq!(shards.misplaced(*spool_id), None);
+            assert_eq!(multi_spool.spool_info(*spool_id).unwrap().shard, shards.index(spool_id));
+            assert_eq!(multi_spool.read_from_spool(*spool_id, signature, &[0, 0, 0, 0]).unwrap(), spool_id.to_vec());
+        }
+        assert!(multi_spool.orphans().unwrap().is_empty());
+        multi_spool.close().unwrap();
+    }
+
+    #[test]
+    fn failover_test() {
+        let primary_dir = tempdir().unwrap();
+        let standby_dir = tempdir().unwrap();
+        let mut primary = MultiSpool::new(&String::from(primary_dir.path().to_str().unwrap())).unwrap();
+        let standby_base = String::from(standby_dir.path().to_str().unwrap());
+        let mut standby = MultiSpool::new(&standby_base).unwrap();
+        standby.set_role(Role::Standby).unwrap();
+        assert_eq!(primary.role(), Role::Primary);
+
+        // A standby takes changes from its primary but not from clients.
+        let mut csprng = thread_rng();
+        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let signature = keypair.sign(&keypair.public.to_bytes());
+        let spool_id = primary.create_spool(keypair.public, signature, &mut csprng).unwrap();
+        standby.accept_epoch(primary.epoch()).unwrap();
+        standby.apply_replicated(&ReplicationEvent::Create {
+            spool_id: spool_id.to_vec(),
+            public_key: keypair.public.to_bytes().to_vec(),
+            policy: None,
+            appenders: None,
+        }).unwrap();
+        match standby.append_to_spool(spool_id, b"hello") {
+            Err(MultiSpoolError::NotPrimary) => {},
+            _ => panic!("a standby took a write"),
+        }
+        assert!(primary.promote().is_err());
+
+        // Once promoted, the old primary's batches are refused and it
+        // fences itself, which it remembers across restarts.
+        assert_eq!(standby.promote().unwrap(), 1);
+        standby.append_to_spool(spool_id, b"hello").unwrap();
+        match standby.accept_epoch(primary.epoch()) {
+            Err(MultiSpoolError::ReplicationError(ReplicationError::StaleEpoch { current: 1, got: 0 })) => {},
+            _ => panic!("a stale primary was not refused"),
+        }
+        primary.fence().unwrap();
+        match primary.append_to_spool(spool_id, b"hello") {
+            Err(MultiSpoolError::NotPrimary) => {},
+            _ => panic!("a fenced primary took a write"),
+        }
+        primary.close().unwrap();
+        standby.close().unwrap();
+        let promoted = MultiSpool::new(&standby_base).unwrap();
+        assert_eq!(promoted.role(), Role::Primary);
+        assert_eq!(promoted.epoch(), 1);
+        let fenced = MultiSpool::new(&String::from(primary_dir.path().to_str().unwrap())).unwrap();
+        assert_eq!(fenced.role(), Role::Fenced);
+    }
+
+    #[test]
+    fn failover_access_control_test() {
+        let primary_dir = tempdir().unwrap();
+        let standby_dir = tempdir().unwrap();
+        let primary = MultiSpool::new(&String::from(primary_dir.path().to_str().unwrap())).unwrap();
+        let standby_base = String::from(standby_dir.path().to_str().unwrap());
+        let mut standby = MultiSpool::new(&standby_base).unwrap();
+        standby.set_role(Role::Standby).unwrap();
+        let log = Arc::new(ReplicationLog::new(1, 100));
+        primary.set_replication_log(Some(log.clone()));
+        primary.set_purge_grace_period(Duration::from_secs(3600));
+
+        let mut csprng = thread_rng();
+        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let signature = keypair.sign(&keypair.public.to_bytes());
+        let writer = Keypair::generate(&mut csprng);
+        let acl_id = primary.create_spool(keypair.public, signature, &mut csprng).unwrap();
+        primary.set_appenders(acl_id, signature, Some(AppenderList::new(vec![writer.public]).unwrap())).unwrap();
+        let token_id = primary.create_spool(keypair.public, signature, &mut csprng).unwrap();
+        primary.set_append_token_key(token_id, signature, Some(TokenKey::generate())).unwrap();
+        let purged_id = primary.create_spool(keypair.public, signature, &mut csprng).unwrap();
+        primary.append_to_spool(purged_id, b"hello").unwrap();
+        primary.purge_spool(purged_id, signature).unwrap();
+        standby.accept_epoch(primary.epoch()).unwrap();
+        for event in &log.pending(0, 100).unwrap().events {
+            standby.apply_replicated(event).unwrap();
+        }
+
+        // The promoted standby, across a restart, restricts and hides
+        // the same spools its old primary did.
+        standby.promote().unwrap();
+        standby.close().unwrap();
+        let promoted = MultiSpool::new(&standby_base).unwrap();
+        match promoted.append_with_token(acl_id, b"hello", &[]) {
+            Err(MultiSpoolError::AppendNotAllowed) => {},
+            _ => panic!("a promoted standby took an append from outside the appender list"),
+        }
+        match promoted.append_with_token(token_id, b"hello", &[]) {
+            Err(MultiSpoolError::InvalidAppendToken) => {},
+            _ => panic!("a promoted standby took an append without a token"),
+        }
+        assert!(promoted.purged_at(purged_id).is_some());
+        assert!(promoted.read_from_spool(purged_id, signature, &[0u8; MESSAGE_ID_SIZE]).is_err());
+        promoted.undelete_spool(purged_id, signature).unwrap();
+        assert_eq!(promoted.read_from_spool(purged_id, signature, &[0u8; MESSAGE_ID_SIZE]).unwrap(), b"hello".to_vec());
+    }
 } // tests
@@ -0,0 +1,819 @@
+// store.rs - The spool operations request handlers need.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Spool stores
+//!
+//! The request handlers work with any `SpoolStore`. `MultiSpool` is
+//! the one backed by disk; `MemorySpoolStore` keeps its spools in
+//! memory, so that applications embedding the handlers can test them
+//! without touching the filesystem.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, BigEndian};
+use ed25519_dalek::{PublicKey, Signature};
+
+use crate::acl::{AppendAuth, AppenderList};
+use crate::errors::{MultiSpoolError, SpoolError};
+use crate::merkle::{self, ReadProof};
+use crate::options::{SpoolOptions, SpoolPolicy};
+use crate::rekey::Rekey;
+use crate::spool::{FoundMessage, MultiSpool, SpoolStats, SPOOL_ID_SIZE, MESSAGE_ID_SIZE, MESSAGE_SIZE};
+use crate::dedup::MAX_IDEMPOTENCY_KEY_SIZE;
+use crate::tokens::{self, TokenKey};
+
+
+/// SpoolStore is the set of spools the request handlers act on. See
+/// the methods of the same name on `MultiSpool` for their contracts.
+pub trait SpoolStore: Send + Sync {
+    /// Returns the ID of the oldest spool owned by `public_key`,
+    /// creating one which behaves as `options` say if there is none,
+    /// and whether it was created. An existing spool created with
+    /// other options is refused with `OptionsMismatch`.
+    fn allocate_spool(&self,
+                      public_key: PublicKey,
+                      signature: Signature,
+                      options: &SpoolOptions)
+                      -> Result<([u8; SPOOL_ID_SIZE], bool), MultiSpoolError>;
+
+    /// Purges a spool, if `signature` is its owner's.
+    fn purge_spool(&self, spool_id: [u8; SPOOL_ID_SIZE], signature: Signature) -> Result<(), MultiSpoolError>;
+
+    /// Restores a purged spool, if `signature` is its owner's.
+    fn undelete_spool(&self, spool_id: [u8; SPOOL_ID_SIZE], signature: Signature) -> Result<(), MultiSpoolError>;
+
+    /// Appends a message, checking the writer's signature and spending
+    /// the token if the spool requires them. Returns false if the
+    /// append was a duplicate.
+    fn append_idempotent(&self,
+                         spool_id: [u8; SPOOL_ID_SIZE],
+                         message: &[u8],
+                         auth: &AppendAuth,
+                         idempotency_key: &[u8],
+                         durable: bool)
+                         -> Result<bool, MultiSpoolError>;
+
+    /// Sets or clears the key append tokens are checked with.
+    fn set_append_token_key(&self,
+                            spool_id: [u8; SPOOL_ID_SIZE],
+                            signature: Signature,
+                            key: Option<TokenKey>)
+                            -> Result<(), MultiSpoolError>;
+
+    /// Sets or clears the writers allowed to append.
+    fn set_appenders(&self,
+                     spool_id: [u8; SPOOL_ID_SIZE],
+                     signature: Signature,
+                     appenders: Option<AppenderList>)
+                     -> Result<(), MultiSpoolError>;
+
+    /// Copies a spool into a new one owned by `new_owner`, or by the
+    /// same owner if None, returning the new spool's ID.
+    fn copy_spool(&self,
+                  spool_id: [u8; SPOOL_ID_SIZE],
+                  signature: Signature,
+                  new_owner: Option<PublicKey>)
+                  -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError>;
+
+    /// Hands a spool over to a new owner key, keeping its spool ID.
+    fn rekey_spool(&self,
+                   spool_id: [u8; SPOOL_ID_SIZE],
+                   signature: Signature,
+                   rekey: &Rekey)
+                   -> Result<(), MultiSpoolError>;
+
+    /// Reads a message, if `public_key` is the spool owner's or a
+    /// reader's and `signature` its signature, along with the unix
+    /// time it was appended at if known.
+    fn read_from_spool(&self,
+                       spool_id: [u8; SPOOL_ID_SIZE],
+                       public_key: &PublicKey,
+                       signature: Signature,
+                       message_id: &[u8; MESSAGE_ID_SIZE])
+                       -> Result<(Vec<u8>, Option<u64>), MultiSpoolError>;
+
+    /// Reads the latest message kept whose Merkle leaf hash is `leaf`
+    /// like `read_from_spool`, from a spool created with a hash index,
+    /// along with the message's ID.
+    fn read_by_hash(&self,
+                    spool_id: [u8; SPOOL_ID_SIZE],
+                    public_key: &PublicKey,
+                    signature: Signature,
+                    leaf: &merkle::Hash)
+                    -> Result<FoundMessage, MultiSpoolError>;
+
+    /// Reads the message `offset` messages before the newest like
+    /// `read_from_spool`, along with the message's ID.
+    fn read_latest(&self,
+                   spool_id: [u8; SPOOL_ID_SIZE],
+                   public_key: &PublicKey,
+                   signature: Signature,
+                   offset: u32)
+                   -> Result<FoundMessage, MultiSpoolError>;
+
+    /// Returns the proof of a message's position in its spool.
+    fn read_proof(&self,
+                  spool_id: [u8; SPOOL_ID_SIZE],
+                  message_id: &[u8; MESSAGE_ID_SIZE])
+                  -> Result<ReadProof, MultiSpoolError>;
+
+    /// Returns what a spool holds, if `signature` is its owner's.
+    fn spool_status(&self, spool_id: [u8; SPOOL_ID_SIZE], signature: Signature) -> Result<SpoolStats, MultiSpoolError>;
+
+    /// Returns the number of messages appended to a spool.
+    fn message_count(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<u64, MultiSpoolError>;
+
+    /// Returns the largest message which may be appended.
+    fn max_message_size(&self) -> usize;
+
+    /// Returns the number of spools.
+    fn spool_count(&self) -> usize;
+
+    /// Checks that the store can take writes.
+    fn check_health(&self) -> Result<(), MultiSpoolError>;
+}
+
+impl SpoolStore for MultiSpool {
+    fn allocate_spool(&self,
+                      public_key: PublicKey,
+                      signature: Signature,
+                      options: &SpoolOptions)
+                      -> Result<([u8; SPOOL_ID_SIZE], bool), MultiSpoolError> {
+        MultiSpool::create_or_get(self, public_key, signature, options)
+    }
+
+    fn purge_spool(&self, spool_id: [u8; SPOOL_ID_SIZE], signature: Signature) -> Result<(), MultiSpoolError> {
+        MultiSpool::purge_spool(self, spool_id, signature)
+    }
+
+    fn undelete_spool(&self, spool_id: [u8; SPOOL_ID_SIZE], signature: Signature) -> Result<(), MultiSpoolError> {
+        MultiSpool::undelete_spool(self, spool_id, signature)
+    }
+
+    fn append_idempotent(&self,
+                         spool_id: [u8; SPOOL_ID_SIZE],
+                         message: &[u8],
+                         auth: &AppendAuth,
+                         idempotency_key: &[u8],
+                         durable: bool)
+                         -> Result<bool, MultiSpoolError> {
+        MultiSpool::append_idempotent(self, spool_id, message, auth, idempotency_key, durable)
+    }
+
+    fn set_append_token_key(&self,
+                            spool_id: [u8; SPOOL_ID_SIZE],
+                            signature: Signature,
+                            key: Option<TokenKey>)
+                            -> Result<(), MultiSpoolError> {
+        MultiSpool::set_append_token_key(self, spool_id, signature, key)
+    }
+
+    fn set_appenders(&self,
+                     spool_id: [u8; SPOOL_ID_SIZE],
+                     signature: Signature,
+                     appenders: Option<AppenderList>)
+                     -> Result<(), MultiSpoolError> {
+        MultiSpool::set_appenders(self, spool_id, signature, appenders)
+    }
+
+    fn read_from_spool(&self,
+                       spool_id: [u8; SPOOL_ID_SIZE],
+                       public_key: &PublicKey,
+                       signature: Signature,
+                       message_id: &[u8; MESSAGE_ID_SIZE])
+                       -> Result<(Vec<u8>, Option<u64>), MultiSpoolError> {
+        MultiSpool::read_as(self, spool_id, public_key, signature, message_id)
+    }
+
+    fn read_by_hash(&self,
+                    spool_id: [u8; SPOOL_ID_SIZE],
+                    public_key: &PublicKey,
+                    signature: Signature,
+                    leaf: &merkle::Hash)
+                    -> Result<FoundMessage, MultiSpoolError> {
+        MultiSpool::read_by_hash(self, spool_id, public_key, signature, leaf)
+    }
+
+    fn read_latest(&self,
+                   spool_id: [u8; SPOOL_ID_SIZE],
+                   public_key: &PublicKey,
+                   signature: Signature,
+                   offset: u32)
+                   -> Result<FoundMessage, MultiSpoolError> {
+        MultiSpool::read_latest(self, spool_id, public_key, signature, offset)
+    }
+
+    fn read_proof(&self,
+                  spool_id: [u8; SPOOL_ID_SIZE],
+                  message_id: &[u8; MESSAGE_ID_SIZE])
+                  -> Result<ReadProof, MultiSpoolError> {
+        MultiSpool::read_proof(self, spool_id, message_id)
+    }
+
+    fn copy_spool(&self,
+                  spool_id: [u8; SPOOL_ID_SIZE],
+                  signature: Signature,
+                  new_owner: Option<PublicKey>)
+                  -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError> {
+        MultiSpool::copy_spool(self, spool_id, signature, new_owner)
+    }
+
+    fn rekey_spool(&self,
+                   spool_id: [u8; SPOOL_ID_SIZE],
+                   signature: Signature,
+                   rekey: &Rekey)
+                   -> Result<(), MultiSpoolError> {
+        MultiSpool::rekey_spool(self, spool_id, signature, rekey)
+    }
+
+    fn spool_status(&self, spool_id: [u8; SPOOL_ID_SIZE], signature: Signature) -> Result<SpoolStats, MultiSpoolError> {
+        MultiSpool::spool_status(self, spool_id, signature)
+    }
+
+    fn message_count(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<u64, MultiSpoolError> {
+        MultiSpool::message_count(self, spool_id)
+    }
+
+    fn max_message_size(&self) -> usize {
+        MultiSpool::max_message_size(self)
+    }
+
+    fn spool_count(&self) -> usize {
+        MultiSpool::spool_count(self)
+    }
+
+    fn check_health(&self) -> Result<(), MultiSpoolError> {
+        MultiSpool::check_health(self)
+    }
+}
+
+struct MemorySpool {
+    public_key: PublicKey,
+    messages: Vec<Vec<u8>>,
+    /// The unix time each message was appended at.
+    appended_at: Vec<u64>,
+    token_key: Option<TokenKey>,
+    spent_tokens: HashSet<Vec<u8>>,
+    appenders: Option<AppenderList>,
+    idempotency_keys: HashSet<Vec<u8>>,
+    purged: bool,
+    policy: SpoolPolicy,
+    /// The number of messages dropped from a circular spool.
+    dropped: usize,
+}
+
+struct MemoryState {
+    spools: HashMap<[u8; SPOOL_ID_SIZE], MemorySpool>,
+    next_id: u64,
+}
+
+/// MemorySpoolStore is a SpoolStore held in memory, for tests. It
+/// checks owner signatures, append tokens, idempotency keys and spool
+/// options like MultiSpool, but has no signature backoff, TTLs or
+/// purge grace period, signs no tree roots, keeps no activity times,
+/// and hands out spool IDs in order.
+pub struct MemorySpoolStore {
+    state: Mutex<MemoryState>,
+    max_message_size: usize,
+}
+
+impl Default for MemorySpoolStore {
+    fn default() -> Self {
+        MemorySpoolStore::new(MESSAGE_SIZE)
+    }
+}
+
+impl MemorySpoolStore {
+    pub fn new(max_message_size: usize) -> MemorySpoolStore {
+        MemorySpoolStore {
+            state: Mutex::new(MemoryState {
+                spools: HashMap::new(),
+                next_id: 0,
+            }),
+            max_message_size: max_message_size,
+        }
+    }
+
+    fn with_spool<F, T>(&self, spool_id: [u8; SPOOL_ID_SIZE], f: F) -> Result<T, MultiSpoolError>
+    where
+        F: FnOnce(&mut MemorySpool) -> Result<T, MultiSpoolError>,
+    {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match state.spools.get_mut(&spool_id) {
+            Some(spool) if !spool.purged => f(spool),
+            _ => Err(MultiSpoolError::NoSuchSpool),
+        }
+    }
+}
+
+fn verify_owner(spool: &MemorySpool, signature: &Signature) -> Result<(), MultiSpoolError> {
+    spool.public_key.verify(&spool.public_key.to_bytes(), signature)?;
+    Ok(())
+}
+
+fn verify_owner_or_reader(spool: &MemorySpool, public_key: &PublicKey, signature: &Signature) -> Result<(), MultiSpoolError> {
+    if *public_key == spool.public_key {
+        verify_owner(spool, signature)
+    } else if spool.policy.is_reader(public_key) {
+        public_key.verify(&public_key.to_bytes(), signature)?;
+        Ok(())
+    } else {
+        Err(MultiSpoolError::NotReader)
+    }
+}
+
+fn message_index(spool: &MemorySpool, message_id: &[u8; MESSAGE_ID_SIZE]) -> Result<usize, MultiSpoolError> {
+    let index = BigEndian::read_u32(message_id) as usize;
+    if index >= spool.messages.len() || index < spool.dropped {
+        return Err(MultiSpoolError::SpoolError(SpoolError::NoSuchMessage))
+    }
+    Ok(index)
+}
+
+impl SpoolStore for MemorySpoolStore {
+    fn allocate_spool(&self,
+                      public_key: PublicKey,
+                      signature: Signature,
+                      options: &SpoolOptions)
+                      -> Result<([u8; SPOOL_ID_SIZE], bool), MultiSpoolError> {
+        public_key.verify(&public_key.to_bytes(), &signature)?;
+        let policy = options.policy(0)?;
+        let appenders = options.appenders()?;
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let existing = state.spools.iter()
+            .filter(|&(_, spool)| spool.public_key == public_key && !spool.purged)
+            .min_by_key(|&(spool_id, _)| *spool_id);
+        if let Some((spool_id, spool)) = existing {
+            if spool.policy != policy {
+                return Err(MultiSpoolError::OptionsMismatch)
+            }
+            return Ok((*spool_id, false))
+        }
+        let mut spool_id = [0u8; SPOOL_ID_SIZE];
+        BigEndian::write_u64(&mut spool_id[SPOOL_ID_SIZE - 8..], state.next_id);
+        state.next_id += 1;
+        state.spools.insert(spool_id, MemorySpool {
+            public_key: public_key,
+            messages: vec![],
+            appended_at: vec![],
+            token_key: None,
+            spent_tokens: HashSet::new(),
+            appenders: appenders,
+            idempotency_keys: HashSet::new(),
+            purged: false,
+            policy: policy,
+            dropped: 0,
+        });
+        Ok((spool_id, true))
+    }
+
+    fn purge_spool(&self, spool_id: [u8; SPOOL_ID_SIZE], signature: Signature) -> Result<(), MultiSpoolError> {
+        self.with_spool(spool_id, |spool| {
+            verify_owner(spool, &signature)?;
+            spool.purged = true;
+            Ok(())
+        })
+    }
+
+    fn undelete_spool(&self, spool_id: [u8; SPOOL_ID_SIZE], signature: Signature) -> Result<(), MultiSpoolError> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match state.spools.get_mut(&spool_id) {
+            Some(spool) if spool.purged => {
+                verify_owner(spool, &signature)?;
+                spool.purged = false;
+                Ok(())
+            },
+            _ => Err(MultiSpoolError::NoSuchSpool),
+        }
+    }
+
+    fn append_idempotent(&self,
+                         spool_id: [u8; SPOOL_ID_SIZE],
+                         message: &[u8],
+                         auth: &AppendAuth,
+                         idempotency_key: &[u8],
+                         _durable: bool)
+                         -> Result<bool, MultiSpoolError> {
+        if idempotency_key.len() > MAX_IDEMPOTENCY_KEY_SIZE {
+            return Err(MultiSpoolError::InvalidIdempotencyKey)
+        }
+        if message.len() > self.max_message_size {
+            return Err(MultiSpoolError::SpoolError(SpoolError::MessageTooLarge(message.len())))
+        }
+        self.with_spool(spool_id, |spool| {
+            if !idempotency_key.is_empty() && spool.idempotency_keys.contains(idempotency_key) {
+                return Ok(false)
+            }
+            if spool.policy.is_full((spool.messages.len() - spool.dropped) as u64) {
+                return Err(MultiSpoolError::SpoolFull)
+            }
+            if let Some(ref appenders) = spool.appenders {
+                if !appenders.verify(&spool_id, message, auth.public_key, auth.signature) {
+                    return Err(MultiSpoolError::AppendNotAllowed)
+                }
+            }
+            if let Some(ref key) = spool.token_key {
+                if !key.verify(&spool_id, auth.token) {
+                    return Err(MultiSpoolError::InvalidAppendToken)
+                }
+            }
+            let window = cmp::max(spool.dropped, spool.messages.len().saturating_sub(spool.policy.dedup_window as usize));
+            if spool.messages[window..].iter().any(|x| x[..] == message[..]) {
+                return Ok(false)
+            }
+            if spool.token_key.is_some() && !spool.spent_tokens.insert(tokens::token_id(auth.token)) {
+                return Err(MultiSpoolError::SpentAppendToken)
+            }
+            spool.messages.push(message.to_vec());
+            spool.appended_at.push(SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0));
+            if spool.policy.circular && spool.messages.len() - spool.dropped > spool.policy.capacity as usize {
+                spool.dropped += 1;
+            }
+            if !idempotency_key.is_empty() {
+                spool.idempotency_keys.insert(idempotency_key.to_vec());
+            }
+            Ok(true)
+        })
+    }
+
+    fn set_append_token_key(&self,
+                            spool_id: [u8; SPOOL_ID_SIZE],
+                            signature: Signature,
+                            key: Option<TokenKey>)
+                            -> Result<(), MultiSpoolError> {
+        self.with_spool(spool_id, |spool| {
+            verify_owner(spool, &signature)?;
+            spool.token_key = key;
+            Ok(())
+        })
+    }
+
+    fn set_appenders(&self,
+                     spool_id: [u8; SPOOL_ID_SIZE],
+                     signature: Signature,
+                     appenders: Option<AppenderList>)
+                     -> Result<(), MultiSpoolError> {
+        self.with_spool(spool_id, |spool| {
+            verify_owner(spool, &signature)?;
+            spool.appenders = appenders;
+            Ok(())
+        })
+    }
+
+    fn copy_spool(&self,
+                  spool_id: [u8; SPOOL_ID_SIZE],
+                  signature: Signature,
+                  new_owner: Option<PublicKey>)
+                  -> Result<[u8; SPOOL_ID_SIZE], MultiSpoolError> {
+        let copy = self.with_spool(spool_id, |spool| {
+            verify_owner(spool, &signature)?;
+            Ok(MemorySpool {
+                public_key: new_owner.unwrap_or(spool.public_key),
+                messages: spool.messages.clone(),
+                appended_at: spool.appended_at.clone(),
+                token_key: spool.token_key.as_ref().and_then(|x| TokenKey::from_bytes(&x.to_bytes()).ok()),
+                spent_tokens: HashSet::new(),
+                appenders: spool.appenders.clone(),
+                idempotency_keys: HashSet::new(),
+                purged: false,
+                policy: spool.policy.clone(),
+                dropped: spool.dropped,
+            })
+        })?;
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut copy_id = [0u8; SPOOL_ID_SIZE];
+        BigEndian::write_u64(&mut copy_id[SPOOL_ID_SIZE - 8..], state.next_id);
+        state.next_id += 1;
+        state.spools.insert(copy_id, copy);
+        Ok(copy_id)
+    }
+
+    fn rekey_spool(&self,
+                   spool_id: [u8; SPOOL_ID_SIZE],
+                   signature: Signature,
+                   rekey: &Rekey)
+                   -> Result<(), MultiSpoolError> {
+        self.with_spool(spool_id, |spool| {
+            verify_owner(spool, &signature)?;
+            if !rekey.verify(&spool_id, &spool.public_key) {
+                return Err(MultiSpoolError::InvalidRekey)
+            }
+            spool.public_key = rekey.new_key;
+            Ok(())
+        })
+    }
+
+    fn read_from_spool(&self,
+                       spool_id: [u8; SPOOL_ID_SIZE],
+                       public_key: &PublicKey,
+                       signature: Signature,
+                       message_id: &[u8; MESSAGE_ID_SIZE])
+                       -> Result<(Vec<u8>, Option<u64>), MultiSpoolError> {
+        self.with_spool(spool_id, |spool| {
+            verify_owner_or_reader(spool, public_key, &signature)?;
+            let index = message_index(spool, message_id)?;
+            Ok((spool.messages[index].clone(), Some(spool.appended_at[index])))
+        })
+    }
+
+    fn read_by_hash(&self,
+                    spool_id: [u8; SPOOL_ID_SIZE],
+                    public_key: &PublicKey,
+                    signature: Signature,
+                    leaf: &merkle::Hash)
+                    -> Result<FoundMessage, MultiSpoolError> {
+        self.with_spool(spool_id, |spool| {
+            verify_owner_or_reader(spool, public_key, &signature)?;
+            if !spool.policy.hash_index {
+                return Err(MultiSpoolError::NoHashIndex)
+            }
+            let index = (spool.dropped..spool.messages.len()).rev()
+                .find(|&x| merkle::leaf_hash(&spool.messages[x]) == *leaf)
+                .ok_or(MultiSpoolError::SpoolError(SpoolError::NoSuchMessage))?;
+            let mut message_id = [0u8; MESSAGE_ID_SIZE];
+            BigEndian::write_u32(&mut message_id, index as u32);
+            Ok((message_id, spool.messages[index].clone(), Some(spool.appended_at[index])))
+        })
+    }
+
+    fn read_latest(&self,
+                   spool_id: [u8; SPOOL_ID_SIZE],
+                   public_key: &PublicKey,
+                   signature: Signature,
+                   offset: u32)
+                   -> Result<FoundMessage, MultiSpoolError> {
+        self.with_spool(spool_id, |spool| {
+            verify_owner_or_reader(spool, public_key, &signature)?;
+            let index = spool.messages.len().checked_sub(offset as usize + 1)
+                .filter(|&x| x >= spool.dropped)
+                .ok_or(MultiSpoolError::SpoolError(SpoolError::NoSuchMessage))?;
+            let mut message_id = [0u8; MESSAGE_ID_SIZE];
+            BigEndian::write_u32(&mut message_id, index as u32);
+            Ok((message_id, spool.messages[index].clone(), Some(spool.appended_at[index])))
+        })
+    }
+
+    fn read_proof(&self,
+                  spool_id: [u8; SPOOL_ID_SIZE],
+                  message_id: &[u8; MESSAGE_ID_SIZE])
+                  -> Result<ReadProof, MultiSpoolError> {
+        self.with_spool(spool_id, |spool| {
+            let index = message_index(spool, message_id)?;
+            let leaves: Vec<merkle::Hash> = spool.messages.iter().map(|x| merkle::leaf_hash(x)).collect();
+            Ok(ReadProof::new(&spool_id, index as u32, &leaves, None))
+        })
+    }
+
+    fn spool_status(&self, spool_id: [u8; SPOOL_ID_SIZE], signature: Signature) -> Result<SpoolStats, MultiSpoolError> {
+        self.with_spool(spool_id, |spool| {
+            verify_owner(spool, &signature)?;
+            Ok(SpoolStats {
+                MessageCount: (spool.messages.len() - spool.dropped) as u64,
+                SizeBytes: spool.messages[spool.dropped..].iter().map(|x| x.len() as u64).sum(),
+                Head: spool.messages.len() as u64,
+                FirstMessage: spool.dropped as u64,
+                Capacity: spool.policy.capacity,
+                ExpiresAt: spool.policy.expires_at,
+                ..SpoolStats::default()
+            })
+        })
+    }
+
+    fn message_count(&self, spool_id: [u8; SPOOL_ID_SIZE]) -> Result<u64, MultiSpoolError> {
+        self.with_spool(spool_id, |spool| Ok(spool.messages.len() as u64))
+    }
+
+    fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    fn spool_count(&self) -> usize {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).spools.values().filter(|x| !x.purged).count()
+    }
+
+    fn check_health(&self) -> Result<(), MultiSpoolError> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use ed25519_dalek::Keypair;
+    use serde_bytes::ByteBuf;
+    use crate::{SpoolRequest, create_spool, append_to_spool, read_from_spool, purge_spool,
+                undelete_spool, spool_status, copy_spool, CREATE_SPOOL_COMMAND, SPOOL_STATUS_COMMAND,
+                rekey_spool, read_by_hash, read_latest, COPY_SPOOL_COMMAND, REKEY_SPOOL_COMMAND, RETRIEVE_BY_HASH_COMMAND, APPEND_MESSAGE_COMMAND,
+                RETRIEVE_MESSAGE_COMMAND, RETRIEVE_LATEST_COMMAND, PURGE_SPOOL_COMMAND, UNDELETE_SPOOL_COMMAND,
+                SPOOL_FULL_STATUS};
+    use super::*;
+
+    fn owner_request(keypair: &Keypair, command: u8, spool_id: &[u8]) -> SpoolRequest {
+        SpoolRequest {
+            Command: command,
+            SpoolID: spool_id.to_vec(),
+            Signature: keypair.sign(&keypair.public.to_bytes()).to_bytes().to_vec(),
+            PublicKey: keypair.public.to_bytes().to_vec(),
+            ..SpoolRequest::default()
+        }
+    }
+
+    #[test]
+    fn memory_spool_store_test() {
+        let mut csprng = OsRng::new().unwrap();
+        let keypair = Keypair::generate(&mut csprng);
+        let store = MemorySpoolStore::default();
+
+        let response = create_spool(owner_request(&keypair, CREATE_SPOOL_COMMAND, &[]), &store);
+        assert_eq!(response.Status, "OK");
+        assert!(!response.Existing);
+        let spool_id = response.SpoolID;
+        assert_eq!(store.spool_count(), 1);
+
+        // Retried creations get the spool the first one made.
+        let response = create_spool(owner_request(&keypair, CREATE_SPOOL_COMMAND, &[]), &store);
+        assert!(response.Existing);
+        assert_eq!(response.SpoolID, spool_id);
+        assert_eq!(store.spool_count(), 1);
+
+        let append = SpoolRequest {
+            Command: APPEND_MESSAGE_COMMAND,
+            SpoolID: spool_id.clone(),
+            Message: b"hello".to_vec(),
+            ..SpoolRequest::default()
+        };
+        assert_eq!(append_to_spool(append, &store).Status, "OK");
+
+        let read = |keypair: &Keypair| {
+            let mut request = owner_request(keypair, RETRIEVE_MESSAGE_COMMAND, &spool_id);
+            request.MessageID = vec![0u8; MESSAGE_ID_SIZE];
+            request
+        };
+        let response = read_from_spool(read(&keypair), &store);
+        assert_eq!(response.Status, "OK");
+        assert_eq!(response.Message, b"hello".to_vec());
+        assert!(response.AppendedAt > 0);
+        assert_eq!(response.Remaining, 0);
+
+        let response = spool_status(owner_request(&keypair, SPOOL_STATUS_COMMAND, &spool_id), &store);
+        assert_eq!(response.Status, "OK");
+        let stats: SpoolStats = serde_cbor::from_slice(&response.Message).unwrap();
+        assert_eq!(stats.MessageCount, 1);
+        assert_eq!(stats.SizeBytes, 5);
+        assert_eq!(stats.Head, 1);
+
+        // Someone else's signature reads nothing.
+        let stranger = Keypair::generate(&mut csprng);
+        let mut forged = read(&keypair);
+        forged.Signature = stranger.sign(&keypair.public.to_bytes()).to_bytes().to_vec();
+        assert_ne!(read_from_spool(forged, &store).Status, "OK");
+        let mut forged = owner_request(&keypair, SPOOL_STATUS_COMMAND, &spool_id);
+        forged.Signature = stranger.sign(&keypair.public.to_bytes()).to_bytes().to_vec();
+        assert_ne!(spool_status(forged, &store).Status, "OK");
+
+        // A copy for a new device key holds the same messages.
+        let device = Keypair::generate(&mut csprng);
+        let mut copy = owner_request(&keypair, COPY_SPOOL_COMMAND, &spool_id);
+        copy.Message = device.public.to_bytes().to_vec();
+        let response = copy_spool(copy, &store);
+        assert_eq!(response.Status, "OK");
+        assert_ne!(response.SpoolID, spool_id);
+        let mut request = owner_request(&device, RETRIEVE_MESSAGE_COMMAND, &response.SpoolID);
+        request.MessageID = vec![0u8; MESSAGE_ID_SIZE];
+        assert_eq!(read_from_spool(request, &store).Message, b"hello".to_vec());
+        let mut forged = owner_request(&stranger, COPY_SPOOL_COMMAND, &spool_id);
+        forged.PublicKey = keypair.public.to_bytes().to_vec();
+        assert_ne!(copy_spool(forged, &store).Status, "OK");
+
+        assert_eq!(purge_spool(owner_request(&keypair, PURGE_SPOOL_COMMAND, &spool_id), &store).Status, "OK");
+        assert_ne!(read_from_spool(read(&keypair), &store).Status, "OK");
+        assert_eq!(undelete_spool(owner_request(&keypair, UNDELETE_SPOOL_COMMAND, &spool_id), &store).Status, "OK");
+        assert_eq!(read_from_spool(read(&keypair), &store).Message, b"hello".to_vec());
+
+        // Re-keying keeps the spool ID.
+        let rekey = |keypair: &Keypair| {
+            let mut request = owner_request(keypair, REKEY_SPOOL_COMMAND, &spool_id);
+            request.Message = Rekey::sign(&device, array_ref![spool_id, 0, SPOOL_ID_SIZE], &keypair.public).to_bytes();
+            rekey_spool(request, &store)
+        };
+        assert_ne!(rekey(&stranger).Status, "OK");
+        assert_eq!(rekey(&keypair).Status, "OK");
+        assert_ne!(rekey(&keypair).Status, "OK");
+        assert_eq!(read_from_spool(read(&device), &store).Message, b"hello".to_vec());
+        assert_ne!(read_from_spool(read(&keypair), &store).Status, "OK");
+    }
+
+    #[test]
+    fn memory_spool_options_test() {
+        let mut csprng = OsRng::new().unwrap();
+        let keypair = Keypair::generate(&mut csprng);
+        let reader = Keypair::generate(&mut csprng);
+        let store = MemorySpoolStore::default();
+        let create = |keypair: &Keypair, options: SpoolOptions| {
+            let mut request = owner_request(keypair, CREATE_SPOOL_COMMAND, &[]);
+            request.Options = Some(options);
+            let response = create_spool(request, &store);
+            assert_eq!(response.Status, "OK");
+            response.SpoolID
+        };
+        let append = |spool_id: &[u8], message: &[u8]| {
+            append_to_spool(SpoolRequest {
+                Command: APPEND_MESSAGE_COMMAND,
+                SpoolID: spool_id.to_vec(),
+                Message: message.to_vec(),
+                ..SpoolRequest::default()
+            }, &store).Status
+        };
+        let read = |keypair: &Keypair, spool_id: &[u8], index: u8| {
+            let mut request = owner_request(keypair, RETRIEVE_MESSAGE_COMMAND, spool_id);
+            request.MessageID = vec![0, 0, 0, index];
+            read_from_spool(request, &store)
+        };
+
+        let full = create(&keypair, SpoolOptions {
+            Capacity: 1,
+            Readers: vec![ByteBuf::from(reader.public.to_bytes().to_vec())],
+            ..SpoolOptions::default()
+        });
+        assert_eq!(append(&full, b"one"), "OK");
+        assert_eq!(append(&full, b"two"), SPOOL_FULL_STATUS);
+        assert_eq!(read(&keypair, &full, 0).Remaining, 0);
+        assert_eq!(read(&reader, &full, 0).Message, b"one".to_vec());
+        let stranger = Keypair::generate(&mut csprng);
+        assert_ne!(read(&stranger, &full, 0).Status, "OK");
+
+        let circular_owner = Keypair::generate(&mut csprng);
+        let circular = create(&circular_owner, SpoolOptions {
+            Capacity: 2,
+            Circular: true,
+            HashIndex: true,
+            ..SpoolOptions::default()
+        });
+        for message in &[b"one", b"two", b"six"] {
+            assert_eq!(append(&circular, *message), "OK");
+        }
+        assert_ne!(read(&circular_owner, &circular, 0).Status, "OK");
+        assert_eq!(read(&circular_owner, &circular, 1).Remaining, 1);
+        assert_eq!(read(&circular_owner, &circular, 2).Message, b"six".to_vec());
+
+        let find = |keypair: &Keypair, spool_id: &[u8], message: &[u8]| {
+            let mut request = owner_request(keypair, RETRIEVE_BY_HASH_COMMAND, spool_id);
+            request.Message = merkle::leaf_hash(message).to_vec();
+            read_by_hash(request, &store)
+        };
+        let response = find(&circular_owner, &circular, b"two");
+        assert_eq!(response.MessageID, vec![0, 0, 0, 1]);
+        assert_eq!(response.Message, b"two".to_vec());
+        assert_eq!(response.Remaining, 1);
+        assert_eq!(find(&circular_owner, &circular, b"one").Status, "error: no such message");
+        assert_eq!(find(&keypair, &full, b"one").Status, "error: no hash index");
+
+        let latest = |keypair: &Keypair, spool_id: &[u8], offset: u8| {
+            let mut request = owner_request(keypair, RETRIEVE_LATEST_COMMAND, spool_id);
+            request.Message = vec![0, 0, 0, offset];
+            read_latest(request, &store)
+        };
+        let response = latest(&circular_owner, &circular, 1);
+        assert_eq!(response.MessageID, vec![0, 0, 0, 1]);
+        assert_eq!(response.Message, b"two".to_vec());
+        assert_eq!(response.Remaining, 1);
+        assert_eq!(latest(&circular_owner, &circular, 0).Message, b"six".to_vec());
+        assert_eq!(latest(&circular_owner, &circular, 2).Status, "error: no such message");
+
+        // Duplicates are answered like appends, but not kept.
+        let dedup_owner = Keypair::generate(&mut csprng);
+        let dedup = create(&dedup_owner, SpoolOptions {
+            DedupWindow: 1,
+            ..SpoolOptions::default()
+        });
+        for message in &[b"one", b"one", b"two", b"one"] {
+            assert_eq!(append(&dedup, *message), "OK");
+        }
+        assert_eq!(read(&dedup_owner, &dedup, 0).Remaining, 2);
+        assert_eq!(read(&dedup_owner, &dedup, 2).Message, b"one".to_vec());
+
+        let mut invalid = owner_request(&stranger, CREATE_SPOOL_COMMAND, &[]);
+        invalid.Options = Some(SpoolOptions {
+            Circular: true,
+            ..SpoolOptions::default()
+        });
+        assert_eq!(create_spool(invalid, &store).Status, "error: invalid spool options");
+    }
+}
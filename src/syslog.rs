@@ -0,0 +1,109 @@
+// syslog.rs - log4rs syslog appender.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A log4rs appender writing to the local syslog socket. Journald
+//! listens on the same socket so this also works on systemd hosts.
+
+use std::io;
+use std::error::Error;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use log::{Level, Record};
+use log4rs::append::Append;
+use log_mdc;
+
+use crate::errors::ConfigError;
+use crate::logging::REQUEST_ID_KEY;
+
+
+/// The default local syslog socket.
+pub const DEFAULT_SYSLOG_PATH: &str = "/dev/log";
+
+/// The default syslog facility.
+pub const DEFAULT_SYSLOG_FACILITY: &str = "daemon";
+
+/// The syslog tag our records are sent with.
+const SYSLOG_IDENT: &str = "multispool";
+
+/// Facility is a syslog facility code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Facility(u8);
+
+impl FromStr for Facility {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Facility, ConfigError> {
+        let code = match s {
+            "user" => 1,
+            "daemon" => 3,
+            "local0" => 16,
+            "local1" => 17,
+            "local2" => 18,
+            "local3" => 19,
+            "local4" => 20,
+            "local5" => 21,
+            "local6" => 22,
+            "local7" => 23,
+            _ => return Err(ConfigError::InvalidSyslogFacility(s.to_string())),
+        };
+        Ok(Facility(code))
+    }
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug => 7,
+        Level::Trace => 7,
+    }
+}
+
+/// SyslogAppender sends each log record as an RFC 3164 style
+/// datagram to the local syslog socket.
+#[derive(Debug)]
+pub struct SyslogAppender {
+    socket: UnixDatagram,
+    path: PathBuf,
+    facility: Facility,
+}
+
+impl SyslogAppender {
+    pub fn new<P: AsRef<Path>>(path: P, facility: Facility) -> io::Result<SyslogAppender> {
+        Ok(SyslogAppender {
+            socket: UnixDatagram::unbound()?,
+            path: PathBuf::from(path.as_ref()),
+            facility: facility,
+        })
+    }
+}
+
+impl Append for SyslogAppender {
+    fn append(&self, record: &Record) -> Result<(), Box<Error + Sync + Send>> {
+        let priority = self.facility.0 * 8 + severity(record.level());
+        let request_id = log_mdc::get(REQUEST_ID_KEY, |x| x.map(|x| format!("request {}: ", x)).unwrap_or_default());
+        let message = format!("<{}>{}[{}]: {}{}", priority, SYSLOG_IDENT, process::id(), request_id, record.args());
+        // Sending to the path rather than connecting once lets us
+        // survive syslog daemon restarts.
+        self.socket.send_to(message.as_bytes(), &self.path)?;
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
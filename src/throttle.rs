@@ -0,0 +1,168 @@
+// throttle.rs - Multi-spool signature failure backoff.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Backoff after signature failures
+//!
+//! Purges, reads and token key changes must be signed by the spool
+//! owner or a reader. After `max_failures` consecutive bad signatures
+//! for a spool it backs off for `backoff`, doubling with every further
+//! failure up to MAX_BACKOFF, and bad signatures arriving meanwhile
+//! are refused as rate limited, which blunts brute force attempts.
+//! Signatures are always checked first: a good one is never refused
+//! and resets the count, so others can't keep the owner out of their
+//! spool.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::spool::SPOOL_ID_SIZE;
+
+
+/// The number of consecutive failures before backing off by default.
+pub const DEFAULT_MAX_SIGNATURE_FAILURES: u32 = 5;
+
+/// The first backoff after too many failures by default, in
+/// milliseconds.
+pub const DEFAULT_SIGNATURE_BACKOFF_MS: u64 = 1000;
+
+/// The longest a spool ever backs off for.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+struct Failures {
+    count: u32,
+    backoff_until: Option<Instant>,
+}
+
+/// SignatureBackoff counts the consecutive signature failures of
+/// each spool.
+pub struct SignatureBackoff {
+    max_failures: u32,
+    backoff: Duration,
+    failures: HashMap<[u8; SPOOL_ID_SIZE], Failures>,
+}
+
+impl Default for SignatureBackoff {
+    fn default() -> SignatureBackoff {
+        SignatureBackoff::new(DEFAULT_MAX_SIGNATURE_FAILURES,
+                              Duration::from_millis(DEFAULT_SIGNATURE_BACKOFF_MS))
+    }
+}
+
+impl SignatureBackoff {
+    pub fn new(max_failures: u32, backoff: Duration) -> SignatureBackoff {
+        SignatureBackoff {
+            max_failures: max_failures,
+            backoff: backoff,
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Changes the policy, keeping the current failure counts.
+    pub fn set_policy(&mut self, max_failures: u32, backoff: Duration) {
+        self.max_failures = max_failures;
+        self.backoff = backoff;
+    }
+
+    /// Returns how much longer bad signatures for the spool are
+    /// refused, if it is backing off.
+    pub fn backoff_at(&self, spool_id: &[u8; SPOOL_ID_SIZE], now: Instant) -> Option<Duration> {
+        match self.failures.get(spool_id).and_then(|x| x.backoff_until) {
+            Some(until) if now < until => Some(until - now),
+            _ => None,
+        }
+    }
+
+    /// Records a bad signature, returning true if it came while the
+    /// spool was backing off and so is to be refused as rate limited.
+    pub fn failure(&mut self, spool_id: &[u8; SPOOL_ID_SIZE]) -> bool {
+        self.failure_at(spool_id, Instant::now())
+    }
+
+    fn failure_at(&mut self, spool_id: &[u8; SPOOL_ID_SIZE], now: Instant) -> bool {
+        let backing_off = self.backoff_at(spool_id, now).is_some();
+        let failures = self.failures.entry(*spool_id).or_insert(Failures {
+            count: 0,
+            backoff_until: None,
+        });
+        failures.count = failures.count.saturating_add(1);
+        if failures.count >= self.max_failures {
+            let doublings = cmp::min(failures.count - self.max_failures, 31);
+            let backoff = self.backoff.checked_mul(1 << doublings).unwrap_or(MAX_BACKOFF);
+            failures.backoff_until = Some(now + cmp::min(backoff, MAX_BACKOFF));
+        }
+        backing_off
+    }
+
+    /// Records a good signature, forgetting earlier failures.
+    pub fn success(&mut self, spool_id: &[u8; SPOOL_ID_SIZE]) {
+        self.failures.remove(spool_id);
+    }
+
+    /// Forgets a deleted spool.
+    pub fn remove_spool(&mut self, spool_id: &[u8; SPOOL_ID_SIZE]) {
+        self.failures.remove(spool_id);
+    }
+
+    /// Returns the number of spools with signature failures counted.
+    pub fn len(&self) -> usize {
+        self.failures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_curve_test() {
+        let alice = [1u8; SPOOL_ID_SIZE];
+        let bob = [2u8; SPOOL_ID_SIZE];
+        let mut backoff = SignatureBackoff::new(2, Duration::from_secs(1));
+        let now = Instant::now();
+        assert!(!backoff.failure_at(&alice, now));
+        assert_eq!(backoff.backoff_at(&alice, now), None);
+        assert!(!backoff.failure_at(&alice, now));
+        assert_eq!(backoff.backoff_at(&alice, now), Some(Duration::from_secs(1)));
+        assert_eq!(backoff.backoff_at(&bob, now), None);
+        assert_eq!(backoff.len(), 1);
+
+        // Failures while backing off are refused, and each doubles
+        // the backoff, up to MAX_BACKOFF.
+        for i in 1..12 {
+            assert!(backoff.failure_at(&alice, now));
+            assert_eq!(backoff.backoff_at(&alice, now), Some(Duration::from_secs(1 << i)));
+        }
+        assert!(backoff.failure_at(&alice, now));
+        assert_eq!(backoff.backoff_at(&alice, now), Some(MAX_BACKOFF));
+        for _ in 0..40 {
+            backoff.failure_at(&alice, now);
+        }
+        assert_eq!(backoff.backoff_at(&alice, now), Some(MAX_BACKOFF));
+        assert_eq!(backoff.backoff_at(&alice, now + MAX_BACKOFF), None);
+        assert!(!backoff.failure_at(&alice, now + MAX_BACKOFF));
+
+        // A good signature forgets the failures.
+        backoff.success(&alice);
+        assert_eq!(backoff.backoff_at(&alice, now), None);
+        assert!(backoff.is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+// tokens.rs - Multi-spool blinded append tokens.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Single-use blinded append tokens
+//!
+//! A spool owner may register a token key with the service, after
+//! which appends to the spool must each spend a token issued by the
+//! owner. Tokens are issued blindly, as in Privacy Pass: a
+//! correspondent hashes a random nonce to a Ristretto point `T`,
+//! sends the owner `r * T` for a random `r`, and unblinds the owner's
+//! answer `k * r * T` into `k * T`. The token is the nonce and
+//! `k * T`, which the service checks against the owner's key `k`
+//! and marks as spent. Neither the service nor the owner can link a
+//! token spent with an append to the correspondent it was issued to.
+//!
+//! Issuance is not verifiable, so an owner could tell correspondents
+//! apart by signing with different keys; owners are trusted with
+//! their own spools.
+
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::errors::TokenError;
+
+
+/// The size of an encoded token key in bytes.
+pub const TOKEN_KEY_SIZE: usize = 32;
+
+/// The size of a token nonce in bytes.
+pub const TOKEN_NONCE_SIZE: usize = 32;
+
+/// The size of an encoded blinded or signed token in bytes.
+pub const BLINDED_TOKEN_SIZE: usize = 32;
+
+/// The size of an append token in bytes, its nonce and signed point.
+pub const APPEND_TOKEN_SIZE: usize = TOKEN_NONCE_SIZE + 32;
+
+/// Prefix of the data token nonces are hashed to points with.
+const TOKEN_CONTEXT: &[u8] = b"multispool append token v1";
+
+fn random_scalar() -> Scalar {
+    let mut wide = [0u8; 64];
+    thread_rng().fill(&mut wide[..]);
+    Scalar::from_bytes_mod_order_wide(&wide)
+}
+
+fn token_point(spool_id: &[u8], nonce: &[u8]) -> RistrettoPoint {
+    let mut input = TOKEN_CONTEXT.to_vec();
+    input.extend_from_slice(spool_id);
+    input.extend_from_slice(nonce);
+    RistrettoPoint::hash_from_bytes::<Sha512>(&input)
+}
+
+fn decode_point(bytes: &[u8]) -> Result<RistrettoPoint, TokenError> {
+    if bytes.len() != 32 {
+        return Err(TokenError::InvalidPoint)
+    }
+    CompressedRistretto::from_slice(bytes).decompress().ok_or(TokenError::InvalidPoint)
+}
+
+/// Returns the identity a spent token is remembered by.
+pub fn token_id(token: &[u8]) -> Vec<u8> {
+    Sha256::digest(&token[..TOKEN_NONCE_SIZE.min(token.len())]).to_vec()
+}
+
+/// TokenKey is the spool owner's secret key tokens are signed with.
+/// The service holds a copy to check tokens against.
+pub struct TokenKey {
+    scalar: Scalar,
+}
+
+impl TokenKey {
+    pub fn generate() -> TokenKey {
+        TokenKey {
+            scalar: random_scalar(),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<TokenKey, TokenError> {
+        if bytes.len() != TOKEN_KEY_SIZE {
+            return Err(TokenError::InvalidKey)
+        }
+        let mut raw = [0u8; TOKEN_KEY_SIZE];
+        raw.copy_from_slice(bytes);
+        match Scalar::from_canonical_bytes(raw) {
+            Some(scalar) if scalar != Scalar::zero() => Ok(TokenKey {
+                scalar: scalar,
+            }),
+            _ => Err(TokenError::InvalidKey),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; TOKEN_KEY_SIZE] {
+        self.scalar.to_bytes()
+    }
+
+    /// Signs a correspondent's blinded token.
+    pub fn sign_blinded(&self, blinded: &[u8]) -> Result<[u8; BLINDED_TOKEN_SIZE], TokenError> {
+        Ok((self.scalar * decode_point(blinded)?).compress().to_bytes())
+    }
+
+    /// Checks that `token` was issued with this key for the spool.
+    pub fn verify(&self, spool_id: &[u8], token: &[u8]) -> bool {
+        if token.len() != APPEND_TOKEN_SIZE {
+            return false
+        }
+        match decode_point(&token[TOKEN_NONCE_SIZE..]) {
+            Ok(signed) => signed == self.scalar * token_point(spool_id, &token[..TOKEN_NONCE_SIZE]),
+            Err(_) => false,
+        }
+    }
+}
+
+/// BlindedToken is a correspondent's token waiting to be signed by
+/// the spool owner.
+pub struct BlindedToken {
+    spool_id: Vec<u8>,
+    nonce: [u8; TOKEN_NONCE_SIZE],
+    blind: Scalar,
+}
+
+impl BlindedToken {
+    /// Starts a token for appending to the spool.
+    pub fn new(spool_id: &[u8]) -> BlindedToken {
+        let mut nonce = [0u8; TOKEN_NONCE_SIZE];
+        thread_rng().fill(&mut nonce);
+        BlindedToken {
+            spool_id: spool_id.to_vec(),
+            nonce: nonce,
+            blind: random_scalar(),
+        }
+    }
+
+    /// Returns the blinded token to send to the spool owner.
+    pub fn blinded(&self) -> [u8; BLINDED_TOKEN_SIZE] {
+        (self.blind * token_point(&self.spool_id, &self.nonce)).compress().to_bytes()
+    }
+
+    /// Unblinds the owner's signature into an append token.
+    pub fn unblind(&self, signed: &[u8]) -> Result<Vec<u8>, TokenError> {
+        let unblinded = self.blind.invert() * decode_point(signed)?;
+        let mut token = self.nonce.to_vec();
+        token.extend_from_slice(unblinded.compress().as_bytes());
+        Ok(token)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blind_token_test() {
+        let key = TokenKey::generate();
+        let request = BlindedToken::new(b"spool id");
+        let token = request.unblind(&key.sign_blinded(&request.blinded()).unwrap()).unwrap();
+        assert_eq!(token.len(), APPEND_TOKEN_SIZE);
+        assert!(key.verify(b"spool id", &token));
+
+        // Tokens are bound to the key and spool.
+        assert!(!TokenKey::generate().verify(b"spool id", &token));
+        assert!(!key.verify(b"other id", &token));
+        let mut forged = token.clone();
+        forged[0] ^= 1;
+        assert!(!key.verify(b"spool id", &forged));
+
+        // The owner never sees the token it signed.
+        assert!(request.blinded()[..] != token[TOKEN_NONCE_SIZE..]);
+        assert_eq!(TokenKey::from_bytes(&key.to_bytes()).unwrap().to_bytes(), key.to_bytes());
+        assert!(TokenKey::from_bytes(&[0u8; TOKEN_KEY_SIZE]).is_err());
+        assert!(key.sign_blinded(&[1u8; 31]).is_err());
+    }
+}
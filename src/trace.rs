@@ -0,0 +1,234 @@
+// trace.rs - Request tracing.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Request tracing. Open spans are kept on a per thread stack so that
+//! a span started while another is open becomes its child. Finished
+//! spans are handed to the installed exporter, typically an
+//! OpenTelemetry collector reached over OTLP/HTTP. Without an
+//! exporter spans cost next to nothing.
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rand::{thread_rng, Rng};
+use serde_json::{self, Value};
+
+
+/// How often queued spans are sent to the collector.
+const EXPORT_INTERVAL_MS: u64 = 5000;
+
+/// Spans beyond this many waiting for export are dropped.
+const MAX_QUEUED_SPANS: usize = 10000;
+
+/// Socket timeout when talking to the collector.
+const EXPORT_TIMEOUT_MS: u64 = 5000;
+
+/// The service name reported to the collector.
+const SERVICE_NAME: &str = "multispool";
+
+/// SpanData is a finished span.
+#[derive(Clone, Debug)]
+pub struct SpanData {
+    pub name: &'static str,
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_span_id: Option<[u8; 8]>,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, String)>,
+}
+
+/// SpanExporter receives every finished span.
+pub trait SpanExporter: Send + Sync {
+    fn export(&self, span: SpanData);
+}
+
+lazy_static! {
+    static ref EXPORTER: Mutex<Option<Arc<SpanExporter>>> = Mutex::new(None);
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static CURRENT: RefCell<Vec<([u8; 16], [u8; 8])>> = RefCell::new(vec![]);
+}
+
+/// Installs the exporter all finished spans are sent to.
+pub fn set_exporter(exporter: Arc<SpanExporter>) {
+    *EXPORTER.lock().unwrap_or_else(PoisonError::into_inner) = Some(exporter);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Span measures an operation from its creation until it is dropped.
+pub struct Span {
+    data: Option<SpanData>,
+}
+
+/// Starts a span as a child of this thread's innermost open span, or
+/// as the root of a new trace.
+pub fn span(name: &'static str) -> Span {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Span { data: None }
+    }
+    let mut rng = thread_rng();
+    let span_id: [u8; 8] = rng.gen();
+    let (trace_id, parent_span_id) = CURRENT.with(|current| {
+        match current.borrow().last() {
+            Some(&(trace_id, parent)) => (trace_id, Some(parent)),
+            None => (rng.gen(), None),
+        }
+    });
+    CURRENT.with(|current| current.borrow_mut().push((trace_id, span_id)));
+    let now = SystemTime::now();
+    Span {
+        data: Some(SpanData {
+            name: name,
+            trace_id: trace_id,
+            span_id: span_id,
+            parent_span_id: parent_span_id,
+            start: now,
+            end: now,
+            attributes: vec![],
+        }),
+    }
+}
+
+impl Span {
+    /// Attaches an attribute to the span.
+    pub fn set_attribute(&mut self, key: &'static str, value: String) {
+        if let Some(ref mut data) = self.data {
+            data.attributes.push((key, value));
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(mut data) = self.data.take() {
+            let span_id = data.span_id;
+            CURRENT.with(|current| current.borrow_mut().retain(|&(_, id)| id != span_id));
+            data.end = SystemTime::now();
+            let exporter = match EXPORTER.lock() {
+                Ok(exporter) => exporter.clone(),
+                Err(_) => None,
+            };
+            if let Some(exporter) = exporter {
+                exporter.export(data);
+            }
+        }
+    }
+}
+
+/// OtlpExporter batches finished spans and periodically posts them
+/// as OTLP/HTTP JSON to an OpenTelemetry collector.
+pub struct OtlpExporter {
+    queue: Mutex<Vec<SpanData>>,
+}
+
+impl OtlpExporter {
+    /// Starts exporting to the collector at `endpoint`, for example
+    /// http://127.0.0.1:4318. Only plain HTTP is supported so the
+    /// collector should be local.
+    pub fn start(endpoint: &str) -> Arc<OtlpExporter> {
+        let address = endpoint.trim_start_matches("http://").trim_end_matches('/').to_string();
+        let exporter = Arc::new(OtlpExporter {
+            queue: Mutex::new(vec![]),
+        });
+        let background = exporter.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(EXPORT_INTERVAL_MS));
+            let spans = mem::replace(&mut *background.queue.lock().unwrap_or_else(PoisonError::into_inner), vec![]);
+            if spans.is_empty() {
+                continue
+            }
+            if let Err(e) = post(&address, &encode(&spans)) {
+                warn!("FAILED to export {} spans to {}: {}", spans.len(), address, e);
+            }
+        });
+        exporter
+    }
+}
+
+impl SpanExporter for OtlpExporter {
+    fn export(&self, span: SpanData) {
+        let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        if queue.len() < MAX_QUEUED_SPANS {
+            queue.push(span);
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    format!("{}", since_epoch.as_secs() as u128 * 1_000_000_000 + since_epoch.subsec_nanos() as u128)
+}
+
+/// Encodes spans as an OTLP ExportTraceServiceRequest in JSON.
+fn encode(spans: &[SpanData]) -> Vec<u8> {
+    let spans: Vec<Value> = spans.iter().map(|span| {
+        let attributes: Vec<Value> = span.attributes.iter().map(|&(key, ref value)| {
+            json!({"key": key, "value": {"stringValue": value}})
+        }).collect();
+        json!({
+            "traceId": hex(&span.trace_id),
+            "spanId": hex(&span.span_id),
+            "parentSpanId": span.parent_span_id.map(|x| hex(&x)).unwrap_or_default(),
+            "name": span.name,
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(span.start),
+            "endTimeUnixNano": unix_nanos(span.end),
+            "attributes": attributes,
+        })
+    }).collect();
+    let request = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": SERVICE_NAME}}],
+            },
+            "scopeSpans": [{
+                "scope": {"name": SERVICE_NAME},
+                "spans": spans,
+            }],
+        }],
+    });
+    serde_json::to_vec(&request).unwrap()
+}
+
+fn post(address: &str, body: &[u8]) -> io::Result<()> {
+    let timeout = Some(Duration::from_millis(EXPORT_TIMEOUT_MS));
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    write!(stream, "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+           address, body.len())?;
+    stream.write_all(body)?;
+    let mut status_line = [0u8; 12];
+    stream.read_exact(&mut status_line)?;
+    if &status_line[9..10] != b"2" {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("collector responded {}", String::from_utf8_lossy(&status_line))))
+    }
+    Ok(())
+}
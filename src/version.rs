@@ -0,0 +1,124 @@
+// version.rs - Multi-spool version and build information.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+
+/// The crate version.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The major version of the spool protocol spoken by this build.
+/// Peers of another major version can't understand each other.
+pub const PROTOCOL_VERSION: u8 = 0;
+
+/// The minor version of the spool protocol spoken by this build.
+/// Minor versions only add fields, which older peers ignore.
+pub const PROTOCOL_MINOR_VERSION: u8 = 1;
+
+/// The major protocol versions this build serves.
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[u8] = &[PROTOCOL_VERSION];
+
+/// Returns the git commit this build was made from, or "unknown" if
+/// it was not built from a git checkout.
+pub fn git_commit() -> &'static str {
+    option_env!("MULTISPOOL_GIT_COMMIT").unwrap_or("unknown")
+}
+
+/// Returns the names of the optional cargo features this build was
+/// compiled with.
+pub fn features() -> Vec<&'static str> {
+    vec![]
+}
+
+/// ProtocolVersion is the version of the spool protocol a request or
+/// response is in.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(default)]
+#[allow(non_snake_case)]
+pub struct ProtocolVersion {
+    pub Major: u8,
+    pub Minor: u8,
+}
+
+impl ProtocolVersion {
+    /// Returns the protocol version of this build.
+    pub fn current() -> ProtocolVersion {
+        ProtocolVersion {
+            Major: PROTOCOL_VERSION,
+            Minor: PROTOCOL_MINOR_VERSION,
+        }
+    }
+
+    /// Returns true if this build serves requests of this version.
+    pub fn is_supported(&self) -> bool {
+        SUPPORTED_PROTOCOL_VERSIONS.contains(&self.Major)
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.Major, self.Minor)
+    }
+}
+
+/// BuildInfo describes a deployed build so that operators can audit
+/// which plugin versions are running.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[allow(non_snake_case)]
+pub struct BuildInfo {
+    pub Version: String,
+    pub GitCommit: String,
+    pub ProtocolVersion: u8,
+    pub Features: Vec<String>,
+}
+
+impl BuildInfo {
+    pub fn new() -> BuildInfo {
+        BuildInfo {
+            Version: VERSION.to_string(),
+            GitCommit: git_commit().to_string(),
+            ProtocolVersion: PROTOCOL_VERSION,
+            Features: features().iter().map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_cbor;
+    use super::*;
+
+    #[test]
+    fn build_info_cbor_test() {
+        let build_info = BuildInfo::new();
+        assert_eq!(build_info.Version, VERSION);
+        let raw = serde_cbor::to_vec(&build_info).unwrap();
+        let decoded: BuildInfo = serde_cbor::from_slice(&raw).unwrap();
+        assert_eq!(decoded, build_info);
+    }
+
+    #[test]
+    fn protocol_version_test() {
+        let current = ProtocolVersion::current();
+        assert!(current.is_supported());
+        assert!(ProtocolVersion::default().is_supported());
+        let next = ProtocolVersion {
+            Major: PROTOCOL_VERSION + 1,
+            Minor: 0,
+        };
+        assert!(!next.is_supported());
+        assert_eq!(format!("{}", next), format!("{}.0", PROTOCOL_VERSION + 1));
+    }
+}
@@ -0,0 +1,95 @@
+// main.go - Writes the golden wire format vectors with the Go memspool.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Command gen writes the golden wire format vectors in testdata/golden
+// by encoding the memspool's own SpoolRequest and SpoolResponse types
+// with the codec the memspool encodes them with.
+package main
+
+import (
+	"io/ioutil"
+	"log"
+	"path/filepath"
+
+	"github.com/katzenpost/memspool/common"
+	"github.com/ugorji/go/codec"
+)
+
+var (
+	spoolID = [common.SpoolIDSize]byte{1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12}
+	message = []byte("golden vector message")
+)
+
+// pattern returns n bytes counting up from first, which is all the
+// fixtures need in place of signatures and keys.
+func pattern(first byte, n int) []byte {
+	b := make([]byte, n)
+	for i := range b {
+		b[i] = first + byte(i)
+	}
+	return b
+}
+
+func write(dir, name string, v interface{}) {
+	var raw []byte
+	if err := codec.NewEncoderBytes(&raw, new(codec.CborHandle)).Encode(v); err != nil {
+		log.Fatalf("encoding %s: %v", name, err)
+	}
+	if err := ioutil.WriteFile(filepath.Join(dir, name), raw, 0644); err != nil {
+		log.Fatal(err)
+	}
+}
+
+func main() {
+	dir := ".."
+	signature := pattern(0x40, 64)
+	publicKey := pattern(0xa0, 32)
+	write(dir, "create_request.cbor", &common.SpoolRequest{
+		Command:   common.CreateSpoolCommand,
+		Signature: signature,
+		PublicKey: publicKey,
+	})
+	write(dir, "purge_request.cbor", &common.SpoolRequest{
+		Command:   common.PurgeSpoolCommand,
+		SpoolID:   spoolID,
+		Signature: signature,
+		PublicKey: publicKey,
+	})
+	write(dir, "append_request.cbor", &common.SpoolRequest{
+		Command: common.AppendMessageCommand,
+		SpoolID: spoolID,
+		Message: message,
+	})
+	write(dir, "read_request.cbor", &common.SpoolRequest{
+		Command:   common.RetrieveMessageCommand,
+		SpoolID:   spoolID,
+		Signature: signature,
+		PublicKey: publicKey,
+		MessageID: 7,
+	})
+	write(dir, "create_response.cbor", &common.SpoolResponse{
+		SpoolID: spoolID,
+		Status:  "OK",
+	})
+	write(dir, "read_response.cbor", &common.SpoolResponse{
+		SpoolID: spoolID,
+		Message: message,
+		Status:  "OK",
+	})
+	write(dir, "error_response.cbor", &common.SpoolResponse{
+		Status: "error: invalid signature",
+	})
+}
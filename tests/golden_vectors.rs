@@ -0,0 +1,107 @@
+// golden_vectors.rs - Wire format interoperability tests.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Checks that the CBOR fixtures in testdata/golden, as encoded by
+//! the Go memspool, decode into our SpoolRequest and SpoolResponse
+//! types, and that our encoding of them decodes back to the same
+//! fields. See testdata/golden/README.md for how the fixtures are made.
+
+extern crate serde_cbor;
+extern crate multispool;
+
+use multispool::{SpoolRequest, SpoolResponse,
+                 CREATE_SPOOL_COMMAND, PURGE_SPOOL_COMMAND,
+                 APPEND_MESSAGE_COMMAND, RETRIEVE_MESSAGE_COMMAND};
+
+const SPOOL_ID: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+const MESSAGE: &[u8] = b"golden vector message";
+
+fn decode_request(raw: &[u8]) -> SpoolRequest {
+    let request: SpoolRequest = serde_cbor::from_slice(raw).unwrap();
+    let again: SpoolRequest = serde_cbor::from_slice(&serde_cbor::to_vec(&request).unwrap()).unwrap();
+    assert_eq!(again.Command, request.Command);
+    assert_eq!(again.SpoolID, request.SpoolID);
+    assert_eq!(again.Signature, request.Signature);
+    assert_eq!(again.PublicKey, request.PublicKey);
+    assert_eq!(again.MessageID, request.MessageID);
+    assert_eq!(again.Message, request.Message);
+    request
+}
+
+fn decode_response(raw: &[u8]) -> SpoolResponse {
+    let response: SpoolResponse = serde_cbor::from_slice(raw).unwrap();
+    let again: SpoolResponse = serde_cbor::from_slice(&serde_cbor::to_vec(&response).unwrap()).unwrap();
+    assert_eq!(again.SpoolID, response.SpoolID);
+    assert_eq!(again.Message, response.Message);
+    assert_eq!(again.Status, response.Status);
+    response
+}
+
+#[test]
+fn create_request_test() {
+    let request = decode_request(include_bytes!("../testdata/golden/create_request.cbor"));
+    assert_eq!(request.Command, CREATE_SPOOL_COMMAND);
+    assert_eq!(request.SpoolID, vec![0u8; 12]);
+    assert!(request.Message.is_empty());
+    assert_eq!(request.Signature.len(), 64);
+    assert_eq!(request.PublicKey.len(), 32);
+}
+
+#[test]
+fn purge_request_test() {
+    let request = decode_request(include_bytes!("../testdata/golden/purge_request.cbor"));
+    assert_eq!(request.Command, PURGE_SPOOL_COMMAND);
+    assert_eq!(request.SpoolID, SPOOL_ID.to_vec());
+}
+
+#[test]
+fn append_request_test() {
+    let request = decode_request(include_bytes!("../testdata/golden/append_request.cbor"));
+    assert_eq!(request.Command, APPEND_MESSAGE_COMMAND);
+    assert_eq!(request.SpoolID, SPOOL_ID.to_vec());
+    assert_eq!(request.Message, MESSAGE.to_vec());
+    assert!(request.Signature.is_empty());
+    assert!(request.PublicKey.is_empty());
+}
+
+#[test]
+fn read_request_test() {
+    let request = decode_request(include_bytes!("../testdata/golden/read_request.cbor"));
+    assert_eq!(request.Command, RETRIEVE_MESSAGE_COMMAND);
+    assert_eq!(request.SpoolID, SPOOL_ID.to_vec());
+    assert_eq!(request.MessageID, vec![0, 0, 0, 7]);
+}
+
+#[test]
+fn create_response_test() {
+    let response = decode_response(include_bytes!("../testdata/golden/create_response.cbor"));
+    assert_eq!(response.SpoolID, SPOOL_ID.to_vec());
+    assert!(response.Message.is_empty());
+    assert_eq!(response.Status, "OK");
+}
+
+#[test]
+fn read_response_test() {
+    let response = decode_response(include_bytes!("../testdata/golden/read_response.cbor"));
+    assert_eq!(response.Message, MESSAGE.to_vec());
+}
+
+#[test]
+fn error_response_test() {
+    let response = decode_response(include_bytes!("../testdata/golden/error_response.cbor"));
+    assert_eq!(response.SpoolID, vec![0u8; 12]);
+    assert_eq!(response.Status, "error: invalid signature");
+}
@@ -0,0 +1,126 @@
+// spool_properties.rs - Property tests of spool invariants.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Runs random sequences of spool operations against a spool on disk
+//! and a vector of the messages it should hold, checking after every
+//! step that the two agree.
+
+extern crate byteorder;
+extern crate multispool;
+extern crate proptest;
+extern crate tempfile;
+
+use std::path::Path;
+use byteorder::{ByteOrder, BigEndian};
+use proptest::prelude::*;
+use tempfile::tempdir;
+
+use multispool::errors::SpoolError;
+use multispool::merkle;
+use multispool::spool::{Spool, MESSAGE_ID_SIZE};
+
+#[derive(Clone, Debug)]
+enum Op {
+    Append(Vec<u8>),
+    Flush,
+    Purge,
+    /// Drops the spool without flushing it and opens it again.
+    Crash,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        6 => prop::collection::vec(any::<u8>(), 0..64).prop_map(Op::Append),
+        1 => Just(Op::Flush),
+        1 => Just(Op::Purge),
+        1 => Just(Op::Crash),
+    ]
+}
+
+fn message_id(index: usize) -> [u8; MESSAGE_ID_SIZE] {
+    let mut message_id = [0u8; MESSAGE_ID_SIZE];
+    BigEndian::write_u32(&mut message_id, index as u32);
+    message_id
+}
+
+/// Checks that the spool holds exactly `messages`, under contiguous
+/// message IDs starting at zero.
+fn check_spool(spool: &Spool, messages: &[Vec<u8>]) -> Result<(), TestCaseError> {
+    prop_assert_eq!(spool.message_count(), messages.len() as u64);
+    for (i, message) in messages.iter().enumerate() {
+        prop_assert_eq!(&spool.read(&message_id(i)).unwrap(), message);
+    }
+    match spool.read(&message_id(messages.len())) {
+        Err(SpoolError::NoSuchMessage) => {},
+        other => prop_assert!(false, "read past the head returned {:?}", other.map(|x| x.len())),
+    }
+    let leaves: Vec<merkle::Hash> = messages.iter().map(|x| merkle::leaf_hash(x)).collect();
+    prop_assert_eq!(spool.leaf_hashes().unwrap(), leaves);
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn append_then_read_returns_the_message(message in prop::collection::vec(any::<u8>(), 0..4096)) {
+        let base_dir = tempdir().unwrap();
+        let mut spool = Spool::new(&Path::new(base_dir.path()).join("spool.sled")).unwrap();
+        spool.append(&message).unwrap();
+        check_spool(&spool, &[message])?;
+    }
+
+    #[test]
+    fn operation_sequences_keep_invariants(ops in prop::collection::vec(op(), 0..40)) {
+        let base_dir = tempdir().unwrap();
+        let path = Path::new(base_dir.path()).join("spool.sled");
+        let mut spool = Spool::new(&path).unwrap();
+        let mut messages: Vec<Vec<u8>> = vec![];
+        // The number of messages known to be on disk.
+        let mut durable = 0;
+        for op in ops {
+            match op {
+                Op::Append(message) => {
+                    spool.append(&message).unwrap();
+                    messages.push(message);
+                },
+                Op::Flush => {
+                    spool.flush().unwrap();
+                    durable = messages.len();
+                },
+                Op::Purge => {
+                    // Purges aren't durable until flushed, like appends.
+                    spool.purge().unwrap();
+                    spool.flush().unwrap();
+                    messages.clear();
+                    durable = 0;
+                },
+                Op::Crash => {
+                    drop(spool);
+                    spool = Spool::new(&path).unwrap();
+                    // The flushed head survives, and whatever else does
+                    // is a prefix of the messages appended since.
+                    let recovered = spool.message_count() as usize;
+                    prop_assert!(recovered >= durable && recovered <= messages.len(),
+                                 "recovered {} messages, {} flushed of {}", recovered, durable, messages.len());
+                    messages.truncate(recovered);
+                    durable = recovered;
+                },
+            }
+            check_spool(&spool, &messages)?;
+        }
+    }
+}
@@ -0,0 +1,189 @@
+// stress.rs - Concurrency stress tests of MultiSpool.
+// Copyright (C) 2019  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Hammers a MultiSpool shared by many threads with a mix of creates,
+//! appends, reads and purges, checking that every operation either
+//! succeeds with the right data or fails because the spool or message
+//! is gone, and that the spools left over are intact.
+
+extern crate byteorder;
+extern crate ed25519_dalek;
+extern crate multispool;
+extern crate rand;
+extern crate tempfile;
+
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::Duration;
+use byteorder::{ByteOrder, BigEndian};
+use ed25519_dalek::{Keypair, Signature};
+use rand::rngs::OsRng;
+use rand::{thread_rng, Rng};
+use tempfile::{tempdir, TempDir};
+
+use multispool::errors::{MultiSpoolError, SpoolError, SpoolSetError};
+use multispool::spool::{MultiSpool, SPOOL_ID_SIZE, MESSAGE_ID_SIZE};
+
+const THREADS: usize = 8;
+const OPS_PER_THREAD: usize = 200;
+
+/// The number of messages read back by the purge races.
+const RACE_MESSAGES: u8 = 10;
+
+fn multi_spool() -> (TempDir, MultiSpool) {
+    let dir = tempdir().unwrap();
+    let multi_spool = MultiSpool::new(&String::from(dir.path().to_str().unwrap())).unwrap();
+    // Delete purged spools at once, the case most likely to race.
+    multi_spool.set_purge_grace_period(Duration::from_secs(0));
+    (dir, multi_spool)
+}
+
+fn owner() -> (Keypair, Signature) {
+    let keypair = Keypair::generate(&mut OsRng::new().unwrap());
+    let signature = keypair.sign(&keypair.public.to_bytes());
+    (keypair, signature)
+}
+
+fn message_id(index: u32) -> [u8; MESSAGE_ID_SIZE] {
+    let mut message_id = [0u8; MESSAGE_ID_SIZE];
+    BigEndian::write_u32(&mut message_id, index);
+    message_id
+}
+
+/// Returns true if the error only says that a spool was purged,
+/// which a spool set lookup and a spool lookup report differently.
+fn spool_is_gone(error: &MultiSpoolError) -> bool {
+    matches!(error, MultiSpoolError::NoSuchSpool | MultiSpoolError::SpoolSetError(SpoolSetError::NoSuchSpoolId))
+}
+
+fn message_is_missing(error: &MultiSpoolError) -> bool {
+    match error {
+        MultiSpoolError::SpoolError(SpoolError::NoSuchMessage) => true,
+        _ => spool_is_gone(error),
+    }
+}
+
+#[test]
+fn mixed_operations_stress_test() {
+    let (_dir, multi_spool) = multi_spool();
+    let (keypair, signature) = owner();
+    let spools: Arc<Mutex<Vec<[u8; SPOOL_ID_SIZE]>>> = Arc::new(Mutex::new(vec![]));
+    let threads: Vec<_> = (0..THREADS).map(|_| {
+        let multi_spool = multi_spool.clone();
+        let spools = spools.clone();
+        let public_key = keypair.public;
+        thread::spawn(move || {
+            let mut rng = thread_rng();
+            let mut csprng = OsRng::new().unwrap();
+            for _ in 0..OPS_PER_THREAD {
+                let op = rng.gen_range(0, 10);
+                if op < 2 || spools.lock().unwrap().is_empty() {
+                    let spool_id = multi_spool.create_spool(public_key, signature, &mut csprng).unwrap();
+                    spools.lock().unwrap().push(spool_id);
+                    continue
+                }
+                let spool_id = {
+                    let spools = spools.lock().unwrap();
+                    spools[rng.gen_range(0, spools.len())]
+                };
+                // Every message is the ID of its spool, so that a read
+                // returning another spool's message is caught.
+                match op {
+                    2..=5 => match multi_spool.append_to_spool(spool_id, &spool_id) {
+                        Ok(()) => {},
+                        Err(ref e) if spool_is_gone(e) => {},
+                        Err(e) => panic!("append failed: {}", e),
+                    },
+                    6..=8 => match multi_spool.read_from_spool(spool_id, signature, &message_id(rng.gen_range(0, 8))) {
+                        Ok(message) => assert_eq!(message, spool_id.to_vec()),
+                        Err(ref e) if message_is_missing(e) => {},
+                        Err(e) => panic!("read failed: {}", e),
+                    },
+                    _ => match multi_spool.purge_spool(spool_id, signature) {
+                        Ok(()) => spools.lock().unwrap().retain(|x| *x != spool_id),
+                        Err(ref e) if spool_is_gone(e) => {},
+                        Err(e) => panic!("purge failed: {}", e),
+                    },
+                }
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let spools = spools.lock().unwrap();
+    assert_eq!(multi_spool.spool_count(), spools.len());
+    for spool_id in spools.iter() {
+        multi_spool.verify_spool(*spool_id).unwrap();
+        let count = multi_spool.spool_info(*spool_id).unwrap().message_count;
+        for index in 0..count {
+            let message = multi_spool.read_from_spool(*spool_id, signature, &message_id(index as u32)).unwrap();
+            assert_eq!(message, spool_id.to_vec());
+        }
+    }
+}
+
+#[test]
+fn purge_racing_read_test() {
+    let (_dir, multi_spool) = multi_spool();
+    let (keypair, signature) = owner();
+    let mut csprng = OsRng::new().unwrap();
+    for _ in 0..20 {
+        let spool_id = multi_spool.create_spool(keypair.public, signature, &mut csprng).unwrap();
+        for i in 0..RACE_MESSAGES {
+            multi_spool.append_to_spool(spool_id, &[i]).unwrap();
+        }
+
+        // Readers and an appender start together with the purge, and
+        // keep going until they see the spool gone.
+        let barrier = Arc::new(Barrier::new(THREADS + 1));
+        let threads: Vec<_> = (0..THREADS).map(|i| {
+            let multi_spool = multi_spool.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let mut rng = thread_rng();
+                barrier.wait();
+                loop {
+                    let result = if i == 0 {
+                        multi_spool.append_to_spool(spool_id, &[RACE_MESSAGES]).map(|_| ())
+                    } else {
+                        let index = rng.gen_range(0, RACE_MESSAGES);
+                        multi_spool.read_from_spool(spool_id, signature, &message_id(u32::from(index)))
+                            .map(|message| assert_eq!(message, vec![index]))
+                    };
+                    match result {
+                        Ok(()) => {},
+                        Err(ref e) if spool_is_gone(e) => return,
+                        Err(e) => panic!("operation racing purge failed: {}", e),
+                    }
+                }
+            })
+        }).collect();
+        barrier.wait();
+        multi_spool.purge_spool(spool_id, signature).unwrap();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        match multi_spool.read_from_spool(spool_id, signature, &message_id(0)) {
+            Err(ref e) if spool_is_gone(e) => {},
+            other => panic!("read after purge returned {:?}", other),
+        }
+        assert!(multi_spool.purge_spool(spool_id, signature).is_err());
+    }
+    assert_eq!(multi_spool.spool_count(), 0);
+}